@@ -1,4 +1,5 @@
 use ruma::{api::appservice::Registration, events::room::message::RoomMessageEventContent};
+use service::appservice::RegistrationOptions;
 
 use crate::{services, Result};
 
@@ -10,9 +11,15 @@ pub(super) async fn register(body: Vec<&str>) -> Result<RoomMessageEventContent>
 	}
 
 	let appservice_config = body[1..body.len().checked_sub(1).unwrap()].join("\n");
-	let parsed_config = serde_yaml::from_str::<Registration>(&appservice_config);
+	let parsed_config = serde_yaml::from_str::<Registration>(&appservice_config).and_then(|yaml| {
+		serde_yaml::from_str::<RegistrationOptions>(&appservice_config).map(|options| (yaml, options))
+	});
 	match parsed_config {
-		Ok(yaml) => match services().appservice.register_appservice(yaml).await {
+		Ok((yaml, options)) => match services()
+			.appservice
+			.register_appservice(yaml, options)
+			.await
+		{
 			Ok(id) => Ok(RoomMessageEventContent::text_plain(format!(
 				"Appservice registered with ID: {id}."
 			))),
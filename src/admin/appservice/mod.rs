@@ -16,6 +16,9 @@ pub(super) enum AppserviceCommand {
 	///
 	/// Registering a new bridge using the ID of an existing bridge will replace
 	/// the old one.
+	///
+	/// Besides the standard registration fields, `transaction_max_events` may
+	/// be set to override the server-wide appservice transaction size.
 	Register,
 
 	/// - Unregister an appservice using its ID
@@ -1,5 +1,6 @@
 use std::{
-	collections::{BTreeMap, HashMap},
+	collections::{BTreeMap, HashMap, VecDeque},
+	fmt::Write,
 	sync::{Arc, Mutex},
 	time::Instant,
 };
@@ -8,17 +9,26 @@ use api::client::validate_and_add_event_id;
 use conduit::{
 	debug, info, log,
 	log::{capture, Capture},
-	warn, Error, Result,
+	utils, warn, Error, PduCount, Result,
 };
 use ruma::{
 	api::{client::error::ErrorKind, federation::event::get_room_state},
-	events::room::message::RoomMessageEventContent,
-	CanonicalJsonObject, EventId, OwnedRoomOrAliasId, RoomId, RoomVersionId, ServerName,
+	events::room::message::{FileMessageEventContent, MessageType, RoomMessageEventContent},
+	serde::Base64,
+	CanonicalJsonObject, CanonicalJsonValue, EventId, OwnedRoomOrAliasId, OwnedServerName, RoomId, RoomVersionId,
+	ServerName,
 };
 use service::{rooms::event_handler::parse_incoming_pdu, sending::resolve::resolve_actual_dest, services, PduEvent};
+use sha2::{Digest, Sha256};
 use tokio::sync::RwLock;
 use tracing_subscriber::EnvFilter;
 
+/// Upper bound on the number of latest events a room DAG export walks
+const DAG_MAX_EVENTS: usize = 5000;
+
+/// Length of the media ID of an uploaded room DAG export
+const DAG_MEDIA_ID_LENGTH: usize = 32;
+
 pub(super) async fn echo(_body: Vec<&str>, message: Vec<String>) -> Result<RoomMessageEventContent> {
 	let message = message.join(" ");
 
@@ -274,7 +284,7 @@ pub(super) async fn ping(_body: Vec<&str>, server: Box<ServerName>) -> Result<Ro
 
 	match services()
 		.sending
-		.send_federation_request(&server, ruma::api::federation::discovery::get_server_version::v1::Request {})
+		.send_federation_request_once(&server, ruma::api::federation::discovery::get_server_version::v1::Request {})
 		.await
 	{
 		Ok(response) => {
@@ -315,7 +325,7 @@ pub(super) async fn change_log_level(
 	_body: Vec<&str>, filter: Option<String>, reset: bool,
 ) -> Result<RoomMessageEventContent> {
 	if reset {
-		let old_filter_layer = match EnvFilter::try_new(&services().globals.config.log) {
+		let old_filter_layer = match EnvFilter::try_new(services().globals.log()) {
 			Ok(s) => s,
 			Err(e) => {
 				return Ok(RoomMessageEventContent::text_plain(format!(
@@ -328,7 +338,7 @@ pub(super) async fn change_log_level(
 			Ok(()) => {
 				return Ok(RoomMessageEventContent::text_plain(format!(
 					"Successfully changed log level back to config value {}",
-					services().globals.config.log
+					services().globals.log()
 				)));
 			},
 			Err(e) => {
@@ -380,7 +390,7 @@ pub(super) async fn sign_json(body: Vec<&str>) -> Result<RoomMessageEventContent
 				&mut value,
 			)
 			.expect("our request json is what ruma expects");
-			let json_text = serde_json::to_string_pretty(&value).expect("canonical json is valid json");
+			let json_text = serde_json::to_string(&value).expect("canonical json is valid json");
 			Ok(RoomMessageEventContent::text_plain(json_text))
 		},
 		Err(e) => Ok(RoomMessageEventContent::text_plain(format!("Invalid json: {e}"))),
@@ -417,6 +427,190 @@ pub(super) async fn verify_json(body: Vec<&str>) -> Result<RoomMessageEventConte
 	}
 }
 
+pub(super) async fn verify_event(_body: Vec<&str>, event_id: Box<EventId>) -> Result<RoomMessageEventContent> {
+	let Some(mut event) = services().rooms.timeline.get_pdu_json(&event_id)? else {
+		return Ok(RoomMessageEventContent::text_plain("Event not found in the database."));
+	};
+
+	let Some(room_id) = event
+		.get("room_id")
+		.and_then(CanonicalJsonValue::as_str)
+		.and_then(|room_id| RoomId::parse(room_id).ok())
+	else {
+		return Ok(RoomMessageEventContent::text_plain("Event has no valid room_id."));
+	};
+
+	let room_version_id = services().rooms.state.get_room_version(&room_id)?;
+	let server_name = services().globals.server_name();
+	let mut msg = format!("Event {event_id} in {room_id} (room version {room_version_id})\n");
+
+	// the event as it is sent over federation
+	if !matches!(room_version_id, RoomVersionId::V1 | RoomVersionId::V2) {
+		event.remove("event_id");
+	}
+
+	match ruma::signatures::reference_hash(&event, &room_version_id) {
+		Ok(_) if matches!(room_version_id, RoomVersionId::V1 | RoomVersionId::V2) => {
+			writeln!(msg, "- Event ID: not derived from the reference hash in this room version")?;
+		},
+		Ok(hash) => {
+			let computed = format!("${hash}");
+			let result = if computed == event_id.as_str() {
+				"match"
+			} else {
+				"MISMATCH"
+			};
+			writeln!(msg, "- Event ID: computed {computed}, {result}")?;
+		},
+		Err(e) => writeln!(msg, "- Event ID: failed to compute the reference hash: {e}")?,
+	}
+
+	let stored_hash = event
+		.get("hashes")
+		.and_then(CanonicalJsonValue::as_object)
+		.and_then(|hashes| hashes.get("sha256"))
+		.and_then(CanonicalJsonValue::as_str)
+		.unwrap_or("none");
+	let computed_hash = content_hash(&event);
+	let result = if computed_hash == stored_hash {
+		"match"
+	} else {
+		"MISMATCH"
+	};
+	writeln!(msg, "- Content hash: stored {stored_hash}, computed {computed_hash}, {result}")?;
+
+	let sender_server = event
+		.get("sender")
+		.and_then(CanonicalJsonValue::as_str)
+		.and_then(|sender| sender.split_once(':'))
+		.map(|(_, server)| server);
+	if let Some(sender_server) = sender_server.filter(|sender_server| *sender_server != server_name.as_str()) {
+		writeln!(msg, "- Sender is not local, the event was not created by us")?;
+
+		let pub_key_map = RwLock::new(BTreeMap::new());
+		if let Err(e) = services()
+			.rooms
+			.event_handler
+			.fetch_required_signing_keys([&event], &pub_key_map)
+			.await
+		{
+			writeln!(msg, "- Failed to fetch the signing keys of the event: {e}")?;
+		}
+
+		let result = sender_signatures(&event, &room_version_id, &pub_key_map.into_inner(), sender_server);
+		writeln!(msg, "- Signatures of {sender_server}: {result}")?;
+	}
+
+	let signatures = event
+		.get("signatures")
+		.and_then(CanonicalJsonValue::as_object)
+		.and_then(|signatures| signatures.get(server_name.as_str()))
+		.and_then(CanonicalJsonValue::as_object)
+		.cloned()
+		.unwrap_or_default();
+
+	let current_key_id = format!("ed25519:{}", services().globals.keypair().version());
+	let keys = services().globals.signing_keys_for(server_name)?;
+	writeln!(msg, "- Signatures of {server_name}:")?;
+	for (key_id, verify_key) in &keys {
+		let label = if key_id.as_str() == current_key_id {
+			"current key"
+		} else {
+			"old key"
+		};
+
+		if !signatures.contains_key(key_id.as_str()) {
+			writeln!(msg, "  - {key_id} ({label}): no signature")?;
+			continue;
+		}
+
+		let result = our_signature(&event, &room_version_id, server_name, key_id.as_str(), &verify_key.key);
+		writeln!(msg, "  - {key_id} ({label}): {result}")?;
+	}
+
+	for key_id in signatures
+		.keys()
+		.filter(|key_id| !keys.keys().any(|known| known.as_str() == key_id.as_str()))
+	{
+		writeln!(msg, "  - {key_id}: signed with a key we do not know")?;
+	}
+
+	Ok(RoomMessageEventContent::notice_markdown(msg))
+}
+
+/// Verifies our signature made with `key_id` alone, so neither the other keys
+/// nor the keys of other servers which signed the event are needed
+fn our_signature(
+	event: &CanonicalJsonObject, room_version_id: &RoomVersionId, server_name: &ServerName, key_id: &str,
+	verify_key: &Base64,
+) -> String {
+	let mut redacted = match ruma::canonical_json::redact(event.clone(), room_version_id, None) {
+		Ok(redacted) => redacted,
+		Err(e) => return format!("could not verify, failed to redact the event: {e}"),
+	};
+
+	let signature = redacted
+		.get("signatures")
+		.and_then(CanonicalJsonValue::as_object)
+		.and_then(|signatures| signatures.get(server_name.as_str()))
+		.and_then(CanonicalJsonValue::as_object)
+		.and_then(|signatures| signatures.get(key_id))
+		.cloned();
+	let Some(signature) = signature else {
+		return "no signature".to_owned();
+	};
+
+	redacted.insert(
+		"signatures".to_owned(),
+		CanonicalJsonValue::Object(BTreeMap::from([(
+			server_name.to_string(),
+			CanonicalJsonValue::Object(BTreeMap::from([(key_id.to_owned(), signature)])),
+		)])),
+	);
+
+	let pub_key_map = BTreeMap::from([(
+		server_name.to_string(),
+		BTreeMap::from([(key_id.to_owned(), verify_key.clone())]),
+	)]);
+	match ruma::signatures::verify_json(&pub_key_map, &redacted) {
+		Ok(()) => "valid".to_owned(),
+		Err(e) => format!("INVALID: {e}"),
+	}
+}
+
+/// Verifies the signatures of the sender's server with its fetched keys.
+/// Without them the event can't be verified, which says nothing about whether
+/// it is signed correctly.
+fn sender_signatures(
+	event: &CanonicalJsonObject, room_version_id: &RoomVersionId,
+	pub_key_map: &BTreeMap<String, BTreeMap<String, Base64>>, sender_server: &str,
+) -> String {
+	if !pub_key_map
+		.get(sender_server)
+		.is_some_and(|keys| !keys.is_empty())
+	{
+		return "could not verify, their signing keys are unknown and could not be fetched".to_owned();
+	}
+
+	match ruma::signatures::verify_event(pub_key_map, event, room_version_id) {
+		Ok(_) => "valid".to_owned(),
+		Err(e) => format!("INVALID: {e}"),
+	}
+}
+
+/// The unpadded base64 SHA-256 of the canonical JSON of the event without its
+/// `unsigned`, `signatures` and `hashes`
+fn content_hash(event: &CanonicalJsonObject) -> String {
+	let mut event = event.clone();
+	event.remove("unsigned");
+	event.remove("signatures");
+	event.remove("hashes");
+
+	let canonical = serde_json::to_vec(&event).expect("canonical json is valid json");
+	let hash: Base64 = Base64::new(Sha256::digest(canonical).to_vec());
+	hash.encode()
+}
+
 #[tracing::instrument(skip(_body))]
 pub(super) async fn first_pdu_in_room(_body: Vec<&str>, room_id: Box<RoomId>) -> Result<RoomMessageEventContent> {
 	if !services()
@@ -460,7 +654,7 @@ pub(super) async fn latest_pdu_in_room(_body: Vec<&str>, room_id: Box<RoomId>) -
 }
 
 #[tracing::instrument(skip(_body))]
-pub(super) async fn force_set_room_state_from_server(
+pub(crate) async fn force_set_room_state_from_server(
 	_body: Vec<&str>, server_name: Box<ServerName>, room_id: Box<RoomId>,
 ) -> Result<RoomMessageEventContent> {
 	if !services()
@@ -582,6 +776,7 @@ pub(super) async fn force_set_room_state_from_server(
 		 m.room.member state"
 	);
 	services().rooms.state_cache.update_joined_count(&room_id)?;
+	services().rooms.metadata.set_incomplete(&room_id, false)?;
 
 	drop(state_lock);
 
@@ -640,3 +835,289 @@ pub(super) fn memory_stats() -> RoomMessageEventContent {
 		html_body,
 	)
 }
+
+pub(super) async fn check_clock(
+	_body: Vec<&str>, servers: Vec<Box<ServerName>>, threshold: u64,
+) -> Result<RoomMessageEventContent> {
+	if !services().globals.config.allow_federation {
+		return Ok(RoomMessageEventContent::text_plain(
+			"Federation is disabled on this homeserver.",
+		));
+	}
+
+	let servers: Vec<OwnedServerName> = if servers.is_empty() {
+		services().globals.trusted_servers()
+	} else {
+		servers.into_iter().map(Into::into).collect()
+	};
+
+	let mut msg = String::new();
+	let mut skews = Vec::new();
+	for server in servers {
+		if *server == *services().globals.server_name() {
+			continue;
+		}
+
+		match clock_skew(&server).await {
+			Ok(skew) => {
+				writeln!(msg, "- {server}: {skew} ms").expect("should be able to write to string buffer");
+				skews.push(skew);
+			},
+			Err(e) => {
+				writeln!(msg, "- {server}: failed: {e}").expect("should be able to write to string buffer");
+			},
+		}
+	}
+
+	if skews.is_empty() {
+		return Ok(RoomMessageEventContent::notice_markdown(format!(
+			"Could not measure clock skew against any server:\n{msg}"
+		)));
+	}
+
+	skews.sort_unstable();
+	let median = skews[skews.len() / 2];
+	services()
+		.globals
+		.update_last_clock_skew(utils::millis_since_unix_epoch(), median)?;
+
+	let threshold_ms = i64::try_from(threshold.saturating_mul(1000)).unwrap_or(i64::MAX);
+	if median.abs() > threshold_ms {
+		warn!("Our clock appears to be off by {median} ms compared to other servers");
+		return Ok(RoomMessageEventContent::notice_markdown(format!(
+			"Our clock appears to be off by **{median} ms** (more than {threshold} seconds). This breaks signature \
+			 validity windows and event ordering, please check NTP on this host.\n\n{msg}"
+		)));
+	}
+
+	Ok(RoomMessageEventContent::notice_markdown(format!(
+		"Median clock skew is {median} ms, within {threshold} seconds.\n\n{msg}"
+	)))
+}
+
+/// Returns the difference between the remote server's clock, as reported by
+/// the `Date` header of its `/_matrix/federation/v1/version` response, and
+/// ours in milliseconds. Positive means the remote server is ahead of us.
+async fn clock_skew(server: &ServerName) -> Result<i64> {
+	let (actual_dest, _) = resolve_actual_dest(server, true).await?;
+
+	let before = utils::millis_since_unix_epoch();
+	let response = services()
+		.globals
+		.client
+		.federation
+		.get(format!("https://{actual_dest}/_matrix/federation/v1/version"))
+		.send()
+		.await?;
+	let after = utils::millis_since_unix_epoch();
+
+	let date = response
+		.headers()
+		.get("date")
+		.ok_or_else(|| Error::Err(format!("{server} did not send a Date header")))?
+		.to_str()
+		.map_err(|e| Error::Err(format!("Invalid Date header from {server}: {e}")))?;
+
+	let remote = chrono::DateTime::parse_from_rfc2822(date)
+		.map_err(|e| Error::Err(format!("Invalid Date header from {server}: {e}")))?
+		.timestamp_millis();
+
+	// the Date header only has second precision, so assume the middle of that
+	// second; likewise assume the response was generated halfway through our
+	// request
+	let remote = remote.saturating_add(500);
+	let local = i64::try_from(before.saturating_add(after.saturating_sub(before) / 2)).unwrap_or(i64::MAX);
+
+	Ok(remote.saturating_sub(local))
+}
+
+/// How an event appears in an exported room DAG
+#[derive(Clone, Copy)]
+enum DagNode {
+	/// One of the latest timeline events walked
+	Latest,
+
+	/// An earlier timeline event, whose own prev_events are not walked
+	Earlier,
+
+	SoftFailed,
+	Outlier,
+
+	/// An event we do not have at all
+	Missing,
+}
+
+pub(super) async fn dag(_body: Vec<&str>, room_id: Box<RoomId>, last: usize) -> Result<RoomMessageEventContent> {
+	let last = last.min(DAG_MAX_EVENTS);
+	let mut nodes: BTreeMap<Arc<EventId>, (DagNode, Option<PduEvent>)> = services()
+		.rooms
+		.timeline
+		.pdus_until(&services().globals.server_user, &room_id, PduCount::max())?
+		.take(last)
+		.filter_map(Result::ok)
+		.map(|(_, pdu)| (pdu.event_id.clone(), (DagNode::Latest, Some(pdu))))
+		.collect();
+
+	if nodes.is_empty() {
+		return Ok(RoomMessageEventContent::text_plain("We do not have any events in this room."));
+	}
+
+	let extremities = services().rooms.state.get_forward_extremities(&room_id)?;
+	let mut pending: VecDeque<_> = nodes.keys().cloned().collect();
+	for extremity in &extremities {
+		if !nodes.contains_key(extremity) {
+			let node = dag_node(extremity)?;
+			if matches!(node.0, DagNode::SoftFailed | DagNode::Outlier) {
+				pending.push_back(extremity.clone());
+			}
+			nodes.insert(extremity.clone(), node);
+		}
+	}
+
+	// soft-failed events and outliers are followed further back, bounded so a
+	// long chain of them cannot make the graph unreadable
+	let max_nodes = last.saturating_mul(2);
+	let mut edges = Vec::new();
+	while let Some(event_id) = pending.pop_front() {
+		let prev_events = nodes
+			.get(&event_id)
+			.and_then(|(_, pdu)| pdu.as_ref())
+			.map(|pdu| pdu.prev_events.clone())
+			.unwrap_or_default();
+
+		for prev_event in prev_events {
+			if !nodes.contains_key(&prev_event) {
+				if nodes.len() >= max_nodes {
+					continue;
+				}
+
+				let node = dag_node(&prev_event)?;
+				if matches!(node.0, DagNode::SoftFailed | DagNode::Outlier) {
+					pending.push_back(prev_event.clone());
+				}
+				nodes.insert(prev_event.clone(), node);
+			}
+
+			edges.push((event_id.clone(), prev_event));
+		}
+	}
+
+	let mut dot = format!("digraph \"{room_id}\" {{\n\trankdir=BT;\n\tnode [shape=box, fontname=monospace];\n");
+	for (event_id, (kind, pdu)) in &nodes {
+		let short_id: String = event_id.as_str().chars().skip(1).take(8).collect();
+		let label = match pdu {
+			Some(pdu) => format!(
+				"{short_id}\\n{}\\n{}\\ndepth {}",
+				escape_dot(&pdu.kind.to_string()),
+				pdu.sender.server_name(),
+				pdu.depth
+			),
+			None => format!("{short_id}\\n(missing)"),
+		};
+
+		let style = match kind {
+			DagNode::Latest => "",
+			DagNode::Earlier => ", style=filled, fillcolor=lightgray",
+			DagNode::SoftFailed => ", style=dashed, color=orange",
+			DagNode::Outlier => ", style=dotted, color=gray40",
+			DagNode::Missing => ", color=red, fontcolor=red",
+		};
+
+		let extremity = if extremities.contains(event_id) {
+			", peripheries=2, penwidth=2"
+		} else {
+			""
+		};
+
+		writeln!(dot, "\t\"{event_id}\" [label=\"{label}\"{style}{extremity}];")
+			.expect("should be able to write to string buffer");
+	}
+
+	for (event_id, prev_event) in &edges {
+		writeln!(dot, "\t\"{event_id}\" -> \"{prev_event}\";").expect("should be able to write to string buffer");
+	}
+	dot.push_str("}\n");
+
+	let file_name = format!("dag-{room_id}.dot");
+	let mxc = format!(
+		"mxc://{}/{}",
+		services().globals.server_name(),
+		utils::random_string(DAG_MEDIA_ID_LENGTH)
+	);
+	services()
+		.media
+		.create(
+			Some(services().globals.server_user.clone()),
+			&mxc,
+			Some(&format!("attachment; filename={file_name}")),
+			Some("text/vnd.graphviz"),
+			dot.as_bytes(),
+		)
+		.await?;
+
+	Ok(RoomMessageEventContent::new(MessageType::File(FileMessageEventContent::plain(
+		file_name,
+		mxc.into(),
+	))))
+}
+
+fn dag_node(event_id: &EventId) -> Result<(DagNode, Option<PduEvent>)> {
+	// soft-failed events are kept as outliers
+	if services()
+		.rooms
+		.pdu_metadata
+		.is_event_soft_failed(event_id)?
+	{
+		let pdu = services().rooms.outlier.get_pdu_outlier(event_id)?;
+		return Ok((DagNode::SoftFailed, pdu));
+	}
+
+	if let Some(pdu) = services().rooms.timeline.get_non_outlier_pdu(event_id)? {
+		return Ok((DagNode::Earlier, Some(pdu)));
+	}
+
+	Ok(match services().rooms.outlier.get_pdu_outlier(event_id)? {
+		Some(pdu) => (DagNode::Outlier, Some(pdu)),
+		None => (DagNode::Missing, None),
+	})
+}
+
+fn escape_dot(s: &str) -> String { s.replace('\\', "\\\\").replace('"', "\\\"") }
+
+#[cfg(test)]
+mod tests {
+	use std::collections::BTreeMap;
+
+	use ruma::{serde::Base64, CanonicalJsonObject, RoomVersionId};
+	use serde_json::json;
+
+	use super::sender_signatures;
+
+	#[test]
+	fn remote_signatures_without_keys_are_not_invalid() {
+		let event: CanonicalJsonObject = serde_json::from_value(json!({
+			"room_id": "!room:remote.example",
+			"sender": "@alice:remote.example",
+			"origin_server_ts": 1,
+			"type": "m.room.message",
+			"content": { "msgtype": "m.text", "body": "hello" },
+			"prev_events": [],
+			"auth_events": [],
+			"depth": 1,
+			"hashes": { "sha256": "" },
+			"signatures": { "remote.example": { "ed25519:key": "AAAA" } },
+		}))
+		.unwrap();
+
+		let no_keys = BTreeMap::new();
+		assert!(
+			sender_signatures(&event, &RoomVersionId::V10, &no_keys, "remote.example").starts_with("could not verify")
+		);
+
+		let wrong_key = BTreeMap::from([(
+			"remote.example".to_owned(),
+			BTreeMap::from([("ed25519:key".to_owned(), Base64::new(vec![0; 32]))]),
+		)]);
+		assert!(sender_signatures(&event, &RoomVersionId::V10, &wrong_key, "remote.example").starts_with("INVALID"));
+	}
+}
@@ -6,6 +6,7 @@ use conduit::Result;
 use ruma::{events::room::message::RoomMessageEventContent, EventId, OwnedRoomOrAliasId, RoomId, ServerName};
 use tester::TesterCommand;
 
+pub(crate) use self::commands::force_set_room_state_from_server;
 use self::commands::*;
 
 #[cfg_attr(test, derive(Debug))]
@@ -99,7 +100,7 @@ pub(super) enum DebugCommand {
 		reset: bool,
 	},
 
-	/// - Verify json signatures
+	/// - Sign json with our server key and print its canonical form
 	///
 	/// This command needs a JSON blob provided in a Markdown code block below
 	/// the command.
@@ -111,6 +112,18 @@ pub(super) enum DebugCommand {
 	/// the command.
 	VerifyJson,
 
+	/// - Checks the hashes and our signatures of an event we have stored
+	///
+	/// Recomputes the reference hash and content hash of the event and
+	/// verifies the signatures of our server with the current and old keys,
+	/// reporting every mismatch. Events of remote senders are also verified
+	/// with the fetched keys of the sender's server. The stored event is not
+	/// modified.
+	VerifyEvent {
+		/// An event ID (a $ followed by the base64 reference hash)
+		event_id: Box<EventId>,
+	},
+
 	/// - Prints the very first PDU in the specified room (typically
 	///   m.room.create)
 	FirstPduInRoom {
@@ -160,6 +173,39 @@ pub(super) enum DebugCommand {
 	/// - Print extended memory usage
 	MemoryStats,
 
+	/// - Compares our clock against the `Date` header of federation responses
+	///   from other servers
+	///
+	/// Clock skew breaks signature validity windows and the ordering of events
+	/// by origin_server_ts. If no servers are specified, the configured
+	/// trusted servers are used. The median skew is saved and shown in
+	/// `server status`.
+	CheckClock {
+		/// Servers to compare our clock against
+		servers: Vec<Box<ServerName>>,
+
+		/// Warn if the measured skew exceeds this many seconds
+		#[arg(short, long, default_value_t = 5)]
+		threshold: u64,
+	},
+
+	/// - Exports the event graph of the latest events in a room as a Graphviz
+	///   DOT file
+	///
+	/// Nodes are labeled with the short event ID, type, sender server and
+	/// depth. Forward extremities are drawn with a double border. The
+	/// prev_events the walk reaches beyond the latest events are shown
+	/// greyed out, soft-failed events dashed, outliers dotted, and events we
+	/// do not have in red, so that gaps in the graph are visible.
+	Dag {
+		/// The room ID
+		room_id: Box<RoomId>,
+
+		/// Number of latest timeline events to walk
+		#[arg(long, default_value_t = 200)]
+		last: usize,
+	},
+
 	/// - Developer test stubs
 	#[command(subcommand)]
 	Tester(TesterCommand),
@@ -194,6 +240,9 @@ pub(super) async fn process(command: DebugCommand, body: Vec<&str>) -> Result<Ro
 		} => change_log_level(body, filter, reset).await?,
 		DebugCommand::SignJson => sign_json(body).await?,
 		DebugCommand::VerifyJson => verify_json(body).await?,
+		DebugCommand::VerifyEvent {
+			event_id,
+		} => verify_event(body, event_id).await?,
 		DebugCommand::FirstPduInRoom {
 			room_id,
 		} => first_pdu_in_room(body, room_id).await?,
@@ -213,6 +262,14 @@ pub(super) async fn process(command: DebugCommand, body: Vec<&str>) -> Result<Ro
 			no_cache,
 		} => resolve_true_destination(body, server_name, no_cache).await?,
 		DebugCommand::MemoryStats => memory_stats(),
+		DebugCommand::CheckClock {
+			servers,
+			threshold,
+		} => check_clock(body, servers, threshold).await?,
+		DebugCommand::Dag {
+			room_id,
+			last,
+		} => dag(body, room_id, last).await?,
 		DebugCommand::Tester(command) => tester::process(command, body).await?,
 	})
 }
@@ -1,9 +1,31 @@
-use std::fmt::Write;
+use std::{
+	fmt::Write,
+	time::{Duration, SystemTime, UNIX_EPOCH},
+};
 
-use ruma::{events::room::message::RoomMessageEventContent, OwnedRoomId, RoomId, ServerName, UserId};
+use conduit::utils;
+use ruma::{
+	events::room::message::{FileMessageEventContent, MessageType, RoomMessageEventContent},
+	RoomId, ServerName, UserId,
+};
+use service::{
+	sending::{capture::MAX_CAPTURE_COUNT, Destination},
+	server_is_ours,
+};
 
 use crate::{escape_html, get_room_info, services, Result};
 
+/// Captured transactions are shown in the admin room up to this many bytes,
+/// beyond which they are uploaded as a file
+const CAPTURES_INLINE_LIMIT: usize = 16 * 1024;
+
+/// Length of the media ID of an uploaded capture dump
+const CAPTURES_MEDIA_ID_LENGTH: usize = 32;
+
+/// State events listed per side of the diff printed by `federation
+/// resync-state`
+const RESYNC_DIFF_LIMIT: usize = 100;
+
 pub(super) async fn disable_room(_body: Vec<&str>, room_id: Box<RoomId>) -> Result<RoomMessageEventContent> {
 	services().rooms.metadata.disable_room(&room_id, true)?;
 	Ok(RoomMessageEventContent::text_plain("Room disabled."))
@@ -14,6 +36,73 @@ pub(super) async fn enable_room(_body: Vec<&str>, room_id: Box<RoomId>) -> Resul
 	Ok(RoomMessageEventContent::text_plain("Room enabled."))
 }
 
+pub(super) async fn block_invites(_body: Vec<&str>, server_name: Box<ServerName>) -> Result<RoomMessageEventContent> {
+	if server_is_ours(&server_name) {
+		return Ok(RoomMessageEventContent::text_plain(
+			"Invites from our own server cannot be blocked.",
+		));
+	}
+
+	services()
+		.rooms
+		.state_cache
+		.block_invites(&server_name, true)?;
+	Ok(RoomMessageEventContent::text_plain(format!(
+		"Invites from {server_name} are now refused."
+	)))
+}
+
+pub(super) async fn unblock_invites(_body: Vec<&str>, server_name: Box<ServerName>) -> Result<RoomMessageEventContent> {
+	let state_cache = &services().rooms.state_cache;
+	state_cache.block_invites(&server_name, false)?;
+
+	let msg = if state_cache.server_may_invite(&server_name)? {
+		format!("Invites from {server_name} are accepted again.")
+	} else {
+		format!(
+			"Removed the block of {server_name}, but its invites are still refused by invite_blocked_servers or \
+			 invite_allowed_servers in the config."
+		)
+	};
+
+	Ok(RoomMessageEventContent::text_plain(msg))
+}
+
+pub(super) async fn list_invite_blocks(_body: Vec<&str>) -> Result<RoomMessageEventContent> {
+	let config = &services().globals.config;
+	let blocked: Vec<_> = services()
+		.rooms
+		.state_cache
+		.invite_blocked_servers()
+		.collect::<Result<_>>()?;
+
+	let mut msg = String::new();
+	writeln!(msg, "Blocked with block-invites ({}):", blocked.len())?;
+	for server in &blocked {
+		writeln!(msg, "{server}")?;
+	}
+
+	writeln!(msg, "\nBlocked in the config ({}):", config.invite_blocked_servers.len())?;
+	for server in &config.invite_blocked_servers {
+		writeln!(msg, "{server}")?;
+	}
+
+	if !config.invite_allowed_servers.is_empty() {
+		write!(
+			msg,
+			"\nOnly invites from our own server and these are accepted: {}",
+			config
+				.invite_allowed_servers
+				.iter()
+				.map(ToString::to_string)
+				.collect::<Vec<_>>()
+				.join(", ")
+		)?;
+	}
+
+	Ok(RoomMessageEventContent::text_plain(msg))
+}
+
 pub(super) async fn incoming_federation(_body: Vec<&str>) -> Result<RoomMessageEventContent> {
 	let map = services().globals.roomid_federationhandletime.read().await;
 	let mut msg = format!("Handling {} incoming pdus:\n", map.len());
@@ -81,7 +170,7 @@ pub(super) async fn remote_user_in_rooms(_body: Vec<&str>, user_id: Box<UserId>)
 		));
 	}
 
-	let mut rooms: Vec<(OwnedRoomId, u64, String)> = services()
+	let mut rooms: Vec<_> = services()
 		.rooms
 		.state_cache
 		.rooms_joined(&user_id)
@@ -101,7 +190,7 @@ pub(super) async fn remote_user_in_rooms(_body: Vec<&str>, user_id: Box<UserId>)
 		rooms.len(),
 		rooms
 			.iter()
-			.map(|(id, members, name)| format!("{id}\tMembers: {members}\tName: {name}"))
+			.map(|(id, members, name, _)| format!("{id}\tMembers: {members}\tName: {name}"))
 			.collect::<Vec<_>>()
 			.join("\n")
 	);
@@ -111,7 +200,7 @@ pub(super) async fn remote_user_in_rooms(_body: Vec<&str>, user_id: Box<UserId>)
 		rooms.len(),
 		rooms
 			.iter()
-			.fold(String::new(), |mut output, (id, members, name)| {
+			.fold(String::new(), |mut output, (id, members, name, _)| {
 				writeln!(
 					output,
 					"<tr><td>{}</td>\t<td>{}</td>\t<td>{}</td></tr>",
@@ -126,3 +215,208 @@ pub(super) async fn remote_user_in_rooms(_body: Vec<&str>, user_id: Box<UserId>)
 
 	Ok(RoomMessageEventContent::text_html(output_plain, output_html))
 }
+
+pub(super) async fn capture(
+	_body: Vec<&str>, server_name: Box<ServerName>, count: usize,
+) -> Result<RoomMessageEventContent> {
+	if server_is_ours(&server_name) {
+		return Ok(RoomMessageEventContent::text_plain("We don't send transactions to ourselves."));
+	}
+
+	if count == 0 {
+		return Ok(RoomMessageEventContent::text_plain("Count must be at least 1."));
+	}
+
+	services().sending.captures.start(&server_name, count);
+
+	Ok(RoomMessageEventContent::text_plain(format!(
+		"Recording the next {} transaction(s) to {server_name} for at most an hour. Use `federation captures \
+		 {server_name}` to show them.",
+		count.min(MAX_CAPTURE_COUNT)
+	)))
+}
+
+pub(super) async fn captures(_body: Vec<&str>, server_name: Box<ServerName>) -> Result<RoomMessageEventContent> {
+	let Some(capture) = services().sending.captures.get(&server_name) else {
+		return Ok(RoomMessageEventContent::text_plain(format!(
+			"No transactions to {server_name} are being captured."
+		)));
+	};
+
+	let mut dump = format!(
+		"{} transaction(s) captured for {server_name}, {} more to record, expiring in {} minutes\n",
+		capture.transactions.len(),
+		capture.remaining,
+		capture.expires_in().as_secs() / 60,
+	);
+	for transaction in &capture.transactions {
+		let sent = transaction
+			.sent
+			.duration_since(UNIX_EPOCH)
+			.unwrap_or_default()
+			.as_secs();
+		writeln!(
+			dump,
+			"\n## Transaction {} (sent at {sent})\n\n### Request\n```json\n{}\n```\n\n### Response\n```\n{}\n```",
+			transaction.txn_id, transaction.request, transaction.response
+		)?;
+	}
+
+	if dump.len() <= CAPTURES_INLINE_LIMIT {
+		return Ok(RoomMessageEventContent::notice_markdown(dump));
+	}
+
+	let file_name = format!("captures-{server_name}.md");
+	let mxc = format!(
+		"mxc://{}/{}",
+		services().globals.server_name(),
+		utils::random_string(CAPTURES_MEDIA_ID_LENGTH)
+	);
+	services()
+		.media
+		.create(
+			Some(services().globals.server_user.clone()),
+			&mxc,
+			Some(&format!("attachment; filename={file_name}")),
+			Some("text/markdown"),
+			dump.as_bytes(),
+		)
+		.await?;
+	services()
+		.sending
+		.captures
+		.add_upload(&server_name, mxc.clone());
+
+	Ok(RoomMessageEventContent::new(MessageType::File(FileMessageEventContent::plain(
+		file_name,
+		mxc.into(),
+	))))
+}
+
+pub(super) async fn incoming_stats(_body: Vec<&str>) -> Result<RoomMessageEventContent> {
+	let origins = services().rooms.event_handler.stats.summary();
+	let prev = services().rooms.event_handler.prev_events.summary();
+	let mut prev = format!(
+		"Missing prev events since startup: {} fetched in {} waves for {} PDUs, at most {} waves for one \
+		 PDU.\n\nInvalid EDUs dropped since startup: {}",
+		prev.events,
+		prev.waves,
+		prev.fetches,
+		prev.max_waves,
+		services().rooms.event_handler.edus.dropped(),
+	);
+
+	let throttled = services().rate_limit.rooms.throttled();
+	if !throttled.is_empty() {
+		write!(
+			prev,
+			"\n\nSends by local users throttled since startup:\n\n| Room | Throttled |\n| --- | --- |\n"
+		)?;
+		for (room_id, count) in throttled {
+			writeln!(prev, "| {room_id} | {count} |")?;
+		}
+	}
+
+	if origins.is_empty() {
+		return Ok(RoomMessageEventContent::notice_markdown(format!(
+			"No PDUs were received during the last day.\n\n{prev}"
+		)));
+	}
+
+	let mut msg = format!(
+		"Servers we received PDUs from during the last day ({}):\n\n| Server | Last hour | Last day | Last seen |\n| \
+		 --- | --- | --- | --- |\n",
+		origins.len()
+	);
+	for origin in &origins {
+		writeln!(
+			msg,
+			"| {} | {} | {} | {:?} ago |",
+			origin.origin,
+			origin.last_hour,
+			origin.last_day,
+			elapsed_secs(origin.last_seen),
+		)?;
+	}
+	write!(msg, "\n{prev}")?;
+
+	Ok(RoomMessageEventContent::notice_markdown(msg))
+}
+
+pub(super) async fn destination_stats(
+	_body: Vec<&str>, server_name: Box<ServerName>,
+) -> Result<RoomMessageEventContent> {
+	let dest = Destination::Normal(server_name.clone().into());
+	let queued = services().sending.db.queued_requests(&dest).count();
+	let active = services().sending.db.active_requests_for(&dest).count();
+	let stats = services()
+		.sending
+		.stats
+		.get(&server_name)
+		.unwrap_or_default();
+
+	let last_success = stats
+		.last_success
+		.map_or_else(|| "never".to_owned(), |time| format!("{:?} ago", elapsed_secs(time)));
+	let last_error = stats.last_error.as_ref().map_or_else(
+		|| "none".to_owned(),
+		|(time, e)| format!("{:?} ago: {}", elapsed_secs(*time), e.replace('|', "\\|").replace('\n', " ")),
+	);
+
+	let mut msg = format!(
+		"Destination stats for {server_name} since startup:\n\n| Queued events | Events in flight | Transactions sent \
+		 | PDUs sent | Requests retried | Last successful send | Last error |\n| --- | --- | --- | --- | --- | --- | \
+		 --- |\n| {queued} | {active} | {} | {} | {} | {last_success} | {last_error} |\n",
+		stats.transactions, stats.pdus, stats.retries,
+	);
+
+	if !stats.edus.is_empty() {
+		msg.push_str("\n| EDU type | Sent |\n| --- | --- |\n");
+		for (edu_type, count) in &stats.edus {
+			writeln!(msg, "| {edu_type} | {count} |")?;
+		}
+	}
+
+	Ok(RoomMessageEventContent::notice_markdown(msg))
+}
+
+pub(super) async fn resync_state(
+	_body: Vec<&str>, room_id: Box<RoomId>, server_name: Box<ServerName>,
+) -> Result<RoomMessageEventContent> {
+	if server_is_ours(&server_name) {
+		return Ok(RoomMessageEventContent::text_plain(
+			"Cannot resync the state of a room from our own server.",
+		));
+	}
+
+	let resync = services()
+		.rooms
+		.event_handler
+		.resync_state(&room_id, &server_name)
+		.await?;
+
+	let mut msg = format!(
+		"Replaced the state of {room_id} with the state of {server_name} at {}: {} events added, {} removed.\n",
+		resync.event_id,
+		resync.added.len(),
+		resync.removed.len(),
+	);
+
+	if !resync.added.is_empty() || !resync.removed.is_empty() {
+		msg.push_str("\n```diff\n");
+		for (sign, events) in [("-", &resync.removed), ("+", &resync.added)] {
+			for (kind, state_key, event_id) in events.iter().take(RESYNC_DIFF_LIMIT) {
+				writeln!(msg, "{sign} {kind} {state_key:?} {event_id}")?;
+			}
+			if events.len() > RESYNC_DIFF_LIMIT {
+				writeln!(msg, "{sign} ... and {} more", events.len().saturating_sub(RESYNC_DIFF_LIMIT))?;
+			}
+		}
+		msg.push_str("```");
+	}
+
+	Ok(RoomMessageEventContent::notice_markdown(msg))
+}
+
+/// Time since `time`, rounded to seconds
+fn elapsed_secs(time: SystemTime) -> Duration { Duration::from_secs(time.elapsed().unwrap_or_default().as_secs()) }
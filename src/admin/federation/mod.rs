@@ -22,6 +22,22 @@ pub(super) enum FederationCommand {
 		room_id: Box<RoomId>,
 	},
 
+	/// - Refuses invites from users of a server to our users
+	///
+	/// Invites sent over federation are answered with M_FORBIDDEN. The block
+	/// is kept in the database, in addition to `invite_blocked_servers`.
+	BlockInvites {
+		server_name: Box<ServerName>,
+	},
+
+	/// - Accepts invites from a server blocked with `block-invites` again
+	UnblockInvites {
+		server_name: Box<ServerName>,
+	},
+
+	/// - Lists the servers invites are refused from
+	ListInviteBlocks,
+
 	/// - Fetch `/.well-known/matrix/support` from the specified server
 	///
 	/// Despite the name, this is not a federation endpoint and does not go
@@ -39,6 +55,50 @@ pub(super) enum FederationCommand {
 	RemoteUserInRooms {
 		user_id: Box<UserId>,
 	},
+
+	/// - Records the next transactions we send to a server and its responses
+	///
+	/// Captures are only kept in memory and expire after an hour, along with
+	/// the transactions they recorded. Use `federation captures` to show them.
+	Capture {
+		server_name: Box<ServerName>,
+
+		/// Number of transactions to record, at most 50
+		#[arg(short, long, default_value_t = 5)]
+		count: usize,
+	},
+
+	/// - Shows the transactions recorded by `federation capture` for a server
+	///
+	/// Long outputs are uploaded as a text file instead.
+	Captures {
+		server_name: Box<ServerName>,
+	},
+
+	/// - Lists the servers we received PDUs from during the last day, and the
+	///   waves missing prev events were fetched in
+	///
+	/// Counts are kept in memory since startup and are approximate to ten
+	/// minutes.
+	IncomingStats,
+
+	/// - Shows the queue of a destination server and the outcome of the
+	///   transactions sent to it since startup
+	DestinationStats {
+		server_name: Box<ServerName>,
+	},
+
+	/// - Replaces the state of a room with the state a remote server has at our
+	///   latest forward extremity
+	///
+	/// For rooms whose state diverged from the rest of federation. The fetched
+	/// state events are checked like those of incoming PDUs, but are not
+	/// resolved against our current state. Refuses to run while incoming PDUs
+	/// of the room are being handled.
+	ResyncState {
+		room_id: Box<RoomId>,
+		server_name: Box<ServerName>,
+	},
 }
 
 pub(super) async fn process(command: FederationCommand, body: Vec<&str>) -> Result<RoomMessageEventContent> {
@@ -50,11 +110,33 @@ pub(super) async fn process(command: FederationCommand, body: Vec<&str>) -> Resu
 			room_id,
 		} => enable_room(body, room_id).await?,
 		FederationCommand::IncomingFederation => incoming_federation(body).await?,
+		FederationCommand::BlockInvites {
+			server_name,
+		} => block_invites(body, server_name).await?,
+		FederationCommand::UnblockInvites {
+			server_name,
+		} => unblock_invites(body, server_name).await?,
+		FederationCommand::ListInviteBlocks => list_invite_blocks(body).await?,
 		FederationCommand::FetchSupportWellKnown {
 			server_name,
 		} => fetch_support_well_known(body, server_name).await?,
 		FederationCommand::RemoteUserInRooms {
 			user_id,
 		} => remote_user_in_rooms(body, user_id).await?,
+		FederationCommand::Capture {
+			server_name,
+			count,
+		} => capture(body, server_name, count).await?,
+		FederationCommand::Captures {
+			server_name,
+		} => captures(body, server_name).await?,
+		FederationCommand::IncomingStats => incoming_stats(body).await?,
+		FederationCommand::DestinationStats {
+			server_name,
+		} => destination_stats(body, server_name).await?,
+		FederationCommand::ResyncState {
+			room_id,
+			server_name,
+		} => resync_state(body, room_id, server_name).await?,
 	})
 }
@@ -2,21 +2,25 @@ use std::time::Instant;
 
 use clap::Parser;
 use conduit::trace;
-use ruma::events::{
-	relation::InReplyTo,
-	room::message::{Relation::Reply, RoomMessageEventContent},
+use ruma::{
+	events::{
+		relation::InReplyTo,
+		room::message::{Relation::Reply, RoomMessageEventContent},
+	},
+	UserId,
 };
 
 extern crate conduit_service as service;
 
-use conduit::Result;
+use conduit::{Error, Result};
 pub(crate) use service::admin::{Command, Service};
 use service::admin::{CommandOutput, CommandResult, HandlerResult};
 
 use crate::{
 	appservice, appservice::AppserviceCommand, check, check::CheckCommand, debug, debug::DebugCommand, federation,
-	federation::FederationCommand, media, media::MediaCommand, query, query::QueryCommand, room, room::RoomCommand,
-	server, server::ServerCommand, services, user, user::UserCommand,
+	federation::FederationCommand, jobs, jobs::JobsCommand, media, media::MediaCommand, query, query::QueryCommand,
+	rate_limit, rate_limit::RateLimitCommand, registration_tokens, registration_tokens::RegistrationTokensCommand,
+	room, room::RoomCommand, server, server::ServerCommand, services, user, user::UserCommand,
 };
 pub(crate) const PAGE_SIZE: usize = 100;
 
@@ -32,6 +36,14 @@ pub(crate) enum AdminCommand {
 	/// - Commands for managing local users
 	Users(UserCommand),
 
+	#[command(subcommand)]
+	/// - Commands for managing registration tokens
+	RegistrationTokens(RegistrationTokensCommand),
+
+	#[command(subcommand)]
+	/// - Commands for managing the rate limits of local users
+	RateLimit(RateLimitCommand),
+
 	#[command(subcommand)]
 	/// - Commands for managing rooms
 	Rooms(RoomCommand),
@@ -56,6 +68,12 @@ pub(crate) enum AdminCommand {
 	/// - Commands for debugging things
 	Debug(DebugCommand),
 
+	/// - List and cancel long-running commands running in the background
+	Jobs {
+		#[command(subcommand)]
+		command: Option<JobsCommand>,
+	},
+
 	#[command(subcommand)]
 	/// - Low-level queries for database getters and iterators
 	Query(QueryCommand),
@@ -66,7 +84,7 @@ pub fn handle(command: Command) -> HandlerResult { Box::pin(handle_command(comma
 
 #[tracing::instrument(skip_all, name = "admin")]
 async fn handle_command(command: Command) -> CommandResult {
-	let Some(mut content) = process_admin_message(command.command).await else {
+	let Some(mut content) = process_admin_message(command.command, command.sender.as_deref()).await else {
 		return Ok(None);
 	};
 
@@ -80,7 +98,7 @@ async fn handle_command(command: Command) -> CommandResult {
 }
 
 // Parse and process a message from the admin room
-async fn process_admin_message(msg: String) -> CommandOutput {
+async fn process_admin_message(msg: String, sender: Option<&UserId>) -> CommandOutput {
 	let mut lines = msg.lines().filter(|l| !l.trim().is_empty());
 	let command = lines.next().expect("each string has at least one line");
 	let body = lines.collect::<Vec<_>>();
@@ -93,10 +111,18 @@ async fn process_admin_message(msg: String) -> CommandOutput {
 		},
 	};
 
+	let target = audit_target(command, &parsed);
 	let timer = Instant::now();
 	let result = process_admin_command(parsed, body).await;
 	let elapsed = timer.elapsed();
 	conduit::debug!(?command, ok = result.is_ok(), "command processed in {elapsed:?}");
+
+	let actor = sender.map_or("console", UserId::as_str);
+	services()
+		.admin
+		.audit
+		.record(actor, "admin_command", &target, &result);
+
 	match result {
 		Ok(reply) => Some(reply),
 		Err(error) => Some(RoomMessageEventContent::notice_markdown(format!(
@@ -105,6 +131,19 @@ async fn process_admin_message(msg: String) -> CommandOutput {
 	}
 }
 
+/// The command line as recorded in the audit log, without the password of
+/// `users create`
+fn audit_target(command_line: &str, command: &AdminCommand) -> String {
+	let command_line = command_line.trim();
+	match command {
+		AdminCommand::Users(UserCommand::Create {
+			password: Some(password),
+			..
+		}) => command_line.replace(password.as_str(), "<redacted>"),
+		_ => command_line.to_owned(),
+	}
+}
+
 // Parse chat messages from the admin room into an AdminCommand object
 fn parse_admin_command(command_line: &str) -> Result<AdminCommand, String> {
 	let mut argv = command_line.split_whitespace().collect::<Vec<_>>();
@@ -155,17 +194,106 @@ fn parse_admin_command(command_line: &str) -> Result<AdminCommand, String> {
 
 #[tracing::instrument(skip_all, name = "command")]
 async fn process_admin_command(command: AdminCommand, body: Vec<&str>) -> Result<RoomMessageEventContent> {
+	if services().globals.read_only() && !is_read_only_command(&command) {
+		return Err(Error::Err(
+			"This command modifies the database, which is opened read-only.".to_owned(),
+		));
+	}
+
 	let reply_message_content = match command {
 		AdminCommand::Appservices(command) => appservice::process(command, body).await?,
 		AdminCommand::Media(command) => media::process(command, body).await?,
 		AdminCommand::Users(command) => user::process(command, body).await?,
+		AdminCommand::RegistrationTokens(command) => registration_tokens::process(command, body).await?,
+		AdminCommand::RateLimit(command) => rate_limit::process(command, body).await?,
 		AdminCommand::Rooms(command) => room::process(command, body).await?,
 		AdminCommand::Federation(command) => federation::process(command, body).await?,
 		AdminCommand::Server(command) => server::process(command, body).await?,
 		AdminCommand::Debug(command) => debug::process(command, body).await?,
 		AdminCommand::Query(command) => query::process(command, body).await?,
 		AdminCommand::Check(command) => check::process(command, body).await?,
+		AdminCommand::Jobs {
+			command,
+		} => jobs::process(command, body).await?,
 	};
 
 	Ok(reply_message_content)
 }
+
+/// Whether the command can run while the database is opened read-only. New
+/// commands are assumed to write unless listed here.
+fn is_read_only_command(command: &AdminCommand) -> bool {
+	match command {
+		AdminCommand::Query(_)
+		| AdminCommand::Check(_)
+		| AdminCommand::Jobs {
+			..
+		} => true,
+		AdminCommand::Appservices(command) => {
+			matches!(command, AppserviceCommand::Show { .. } | AppserviceCommand::List)
+		},
+		AdminCommand::Users(command) => matches!(
+			command,
+			UserCommand::List
+				| UserCommand::ListJoinedRooms { .. }
+				| UserCommand::GetRoomTags { .. }
+				| UserCommand::ListPushers { .. }
+				| UserCommand::SyncStatus { .. }
+		),
+		AdminCommand::Rooms(command) => matches!(
+			command,
+			RoomCommand::List { .. }
+				| RoomCommand::ListMembers { .. }
+				| RoomCommand::Info(_)
+				| RoomCommand::Export { .. }
+				| RoomCommand::PowerLevels { .. }
+				| RoomCommand::Incomplete {
+					resync: false
+				} | RoomCommand::Alias(room::RoomAliasCommand::Which { .. } | room::RoomAliasCommand::List { .. })
+				| RoomCommand::Directory(room::RoomDirectoryCommand::List { .. })
+				| RoomCommand::Moderation(room::RoomModerationCommand::ListBannedRooms)
+		),
+		AdminCommand::Federation(command) => matches!(
+			command,
+			FederationCommand::IncomingFederation
+				| FederationCommand::ListInviteBlocks
+				| FederationCommand::FetchSupportWellKnown { .. }
+				| FederationCommand::RemoteUserInRooms { .. }
+				| FederationCommand::IncomingStats
+				| FederationCommand::DestinationStats { .. }
+		),
+		AdminCommand::Server(command) => matches!(
+			command,
+			ServerCommand::Uptime
+				| ServerCommand::Status
+				| ServerCommand::ShowConfig
+				| ServerCommand::MemoryUsage
+				| ServerCommand::ClearDatabaseCaches { .. }
+				| ServerCommand::ClearServiceCaches { .. }
+				| ServerCommand::ListBackups
+				| ServerCommand::ListDatabaseFiles
+				| ServerCommand::DbActivity { .. }
+				| ServerCommand::Audit { .. }
+				| ServerCommand::Reload
+				| ServerCommand::Restart
+				| ServerCommand::Shutdown
+		),
+		AdminCommand::Media(command) => matches!(command, MediaCommand::ListUser { .. }),
+		AdminCommand::RegistrationTokens(command) => matches!(command, RegistrationTokensCommand::List),
+		AdminCommand::RateLimit(command) => matches!(command, RateLimitCommand::Show { .. }),
+		AdminCommand::Debug(command) => matches!(
+			command,
+			DebugCommand::Echo { .. }
+				| DebugCommand::ParsePdu
+				| DebugCommand::GetPdu { .. }
+				| DebugCommand::GetRoomState { .. }
+				| DebugCommand::Ping { .. }
+				| DebugCommand::ChangeLogLevel { .. }
+				| DebugCommand::SignJson
+				| DebugCommand::VerifyJson
+				| DebugCommand::FirstPduInRoom { .. }
+				| DebugCommand::LatestPduInRoom { .. }
+				| DebugCommand::MemoryStats
+		),
+	}
+}
@@ -0,0 +1,47 @@
+use std::fmt::Write;
+
+use ruma::events::room::message::RoomMessageEventContent;
+
+use crate::{services, Result};
+
+pub(super) async fn list(_body: Vec<&str>) -> Result<RoomMessageEventContent> {
+	let jobs = services().admin.jobs.list();
+	if jobs.is_empty() {
+		return Ok(RoomMessageEventContent::text_plain("No jobs are running."));
+	}
+
+	let mut msg = format!("Running jobs ({}):\n", jobs.len());
+	for job in jobs {
+		let cancelling = if job.is_cancelled() {
+			" (cancelling)"
+		} else {
+			""
+		};
+
+		writeln!(
+			msg,
+			"- {}: `{}` running for {:?}{cancelling}: {}",
+			job.id,
+			job.name,
+			job.elapsed(),
+			job.progress()
+		)?;
+	}
+
+	Ok(RoomMessageEventContent::notice_markdown(msg))
+}
+
+pub(super) async fn cancel(_body: Vec<&str>, job_id: u64) -> Result<RoomMessageEventContent> {
+	let Some(job) = services().admin.jobs.get(job_id) else {
+		return Ok(RoomMessageEventContent::text_plain(format!(
+			"No running job with ID {job_id}."
+		)));
+	};
+
+	job.cancel();
+
+	Ok(RoomMessageEventContent::text_plain(format!(
+		"Requested cancellation of job {job_id} ({}).",
+		job.name
+	)))
+}
@@ -0,0 +1,32 @@
+mod commands;
+
+use clap::Subcommand;
+use conduit::Result;
+use ruma::events::room::message::RoomMessageEventContent;
+
+use self::commands::*;
+
+#[cfg_attr(test, derive(Debug))]
+#[derive(Subcommand)]
+pub(super) enum JobsCommand {
+	/// - List the running jobs with their elapsed time and progress
+	List,
+
+	/// - Request cancellation of a running job
+	///
+	/// The job stops at its next checkpoint and posts a summary of the work it
+	/// did before stopping.
+	Cancel {
+		/// The job ID, as shown by `!admin jobs`
+		job_id: u64,
+	},
+}
+
+pub(super) async fn process(command: Option<JobsCommand>, body: Vec<&str>) -> Result<RoomMessageEventContent> {
+	Ok(match command.unwrap_or(JobsCommand::List) {
+		JobsCommand::List => list(body).await?,
+		JobsCommand::Cancel {
+			job_id,
+		} => cancel(body, job_id).await?,
+	})
+}
@@ -1,8 +1,24 @@
-use conduit::Result;
-use ruma::{events::room::message::RoomMessageEventContent, EventId, MxcUri};
-use tracing::{debug, info};
+use std::{
+	collections::HashSet,
+	fmt::Write as _,
+	sync::Arc,
+	time::{Duration, SystemTime, UNIX_EPOCH},
+};
 
-use crate::services;
+use api::client::{fetch_remote_content, fetch_remote_thumbnail};
+use conduit::{Error, Result};
+use ruma::{
+	api::client::media::get_content_thumbnail, events::room::message::RoomMessageEventContent, EventId, JsOption,
+	MxcUri, OwnedMxcUri, UInt,
+};
+use service::{admin::jobs::Job, media::FileMeta, sending::resolve::resolve_actual_dest, server_is_ours};
+use sha2::{Digest, Sha256};
+use tracing::{debug, info, warn};
+
+use crate::{services, utils::parse_local_user_id};
+
+/// How long to wait for remote media, as clients do by default
+const REMOTE_MEDIA_TIMEOUT: Duration = Duration::from_secs(20);
 
 pub(super) async fn delete(
 	_body: Vec<&str>, mxc: Option<Box<MxcUri>>, event_id: Option<Box<EventId>>,
@@ -177,3 +193,322 @@ pub(super) async fn delete_past_remote_media(
 		"Deleted {deleted_count} total files.",
 	)))
 }
+
+pub(super) async fn purge_remote(_body: Vec<&str>, before_days: u64, force: bool) -> Result<RoomMessageEventContent> {
+	let before = SystemTime::now()
+		.checked_sub(Duration::from_secs(before_days.saturating_mul(60 * 60 * 24)))
+		.ok_or_else(|| Error::Err("Number of days is too large".to_owned()))?;
+
+	let job = services()
+		.admin
+		.jobs
+		.spawn("media purge-remote", move |job| purge_remote_media(job, before, force));
+
+	Ok(RoomMessageEventContent::text_plain(format!(
+		"Purging remote media as job {}. Use `!admin jobs` to follow its progress.",
+		job.id
+	)))
+}
+
+async fn purge_remote_media(job: Arc<Job>, before: SystemTime, force: bool) -> Result<RoomMessageEventContent> {
+	let avatars = avatar_mxcs()?;
+	let mut skipped = Vec::new();
+	let mut purged_count: usize = 0;
+	let mut purged_bytes: u64 = 0;
+
+	let mxcs = services().media.remote_media_before(before).await?;
+	let media_count = mxcs.len();
+	for (i, mxc) in mxcs.into_iter().enumerate() {
+		if job.is_cancelled() {
+			break;
+		}
+
+		job.set_progress(format!(
+			"checked {i} of {media_count} media files, purged {purged_count} ({purged_bytes} bytes)"
+		));
+
+		if avatars.contains(&mxc) {
+			if !force {
+				skipped.push(mxc);
+				continue;
+			}
+
+			warn!("Purging remote media {mxc} which is in use as an avatar");
+		}
+
+		let size = services().media.file_size(mxc.as_str()).await?;
+		debug!("Purging remote media {mxc} ({size} bytes)");
+		services().media.delete(mxc.as_str()).await?;
+		purged_count = purged_count.saturating_add(1);
+		purged_bytes = purged_bytes.saturating_add(size);
+	}
+
+	let mut msg = format!("Purged {purged_count} remote media files, reclaiming {purged_bytes} bytes.");
+	if !skipped.is_empty() {
+		writeln!(
+			msg,
+			"\n\nSkipped {} media files in use as avatars (use --force to purge them anyway):\n```",
+			skipped.len()
+		)?;
+		for mxc in skipped {
+			writeln!(msg, "{mxc}")?;
+		}
+		msg.push_str("```");
+	}
+
+	Ok(RoomMessageEventContent::notice_markdown(msg))
+}
+
+pub(super) async fn list_user(_body: Vec<&str>, user_id: String) -> Result<RoomMessageEventContent> {
+	let user_id = parse_local_user_id(&user_id)?;
+	let mxcs = services().media.list_user_media(&user_id);
+	if mxcs.is_empty() {
+		return Ok(RoomMessageEventContent::text_plain(format!(
+			"{user_id} has not uploaded any media."
+		)));
+	}
+
+	let mut msg = format!("{user_id} has uploaded {} media files:\n```\n", mxcs.len());
+	for mxc in mxcs {
+		let size = services().media.file_size(mxc.as_str()).await.ok();
+		let created = services()
+			.media
+			.created_at(mxc.as_str())
+			.await
+			.ok()
+			.flatten()
+			.and_then(|created| created.duration_since(UNIX_EPOCH).ok());
+
+		writeln!(
+			msg,
+			"{mxc} | {} | uploaded at {}",
+			size.map_or_else(|| "unknown size".to_owned(), |size| format!("{size} bytes")),
+			created.map_or_else(|| "unknown".to_owned(), |created| created.as_millis().to_string()),
+		)?;
+	}
+	msg.push_str("```");
+
+	Ok(RoomMessageEventContent::notice_markdown(msg))
+}
+
+pub(super) async fn download_remote(
+	_body: Vec<&str>, mxc: Box<MxcUri>, force: bool, thumbnail: Option<String>,
+) -> Result<RoomMessageEventContent> {
+	let (Ok(server_name), Ok(media_id)) = (mxc.server_name(), mxc.media_id()) else {
+		return Ok(RoomMessageEventContent::text_plain("Invalid MXC URL."));
+	};
+
+	if server_is_ours(server_name) {
+		return Ok(RoomMessageEventContent::text_plain(
+			"This MXC URL refers to local media, which is never fetched remotely.",
+		));
+	}
+
+	let dimensions = thumbnail.as_deref().map(parse_dimensions).transpose()?;
+	let mut msg = format!("Fetching {mxc}");
+	if let Some((width, height)) = dimensions {
+		write!(msg, " as a {width}x{height} thumbnail")?;
+	}
+	msg.push_str("\n```\n");
+
+	let cached = match dimensions {
+		Some((width, height)) => {
+			services()
+				.media
+				.get_thumbnail(mxc.as_str(), width, height, false)
+				.await?
+		},
+		None => services().media.get(mxc.as_str()).await?,
+	};
+
+	if let Some(cached) = &cached {
+		writeln!(msg, "Cached: {}", describe_media(cached.content_type.as_deref(), &cached.file))?;
+		if !force {
+			msg.push_str("```\nAlready cached; use --force to download it again.");
+			return Ok(RoomMessageEventContent::notice_markdown(msg));
+		}
+	}
+
+	match resolve_actual_dest(server_name, true).await {
+		Ok((actual_dest, hostname_uri)) => writeln!(msg, "Resolved {server_name} to {actual_dest} ({hostname_uri})")?,
+		Err(e) => {
+			writeln!(msg, "Resolving {server_name} failed: {e}\n```")?;
+			return Ok(RoomMessageEventContent::notice_markdown(msg));
+		},
+	}
+
+	let timer = tokio::time::Instant::now();
+	let fetched = match dimensions {
+		Some((width, height)) => fetch_remote_thumbnail(
+			mxc.as_str(),
+			get_content_thumbnail::v3::Request {
+				allow_remote: true,
+				height: UInt::from(height),
+				width: UInt::from(width),
+				method: None,
+				server_name: server_name.to_owned(),
+				media_id: media_id.to_owned(),
+				timeout_ms: REMOTE_MEDIA_TIMEOUT,
+				allow_redirect: false,
+			},
+		)
+		.await
+		.map(|response| FileMeta {
+			content_disposition: response.content_disposition,
+			content_type: response.content_type,
+			file: response.file,
+		}),
+		None => fetch_remote_content(mxc.as_str(), server_name, media_id.to_owned(), false, REMOTE_MEDIA_TIMEOUT)
+			.await
+			.map(|response| FileMeta {
+				content_disposition: response.content_disposition,
+				content_type: response.content_type,
+				file: response.file,
+			}),
+	};
+	let elapsed = timer.elapsed();
+
+	let fetched = match fetched {
+		Ok(fetched) => fetched,
+		Err(e) => {
+			writeln!(msg, "Request failed after {elapsed:?}: {e}\n```")?;
+			return Ok(RoomMessageEventContent::notice_markdown(msg));
+		},
+	};
+
+	writeln!(msg, "Request succeeded in {elapsed:?}")?;
+	writeln!(
+		msg,
+		"Fetched: {}",
+		describe_media(fetched.content_type.as_deref(), &fetched.file)
+	)?;
+
+	if cached
+		.as_ref()
+		.is_some_and(|cached| cached.file == fetched.file && cached.content_type == fetched.content_type)
+	{
+		msg.push_str("```\nThe remote media is unchanged; kept the cached copy.");
+		return Ok(RoomMessageEventContent::notice_markdown(msg));
+	}
+
+	match dimensions {
+		Some((width, height)) => {
+			services()
+				.media
+				.upload_thumbnail(
+					None,
+					mxc.as_str(),
+					fetched.content_disposition.as_deref(),
+					fetched.content_type.as_deref(),
+					width,
+					height,
+					&fetched.file,
+				)
+				.await?;
+		},
+		None => {
+			if cached.is_some() {
+				services().media.delete(mxc.as_str()).await?;
+			}
+
+			services()
+				.media
+				.create(
+					None,
+					mxc.as_str(),
+					fetched.content_disposition.as_deref(),
+					fetched.content_type.as_deref(),
+					&fetched.file,
+				)
+				.await?;
+		},
+	}
+
+	msg.push_str(if cached.is_some() {
+		"```\nThe remote media changed; replaced the cached copy."
+	} else {
+		"```\nStored the media in the cache."
+	});
+
+	Ok(RoomMessageEventContent::notice_markdown(msg))
+}
+
+pub(super) async fn dedup_existing(_body: Vec<&str>) -> Result<RoomMessageEventContent> {
+	if !services().globals.config.media_deduplicate {
+		return Ok(RoomMessageEventContent::text_plain(
+			"Media deduplication is disabled; enable `media_deduplicate` first.",
+		));
+	}
+
+	let job = services()
+		.admin
+		.jobs
+		.spawn("media dedup-existing", dedup_existing_media);
+
+	Ok(RoomMessageEventContent::text_plain(format!(
+		"Deduplicating existing media as job {}. Use `!admin jobs` to follow its progress.",
+		job.id
+	)))
+}
+
+async fn dedup_existing_media(job: Arc<Job>) -> Result<RoomMessageEventContent> {
+	let (removed, reclaimed) = services().media.dedup_existing(&job).await?;
+
+	Ok(RoomMessageEventContent::text_plain(format!(
+		"Removed {removed} duplicate media files, reclaiming {reclaimed} bytes."
+	)))
+}
+
+pub(super) async fn tier_status(_body: Vec<&str>) -> Result<RoomMessageEventContent> {
+	if services().globals.config.media_cold_path.is_none() {
+		return Ok(RoomMessageEventContent::text_plain(
+			"Media storage tiering is disabled; set `media_cold_path` first.",
+		));
+	}
+
+	let status = services().media.tier_status().await?;
+	let progress = status.progress.unwrap_or_else(|| "not running".to_owned());
+
+	Ok(RoomMessageEventContent::text_plain(format!(
+		"Hot: {} files, {} bytes\nCold: {} files, {} bytes\nMigration: {progress}",
+		status.hot_files, status.hot_bytes, status.cold_files, status.cold_bytes
+	)))
+}
+
+/// Parses thumbnail dimensions given as `WxH`
+fn parse_dimensions(dimensions: &str) -> Result<(u32, u32)> {
+	dimensions
+		.split_once('x')
+		.and_then(|(width, height)| Some((width.parse().ok()?, height.parse().ok()?)))
+		.ok_or_else(|| Error::Err(format!("Invalid thumbnail size {dimensions:?}, expected WxH")))
+}
+
+/// Content type, size and SHA-256 hash of a media file
+fn describe_media(content_type: Option<&str>, file: &[u8]) -> String {
+	format!(
+		"content-type {}, {} bytes, sha256 {:x}",
+		content_type.unwrap_or("unknown"),
+		file.len(),
+		Sha256::digest(file)
+	)
+}
+
+/// Collects the MXC URIs in use as avatars by users or rooms known to us.
+fn avatar_mxcs() -> Result<HashSet<OwnedMxcUri>> {
+	let mut avatars = HashSet::new();
+	for user_id in services().users.iter().filter_map(Result::ok) {
+		if let Some(avatar_url) = services().users.avatar_url(&user_id)? {
+			avatars.insert(avatar_url);
+		}
+	}
+
+	for room_id in services().rooms.metadata.iter_ids().filter_map(Result::ok) {
+		if let JsOption::Some(avatar) = services().rooms.state_accessor.get_avatar(&room_id)? {
+			if let Some(url) = avatar.url {
+				avatars.insert(url);
+			}
+		}
+	}
+
+	Ok(avatars)
+}
@@ -36,6 +36,53 @@ pub(super) enum MediaCommand {
 		#[arg(short, long)]
 		force: bool,
 	},
+
+	/// - Deletes all remote media first stored on this server more than N days
+	///   ago, reporting the disk space reclaimed
+	///
+	/// Media in use as a user or room avatar is skipped unless --force is
+	/// given. The media is purged in the background; use `!admin jobs` to
+	/// follow the progress or cancel it.
+	PurgeRemote {
+		/// Purge remote media stored more than this many days ago
+		#[arg(long)]
+		before_days: u64,
+		/// Also purge media in use as a user or room avatar
+		#[arg(short, long)]
+		force: bool,
+	},
+
+	/// - Lists the media uploaded by a local user with sizes and upload times
+	ListUser {
+		user_id: String,
+	},
+
+	/// - Fetches remote media through the normal media-fetch path, reporting
+	///   each step, and stores it in the media cache
+	DownloadRemote {
+		/// The MXC URL of the remote media
+		mxc: Box<MxcUri>,
+
+		/// Re-download the media even if it is cached, replacing the cached
+		/// copy if the remote content changed
+		#[arg(short, long)]
+		force: bool,
+
+		/// Fetch a thumbnail of the given size instead, e.g. "96x96"
+		#[arg(long, value_name = "WxH")]
+		thumbnail: Option<String>,
+	},
+
+	/// - Deduplicates media stored before `media_deduplicate` was enabled
+	///
+	/// Files with the same content as another one are removed, their media
+	/// referring to the remaining copy. The media is deduplicated in the
+	/// background; use `!admin jobs` to follow the progress or cancel it.
+	DedupExisting,
+
+	/// - Shows the number and size of media files in the media directory and in
+	///   `media_cold_path`, and the progress of moving media to cold storage
+	TierStatus,
 }
 
 pub(super) async fn process(command: MediaCommand, body: Vec<&str>) -> Result<RoomMessageEventContent> {
@@ -49,5 +96,19 @@ pub(super) async fn process(command: MediaCommand, body: Vec<&str>) -> Result<Ro
 			duration,
 			force,
 		} => delete_past_remote_media(body, duration, force).await?,
+		MediaCommand::PurgeRemote {
+			before_days,
+			force,
+		} => purge_remote(body, before_days, force).await?,
+		MediaCommand::ListUser {
+			user_id,
+		} => list_user(body, user_id).await?,
+		MediaCommand::DownloadRemote {
+			mxc,
+			force,
+			thumbnail,
+		} => download_remote(body, mxc, force, thumbnail).await?,
+		MediaCommand::DedupExisting => dedup_existing(body).await?,
+		MediaCommand::TierStatus => tier_status(body).await?,
 	})
 }
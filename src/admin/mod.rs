@@ -5,8 +5,11 @@ pub(crate) mod check;
 pub(crate) mod debug;
 pub(crate) mod federation;
 pub(crate) mod handler;
+pub(crate) mod jobs;
 pub(crate) mod media;
 pub(crate) mod query;
+pub(crate) mod rate_limit;
+pub(crate) mod registration_tokens;
 pub(crate) mod room;
 pub(crate) mod server;
 pub(crate) mod user;
@@ -22,7 +25,7 @@ pub(crate) use service::{services, user_is_local};
 
 pub(crate) use crate::{
 	handler::Service,
-	utils::{escape_html, get_room_info},
+	utils::{escape_html, get_room_info, RoomKind},
 };
 
 mod_ctor! {}
@@ -16,8 +16,25 @@ pub(super) async fn appservice(subcommand: Appservice) -> Result<RoomMessageEven
 				.get_registration(appservice_id.as_ref());
 			let query_time = timer.elapsed();
 
+			let effective = services()
+				.appservice
+				.get_registration_info(appservice_id.as_ref())
+				.await
+				.map(|info| {
+					format!(
+						"\n\nEffective values:\n- rate_limited: {}\n- transaction_max_events: {}\n- \
+						 suppress_ephemeral_federation: {}\n- receive_ephemeral: {}\n- org.matrix.msc3202: {}",
+						info.is_rate_limited(),
+						info.transaction_max_events(),
+						info.options.suppress_ephemeral_federation,
+						info.options.receive_ephemeral,
+						info.options.msc3202,
+					)
+				})
+				.unwrap_or_default();
+
 			Ok(RoomMessageEventContent::notice_markdown(format!(
-				"Query completed in {query_time:?}:\n\n```rs\n{results:#?}\n```"
+				"Query completed in {query_time:?}:\n\n```rs\n{results:#?}\n```{effective}"
 			)))
 		},
 		Appservice::All => {
@@ -0,0 +1,41 @@
+use std::sync::Arc;
+
+use ruma::{events::room::message::RoomMessageEventContent, EventId, OwnedEventId};
+
+use crate::{services, Result};
+
+/// Number of event IDs shown from each end of the auth chain
+const SHOWN_EVENTS: usize = 5;
+
+/// Walks the auth chain of an event the way the `event_auth` federation route
+/// does, using and filling the auth chain cache. The chain is ordered by
+/// short event ID, which roughly follows the order we learned of the events.
+pub(super) async fn auth_chain(event_id: Box<EventId>) -> Result<RoomMessageEventContent> {
+	let Some(pdu) = services().rooms.timeline.get_pdu(&event_id)? else {
+		return Ok(RoomMessageEventContent::notice_plain("Event not found."));
+	};
+
+	let timer = tokio::time::Instant::now();
+	let chain: Vec<OwnedEventId> = services()
+		.rooms
+		.auth_chain
+		.event_ids_iter(&pdu.room_id, vec![Arc::from(&*event_id)])
+		.await?
+		.filter(|id| **id != *event_id)
+		.map(|id| (*id).to_owned())
+		.collect();
+	let query_time = timer.elapsed();
+
+	let first: Vec<_> = chain.iter().take(SHOWN_EVENTS).collect();
+	let last: Vec<_> = chain
+		.iter()
+		.skip(chain.len().saturating_sub(SHOWN_EVENTS).max(first.len()))
+		.collect();
+
+	Ok(RoomMessageEventContent::notice_markdown(format!(
+		"Query completed in {query_time:?}:\n\nAuth chain of {event_id} in {} has {} \
+		 events.\n\nFirst:\n```rs\n{first:#?}\n```\nLast:\n```rs\n{last:#?}\n```",
+		pdu.room_id,
+		chain.len(),
+	)))
+}
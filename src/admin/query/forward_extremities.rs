@@ -0,0 +1,21 @@
+use ruma::{events::room::message::RoomMessageEventContent, RoomId};
+
+use crate::{services, Result};
+
+/// Lists the forward extremities of a room, which new local events reference
+/// as their prev_events
+pub(super) async fn forward_extremities(room_id: Box<RoomId>) -> Result<RoomMessageEventContent> {
+	let timer = tokio::time::Instant::now();
+	let extremities = services().rooms.state.get_forward_extremities(&room_id)?;
+	let query_time = timer.elapsed();
+
+	let limit = services().globals.config.forward_extremities_soft_limit;
+	let mut extremities: Vec<_> = extremities.into_iter().collect();
+	extremities.sort_unstable();
+
+	Ok(RoomMessageEventContent::notice_markdown(format!(
+		"Query completed in {query_time:?}:\n\n{room_id} has {} forward extremities (soft limit \
+		 {limit}).\n\n```rs\n{extremities:#?}\n```",
+		extremities.len(),
+	)))
+}
@@ -1,3 +1,5 @@
+use std::time::Duration;
+
 use ruma::events::room::message::RoomMessageEventContent;
 
 use super::Globals;
@@ -35,13 +37,29 @@ pub(super) async fn globals(subcommand: Globals) -> Result<RoomMessageEventConte
 		},
 		Globals::LoadKeypair => {
 			let timer = tokio::time::Instant::now();
-			let results = services().globals.db.load_keypair();
+			let results = services()
+				.globals
+				.db
+				.load_keypair(services().globals.read_only());
 			let query_time = timer.elapsed();
 
 			Ok(RoomMessageEventContent::notice_markdown(format!(
 				"Query completed in {query_time:?}:\n\n```rs\n{results:#?}\n```"
 			)))
 		},
+		Globals::CounterRate => {
+			let timer = tokio::time::Instant::now();
+			let last_minute = services().globals.counter_rate(Duration::from_secs(60))?;
+			let last_hour = services()
+				.globals
+				.counter_rate(Duration::from_secs(60 * 60))?;
+			let query_time = timer.elapsed();
+
+			Ok(RoomMessageEventContent::notice_markdown(format!(
+				"Query completed in {query_time:?}:\n\n```rs\nlast minute: {} over {:?}\nlast hour: {} over {:?}\n```",
+				last_minute.0, last_minute.1, last_hour.0, last_hour.1
+			)))
+		},
 		Globals::SigningKeysFor {
 			origin,
 		} => {
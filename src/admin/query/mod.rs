@@ -1,8 +1,11 @@
 mod account_data;
 mod appservice;
+mod auth_chain;
+mod forward_extremities;
 mod globals;
 mod presence;
 mod room_alias;
+mod room_spaces;
 mod room_state_cache;
 mod sending;
 mod users;
@@ -12,12 +15,13 @@ use conduit::Result;
 use room_state_cache::room_state_cache;
 use ruma::{
 	events::{room::message::RoomMessageEventContent, RoomAccountDataEventType},
-	RoomAliasId, RoomId, ServerName, UserId,
+	EventId, RoomAliasId, RoomId, ServerName, UserId,
 };
 
 use self::{
-	account_data::account_data, appservice::appservice, globals::globals, presence::presence, room_alias::room_alias,
-	sending::sending, users::users,
+	account_data::account_data, appservice::appservice, auth_chain::auth_chain,
+	forward_extremities::forward_extremities, globals::globals, presence::presence, room_alias::room_alias,
+	room_spaces::room_spaces, sending::sending, users::users,
 };
 
 #[cfg_attr(test, derive(Debug))]
@@ -32,6 +36,18 @@ pub(super) enum QueryCommand {
 	#[command(subcommand)]
 	Appservice(Appservice),
 
+	/// - Size and bounds of an event's auth chain, as served over federation
+	AuthChain {
+		/// An event ID (the $ character followed by the base64 reference hash)
+		event_id: Box<EventId>,
+	},
+
+	/// - Number and IDs of a room's forward extremities
+	ForwardExtremities {
+		/// Full room ID
+		room_id: Box<RoomId>,
+	},
+
 	/// - presence.rs iterators and getters
 	#[command(subcommand)]
 	Presence(Presence),
@@ -44,6 +60,10 @@ pub(super) enum QueryCommand {
 	#[command(subcommand)]
 	RoomStateCache(RoomStateCache),
 
+	/// - rooms/spaces space hierarchy cache
+	#[command(subcommand)]
+	RoomSpaces(RoomSpaces),
+
 	/// - globals.rs iterators and getters
 	#[command(subcommand)]
 	Globals(Globals),
@@ -112,6 +132,10 @@ pub(super) enum Presence {
 		/// UNIX timestamp since (u64)
 		since: u64,
 	},
+
+	/// - Shows the effective outgoing presence policy and the last presence
+	///   batch sent to each server.
+	Outbound,
 }
 
 #[cfg_attr(test, derive(Debug))]
@@ -131,6 +155,29 @@ pub(super) enum RoomAlias {
 
 	/// - Iterator of all our local aliases in our database with their room IDs
 	AllLocalAliases,
+
+	/// - Cached results of resolving room aliases of other servers
+	RemoteCache,
+
+	/// - Forgets the cached result of the alias, or of every alias
+	FlushRemoteCache {
+		/// Full room alias
+		alias: Option<Box<RoomAliasId>>,
+	},
+}
+
+#[cfg_attr(test, derive(Debug))]
+#[derive(Subcommand)]
+/// The space hierarchy cache in src/service/rooms/spaces/mod.rs
+pub(super) enum RoomSpaces {
+	/// - Lists the rooms in the space hierarchy cache
+	Cache,
+
+	/// - Clears the space hierarchy cache
+	ClearCache {
+		/// Only clear the cached summary of this room ID
+		room_id: Option<Box<RoomId>>,
+	},
 }
 
 #[cfg_attr(test, derive(Debug))]
@@ -217,6 +264,9 @@ pub(super) enum Globals {
 
 	LoadKeypair,
 
+	/// - How far the global counter advanced in the last minute and hour
+	CounterRate,
+
 	/// - This returns an empty `Ok(BTreeMap<..>)` when there are no keys found
 	///   for the server.
 	SigningKeysFor {
@@ -278,6 +328,10 @@ pub(super) enum Sending {
 	GetLatestEduCount {
 		server_name: Box<ServerName>,
 	},
+
+	/// - Counts the pushes not yet acknowledged by the push gateways per user,
+	///   with a histogram of their age
+	PendingPushes,
 }
 
 #[cfg_attr(test, derive(Debug))]
@@ -285,6 +339,14 @@ pub(super) enum Sending {
 /// All the getters and iterators from src/database/key_value/users.rs
 pub(super) enum Users {
 	Iter,
+
+	/// - In-flight device verifications relayed to local devices, and the ones
+	///   stuck after timing out with a remote counterpart
+	Verifications,
+
+	/// - Counts of the `m.room_key.withheld` to-device messages relayed to
+	///   local devices since startup, by reason code
+	WithheldKeys,
 }
 
 /// Processes admin query commands
@@ -292,9 +354,16 @@ pub(super) async fn process(command: QueryCommand, _body: Vec<&str>) -> Result<R
 	Ok(match command {
 		QueryCommand::AccountData(command) => account_data(command).await?,
 		QueryCommand::Appservice(command) => appservice(command).await?,
+		QueryCommand::AuthChain {
+			event_id,
+		} => auth_chain(event_id).await?,
+		QueryCommand::ForwardExtremities {
+			room_id,
+		} => forward_extremities(room_id).await?,
 		QueryCommand::Presence(command) => presence(command).await?,
 		QueryCommand::RoomAlias(command) => room_alias(command).await?,
 		QueryCommand::RoomStateCache(command) => room_state_cache(command).await?,
+		QueryCommand::RoomSpaces(command) => room_spaces(command).await?,
 		QueryCommand::Globals(command) => globals(command).await?,
 		QueryCommand::Sending(command) => sending(command).await?,
 		QueryCommand::Users(command) => users(command).await?,
@@ -1,3 +1,5 @@
+use std::fmt::Write;
+
 use ruma::events::room::message::RoomMessageEventContent;
 
 use super::Presence;
@@ -30,5 +32,52 @@ pub(super) async fn presence(subcommand: Presence) -> Result<RoomMessageEventCon
 				"Query completed in {query_time:?}:\n\n```rs\n{presence_since:#?}\n```"
 			)))
 		},
+		Presence::Outbound => {
+			let config = &services().globals.config;
+			let mut msg = format!(
+				"Outgoing presence: {}\nBatch window: {}ms\n",
+				if config.allow_outgoing_presence && config.allow_local_presence {
+					"enabled"
+				} else {
+					"disabled"
+				},
+				config.presence_federation_batch_window_ms,
+			);
+
+			if config.presence_federation_allowlist.is_empty() {
+				msg.push_str("Servers: all sharing a room\n");
+			} else {
+				let allowlist: Vec<_> = config
+					.presence_federation_allowlist
+					.iter()
+					.map(ToString::to_string)
+					.collect();
+				writeln!(msg, "Servers: only {}", allowlist.join(", "))
+					.expect("should be able to write to string buffer");
+			}
+
+			match config.presence_federation_max_room_members {
+				Some(max) => writeln!(msg, "Rooms: up to {max} joined members"),
+				None => writeln!(msg, "Rooms: any size"),
+			}
+			.expect("should be able to write to string buffer");
+
+			let batches = services().sending.presence.all();
+			if batches.is_empty() {
+				msg.push_str("\nNo presence batches sent since startup.");
+			} else {
+				msg.push_str("\n| Server | Last batch | Last sent | Total users |\n| --- | --- | --- | --- |\n");
+				for (server, batch) in batches {
+					let ago = batch
+						.last_sent
+						.and_then(|sent| sent.elapsed().ok())
+						.map_or_else(|| "never".to_owned(), |ago| format!("{}s ago", ago.as_secs()));
+					writeln!(msg, "| {server} | {} | {ago} | {} |", batch.last_size, batch.total)
+						.expect("should be able to write to string buffer");
+				}
+			}
+
+			Ok(RoomMessageEventContent::notice_markdown(msg))
+		},
 	}
 }
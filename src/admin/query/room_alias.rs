@@ -1,4 +1,7 @@
+use std::{fmt::Write, time::Duration};
+
 use ruma::events::room::message::RoomMessageEventContent;
+use service::rooms::alias::cache::Resolution;
 
 use super::RoomAlias;
 use crate::{services, Result};
@@ -41,5 +44,49 @@ pub(super) async fn room_alias(subcommand: RoomAlias) -> Result<RoomMessageEvent
 				"Query completed in {query_time:?}:\n\n```rs\n{aliases:#?}\n```"
 			)))
 		},
+		RoomAlias::RemoteCache => {
+			let cached = services().rooms.alias.remote_cache.all();
+			if cached.is_empty() {
+				return Ok(RoomMessageEventContent::notice_plain("No room alias results are cached."));
+			}
+
+			let mut msg = "| Alias | Result | Servers | Expires in |\n| --- | --- | --- | --- |\n".to_owned();
+			for (alias, cached) in cached {
+				let expires_in = Duration::from_secs(
+					cached
+						.ttl
+						.saturating_sub(cached.cached_at.elapsed())
+						.as_secs(),
+				);
+				let (result, servers) = match &cached.result {
+					Resolution::Resolved {
+						room_id,
+						servers,
+					} => (room_id.to_string(), servers),
+					Resolution::Failed {
+						error,
+						servers,
+					} => (format!("failed: {error}"), servers),
+				};
+				let servers: Vec<_> = servers.iter().map(ToString::to_string).collect();
+
+				writeln!(msg, "| {alias} | {result} | {} | {expires_in:?} |", servers.join(", "))
+					.expect("should be able to write to string buffer");
+			}
+
+			Ok(RoomMessageEventContent::notice_markdown(msg))
+		},
+		RoomAlias::FlushRemoteCache {
+			alias,
+		} => {
+			let cache = &services().rooms.alias.remote_cache;
+			let msg = match alias {
+				Some(alias) if cache.remove(&alias) => format!("Forgot the cached result of {alias}."),
+				Some(alias) => format!("No result of {alias} is cached."),
+				None => format!("Forgot {} cached room alias results.", cache.clear()),
+			};
+
+			Ok(RoomMessageEventContent::notice_plain(msg))
+		},
 	}
 }
@@ -0,0 +1,32 @@
+use ruma::events::room::message::RoomMessageEventContent;
+
+use super::RoomSpaces;
+use crate::{services, Result};
+
+/// The space hierarchy cache in src/service/rooms/spaces/mod.rs
+pub(super) async fn room_spaces(subcommand: RoomSpaces) -> Result<RoomMessageEventContent> {
+	match subcommand {
+		RoomSpaces::Cache => {
+			let timer = tokio::time::Instant::now();
+			let results = services().rooms.spaces.cached_rooms().await;
+			let query_time = timer.elapsed();
+
+			Ok(RoomMessageEventContent::notice_markdown(format!(
+				"Query completed in {query_time:?}:\n\n```rs\n{results:#?}\n```"
+			)))
+		},
+		RoomSpaces::ClearCache {
+			room_id,
+		} => {
+			let removed = services()
+				.rooms
+				.spaces
+				.clear_cache(room_id.as_deref())
+				.await;
+
+			Ok(RoomMessageEventContent::text_plain(format!(
+				"Removed {removed} entries from the space hierarchy cache."
+			)))
+		},
+	}
+}
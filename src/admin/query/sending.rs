@@ -1,7 +1,21 @@
-use ruma::events::room::message::RoomMessageEventContent;
+use std::{collections::BTreeMap, fmt::Write};
+
+use conduit::utils;
+use ruma::{events::room::message::RoomMessageEventContent, OwnedUserId};
 
 use super::Sending;
-use crate::{service::sending::Destination, services, Result};
+use crate::{
+	service::sending::{Destination, SendingEvent},
+	services, Result,
+};
+
+/// Upper bounds of the age buckets of pending pushes, in milliseconds
+const PUSH_AGE_BUCKETS: [(&str, u64); 4] = [
+	("1m", 60 * 1000),
+	("10m", 10 * 60 * 1000),
+	("1h", 60 * 60 * 1000),
+	("1d", 24 * 60 * 60 * 1000),
+];
 
 /// All the getters and iterators in key_value/sending.rs
 pub(super) async fn sending(subcommand: Sending) -> Result<RoomMessageEventContent> {
@@ -173,6 +187,56 @@ pub(super) async fn sending(subcommand: Sending) -> Result<RoomMessageEventConte
 				"Query completed in {query_time:?}:\n\n```rs\n{active_requests:#?}\n```"
 			)))
 		},
+		Sending::PendingPushes => {
+			let timer = tokio::time::Instant::now();
+			let now = utils::millis_since_unix_epoch();
+			let mut users = BTreeMap::<OwnedUserId, [usize; PUSH_AGE_BUCKETS.len() + 1]>::new();
+			for (_, dest, event) in services().sending.db.push_requests().filter_map(Result::ok) {
+				let (Destination::Push(user_id, _), SendingEvent::Pdu(pdu_id)) = (dest, event) else {
+					continue;
+				};
+
+				let age = services()
+					.rooms
+					.timeline
+					.get_pdu_from_id(&pdu_id)?
+					.map_or(0, |pdu| now.saturating_sub(pdu.origin_server_ts.into()));
+
+				let bucket = PUSH_AGE_BUCKETS
+					.iter()
+					.position(|(_, max)| age < *max)
+					.unwrap_or(PUSH_AGE_BUCKETS.len());
+
+				let counts = users.entry(user_id).or_default();
+				counts[bucket] = counts[bucket].saturating_add(1);
+			}
+			let query_time = timer.elapsed();
+
+			if users.is_empty() {
+				return Ok(RoomMessageEventContent::notice_markdown(format!(
+					"Query completed in {query_time:?}:\n\nNo pending pushes."
+				)));
+			}
+
+			let mut msg = format!("Query completed in {query_time:?}:\n\n| User | Pending |");
+			for (label, _) in PUSH_AGE_BUCKETS {
+				write!(msg, " < {label} |").expect("should be able to write to string buffer");
+			}
+			msg.push_str(" older |\n| --- | --- |");
+			msg.push_str(&" --- |".repeat(PUSH_AGE_BUCKETS.len().saturating_add(1)));
+			msg.push('\n');
+
+			for (user_id, counts) in users {
+				let total: usize = counts.iter().sum();
+				write!(msg, "| {user_id} | {total} |").expect("should be able to write to string buffer");
+				for count in counts {
+					write!(msg, " {count} |").expect("should be able to write to string buffer");
+				}
+				msg.push('\n');
+			}
+
+			Ok(RoomMessageEventContent::notice_markdown(msg))
+		},
 		Sending::GetLatestEduCount {
 			server_name,
 		} => {
@@ -1,3 +1,5 @@
+use std::fmt::Write;
+
 use ruma::events::room::message::RoomMessageEventContent;
 
 use super::Users;
@@ -17,5 +19,51 @@ pub(super) async fn users(subcommand: Users) -> Result<RoomMessageEventContent>
 				"Query completed in {query_time:?}:\n\n```rs\n{users:#?}\n```"
 			)))
 		},
+		Users::Verifications => {
+			let stats = services().users.verifications.stats();
+
+			let mut msg = format!(
+				"Active verifications: {}\nStuck verifications: {}\n\nSince startup: {} completed, {} cancelled, {} \
+				 timed out and cancelled by the server",
+				stats.active,
+				stats.stuck.len(),
+				stats.completed,
+				stats.cancelled,
+				stats.timed_out,
+			);
+
+			if !stats.stuck.is_empty() {
+				msg.push_str("\n\nStuck:\n");
+				for (transaction_id, verification) in &stats.stuck {
+					writeln!(
+						msg,
+						"- `{transaction_id}`: {} ({}) to {}, started {:?} ago",
+						verification.requester,
+						verification.requester_device,
+						verification.recipient,
+						verification.started.elapsed(),
+					)
+					.expect("should be able to write to string buffer");
+				}
+			}
+
+			Ok(RoomMessageEventContent::notice_markdown(msg))
+		},
+		Users::WithheldKeys => {
+			let counts = services().users.to_device.withheld_counts();
+			if counts.is_empty() {
+				return Ok(RoomMessageEventContent::notice_plain(
+					"No withheld room key notices were relayed since startup.",
+				));
+			}
+
+			let mut msg =
+				"Withheld room key notices relayed since startup:\n\n| Code | Relayed |\n| --- | --- |\n".to_owned();
+			for (code, count) in &counts {
+				writeln!(msg, "| {code} | {count} |").expect("should be able to write to string buffer");
+			}
+
+			Ok(RoomMessageEventContent::notice_markdown(msg))
+		},
 	}
 }
@@ -0,0 +1,130 @@
+use std::fmt::Write as _;
+
+use conduit::{config::RateLimitBucket, Error, Result};
+use ruma::{events::room::message::RoomMessageEventContent, OwnedRoomId};
+use service::rate_limit::Class;
+
+use crate::{services, utils::parse_local_user_id};
+
+pub(super) async fn show(_body: Vec<&str>, user_id: String) -> Result<RoomMessageEventContent> {
+	let user_id = parse_local_user_id(&user_id)?;
+	let rate_limit = &services().rate_limit;
+
+	let mut msg = String::new();
+	if !services().globals.rate_limit().enabled {
+		msg.push_str("Rate limiting is disabled in the config.\n\n");
+	}
+
+	if services().users.is_admin(&user_id)? {
+		msg.push_str("This user is a server admin and is not rate limited.\n\n");
+	}
+
+	msg.push_str("| Class | Burst | Per second | Source | Tokens left |\n| --- | --- | --- | --- | --- |\n");
+	for class in Class::ALL {
+		let limit = rate_limit.limit(&user_id, class)?;
+		let source = if rate_limit.get_override(&user_id, class)?.is_some() {
+			"override"
+		} else {
+			"config"
+		};
+
+		let tokens = rate_limit
+			.tokens(&user_id, class)?
+			.map_or_else(|| "full".to_owned(), |tokens| format!("{tokens:.2}"));
+
+		writeln!(
+			msg,
+			"| {class} | {} | {} | {source} | {tokens} |",
+			limit.burst, limit.per_second
+		)
+		.expect("should be able to write to string buffer");
+	}
+
+	Ok(RoomMessageEventContent::notice_markdown(msg))
+}
+
+pub(super) async fn override_limit(
+	_body: Vec<&str>, user_id: String, class: Class, burst: u32, per_second: f64,
+) -> Result<RoomMessageEventContent> {
+	let user_id = parse_local_user_id(&user_id)?;
+	check_bucket(burst, per_second)?;
+
+	services().rate_limit.set_override(
+		&user_id,
+		class,
+		Some(RateLimitBucket {
+			burst,
+			per_second,
+		}),
+	)?;
+
+	Ok(RoomMessageEventContent::notice_markdown(format!(
+		"{user_id} may now make {burst} {class} requests at once, refilled by {per_second} per second."
+	)))
+}
+
+pub(super) async fn reset(_body: Vec<&str>, user_id: String, class: Class) -> Result<RoomMessageEventContent> {
+	let user_id = parse_local_user_id(&user_id)?;
+	if services()
+		.rate_limit
+		.get_override(&user_id, class)?
+		.is_none()
+	{
+		return Ok(RoomMessageEventContent::notice_plain(format!(
+			"{user_id} has no {class} override."
+		)));
+	}
+
+	services().rate_limit.set_override(&user_id, class, None)?;
+
+	Ok(RoomMessageEventContent::notice_markdown(format!(
+		"Removed the {class} override of {user_id}, the configured default applies again."
+	)))
+}
+
+pub(super) async fn room_override(
+	_body: Vec<&str>, room_id: OwnedRoomId, burst: u32, per_second: f64,
+) -> Result<RoomMessageEventContent> {
+	check_bucket(burst, per_second)?;
+
+	services().rate_limit.rooms.set_override(
+		&room_id,
+		Some(RateLimitBucket {
+			burst,
+			per_second,
+		}),
+	)?;
+
+	Ok(RoomMessageEventContent::notice_markdown(format!(
+		"Local users may now send {burst} events at once to {room_id}, refilled by {per_second} per second."
+	)))
+}
+
+pub(super) async fn room_reset(_body: Vec<&str>, room_id: OwnedRoomId) -> Result<RoomMessageEventContent> {
+	if services()
+		.rate_limit
+		.rooms
+		.get_override(&room_id)?
+		.is_none()
+	{
+		return Ok(RoomMessageEventContent::notice_plain(format!(
+			"{room_id} has no send override."
+		)));
+	}
+
+	services().rate_limit.rooms.set_override(&room_id, None)?;
+
+	Ok(RoomMessageEventContent::notice_markdown(format!(
+		"Removed the send override of {room_id}, the configured default applies again."
+	)))
+}
+
+fn check_bucket(burst: u32, per_second: f64) -> Result<()> {
+	if burst == 0 || !per_second.is_finite() || per_second <= 0.0 {
+		return Err(Error::Err(
+			"The burst and the rate per second must be greater than zero.".to_owned(),
+		));
+	}
+
+	Ok(())
+}
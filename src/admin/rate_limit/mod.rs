@@ -0,0 +1,86 @@
+mod commands;
+
+use clap::Subcommand;
+use conduit::Result;
+use ruma::{events::room::message::RoomMessageEventContent, OwnedRoomId};
+use service::rate_limit::Class;
+
+use self::commands::*;
+
+#[cfg_attr(test, derive(Debug))]
+#[derive(Subcommand)]
+pub(super) enum RateLimitCommand {
+	/// - Show the rate limits applying to a local user and their current
+	///   buckets
+	Show {
+		user_id: String,
+	},
+
+	/// - Override a user's bucket for a class of endpoints
+	///
+	/// Classes are login, registration, message, join and media. The override
+	/// is kept until reset and replaces the configured default.
+	Override {
+		user_id: String,
+
+		class: Class,
+
+		/// Number of requests allowed at once
+		burst: u32,
+
+		/// Number of requests the bucket is refilled by every second
+		per_second: f64,
+	},
+
+	/// - Remove a user's override, returning to the configured default
+	Reset {
+		user_id: String,
+
+		class: Class,
+	},
+
+	/// - Override the bucket of local users sending timeline events to a room
+	///
+	/// Users at or above `room_send_exempt_power_level` in the room stay
+	/// exempt. The override is kept until reset.
+	RoomOverride {
+		room_id: OwnedRoomId,
+
+		/// Number of events allowed at once
+		burst: u32,
+
+		/// Number of events the bucket is refilled by every second
+		per_second: f64,
+	},
+
+	/// - Remove a room's override, returning to the configured default
+	RoomReset {
+		room_id: OwnedRoomId,
+	},
+}
+
+pub(super) async fn process(command: RateLimitCommand, body: Vec<&str>) -> Result<RoomMessageEventContent> {
+	Ok(match command {
+		RateLimitCommand::Show {
+			user_id,
+		} => show(body, user_id).await?,
+		RateLimitCommand::Override {
+			user_id,
+			class,
+			burst,
+			per_second,
+		} => override_limit(body, user_id, class, burst, per_second).await?,
+		RateLimitCommand::Reset {
+			user_id,
+			class,
+		} => reset(body, user_id, class).await?,
+		RateLimitCommand::RoomOverride {
+			room_id,
+			burst,
+			per_second,
+		} => room_override(body, room_id, burst, per_second).await?,
+		RateLimitCommand::RoomReset {
+			room_id,
+		} => room_reset(body, room_id).await?,
+	})
+}
@@ -0,0 +1,73 @@
+use std::{fmt::Write as _, time::Duration};
+
+use conduit::{utils, Result};
+use ruma::events::room::message::RoomMessageEventContent;
+
+use crate::services;
+
+pub(super) async fn create(
+	_body: Vec<&str>, token: Option<String>, uses_allowed: Option<u64>, expires_in: Option<Duration>,
+) -> Result<RoomMessageEventContent> {
+	let token = services()
+		.uiaa
+		.registration_tokens
+		.create(token, uses_allowed, expires_in)?;
+
+	let uses = uses_allowed.map_or_else(|| "unlimited uses".to_owned(), |uses| format!("{uses} use(s)"));
+	let expiry = expires_in.map_or_else(|| "does not expire".to_owned(), |expires_in| format!("expires in {expires_in:?}"));
+
+	Ok(RoomMessageEventContent::notice_markdown(format!(
+		"Created registration token `{token}` ({uses}, {expiry})."
+	)))
+}
+
+pub(super) async fn list(_body: Vec<&str>) -> Result<RoomMessageEventContent> {
+	let tokens = services().uiaa.registration_tokens.list()?;
+	let config_token = services().globals.registration_token().is_some();
+	if tokens.is_empty() && !config_token {
+		return Ok(RoomMessageEventContent::notice_plain(
+			"There are no registration tokens; registration does not require one.",
+		));
+	}
+
+	let mut msg = String::new();
+	if config_token {
+		msg.push_str("The `registration_token` config option is set and valid with unlimited uses.\n\n");
+	}
+
+	if !tokens.is_empty() {
+		msg.push_str("| Token | Uses | Expires | Valid | Accounts |\n| --- | --- | --- | --- | --- |\n");
+	}
+
+	let now = utils::millis_since_unix_epoch();
+	for (token, info) in tokens {
+		let uses = match info.uses_allowed {
+			Some(allowed) => format!("{}/{allowed}", info.completed),
+			None => info.completed.to_string(),
+		};
+
+		let expires = match info.expiry_time {
+			Some(expiry) if expiry > now => format!("in {:?}", Duration::from_millis(expiry.saturating_sub(now))),
+			Some(_) => "expired".to_owned(),
+			None => "never".to_owned(),
+		};
+
+		let accounts = services().uiaa.registration_tokens.users_of(&token).join(", ");
+		writeln!(msg, "| `{token}` | {uses} | {expires} | {} | {accounts} |", info.is_valid())
+			.expect("should be able to write to string buffer");
+	}
+
+	Ok(RoomMessageEventContent::notice_markdown(msg))
+}
+
+pub(super) async fn revoke(_body: Vec<&str>, token: String) -> Result<RoomMessageEventContent> {
+	if !services().uiaa.registration_tokens.revoke(&token)? {
+		return Ok(RoomMessageEventContent::notice_plain(
+			"There is no such registration token. The `registration_token` config option cannot be revoked here.",
+		));
+	}
+
+	Ok(RoomMessageEventContent::notice_markdown(format!(
+		"Revoked registration token `{token}`."
+	)))
+}
@@ -0,0 +1,57 @@
+mod commands;
+
+use std::time::Duration;
+
+use clap::Subcommand;
+use conduit::Result;
+use ruma::events::room::message::RoomMessageEventContent;
+
+use self::commands::*;
+use crate::utils::parse_duration;
+
+#[cfg_attr(test, derive(Debug))]
+#[derive(Subcommand)]
+pub(super) enum RegistrationTokensCommand {
+	/// - Create a registration token
+	///
+	/// Once any token exists, registering requires a valid token. The
+	/// `registration_token` config option stays valid with unlimited uses.
+	Create {
+		/// The token, if unspecified one is generated
+		token: Option<String>,
+
+		/// Number of registrations the token can be used for, unlimited if
+		/// unspecified
+		#[arg(long)]
+		uses_allowed: Option<u64>,
+
+		/// How long the token is valid for, e.g. "7d" or "12h"; forever if
+		/// unspecified
+		#[arg(long, value_parser = parse_duration)]
+		expires_in: Option<Duration>,
+	},
+
+	/// - List the registration tokens with their uses and expiry
+	List,
+
+	/// - Revoke a registration token
+	///
+	/// Accounts which registered with it keep recording it.
+	Revoke {
+		token: String,
+	},
+}
+
+pub(super) async fn process(command: RegistrationTokensCommand, body: Vec<&str>) -> Result<RoomMessageEventContent> {
+	Ok(match command {
+		RegistrationTokensCommand::Create {
+			token,
+			uses_allowed,
+			expires_in,
+		} => create(body, token, uses_allowed, expires_in).await?,
+		RegistrationTokensCommand::List => list(body).await?,
+		RegistrationTokensCommand::Revoke {
+			token,
+		} => revoke(body, token).await?,
+	})
+}
@@ -4,11 +4,14 @@ mod room_directory_commands;
 mod room_info_commands;
 mod room_moderation_commands;
 
-use clap::Subcommand;
+use std::time::Duration;
+
+use clap::{Subcommand, ValueEnum};
 use conduit::Result;
-use ruma::{events::room::message::RoomMessageEventContent, RoomId, RoomOrAliasId};
+use ruma::{events::room::message::RoomMessageEventContent, RoomId, RoomOrAliasId, UserId};
 
-use self::room_commands::list;
+use self::room_commands::{bulk_invite, export, incomplete, list, list_members, power_levels, purge, set_power};
+use crate::{utils::parse_duration, RoomKind};
 
 #[cfg_attr(test, derive(Debug))]
 #[derive(Subcommand)]
@@ -16,6 +19,32 @@ pub(super) enum RoomCommand {
 	/// - List all rooms the server knows about
 	List {
 		page: Option<usize>,
+
+		/// Which rooms to list: rooms for messages, spaces, or both
+		#[arg(long = "type", value_enum, default_value_t)]
+		room_type: RoomKind,
+	},
+
+	/// - List the members of a room grouped by homeserver
+	///
+	/// Servers are ordered by their number of members. Each member is listed
+	/// with their displayname and the time of their current member event.
+	ListMembers {
+		room_id: Box<RoomId>,
+
+		page: Option<usize>,
+
+		/// Which members to list
+		#[arg(long, value_enum, default_value_t)]
+		membership: MemberFilter,
+
+		/// How to order the members of each server
+		#[arg(long, value_enum, default_value_t)]
+		sort: MemberSort,
+
+		/// Only list members of this server
+		#[arg(long)]
+		local_only: bool,
 	},
 
 	#[command(subcommand)]
@@ -33,6 +62,113 @@ pub(super) enum RoomCommand {
 	#[command(subcommand)]
 	/// - Manage the room directory
 	Directory(RoomDirectoryCommand),
+
+	/// - Export all events of a room to a JSON lines file
+	///
+	/// Each line holds one event in its canonical federation form, including
+	/// signatures and hashes. Redacted events are exported redacted. The file
+	/// is written to the configured `exports_path` in the background; use
+	/// `!admin jobs` to follow the progress or cancel it.
+	Export {
+		/// The room ID of the room to export
+		room_id: Box<RoomId>,
+
+		/// Name of the file to write in the exports directory, defaults to one
+		/// derived from the room ID and the current time
+		#[arg(short, long)]
+		output: Option<String>,
+
+		/// Only export events sent at or after this timestamp (milliseconds
+		/// since the unix epoch)
+		#[arg(long)]
+		since_ts: Option<u64>,
+
+		/// Only export events sent at or before this timestamp (milliseconds
+		/// since the unix epoch)
+		#[arg(long)]
+		until_ts: Option<u64>,
+
+		/// Overwrite the output file if it already exists
+		#[arg(short, long)]
+		force: bool,
+	},
+
+	/// - List rooms whose member list is known to be incomplete
+	///
+	/// This happens when a remote join fails after the join event was accepted
+	/// but before the room state was applied. Member listings for these rooms
+	/// are refused until their state has been resynced.
+	Incomplete {
+		/// Refetch the state of every incomplete room from a server residing in
+		/// it, in the background
+		#[arg(long)]
+		resync: bool,
+	},
+
+	/// - Invites a list of users to a room from a newline delimited codeblock
+	///   of user IDs, local or remote
+	///
+	/// The invites are sent in the background, waiting --delay between two of
+	/// them to stay clear of remote spam protections; use `!admin jobs` to
+	/// follow the progress or cancel it. Invites to servers which could not be
+	/// reached are retried once at the end. The final report lists the users
+	/// invited, those already joined or invited, and failures with reasons.
+	BulkInvite {
+		room_id: Box<RoomId>,
+
+		/// Local user to send the invites as, defaults to the server user. They
+		/// have to be joined to the room with the power level to invite.
+		#[arg(long = "as")]
+		sender: Option<String>,
+
+		/// Time to wait between two invites, e.g. "500ms"
+		#[arg(long, value_parser = parse_duration, default_value = "1s")]
+		delay: Duration,
+	},
+
+	/// - Shows the content of the room's current m.room.power_levels event
+	PowerLevels {
+		room_id: Box<RoomId>,
+	},
+
+	/// - Sets the power level of a user in a room
+	///
+	/// The updated m.room.power_levels event is sent by the server user, who
+	/// has to be joined to the room with enough power to make the change.
+	/// Lowering the server user below the level needed to change the power
+	/// levels again must be confirmed with --yes-i-know.
+	SetPower {
+		room_id: Box<RoomId>,
+
+		user_id: Box<UserId>,
+
+		level: i64,
+
+		/// Join the server user to the room first if it is not joined. This
+		/// only succeeds if the room's join rules let it join.
+		#[arg(long)]
+		force_join_server_user: bool,
+
+		/// Confirms that the server user may lose the power to change the
+		/// power levels of the room
+		#[arg(long)]
+		yes_i_know: bool,
+	},
+
+	/// - Deletes a banned room from the database
+	///
+	/// Removes the room's events including backfilled ones, its state, aliases,
+	/// search index, threads, receipts and lazy-loading records, reporting the
+	/// number of keys removed from each table. Only rooms which are banned and
+	/// have no local users joined can be purged; the room stays banned
+	/// afterwards. This cannot be undone.
+	Purge {
+		room_id: Box<RoomId>,
+
+		/// Confirms that the room is to be irrecoverably deleted
+		#[arg(long)]
+		yes_i_really_mean_it: bool,
+	},
 }
 
 #[cfg_attr(test, derive(Debug))]
@@ -74,14 +210,15 @@ pub(super) enum RoomAliasCommand {
 		room_alias_localpart: String,
 	},
 
-	/// - Show which room is using an alias
+	/// - Show which room is using an alias, who created it, and whether the
+	///   room's canonical alias event lists it
 	Which {
 		/// The alias localpart to look up (`alias`, not
 		/// `#alias:servername.tld`)
 		room_alias_localpart: String,
 	},
 
-	/// - List aliases currently being used
+	/// - List aliases currently being used, along with who created them
 	List {
 		/// If set, only list the aliases for this room
 		room_id: Option<Box<RoomId>>,
@@ -106,6 +243,10 @@ pub(super) enum RoomDirectoryCommand {
 	/// - List rooms that are published
 	List {
 		page: Option<usize>,
+
+		/// Which rooms to list: rooms for messages, spaces, or both
+		#[arg(long = "type", value_enum, default_value_t)]
+		room_type: RoomKind,
 	},
 }
 
@@ -138,6 +279,9 @@ pub(super) enum RoomModerationCommand {
 
 	/// - Bans a list of rooms (room IDs and room aliases) from a newline
 	///   delimited codeblock similar to `user deactivate-all`
+	///
+	/// The rooms are banned in the background; use `!admin jobs` to follow
+	/// the progress or cancel it.
 	BanListOfRooms {
 		#[arg(short, long)]
 		/// Evicts admins out of the room and ignores any potential errors when
@@ -167,6 +311,24 @@ pub(super) enum RoomModerationCommand {
 	ListBannedRooms,
 }
 
+/// Members listed by `rooms list-members`, by their current membership
+#[derive(Clone, Copy, Debug, Default, ValueEnum)]
+pub(super) enum MemberFilter {
+	#[default]
+	Join,
+	Invite,
+	Ban,
+	Leave,
+}
+
+#[derive(Clone, Copy, Debug, Default, ValueEnum)]
+pub(super) enum MemberSort {
+	/// Most recent member event first
+	#[default]
+	Joined,
+	Name,
+}
+
 pub(super) async fn process(command: RoomCommand, body: Vec<&str>) -> Result<RoomMessageEventContent> {
 	Ok(match command {
 		RoomCommand::Info(command) => room_info_commands::process(command, body).await?,
@@ -179,6 +341,50 @@ pub(super) async fn process(command: RoomCommand, body: Vec<&str>) -> Result<Roo
 
 		RoomCommand::List {
 			page,
-		} => list(body, page).await?,
+			room_type,
+		} => list(body, page, room_type).await?,
+
+		RoomCommand::ListMembers {
+			room_id,
+			page,
+			membership,
+			sort,
+			local_only,
+		} => list_members(body, room_id, page, membership, sort, local_only).await?,
+
+		RoomCommand::Export {
+			room_id,
+			output,
+			since_ts,
+			until_ts,
+			force,
+		} => export(body, room_id, output, since_ts, until_ts, force).await?,
+
+		RoomCommand::Incomplete {
+			resync,
+		} => incomplete(body, resync).await?,
+
+		RoomCommand::BulkInvite {
+			room_id,
+			sender,
+			delay,
+		} => bulk_invite(body, room_id, sender, delay).await?,
+
+		RoomCommand::PowerLevels {
+			room_id,
+		} => power_levels(body, room_id).await?,
+
+		RoomCommand::SetPower {
+			room_id,
+			user_id,
+			level,
+			force_join_server_user,
+			yes_i_know,
+		} => set_power(body, room_id, user_id, level, force_join_server_user, yes_i_know).await?,
+
+		RoomCommand::Purge {
+			room_id,
+			yes_i_really_mean_it,
+		} => purge(body, room_id, yes_i_really_mean_it).await?,
 	})
 }
@@ -1,6 +1,13 @@
 use std::fmt::Write;
 
-use ruma::{events::room::message::RoomMessageEventContent, RoomAliasId};
+use conduit::Error;
+use ruma::{
+	events::{
+		room::{canonical_alias::RoomCanonicalAliasEventContent, message::RoomMessageEventContent},
+		StateEventType,
+	},
+	RoomAliasId, RoomId,
+};
 
 use super::RoomAliasCommand;
 use crate::{escape_html, services, Result};
@@ -30,26 +37,32 @@ pub(super) async fn process(command: RoomAliasCommand, _body: Vec<&str>) -> Resu
 					room_id,
 					..
 				} => match (force, services().rooms.alias.resolve_local_alias(&room_alias)) {
-					(true, Ok(Some(id))) => match services()
-						.rooms
-						.alias
-						.set_alias(&room_alias, &room_id, server_user)
-					{
-						Ok(()) => Ok(RoomMessageEventContent::text_plain(format!(
-							"Successfully overwrote alias (formerly {id})"
-						))),
-						Err(err) => Ok(RoomMessageEventContent::text_plain(format!("Failed to remove alias: {err}"))),
+					(true, Ok(Some(id))) => {
+						// drop the old mapping first so the previous room no longer lists the alias
+						if let Err(err) = services()
+							.rooms
+							.alias
+							.remove_alias(&room_alias, server_user)
+							.await
+						{
+							return Ok(RoomMessageEventContent::text_plain(format!("Failed to remove alias: {err}")));
+						}
+
+						match set_alias(&room_alias, &room_id) {
+							Ok(directory) => Ok(RoomMessageEventContent::text_plain(format!(
+								"Successfully overwrote alias (formerly {id}){directory}"
+							))),
+							Err(err) => Ok(RoomMessageEventContent::text_plain(format!("Failed to set alias: {err}"))),
+						}
 					},
 					(false, Ok(Some(id))) => Ok(RoomMessageEventContent::text_plain(format!(
 						"Refusing to overwrite in use alias for {id}, use -f or --force to overwrite"
 					))),
-					(_, Ok(None)) => match services()
-						.rooms
-						.alias
-						.set_alias(&room_alias, &room_id, server_user)
-					{
-						Ok(()) => Ok(RoomMessageEventContent::text_plain("Successfully set alias")),
-						Err(err) => Ok(RoomMessageEventContent::text_plain(format!("Failed to remove alias: {err}"))),
+					(_, Ok(None)) => match set_alias(&room_alias, &room_id) {
+						Ok(directory) => Ok(RoomMessageEventContent::text_plain(format!(
+							"Successfully set alias{directory}"
+						))),
+						Err(err) => Ok(RoomMessageEventContent::text_plain(format!("Failed to set alias: {err}"))),
 					},
 					(_, Err(err)) => Ok(RoomMessageEventContent::text_plain(format!("Unable to lookup alias: {err}"))),
 				},
@@ -71,7 +84,19 @@ pub(super) async fn process(command: RoomAliasCommand, _body: Vec<&str>) -> Resu
 				RoomAliasCommand::Which {
 					..
 				} => match services().rooms.alias.resolve_local_alias(&room_alias) {
-					Ok(Some(id)) => Ok(RoomMessageEventContent::text_plain(format!("Alias resolves to {id}"))),
+					Ok(Some(id)) => {
+						let creator = services()
+							.rooms
+							.alias
+							.who_created_alias(&room_alias)?
+							.map_or_else(|| "unknown".to_owned(), |user_id| user_id.to_string());
+						let in_canonical_alias = canonical_alias_contains(&id, &room_alias)?;
+
+						Ok(RoomMessageEventContent::text_plain(format!(
+							"Alias resolves to {id}\nCreated by: {creator}\nIn the room's canonical alias event: \
+							 {in_canonical_alias}"
+						)))
+					},
 					Ok(None) => Ok(RoomMessageEventContent::text_plain("Alias isn't in use.")),
 					Err(err) => Ok(RoomMessageEventContent::text_plain(format!("Unable to lookup alias: {err}"))),
 				},
@@ -91,16 +116,34 @@ pub(super) async fn process(command: RoomAliasCommand, _body: Vec<&str>) -> Resu
 					.collect::<Result<Vec<_>, _>>();
 				match aliases {
 					Ok(aliases) => {
-						let plain_list = aliases.iter().fold(String::new(), |mut output, alias| {
-							writeln!(output, "- {alias}").expect("should be able to write to string buffer");
-							output
-						});
+						let aliases: Vec<_> = aliases
+							.into_iter()
+							.map(|alias| {
+								let creator = alias_creator(&alias);
+								(alias, creator)
+							})
+							.collect();
+
+						let plain_list = aliases
+							.iter()
+							.fold(String::new(), |mut output, (alias, creator)| {
+								writeln!(output, "- {alias} (created by {creator})")
+									.expect("should be able to write to string buffer");
+								output
+							});
 
-						let html_list = aliases.iter().fold(String::new(), |mut output, alias| {
-							writeln!(output, "<li>{}</li>", escape_html(alias.as_ref()))
+						let html_list = aliases
+							.iter()
+							.fold(String::new(), |mut output, (alias, creator)| {
+								writeln!(
+									output,
+									"<li>{} (created by {})</li>",
+									escape_html(alias.as_ref()),
+									escape_html(creator)
+								)
 								.expect("should be able to write to string buffer");
-							output
-						});
+								output
+							});
 
 						let plain = format!("Aliases for {room_id}:\n{plain_list}");
 						let html = format!("Aliases for {room_id}:\n<ul>{html_list}</ul>");
@@ -117,23 +160,33 @@ pub(super) async fn process(command: RoomAliasCommand, _body: Vec<&str>) -> Resu
 				match aliases {
 					Ok(aliases) => {
 						let server_name = services().globals.server_name();
+						let aliases: Vec<_> = aliases
+							.into_iter()
+							.map(|(alias, id)| {
+								let creator = RoomAliasId::parse(format!("#{id}:{server_name}"))
+									.map_or_else(|_| "unknown".to_owned(), |room_alias| alias_creator(&room_alias));
+								(alias, id, creator)
+							})
+							.collect();
+
 						let plain_list = aliases
 							.iter()
-							.fold(String::new(), |mut output, (alias, id)| {
-								writeln!(output, "- `{alias}` -> #{id}:{server_name}")
+							.fold(String::new(), |mut output, (alias, id, creator)| {
+								writeln!(output, "- `{alias}` -> #{id}:{server_name} (created by {creator})")
 									.expect("should be able to write to string buffer");
 								output
 							});
 
 						let html_list = aliases
 							.iter()
-							.fold(String::new(), |mut output, (alias, id)| {
+							.fold(String::new(), |mut output, (alias, id, creator)| {
 								writeln!(
 									output,
-									"<li><code>{}</code> -> #{}:{}</li>",
+									"<li><code>{}</code> -> #{}:{} (created by {})</li>",
 									escape_html(alias.as_ref()),
 									escape_html(id.as_ref()),
-									server_name
+									server_name,
+									escape_html(creator)
 								)
 								.expect("should be able to write to string buffer");
 								output
@@ -149,3 +202,59 @@ pub(super) async fn process(command: RoomAliasCommand, _body: Vec<&str>) -> Resu
 		},
 	}
 }
+
+/// Points the alias at the room. If this is the first alias of a room that is
+/// published to the room directory, its directory entry is refreshed so it is
+/// listed with the new alias.
+fn set_alias(room_alias: &RoomAliasId, room_id: &RoomId) -> Result<&'static str> {
+	let first_alias = services()
+		.rooms
+		.alias
+		.local_aliases_for_room(room_id)
+		.next()
+		.is_none();
+
+	services()
+		.rooms
+		.alias
+		.set_alias(room_alias, room_id, &services().globals.server_user)?;
+
+	if first_alias && services().rooms.directory.is_public_room(room_id)? {
+		services().rooms.directory.set_public(room_id)?;
+		return Ok(", and updated the room's directory entry");
+	}
+
+	Ok("")
+}
+
+/// Checks whether the alias is the canonical alias or one of the alt aliases
+/// in the room's m.room.canonical_alias event
+fn canonical_alias_contains(room_id: &RoomId, room_alias: &RoomAliasId) -> Result<bool> {
+	let Some(event) =
+		services()
+			.rooms
+			.state_accessor
+			.room_state_get(room_id, &StateEventType::RoomCanonicalAlias, "")?
+	else {
+		return Ok(false);
+	};
+
+	let content: RoomCanonicalAliasEventContent = serde_json::from_str(event.content.get())
+		.map_err(|_| Error::bad_database("Invalid canonical alias event in database."))?;
+
+	Ok(content.alias.as_deref() == Some(room_alias)
+		|| content
+			.alt_aliases
+			.iter()
+			.any(|alias| **alias == *room_alias))
+}
+
+fn alias_creator(room_alias: &RoomAliasId) -> String {
+	services()
+		.rooms
+		.alias
+		.who_created_alias(room_alias)
+		.ok()
+		.flatten()
+		.map_or_else(|| "unknown".to_owned(), |user_id| user_id.to_string())
+}
@@ -1,10 +1,48 @@
-use std::fmt::Write;
+use std::{
+	collections::BTreeMap,
+	fmt::Write,
+	io,
+	path::{Component, Path},
+	sync::Arc,
+	time::Duration,
+};
 
-use ruma::{events::room::message::RoomMessageEventContent, OwnedRoomId};
+use api::client::{invite_helper, join_room_by_id_helper};
+use conduit::{debug, utils, Error, PduCount};
+use ruma::{
+	events::{
+		room::{
+			member::{MembershipState, RoomMemberEventContent},
+			message::RoomMessageEventContent,
+			power_levels::{RoomPowerLevels, RoomPowerLevelsEventContent},
+		},
+		StateEventType, TimelineEventType,
+	},
+	Int, OwnedRoomId, OwnedServerName, OwnedUserId, RoomId, UserId,
+};
+use serde_json::value::to_raw_value;
+use service::{admin::jobs::Job, pdu::PduBuilder};
+use tokio::{
+	fs::{File, OpenOptions},
+	io::{AsyncWriteExt, BufWriter},
+	time::sleep,
+};
 
-use crate::{escape_html, get_room_info, handler::PAGE_SIZE, services, Result};
+use super::{MemberFilter, MemberSort};
+use crate::{
+	debug::force_set_room_state_from_server, escape_html, get_room_info, handler::PAGE_SIZE, services, user_is_local,
+	utils::parse_active_local_user_id, Result, RoomKind,
+};
 
-pub(super) async fn list(_body: Vec<&str>, page: Option<usize>) -> Result<RoomMessageEventContent> {
+/// Number of events read from the database at once during a room export
+const EXPORT_BATCH_SIZE: usize = 1000;
+
+/// Number of exported events between two progress reports to the admin room
+const EXPORT_PROGRESS_INTERVAL: usize = 10_000;
+
+pub(super) async fn list(
+	_body: Vec<&str>, page: Option<usize>, room_type: RoomKind,
+) -> Result<RoomMessageEventContent> {
 	// TODO: i know there's a way to do this with clap, but i can't seem to find it
 	let page = page.unwrap_or(1);
 	let mut rooms = services()
@@ -13,6 +51,7 @@ pub(super) async fn list(_body: Vec<&str>, page: Option<usize>) -> Result<RoomMe
 		.iter_ids()
 		.filter_map(Result::ok)
 		.map(|id: OwnedRoomId| get_room_info(&id))
+		.filter(|(_, _, _, kind)| room_type.includes(kind.as_ref()))
 		.collect::<Vec<_>>();
 	rooms.sort_by_key(|r| r.1);
 	rooms.reverse();
@@ -31,7 +70,7 @@ pub(super) async fn list(_body: Vec<&str>, page: Option<usize>) -> Result<RoomMe
 		"Rooms:\n{}",
 		rooms
 			.iter()
-			.map(|(id, members, name)| format!("{id}\tMembers: {members}\tName: {name}"))
+			.map(|(id, members, name, _)| format!("{id}\tMembers: {members}\tName: {name}"))
 			.collect::<Vec<_>>()
 			.join("\n")
 	);
@@ -40,7 +79,7 @@ pub(super) async fn list(_body: Vec<&str>, page: Option<usize>) -> Result<RoomMe
 		 {page}</caption>\n<tr><th>id</th>\t<th>members</th>\t<th>name</th></tr>\n{}</table>",
 		rooms
 			.iter()
-			.fold(String::new(), |mut output, (id, members, name)| {
+			.fold(String::new(), |mut output, (id, members, name, _)| {
 				writeln!(
 					output,
 					"<tr><td>{}</td>\t<td>{}</td>\t<td>{}</td></tr>",
@@ -54,3 +93,693 @@ pub(super) async fn list(_body: Vec<&str>, page: Option<usize>) -> Result<RoomMe
 	);
 	Ok(RoomMessageEventContent::text_html(output_plain, output_html))
 }
+
+pub(super) async fn list_members(
+	_body: Vec<&str>, room_id: Box<RoomId>, page: Option<usize>, membership: MemberFilter, sort: MemberSort,
+	local_only: bool,
+) -> Result<RoomMessageEventContent> {
+	if !services().rooms.metadata.exists(&room_id)? {
+		return Ok(RoomMessageEventContent::text_plain("We don't know about this room."));
+	}
+
+	let state_cache = &services().rooms.state_cache;
+	let candidates: Vec<OwnedUserId> = match membership {
+		MemberFilter::Join => state_cache
+			.room_members(&room_id)
+			.filter_map(Result::ok)
+			.collect(),
+		MemberFilter::Invite => state_cache
+			.room_members_invited(&room_id)
+			.filter_map(Result::ok)
+			.collect(),
+		// users banned before ever joining aren't tracked as left, only their member
+		// event knows them
+		MemberFilter::Ban | MemberFilter::Leave => services()
+			.rooms
+			.state_accessor
+			.room_state_full(&room_id)
+			.await?
+			.into_iter()
+			.filter(|((kind, _), pdu)| {
+				*kind == StateEventType::RoomMember
+					&& serde_json::from_str::<RoomMemberEventContent>(pdu.content.get()).is_ok_and(|content| {
+						matches!(content.membership, MembershipState::Ban | MembershipState::Leave)
+					})
+			})
+			.filter_map(|((_, state_key), _)| UserId::parse(state_key).ok())
+			.collect(),
+	};
+
+	let mut servers: BTreeMap<OwnedServerName, Vec<(OwnedUserId, Option<String>, Option<u64>)>> = BTreeMap::new();
+	for user_id in candidates {
+		if local_only && !user_is_local(&user_id) {
+			continue;
+		}
+
+		let pdu =
+			services()
+				.rooms
+				.state_accessor
+				.room_state_get(&room_id, &StateEventType::RoomMember, user_id.as_str())?;
+		let content = pdu
+			.as_ref()
+			.and_then(|pdu| serde_json::from_str::<RoomMemberEventContent>(pdu.content.get()).ok());
+
+		// bans and leaves are both listed above, the member event tells them apart
+		let banned = content
+			.as_ref()
+			.is_some_and(|content| content.membership == MembershipState::Ban);
+		if matches!(membership, MemberFilter::Ban if !banned) || matches!(membership, MemberFilter::Leave if banned) {
+			continue;
+		}
+
+		let displayname = content
+			.and_then(|content| content.displayname)
+			.or_else(|| services().users.displayname(&user_id).ok().flatten());
+		let joined_at = pdu.map(|pdu| u64::from(pdu.origin_server_ts));
+
+		servers
+			.entry(user_id.server_name().to_owned())
+			.or_default()
+			.push((user_id, displayname, joined_at));
+	}
+
+	if servers.is_empty() {
+		return Ok(RoomMessageEventContent::notice_plain("No members found."));
+	}
+
+	let mut servers: Vec<_> = servers.into_iter().collect();
+	servers.sort_by(|(_, a), (_, b)| b.len().cmp(&a.len()));
+
+	let total: usize = servers.iter().map(|(_, members)| members.len()).sum();
+	let mut msg = format!("{total} members on {} servers:\n", servers.len());
+	for (server, members) in &servers {
+		writeln!(msg, "- {server}: {}", members.len()).expect("should be able to write to string buffer");
+	}
+
+	for (_, members) in &mut servers {
+		match sort {
+			MemberSort::Joined => members.sort_by(|(_, _, a), (_, _, b)| b.cmp(a)),
+			MemberSort::Name => members.sort_by(|(a_id, a, _), (b_id, b, _)| {
+				a.as_deref()
+					.unwrap_or(a_id.as_str())
+					.to_lowercase()
+					.cmp(&b.as_deref().unwrap_or(b_id.as_str()).to_lowercase())
+			}),
+		}
+	}
+
+	let page = page.unwrap_or(1);
+	let members: Vec<_> = servers
+		.iter()
+		.flat_map(|(server, members)| members.iter().map(move |member| (server, member)))
+		.skip(page.saturating_sub(1).saturating_mul(PAGE_SIZE))
+		.take(PAGE_SIZE)
+		.collect();
+
+	if members.is_empty() {
+		writeln!(msg, "\nNo more members.").expect("should be able to write to string buffer");
+		return Ok(RoomMessageEventContent::notice_markdown(msg));
+	}
+
+	writeln!(
+		msg,
+		"\nMembers - page {page}:\n\n| Server | User | Displayname | Member since |\n| --- | --- | --- | --- |"
+	)
+	.expect("should be able to write to string buffer");
+	for (server, (user_id, displayname, joined_at)) in members {
+		let joined_at = joined_at
+			.and_then(|ts| i64::try_from(ts).ok())
+			.and_then(chrono::DateTime::from_timestamp_millis)
+			.map_or_else(|| "unknown".to_owned(), |time| time.format("%Y-%m-%d %H:%M:%S").to_string());
+
+		// Displaynames are chosen by the users, so they must not break out of the
+		// table cell nor be rendered as HTML
+		let displayname = escape_html(displayname.as_deref().unwrap_or(""))
+			.replace('|', "\\|")
+			.replace(['\n', '\r'], " ");
+		writeln!(msg, "| {server} | `{user_id}` | {displayname} | {joined_at} |")
+			.expect("should be able to write to string buffer");
+	}
+
+	Ok(RoomMessageEventContent::notice_markdown(msg))
+}
+
+pub(super) async fn export(
+	_body: Vec<&str>, room_id: Box<RoomId>, output: Option<String>, since_ts: Option<u64>, until_ts: Option<u64>,
+	force: bool,
+) -> Result<RoomMessageEventContent> {
+	let Some(exports_path) = services().globals.config.exports_path.clone() else {
+		return Ok(RoomMessageEventContent::text_plain(
+			"Configure exports_path to enable room exports.",
+		));
+	};
+
+	if !services().rooms.metadata.exists(&room_id)? {
+		return Ok(RoomMessageEventContent::text_plain("We don't know about this room."));
+	}
+
+	let file_name = output.unwrap_or_else(|| {
+		let room_id = room_id
+			.as_str()
+			.replace(|c: char| !c.is_ascii_alphanumeric(), "_");
+		format!("{room_id}-{}.jsonl", utils::millis_since_unix_epoch())
+	});
+
+	// Only plain file names are accepted so nothing is written outside of the
+	// exports directory.
+	let mut components = Path::new(&file_name).components();
+	if !matches!((components.next(), components.next()), (Some(Component::Normal(_)), None)) {
+		return Ok(RoomMessageEventContent::text_plain(
+			"The output must be a file name, exports are always written to the exports directory.",
+		));
+	}
+
+	let path = exports_path.join(&file_name);
+	let mut options = OpenOptions::new();
+	if force {
+		options.write(true).create(true).truncate(true);
+	} else {
+		options.write(true).create_new(true);
+	}
+
+	let file = match options.open(&path).await {
+		Ok(file) => file,
+		Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+			return Ok(RoomMessageEventContent::text_plain(format!(
+				"{file_name} already exists in the exports directory, use --force to overwrite it."
+			)));
+		},
+		Err(e) => return Err(e.into()),
+	};
+
+	let room_id: OwnedRoomId = room_id.into();
+	let job = services().admin.jobs.spawn("rooms export", move |job| {
+		export_room(job, room_id, file, file_name, since_ts, until_ts)
+	});
+
+	Ok(RoomMessageEventContent::text_plain(format!(
+		"Exporting the room as job {}. Use `!admin jobs` to follow its progress.",
+		job.id
+	)))
+}
+
+async fn export_room(
+	job: Arc<Job>, room_id: OwnedRoomId, file: File, file_name: String, since_ts: Option<u64>, until_ts: Option<u64>,
+) -> Result<RoomMessageEventContent> {
+	let server_user = &services().globals.server_user;
+	let mut writer = BufWriter::new(file);
+	let mut from = PduCount::min();
+	let mut exported: usize = 0;
+
+	while !job.is_cancelled() {
+		let batch = services()
+			.rooms
+			.timeline
+			.pdus_after(server_user, &room_id, from)?
+			.take(EXPORT_BATCH_SIZE)
+			.collect::<Result<Vec<_>>>()?;
+
+		let Some(&(last, _)) = batch.last() else {
+			break;
+		};
+		from = last;
+
+		for (_, pdu) in batch {
+			let origin_server_ts = u64::from(pdu.origin_server_ts);
+			if since_ts.is_some_and(|since_ts| origin_server_ts < since_ts)
+				|| until_ts.is_some_and(|until_ts| origin_server_ts > until_ts)
+			{
+				continue;
+			}
+
+			// Exported from the stored json to keep signatures and hashes intact
+			let Some((_, pdu_json)) = services()
+				.rooms
+				.timeline
+				.get_pdu_json_with_id(&pdu.event_id)?
+			else {
+				continue;
+			};
+
+			let mut line = serde_json::to_vec(&pdu_json).expect("canonical json can be serialized");
+			line.push(b'\n');
+			writer.write_all(&line).await?;
+
+			exported = exported.saturating_add(1);
+			if exported % EXPORT_PROGRESS_INTERVAL == 0 {
+				job.set_progress(format!("exported {exported} events"));
+				services()
+					.admin
+					.send_text(&format!("Exported {exported} events from {room_id} so far."))
+					.await;
+			}
+		}
+	}
+
+	writer.flush().await?;
+
+	Ok(RoomMessageEventContent::text_plain(format!(
+		"Exported {exported} events from {room_id} to {file_name}."
+	)))
+}
+
+pub(super) async fn incomplete(_body: Vec<&str>, resync: bool) -> Result<RoomMessageEventContent> {
+	let rooms: Vec<OwnedRoomId> = services()
+		.rooms
+		.metadata
+		.list_incomplete_rooms()
+		.filter_map(Result::ok)
+		.collect();
+
+	if rooms.is_empty() {
+		return Ok(RoomMessageEventContent::text_plain("No rooms have an incomplete member list."));
+	}
+
+	if !resync {
+		let mut msg = format!("Rooms with an incomplete member list ({}):\n", rooms.len());
+		for room_id in &rooms {
+			writeln!(msg, "- {room_id}")?;
+		}
+
+		return Ok(RoomMessageEventContent::text_markdown(msg));
+	}
+
+	let count = rooms.len();
+	let job = services()
+		.admin
+		.jobs
+		.spawn("resync_incomplete_rooms", move |job| resync_incomplete_rooms(job, rooms));
+
+	Ok(RoomMessageEventContent::text_plain(format!(
+		"Started job {} to resync the state of {count} rooms.",
+		job.id
+	)))
+}
+
+async fn resync_incomplete_rooms(job: Arc<Job>, rooms: Vec<OwnedRoomId>) -> Result<RoomMessageEventContent> {
+	let total = rooms.len();
+	let mut failed = Vec::new();
+
+	for (i, room_id) in rooms.into_iter().enumerate() {
+		if job.is_cancelled() {
+			break;
+		}
+
+		job.set_progress(format!("{i}/{total} rooms, resyncing {room_id}"));
+
+		let servers: Vec<_> = services()
+			.rooms
+			.state_cache
+			.room_servers(&room_id)
+			.filter_map(Result::ok)
+			.filter(|server| *server != services().globals.config.server_name)
+			.collect();
+
+		for server in servers {
+			if let Err(e) =
+				force_set_room_state_from_server(Vec::new(), server.clone().into(), room_id.clone().into()).await
+			{
+				debug!("Failed to resync state of {room_id} from {server}: {e}");
+			}
+
+			if !services().rooms.metadata.is_incomplete(&room_id)? {
+				break;
+			}
+		}
+
+		if services().rooms.metadata.is_incomplete(&room_id)? {
+			failed.push(room_id);
+		}
+	}
+
+	let mut msg = format!("Resynced incomplete rooms, {} could not be resynced.\n", failed.len());
+	for room_id in &failed {
+		writeln!(msg, "- {room_id}")?;
+	}
+
+	Ok(RoomMessageEventContent::text_markdown(msg))
+}
+
+pub(super) async fn purge(
+	_body: Vec<&str>, room_id: Box<RoomId>, yes_i_really_mean_it: bool,
+) -> Result<RoomMessageEventContent> {
+	if !yes_i_really_mean_it {
+		return Ok(RoomMessageEventContent::text_plain(
+			"Purging a room irrecoverably deletes it from the database. Pass --yes-i-really-mean-it to proceed.",
+		));
+	}
+
+	if !services().rooms.metadata.is_banned(&room_id)? {
+		return Ok(RoomMessageEventContent::text_plain(
+			"Only banned rooms can be purged, ban the room first.",
+		));
+	}
+
+	if services()
+		.rooms
+		.state_cache
+		.local_users_in_room(&room_id)
+		.next()
+		.is_some()
+	{
+		return Ok(RoomMessageEventContent::text_plain(
+			"Local users are still joined to the room, they have to leave it before it can be purged.",
+		));
+	}
+
+	let room_id: OwnedRoomId = room_id.into();
+	let job = services()
+		.admin
+		.jobs
+		.spawn("rooms purge", move |job| purge_room(job, room_id));
+
+	Ok(RoomMessageEventContent::text_plain(format!(
+		"Purging the room as job {}. Use `!admin jobs` to follow its progress.",
+		job.id
+	)))
+}
+
+async fn purge_room(job: Arc<Job>, room_id: OwnedRoomId) -> Result<RoomMessageEventContent> {
+	let removed = services().rooms.metadata.purge_room(&room_id, &job).await?;
+
+	let total: usize = removed.values().sum();
+	let mut msg =
+		format!("Removed {total} keys of {room_id}, which stays banned.\n\n| Table | Keys removed |\n| --- | --- |\n");
+	for (table, count) in &removed {
+		writeln!(msg, "| {table} | {count} |")?;
+	}
+
+	Ok(RoomMessageEventContent::text_markdown(msg))
+}
+
+pub(super) async fn bulk_invite(
+	body: Vec<&str>, room_id: Box<RoomId>, sender: Option<String>, delay: Duration,
+) -> Result<RoomMessageEventContent> {
+	if body.len() < 2 || !body[0].trim().starts_with("```") || body.last().unwrap_or(&"").trim() != "```" {
+		return Ok(RoomMessageEventContent::text_plain(
+			"Expected code block in command body. Add --help for details.",
+		));
+	}
+
+	let sender = match sender {
+		Some(sender) => parse_active_local_user_id(&sender)?,
+		None => services().globals.server_user.clone(),
+	};
+
+	if !services().rooms.state_cache.is_joined(&sender, &room_id)? {
+		return Ok(RoomMessageEventContent::text_plain(format!(
+			"{sender} is not joined to {room_id}."
+		)));
+	}
+
+	if !services()
+		.rooms
+		.state_accessor
+		.user_has_invite_power(&sender, &room_id)?
+	{
+		return Ok(RoomMessageEventContent::text_plain(format!(
+			"{sender} does not have the power level to invite users to {room_id}."
+		)));
+	}
+
+	let mut report = InviteReport::default();
+	let mut user_ids: Vec<OwnedUserId> = Vec::new();
+	for line in body
+		.iter()
+		.skip(1)
+		.take(body.len().saturating_sub(2))
+		.map(|line| line.trim())
+		.filter(|line| !line.is_empty())
+	{
+		match UserId::parse(line) {
+			Ok(user_id) if !user_ids.contains(&user_id) => user_ids.push(user_id),
+			Ok(_) => {},
+			Err(e) => report
+				.failed
+				.push((line.to_owned(), format!("invalid user ID: {e}"))),
+		}
+	}
+
+	if user_ids.is_empty() {
+		return Ok(RoomMessageEventContent::text_plain("No valid user IDs were given."));
+	}
+
+	let user_count = user_ids.len();
+	let room_id: OwnedRoomId = room_id.into();
+	let job = services()
+		.admin
+		.jobs
+		.spawn("rooms bulk-invite", move |job| {
+			invite_users(job, room_id, sender, user_ids, delay, report)
+		});
+
+	Ok(RoomMessageEventContent::text_plain(format!(
+		"Inviting {user_count} users as job {}. Use `!admin jobs` to follow its progress.",
+		job.id
+	)))
+}
+
+#[derive(Default)]
+struct InviteReport {
+	invited: Vec<OwnedUserId>,
+	already_members: Vec<(OwnedUserId, &'static str)>,
+	failed: Vec<(String, String)>,
+}
+
+async fn invite_users(
+	job: Arc<Job>, room_id: OwnedRoomId, sender: OwnedUserId, user_ids: Vec<OwnedUserId>, delay: Duration,
+	mut report: InviteReport,
+) -> Result<RoomMessageEventContent> {
+	let total = user_ids.len();
+	let mut unreachable = Vec::new();
+	let mut sent_any = false;
+	let mut cancelled = false;
+
+	for (i, user_id) in user_ids.into_iter().enumerate() {
+		if job.is_cancelled() {
+			cancelled = true;
+			break;
+		}
+
+		job.set_progress(format!("inviting user {} of {total}: {user_id}", i.saturating_add(1)));
+
+		if let Some(membership) = current_membership(&room_id, &user_id)? {
+			report.already_members.push((user_id, membership));
+			continue;
+		}
+
+		if sent_any {
+			sleep(delay).await;
+		}
+		sent_any = true;
+
+		match invite_helper(&sender, &user_id, &room_id, None, false).await {
+			Ok(()) => report.invited.push(user_id),
+			Err(e) if !user_is_local(&user_id) && is_unreachable(&e) => {
+				debug!("Retrying the invite of {user_id} later, their server could not be reached: {e}");
+				unreachable.push(user_id);
+			},
+			Err(e) => report.failed.push((user_id.to_string(), e.to_string())),
+		}
+	}
+
+	let retries = unreachable.len();
+	for (i, user_id) in unreachable.into_iter().enumerate() {
+		if cancelled || job.is_cancelled() {
+			cancelled = true;
+			report
+				.failed
+				.push((user_id.to_string(), "server unreachable, not retried".to_owned()));
+			continue;
+		}
+
+		job.set_progress(format!("retrying invite {} of {retries}: {user_id}", i.saturating_add(1)));
+		sleep(delay).await;
+
+		match invite_helper(&sender, &user_id, &room_id, None, false).await {
+			Ok(()) => report.invited.push(user_id),
+			Err(e) => report
+				.failed
+				.push((user_id.to_string(), format!("server unreachable: {e}"))),
+		}
+	}
+
+	let mut msg = format!(
+		"{} bulk invite to {room_id} as {sender}: {} invited, {} already members, {} failed.\n",
+		if cancelled {
+			"Stopped"
+		} else {
+			"Finished"
+		},
+		report.invited.len(),
+		report.already_members.len(),
+		report.failed.len(),
+	);
+
+	if !report.invited.is_empty() {
+		msg.push_str("\nInvited:\n");
+		for user_id in &report.invited {
+			writeln!(msg, "- {user_id}")?;
+		}
+	}
+
+	if !report.already_members.is_empty() {
+		msg.push_str("\nAlready members:\n");
+		for (user_id, membership) in &report.already_members {
+			writeln!(msg, "- {user_id} ({membership})")?;
+		}
+	}
+
+	if !report.failed.is_empty() {
+		msg.push_str("\nFailed:\n");
+		for (user_id, reason) in &report.failed {
+			writeln!(msg, "- {}: {}", escape_html(user_id), escape_html(reason))?;
+		}
+	}
+
+	Ok(RoomMessageEventContent::text_markdown(msg))
+}
+
+/// The membership which makes inviting the user pointless, if they have one
+fn current_membership(room_id: &RoomId, user_id: &UserId) -> Result<Option<&'static str>> {
+	let state_cache = &services().rooms.state_cache;
+	if state_cache.is_joined(user_id, room_id)? {
+		Ok(Some("joined"))
+	} else if state_cache.is_invited(user_id, room_id)? {
+		Ok(Some("invited"))
+	} else {
+		Ok(None)
+	}
+}
+
+/// Whether a remote invite failed because the user's server could not be
+/// reached, so it may succeed later
+fn is_unreachable(e: &Error) -> bool {
+	match e {
+		Error::Reqwest(e) => e.is_connect() || e.is_timeout(),
+		Error::Federation(_, e) => e.status_code.is_server_error(),
+		_ => false,
+	}
+}
+
+pub(super) async fn power_levels(_body: Vec<&str>, room_id: Box<RoomId>) -> Result<RoomMessageEventContent> {
+	let Some(event) = services()
+		.rooms
+		.state_accessor
+		.room_state_get(&room_id, &StateEventType::RoomPowerLevels, "")?
+	else {
+		return Ok(RoomMessageEventContent::text_plain(format!(
+			"{room_id} has no m.room.power_levels event."
+		)));
+	};
+
+	let content: serde_json::Value = serde_json::from_str(event.content.get())
+		.map_err(|_| Error::bad_database("Invalid m.room.power_levels event in database."))?;
+	let content = serde_json::to_string_pretty(&content).expect("json value serializes");
+
+	Ok(RoomMessageEventContent::notice_markdown(format!(
+		"Power levels of {room_id} set by {} in {}:\n\n```json\n{content}\n```",
+		event.sender, event.event_id
+	)))
+}
+
+pub(super) async fn set_power(
+	_body: Vec<&str>, room_id: Box<RoomId>, user_id: Box<UserId>, level: i64, force_join_server_user: bool,
+	yes_i_know: bool,
+) -> Result<RoomMessageEventContent> {
+	let Some(level) = Int::new(level) else {
+		return Ok(RoomMessageEventContent::text_plain(
+			"The power level is out of the range of integers allowed in events.",
+		));
+	};
+
+	let server_user = &services().globals.server_user;
+	if !services()
+		.rooms
+		.state_cache
+		.is_joined(server_user, &room_id)?
+	{
+		if !force_join_server_user {
+			return Ok(RoomMessageEventContent::text_plain(format!(
+				"{server_user} is not joined to {room_id}. Invite it from an account with the power to do so and run \
+				 this command again, or pass --force-join-server-user if the room's join rules let it join."
+			)));
+		}
+
+		let servers: Vec<OwnedServerName> = services()
+			.rooms
+			.state_cache
+			.room_servers(&room_id)
+			.filter_map(Result::ok)
+			.chain(room_id.server_name().map(ToOwned::to_owned))
+			.collect();
+		join_room_by_id_helper(Some(server_user), &room_id, None, &servers, None).await?;
+	}
+
+	// Held from reading the power levels to sending the new ones, so concurrent
+	// changes are not overwritten
+	let state_lock = services().globals.roomid_mutex_state.lock(&room_id).await;
+	let Some(event) = services()
+		.rooms
+		.state_accessor
+		.room_state_get(&room_id, &StateEventType::RoomPowerLevels, "")?
+	else {
+		return Ok(RoomMessageEventContent::text_plain(format!(
+			"{room_id} has no m.room.power_levels event to change."
+		)));
+	};
+
+	// Edit the content as JSON so fields we don't know about are kept
+	let mut content: serde_json::Map<String, serde_json::Value> = serde_json::from_str(event.content.get())
+		.map_err(|_| Error::bad_database("Invalid m.room.power_levels event in database."))?;
+	let previous = content
+		.get("users")
+		.and_then(|users| users.get(user_id.as_str()))
+		.cloned();
+	content
+		.entry("users")
+		.or_insert_with(|| serde_json::Value::Object(serde_json::Map::new()))
+		.as_object_mut()
+		.ok_or_else(|| Error::bad_database("Invalid users in m.room.power_levels event."))?
+		.insert(user_id.to_string(), i64::from(level).into());
+
+	let new_power_levels = match serde_json::from_value::<RoomPowerLevelsEventContent>(content.clone().into()) {
+		Ok(new_power_levels) => RoomPowerLevels::from(new_power_levels),
+		Err(e) => {
+			return Ok(RoomMessageEventContent::text_plain(format!(
+				"The updated power levels would not be valid: {e}"
+			)));
+		},
+	};
+
+	if !yes_i_know && !new_power_levels.user_can_send_state(server_user, StateEventType::RoomPowerLevels) {
+		return Ok(RoomMessageEventContent::text_plain(format!(
+			"This change would leave {server_user} unable to change the power levels of {room_id} again, so this \
+			 command could not be used to fix them later. Pass --yes-i-know to proceed anyway."
+		)));
+	}
+
+	let event_id = services()
+		.rooms
+		.timeline
+		.build_and_append_pdu(
+			PduBuilder {
+				event_type: TimelineEventType::RoomPowerLevels,
+				content: to_raw_value(&content).expect("json object is valid json"),
+				unsigned: None,
+				state_key: Some(String::new()),
+				redacts: None,
+			},
+			server_user,
+			&room_id,
+			&state_lock,
+		)
+		.await?;
+	drop(state_lock);
+
+	let previous = previous.map_or_else(|| "the default".to_owned(), |previous| previous.to_string());
+	Ok(RoomMessageEventContent::text_plain(format!(
+		"Changed the power level of {user_id} in {room_id} from {previous} to {level} in {event_id}."
+	)))
+}
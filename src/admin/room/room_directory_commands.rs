@@ -21,6 +21,7 @@ pub(super) async fn process(command: RoomDirectoryCommand, _body: Vec<&str>) ->
 		},
 		RoomDirectoryCommand::List {
 			page,
+			room_type,
 		} => {
 			// TODO: i know there's a way to do this with clap, but i can't seem to find it
 			let page = page.unwrap_or(1);
@@ -30,6 +31,7 @@ pub(super) async fn process(command: RoomDirectoryCommand, _body: Vec<&str>) ->
 				.public_rooms()
 				.filter_map(Result::ok)
 				.map(|id: OwnedRoomId| get_room_info(&id))
+				.filter(|(_, _, _, kind)| room_type.includes(kind.as_ref()))
 				.collect::<Vec<_>>();
 			rooms.sort_by_key(|r| r.1);
 			rooms.reverse();
@@ -48,7 +50,7 @@ pub(super) async fn process(command: RoomDirectoryCommand, _body: Vec<&str>) ->
 				"Rooms:\n{}",
 				rooms
 					.iter()
-					.map(|(id, members, name)| format!("{id}\tMembers: {members}\tName: {name}"))
+					.map(|(id, members, name, _)| format!("{id}\tMembers: {members}\tName: {name}"))
 					.collect::<Vec<_>>()
 					.join("\n")
 			);
@@ -57,7 +59,7 @@ pub(super) async fn process(command: RoomDirectoryCommand, _body: Vec<&str>) ->
 				 {page}</caption>\n<tr><th>id</th>\t<th>members</th>\t<th>name</th></tr>\n{}</table>",
 				rooms
 					.iter()
-					.fold(String::new(), |mut output, (id, members, name)| {
+					.fold(String::new(), |mut output, (id, members, name, _)| {
 						writeln!(
 							output,
 							"<tr><td>{}</td>\t<td>{}</td>\t<td>{}</td></tr>",
@@ -1,7 +1,10 @@
+use std::sync::Arc;
+
 use api::client::leave_room;
 use ruma::{
 	events::room::message::RoomMessageEventContent, OwnedRoomId, OwnedUserId, RoomAliasId, RoomId, RoomOrAliasId,
 };
+use service::admin::jobs::Job;
 use tracing::{debug, error, info, warn};
 
 use super::{super::Service, RoomModerationCommand};
@@ -195,7 +198,6 @@ async fn ban_list_of_rooms(body: Vec<&str>, force: bool, disable_federation: boo
 
 	let admin_room_alias = &services().globals.admin_alias;
 
-	let mut room_ban_count: usize = 0;
 	let mut room_ids: Vec<OwnedRoomId> = Vec::new();
 
 	for &room in &rooms_s {
@@ -305,7 +307,35 @@ async fn ban_list_of_rooms(body: Vec<&str>, force: bool, disable_federation: boo
 		}
 	}
 
-	for room_id in room_ids {
+	let room_count = room_ids.len();
+	let job = services()
+		.admin
+		.jobs
+		.spawn("rooms moderation ban-list-of-rooms", move |job| {
+			ban_rooms(job, room_ids, force, disable_federation)
+		});
+
+	Ok(RoomMessageEventContent::text_plain(format!(
+		"Banning {room_count} rooms as job {}. Use `!admin jobs` to follow its progress.",
+		job.id
+	)))
+}
+
+async fn ban_rooms(
+	job: Arc<Job>, room_ids: Vec<OwnedRoomId>, force: bool, disable_federation: bool,
+) -> Result<RoomMessageEventContent> {
+	let room_count = room_ids.len();
+	let mut room_ban_count: usize = 0;
+
+	for (i, room_id) in room_ids.into_iter().enumerate() {
+		if job.is_cancelled() {
+			return Ok(RoomMessageEventContent::text_plain(format!(
+				"Stopped bulk room ban after banning {room_ban_count} of {room_count} rooms."
+			)));
+		}
+
+		job.set_progress(format!("banning room {} of {room_count}: {room_id}", i.saturating_add(1)));
+
 		if services().rooms.metadata.ban_room(&room_id, true).is_ok() {
 			debug!("Banned {room_id} successfully");
 			room_ban_count = room_ban_count.saturating_add(1);
@@ -501,7 +531,7 @@ async fn list_banned_rooms(_body: Vec<&str>) -> Result<RoomMessageEventContent>
 				rooms.len(),
 				rooms
 					.iter()
-					.map(|(id, members, name)| format!("{id}\tMembers: {members}\tName: {name}"))
+					.map(|(id, members, name, _)| format!("{id}\tMembers: {members}\tName: {name}"))
 					.collect::<Vec<_>>()
 					.join("\n")
 			);
@@ -1,5 +1,8 @@
-use conduit::{warn, Result};
+use std::{fmt::Write, time::Duration};
+
+use conduit::{utils, warn, Result};
 use ruma::events::room::message::RoomMessageEventContent;
+use service::user_is_local;
 
 use crate::services;
 
@@ -21,6 +24,105 @@ pub(super) async fn uptime(_body: Vec<&str>) -> Result<RoomMessageEventContent>
 	Ok(RoomMessageEventContent::notice_plain(result))
 }
 
+pub(super) async fn status(_body: Vec<&str>) -> Result<RoomMessageEventContent> {
+	let uptime = services()
+		.server
+		.started
+		.elapsed()
+		.expect("standard duration")
+		.as_secs();
+	let (last_minute, _) = services().globals.counter_rate(Duration::from_secs(60))?;
+	let (last_hour, _) = services()
+		.globals
+		.counter_rate(Duration::from_secs(60 * 60))?;
+
+	let clock_skew = match services().globals.last_clock_skew()? {
+		Some((measured_at, skew)) => {
+			let ago = utils::millis_since_unix_epoch().saturating_sub(measured_at) / 1000;
+			format!("{skew} ms (measured {ago} seconds ago)")
+		},
+		None => "never measured, see debug check-clock".to_owned(),
+	};
+
+	let globals = &services().globals;
+	let backups_enabled = globals
+		.config
+		.database_backup_path
+		.as_ref()
+		.is_some_and(|path| !path.as_os_str().is_empty());
+	let last_backup = match globals.db.last_backup()? {
+		_ if !backups_enabled => "backups disabled".to_owned(),
+		Some(timestamp) => chrono::DateTime::from_timestamp(timestamp, 0)
+			.map_or_else(|| timestamp.to_string(), |time| time.format("%Y-%m-%d %H:%M:%S").to_string()),
+		None => "never".to_owned(),
+	};
+
+	let mut users: usize = 0;
+	let mut devices: usize = 0;
+	for user_id in services().users.iter().filter_map(Result::ok) {
+		if user_is_local(&user_id) && !services().users.is_deactivated(&user_id).unwrap_or(true) {
+			users = users.saturating_add(1);
+			devices = devices.saturating_add(services().users.all_device_ids(&user_id).count());
+		}
+	}
+
+	let sending = &services().sending.db;
+	let mut msg = String::new();
+	writeln!(msg, "Version: {}", conduit::version())?;
+	writeln!(
+		msg,
+		"Uptime: {} days, {} hours, {} minutes",
+		uptime / 86400,
+		(uptime % 86400) / 3600,
+		(uptime % 3600) / 60
+	)?;
+	writeln!(
+		msg,
+		"Database: {}, {} MiB on disk, {} MiB of caches",
+		globals.config.database_backend,
+		globals.db.size()? / (1024 * 1024),
+		globals.db.cache_usage() / (1024 * 1024),
+	)?;
+	writeln!(msg, "Last backup: {last_backup}")?;
+	writeln!(
+		msg,
+		"Local users: {users}, devices: {devices}, rooms: {}",
+		services().rooms.metadata.iter_ids().count()
+	)?;
+	writeln!(
+		msg,
+		"Global counter: {}, {last_minute} in the last minute, {last_hour} in the last hour",
+		globals.current_count()?
+	)?;
+	writeln!(
+		msg,
+		"Sending queue: {} in flight, {} queued",
+		sending.active_requests().count(),
+		sending.queued_total()
+	)?;
+	writeln!(
+		msg,
+		"Federation: {}",
+		if globals.allow_federation() {
+			"enabled"
+		} else {
+			"disabled"
+		}
+	)?;
+	writeln!(
+		msg,
+		"Registration: {}",
+		if globals.allow_registration() {
+			"open"
+		} else {
+			"closed"
+		}
+	)?;
+	write!(msg, "Clock skew: {clock_skew}")?;
+
+	Ok(RoomMessageEventContent::notice_plain(msg))
+}
+
 pub(super) async fn show_config(_body: Vec<&str>) -> Result<RoomMessageEventContent> {
 	// Construct and send the response
 	Ok(RoomMessageEventContent::text_plain(format!("{}", services().globals.config)))
@@ -63,6 +165,30 @@ pub(super) async fn list_backups(_body: Vec<&str>) -> Result<RoomMessageEventCon
 	}
 }
 
+pub(super) async fn audit(_body: Vec<&str>, actor: Option<String>, limit: usize) -> Result<RoomMessageEventContent> {
+	let entries = services().admin.audit.entries(actor.as_deref(), limit)?;
+	if entries.is_empty() {
+		return Ok(RoomMessageEventContent::notice_plain("No audit log entries found."));
+	}
+
+	let mut msg = "| Time | Actor | Action | Target | Outcome |\n| --- | --- | --- | --- | --- |\n".to_owned();
+	for entry in entries {
+		let time = i64::try_from(entry.ts)
+			.ok()
+			.and_then(chrono::DateTime::from_timestamp_millis)
+			.map_or_else(|| entry.ts.to_string(), |time| time.format("%Y-%m-%d %H:%M:%S").to_string());
+
+		writeln!(
+			msg,
+			"| {time} | {} | {} | `{}` | {} |",
+			entry.actor, entry.action, entry.target, entry.outcome
+		)
+		.expect("should be able to write to string buffer");
+	}
+
+	Ok(RoomMessageEventContent::notice_markdown(msg))
+}
+
 pub(super) async fn backup_database(_body: Vec<&str>) -> Result<RoomMessageEventContent> {
 	let mut result = services()
 		.server
@@ -87,6 +213,39 @@ pub(super) async fn list_database_files(_body: Vec<&str>) -> Result<RoomMessageE
 	Ok(RoomMessageEventContent::notice_markdown(result))
 }
 
+pub(super) async fn db_activity(_body: Vec<&str>, reset: bool) -> Result<RoomMessageEventContent> {
+	let globals = &services().globals;
+	if !globals.config.database_activity_counters {
+		return Ok(RoomMessageEventContent::text_plain(
+			"Database activity is not counted, enable database_activity_counters in the config first.",
+		));
+	}
+
+	let (activity, since) = globals.db.activity();
+	let mut msg = format!(
+		"Database activity over the last {} seconds:\n\n| Tree | Writes | Inserts | Removes | Reads |\n| --- | --- | \
+		 --- | --- | --- |\n",
+		since.elapsed().as_secs()
+	);
+	for (name, activity) in &activity {
+		writeln!(
+			msg,
+			"| {name} | {} | {} | {} | {} |",
+			activity.writes(),
+			activity.inserts,
+			activity.removes,
+			activity.gets
+		)?;
+	}
+
+	if reset {
+		globals.db.reset_activity();
+		msg.push_str("\nCounters were reset.");
+	}
+
+	Ok(RoomMessageEventContent::notice_markdown(msg))
+}
+
 pub(super) async fn admin_notice(_body: Vec<&str>, message: Vec<String>) -> Result<RoomMessageEventContent> {
 	let message = message.join(" ");
 	services().admin.send_text(&message).await;
@@ -94,6 +253,12 @@ pub(super) async fn admin_notice(_body: Vec<&str>, message: Vec<String>) -> Resu
 	Ok(RoomMessageEventContent::notice_plain("Notice was sent to #admins"))
 }
 
+pub(super) async fn reload_config(_body: Vec<&str>) -> Result<RoomMessageEventContent> {
+	let report = services().globals.reload_config()?;
+
+	Ok(RoomMessageEventContent::notice_plain(report.to_string()))
+}
+
 #[cfg(conduit_mods)]
 pub(super) async fn reload(_body: Vec<&str>) -> Result<RoomMessageEventContent> {
 	services().server.reload()?;
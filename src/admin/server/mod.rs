@@ -12,6 +12,11 @@ pub(super) enum ServerCommand {
 	/// - Time elapsed since startup
 	Uptime,
 
+	/// - Summary of the server's health: version, uptime, database, counts of
+	///   users, devices and rooms, global counter, sending queue, federation
+	///   and registration state, last backup and clock skew
+	Status,
+
 	/// - Show configuration values
 	ShowConfig,
 
@@ -40,11 +45,40 @@ pub(super) enum ServerCommand {
 	/// - List database files
 	ListDatabaseFiles,
 
+	/// - Show the reads, writes and removals of each database tree since
+	///   startup or the last reset, busiest first
+	///
+	/// Requires `database_activity_counters` to be enabled.
+	DbActivity {
+		/// Start counting again after showing the numbers
+		#[arg(long)]
+		reset: bool,
+	},
+
+	/// - Show the most recent entries of the audit log of sensitive actions
+	Audit {
+		/// Only show the actions of this user, or `console`
+		#[arg(long)]
+		actor: Option<String>,
+
+		#[arg(long, default_value_t = 50)]
+		limit: usize,
+	},
+
 	/// - Send a message to the admin room.
 	AdminNotice {
 		message: Vec<String>,
 	},
 
+	/// - Read the config file again and apply the settings which can be changed
+	///   without a restart
+	///
+	/// Currently these are `log`, the registration settings, `trusted_servers`,
+	/// `auto_join_rooms` and the TURN settings. Other changed settings are
+	/// listed but only take effect after a restart. Sending SIGHUP to the
+	/// server does the same.
+	ReloadConfig,
+
 	#[cfg(conduit_mods)]
 	/// - Hot-reload the server
 	Reload,
@@ -60,6 +94,7 @@ pub(super) enum ServerCommand {
 pub(super) async fn process(command: ServerCommand, body: Vec<&str>) -> Result<RoomMessageEventContent> {
 	Ok(match command {
 		ServerCommand::Uptime => uptime(body).await?,
+		ServerCommand::Status => status(body).await?,
 		ServerCommand::ShowConfig => show_config(body).await?,
 		ServerCommand::MemoryUsage => memory_usage(body).await?,
 		ServerCommand::ClearDatabaseCaches {
@@ -71,9 +106,17 @@ pub(super) async fn process(command: ServerCommand, body: Vec<&str>) -> Result<R
 		ServerCommand::ListBackups => list_backups(body).await?,
 		ServerCommand::BackupDatabase => backup_database(body).await?,
 		ServerCommand::ListDatabaseFiles => list_database_files(body).await?,
+		ServerCommand::DbActivity {
+			reset,
+		} => db_activity(body, reset).await?,
+		ServerCommand::Audit {
+			actor,
+			limit,
+		} => audit(body, actor, limit).await?,
 		ServerCommand::AdminNotice {
 			message,
 		} => admin_notice(body, message).await?,
+		ServerCommand::ReloadConfig => reload_config(body).await?,
 		#[cfg(conduit_mods)]
 		ServerCommand::Reload => reload(body).await?,
 		#[cfg(unix)]
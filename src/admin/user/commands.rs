@@ -1,24 +1,35 @@
-use std::{collections::BTreeMap, fmt::Write as _};
+use std::{collections::BTreeMap, fmt::Write as _, sync::Arc, time::Duration};
 
 use api::client::{join_room_by_id_helper, leave_all_rooms, update_avatar_url, update_displayname};
 use conduit::{utils, Result};
 use ruma::{
+	api::client::push::PusherKind,
 	events::{
+		push_rules::{PushRulesEvent, PushRulesEventContent},
 		room::message::RoomMessageEventContent,
 		tag::{TagEvent, TagEventContent, TagInfo},
-		RoomAccountDataEventType,
+		GlobalAccountDataEventType, RoomAccountDataEventType,
 	},
+	push::Ruleset,
 	OwnedRoomId, OwnedUserId, RoomId,
 };
+use service::{account_data, admin::jobs::Job, user_is_local};
 use tracing::{error, info, warn};
 
 use crate::{
 	escape_html, get_room_info, services,
 	utils::{parse_active_local_user_id, parse_local_user_id},
+	RoomKind,
 };
 
 const AUTO_GEN_PASSWORD_LENGTH: usize = 25;
 
+const SECS_PER_DAY: u64 = 24 * 60 * 60;
+
+/// Number of accounts checked between two yields while purging the keys of
+/// deactivated accounts
+const PURGE_BATCH_SIZE: usize = 100;
+
 pub(super) async fn list(_body: Vec<&str>) -> Result<RoomMessageEventContent> {
 	match services().users.list_local_users() {
 		Ok(users) => {
@@ -42,6 +53,12 @@ pub(super) async fn create(
 		return Ok(RoomMessageEventContent::text_plain(format!("Userid {user_id} already exists")));
 	}
 
+	if services().appservice.is_exclusive_user_id(&user_id).await {
+		return Ok(RoomMessageEventContent::text_plain(format!(
+			"Userid {user_id} is reserved by an appservice. Register it through the appservice instead."
+		)));
+	}
+
 	let password = password.unwrap_or_else(|| utils::random_string(AUTO_GEN_PASSWORD_LENGTH));
 
 	// Create user
@@ -82,8 +99,8 @@ pub(super) async fn create(
 		.expect("to json value always works"),
 	)?;
 
-	if !services().globals.config.auto_join_rooms.is_empty() {
-		for room in &services().globals.config.auto_join_rooms {
+	if !services().globals.auto_join_rooms().is_empty() {
+		for room in &services().globals.auto_join_rooms() {
 			if !services()
 				.rooms
 				.state_cache
@@ -239,9 +256,39 @@ pub(super) async fn deactivate_all(
 		}
 	}
 
+	let user_count = user_ids.len();
+	let admins = admins.join(", ");
+	let job = services()
+		.admin
+		.jobs
+		.spawn("users deactivate-all", move |job| {
+			deactivate_users(job, user_ids, admins, no_leave_rooms)
+		});
+
+	Ok(RoomMessageEventContent::text_plain(format!(
+		"Deactivating {user_count} accounts as job {}. Use `!admin jobs` to follow its progress.",
+		job.id
+	)))
+}
+
+async fn deactivate_users(
+	job: Arc<Job>, user_ids: Vec<OwnedUserId>, admins: String, no_leave_rooms: bool,
+) -> Result<RoomMessageEventContent> {
+	let user_count = user_ids.len();
 	let mut deactivation_count: usize = 0;
 
-	for user_id in user_ids {
+	for (i, user_id) in user_ids.into_iter().enumerate() {
+		if job.is_cancelled() {
+			return Ok(RoomMessageEventContent::text_plain(format!(
+				"Stopped after deactivating {deactivation_count} of {user_count} accounts."
+			)));
+		}
+
+		job.set_progress(format!(
+			"deactivating account {} of {user_count}: {user_id}",
+			i.saturating_add(1)
+		));
+
 		match services().users.deactivate_account(&user_id) {
 			Ok(()) => {
 				deactivation_count = deactivation_count.saturating_add(1);
@@ -273,23 +320,88 @@ pub(super) async fn deactivate_all(
 		)))
 	} else {
 		Ok(RoomMessageEventContent::text_plain(format!(
-			"Deactivated {deactivation_count} accounts.\nSkipped admin accounts: {}. Use --force to deactivate admin \
-			 accounts",
-			admins.join(", ")
+			"Deactivated {deactivation_count} accounts.\nSkipped admin accounts: {admins}. Use --force to deactivate \
+			 admin accounts"
 		)))
 	}
 }
 
-pub(super) async fn list_joined_rooms(_body: Vec<&str>, user_id: String) -> Result<RoomMessageEventContent> {
+pub(super) async fn purge_deactivated_keys(_body: Vec<&str>) -> Result<RoomMessageEventContent> {
+	let job = services()
+		.admin
+		.jobs
+		.spawn("users purge-deactivated-keys", purge_keys_of_deactivated);
+
+	Ok(RoomMessageEventContent::text_plain(format!(
+		"Purging the keys of deactivated accounts as job {}. Use `!admin jobs` to follow its progress.",
+		job.id
+	)))
+}
+
+async fn purge_keys_of_deactivated(job: Arc<Job>) -> Result<RoomMessageEventContent> {
+	let user_ids = services()
+		.users
+		.iter()
+		.filter_map(Result::ok)
+		.filter(|user_id| user_is_local(user_id))
+		.collect::<Vec<_>>();
+
+	let user_count = user_ids.len();
+	let (mut purged_users, mut removed): (usize, usize) = (0, 0);
+
+	for (i, batch) in user_ids.chunks(PURGE_BATCH_SIZE).enumerate() {
+		if job.is_cancelled() {
+			break;
+		}
+
+		job.set_progress(format!(
+			"checked {} of {user_count} accounts, purged {purged_users}",
+			i.saturating_mul(PURGE_BATCH_SIZE)
+		));
+
+		let _cork = services().globals.db.cork();
+		for user_id in batch {
+			if !services().users.is_deactivated(user_id)? {
+				continue;
+			}
+
+			let count = services().users.remove_devices_and_keys(user_id)?;
+			if count > 0 {
+				purged_users = purged_users.saturating_add(1);
+				removed = removed.saturating_add(count);
+			}
+		}
+
+		tokio::task::yield_now().await;
+	}
+
+	Ok(RoomMessageEventContent::text_plain(format!(
+		"Removed {removed} devices, keys and backups of {purged_users} deactivated accounts."
+	)))
+}
+
+pub(super) async fn forget_all_left(_body: Vec<&str>, user_id: String) -> Result<RoomMessageEventContent> {
+	let user_id = parse_local_user_id(&user_id)?;
+	let forgotten = services().rooms.state_cache.forget_all_left(&user_id)?;
+
+	Ok(RoomMessageEventContent::text_plain(format!(
+		"Forgot {forgotten} rooms {user_id} left."
+	)))
+}
+
+pub(super) async fn list_joined_rooms(
+	_body: Vec<&str>, user_id: String, room_type: RoomKind,
+) -> Result<RoomMessageEventContent> {
 	// Validate user id
 	let user_id = parse_local_user_id(&user_id)?;
 
-	let mut rooms: Vec<(OwnedRoomId, u64, String)> = services()
+	let mut rooms: Vec<_> = services()
 		.rooms
 		.state_cache
 		.rooms_joined(&user_id)
 		.filter_map(Result::ok)
 		.map(|room_id| get_room_info(&room_id))
+		.filter(|(_, _, _, kind)| room_type.includes(kind.as_ref()))
 		.collect();
 
 	if rooms.is_empty() {
@@ -304,7 +416,7 @@ pub(super) async fn list_joined_rooms(_body: Vec<&str>, user_id: String) -> Resu
 		rooms.len(),
 		rooms
 			.iter()
-			.map(|(id, members, name)| format!("{id}\tMembers: {members}\tName: {name}"))
+			.map(|(id, members, name, _)| format!("{id}\tMembers: {members}\tName: {name}"))
 			.collect::<Vec<_>>()
 			.join("\n")
 	);
@@ -315,7 +427,7 @@ pub(super) async fn list_joined_rooms(_body: Vec<&str>, user_id: String) -> Resu
 		rooms.len(),
 		rooms
 			.iter()
-			.fold(String::new(), |mut output, (id, members, name)| {
+			.fold(String::new(), |mut output, (id, members, name, _)| {
 				writeln!(
 					output,
 					"<tr><td>{}</td>\t<td>{}</td>\t<td>{}</td></tr>",
@@ -421,3 +533,231 @@ pub(super) async fn get_room_tags(
 		tags_event.content.tags
 	)))
 }
+
+pub(super) async fn list_pushers(_body: Vec<&str>, user_id: String) -> Result<RoomMessageEventContent> {
+	let user_id = parse_local_user_id(&user_id)?;
+	let pushers = services().pusher.get_pushers(&user_id)?;
+	if pushers.is_empty() {
+		return Ok(RoomMessageEventContent::notice_plain(format!("{user_id} has no pushers.")));
+	}
+
+	let mut plain_msg = format!("{} pusher(s) of {user_id}:\n```\n", pushers.len());
+	for pusher in pushers {
+		let pushkey = &pusher.ids.pushkey;
+		let destination = match &pusher.kind {
+			PusherKind::Http(http) => http.url.as_str(),
+			PusherKind::Email(_) => "email",
+			_ => "unknown",
+		};
+
+		let health = services().pusher.health(&user_id, pushkey);
+		let state = if services().pusher.is_disabled(&user_id, pushkey)? {
+			"disabled".to_owned()
+		} else if health.failures == 0 {
+			"ok".to_owned()
+		} else {
+			format!("failing, retrying after {:?}", health.backoff())
+		};
+
+		writeln!(
+			plain_msg,
+			"{pushkey} ({}, {}) -> {destination}: {state}",
+			pusher.ids.app_id, pusher.device_display_name
+		)?;
+
+		if let Some((time, error)) = &health.last_failure {
+			writeln!(
+				plain_msg,
+				"    {} failure(s), {} rejection(s), last {:?} ago: {error}",
+				health.failures,
+				health.rejections,
+				time.elapsed()
+			)?;
+		}
+	}
+	plain_msg += "```";
+
+	Ok(RoomMessageEventContent::notice_markdown(plain_msg))
+}
+
+pub(super) async fn enable_pusher(
+	_body: Vec<&str>, user_id: String, pushkey: String,
+) -> Result<RoomMessageEventContent> {
+	let user_id = parse_local_user_id(&user_id)?;
+	if services().pusher.get_pusher(&user_id, &pushkey)?.is_none() {
+		return Ok(RoomMessageEventContent::notice_plain(format!(
+			"{user_id} has no pusher with this pushkey."
+		)));
+	}
+
+	if !services().pusher.is_disabled(&user_id, &pushkey)? {
+		return Ok(RoomMessageEventContent::notice_plain("This pusher is not disabled."));
+	}
+
+	services().pusher.enable_pusher(&user_id, &pushkey)?;
+
+	Ok(RoomMessageEventContent::notice_plain(format!(
+		"Re-enabled pusher {pushkey:?} of {user_id}."
+	)))
+}
+
+pub(super) async fn sync_status(_body: Vec<&str>, user_id: String) -> Result<RoomMessageEventContent> {
+	let user_id = parse_local_user_id(&user_id)?;
+	let sessions = services().users.sync_sessions.get(&user_id);
+	if sessions.is_empty() {
+		return Ok(RoomMessageEventContent::notice_plain(format!(
+			"{user_id} has not synced since startup."
+		)));
+	}
+
+	let mut plain_msg = format!("Sync requests of {user_id}:\n```\n");
+	for (device_id, session) in sessions {
+		let state = match session.connected_at {
+			Some(connected_at) if session.connections > 0 => format!(
+				"{} long-poll(s) connected for {:?}",
+				session.connections,
+				connected_at.elapsed()
+			),
+			_ => "not connected".to_owned(),
+		};
+
+		let last_response = session
+			.last_response
+			.and_then(|time| time.elapsed().ok())
+			.map_or_else(|| "never".to_owned(), |ago| format!("{ago:?} ago"));
+
+		writeln!(
+			plain_msg,
+			"{device_id}: {state}, since {}, last response {last_response}",
+			session.since.as_deref().unwrap_or("none (initial sync)")
+		)?;
+	}
+	plain_msg += "```";
+
+	Ok(RoomMessageEventContent::notice_markdown(plain_msg))
+}
+
+pub(super) async fn kick_sync(
+	_body: Vec<&str>, user_id: String, device_id: Option<OwnedDeviceId>,
+) -> Result<RoomMessageEventContent> {
+	let user_id = parse_local_user_id(&user_id)?;
+	let kicked = services()
+		.users
+		.sync_sessions
+		.kick(&user_id, device_id.as_deref());
+
+	if kicked.is_empty() {
+		return Ok(RoomMessageEventContent::notice_plain(format!(
+			"{user_id} has no sync long-poll in progress."
+		)));
+	}
+
+	let devices: Vec<_> = kicked.iter().map(ToString::to_string).collect();
+	Ok(RoomMessageEventContent::notice_plain(format!(
+		"Kicked the sync of {user_id} on: {}",
+		devices.join(", ")
+	)))
+}
+
+pub(super) async fn mark_direct(
+	_body: Vec<&str>, user_id: String, room_id: Box<RoomId>, other_user: OwnedUserId,
+) -> Result<RoomMessageEventContent> {
+	let user_id = parse_active_local_user_id(&user_id)?;
+	if !services().rooms.metadata.exists(&room_id)? {
+		return Ok(RoomMessageEventContent::notice_plain(format!(
+			"Room {room_id} is not known to this server."
+		)));
+	}
+
+	if !services()
+		.account_data
+		.add_direct(&user_id, &other_user, &room_id)?
+	{
+		return Ok(RoomMessageEventContent::notice_plain(format!(
+			"{room_id} already is a direct chat with {other_user} for {user_id}."
+		)));
+	}
+
+	Ok(RoomMessageEventContent::notice_plain(format!(
+		"Marked {room_id} as a direct chat with {other_user} for {user_id}."
+	)))
+}
+
+pub(super) async fn repair_account_data(
+	_body: Vec<&str>, user_id: String, reset_push_rules: bool,
+) -> Result<RoomMessageEventContent> {
+	let user_id = parse_local_user_id(&user_id)?;
+
+	let mut problems = Vec::new();
+	for (room_id, event_type, data) in services().account_data.all(&user_id)? {
+		let in_room = room_id.is_some();
+		let location = room_id.map_or_else(|| "global".to_owned(), |room_id| room_id.to_string());
+		let problem = match serde_json::from_slice::<serde_json::Value>(&data) {
+			Err(e) => Some(format!("not JSON: {e}")),
+			Ok(data) if account_data::is_too_large(&data) => Some(format!("{} bytes", data.to_string().len())),
+			Ok(data) => account_data::check_content(in_room, &event_type, &data)
+				.err()
+				.map(|e| e.to_string()),
+		};
+
+		if let Some(problem) = problem {
+			problems.push(format!("{event_type} ({location}): {problem}"));
+		}
+	}
+
+	let mut msg = if problems.is_empty() {
+		format!("All account data of {user_id} is valid.")
+	} else {
+		format!("Invalid account data of {user_id}:\n```\n{}\n```", problems.join("\n"))
+	};
+
+	if reset_push_rules {
+		services().account_data.update(
+			None,
+			&user_id,
+			GlobalAccountDataEventType::PushRules.to_string().into(),
+			&serde_json::to_value(PushRulesEvent {
+				content: PushRulesEventContent {
+					global: Ruleset::server_default(&user_id),
+				},
+			})
+			.expect("to json value always works"),
+		)?;
+
+		write!(msg, "\n\nReset the push rules of {user_id} to the server defaults.")?;
+	}
+
+	Ok(RoomMessageEventContent::notice_markdown(msg))
+}
+
+pub(super) async fn list_active(
+	_body: Vec<&str>, since: Option<Duration>, list: bool,
+) -> Result<RoomMessageEventContent> {
+	let counts = services().users.activity.counts().await?;
+	let mut msg = format!(
+		"Active local users:\n```\nlast day: {}\nlast 7 days: {}\nlast 30 days: {}\nnever active: {}\ndeactivated: \
+		 {}\n```\nNot counted: {} guest(s), {} appservice user(s)",
+		counts.day,
+		counts.week,
+		counts.month,
+		counts.never_active,
+		counts.deactivated,
+		counts.guests,
+		counts.appservice
+	);
+
+	if list {
+		let days = since.map_or(30, |since| since.as_secs().div_ceil(SECS_PER_DAY).max(1));
+		let active = services().users.activity.active_within(days).await?;
+		write!(msg, "\n\n{} user(s) active within the last {days} day(s):\n```\n", active.len())?;
+		for (user_id, ago) in active {
+			match ago {
+				0 => writeln!(msg, "{user_id} (today)")?,
+				ago => writeln!(msg, "{user_id} ({ago} day(s) ago)")?,
+			}
+		}
+		msg.push_str("```");
+	}
+
+	Ok(RoomMessageEventContent::notice_markdown(msg))
+}
@@ -1,10 +1,13 @@
 mod commands;
 
+use std::time::Duration;
+
 use clap::Subcommand;
 use conduit::Result;
-use ruma::{events::room::message::RoomMessageEventContent, RoomId};
+use ruma::{events::room::message::RoomMessageEventContent, OwnedDeviceId, OwnedUserId, RoomId};
 
 use self::commands::*;
+use crate::{utils::parse_duration, RoomKind};
 
 #[cfg_attr(test, derive(Debug))]
 #[derive(Subcommand)]
@@ -47,6 +50,9 @@ pub(super) enum UserCommand {
 	///
 	/// This command needs a newline separated list of users provided in a
 	/// Markdown code block below the command.
+	///
+	/// The accounts are deactivated in the background; use `!admin jobs` to
+	/// follow the progress or cancel it.
 	DeactivateAll {
 		#[arg(short, long)]
 		/// Remove users from their joined rooms
@@ -56,6 +62,14 @@ pub(super) enum UserCommand {
 		force: bool,
 	},
 
+	/// - Removes the devices and encryption keys left over by deactivated
+	///   accounts
+	///
+	/// Accounts deactivated by older versions kept their device keys,
+	/// cross-signing keys, key backups and to-device events. This cleans them
+	/// up in the background; use `!admin jobs` to follow the progress.
+	PurgeDeactivatedKeys,
+
 	/// - List local users in the database
 	List,
 
@@ -63,6 +77,19 @@ pub(super) enum UserCommand {
 	///   joined in
 	ListJoinedRooms {
 		user_id: String,
+
+		/// Which rooms to list: rooms for messages, spaces, or both
+		#[arg(long = "type", value_enum, default_value_t)]
+		room_type: RoomKind,
+	},
+
+	/// - Forgets every room a local user left, except those they are banned
+	///   from
+	///
+	/// Their tags, room account data, notification counts and receipts in
+	/// those rooms are deleted.
+	ForgetAllLeft {
+		user_id: String,
 	},
 
 	/// - Puts a room tag for the specified user and room ID.
@@ -90,11 +117,69 @@ pub(super) enum UserCommand {
 		user_id: String,
 		room_id: Box<RoomId>,
 	},
+
+	/// - Lists the pushers of a local user with their failures and backoff
+	ListPushers {
+		user_id: String,
+	},
+
+	/// - Re-enables a pusher which was disabled after being rejected by its
+	///   push gateway
+	EnablePusher {
+		user_id: String,
+		pushkey: String,
+	},
+
+	/// - Shows the sync requests of each device of a local user, for debugging
+	///   clients whose sync never returns
+	SyncStatus {
+		user_id: String,
+	},
+
+	/// - Makes the sync long-polls of a local user in progress return
+	///   immediately, only those of the given device if specified
+	KickSync {
+		user_id: String,
+		device_id: Option<OwnedDeviceId>,
+	},
+
+	/// - Marks a room as a direct chat with another user in the `m.direct`
+	///   account data of a local user, for fixing up existing rooms
+	MarkDirect {
+		user_id: String,
+		room_id: Box<RoomId>,
+		other_user: OwnedUserId,
+	},
+
+	/// - Lists the account data entries of a local user which are too large or
+	///   don't match their event type, such as malformed push rules
+	///
+	/// Use --reset-push-rules to replace the user's push rules with the server
+	/// defaults.
+	RepairAccountData {
+		user_id: String,
+		#[arg(long)]
+		reset_push_rules: bool,
+	},
+
+	/// - Count the local users active within the last 1, 7 and 30 days
+	///
+	/// A user is active on days they make an authenticated request. Use
+	/// --list to list the users active within --since, 30 days by default.
+	ListActive {
+		/// e.g. "7d"; rounded up to whole days
+		#[arg(long, value_parser = parse_duration)]
+		since: Option<Duration>,
+
+		#[arg(long)]
+		list: bool,
+	},
 }
 
 pub(super) async fn process(command: UserCommand, body: Vec<&str>) -> Result<RoomMessageEventContent> {
 	Ok(match command {
 		UserCommand::List => list(body).await?,
+		UserCommand::PurgeDeactivatedKeys => purge_deactivated_keys(body).await?,
 		UserCommand::Create {
 			username,
 			password,
@@ -112,7 +197,11 @@ pub(super) async fn process(command: UserCommand, body: Vec<&str>) -> Result<Roo
 		} => deactivate_all(body, no_leave_rooms, force).await?,
 		UserCommand::ListJoinedRooms {
 			user_id,
-		} => list_joined_rooms(body, user_id).await?,
+			room_type,
+		} => list_joined_rooms(body, user_id, room_type).await?,
+		UserCommand::ForgetAllLeft {
+			user_id,
+		} => forget_all_left(body, user_id).await?,
 		UserCommand::PutRoomTag {
 			user_id,
 			room_id,
@@ -127,5 +216,32 @@ pub(super) async fn process(command: UserCommand, body: Vec<&str>) -> Result<Roo
 			user_id,
 			room_id,
 		} => get_room_tags(body, user_id, room_id).await?,
+		UserCommand::ListPushers {
+			user_id,
+		} => list_pushers(body, user_id).await?,
+		UserCommand::EnablePusher {
+			user_id,
+			pushkey,
+		} => enable_pusher(body, user_id, pushkey).await?,
+		UserCommand::SyncStatus {
+			user_id,
+		} => sync_status(body, user_id).await?,
+		UserCommand::KickSync {
+			user_id,
+			device_id,
+		} => kick_sync(body, user_id, device_id).await?,
+		UserCommand::MarkDirect {
+			user_id,
+			room_id,
+			other_user,
+		} => mark_direct(body, user_id, room_id, other_user).await?,
+		UserCommand::RepairAccountData {
+			user_id,
+			reset_push_rules,
+		} => repair_account_data(body, user_id, reset_push_rules).await?,
+		UserCommand::ListActive {
+			since,
+			list,
+		} => list_active(body, since, list).await?,
 	})
 }
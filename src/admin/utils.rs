@@ -1,5 +1,8 @@
+use std::time::Duration;
+
+use clap::ValueEnum;
 use conduit_core::Error;
-use ruma::{OwnedRoomId, OwnedUserId, RoomId, UserId};
+use ruma::{room::RoomType, OwnedRoomId, OwnedUserId, RoomId, UserId};
 use service::user_is_local;
 
 use crate::{services, Result};
@@ -10,7 +13,7 @@ pub(crate) fn escape_html(s: &str) -> String {
 		.replace('>', "&gt;")
 }
 
-pub(crate) fn get_room_info(id: &RoomId) -> (OwnedRoomId, u64, String) {
+pub(crate) fn get_room_info(id: &RoomId) -> (OwnedRoomId, u64, String, Option<RoomType>) {
 	(
 		id.into(),
 		services()
@@ -27,9 +30,31 @@ pub(crate) fn get_room_info(id: &RoomId) -> (OwnedRoomId, u64, String) {
 			.ok()
 			.flatten()
 			.unwrap_or_else(|| id.to_string()),
+		services().rooms.metadata.room_type(id).ok().flatten(),
 	)
 }
 
+/// Which rooms a listing includes, by the `type` of their create event
+#[derive(Clone, Copy, Debug, Default, ValueEnum)]
+pub(crate) enum RoomKind {
+	/// Rooms for messages, i.e. anything but spaces
+	Room,
+	Space,
+	#[default]
+	All,
+}
+
+impl RoomKind {
+	pub(crate) fn includes(self, room_type: Option<&RoomType>) -> bool {
+		let is_space = matches!(room_type, Some(RoomType::Space));
+		match self {
+			Self::Room => !is_space,
+			Self::Space => is_space,
+			Self::All => true,
+		}
+	}
+}
+
 /// Parses user ID
 pub(crate) fn parse_user_id(user_id: &str) -> Result<OwnedUserId> {
 	UserId::parse_with_server_name(user_id.to_lowercase(), services().globals.server_name())
@@ -61,3 +86,8 @@ pub(crate) fn parse_active_local_user_id(user_id: &str) -> Result<OwnedUserId> {
 
 	Ok(user_id)
 }
+
+/// Parses a duration such as "7d" or "12h", for clap
+pub(crate) fn parse_duration(duration: &str) -> Result<Duration, String> {
+	cyborgtime::parse_duration(duration).map_err(|e| e.to_string())
+}
@@ -1,6 +1,5 @@
 use std::fmt::Write;
 
-use axum_client_ip::InsecureClientIp;
 use conduit::debug_info;
 use register::RegistrationKind;
 use ruma::{
@@ -12,10 +11,10 @@ use ruma::{
 			ThirdPartyIdRemovalStatus,
 		},
 		error::ErrorKind,
-		uiaa::{AuthFlow, AuthType, UiaaInfo},
+		uiaa::{AuthData, AuthFlow, AuthType, UiaaInfo},
 	},
 	events::{room::message::RoomMessageEventContent, GlobalAccountDataEventType},
-	push, OwnedRoomId, UserId,
+	push, CanonicalJsonValue, OwnedDeviceId, OwnedRoomId, OwnedUserId, UserId,
 };
 use tracing::{error, info, warn};
 
@@ -40,9 +39,9 @@ const RANDOM_USER_ID_LENGTH: usize = 10;
 ///
 /// Note: This will not reserve the username, so the username might become
 /// invalid when trying to register
-#[tracing::instrument(skip_all, fields(%client), name = "register_available")]
+#[tracing::instrument(skip_all, fields(client = %body.client), name = "register_available")]
 pub(crate) async fn get_register_available_route(
-	InsecureClientIp(client): InsecureClientIp, body: Ruma<get_username_availability::v3::Request>,
+	body: Ruma<get_username_availability::v3::Request>,
 ) -> Result<get_username_availability::v3::Response> {
 	// Validate user id
 	let user_id = UserId::parse_with_server_name(body.username.to_lowercase(), services().globals.server_name())
@@ -82,6 +81,8 @@ pub(crate) async fn get_register_available_route(
 /// - Only works if registration is enabled
 /// - If type is guest: ignores all parameters except
 ///   initial_device_display_name
+/// - If `guest_access_token` is given: upgrades that guest account, keeping its
+///   user id, device and rooms
 /// - If sender is not appservice: Requires UIAA (but we only use a dummy stage)
 /// - If type is not guest and no username is given: Always fails after UIAA
 ///   check
@@ -89,10 +90,8 @@ pub(crate) async fn get_register_available_route(
 /// - If `inhibit_login` is false: Creates a device and returns device id and
 ///   access_token
 #[allow(clippy::doc_markdown)]
-#[tracing::instrument(skip_all, fields(%client), name = "register")]
-pub(crate) async fn register_route(
-	InsecureClientIp(client): InsecureClientIp, body: Ruma<register::v3::Request>,
-) -> Result<register::v3::Response> {
+#[tracing::instrument(skip_all, fields(client = %body.client), name = "register")]
+pub(crate) async fn register_route(body: Ruma<register::v3::Request>) -> Result<register::v3::Response> {
 	if !services().globals.allow_registration() && body.appservice_info.is_none() {
 		info!(
 			"Registration disabled and request not from known appservice, rejecting registration attempt for username \
@@ -106,7 +105,7 @@ pub(crate) async fn register_route(
 
 	if is_guest
 		&& (!services().globals.allow_guest_registration()
-			|| (services().globals.allow_registration() && services().globals.config.registration_token.is_some()))
+			|| (services().globals.allow_registration() && services().uiaa.registration_tokens.is_required()))
 	{
 		info!(
 			"Guest registration disabled / registration enabled with token configured, rejecting guest registration \
@@ -130,7 +129,21 @@ pub(crate) async fn register_route(
 		return Err(Error::BadRequest(ErrorKind::forbidden(), "Registration temporarily disabled."));
 	}
 
+	let upgraded_guest = match body.json_body.as_ref() {
+		Some(CanonicalJsonValue::Object(json)) if !is_guest => match json.get("guest_access_token") {
+			Some(CanonicalJsonValue::String(guest_access_token)) => {
+				Some(guest_to_upgrade(guest_access_token, body.username.as_deref())?)
+			},
+			_ => None,
+		},
+		_ => None,
+	};
+
 	let user_id = match (&body.username, is_guest) {
+		_ if upgraded_guest.is_some() => upgraded_guest
+			.as_ref()
+			.map(|(guest_id, _)| guest_id.clone())
+			.expect("checked above"),
 		(Some(username), false) => {
 			let proposed_user_id =
 				UserId::parse_with_server_name(username.to_lowercase(), services().globals.server_name())
@@ -164,21 +177,24 @@ pub(crate) async fn register_route(
 		},
 	};
 
-	if body.body.login_type == Some(LoginType::ApplicationService) {
-		if let Some(ref info) = body.appservice_info {
-			if !info.is_user_match(&user_id) {
-				return Err(Error::BadRequest(ErrorKind::Exclusive, "User is not in namespace."));
-			}
-		} else {
-			return Err(Error::BadRequest(ErrorKind::MissingToken, "Missing appservice token."));
-		}
-	} else if services().appservice.is_exclusive_user_id(&user_id).await {
-		return Err(Error::BadRequest(ErrorKind::Exclusive, "User ID reserved by appservice."));
-	}
+	let appservice_info = if body.body.login_type == Some(LoginType::ApplicationService) {
+		Some(
+			body.appservice_info
+				.as_ref()
+				.ok_or(Error::BadRequest(ErrorKind::MissingToken, "Missing appservice token."))?,
+		)
+	} else {
+		None
+	};
+
+	services()
+		.appservice
+		.check_user_id(&user_id, appservice_info)
+		.await?;
 
 	// UIAA
 	let mut uiaainfo;
-	let skip_auth = if services().globals.config.registration_token.is_some() {
+	let skip_auth = if services().uiaa.registration_tokens.is_required() {
 		// Registration token required
 		uiaainfo = UiaaInfo {
 			flows: vec![AuthFlow {
@@ -236,8 +252,21 @@ pub(crate) async fn register_route(
 		body.password.as_deref()
 	};
 
-	// Create user
-	services().users.create(&user_id, password)?;
+	if upgraded_guest.is_some() {
+		services().users.upgrade_guest(&user_id, password)?;
+	} else if is_guest {
+		services().users.create_guest(&user_id)?;
+	} else {
+		services().users.create(&user_id, password)?;
+	}
+
+	// Count the use of the registration token, which is remembered for auditing
+	if let Some(AuthData::RegistrationToken(token)) = body.auth.as_ref().filter(|_| !skip_auth) {
+		services()
+			.uiaa
+			.registration_tokens
+			.use_token(&user_id, token.token.trim())?;
+	}
 
 	// Default to pretty displayname
 	let mut displayname = user_id.localpart().to_owned();
@@ -249,23 +278,26 @@ pub(crate) async fn register_route(
 			.expect("should be able to write to string buffer");
 	}
 
-	services()
-		.users
-		.set_displayname(&user_id, Some(displayname.clone()))
-		.await?;
-
-	// Initial account data
-	services().account_data.update(
-		None,
-		&user_id,
-		GlobalAccountDataEventType::PushRules.to_string().into(),
-		&serde_json::to_value(ruma::events::push_rules::PushRulesEvent {
-			content: ruma::events::push_rules::PushRulesEventContent {
-				global: push::Ruleset::server_default(&user_id),
-			},
-		})
-		.expect("to json always works"),
-	)?;
+	// Upgraded guests keep their profile and account data
+	if upgraded_guest.is_none() {
+		services()
+			.users
+			.set_displayname(&user_id, Some(displayname.clone()))
+			.await?;
+
+		// Initial account data
+		services().account_data.update(
+			None,
+			&user_id,
+			GlobalAccountDataEventType::PushRules.to_string().into(),
+			&serde_json::to_value(ruma::events::push_rules::PushRulesEvent {
+				content: ruma::events::push_rules::PushRulesEventContent {
+					global: push::Ruleset::server_default(&user_id),
+				},
+			})
+			.expect("to json always works"),
+		)?;
+	}
 
 	// Inhibit login does not work for guests
 	if !is_guest && body.inhibit_login {
@@ -278,24 +310,41 @@ pub(crate) async fn register_route(
 		});
 	}
 
-	// Generate new device id if the user didn't specify one
-	let device_id = if is_guest {
-		None
-	} else {
-		body.device_id.clone()
-	}
-	.unwrap_or_else(|| utils::random_string(DEVICE_ID_LENGTH).into());
-
 	// Generate new token for the device
 	let token = utils::random_string(TOKEN_LENGTH);
 
-	// Create device for this account
-	services()
-		.users
-		.create_device(&user_id, &device_id, &token, body.initial_device_display_name.clone())?;
+	let device_id = if let Some((_, device_id)) = upgraded_guest {
+		// Keep the guest's device, replacing its access token
+		services().users.set_token(&user_id, &device_id, &token)?;
+		device_id
+	} else {
+		// Generate new device id if the user didn't specify one
+		let device_id = if is_guest {
+			None
+		} else {
+			body.device_id.clone()
+		}
+		.unwrap_or_else(|| utils::random_string(DEVICE_ID_LENGTH).into());
+
+		// Create device for this account
+		services()
+			.users
+			.create_device(&user_id, &device_id, &token, body.initial_device_display_name.clone())?;
+
+		device_id
+	};
+
+	let (refresh_token, expires_in) = if body.refresh_token {
+		let (refresh_token, expires_in) = services().users.issue_refresh_token(&user_id, &device_id)?;
+		(Some(refresh_token), Some(expires_in))
+	} else {
+		(None, None)
+	};
 
 	debug_info!(%user_id, %device_id, "User account was created");
 
+	let client = body.client;
+
 	// log in conduit admin channel if a non-guest user registered
 	if body.appservice_info.is_none() && !is_guest {
 		info!("New user \"{user_id}\" registered on this server.");
@@ -361,10 +410,10 @@ pub(crate) async fn register_route(
 	}
 
 	if body.appservice_info.is_none()
-		&& !services().globals.config.auto_join_rooms.is_empty()
+		&& !services().globals.auto_join_rooms().is_empty()
 		&& (services().globals.allow_guests_auto_join_rooms() || !is_guest)
 	{
-		for room in &services().globals.config.auto_join_rooms {
+		for room in &services().globals.auto_join_rooms() {
 			if !services()
 				.rooms
 				.state_cache
@@ -397,11 +446,42 @@ pub(crate) async fn register_route(
 		access_token: Some(token),
 		user_id,
 		device_id: Some(device_id),
-		refresh_token: None,
-		expires_in: None,
+		refresh_token,
+		expires_in,
 	})
 }
 
+/// The guest account and device of `guest_access_token`, which registering
+/// upgrades to a full account. A username may only be given if it is the
+/// guest's own.
+fn guest_to_upgrade(guest_access_token: &str, username: Option<&str>) -> Result<(OwnedUserId, OwnedDeviceId)> {
+	let (guest_id, device_id) = services()
+		.users
+		.find_from_token(guest_access_token)?
+		.ok_or(Error::BadRequest(
+			ErrorKind::UnknownToken {
+				soft_logout: false,
+			},
+			"Unknown guest access token.",
+		))?;
+
+	if !services().users.is_guest(&guest_id)? {
+		return Err(Error::BadRequest(
+			ErrorKind::forbidden(),
+			"Access token is not of a guest account.",
+		));
+	}
+
+	if username.is_some_and(|username| username.to_lowercase() != guest_id.localpart()) {
+		return Err(Error::BadRequest(
+			ErrorKind::InvalidUsername,
+			"Guest accounts can only be upgraded with their own username.",
+		));
+	}
+
+	Ok((guest_id, device_id.into()))
+}
+
 /// # `POST /_matrix/client/r0/account/password`
 ///
 /// Changes the password of this account.
@@ -419,9 +499,9 @@ pub(crate) async fn register_route(
 ///   last seen ts)
 /// - Forgets to-device events
 /// - Triggers device list updates
-#[tracing::instrument(skip_all, fields(%client), name = "change_password")]
+#[tracing::instrument(skip_all, fields(client = %body.client), name = "change_password")]
 pub(crate) async fn change_password_route(
-	InsecureClientIp(client): InsecureClientIp, body: Ruma<change_password::v3::Request>,
+	body: Ruma<change_password::v3::Request>,
 ) -> Result<change_password::v3::Response> {
 	let sender_user = body.sender_user.as_ref().expect("user is authenticated");
 	let sender_device = body.sender_device.as_ref().expect("user is authenticated");
@@ -454,20 +534,25 @@ pub(crate) async fn change_password_route(
 		return Err(Error::BadRequest(ErrorKind::NotJson, "Not json."));
 	}
 
-	services()
+	let result = services()
 		.users
-		.set_password(sender_user, Some(&body.new_password))?;
+		.set_password(sender_user, Some(&body.new_password));
+	services()
+		.admin
+		.audit
+		.record(sender_user.as_str(), "password_change", sender_user.as_str(), &result);
+	result?;
 
 	if body.logout_devices {
 		// Logout all devices except the current one
-		for id in services()
+		let device_ids: Vec<_> = services()
 			.users
 			.all_device_ids(sender_user)
 			.filter_map(Result::ok)
 			.filter(|id| id != sender_device)
-		{
-			services().users.remove_device(sender_user, &id)?;
-		}
+			.collect();
+
+		services().users.remove_devices(sender_user, &device_ids)?;
 	}
 
 	info!("User {sender_user} changed their password.");
@@ -493,7 +578,7 @@ pub(crate) async fn whoami_route(body: Ruma<whoami::v3::Request>) -> Result<whoa
 	Ok(whoami::v3::Response {
 		user_id: sender_user.clone(),
 		device_id,
-		is_guest: services().users.is_deactivated(sender_user)? && body.appservice_info.is_none(),
+		is_guest: services().users.is_guest(sender_user)? && body.appservice_info.is_none(),
 	})
 }
 
@@ -508,10 +593,8 @@ pub(crate) async fn whoami_route(body: Ruma<whoami::v3::Request>) -> Result<whoa
 /// - Forgets all to-device events
 /// - Triggers device list updates
 /// - Removes ability to log in again
-#[tracing::instrument(skip_all, fields(%client), name = "deactivate")]
-pub(crate) async fn deactivate_route(
-	InsecureClientIp(client): InsecureClientIp, body: Ruma<deactivate::v3::Request>,
-) -> Result<deactivate::v3::Response> {
+#[tracing::instrument(skip_all, fields(client = %body.client), name = "deactivate")]
+pub(crate) async fn deactivate_route(body: Ruma<deactivate::v3::Request>) -> Result<deactivate::v3::Response> {
 	let sender_user = body.sender_user.as_ref().expect("user is authenticated");
 	let sender_device = body.sender_device.as_ref().expect("user is authenticated");
 
@@ -619,19 +702,18 @@ pub(crate) async fn request_3pid_management_token_via_msisdn_route(
 ///
 /// Checks if the provided registration token is valid at the time of checking
 ///
-/// Currently does not have any ratelimiting, and this isn't very practical as
-/// there is only one registration token allowed.
+/// Currently does not have any ratelimiting.
 pub(crate) async fn check_registration_token_validity(
 	body: Ruma<check_registration_token_validity::v1::Request>,
 ) -> Result<check_registration_token_validity::v1::Response> {
-	let Some(reg_token) = services().globals.config.registration_token.clone() else {
+	if !services().uiaa.registration_tokens.is_required() {
 		return Err(Error::BadRequest(
 			ErrorKind::forbidden(),
 			"Server does not allow token registration.",
 		));
-	};
+	}
 
 	Ok(check_registration_token_validity::v1::Response {
-		valid: reg_token == body.token,
+		valid: services().uiaa.registration_tokens.is_valid(&body.token)?,
 	})
 }
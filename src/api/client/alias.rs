@@ -16,6 +16,13 @@ use crate::{service::server_is_ours, services, Error, Result, Ruma};
 pub(crate) async fn create_alias_route(body: Ruma<create_alias::v3::Request>) -> Result<create_alias::v3::Response> {
 	let sender_user = body.sender_user.as_ref().expect("user is authenticated");
 
+	if services().users.is_guest(sender_user)? {
+		return Err(Error::BadRequest(
+			ErrorKind::GuestAccessForbidden,
+			"Guests cannot set room aliases.",
+		));
+	}
+
 	service::rooms::alias::appservice_checks(&body.room_alias, &body.appservice_info).await?;
 
 	// this isn't apart of alias_checks or delete alias route because we should
@@ -106,7 +106,8 @@ pub(crate) async fn delete_backup_version_route(
 
 	services()
 		.key_backups
-		.delete_backup(sender_user, &body.version)?;
+		.delete_backup(sender_user, &body.version)
+		.await?;
 
 	Ok(delete_backup_version::v3::Response {})
 }
@@ -140,7 +141,8 @@ pub(crate) async fn add_backup_keys_route(
 		for (session_id, key_data) in &room.sessions {
 			services()
 				.key_backups
-				.add_key(sender_user, &body.version, room_id, session_id, key_data)?;
+				.add_key(sender_user, &body.version, room_id, session_id, key_data)
+				.await?;
 		}
 	}
 
@@ -185,7 +187,8 @@ pub(crate) async fn add_backup_keys_for_room_route(
 	for (session_id, key_data) in &body.sessions {
 		services()
 			.key_backups
-			.add_key(sender_user, &body.version, &body.room_id, session_id, key_data)?;
+			.add_key(sender_user, &body.version, &body.room_id, session_id, key_data)
+			.await?;
 	}
 
 	Ok(add_backup_keys_for_room::v3::Response {
@@ -228,7 +231,8 @@ pub(crate) async fn add_backup_keys_for_session_route(
 
 	services()
 		.key_backups
-		.add_key(sender_user, &body.version, &body.room_id, &body.session_id, &body.session_data)?;
+		.add_key(sender_user, &body.version, &body.room_id, &body.session_id, &body.session_data)
+		.await?;
 
 	Ok(add_backup_keys_for_session::v3::Response {
 		count: (UInt::try_from(
@@ -303,7 +307,8 @@ pub(crate) async fn delete_backup_keys_route(
 
 	services()
 		.key_backups
-		.delete_all_keys(sender_user, &body.version)?;
+		.delete_all_keys(sender_user, &body.version)
+		.await?;
 
 	Ok(delete_backup_keys::v3::Response {
 		count: (UInt::try_from(
@@ -328,7 +333,8 @@ pub(crate) async fn delete_backup_keys_for_room_route(
 
 	services()
 		.key_backups
-		.delete_room_keys(sender_user, &body.version, &body.room_id)?;
+		.delete_room_keys(sender_user, &body.version, &body.room_id)
+		.await?;
 
 	Ok(delete_backup_keys_for_room::v3::Response {
 		count: (UInt::try_from(
@@ -353,7 +359,8 @@ pub(crate) async fn delete_backup_keys_for_session_route(
 
 	services()
 		.key_backups
-		.delete_room_key(sender_user, &body.version, &body.room_id, &body.session_id)?;
+		.delete_room_key(sender_user, &body.version, &body.room_id, &body.session_id)
+		.await?;
 
 	Ok(delete_backup_keys_for_session::v3::Response {
 		count: (UInt::try_from(
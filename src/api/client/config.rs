@@ -3,7 +3,7 @@ use ruma::{
 		config::{get_global_account_data, get_room_account_data, set_global_account_data, set_room_account_data},
 		error::ErrorKind,
 	},
-	events::{AnyGlobalAccountDataEventContent, AnyRoomAccountDataEventContent},
+	events::{AnyGlobalAccountDataEventContent, AnyRoomAccountDataEventContent, GlobalAccountDataEventType},
 	serde::Raw,
 	OwnedUserId, RoomId,
 };
@@ -91,17 +91,19 @@ fn set_account_data(
 	let data: serde_json::Value =
 		serde_json::from_str(data.get()).map_err(|_| Error::BadRequest(ErrorKind::BadJson, "Data is invalid."))?;
 
-	services().account_data.update(
-		room_id,
-		sender_user,
-		event_type.into(),
-		&json!({
-			"type": event_type,
-			"content": data,
-		}),
-	)?;
+	let event = json!({
+		"type": event_type,
+		"content": data,
+	});
+
+	// m.direct is also updated by the server when accepting direct invites
+	if room_id.is_none() && event_type == GlobalAccountDataEventType::Direct.to_string() {
+		return services().account_data.set_direct(sender_user, &event);
+	}
 
-	Ok(())
+	services()
+		.account_data
+		.update(room_id, sender_user, event_type.into(), &event)
 }
 
 #[derive(Deserialize)]
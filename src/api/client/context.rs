@@ -6,6 +6,7 @@ use ruma::{
 };
 use tracing::error;
 
+use super::{ignored_filter, pagination::Paginated};
 use crate::{services, Error, Result, Ruma};
 
 /// # `GET /_matrix/client/r0/rooms/{roomId}/context`
@@ -62,23 +63,28 @@ pub(crate) async fn get_context_route(body: Ruma<get_context::v3::Request>) -> R
 		lazy_loaded.insert(base_event.sender.as_str().to_owned());
 	}
 
-	// Use limit or else 10, with maximum 100
-	let limit = usize::try_from(body.limit).unwrap_or(10).min(100);
+	let limit = Paginated::Context.limit(Some(body.limit));
 
+	let mut base_event = (*base_event).clone();
+	services()
+		.rooms
+		.threads
+		.add_bundled_aggregation(sender_user, &mut base_event)?;
 	let base_event = base_event.to_room_event();
 
-	let events_before: Vec<_> = services()
+	let mut events_before: Vec<_> = services()
 		.rooms
 		.timeline
 		.pdus_until(sender_user, &room_id, base_token)?
 		.take(limit / 2)
 		.filter_map(Result::ok) // Remove buggy events
 		.filter(|(_, pdu)| {
-			services()
-				.rooms
-				.state_accessor
-				.user_can_see_event(sender_user, &room_id, &pdu.event_id)
-				.unwrap_or(false)
+			ignored_filter(pdu, sender_user)
+				&& services()
+					.rooms
+					.state_accessor
+					.user_can_see_event(sender_user, &room_id, &pdu.event_id)
+					.unwrap_or(false)
 		})
 		.collect();
 
@@ -98,23 +104,31 @@ pub(crate) async fn get_context_route(body: Ruma<get_context::v3::Request>) -> R
 		.last()
 		.map_or_else(|| base_token.stringify(), |(count, _)| count.stringify());
 
+	for (_, pdu) in &mut events_before {
+		services()
+			.rooms
+			.threads
+			.add_bundled_aggregation(sender_user, pdu)?;
+	}
+
 	let events_before: Vec<_> = events_before
 		.into_iter()
 		.map(|(_, pdu)| pdu.to_room_event())
 		.collect();
 
-	let events_after: Vec<_> = services()
+	let mut events_after: Vec<_> = services()
 		.rooms
 		.timeline
 		.pdus_after(sender_user, &room_id, base_token)?
 		.take(limit / 2)
 		.filter_map(Result::ok) // Remove buggy events
 		.filter(|(_, pdu)| {
-			services()
-				.rooms
-				.state_accessor
-				.user_can_see_event(sender_user, &room_id, &pdu.event_id)
-				.unwrap_or(false)
+			ignored_filter(pdu, sender_user)
+				&& services()
+					.rooms
+					.state_accessor
+					.user_can_see_event(sender_user, &room_id, &pdu.event_id)
+					.unwrap_or(false)
 		})
 		.collect();
 
@@ -157,6 +171,13 @@ pub(crate) async fn get_context_route(body: Ruma<get_context::v3::Request>) -> R
 		.last()
 		.map_or_else(|| base_token.stringify(), |(count, _)| count.stringify());
 
+	for (_, pdu) in &mut events_after {
+		services()
+			.rooms
+			.threads
+			.add_bundled_aggregation(sender_user, pdu)?;
+	}
+
 	let events_after: Vec<_> = events_after
 		.into_iter()
 		.map(|(_, pdu)| pdu.to_room_event())
@@ -0,0 +1,139 @@
+use ruma::api::client::{
+	dehydrated_device::{delete_dehydrated_device, get_dehydrated_device, get_events, put_dehydrated_device},
+	error::ErrorKind,
+};
+use service::users::DehydratedDevice;
+
+use crate::{services, Error, Result, Ruma};
+
+/// Maximum number of to-device events returned in one page of a dehydrated
+/// device's events
+const MAX_EVENTS: usize = 100;
+
+/// # `PUT /_matrix/client/unstable/org.matrix.msc3814.v1/dehydrated_device`
+///
+/// Uploads a dehydrated device with its device keys and one-time keys.
+///
+/// - Replaces any previous dehydrated device, dropping its keys and pending
+///   to-device messages
+pub(crate) async fn put_dehydrated_device_route(
+	body: Ruma<put_dehydrated_device::unstable::Request>,
+) -> Result<put_dehydrated_device::unstable::Response> {
+	let sender_user = body.sender_user.as_ref().expect("user is authenticated");
+
+	if services()
+		.users
+		.get_device_metadata(sender_user, &body.device_id)?
+		.is_some()
+	{
+		return Err(Error::BadRequest(
+			ErrorKind::InvalidParam,
+			"Device ID is already in use by one of your devices.",
+		));
+	}
+
+	services().users.set_dehydrated_device(
+		sender_user,
+		&DehydratedDevice {
+			device_id: body.device_id.clone(),
+			display_name: body.initial_device_display_name.clone(),
+			device_data: body.device_data.clone(),
+		},
+	)?;
+
+	services()
+		.users
+		.add_device_keys(sender_user, &body.device_id, &body.device_keys)?;
+
+	for (key_key, key_value) in &body.one_time_keys {
+		services()
+			.users
+			.add_one_time_key(sender_user, &body.device_id, key_key, key_value)?;
+	}
+
+	Ok(put_dehydrated_device::unstable::Response {
+		device_id: body.device_id.clone(),
+	})
+}
+
+/// # `GET /_matrix/client/unstable/org.matrix.msc3814.v1/dehydrated_device`
+///
+/// Gets the sender user's dehydrated device, if any.
+pub(crate) async fn get_dehydrated_device_route(
+	body: Ruma<get_dehydrated_device::unstable::Request>,
+) -> Result<get_dehydrated_device::unstable::Response> {
+	let sender_user = body.sender_user.as_ref().expect("user is authenticated");
+
+	let device = services()
+		.users
+		.get_dehydrated_device(sender_user)?
+		.ok_or(Error::BadRequest(ErrorKind::NotFound, "No dehydrated device found."))?;
+
+	Ok(get_dehydrated_device::unstable::Response {
+		device_id: device.device_id,
+		device_data: device.device_data,
+	})
+}
+
+/// # `DELETE /_matrix/client/unstable/org.matrix.msc3814.v1/dehydrated_device`
+///
+/// Deletes the sender user's dehydrated device with its keys and pending
+/// to-device messages.
+pub(crate) async fn delete_dehydrated_device_route(
+	body: Ruma<delete_dehydrated_device::unstable::Request>,
+) -> Result<delete_dehydrated_device::unstable::Response> {
+	let sender_user = body.sender_user.as_ref().expect("user is authenticated");
+
+	let device_id = services()
+		.users
+		.remove_dehydrated_device(sender_user)?
+		.ok_or(Error::BadRequest(ErrorKind::NotFound, "No dehydrated device found."))?;
+
+	Ok(delete_dehydrated_device::unstable::Response {
+		device_id,
+	})
+}
+
+/// # `POST /_matrix/client/unstable/org.matrix.msc3814.v1/dehydrated_device/{deviceId}/events`
+///
+/// Pages through the to-device messages which accumulated for the dehydrated
+/// device while it was not in use, so a new session can rehydrate it.
+///
+/// - Messages before `next_batch` are deleted, as the client received them
+pub(crate) async fn get_dehydrated_events_route(
+	body: Ruma<get_events::unstable::Request>,
+) -> Result<get_events::unstable::Response> {
+	let sender_user = body.sender_user.as_ref().expect("user is authenticated");
+
+	if services()
+		.users
+		.get_dehydrated_device(sender_user)?
+		.filter(|device| device.device_id == body.device_id)
+		.is_none()
+	{
+		return Err(Error::BadRequest(ErrorKind::forbidden(), "Not the user's dehydrated device."));
+	}
+
+	let since = body
+		.next_batch
+		.as_deref()
+		.map(str::parse::<u64>)
+		.transpose()
+		.map_err(|_| Error::BadRequest(ErrorKind::InvalidParam, "Invalid next_batch."))?
+		.unwrap_or(0);
+
+	services()
+		.users
+		.remove_to_device_events(sender_user, &body.device_id, since)?;
+
+	let events = services()
+		.users
+		.get_to_device_events_after(sender_user, &body.device_id, since, MAX_EVENTS)?;
+
+	let next_batch = events.last().map_or(since, |(count, _)| *count).to_string();
+
+	Ok(get_events::unstable::Response {
+		next_batch: Some(next_batch),
+		events: events.into_iter().map(|(_, event)| event).collect(),
+	})
+}
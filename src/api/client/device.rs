@@ -68,7 +68,7 @@ pub(crate) async fn update_device_route(body: Ruma<update_device::v3::Request>)
 /// - Invalidates access token
 /// - Deletes device metadata (device id, device display name, last seen ip,
 ///   last seen ts)
-/// - Forgets to-device events
+/// - Forgets to-device events, one-time keys and device keys
 /// - Triggers device list updates
 pub(crate) async fn delete_device_route(body: Ruma<delete_device::v3::Request>) -> Result<delete_device::v3::Response> {
 	let sender_user = body.sender_user.as_ref().expect("user is authenticated");
@@ -103,24 +103,32 @@ pub(crate) async fn delete_device_route(body: Ruma<delete_device::v3::Request>)
 		return Err(Error::BadRequest(ErrorKind::NotJson, "Not json."));
 	}
 
-	services()
-		.users
-		.remove_device(sender_user, &body.device_id)?;
+	let result = services().users.remove_device(sender_user, &body.device_id);
+	services().admin.audit.record(
+		sender_user.as_str(),
+		"device_delete",
+		&format!("{sender_user} {}", body.device_id),
+		&result,
+	);
+	result?;
+
+	services().appservice.flush_ephemeral(sender_user).await?;
 
 	Ok(delete_device::v3::Response {})
 }
 
-/// # `PUT /_matrix/client/r0/devices/{deviceId}`
+/// # `POST /_matrix/client/r0/delete_devices`
 ///
-/// Deletes the given device.
+/// Deletes the given devices.
 ///
-/// - Requires UIAA to verify user password
+/// - Requires UIAA to verify user password once for all devices
+/// - Ignores unknown device IDs
 ///
 /// For each device:
 /// - Invalidates access token
 /// - Deletes device metadata (device id, device display name, last seen ip,
 ///   last seen ts)
-/// - Forgets to-device events
+/// - Forgets to-device events, one-time keys and device keys
 /// - Triggers device list updates
 pub(crate) async fn delete_devices_route(
 	body: Ruma<delete_devices::v3::Request>,
@@ -157,9 +165,32 @@ pub(crate) async fn delete_devices_route(
 		return Err(Error::BadRequest(ErrorKind::NotJson, "Not json."));
 	}
 
+	let mut device_ids = Vec::with_capacity(body.devices.len());
 	for device_id in &body.devices {
-		services().users.remove_device(sender_user, device_id)?;
+		if services()
+			.users
+			.get_device_metadata(sender_user, device_id)?
+			.is_some()
+		{
+			device_ids.push(device_id.clone());
+		}
 	}
 
+	let result = services().users.remove_devices(sender_user, &device_ids);
+	let target = device_ids
+		.iter()
+		.map(|device_id| device_id.as_str())
+		.collect::<Vec<_>>()
+		.join(" ");
+	services().admin.audit.record(
+		sender_user.as_str(),
+		"device_delete",
+		&format!("{sender_user} {target}"),
+		&result,
+	);
+	result?;
+
+	services().appservice.flush_ephemeral(sender_user).await?;
+
 	Ok(delete_devices::v3::Response {})
 }
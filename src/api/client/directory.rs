@@ -1,4 +1,5 @@
-use axum_client_ip::InsecureClientIp;
+use std::{cmp::Reverse, fmt};
+
 use ruma::{
 	api::{
 		client::{
@@ -8,16 +9,17 @@ use ruma::{
 		},
 		federation,
 	},
-	directory::{Filter, PublicRoomJoinRule, PublicRoomsChunk, RoomNetwork},
+	directory::{Filter, PublicRoomJoinRule, PublicRoomsChunk, RoomNetwork, RoomTypeFilter},
 	events::{
 		room::{
 			avatar::RoomAvatarEventContent,
-			create::RoomCreateEventContent,
 			join_rules::{JoinRule, RoomJoinRulesEventContent},
+			message::RoomMessageEventContent,
 		},
 		StateEventType,
 	},
-	uint, ServerName, UInt,
+	room::RoomType,
+	uint, OwnedRoomId, RoomId, ServerName, UInt, UserId,
 };
 use tracing::{error, info, warn};
 
@@ -28,9 +30,15 @@ use crate::{service::server_is_ours, services, Error, Result, Ruma};
 /// Lists the public rooms on this server.
 ///
 /// - Rooms are ordered by the number of joined members
-#[tracing::instrument(skip_all, fields(%client), name = "publicrooms")]
+/// - The search term matches words of the name, topic and canonical alias
+///   starting with each of its words
+/// - Pagination tokens refer to a room, so pages stay stable when rooms are
+///   published or removed in between
+/// - The `room_types` filter selects rooms by the type recorded when they were
+///   published, such as spaces
+#[tracing::instrument(skip_all, fields(client = %body.client), name = "publicrooms")]
 pub(crate) async fn get_public_rooms_filtered_route(
-	InsecureClientIp(client): InsecureClientIp, body: Ruma<get_public_rooms_filtered::v3::Request>,
+	body: Ruma<get_public_rooms_filtered::v3::Request>,
 ) -> Result<get_public_rooms_filtered::v3::Response> {
 	if let Some(server) = &body.server {
 		if services()
@@ -66,9 +74,9 @@ pub(crate) async fn get_public_rooms_filtered_route(
 /// Lists the public rooms on this server.
 ///
 /// - Rooms are ordered by the number of joined members
-#[tracing::instrument(skip_all, fields(%client), name = "publicrooms")]
+#[tracing::instrument(skip_all, fields(client = %body.client), name = "publicrooms")]
 pub(crate) async fn get_public_rooms_route(
-	InsecureClientIp(client): InsecureClientIp, body: Ruma<get_public_rooms::v3::Request>,
+	body: Ruma<get_public_rooms::v3::Request>,
 ) -> Result<get_public_rooms::v3::Response> {
 	if let Some(server) = &body.server {
 		if services()
@@ -108,10 +116,14 @@ pub(crate) async fn get_public_rooms_route(
 ///
 /// Sets the visibility of a given room in the room directory.
 ///
-/// - TODO: Access control checks
-#[tracing::instrument(skip_all, fields(%client), name = "room_directory")]
+/// - Requires being joined and the power level to send
+///   `m.room.canonical_alias`, unless the sender is a server admin
+/// - With `lockdown_public_room_directory` only server admins can publish
+/// - Publishing is announced in the admin room
+/// - Unpublishing removes the room from the directory search index
+#[tracing::instrument(skip_all, fields(client = %body.client), name = "room_directory")]
 pub(crate) async fn set_room_visibility_route(
-	InsecureClientIp(client): InsecureClientIp, body: Ruma<set_room_visibility::v3::Request>,
+	body: Ruma<set_room_visibility::v3::Request>,
 ) -> Result<set_room_visibility::v3::Response> {
 	let sender_user = body.sender_user.as_ref().expect("user is authenticated");
 
@@ -120,9 +132,18 @@ pub(crate) async fn set_room_visibility_route(
 		return Err(Error::BadRequest(ErrorKind::NotFound, "Room not found"));
 	}
 
+	let is_admin = services().users.is_admin(sender_user)?;
+	if !is_admin && !user_can_change_visibility(sender_user, &body.room_id)? {
+		return Err(Error::BadRequest(
+			ErrorKind::forbidden(),
+			"You must be in the room and allowed to change its canonical alias to change its visibility in the room \
+			 directory.",
+		));
+	}
+
 	match &body.visibility {
 		room::Visibility::Public => {
-			if services().globals.config.lockdown_public_room_directory && !services().users.is_admin(sender_user)? {
+			if services().globals.config.lockdown_public_room_directory && !is_admin {
 				info!(
 					"Non-admin user {sender_user} tried to publish {0} to the room directory while \
 					 \"lockdown_public_room_directory\" is enabled",
@@ -131,12 +152,23 @@ pub(crate) async fn set_room_visibility_route(
 
 				return Err(Error::BadRequest(
 					ErrorKind::forbidden(),
-					"Publishing rooms to the room directory is not allowed",
+					"Publishing rooms to the room directory is restricted to server admins on this server.",
 				));
 			}
 
+			if services().rooms.directory.is_public_room(&body.room_id)? {
+				return Ok(set_room_visibility::v3::Response {});
+			}
+
 			services().rooms.directory.set_public(&body.room_id)?;
 			info!("{sender_user} made {0} public", body.room_id);
+			services()
+				.admin
+				.send_message(RoomMessageEventContent::notice_plain(format!(
+					"{sender_user} published {} to the room directory.",
+					body.room_id
+				)))
+				.await;
 		},
 		room::Visibility::Private => services().rooms.directory.set_not_public(&body.room_id)?,
 		_ => {
@@ -150,6 +182,16 @@ pub(crate) async fn set_room_visibility_route(
 	Ok(set_room_visibility::v3::Response {})
 }
 
+/// Whether the user may publish or unpublish the room, which like Synapse
+/// requires being joined and the power level to send `m.room.canonical_alias`
+fn user_can_change_visibility(user_id: &UserId, room_id: &RoomId) -> Result<bool> {
+	Ok(services().rooms.state_cache.is_joined(user_id, room_id)?
+		&& services()
+			.rooms
+			.state_accessor
+			.user_can_send_state(user_id, room_id, StateEventType::RoomCanonicalAlias)?)
+}
+
 /// # `GET /_matrix/client/r0/directory/list/room/{roomId}`
 ///
 /// Gets the visibility of a given room in the room directory.
@@ -200,30 +242,30 @@ pub(crate) async fn get_public_rooms_filtered_helper(
 
 	// Use limit or else 10, with maximum 100
 	let limit = limit.map_or(10, u64::from);
-	let mut num_since: u64 = 0;
-
-	if let Some(s) = &since {
-		let mut characters = s.chars();
-		let backwards = match characters.next() {
-			Some('n') => false,
-			Some('p') => true,
-			_ => return Err(Error::BadRequest(ErrorKind::InvalidParam, "Invalid `since` token")),
-		};
+	let limit = usize::try_from(limit).unwrap_or(usize::MAX);
+	let since = since.map(DirectoryPosition::parse).transpose()?;
 
-		num_since = characters
-			.collect::<String>()
-			.parse()
-			.map_err(|_| Error::BadRequest(ErrorKind::InvalidParam, "Invalid `since` token."))?;
-
-		if backwards {
-			num_since = num_since.saturating_sub(limit);
-		}
-	}
+	let matching = filter
+		.generic_search_term
+		.as_deref()
+		.map(|search_term| services().rooms.directory.search(search_term))
+		.transpose()?
+		.flatten();
 
 	let mut all_rooms: Vec<_> = services()
 		.rooms
 		.directory
 		.public_rooms()
+		.filter(|room_id| match (&matching, room_id) {
+			(Some(matching), Ok(room_id)) => matching.contains(room_id),
+			_ => true,
+		})
+		// Our member count of rooms with incomplete membership would be wrong
+		.filter(|room_id| {
+			room_id
+				.as_ref()
+				.map_or(true, |room_id| !services().rooms.metadata.is_incomplete(room_id).unwrap_or(false))
+		})
 		.map(|room_id| {
 			let room_id = room_id?;
 
@@ -246,7 +288,7 @@ pub(crate) async fn get_public_rooms_filtered_helper(
 				topic: services()
 					.rooms
 					.state_accessor
-					.get_room_topic(&room_id)
+					.get_room_topic_summary(&room_id)
 					.unwrap_or(None),
 				world_readable: services().rooms.state_accessor.is_world_readable(&room_id)?,
 				guest_can_join: services()
@@ -284,82 +326,52 @@ pub(crate) async fn get_public_rooms_filtered_helper(
 					.transpose()?
 					.flatten()
 					.ok_or_else(|| Error::bad_database("Missing room join rule event for room."))?,
-				room_type: services()
-					.rooms
-					.state_accessor
-					.room_state_get(&room_id, &StateEventType::RoomCreate, "")?
-					.map(|s| {
-						serde_json::from_str::<RoomCreateEventContent>(s.content.get()).map_err(|e| {
-							error!("Invalid room create event in database: {}", e);
-							Error::BadDatabase("Invalid room create event in database.")
-						})
-					})
-					.transpose()?
-					.and_then(|e| e.room_type),
+				room_type: services().rooms.directory.room_type(&room_id)?,
 				room_id,
 			};
 			Ok(chunk)
 		})
 		.filter_map(|r: Result<_>| r.ok()) // Filter out buggy rooms
 		.filter(|chunk| {
-			if let Some(query) = filter.generic_search_term.as_ref().map(|q| q.to_lowercase()) {
-				if let Some(name) = &chunk.name {
-					if name.as_str().to_lowercase().contains(&query) {
-						return true;
-					}
-				}
-
-				if let Some(topic) = &chunk.topic {
-					if topic.to_lowercase().contains(&query) {
-						return true;
-					}
-				}
-
-				if let Some(canonical_alias) = &chunk.canonical_alias {
-					if canonical_alias.as_str().to_lowercase().contains(&query) {
-						return true;
-					}
-				}
-
-				false
-			} else {
-				// No search term
-				true
-			}
+			filter.room_types.is_empty()
+				|| filter
+					.room_types
+					.iter()
+					.any(|room_type| matches_room_type(room_type, chunk.room_type.as_ref()))
 		})
 		// We need to collect all, so we can sort by member count
 		.collect();
 
-	all_rooms.sort_by(|l, r| r.num_joined_members.cmp(&l.num_joined_members));
+	all_rooms.sort_by_cached_key(DirectoryPosition::of);
 
 	let total_room_count_estimate = UInt::try_from(all_rooms.len()).unwrap_or_else(|_| uint!(0));
 
-	let chunk: Vec<_> = all_rooms
-		.into_iter()
-		.skip(
-			num_since
-				.try_into()
-				.expect("num_since should not be this high"),
-		)
-		.take(limit.try_into().expect("limit should not be this high"))
-		.collect();
-
-	let prev_batch = if num_since == 0 {
-		None
-	} else {
-		Some(format!("p{num_since}"))
+	// Pages start after or end before the position of the token, so rooms
+	// published or removed in between do not shift them.
+	let (start, end) = match since {
+		None => (0, limit.min(all_rooms.len())),
+		Some((false, position)) => {
+			let start = all_rooms.partition_point(|chunk| DirectoryPosition::of(chunk) <= position);
+			(start, start.saturating_add(limit).min(all_rooms.len()))
+		},
+		Some((true, position)) => {
+			let end = all_rooms.partition_point(|chunk| DirectoryPosition::of(chunk) < position);
+			(end.saturating_sub(limit), end)
+		},
 	};
 
-	let next_batch = if chunk.len() < limit.try_into().unwrap() {
-		None
-	} else {
-		Some(format!(
-			"n{}",
-			num_since
-				.checked_add(limit)
-				.expect("num_since and limit should not be that large")
-		))
-	};
+	let prev_batch = all_rooms
+		.get(start)
+		.filter(|_| start > 0)
+		.map(|chunk| format!("p{}", DirectoryPosition::of(chunk)));
+
+	let next_batch = end
+		.checked_sub(1)
+		.and_then(|last| all_rooms.get(last))
+		.filter(|_| end < all_rooms.len())
+		.map(|chunk| format!("n{}", DirectoryPosition::of(chunk)));
+
+	let chunk: Vec<_> = all_rooms.drain(start..end).collect();
 
 	Ok(get_public_rooms_filtered::v3::Response {
 		chunk,
@@ -368,3 +380,46 @@ pub(crate) async fn get_public_rooms_filtered_helper(
 		total_room_count_estimate: Some(total_room_count_estimate),
 	})
 }
+
+/// Position of a room in the local directory, which is ordered by descending
+/// joined member count then room ID. Serialized into pagination tokens as
+/// `{count}:{room_id}` behind an `n` or `p` direction prefix.
+#[derive(PartialEq, Eq, PartialOrd, Ord)]
+struct DirectoryPosition(Reverse<u64>, OwnedRoomId);
+
+impl DirectoryPosition {
+	fn of(chunk: &PublicRoomsChunk) -> Self { Self(Reverse(chunk.num_joined_members.into()), chunk.room_id.clone()) }
+
+	/// Parses a `since` token into whether it pages backwards and the position
+	/// it refers to
+	fn parse(since: &str) -> Result<(bool, Self)> {
+		let invalid = || Error::BadRequest(ErrorKind::InvalidParam, "Invalid `since` token.");
+
+		let (backwards, position) = if let Some(position) = since.strip_prefix('n') {
+			(false, position)
+		} else if let Some(position) = since.strip_prefix('p') {
+			(true, position)
+		} else {
+			return Err(invalid());
+		};
+
+		let (count, room_id) = position.split_once(':').ok_or_else(invalid)?;
+		let count = count.parse().map_err(|_| invalid())?;
+		let room_id = RoomId::parse(room_id).map_err(|_| invalid())?;
+
+		Ok((backwards, Self(Reverse(count), room_id)))
+	}
+}
+
+impl fmt::Display for DirectoryPosition {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result { write!(f, "{}:{}", self.0 .0, self.1) }
+}
+
+/// Whether a room of type `room_type` is included by a `room_types` filter
+/// entry; custom room types are never matched.
+fn matches_room_type(filter: &RoomTypeFilter, room_type: Option<&RoomType>) -> bool {
+	matches!(
+		(filter, room_type),
+		(RoomTypeFilter::Default, None) | (RoomTypeFilter::Space, Some(RoomType::Space))
+	)
+}
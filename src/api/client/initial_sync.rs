@@ -0,0 +1,187 @@
+use conduit::PduCount;
+use ruma::{
+	api::client::{error::ErrorKind, room::Visibility},
+	events::room::member::MembershipState,
+};
+
+use super::ignored_filter;
+use crate::{services, Error, Result, Ruma};
+
+/// Messages of the room returned with its state
+const INITIAL_SYNC_MESSAGES: usize = 20;
+
+/// # `GET /_matrix/client/v3/rooms/{roomId}/initialSync`
+///
+/// Gets the current state and the latest messages of a room, which is how
+/// guests and other users peek into rooms they are not in.
+///
+/// - Members of the room and anyone for `world_readable` rooms may ask
+/// - Only messages the user is allowed to see are returned
+pub(crate) async fn room_initial_sync_route(
+	body: Ruma<room_initial_sync::Request>,
+) -> Result<room_initial_sync::Response> {
+	let sender_user = body.sender_user.as_ref().expect("user is authenticated");
+	let room_id = &body.room_id;
+
+	let membership = if services()
+		.rooms
+		.state_cache
+		.is_joined(sender_user, room_id)?
+	{
+		Some(MembershipState::Join)
+	} else if services()
+		.rooms
+		.state_cache
+		.is_invited(sender_user, room_id)?
+	{
+		Some(MembershipState::Invite)
+	} else if services().rooms.state_cache.is_left(sender_user, room_id)? {
+		Some(MembershipState::Leave)
+	} else {
+		None
+	};
+
+	let world_readable = services().rooms.state_accessor.is_world_readable(room_id)?;
+	if !may_peek(membership.as_ref(), world_readable) {
+		return Err(Error::BadRequest(
+			ErrorKind::forbidden(),
+			"You are not in this room and it is not world readable.",
+		));
+	}
+
+	let mut chunk: Vec<_> = services()
+		.rooms
+		.timeline
+		.pdus_until(sender_user, room_id, PduCount::max())?
+		.filter_map(Result::ok)
+		.filter(|(_, pdu)| {
+			ignored_filter(pdu, sender_user)
+				&& services()
+					.rooms
+					.state_accessor
+					.user_can_see_event(sender_user, room_id, &pdu.event_id)
+					.unwrap_or(false)
+		})
+		.take(INITIAL_SYNC_MESSAGES)
+		.collect();
+
+	// oldest first, paginating backwards from `start`
+	chunk.reverse();
+	let start = chunk.first().map_or(PduCount::max(), |(count, _)| *count);
+	let end = services()
+		.rooms
+		.timeline
+		.last_timeline_count(sender_user, room_id)?;
+
+	let state = services()
+		.rooms
+		.state_accessor
+		.room_state_full(room_id)
+		.await?
+		.values()
+		.map(|pdu| pdu.to_state_event())
+		.collect();
+
+	let account_data = services()
+		.account_data
+		.changes_since(Some(room_id), sender_user, 0)?
+		.into_values()
+		.map(|event| event.cast())
+		.collect();
+
+	let visibility = if services().rooms.directory.is_public_room(room_id)? {
+		Visibility::Public
+	} else {
+		Visibility::Private
+	};
+
+	Ok(room_initial_sync::Response {
+		room_id: room_id.clone(),
+		membership,
+		messages: room_initial_sync::PaginationChunk {
+			chunk: chunk.iter().map(|(_, pdu)| pdu.to_room_event()).collect(),
+			start: start.stringify(),
+			end: end.stringify(),
+		},
+		state,
+		visibility: Some(visibility),
+		account_data,
+	})
+}
+
+/// Members see the room they are in, everyone else only world readable rooms
+fn may_peek(membership: Option<&MembershipState>, world_readable: bool) -> bool {
+	world_readable || membership == Some(&MembershipState::Join)
+}
+
+pub(crate) mod room_initial_sync {
+	use ruma::{
+		api::{client::room::Visibility, request, response, Metadata},
+		events::{room::member::MembershipState, AnyRoomAccountDataEvent, AnyStateEvent, AnyTimelineEvent},
+		metadata,
+		serde::Raw,
+		OwnedRoomId,
+	};
+	use serde::{Deserialize, Serialize};
+
+	const METADATA: Metadata = metadata! {
+		method: GET,
+		rate_limited: false,
+		authentication: AccessToken,
+		history: {
+			1.0 => "/_matrix/client/r0/rooms/:room_id/initialSync",
+			1.1 => "/_matrix/client/v3/rooms/:room_id/initialSync",
+		}
+	};
+
+	#[request]
+	pub struct Request {
+		/// The room to get the state and messages of
+		#[ruma_api(path)]
+		pub room_id: OwnedRoomId,
+	}
+
+	#[response]
+	pub struct Response {
+		pub room_id: OwnedRoomId,
+
+		/// The user's membership, if they have one
+		#[serde(skip_serializing_if = "Option::is_none")]
+		pub membership: Option<MembershipState>,
+
+		pub messages: PaginationChunk,
+
+		pub state: Vec<Raw<AnyStateEvent>>,
+
+		/// Whether the room is published in the room directory
+		#[serde(skip_serializing_if = "Option::is_none")]
+		pub visibility: Option<Visibility>,
+
+		pub account_data: Vec<Raw<AnyRoomAccountDataEvent>>,
+	}
+
+	#[derive(Deserialize, Serialize)]
+	pub struct PaginationChunk {
+		pub chunk: Vec<Raw<AnyTimelineEvent>>,
+		pub start: String,
+		pub end: String,
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use ruma::events::room::member::MembershipState;
+
+	use super::may_peek;
+
+	#[test]
+	fn peeking() {
+		assert!(may_peek(None, true));
+		assert!(may_peek(Some(&MembershipState::Leave), true));
+		assert!(may_peek(Some(&MembershipState::Join), false));
+
+		assert!(!may_peek(None, false));
+		assert!(!may_peek(Some(&MembershipState::Invite), false));
+		assert!(!may_peek(Some(&MembershipState::Leave), false));
+	}
+}
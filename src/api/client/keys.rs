@@ -8,6 +8,7 @@ use futures_util::{stream::FuturesUnordered, StreamExt};
 use ruma::{
 	api::{
 		client::{
+			device::Device,
 			error::ErrorKind,
 			keys::{claim_keys, get_key_changes, get_keys, upload_keys, upload_signatures, upload_signing_keys},
 			uiaa::{AuthFlow, AuthType, UiaaInfo},
@@ -15,7 +16,7 @@ use ruma::{
 		federation,
 	},
 	serde::Raw,
-	DeviceKeyAlgorithm, OwnedDeviceId, OwnedUserId, UserId,
+	DeviceId, DeviceKeyAlgorithm, OwnedDeviceId, OwnedUserId, UserId,
 };
 use serde_json::json;
 use tracing::debug;
@@ -59,6 +60,10 @@ pub(crate) async fn upload_keys_route(body: Ruma<upload_keys::v3::Request>) -> R
 		}
 	}
 
+	if !body.one_time_keys.is_empty() || body.device_keys.is_some() {
+		services().appservice.flush_ephemeral(sender_user).await?;
+	}
+
 	Ok(upload_keys::v3::Response {
 		one_time_key_counts: services()
 			.users
@@ -141,6 +146,8 @@ pub(crate) async fn upload_signing_keys_route(
 			&body.user_signing_key,
 			true, // notify so that other users see the new keys
 		)?;
+
+		services().appservice.flush_ephemeral(sender_user).await?;
 	}
 
 	Ok(upload_signing_keys::v3::Response {})
@@ -182,6 +189,8 @@ pub(crate) async fn upload_signatures_route(
 					.sign_key(user_id, key_id, signature, sender_user)?;
 			}
 		}
+
+		services().appservice.flush_ephemeral(user_id).await?;
 	}
 
 	Ok(upload_signatures::v3::Response {
@@ -272,12 +281,10 @@ pub(crate) async fn get_keys_helper<F: Fn(&UserId) -> bool + Send>(
 
 		if device_ids.is_empty() {
 			let mut container = BTreeMap::new();
-			for device_id in services().users.all_device_ids(user_id) {
+			for device_id in services().users.all_device_ids_with_dehydrated(user_id) {
 				let device_id = device_id?;
 				if let Some(mut keys) = services().users.get_device_keys(user_id, &device_id)? {
-					let metadata = services()
-						.users
-						.get_device_metadata(user_id, &device_id)?
+					let metadata = device_metadata(user_id, &device_id)?
 						.ok_or_else(|| Error::bad_database("all_device_keys contained nonexistent device."))?;
 
 					add_unsigned_device_display_name(&mut keys, metadata, include_display_names)
@@ -291,13 +298,10 @@ pub(crate) async fn get_keys_helper<F: Fn(&UserId) -> bool + Send>(
 			for device_id in device_ids {
 				let mut container = BTreeMap::new();
 				if let Some(mut keys) = services().users.get_device_keys(user_id, device_id)? {
-					let metadata = services()
-						.users
-						.get_device_metadata(user_id, device_id)?
-						.ok_or(Error::BadRequest(
-							ErrorKind::InvalidParam,
-							"Tried to get keys for nonexistent device.",
-						))?;
+					let metadata = device_metadata(user_id, device_id)?.ok_or(Error::BadRequest(
+						ErrorKind::InvalidParam,
+						"Tried to get keys for nonexistent device.",
+					))?;
 
 					add_unsigned_device_display_name(&mut keys, metadata, include_display_names)
 						.map_err(|_| Error::bad_database("invalid device keys in database"))?;
@@ -423,6 +427,25 @@ pub(crate) async fn get_keys_helper<F: Fn(&UserId) -> bool + Send>(
 	})
 }
 
+/// Metadata of a device, or of the user's dehydrated device which has none of
+/// its own.
+fn device_metadata(user_id: &UserId, device_id: &DeviceId) -> Result<Option<Device>> {
+	if let Some(metadata) = services().users.get_device_metadata(user_id, device_id)? {
+		return Ok(Some(metadata));
+	}
+
+	Ok(services()
+		.users
+		.get_dehydrated_device(user_id)?
+		.filter(|device| device.device_id == device_id)
+		.map(|device| Device {
+			device_id: device.device_id,
+			display_name: device.display_name,
+			last_seen_ip: None,
+			last_seen_ts: None,
+		}))
+}
+
 fn add_unsigned_device_display_name(
 	keys: &mut Raw<ruma::encryption::DeviceKeys>, metadata: ruma::api::client::device::Device,
 	include_display_names: bool,
@@ -474,6 +497,11 @@ pub(crate) async fn claim_keys_helper(
 				container.insert(device_id.clone(), c);
 			}
 		}
+
+		if user_is_local(user_id) && !container.is_empty() {
+			services().appservice.flush_ephemeral(user_id).await?;
+		}
+
 		one_time_keys.insert(user_id.clone(), container);
 	}
 
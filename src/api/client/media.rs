@@ -1,5 +1,6 @@
 use std::{io::Cursor, sync::Arc, time::Duration};
 
+use http::Uri;
 use image::io::Reader as ImgReader;
 use ipaddress::IPAddress;
 use reqwest::Url;
@@ -340,8 +341,9 @@ pub(crate) async fn get_content_as_filename_v1_route(
 /// - Only redirects if `allow_redirect` is true
 /// - Uses client-provided `timeout_ms` if available, else defaults to 20
 ///   seconds
+/// - Sends animated thumbnails of animated images if `animated` is true
 pub(crate) async fn get_content_thumbnail_route(
-	body: Ruma<get_content_thumbnail::v3::Request>,
+	uri: Uri, body: Ruma<get_content_thumbnail::v3::Request>,
 ) -> Result<get_content_thumbnail::v3::Response> {
 	let mxc = format!("mxc://{}/{}", body.server_name, body.media_id);
 
@@ -359,6 +361,7 @@ pub(crate) async fn get_content_thumbnail_route(
 			body.height
 				.try_into()
 				.map_err(|_| Error::BadRequest(ErrorKind::InvalidParam, "Height is invalid."))?,
+			wants_animated(&uri),
 		)
 		.await?
 	{
@@ -372,33 +375,20 @@ pub(crate) async fn get_content_thumbnail_route(
 			content_disposition,
 		})
 	} else if !server_is_ours(&body.server_name) && body.allow_remote {
-		if services()
-			.globals
-			.prevent_media_downloads_from()
-			.contains(&body.server_name)
-		{
-			// we'll lie to the client and say the blocked server's media was not found and
-			// log. the client has no way of telling anyways so this is a security bonus.
-			debug_warn!("Received request for media `{}` on blocklisted server", mxc);
-			return Err(Error::BadRequest(ErrorKind::NotFound, "Media not found."));
-		}
-
-		match services()
-			.sending
-			.send_federation_request(
-				&body.server_name,
-				get_content_thumbnail::v3::Request {
-					allow_remote: body.allow_remote,
-					height: body.height,
-					width: body.width,
-					method: body.method.clone(),
-					server_name: body.server_name.clone(),
-					media_id: body.media_id.clone(),
-					timeout_ms: body.timeout_ms,
-					allow_redirect: body.allow_redirect,
-				},
-			)
-			.await
+		match fetch_remote_thumbnail(
+			&mxc,
+			get_content_thumbnail::v3::Request {
+				allow_remote: body.allow_remote,
+				height: body.height,
+				width: body.width,
+				method: body.method.clone(),
+				server_name: body.server_name.clone(),
+				media_id: body.media_id.clone(),
+				timeout_ms: body.timeout_ms,
+				allow_redirect: body.allow_redirect,
+			},
+		)
+		.await
 		{
 			Ok(get_thumbnail_response) => {
 				services()
@@ -451,13 +441,45 @@ pub(crate) async fn get_content_thumbnail_route(
 /// - Uses client-provided `timeout_ms` if available, else defaults to 20
 ///   seconds
 pub(crate) async fn get_content_thumbnail_v1_route(
-	body: Ruma<get_content_thumbnail::v3::Request>,
+	uri: Uri, body: Ruma<get_content_thumbnail::v3::Request>,
 ) -> Result<RumaResponse<get_content_thumbnail::v3::Response>> {
-	get_content_thumbnail_route(body).await.map(RumaResponse)
+	get_content_thumbnail_route(uri, body)
+		.await
+		.map(RumaResponse)
+}
+
+/// Whether the `animated` query parameter of a thumbnail request is set, which
+/// our version of ruma does not parse yet
+fn wants_animated(uri: &Uri) -> bool {
+	serde_html_form::from_str::<Vec<(String, String)>>(uri.query().unwrap_or_default()).is_ok_and(|query| {
+		query
+			.iter()
+			.any(|(key, value)| key == "animated" && value == "true")
+	})
 }
 
 async fn get_remote_content(
 	mxc: &str, server_name: &ruma::ServerName, media_id: String, allow_redirect: bool, timeout_ms: Duration,
+) -> Result<get_content::v3::Response, Error> {
+	let response = fetch_remote_content(mxc, server_name, media_id, allow_redirect, timeout_ms).await?;
+
+	services()
+		.media
+		.create(
+			None,
+			mxc,
+			response.content_disposition.as_deref(),
+			response.content_type.as_deref(),
+			&response.file,
+		)
+		.await?;
+
+	Ok(response)
+}
+
+/// Fetches remote media over federation without storing it.
+pub async fn fetch_remote_content(
+	mxc: &str, server_name: &ruma::ServerName, media_id: String, allow_redirect: bool, timeout_ms: Duration,
 ) -> Result<get_content::v3::Response, Error> {
 	if services()
 		.globals
@@ -490,17 +512,6 @@ async fn get_remote_content(
 		None,
 	));
 
-	services()
-		.media
-		.create(
-			None,
-			mxc,
-			content_disposition.as_deref(),
-			content_response.content_type.as_deref(),
-			&content_response.file,
-		)
-		.await?;
-
 	Ok(get_content::v3::Response {
 		file: content_response.file,
 		content_type: content_response.content_type,
@@ -510,6 +521,28 @@ async fn get_remote_content(
 	})
 }
 
+/// Fetches a remote media thumbnail over federation without storing it.
+pub async fn fetch_remote_thumbnail(
+	mxc: &str, request: get_content_thumbnail::v3::Request,
+) -> Result<get_content_thumbnail::v3::Response, Error> {
+	if services()
+		.globals
+		.prevent_media_downloads_from()
+		.contains(&request.server_name)
+	{
+		// we'll lie to the client and say the blocked server's media was not found and
+		// log. the client has no way of telling anyways so this is a security bonus.
+		debug_warn!("Received request for media `{mxc}` on blocklisted server");
+		return Err(Error::BadRequest(ErrorKind::NotFound, "Media not found."));
+	}
+
+	let server_name = request.server_name.clone();
+	services()
+		.sending
+		.send_federation_request(&server_name, request)
+		.await
+}
+
 async fn download_image(client: &reqwest::Client, url: &str) -> Result<UrlPreviewData> {
 	let image = client.get(url).send().await?.bytes().await?;
 	let mxc = format!(
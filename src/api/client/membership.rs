@@ -6,7 +6,6 @@ use std::{
 	time::{Duration, Instant},
 };
 
-use axum_client_ip::InsecureClientIp;
 use conduit::utils::mutex_map;
 use ruma::{
 	api::{
@@ -40,11 +39,27 @@ use crate::{
 	client::{update_avatar_url, update_displayname},
 	service::{
 		pdu::{gen_event_id_canonical_json, PduBuilder},
+		rooms::alias::cache::Resolution,
 		server_is_ours, user_is_local,
 	},
 	services, utils, Error, PduEvent, Result, Ruma,
 };
 
+/// Most servers a client may ask to join a room through
+const MAX_VIA_SERVERS: usize = 20;
+
+/// Guests may only join rooms which allow guest access
+fn guest_join_check(user_id: &UserId, room_id: &RoomId) -> Result<()> {
+	if services().users.is_guest(user_id)? && !services().rooms.state_accessor.guest_can_join(room_id)? {
+		return Err(Error::BadRequest(
+			ErrorKind::GuestAccessForbidden,
+			"Guests are not allowed to join this room.",
+		));
+	}
+
+	Ok(())
+}
+
 /// Checks if the room is banned in any way possible and the sender user is not
 /// an admin.
 ///
@@ -156,6 +171,42 @@ async fn banned_room_check(
 	Ok(())
 }
 
+/// Cleans up the servers a client asked to join through: drops duplicates and
+/// our own name, and keeps at most `MAX_VIA_SERVERS`. When none are left and
+/// the room is known, falls back to the server of the room ID and the servers
+/// of cached resolutions of aliases to the room.
+fn normalize_via(via: Vec<OwnedServerName>, room_id: Option<&RoomId>) -> Vec<OwnedServerName> {
+	let mut servers = dedup_via(via);
+	if servers.is_empty() {
+		if let Some(room_id) = room_id {
+			servers.extend(room_id.server_name().map(ToOwned::to_owned));
+			for (_, cached) in services().rooms.alias.remote_cache.all() {
+				if let Resolution::Resolved {
+					room_id: resolved,
+					servers: resolved_servers,
+				} = cached.result
+				{
+					if &*resolved == room_id {
+						servers.extend(resolved_servers);
+					}
+				}
+			}
+
+			servers = dedup_via(servers);
+		}
+	}
+
+	servers
+}
+
+fn dedup_via(via: Vec<OwnedServerName>) -> Vec<OwnedServerName> {
+	let mut seen = HashSet::new();
+	via.into_iter()
+		.filter(|server| !server_is_ours(server) && seen.insert(server.clone()))
+		.take(MAX_VIA_SERVERS)
+		.collect()
+}
+
 /// # `POST /_matrix/client/r0/rooms/{roomId}/join`
 ///
 /// Tries to join the sender user into a room.
@@ -164,13 +215,14 @@ async fn banned_room_check(
 ///   rules locally
 /// - If the server does not know about the room: asks other servers over
 ///   federation
-#[tracing::instrument(skip_all, fields(%client_ip), name = "join")]
+#[tracing::instrument(skip_all, fields(client_ip = %body.client), name = "join")]
 pub(crate) async fn join_room_by_id_route(
-	InsecureClientIp(client_ip): InsecureClientIp, body: Ruma<join_room_by_id::v3::Request>,
+	body: Ruma<join_room_by_id::v3::Request>,
 ) -> Result<join_room_by_id::v3::Response> {
 	let sender_user = body.sender_user.as_ref().expect("user is authenticated");
 
-	banned_room_check(sender_user, Some(&body.room_id), body.room_id.server_name(), client_ip).await?;
+	banned_room_check(sender_user, Some(&body.room_id), body.room_id.server_name(), body.client).await?;
+	guest_join_check(sender_user, &body.room_id)?;
 
 	// There is no body.server_name for /roomId/join
 	let mut servers = services()
@@ -217,18 +269,19 @@ pub(crate) async fn join_room_by_id_route(
 /// - If the server does not know about the room: use the server name query
 ///   param if specified. if not specified, asks other servers over federation
 ///   via room alias server name and room ID server name
-#[tracing::instrument(skip_all, fields(%client), name = "join")]
+#[tracing::instrument(skip_all, fields(client = %body.client), name = "join")]
 pub(crate) async fn join_room_by_id_or_alias_route(
-	InsecureClientIp(client): InsecureClientIp, body: Ruma<join_room_by_id_or_alias::v3::Request>,
+	body: Ruma<join_room_by_id_or_alias::v3::Request>,
 ) -> Result<join_room_by_id_or_alias::v3::Response> {
 	let sender_user = body.sender_user.as_deref().expect("user is authenticated");
+	let client = body.client;
 	let body = body.body;
 
 	let (servers, room_id) = match OwnedRoomId::try_from(body.room_id_or_alias) {
 		Ok(room_id) => {
 			banned_room_check(sender_user, Some(&room_id), room_id.server_name(), client).await?;
 
-			let mut servers = body.server_name.clone();
+			let mut servers = normalize_via(body.server_name, Some(&room_id));
 			servers.extend(
 				services()
 					.rooms
@@ -258,16 +311,17 @@ pub(crate) async fn join_room_by_id_or_alias_route(
 			(servers, room_id)
 		},
 		Err(room_alias) => {
+			let via = normalize_via(body.server_name, None);
 			let response = services()
 				.rooms
 				.alias
-				.resolve_alias(&room_alias, Some(&body.server_name.clone()))
+				.resolve_alias(&room_alias, Some(&via))
 				.await?;
 			let (room_id, mut pre_servers) = response;
 
 			banned_room_check(sender_user, Some(&room_id), Some(room_alias.server_name()), client).await?;
 
-			let mut servers = body.server_name;
+			let mut servers = via;
 			if let Some(pre_servers) = &mut pre_servers {
 				servers.append(pre_servers);
 			}
@@ -297,6 +351,8 @@ pub(crate) async fn join_room_by_id_or_alias_route(
 		},
 	};
 
+	guest_join_check(sender_user, &room_id)?;
+
 	let join_room_response = join_room_by_id_helper(
 		Some(sender_user),
 		&room_id,
@@ -327,12 +383,17 @@ pub(crate) async fn leave_room_route(body: Ruma<leave_room::v3::Request>) -> Res
 /// # `POST /_matrix/client/r0/rooms/{roomId}/invite`
 ///
 /// Tries to send an invite event into the room.
-#[tracing::instrument(skip_all, fields(%client), name = "invite")]
-pub(crate) async fn invite_user_route(
-	InsecureClientIp(client): InsecureClientIp, body: Ruma<invite_user::v3::Request>,
-) -> Result<invite_user::v3::Response> {
+#[tracing::instrument(skip_all, fields(client = %body.client), name = "invite")]
+pub(crate) async fn invite_user_route(body: Ruma<invite_user::v3::Request>) -> Result<invite_user::v3::Response> {
 	let sender_user = body.sender_user.as_ref().expect("user is authenticated");
 
+	if services().users.is_guest(sender_user)? {
+		return Err(Error::BadRequest(
+			ErrorKind::GuestAccessForbidden,
+			"Guests cannot invite users.",
+		));
+	}
+
 	if !services().users.is_admin(sender_user)? && services().globals.block_non_admin_invites() {
 		info!(
 			"User {sender_user} is not an admin and attempted to send an invite to room {}",
@@ -344,7 +405,7 @@ pub(crate) async fn invite_user_route(
 		));
 	}
 
-	banned_room_check(sender_user, Some(&body.room_id), body.room_id.server_name(), client).await?;
+	banned_room_check(sender_user, Some(&body.room_id), body.room_id.server_name(), body.client).await?;
 
 	if let invite_user::v3::InvitationRecipient::UserId {
 		user_id,
@@ -450,7 +511,7 @@ pub(crate) async fn ban_user_route(body: Ruma<ban_user::v3::Request>) -> Result<
 		.lock(&body.room_id)
 		.await;
 
-	services()
+	let result = services()
 		.rooms
 		.timeline
 		.build_and_append_pdu(
@@ -465,10 +526,18 @@ pub(crate) async fn ban_user_route(body: Ruma<ban_user::v3::Request>) -> Result<
 			&body.room_id,
 			&state_lock,
 		)
-		.await?;
+		.await;
 
 	drop(state_lock);
 
+	services().admin.audit.record(
+		sender_user.as_str(),
+		"room_ban",
+		&format!("{} in {}", body.user_id, body.room_id),
+		&result,
+	);
+	result?;
+
 	Ok(ban_user::v3::Response::new())
 }
 
@@ -554,20 +623,49 @@ pub(crate) async fn forget_room_route(body: Ruma<forget_room::v3::Request>) -> R
 
 /// # `POST /_matrix/client/r0/joined_rooms`
 ///
-/// Lists all rooms the user has joined.
+/// Lists all rooms the user has joined, leaving out rooms whose membership is
+/// still incomplete with `hide_incomplete_joined_rooms`.
 pub(crate) async fn joined_rooms_route(body: Ruma<joined_rooms::v3::Request>) -> Result<joined_rooms::v3::Response> {
 	let sender_user = body.sender_user.as_ref().expect("user is authenticated");
 
 	Ok(joined_rooms::v3::Response {
-		joined_rooms: services()
-			.rooms
-			.state_cache
-			.rooms_joined(sender_user)
-			.filter_map(Result::ok)
-			.collect(),
+		joined_rooms: listed_joined_rooms(
+			services()
+				.rooms
+				.state_cache
+				.rooms_joined(sender_user)
+				.filter_map(Result::ok),
+			services().globals.config.hide_incomplete_joined_rooms,
+			|room_id| services().rooms.metadata.is_incomplete(room_id),
+		),
 	})
 }
 
+/// The joined rooms to list, without the ones with incomplete membership when
+/// `hide_incomplete` is set
+fn listed_joined_rooms<I, F>(rooms: I, hide_incomplete: bool, is_incomplete: F) -> Vec<OwnedRoomId>
+where
+	I: Iterator<Item = OwnedRoomId>,
+	F: Fn(&RoomId) -> Result<bool>,
+{
+	rooms
+		.filter(|room_id| !hide_incomplete || !is_incomplete(room_id).unwrap_or(false))
+		.collect()
+}
+
+/// Rejects member listings for rooms whose membership we know is incomplete,
+/// with an error the client can retry once the room state has been resynced.
+fn check_membership_complete(room_id: &RoomId) -> Result<()> {
+	if services().rooms.metadata.is_incomplete(room_id)? {
+		return Err(Error::BadRequest(
+			ErrorKind::UnableToAuthorizeJoin,
+			"Room membership is still being synced from other servers, try again later.",
+		));
+	}
+
+	Ok(())
+}
+
 /// # `POST /_matrix/client/r0/rooms/{roomId}/members`
 ///
 /// Lists all joined users in a room (TODO: at a specific point in time, with a
@@ -590,6 +688,8 @@ pub(crate) async fn get_member_events_route(
 		));
 	}
 
+	check_membership_complete(&body.room_id)?;
+
 	Ok(get_member_events::v3::Response {
 		chunk: services()
 			.rooms
@@ -625,6 +725,8 @@ pub(crate) async fn joined_members_route(
 		));
 	}
 
+	check_membership_complete(&body.room_id)?;
+
 	let mut joined = BTreeMap::new();
 	for user_id in services()
 		.rooms
@@ -632,8 +734,19 @@ pub(crate) async fn joined_members_route(
 		.room_members(&body.room_id)
 		.filter_map(Result::ok)
 	{
-		let display_name = services().users.displayname(&user_id)?;
-		let avatar_url = services().users.avatar_url(&user_id)?;
+		let (display_name, avatar_url) = if user_is_local(&user_id) {
+			(services().users.displayname(&user_id)?, services().users.avatar_url(&user_id)?)
+		} else if let Some(profile) = services().users.profiles.cached(&user_id)? {
+			(profile.displayname, profile.avatar_url)
+		} else {
+			// no copy of the profile yet, use what the member event says
+			services()
+				.rooms
+				.state_accessor
+				.get_member(&body.room_id, &user_id)?
+				.map(|member| (member.displayname, member.avatar_url))
+				.unwrap_or_default()
+		};
 
 		joined.insert(
 			user_id,
@@ -772,18 +885,7 @@ async fn join_room_by_id_helper_remote(
 	let mut join_event = join_event_stub;
 
 	info!("Asking {remote_server} for send_join in room {room_id}");
-	let send_join_response = services()
-		.sending
-		.send_federation_request(
-			&remote_server,
-			federation::membership::create_join_event::v2::Request {
-				room_id: room_id.to_owned(),
-				event_id: event_id.to_owned(),
-				pdu: PduEvent::convert_to_outgoing_federation_event(join_event.clone()),
-				omit_members: false,
-			},
-		)
-		.await?;
+	let send_join_response = send_join_request(&remote_server, room_id, event_id, join_event.clone()).await?;
 
 	info!("send_join finished");
 
@@ -850,6 +952,10 @@ async fn join_room_by_id_helper_remote(
 							);
 						},
 					}
+				} else {
+					// the field is optional, the join is rejected later if the room needed the
+					// signature
+					debug!("Server {remote_server} did not send the signed join event back");
 				}
 			},
 			_ => {
@@ -959,6 +1065,10 @@ async fn join_room_by_id_helper_remote(
 	}
 
 	info!("Saving state from send_join");
+	// Until the state from send_join has been applied our member list for the room
+	// may be missing users, so flag it as incomplete in case we fail before then.
+	services().rooms.metadata.set_incomplete(room_id, true)?;
+
 	let (statehash_before_join, new, removed) = services().rooms.state_compressor.save_state(
 		room_id,
 		Arc::new(
@@ -980,6 +1090,8 @@ async fn join_room_by_id_helper_remote(
 		.force_state(room_id, statehash_before_join, new, removed, &state_lock)
 		.await?;
 
+	services().rooms.metadata.set_incomplete(room_id, false)?;
+
 	info!("Updating joined counts for new room");
 	services().rooms.state_cache.update_joined_count(room_id)?;
 
@@ -1201,18 +1313,7 @@ async fn join_room_by_id_helper_local(
 		// It has enough fields to be called a proper event now
 		let join_event = join_event_stub;
 
-		let send_join_response = services()
-			.sending
-			.send_federation_request(
-				&remote_server,
-				federation::membership::create_join_event::v2::Request {
-					room_id: room_id.to_owned(),
-					event_id: event_id.to_owned(),
-					pdu: PduEvent::convert_to_outgoing_federation_event(join_event.clone()),
-					omit_members: false,
-				},
-			)
-			.await?;
+		let send_join_response = send_join_request(&remote_server, room_id, event_id, join_event.clone()).await?;
 
 		if let Some(signed_raw) = send_join_response.room_state.event {
 			let Ok((signed_event_id, signed_value)) = gen_event_id_canonical_json(&signed_raw, &room_version_id) else {
@@ -1325,6 +1426,41 @@ async fn make_join_request(
 	make_join_response_and_server
 }
 
+/// Sends the join event to the resident server. We always ask for the full
+/// state as we can't handle partial state joins (MSC3706).
+async fn send_join_request(
+	remote_server: &ServerName, room_id: &RoomId, event_id: &EventId, join_event: CanonicalJsonObject,
+) -> Result<federation::membership::create_join_event::v2::Response> {
+	let response = services()
+		.sending
+		.send_federation_request(
+			remote_server,
+			federation::membership::create_join_event::v2::Request {
+				room_id: room_id.to_owned(),
+				event_id: event_id.to_owned(),
+				pdu: PduEvent::convert_to_outgoing_federation_event(join_event),
+				omit_members: false,
+			},
+		)
+		.await?;
+
+	check_full_state(&response.room_state)?;
+	Ok(response)
+}
+
+/// Rejects a send_join response which left out members although we asked for
+/// the full state. All other fields besides the state and auth chain are
+/// optional.
+fn check_full_state(room_state: &federation::membership::create_join_event::v2::RoomState) -> Result<()> {
+	if room_state.members_omitted {
+		return Err(Error::BadServerResponse(
+			"Server sent partial state in its send_join response although we asked for the full state.",
+		));
+	}
+
+	Ok(())
+}
+
 pub async fn validate_and_add_event_id(
 	pdu: &RawJsonValue, room_version: &RoomVersionId, pub_key_map: &RwLock<BTreeMap<String, BTreeMap<String, Base64>>>,
 ) -> Result<(OwnedEventId, CanonicalJsonObject)> {
@@ -1383,7 +1519,7 @@ pub async fn validate_and_add_event_id(
 	Ok((event_id, value))
 }
 
-pub(crate) async fn invite_helper(
+pub async fn invite_helper(
 	sender_user: &UserId, user_id: &UserId, room_id: &RoomId, reason: Option<String>, is_direct: bool,
 ) -> Result<()> {
 	if !services().users.is_admin(user_id)? && services().globals.block_non_admin_invites() {
@@ -1394,6 +1530,16 @@ pub(crate) async fn invite_helper(
 		));
 	}
 
+	if user_is_local(user_id) {
+		if let Some(reason) = services()
+			.rooms
+			.state_cache
+			.invite_refusal(sender_user, user_id)?
+		{
+			return Err(Error::BadRequest(ErrorKind::forbidden(), reason));
+		}
+	}
+
 	if !user_is_local(user_id) {
 		let (pdu, pdu_json, invite_room_state) = {
 			let state_lock = services().globals.roomid_mutex_state.lock(room_id).await;
@@ -1580,9 +1726,21 @@ pub async fn leave_room(user_id: &UserId, room_id: &RoomId, reason: Option<Strin
 		.state_cache
 		.server_in_room(services().globals.server_name(), room_id)?
 	{
-		if let Err(e) = remote_leave_room(user_id, room_id).await {
-			warn!("Failed to leave room {} remotely: {}", user_id, e);
-			// Don't tell the client about this error
+		let rejected_at = match remote_leave_room(user_id, room_id).await {
+			Ok(origin_server_ts) => origin_server_ts,
+			Err(e) => {
+				warn!("Failed to leave room {} remotely: {}", user_id, e);
+				// Don't tell the client about this error
+				utils::millis_since_unix_epoch()
+			},
+		};
+
+		// Some servers send the invite again after it was rejected
+		if services().rooms.state_cache.is_invited(user_id, room_id)? {
+			services()
+				.rooms
+				.state_cache
+				.mark_invite_rejected(user_id, room_id, rejected_at)?;
 		}
 
 		let last_state = services()
@@ -1658,7 +1816,9 @@ pub async fn leave_room(user_id: &UserId, room_id: &RoomId, reason: Option<Strin
 	Ok(())
 }
 
-async fn remote_leave_room(user_id: &UserId, room_id: &RoomId) -> Result<()> {
+/// Leaves a room we are not in through a remote server, returning the
+/// `origin_server_ts` of the leave event
+async fn remote_leave_room(user_id: &UserId, room_id: &RoomId) -> Result<u64> {
 	let mut make_leave_response_and_server = Err(Error::BadServerResponse("No server available to assist in leaving."));
 
 	let invite_state = services()
@@ -1728,10 +1888,11 @@ async fn remote_leave_room(user_id: &UserId, room_id: &RoomId) -> Result<()> {
 		"origin".to_owned(),
 		CanonicalJsonValue::String(services().globals.server_name().as_str().to_owned()),
 	);
+	let origin_server_ts = utils::millis_since_unix_epoch();
 	leave_event_stub.insert(
 		"origin_server_ts".to_owned(),
 		CanonicalJsonValue::Integer(
-			utils::millis_since_unix_epoch()
+			origin_server_ts
 				.try_into()
 				.expect("Timestamp is valid js_int value"),
 		),
@@ -1781,5 +1942,183 @@ async fn remote_leave_room(user_id: &UserId, room_id: &RoomId) -> Result<()> {
 		)
 		.await?;
 
-	Ok(())
+	Ok(origin_server_ts)
+}
+
+#[cfg(test)]
+mod tests {
+	use std::collections::{BTreeMap, BTreeSet};
+
+	use ruma::{
+		api::{federation::membership::create_join_event, IncomingResponse},
+		owned_room_id,
+		serde::Base64,
+		signatures::Verified,
+		CanonicalJsonObject, OwnedEventId, RoomVersionId,
+	};
+	use serde_json::value::RawValue as RawJsonValue;
+
+	use super::{check_full_state, listed_joined_rooms};
+	use crate::service::pdu::gen_event_id_canonical_json;
+
+	/// The key the fixture events are signed with, as `ed25519:test` of their
+	/// origin
+	const FIXTURE_KEY: &str = "GxhUKYTmDgUoeEirTh4LVqoWU2q/y9aPqIZ72Cnrg+0";
+
+	// The fixtures have the shape Synapse and Dendrite respond with (fields
+	// present, omitted and ordered as they send them). Their events are
+	// hashed and signed, and refer to each other by their real event IDs.
+	fn parse(body: &str) -> create_join_event::v2::Response {
+		let response = http::Response::builder()
+			.status(200)
+			.body(body.as_bytes())
+			.expect("valid response");
+		create_join_event::v2::Response::try_from_http_response(response).expect("send_join response parses")
+	}
+
+	/// The events with their IDs, after checking their hashes and signatures
+	fn verified(pdus: &[Box<RawJsonValue>], server: &str) -> Vec<(OwnedEventId, CanonicalJsonObject)> {
+		let keys = BTreeMap::from([(
+			server.to_owned(),
+			BTreeMap::from([("ed25519:test".to_owned(), Base64::parse(FIXTURE_KEY).unwrap())]),
+		)]);
+
+		pdus.iter()
+			.map(|pdu| {
+				let (event_id, value) = gen_event_id_canonical_json(pdu, &RoomVersionId::V10).unwrap();
+				let verified = ruma::signatures::verify_event(&keys, &value, &RoomVersionId::V10).unwrap();
+				assert_eq!(verified, Verified::All);
+				(event_id, value)
+			})
+			.collect()
+	}
+
+	fn state_keys(events: &[(OwnedEventId, CanonicalJsonObject)]) -> Vec<(String, String)> {
+		events
+			.iter()
+			.map(|(_, value)| {
+				(
+					value["type"].as_str().unwrap().to_owned(),
+					value["state_key"].as_str().unwrap().to_owned(),
+				)
+			})
+			.collect()
+	}
+
+	fn auth_events(events: &[(OwnedEventId, CanonicalJsonObject)]) -> BTreeSet<String> {
+		events
+			.iter()
+			.flat_map(|(_, value)| value["auth_events"].as_array().unwrap().iter())
+			.map(|event_id| event_id.as_str().unwrap().to_owned())
+			.collect()
+	}
+
+	fn ids(events: &[(OwnedEventId, CanonicalJsonObject)]) -> BTreeSet<String> {
+		events
+			.iter()
+			.map(|(event_id, _)| event_id.to_string())
+			.collect()
+	}
+
+	fn full_state(server: &str) -> Vec<(String, String)> {
+		let (alice, bob) = (format!("@alice:{server}"), format!("@bob:{server}"));
+		[
+			("m.room.create", ""),
+			("m.room.member", alice.as_str()),
+			("m.room.power_levels", ""),
+			("m.room.join_rules", ""),
+			("m.room.history_visibility", ""),
+			("m.room.member", bob.as_str()),
+			("m.room.name", ""),
+		]
+		.into_iter()
+		.map(|(kind, state_key)| (kind.to_owned(), state_key.to_owned()))
+		.collect()
+	}
+
+	#[test]
+	fn synapse_send_join_response() {
+		let response = parse(include_str!("fixtures/send_join_synapse.json"));
+		assert!(!response.room_state.members_omitted);
+		check_full_state(&response.room_state).unwrap();
+
+		let state = verified(&response.room_state.state, "synapse.example");
+		let auth_chain = verified(&response.room_state.auth_chain, "synapse.example");
+		assert_eq!(state_keys(&state), full_state("synapse.example"));
+
+		// the auth chain holds every auth event of the state, which all are
+		// current state themselves in this room
+		assert_eq!(ids(&auth_chain), auth_events(&state));
+		assert!(ids(&auth_chain).is_subset(&ids(&state)));
+
+		// the join event signed by the resident server is authorized by the
+		// state it sent
+		let event = response.room_state.event.unwrap();
+		let (_, join) = gen_event_id_canonical_json(&event, &RoomVersionId::V10).unwrap();
+		assert_eq!(join["state_key"].as_str(), Some("@carol:conduwuit.example"));
+		let join_auth_events: BTreeSet<_> = join["auth_events"]
+			.as_array()
+			.unwrap()
+			.iter()
+			.map(|event_id| event_id.as_str().unwrap().to_owned())
+			.collect();
+		assert!(join_auth_events.is_subset(&ids(&state)));
+	}
+
+	#[test]
+	fn dendrite_send_join_response() {
+		// no `event`, `members_omitted` nor `servers_in_room`
+		let response = parse(include_str!("fixtures/send_join_dendrite.json"));
+		assert!(response.room_state.event.is_none());
+		assert!(response.room_state.servers_in_room.is_none());
+		check_full_state(&response.room_state).unwrap();
+
+		let state = verified(&response.room_state.state, "dendrite.example");
+		let auth_chain = verified(&response.room_state.auth_chain, "dendrite.example");
+		assert_eq!(state_keys(&state), full_state("dendrite.example"));
+		assert_eq!(ids(&auth_chain), auth_events(&state));
+	}
+
+	#[test]
+	fn partial_state_is_rejected() {
+		let response = parse(include_str!("fixtures/send_join_partial.json"));
+		assert!(response.room_state.members_omitted);
+		assert_eq!(
+			response
+				.room_state
+				.servers_in_room
+				.map(|servers| servers.len()),
+			Some(1)
+		);
+		check_full_state(&response.room_state).unwrap_err();
+
+		// bob's membership is only found in the auth chain, which leaves out
+		// the events of the state
+		let state = verified(&response.room_state.state, "synapse.example");
+		let auth_chain = verified(&response.room_state.auth_chain, "synapse.example");
+		assert!(!state_keys(&state).contains(&("m.room.member".to_owned(), "@bob:synapse.example".to_owned())));
+		assert_eq!(
+			state_keys(&auth_chain),
+			[("m.room.member".to_owned(), "@bob:synapse.example".to_owned())]
+		);
+		assert!(ids(&auth_chain).is_disjoint(&ids(&state)));
+	}
+
+	#[test]
+	fn incomplete_rooms_hidden_on_opt_in() {
+		let complete = owned_room_id!("!complete:example.com");
+		let incomplete = owned_room_id!("!incomplete:example.com");
+		let rooms = || [complete.clone(), incomplete.clone()].into_iter();
+		let is_incomplete = |room_id: &ruma::RoomId| Ok(room_id == &*incomplete);
+
+		assert_eq!(
+			listed_joined_rooms(rooms(), false, is_incomplete),
+			[complete.clone(), incomplete.clone()]
+		);
+		assert_eq!(listed_joined_rooms(rooms(), true, is_incomplete), [complete.clone()]);
+
+		// rooms whose flag can't be read are listed
+		let unreadable = |_: &ruma::RoomId| Err(crate::Error::bad_database("unreadable flag"));
+		assert_eq!(listed_joined_rooms(rooms(), true, unreadable), [complete, incomplete]);
+	}
 }
@@ -12,6 +12,7 @@ use ruma::{
 };
 use serde_json::{from_str, Value};
 
+use super::pagination::Paginated;
 use crate::{service::pdu::PduBuilder, services, utils, Error, PduEvent, Result, Ruma};
 
 /// # `PUT /_matrix/client/v3/rooms/{roomId}/send/{eventType}/{txnId}`
@@ -72,6 +73,12 @@ pub(crate) async fn send_message_event_route(
 		});
 	}
 
+	if body.event_type == MessageLikeEventType::RoomMessage && services().globals.config.strict_message_validation {
+		let content: Value = from_str(body.body.body.json().get())
+			.map_err(|_| Error::BadRequest(ErrorKind::BadJson, "Invalid JSON body."))?;
+		validate_message_content(&content).map_err(|e| Error::BadRequest(ErrorKind::BadJson, e))?;
+	}
+
 	let mut unsigned = BTreeMap::new();
 	unsigned.insert("transaction_id".to_owned(), body.txn_id.to_string().into());
 
@@ -102,6 +109,93 @@ pub(crate) async fn send_message_event_route(
 	Ok(send_message_event::v3::Response::new((*event_id).to_owned()))
 }
 
+/// Fields which must be present in a message, depending on its `msgtype`
+struct MsgtypeRule {
+	msgtype: &'static str,
+
+	/// Each entry lists alternative fields of which at least one must be set,
+	/// with the error returned if none is
+	required: &'static [(&'static [&'static str], &'static str)],
+}
+
+const BODY: (&[&str], &str) = (&["body"], "Missing field `body` for this msgtype.");
+const MEDIA: (&[&str], &str) = (&["url", "file"], "Missing field `url` or `file` for this msgtype.");
+
+/// Required fields of the known msgtypes; other msgtypes are not checked
+const MSGTYPE_RULES: &[MsgtypeRule] = &[
+	MsgtypeRule {
+		msgtype: "m.text",
+		required: &[BODY],
+	},
+	MsgtypeRule {
+		msgtype: "m.notice",
+		required: &[BODY],
+	},
+	MsgtypeRule {
+		msgtype: "m.emote",
+		required: &[BODY],
+	},
+	MsgtypeRule {
+		msgtype: "m.image",
+		required: &[BODY, MEDIA],
+	},
+	MsgtypeRule {
+		msgtype: "m.file",
+		required: &[BODY, MEDIA],
+	},
+	MsgtypeRule {
+		msgtype: "m.audio",
+		required: &[BODY, MEDIA],
+	},
+	MsgtypeRule {
+		msgtype: "m.video",
+		required: &[BODY, MEDIA],
+	},
+	MsgtypeRule {
+		msgtype: "m.location",
+		required: &[BODY, (&["geo_uri"], "Missing field `geo_uri` for this msgtype.")],
+	},
+	MsgtypeRule {
+		msgtype: "m.server_notice",
+		required: &[
+			BODY,
+			(&["server_notice_type"], "Missing field `server_notice_type` for this msgtype."),
+		],
+	},
+];
+
+/// Checks an `m.room.message` content against the rule for its `msgtype`.
+/// Present `info` and `body` fields must have the right JSON type.
+fn validate_message_content(content: &Value) -> Result<(), &'static str> {
+	let Some(msgtype) = content.get("msgtype") else {
+		return Err("Missing field `msgtype`.");
+	};
+
+	let Some(msgtype) = msgtype.as_str() else {
+		return Err("Field `msgtype` must be a string.");
+	};
+
+	let Some(rule) = MSGTYPE_RULES.iter().find(|rule| rule.msgtype == msgtype) else {
+		return Ok(());
+	};
+
+	for (fields, error) in rule.required {
+		if !fields.iter().any(|field| content.get(field).is_some()) {
+			return Err(error);
+		}
+	}
+
+	if content.get("body").is_some_and(|body| !body.is_string()) {
+		return Err("Field `body` must be a string.");
+	}
+
+	if content.get("info").is_some_and(|info| !info.is_object()) {
+		return Err("Field `info` must be an object.");
+	}
+
+	Ok(())
+}
+
 /// # `GET /_matrix/client/r0/rooms/{roomId}/messages`
 ///
 /// Allows paginating through room history.
@@ -133,7 +227,7 @@ pub(crate) async fn get_message_events_route(
 		.lazy_load_confirm_delivery(sender_user, sender_device, &body.room_id, from)
 		.await?;
 
-	let limit = usize::try_from(body.limit).unwrap_or(10).min(100);
+	let limit = Paginated::Messages.limit(Some(body.limit));
 
 	let next_token;
 
@@ -143,12 +237,12 @@ pub(crate) async fn get_message_events_route(
 
 	match body.dir {
 		ruma::api::Direction::Forward => {
-			let events_after: Vec<_> = services()
+			let mut events_after: Vec<_> = services()
 				.rooms
 				.timeline
 				.pdus_after(sender_user, &body.room_id, from)?
 				.filter_map(Result::ok) // Filter out buggy events
-				.filter(|(_, pdu)| { contains_url_filter(pdu, &body.filter) && visibility_filter(pdu, sender_user, &body.room_id)
+				.filter(|(_, pdu)| { contains_url_filter(pdu, &body.filter) && visibility_filter(pdu, sender_user, &body.room_id) && ignored_filter(pdu, sender_user)
 
 				})
 				.take_while(|&(k, _)| Some(k) != to) // Stop at `to`
@@ -175,6 +269,13 @@ pub(crate) async fn get_message_events_route(
 
 			next_token = events_after.last().map(|(count, _)| count).copied();
 
+			for (_, pdu) in &mut events_after {
+				services()
+					.rooms
+					.threads
+					.add_bundled_aggregation(sender_user, pdu)?;
+			}
+
 			let events_after: Vec<_> = events_after
 				.into_iter()
 				.map(|(_, pdu)| pdu.to_room_event())
@@ -190,12 +291,12 @@ pub(crate) async fn get_message_events_route(
 				.timeline
 				.backfill_if_required(&body.room_id, from)
 				.await?;
-			let events_before: Vec<_> = services()
+			let mut events_before: Vec<_> = services()
 				.rooms
 				.timeline
 				.pdus_until(sender_user, &body.room_id, from)?
 				.filter_map(Result::ok) // Filter out buggy events
-				.filter(|(_, pdu)| {contains_url_filter(pdu, &body.filter) && visibility_filter(pdu, sender_user, &body.room_id)})
+				.filter(|(_, pdu)| {contains_url_filter(pdu, &body.filter) && visibility_filter(pdu, sender_user, &body.room_id) && ignored_filter(pdu, sender_user)})
 				.take_while(|&(k, _)| Some(k) != to) // Stop at `to`
 				.take(limit)
 				.collect();
@@ -220,6 +321,13 @@ pub(crate) async fn get_message_events_route(
 
 			next_token = events_before.last().map(|(count, _)| count).copied();
 
+			for (_, pdu) in &mut events_before {
+				services()
+					.rooms
+					.threads
+					.add_bundled_aggregation(sender_user, pdu)?;
+			}
+
 			let events_before: Vec<_> = events_before
 				.into_iter()
 				.map(|(_, pdu)| pdu.to_room_event())
@@ -264,6 +372,15 @@ fn visibility_filter(pdu: &PduEvent, user_id: &UserId, room_id: &RoomId) -> bool
 		.unwrap_or(false)
 }
 
+/// Hides events sent by users that `user_id` ignores. State events are kept so
+/// clients still see changes to the room.
+pub(crate) fn ignored_filter(pdu: &PduEvent, user_id: &UserId) -> bool {
+	pdu.state_key.is_some()
+		|| !services()
+			.account_data
+			.user_is_ignored(&pdu.sender, user_id)
+}
+
 fn contains_url_filter(pdu: &PduEvent, filter: &RoomEventFilter) -> bool {
 	if filter.url_filter.is_none() {
 		return true;
@@ -276,3 +393,54 @@ fn contains_url_filter(pdu: &PduEvent, filter: &RoomEventFilter) -> bool {
 		None => true,
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use serde_json::json;
+
+	use super::validate_message_content;
+
+	#[test]
+	fn valid_messages() {
+		validate_message_content(&json!({"msgtype": "m.text", "body": "hi"})).unwrap();
+		validate_message_content(&json!({
+			"msgtype": "m.image",
+			"body": "cat.png",
+			"url": "mxc://example.com/cat",
+			"info": {"mimetype": "image/png"},
+		}))
+		.unwrap();
+		validate_message_content(&json!({"msgtype": "m.file", "body": "x", "file": {}})).unwrap();
+	}
+
+	#[test]
+	fn missing_fields() {
+		assert_eq!(
+			validate_message_content(&json!({"msgtype": "m.text"})),
+			Err("Missing field `body` for this msgtype.")
+		);
+		assert_eq!(
+			validate_message_content(&json!({"msgtype": "m.image", "body": "cat.png"})),
+			Err("Missing field `url` or `file` for this msgtype.")
+		);
+		assert_eq!(
+			validate_message_content(&json!({"body": "hi"})),
+			Err("Missing field `msgtype`.")
+		);
+	}
+
+	#[test]
+	fn wrong_types() {
+		assert_eq!(
+			validate_message_content(&json!({"msgtype": "m.text", "body": 1})),
+			Err("Field `body` must be a string.")
+		);
+		assert_eq!(
+			validate_message_content(&json!({"msgtype": "m.video", "body": "v", "url": "mxc://a/b", "info": []})),
+			Err("Field `info` must be an object.")
+		);
+	}
+
+	#[test]
+	fn custom_msgtypes_pass() { validate_message_content(&json!({"msgtype": "org.example.custom"})).unwrap(); }
+}
@@ -4,13 +4,16 @@ pub(super) mod backup;
 pub(super) mod capabilities;
 pub(super) mod config;
 pub(super) mod context;
+pub(super) mod dehydrated_device;
 pub(super) mod device;
 pub(super) mod directory;
 pub(super) mod filter;
+pub(super) mod initial_sync;
 pub(super) mod keys;
 pub(super) mod media;
 pub(super) mod membership;
 pub(super) mod message;
+mod pagination;
 pub(super) mod presence;
 pub(super) mod profile;
 pub(super) mod push;
@@ -40,13 +43,16 @@ pub(super) use backup::*;
 pub(super) use capabilities::*;
 pub(super) use config::*;
 pub(super) use context::*;
+pub(super) use dehydrated_device::*;
 pub(super) use device::*;
 pub(super) use directory::*;
 pub(super) use filter::*;
+pub(super) use initial_sync::*;
 pub(super) use keys::*;
 pub(super) use media::*;
+pub use media::{fetch_remote_content, fetch_remote_thumbnail};
 pub(super) use membership::*;
-pub use membership::{join_room_by_id_helper, leave_all_rooms, leave_room, validate_and_add_event_id};
+pub use membership::{invite_helper, join_room_by_id_helper, leave_all_rooms, leave_room, validate_and_add_event_id};
 pub(super) use message::*;
 pub(super) use presence::*;
 pub(super) use profile::*;
@@ -0,0 +1,85 @@
+use ruma::UInt;
+use tracing::debug;
+
+use crate::services;
+
+/// Client endpoints which return a page of events or users, and whose `limit`
+/// parameter is capped by `limits.max_pagination`
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) enum Paginated {
+	Messages,
+	Context,
+	Relations,
+	Threads,
+	Notifications,
+	UserDirectory,
+}
+
+impl Paginated {
+	/// Number of events returned when the client sends no `limit`
+	fn default_limit(self) -> usize {
+		match self {
+			Self::Messages
+			| Self::Context
+			| Self::Relations
+			| Self::Threads
+			| Self::Notifications
+			| Self::UserDirectory => 10,
+		}
+	}
+
+	/// The requested `limit` or the endpoint's default, silently clamped to
+	/// the configured maximum as the spec allows
+	pub(crate) fn limit(self, requested: Option<UInt>) -> usize {
+		let max = services().globals.config.limits.max_pagination;
+		let limit = clamp_limit(self, requested, max);
+		if requested.is_some_and(|requested| usize::try_from(requested).map_or(true, |requested| requested > limit)) {
+			debug!("Clamped {self:?} limit of {requested:?} to {limit}");
+		}
+
+		limit
+	}
+}
+
+fn clamp_limit(endpoint: Paginated, requested: Option<UInt>, max: usize) -> usize {
+	requested
+		.map_or(Ok(endpoint.default_limit()), usize::try_from)
+		.unwrap_or(usize::MAX)
+		.min(max)
+}
+
+#[cfg(test)]
+mod tests {
+	use ruma::{uint, UInt};
+
+	use super::{clamp_limit, Paginated};
+
+	#[test]
+	fn defaults_and_maximum() {
+		let cases = [
+			(Paginated::Messages, None, 10),
+			(Paginated::Context, None, 10),
+			(Paginated::Relations, None, 10),
+			(Paginated::Threads, None, 10),
+			(Paginated::Notifications, None, 10),
+			(Paginated::UserDirectory, None, 10),
+			(Paginated::Messages, Some(uint!(0)), 0),
+			(Paginated::Messages, Some(uint!(50)), 50),
+			(Paginated::Context, Some(uint!(100)), 100),
+			(Paginated::Relations, Some(uint!(101)), 100),
+			(Paginated::Threads, Some(uint!(10_000_000)), 100),
+			(Paginated::Notifications, Some(UInt::MAX), 100),
+			(Paginated::UserDirectory, Some(uint!(1000)), 100),
+		];
+
+		for (endpoint, requested, expected) in cases {
+			assert_eq!(
+				clamp_limit(endpoint, requested, 100),
+				expected,
+				"{endpoint:?} with limit {requested:?}"
+			);
+		}
+
+		assert_eq!(clamp_limit(Paginated::Messages, None, 5), 5);
+	}
+}
@@ -1,20 +1,17 @@
 use ruma::{
-	api::{
-		client::{
-			error::ErrorKind,
-			profile::{get_avatar_url, get_display_name, get_profile, set_avatar_url, set_display_name},
-		},
-		federation,
+	api::client::{
+		error::ErrorKind,
+		profile::{get_avatar_url, get_display_name, get_profile, set_avatar_url, set_display_name},
 	},
 	events::{room::member::RoomMemberEventContent, StateEventType, TimelineEventType},
 	presence::PresenceState,
-	OwnedMxcUri, OwnedRoomId, OwnedUserId,
+	OwnedMxcUri, OwnedRoomId, OwnedUserId, UserId,
 };
 use serde_json::value::to_raw_value;
 use tracing::warn;
 
 use crate::{
-	service::{pdu::PduBuilder, user_is_local},
+	service::{pdu::PduBuilder, user_is_local, users::Profile},
 	services, Error, Result, Ruma,
 };
 
@@ -50,55 +47,14 @@ pub(crate) async fn set_displayname_route(
 ///
 /// Returns the displayname of the user.
 ///
-/// - If user is on another server and we do not have a local copy already fetch
-///   displayname over federation
+/// - Remote users are served like `GET /profile/{userId}`
 pub(crate) async fn get_displayname_route(
 	body: Ruma<get_display_name::v3::Request>,
 ) -> Result<get_display_name::v3::Response> {
-	if !user_is_local(&body.user_id) {
-		// Create and update our local copy of the user
-		if let Ok(response) = services()
-			.sending
-			.send_federation_request(
-				body.user_id.server_name(),
-				federation::query::get_profile_information::v1::Request {
-					user_id: body.user_id.clone(),
-					field: None, // we want the full user's profile to update locally too
-				},
-			)
-			.await
-		{
-			if !services().users.exists(&body.user_id)? {
-				services().users.create(&body.user_id, None)?;
-			}
-
-			services()
-				.users
-				.set_displayname(&body.user_id, response.displayname.clone())
-				.await?;
-			services()
-				.users
-				.set_avatar_url(&body.user_id, response.avatar_url.clone())
-				.await?;
-			services()
-				.users
-				.set_blurhash(&body.user_id, response.blurhash.clone())
-				.await?;
-
-			return Ok(get_display_name::v3::Response {
-				displayname: response.displayname,
-			});
-		}
-	}
-
-	if !services().users.exists(&body.user_id)? {
-		// Return 404 if this user doesn't exist and we couldn't fetch it over
-		// federation
-		return Err(Error::BadRequest(ErrorKind::NotFound, "Profile was not found."));
-	}
+	let profile = profile_of(&body.user_id).await?;
 
 	Ok(get_display_name::v3::Response {
-		displayname: services().users.displayname(&body.user_id)?,
+		displayname: profile.displayname,
 	})
 }
 
@@ -140,57 +96,15 @@ pub(crate) async fn set_avatar_url_route(
 ///
 /// Returns the `avatar_url` and `blurhash` of the user.
 ///
-/// - If user is on another server and we do not have a local copy already fetch
-///   `avatar_url` and blurhash over federation
+/// - Remote users are served like `GET /profile/{userId}`
 pub(crate) async fn get_avatar_url_route(
 	body: Ruma<get_avatar_url::v3::Request>,
 ) -> Result<get_avatar_url::v3::Response> {
-	if !user_is_local(&body.user_id) {
-		// Create and update our local copy of the user
-		if let Ok(response) = services()
-			.sending
-			.send_federation_request(
-				body.user_id.server_name(),
-				federation::query::get_profile_information::v1::Request {
-					user_id: body.user_id.clone(),
-					field: None, // we want the full user's profile to update locally as well
-				},
-			)
-			.await
-		{
-			if !services().users.exists(&body.user_id)? {
-				services().users.create(&body.user_id, None)?;
-			}
-
-			services()
-				.users
-				.set_displayname(&body.user_id, response.displayname.clone())
-				.await?;
-			services()
-				.users
-				.set_avatar_url(&body.user_id, response.avatar_url.clone())
-				.await?;
-			services()
-				.users
-				.set_blurhash(&body.user_id, response.blurhash.clone())
-				.await?;
-
-			return Ok(get_avatar_url::v3::Response {
-				avatar_url: response.avatar_url,
-				blurhash: response.blurhash,
-			});
-		}
-	}
-
-	if !services().users.exists(&body.user_id)? {
-		// Return 404 if this user doesn't exist and we couldn't fetch it over
-		// federation
-		return Err(Error::BadRequest(ErrorKind::NotFound, "Profile was not found."));
-	}
+	let profile = profile_of(&body.user_id).await?;
 
 	Ok(get_avatar_url::v3::Response {
-		avatar_url: services().users.avatar_url(&body.user_id)?,
-		blurhash: services().users.blurhash(&body.user_id)?,
+		avatar_url: profile.avatar_url,
+		blurhash: profile.blurhash,
 	})
 }
 
@@ -198,60 +112,38 @@ pub(crate) async fn get_avatar_url_route(
 ///
 /// Returns the displayname, avatar_url and blurhash of the user.
 ///
-/// - If user is on another server and we do not have a local copy already,
-///   fetch profile over federation.
+/// - Remote users are served from our local copy, which is refreshed over
+///   federation in the background once stale
+/// - Without a local copy the profile is fetched over federation, returning an
+///   empty profile if the other server does not answer in time
 pub(crate) async fn get_profile_route(body: Ruma<get_profile::v3::Request>) -> Result<get_profile::v3::Response> {
-	if !user_is_local(&body.user_id) {
-		// Create and update our local copy of the user
-		if let Ok(response) = services()
-			.sending
-			.send_federation_request(
-				body.user_id.server_name(),
-				federation::query::get_profile_information::v1::Request {
-					user_id: body.user_id.clone(),
-					field: None,
-				},
-			)
-			.await
-		{
-			if !services().users.exists(&body.user_id)? {
-				services().users.create(&body.user_id, None)?;
-			}
-
-			services()
-				.users
-				.set_displayname(&body.user_id, response.displayname.clone())
-				.await?;
-			services()
-				.users
-				.set_avatar_url(&body.user_id, response.avatar_url.clone())
-				.await?;
-			services()
-				.users
-				.set_blurhash(&body.user_id, response.blurhash.clone())
-				.await?;
-
-			return Ok(get_profile::v3::Response {
-				displayname: response.displayname,
-				avatar_url: response.avatar_url,
-				blurhash: response.blurhash,
-			});
-		}
-	}
-
-	if !services().users.exists(&body.user_id)? {
-		// Return 404 if this user doesn't exist and we couldn't fetch it over
-		// federation
-		return Err(Error::BadRequest(ErrorKind::NotFound, "Profile was not found."));
-	}
+	let profile = profile_of(&body.user_id).await?;
 
 	Ok(get_profile::v3::Response {
-		avatar_url: services().users.avatar_url(&body.user_id)?,
-		blurhash: services().users.blurhash(&body.user_id)?,
-		displayname: services().users.displayname(&body.user_id)?,
+		displayname: profile.displayname,
+		avatar_url: profile.avatar_url,
+		blurhash: profile.blurhash,
 	})
 }
 
+/// Profile of a local user, or of a remote user from our local copy or over
+/// federation
+async fn profile_of(user_id: &UserId) -> Result<Profile> {
+	let profile = if user_is_local(user_id) {
+		services()
+			.users
+			.exists(user_id)?
+			.then(|| services().users.profile(user_id))
+			.transpose()?
+	} else {
+		services().users.profiles.get(user_id).await?
+	};
+
+	// Return 404 if this user doesn't exist and we couldn't fetch it over
+	// federation
+	profile.ok_or(Error::BadRequest(ErrorKind::NotFound, "Profile was not found."))
+}
+
 pub async fn update_displayname(
 	user_id: OwnedUserId, displayname: Option<String>, all_joined_rooms: Vec<OwnedRoomId>,
 ) -> Result<()> {
@@ -2,14 +2,15 @@ use ruma::{
 	api::client::{
 		error::ErrorKind,
 		push::{
-			delete_pushrule, get_pushers, get_pushrule, get_pushrule_actions, get_pushrule_enabled, get_pushrules_all,
-			set_pusher, set_pushrule, set_pushrule_actions, set_pushrule_enabled, RuleScope,
+			delete_pushrule, get_notifications, get_pushers, get_pushrule, get_pushrule_actions, get_pushrule_enabled,
+			get_pushrules_all, set_pusher, set_pushrule, set_pushrule_actions, set_pushrule_enabled, RuleScope,
 		},
 	},
 	events::{push_rules::PushRulesEvent, GlobalAccountDataEventType},
 	push::{InsertPushRuleError, RemovePushRuleError, Ruleset},
 };
 
+use super::pagination::Paginated;
 use crate::{services, Error, Result, Ruma};
 
 /// # `GET /_matrix/client/r0/pushrules/`
@@ -365,7 +366,65 @@ pub(crate) async fn get_pushers_route(body: Ruma<get_pushers::v3::Request>) -> R
 pub(crate) async fn set_pushers_route(body: Ruma<set_pusher::v3::Request>) -> Result<set_pusher::v3::Response> {
 	let sender_user = body.sender_user.as_ref().expect("user is authenticated");
 
-	services().pusher.set_pusher(sender_user, &body.action)?;
+	services()
+		.pusher
+		.set_pusher(sender_user, body.sender_device.as_deref(), &body.action)?;
 
 	Ok(set_pusher::v3::Response::default())
 }
+
+/// # `GET /_matrix/client/v3/notifications`
+///
+/// Lists the events the sender user was notified of, most recent first.
+///
+/// - Only the latest 500 notifications of each user are kept
+pub(crate) async fn get_notifications_route(
+	body: Ruma<get_notifications::v3::Request>,
+) -> Result<get_notifications::v3::Response> {
+	let sender_user = body.sender_user.as_ref().expect("user is authenticated");
+
+	let limit = Paginated::Notifications.limit(body.limit);
+
+	let from = if let Some(from) = &body.from {
+		from.parse()
+			.map_err(|_| Error::BadRequest(ErrorKind::InvalidParam, "Invalid from token."))?
+	} else {
+		u64::MAX
+	};
+
+	let only_highlight = body.only.as_deref() == Some("highlight");
+
+	let mut notifications = Vec::new();
+	let mut next_token = None;
+	let mut last_count = from;
+	for (count, notification) in services()
+		.rooms
+		.user
+		.notifications_until(sender_user, from)
+		.filter_map(Result::ok)
+		.filter(|(_, notification)| !only_highlight || notification.highlight)
+	{
+		let Some(pdu) = services().rooms.timeline.get_pdu(&notification.event_id)? else {
+			continue;
+		};
+
+		if notifications.len() >= limit {
+			next_token = Some(last_count.to_string());
+			break;
+		}
+
+		notifications.push(get_notifications::v3::Notification::new(
+			notification.actions,
+			pdu.to_sync_room_event(),
+			notification.read,
+			notification.room_id,
+			notification.ts,
+		));
+		last_count = count;
+	}
+
+	Ok(get_notifications::v3::Response {
+		next_token,
+		notifications,
+	})
+}
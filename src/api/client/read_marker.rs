@@ -7,7 +7,7 @@ use ruma::{
 		receipt::{ReceiptThread, ReceiptType},
 		RoomAccountDataEventType,
 	},
-	MilliSecondsSinceUnixEpoch,
+	EventId, MilliSecondsSinceUnixEpoch, RoomId, UserId,
 };
 
 use crate::{services, Error, Result, Ruma};
@@ -38,11 +38,11 @@ pub(crate) async fn set_read_marker_route(
 		)?;
 	}
 
-	if body.private_read_receipt.is_some() || body.read_receipt.is_some() {
-		services()
-			.rooms
-			.user
-			.reset_notification_counts(sender_user, &body.room_id)?;
+	for event in [&body.private_read_receipt, &body.read_receipt]
+		.into_iter()
+		.flatten()
+	{
+		mark_read(sender_user, &body.room_id, event)?;
 	}
 
 	if let Some(event) = &body.private_read_receipt {
@@ -107,10 +107,7 @@ pub(crate) async fn create_receipt_route(
 		&body.receipt_type,
 		create_receipt::v3::ReceiptType::Read | create_receipt::v3::ReceiptType::ReadPrivate
 	) {
-		services()
-			.rooms
-			.user
-			.reset_notification_counts(sender_user, &body.room_id)?;
+		mark_read(sender_user, &body.room_id, &body.event_id)?;
 	}
 
 	match body.receipt_type {
@@ -176,3 +173,26 @@ pub(crate) async fn create_receipt_route(
 
 	Ok(create_receipt::v3::Response {})
 }
+
+/// Resets the notification counts of the user in the room and marks their
+/// notifications up to the event as read. Receipts for events we do not have
+/// are ignored.
+fn mark_read(user_id: &UserId, room_id: &RoomId, event_id: &EventId) -> Result<()> {
+	let Some(count) = services().rooms.timeline.get_pdu_count(event_id)? else {
+		return Ok(());
+	};
+
+	services()
+		.rooms
+		.user
+		.reset_notification_counts(user_id, room_id, count)?;
+
+	if let PduCount::Normal(count) = count {
+		services()
+			.rooms
+			.user
+			.mark_notifications_read(user_id, room_id, count)?;
+	}
+
+	Ok(())
+}
@@ -1,8 +1,4 @@
-use ruma::{
-	api::client::redact::redact_event,
-	events::{room::redaction::RoomRedactionEventContent, TimelineEventType},
-};
-use serde_json::value::to_raw_value;
+use ruma::api::client::redact::redact_event;
 
 use crate::{service::pdu::PduBuilder, services, Result, Ruma};
 
@@ -25,17 +21,7 @@ pub(crate) async fn redact_event_route(body: Ruma<redact_event::v3::Request>) ->
 		.rooms
 		.timeline
 		.build_and_append_pdu(
-			PduBuilder {
-				event_type: TimelineEventType::RoomRedaction,
-				content: to_raw_value(&RoomRedactionEventContent {
-					redacts: Some(body.event_id.clone()),
-					reason: body.reason.clone(),
-				})
-				.expect("event is valid, we just created it"),
-				unsigned: None,
-				state_key: None,
-				redacts: Some(body.event_id.into()),
-			},
+			PduBuilder::redaction(&body.event_id, body.reason.clone()),
 			sender_user,
 			&body.room_id,
 			&state_lock,
@@ -2,6 +2,7 @@ use ruma::api::client::relations::{
 	get_relating_events, get_relating_events_with_rel_type, get_relating_events_with_rel_type_and_event_type,
 };
 
+use super::pagination::Paginated;
 use crate::{services, Result, Ruma};
 
 /// # `GET /_matrix/client/r0/rooms/{roomId}/relations/{eventId}/{relType}/{eventType}`
@@ -21,7 +22,7 @@ pub(crate) async fn get_relating_events_with_rel_type_and_event_type_route(
 			&Some(body.rel_type.clone()),
 			&body.from,
 			&body.to,
-			&body.limit,
+			Paginated::Relations.limit(body.limit),
 			body.recurse,
 			body.dir,
 		)?;
@@ -51,7 +52,7 @@ pub(crate) async fn get_relating_events_with_rel_type_route(
 			&Some(body.rel_type.clone()),
 			&body.from,
 			&body.to,
-			&body.limit,
+			Paginated::Relations.limit(body.limit),
 			body.recurse,
 			body.dir,
 		)?;
@@ -81,7 +82,7 @@ pub(crate) async fn get_relating_events_route(
 			&None,
 			&body.from,
 			&body.to,
-			&body.limit,
+			Paginated::Relations.limit(body.limit),
 			body.recurse,
 			body.dir,
 		)
@@ -68,6 +68,13 @@ pub(crate) async fn create_room_route(body: Ruma<create_room::v3::Request>) -> R
 
 	let sender_user = body.sender_user.as_ref().expect("user is authenticated");
 
+	if services().users.is_guest(sender_user)? {
+		return Err(Error::BadRequest(
+			ErrorKind::GuestAccessForbidden,
+			"Guests cannot create rooms.",
+		));
+	}
+
 	if !services().globals.allow_room_creation()
 		&& body.appservice_info.is_none()
 		&& !services().users.is_admin(sender_user)?
@@ -221,9 +228,9 @@ pub(crate) async fn create_room_route(body: Ruma<create_room::v3::Request>) -> R
 		.rooms
 		.timeline
 		.build_and_append_pdu(
-			PduBuilder {
-				event_type: TimelineEventType::RoomMember,
-				content: to_raw_value(&RoomMemberEventContent {
+			PduBuilder::state(
+				sender_user.to_string(),
+				&RoomMemberEventContent {
 					membership: MembershipState::Join,
 					displayname: services().users.displayname(sender_user)?,
 					avatar_url: services().users.avatar_url(sender_user)?,
@@ -232,12 +239,8 @@ pub(crate) async fn create_room_route(body: Ruma<create_room::v3::Request>) -> R
 					blurhash: services().users.blurhash(sender_user)?,
 					reason: None,
 					join_authorized_via_users_server: None,
-				})
-				.expect("event is valid, we just created it"),
-				unsigned: None,
-				state_key: Some(sender_user.to_string()),
-				redacts: None,
-			},
+				},
+			),
 			sender_user,
 			&room_id,
 			&state_lock,
@@ -261,8 +264,17 @@ pub(crate) async fn create_room_route(body: Ruma<create_room::v3::Request>) -> R
 		}
 	}
 
-	let power_levels_content =
-		default_power_levels_content(&body.power_level_content_override, &body.visibility, users)?;
+	let power_levels_content = default_power_levels_content(
+		services()
+			.globals
+			.config
+			.room_creation
+			.default_power_levels
+			.as_ref(),
+		&body.power_level_content_override,
+		&body.visibility,
+		users,
+	)?;
 
 	services()
 		.rooms
@@ -287,17 +299,13 @@ pub(crate) async fn create_room_route(body: Ruma<create_room::v3::Request>) -> R
 			.rooms
 			.timeline
 			.build_and_append_pdu(
-				PduBuilder {
-					event_type: TimelineEventType::RoomCanonicalAlias,
-					content: to_raw_value(&RoomCanonicalAliasEventContent {
+				PduBuilder::state(
+					String::new(),
+					&RoomCanonicalAliasEventContent {
 						alias: Some(room_alias_id.to_owned()),
 						alt_aliases: vec![],
-					})
-					.expect("We checked that alias earlier, it must be fine"),
-					unsigned: None,
-					state_key: Some(String::new()),
-					redacts: None,
-				},
+					},
+				),
 				sender_user,
 				&room_id,
 				&state_lock,
@@ -312,18 +320,14 @@ pub(crate) async fn create_room_route(body: Ruma<create_room::v3::Request>) -> R
 		.rooms
 		.timeline
 		.build_and_append_pdu(
-			PduBuilder {
-				event_type: TimelineEventType::RoomJoinRules,
-				content: to_raw_value(&RoomJoinRulesEventContent::new(match preset {
+			PduBuilder::state(
+				String::new(),
+				&RoomJoinRulesEventContent::new(match preset {
 					RoomPreset::PublicChat => JoinRule::Public,
 					// according to spec "invite" is the default
 					_ => JoinRule::Invite,
-				}))
-				.expect("event is valid, we just created it"),
-				unsigned: None,
-				state_key: Some(String::new()),
-				redacts: None,
-			},
+				}),
+			),
 			sender_user,
 			&room_id,
 			&state_lock,
@@ -335,14 +339,10 @@ pub(crate) async fn create_room_route(body: Ruma<create_room::v3::Request>) -> R
 		.rooms
 		.timeline
 		.build_and_append_pdu(
-			PduBuilder {
-				event_type: TimelineEventType::RoomHistoryVisibility,
-				content: to_raw_value(&RoomHistoryVisibilityEventContent::new(HistoryVisibility::Shared))
-					.expect("event is valid, we just created it"),
-				unsigned: None,
-				state_key: Some(String::new()),
-				redacts: None,
-			},
+			PduBuilder::state(
+				String::new(),
+				&RoomHistoryVisibilityEventContent::new(HistoryVisibility::Shared),
+			),
 			sender_user,
 			&room_id,
 			&state_lock,
@@ -354,17 +354,13 @@ pub(crate) async fn create_room_route(body: Ruma<create_room::v3::Request>) -> R
 		.rooms
 		.timeline
 		.build_and_append_pdu(
-			PduBuilder {
-				event_type: TimelineEventType::RoomGuestAccess,
-				content: to_raw_value(&RoomGuestAccessEventContent::new(match preset {
+			PduBuilder::state(
+				String::new(),
+				&RoomGuestAccessEventContent::new(match preset {
 					RoomPreset::PublicChat => GuestAccess::Forbidden,
 					_ => GuestAccess::CanJoin,
-				}))
-				.expect("event is valid, we just created it"),
-				unsigned: None,
-				state_key: Some(String::new()),
-				redacts: None,
-			},
+				}),
+			),
 			sender_user,
 			&room_id,
 			&state_lock,
@@ -410,14 +406,7 @@ pub(crate) async fn create_room_route(body: Ruma<create_room::v3::Request>) -> R
 			.rooms
 			.timeline
 			.build_and_append_pdu(
-				PduBuilder {
-					event_type: TimelineEventType::RoomName,
-					content: to_raw_value(&RoomNameEventContent::new(name.clone()))
-						.expect("event is valid, we just created it"),
-					unsigned: None,
-					state_key: Some(String::new()),
-					redacts: None,
-				},
+				PduBuilder::state(String::new(), &RoomNameEventContent::new(name.clone())),
 				sender_user,
 				&room_id,
 				&state_lock,
@@ -430,16 +419,12 @@ pub(crate) async fn create_room_route(body: Ruma<create_room::v3::Request>) -> R
 			.rooms
 			.timeline
 			.build_and_append_pdu(
-				PduBuilder {
-					event_type: TimelineEventType::RoomTopic,
-					content: to_raw_value(&RoomTopicEventContent {
+				PduBuilder::state(
+					String::new(),
+					&RoomTopicEventContent {
 						topic: topic.clone(),
-					})
-					.expect("event is valid, we just created it"),
-					unsigned: None,
-					state_key: Some(String::new()),
-					redacts: None,
-				},
+					},
+				),
 				sender_user,
 				&room_id,
 				&state_lock,
@@ -505,6 +490,10 @@ pub(crate) async fn get_room_event_route(
 
 	let mut event = (*event).clone();
 	event.add_age()?;
+	services()
+		.rooms
+		.threads
+		.add_bundled_aggregation(sender_user, &mut event)?;
 
 	Ok(get_room_event::v3::Response {
 		event: event.to_room_event(),
@@ -551,9 +540,17 @@ pub(crate) async fn get_room_aliases_route(body: Ruma<aliases::v3::Request>) ->
 /// - Transfers some state events
 /// - Moves local aliases
 /// - Modifies old room power levels to prevent users from speaking
+/// - Guests cannot upgrade rooms
 pub(crate) async fn upgrade_room_route(body: Ruma<upgrade_room::v3::Request>) -> Result<upgrade_room::v3::Response> {
 	let sender_user = body.sender_user.as_ref().expect("user is authenticated");
 
+	if services().users.is_guest(sender_user)? {
+		return Err(Error::BadRequest(
+			ErrorKind::GuestAccessForbidden,
+			"Guests cannot upgrade rooms.",
+		));
+	}
+
 	if !services()
 		.globals
 		.supported_room_versions()
@@ -586,17 +583,13 @@ pub(crate) async fn upgrade_room_route(body: Ruma<upgrade_room::v3::Request>) ->
 		.rooms
 		.timeline
 		.build_and_append_pdu(
-			PduBuilder {
-				event_type: TimelineEventType::RoomTombstone,
-				content: to_raw_value(&RoomTombstoneEventContent {
+			PduBuilder::state(
+				String::new(),
+				&RoomTombstoneEventContent {
 					body: "This room has been replaced".to_owned(),
 					replacement_room: replacement_room.clone(),
-				})
-				.expect("event is valid, we just created it"),
-				unsigned: None,
-				state_key: Some(String::new()),
-				redacts: None,
-			},
+				},
+			),
 			sender_user,
 			&body.room_id,
 			&state_lock,
@@ -709,9 +702,9 @@ pub(crate) async fn upgrade_room_route(body: Ruma<upgrade_room::v3::Request>) ->
 		.rooms
 		.timeline
 		.build_and_append_pdu(
-			PduBuilder {
-				event_type: TimelineEventType::RoomMember,
-				content: to_raw_value(&RoomMemberEventContent {
+			PduBuilder::state(
+				sender_user.to_string(),
+				&RoomMemberEventContent {
 					membership: MembershipState::Join,
 					displayname: services().users.displayname(sender_user)?,
 					avatar_url: services().users.avatar_url(sender_user)?,
@@ -720,12 +713,8 @@ pub(crate) async fn upgrade_room_route(body: Ruma<upgrade_room::v3::Request>) ->
 					blurhash: services().users.blurhash(sender_user)?,
 					reason: None,
 					join_authorized_via_users_server: None,
-				})
-				.expect("event is valid, we just created it"),
-				unsigned: None,
-				state_key: Some(sender_user.to_string()),
-				redacts: None,
-			},
+				},
+			),
 			sender_user,
 			&replacement_room,
 			&state_lock,
@@ -734,30 +723,18 @@ pub(crate) async fn upgrade_room_route(body: Ruma<upgrade_room::v3::Request>) ->
 
 	// Replicate transferable state events to the new room
 	for event_type in TRANSFERABLE_STATE_EVENTS {
-		let event_content = match services()
+		let Some(event) = services()
 			.rooms
 			.state_accessor
 			.room_state_get(&body.room_id, event_type, "")?
-		{
-			Some(v) => v.content.clone(),
-			None => continue, // Skipping missing events.
+		else {
+			continue; // Skipping missing events.
 		};
 
 		services()
 			.rooms
 			.timeline
-			.build_and_append_pdu(
-				PduBuilder {
-					event_type: event_type.to_string().into(),
-					content: event_content,
-					unsigned: None,
-					state_key: Some(String::new()),
-					redacts: None,
-				},
-				sender_user,
-				&replacement_room,
-				&state_lock,
-			)
+			.build_and_append_pdu(PduBuilder::copy_state(&event), sender_user, &replacement_room, &state_lock)
 			.await?;
 	}
 
@@ -805,13 +782,7 @@ pub(crate) async fn upgrade_room_route(body: Ruma<upgrade_room::v3::Request>) ->
 		.rooms
 		.timeline
 		.build_and_append_pdu(
-			PduBuilder {
-				event_type: TimelineEventType::RoomPowerLevels,
-				content: to_raw_value(&power_levels_event_content).expect("event is valid, we just created it"),
-				unsigned: None,
-				state_key: Some(String::new()),
-				redacts: None,
-			},
+			PduBuilder::state(String::new(), &power_levels_event_content),
 			sender_user,
 			&body.room_id,
 			&state_lock,
@@ -826,13 +797,15 @@ pub(crate) async fn upgrade_room_route(body: Ruma<upgrade_room::v3::Request>) ->
 	})
 }
 
-/// creates the power_levels_content for the PDU builder
+/// Builds the power levels of a new room. The configured template is applied
+/// over our defaults, followed by the power levels the preset gives to
+/// `users`, and the client's override last.
 fn default_power_levels_content(
-	power_level_content_override: &Option<Raw<RoomPowerLevelsEventContent>>, visibility: &room::Visibility,
-	users: BTreeMap<OwnedUserId, Int>,
+	template: Option<&JsonObject>, power_level_content_override: &Option<Raw<RoomPowerLevelsEventContent>>,
+	visibility: &room::Visibility, users: BTreeMap<OwnedUserId, Int>,
 ) -> Result<serde_json::Value> {
 	let mut power_levels_content = serde_json::to_value(RoomPowerLevelsEventContent {
-		users,
+		users: users.clone(),
 		..Default::default()
 	})
 	.expect("event is valid, we just created it");
@@ -856,6 +829,14 @@ fn default_power_levels_content(
 			serde_json::to_value(50).expect("50 is valid Value");
 	}
 
+	if let Some(template) = template {
+		apply_power_levels_template(&mut power_levels_content, template);
+		for (user_id, power_level) in users {
+			power_levels_content["users"][user_id.as_str()] =
+				serde_json::to_value(power_level).expect("Int is valid Value");
+		}
+	}
+
 	if let Some(power_level_content_override) = power_level_content_override {
 		let json: JsonObject = serde_json::from_str(power_level_content_override.json().get())
 			.map_err(|_| Error::BadRequest(ErrorKind::BadJson, "Invalid power_level_content_override."))?;
@@ -868,6 +849,19 @@ fn default_power_levels_content(
 	Ok(power_levels_content)
 }
 
+/// Merges the template into the power levels, key by key for objects such as
+/// `events` and `users`
+fn apply_power_levels_template(power_levels_content: &mut serde_json::Value, template: &JsonObject) {
+	for (key, value) in template {
+		match (&mut power_levels_content[key], value) {
+			(serde_json::Value::Object(current), serde_json::Value::Object(value)) => {
+				current.extend(value.clone());
+			},
+			(current, value) => *current = value.clone(),
+		}
+	}
+}
+
 /// if a room is being created with a room alias, run our checks
 async fn room_alias_check(
 	room_alias_name: &str, appservice_info: &Option<RegistrationInfo>,
@@ -910,17 +904,10 @@ async fn room_alias_check(
 		return Err(Error::BadRequest(ErrorKind::RoomInUse, "Room alias already exists."));
 	}
 
-	if let Some(ref info) = appservice_info {
-		if !info.aliases.is_match(full_room_alias.as_str()) {
-			return Err(Error::BadRequest(ErrorKind::Exclusive, "Room alias is not in namespace."));
-		}
-	} else if services()
+	services()
 		.appservice
-		.is_exclusive_alias(&full_room_alias)
-		.await
-	{
-		return Err(Error::BadRequest(ErrorKind::Exclusive, "Room alias reserved by appservice."));
-	}
+		.check_alias(&full_room_alias, appservice_info.as_ref())
+		.await?;
 
 	debug_info!("Full room alias: {full_room_alias}");
 
@@ -960,3 +947,107 @@ fn custom_room_id_check(custom_room_id: &str) -> Result<OwnedRoomId> {
 		Error::BadRequest(ErrorKind::InvalidParam, "Custom room ID could not be parsed")
 	})
 }
+
+#[cfg(test)]
+mod tests {
+	use std::collections::BTreeMap;
+
+	use ruma::{api::client::room::Visibility, int, serde::Raw, user_id};
+	use serde_json::json;
+
+	use super::default_power_levels_content;
+	use crate::{
+		service::{pdu::PduBuilder, rooms::state_accessor::summary_topic},
+		PduEvent,
+	};
+
+	fn topic_event(room_id: &str, content: &serde_json::Value) -> PduEvent {
+		serde_json::from_value(json!({
+			"event_id": "$topic",
+			"room_id": room_id,
+			"sender": "@alice:example.com",
+			"origin_server_ts": 1,
+			"type": "m.room.topic",
+			"state_key": "",
+			"content": content,
+			"prev_events": [],
+			"depth": 1,
+			"auth_events": [],
+			"hashes": { "sha256": "" },
+		}))
+		.unwrap()
+	}
+
+	fn template(value: serde_json::Value) -> ruma::serde::JsonObject {
+		let serde_json::Value::Object(template) = value else {
+			panic!("template is an object");
+		};
+
+		template
+	}
+
+	#[test]
+	fn template_merges_beneath_override() {
+		let creator = user_id!("@creator:example.com");
+		let template = template(json!({
+			"events_default": 0,
+			"invite": 50,
+			"kick": 75,
+			"events": { "m.room.pinned_events": 50 },
+		}));
+		let power_level_override = Some(Raw::from_json(
+			serde_json::value::to_raw_value(&json!({ "invite": 0 })).unwrap(),
+		));
+		let users = BTreeMap::from([(creator.to_owned(), int!(100))]);
+
+		let content =
+			default_power_levels_content(Some(&template), &power_level_override, &Visibility::Private, users).unwrap();
+
+		// the client's override wins over the template
+		assert_eq!(content["invite"], 0);
+		// the template wins over our defaults
+		assert_eq!(content["events_default"], 0);
+		assert_eq!(content["kick"], 75);
+		// objects are merged key by key
+		assert_eq!(content["events"]["m.room.pinned_events"], 50);
+		assert_eq!(content["events"]["m.room.power_levels"], 100);
+	}
+
+	#[test]
+	fn preset_users_apply_after_template() {
+		let creator = user_id!("@creator:example.com");
+		let invitee = user_id!("@invitee:example.com");
+		let template = template(json!({
+			"users": { "@creator:example.com": 0, "@moderator:example.com": 50 },
+		}));
+		// trusted_private_chat gives the invitees the creator's power level
+		let users = BTreeMap::from([(creator.to_owned(), int!(100)), (invitee.to_owned(), int!(100))]);
+
+		let content = default_power_levels_content(Some(&template), &None, &Visibility::Private, users).unwrap();
+
+		assert_eq!(content["users"]["@creator:example.com"], 100);
+		assert_eq!(content["users"]["@invitee:example.com"], 100);
+		assert_eq!(content["users"]["@moderator:example.com"], 50);
+	}
+
+	#[test]
+	fn upgraded_rich_topic_in_directory() {
+		let original = json!({
+			"topic": { "malformed": true },
+			"m.topic": [
+				{ "mimetype": "text/html", "body": "<b>Rich</b> topic" },
+				{ "mimetype": "text/plain", "body": "Rich topic" },
+			],
+		});
+		let topic = topic_event("!old:example.com", &original);
+
+		// upgrade_room_route copies the transferable state of the old room
+		let copy = PduBuilder::copy_state(&topic);
+		let content: serde_json::Value = serde_json::from_str(copy.content.get()).unwrap();
+		assert_eq!(content, original);
+		let upgraded = topic_event("!new:example.com", &content);
+
+		// the public rooms chunk of the new room shows the plain representation
+		assert_eq!(summary_topic(&upgraded).as_deref(), Some("Rich topic"));
+	}
+}
@@ -5,23 +5,31 @@ use ruma::{
 		error::ErrorKind,
 		search::search_events::{
 			self,
-			v3::{EventContextResult, ResultCategories, ResultRoomEvents, SearchResult},
+			v3::{
+				EventContextResult, GroupingKey, OrderBy, ResultCategories, ResultGroup, ResultRoomEvents,
+				RoomIdOrUserId, SearchResult,
+			},
 		},
 	},
 	events::AnyStateEvent,
 	serde::Raw,
-	uint, OwnedRoomId,
+	uint, OwnedRoomId, RoomId, UInt,
 };
 use tracing::debug;
 
 use crate::{services, Error, Result, Ruma};
 
+/// Maximum number of results loaded to be ordered by rank
+const MAX_RANKED_RESULTS: usize = 1000;
+
 /// # `POST /_matrix/client/r0/search`
 ///
 /// Searches rooms for messages.
 ///
 /// - Only works if the user is currently joined to the room (TODO: Respect
 ///   history visibility)
+/// - Results are ordered by recency, or with `order_by: rank` by how often the
+///   search terms occur in recent messages, and can be grouped by room
 pub(crate) async fn search_events_route(body: Ruma<search_events::v3::Request>) -> Result<search_events::v3::Response> {
 	let sender_user = body.sender_user.as_ref().expect("user is authenticated");
 
@@ -88,7 +96,14 @@ pub(crate) async fn search_events_route(body: Ruma<search_events::v3::Request>)
 		}
 	}
 
+	let (skip, group_room) = parse_next_batch(body.next_batch.as_deref())?;
+	let room_ids = match group_room {
+		Some(ref room_id) => vec![room_id.clone()],
+		None => room_ids,
+	};
+
 	let mut searches = Vec::new();
+	let mut words = Vec::new();
 
 	for room_id in &room_ids {
 		if !services()
@@ -102,78 +117,138 @@ pub(crate) async fn search_events_route(body: Ruma<search_events::v3::Request>)
 			));
 		}
 
-		if let Some(search) = services()
+		if let Some((search, search_words)) = services()
 			.rooms
 			.search
 			.search_pdus(room_id, &search_criteria.search_term)?
 		{
-			searches.push(search.0.peekable());
+			searches.push(search.peekable());
+			words = search_words;
 		}
 	}
 
-	let skip: usize = match body.next_batch.as_ref().map(|s| s.parse()) {
-		Some(Ok(s)) => s,
-		Some(Err(_)) => return Err(Error::BadRequest(ErrorKind::InvalidParam, "Invalid next_batch token.")),
-		None => 0, // Default to the start
-	};
-
-	let mut results = Vec::new();
+	let by_rank = matches!(search_criteria.order_by, Some(OrderBy::Rank));
 	let next_batch = skip.saturating_add(limit);
 
-	for _ in 0..next_batch {
+	// Newest first across rooms, tagged with the index of their room's search
+	let mut candidates = Vec::new();
+	let wanted = if by_rank {
+		MAX_RANKED_RESULTS
+	} else {
+		next_batch
+	};
+	for _ in 0..wanted {
 		if let Some(s) = searches
 			.iter_mut()
-			.map(|s| (s.peek().cloned(), s))
-			.max_by_key(|(peek, _)| peek.clone())
-			.and_then(|(_, i)| i.next())
+			.enumerate()
+			.map(|(i, s)| (s.peek().cloned(), i, s))
+			.max_by_key(|(peek, ..)| peek.clone())
+			.and_then(|(_, i, s)| Some((i, s.next()?)))
 		{
-			results.push(s);
+			candidates.push(s);
 		}
 	}
 
-	let results: Vec<_> = results
-		.iter()
-		.skip(skip)
-		.filter_map(|result| {
-			services()
-				.rooms
-				.timeline
-				.get_pdu_from_id(result)
-				.ok()?
-				.filter(|pdu| {
-					!pdu.is_redacted()
-						&& services()
-							.rooms
-							.state_accessor
-							.user_can_see_event(sender_user, &pdu.room_id, &pdu.event_id)
-							.unwrap_or(false)
-				})
-				.map(|pdu| pdu.to_room_event())
-		})
-		.map(|result| {
-			Ok::<_, Error>(SearchResult {
-				context: EventContextResult {
-					end: None,
-					events_after: Vec::new(),
-					events_before: Vec::new(),
-					profile_info: BTreeMap::new(),
-					start: None,
-				},
-				rank: None,
-				result: Some(result),
+	let visible = |(i, pdu_id): &(usize, Vec<u8>)| {
+		services()
+			.rooms
+			.timeline
+			.get_pdu_from_id(pdu_id)
+			.ok()?
+			.filter(|pdu| {
+				!pdu.is_redacted()
+					&& services()
+						.rooms
+						.state_accessor
+						.user_can_see_event(sender_user, &pdu.room_id, &pdu.event_id)
+						.unwrap_or(false)
 			})
+			.map(|pdu| (*i, pdu))
+	};
+
+	// Ranking loads every result before skipping the earlier pages. Recency
+	// skips the unfiltered results, as the earlier pages were never loaded.
+	let (consumed, mut results, count): (Vec<_>, Vec<_>, _) = if by_rank {
+		let mut ranked: Vec<_> = candidates
+			.iter()
+			.filter_map(visible)
+			.map(|(i, pdu)| (i, services().rooms.search.rank(&pdu, &words), pdu))
+			.collect();
+
+		ranked.sort_by(|(_, a, _), (_, b, _)| b.total_cmp(a));
+		let consumed = ranked.iter().take(next_batch).map(|(i, ..)| *i).collect();
+		let count = ranked.len();
+		let results = ranked
+			.into_iter()
+			.skip(skip)
+			.map(|(i, rank, pdu)| (i, Some(rank), pdu))
+			.collect();
+		(consumed, results, Some(count))
+	} else {
+		let consumed = candidates.iter().map(|(i, _)| *i).collect();
+		let results = candidates
+			.iter()
+			.skip(skip)
+			.filter_map(visible)
+			.map(|(i, pdu)| (i, None, pdu))
+			.collect();
+		(consumed, results, None)
+	};
+
+	let rest = results.split_off(limit.min(results.len()));
+	let count = count.unwrap_or(results.len());
+	let mut has_more = |i: usize| rest.iter().any(|(j, ..)| *j == i) || searches[i].peek().is_some();
+
+	let mut groups = BTreeMap::new();
+	if search_criteria
+		.groupings
+		.group_by
+		.iter()
+		.any(|grouping| grouping.key == Some(GroupingKey::RoomId))
+	{
+		let mut by_room = BTreeMap::new();
+		for (i, _, pdu) in &results {
+			let order = by_room.len();
+			let group = by_room
+				.entry(RoomIdOrUserId::RoomId(pdu.room_id.clone()))
+				.or_insert_with(|| {
+					let room_next_batch = consumed.iter().filter(|j| *j == i).count();
+					ResultGroup {
+						next_batch: has_more(*i).then(|| format!("{room_next_batch}/{}", pdu.room_id)),
+						order: Some(order.try_into().unwrap_or(UInt::MAX)),
+						results: Vec::new(),
+					}
+				});
+			group.results.push((*pdu.event_id).to_owned());
+		}
+		groups.insert(GroupingKey::RoomId, by_room);
+	}
+
+	let more_results = !rest.is_empty() || searches.iter_mut().any(|s| s.peek().is_some());
+	let next_batch = more_results.then(|| match &group_room {
+		Some(room_id) => format!("{next_batch}/{room_id}"),
+		None => next_batch.to_string(),
+	});
+
+	let results: Vec<_> = results
+		.into_iter()
+		.map(|(_, rank, pdu)| SearchResult {
+			context: EventContextResult {
+				end: None,
+				events_after: Vec::new(),
+				events_before: Vec::new(),
+				profile_info: BTreeMap::new(),
+				start: None,
+			},
+			rank,
+			result: Some(pdu.to_room_event()),
 		})
-		.filter_map(Result::ok)
-		.take(limit)
 		.collect();
 
-	let more_unloaded_results = searches.iter_mut().any(|s| s.peek().is_some());
-	let next_batch = more_unloaded_results.then(|| next_batch.to_string());
-
 	Ok(search_events::v3::Response::new(ResultCategories {
 		room_events: ResultRoomEvents {
-			count: Some(results.len().try_into().unwrap_or_else(|_| uint!(0))),
-			groups: BTreeMap::new(), // TODO
+			count: Some(count.try_into().unwrap_or_else(|_| uint!(0))),
+			groups,
 			next_batch,
 			results,
 			state: room_states,
@@ -185,3 +260,26 @@ pub(crate) async fn search_events_route(body: Ruma<search_events::v3::Request>)
 		},
 	}))
 }
+
+/// Parses a `next_batch` token: the number of results to skip, followed by
+/// `/` and a room ID for the tokens of a room's result group.
+fn parse_next_batch(next_batch: Option<&str>) -> Result<(usize, Option<OwnedRoomId>)> {
+	let Some(next_batch) = next_batch else {
+		return Ok((0, None)); // Default to the start
+	};
+
+	let (skip, room_id) = match next_batch.split_once('/') {
+		Some((skip, room_id)) => (skip, Some(room_id)),
+		None => (next_batch, None),
+	};
+
+	let skip = skip
+		.parse()
+		.map_err(|_| Error::BadRequest(ErrorKind::InvalidParam, "Invalid next_batch token."))?;
+	let room_id = room_id
+		.map(RoomId::parse)
+		.transpose()
+		.map_err(|_| Error::BadRequest(ErrorKind::InvalidParam, "Invalid next_batch token."))?;
+
+	Ok((skip, room_id))
+}
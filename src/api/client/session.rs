@@ -1,25 +1,28 @@
+use std::time::Duration;
+
 use ruma::{
 	api::client::{
 		error::ErrorKind,
 		session::{
+			get_login_token,
 			get_login_types::{
 				self,
-				v3::{ApplicationServiceLoginType, PasswordLoginType},
+				v3::{ApplicationServiceLoginType, PasswordLoginType, TokenLoginType},
 			},
 			login::{
 				self,
 				v3::{DiscoveryInfo, HomeserverInfo},
 			},
-			logout, logout_all,
+			logout, logout_all, refresh_token,
 		},
-		uiaa::UserIdentifier,
+		uiaa::{AuthFlow, AuthType, UiaaInfo, UserIdentifier},
 	},
-	UserId,
+	OwnedUserId, ServerName, UserId,
 };
 use serde::Deserialize;
 use tracing::{debug, info, warn};
 
-use super::{DEVICE_ID_LENGTH, TOKEN_LENGTH};
+use super::{DEVICE_ID_LENGTH, SESSION_ID_LENGTH, TOKEN_LENGTH};
 use crate::{services, utils, utils::hash, Error, Result, Ruma};
 
 #[derive(Debug, Deserialize)]
@@ -28,6 +31,21 @@ struct Claims {
 	//exp: usize,
 }
 
+/// The user a JWT of a token login is for
+fn jwt_user(token: &str, key: &jsonwebtoken::DecodingKey, server_name: &ServerName) -> Result<OwnedUserId> {
+	let token = jsonwebtoken::decode::<Claims>(token, key, &jsonwebtoken::Validation::default()).map_err(|e| {
+		warn!("Failed to parse JWT token from user logging in: {e}");
+		Error::BadRequest(ErrorKind::InvalidUsername, "Token is invalid.")
+	})?;
+
+	let username = token.claims.sub.to_lowercase();
+
+	UserId::parse_with_server_name(username, server_name).map_err(|e| {
+		warn!("Failed to parse username from user logging in: {e}");
+		Error::BadRequest(ErrorKind::InvalidUsername, "Username is invalid.")
+	})
+}
+
 /// # `GET /_matrix/client/v3/login`
 ///
 /// Get the supported login types of this server. One of these should be used as
@@ -35,10 +53,18 @@ struct Claims {
 pub(crate) async fn get_login_types_route(
 	_body: Ruma<get_login_types::v3::Request>,
 ) -> Result<get_login_types::v3::Response> {
-	Ok(get_login_types::v3::Response::new(vec![
+	let mut flows = vec![
 		get_login_types::v3::LoginType::Password(PasswordLoginType::default()),
 		get_login_types::v3::LoginType::ApplicationService(ApplicationServiceLoginType::default()),
-	]))
+	];
+
+	if services().globals.login_via_existing_session() {
+		flows.push(get_login_types::v3::LoginType::Token(TokenLoginType {
+			get_login_token: true,
+		}));
+	}
+
+	Ok(get_login_types::v3::Response::new(flows))
 }
 
 /// # `POST /_matrix/client/v3/login`
@@ -47,7 +73,7 @@ pub(crate) async fn get_login_types_route(
 /// requests.
 ///
 /// - The user needs to authenticate using their password (or if enabled using a
-///   json web token)
+///   json web token or a login token from an existing session)
 /// - If `device_id` is known: invalidates old access token of that device
 /// - If `device_id` is unknown: creates a new device
 /// - Returns access token that is associated with the user and device
@@ -96,20 +122,21 @@ pub(crate) async fn login_route(body: Ruma<login::v3::Request>) -> Result<login:
 			token,
 		}) => {
 			debug!("Got token login type");
-			if let Some(jwt_decoding_key) = services().globals.jwt_decoding_key() {
-				let token =
-					jsonwebtoken::decode::<Claims>(token, jwt_decoding_key, &jsonwebtoken::Validation::default())
-						.map_err(|e| {
-							warn!("Failed to parse JWT token from user logging in: {e}");
-							Error::BadRequest(ErrorKind::InvalidUsername, "Token is invalid.")
-						})?;
-
-				let username = token.claims.sub.to_lowercase();
-
-				UserId::parse_with_server_name(username, services().globals.server_name()).map_err(|e| {
-					warn!("Failed to parse username from user logging in: {e}");
-					Error::BadRequest(ErrorKind::InvalidUsername, "Username is invalid.")
-				})?
+			let login_token_user = if services().globals.login_via_existing_session() {
+				services().users.find_from_login_token(token)?
+			} else {
+				None
+			};
+
+			if let Some(user_id) = login_token_user {
+				user_id
+			} else if let Some(jwt_decoding_key) = services().globals.jwt_decoding_key() {
+				jwt_user(token, jwt_decoding_key, services().globals.server_name())?
+			} else if services().globals.login_via_existing_session() {
+				return Err(Error::BadRequest(
+					ErrorKind::forbidden(),
+					"Token is invalid or has already been used.",
+				));
 			} else {
 				return Err(Error::BadRequest(
 					ErrorKind::Unknown,
@@ -178,6 +205,13 @@ pub(crate) async fn login_route(body: Ruma<login::v3::Request>) -> Result<login:
 			.create_device(&user_id, &device_id, &token, body.initial_device_display_name.clone())?;
 	}
 
+	let (refresh_token, expires_in) = if body.refresh_token {
+		let (refresh_token, expires_in) = services().users.issue_refresh_token(&user_id, &device_id)?;
+		(Some(refresh_token), Some(expires_in))
+	} else {
+		(None, None)
+	};
+
 	// send client well-known if specified so the client knows to reconfigure itself
 	let client_discovery_info: Option<DiscoveryInfo> = services()
 		.globals
@@ -196,20 +230,99 @@ pub(crate) async fn login_route(body: Ruma<login::v3::Request>) -> Result<login:
 		access_token: token,
 		device_id,
 		well_known: client_discovery_info,
-		expires_in: None,
+		expires_in,
 		home_server: Some(services().globals.server_name().to_owned()),
-		refresh_token: None,
+		refresh_token,
+	})
+}
+
+/// # `POST /_matrix/client/v3/refresh`
+///
+/// Exchanges a refresh token for a new access token and refresh token
+/// (MSC2918).
+///
+/// - Invalidates the old access token and refresh token
+/// - A refresh token used during the last few seconds returns the same new
+///   tokens again, so concurrent refreshes do not log the client out
+pub(crate) async fn refresh_token_route(body: Ruma<refresh_token::v3::Request>) -> Result<refresh_token::v3::Response> {
+	let tokens = services()
+		.users
+		.refresh_token(&body.refresh_token, utils::random_string(TOKEN_LENGTH))?;
+
+	Ok(refresh_token::v3::Response {
+		access_token: tokens.access_token,
+		refresh_token: Some(tokens.refresh_token),
+		expires_in_ms: Some(tokens.expires_in),
 	})
 }
 
+/// # `POST /_matrix/client/v1/login/get_token`
+///
+/// Generates a short-lived, single-use token that can be used to log in a new
+/// device with `m.login.token` (MSC3882).
+///
+/// - Requires `login_via_existing_session` to be enabled
+/// - Requires UIAA to verify user password
+pub(crate) async fn get_login_token_route(
+	body: Ruma<get_login_token::v1::Request>,
+) -> Result<get_login_token::v1::Response> {
+	let sender_user = body.sender_user.as_ref().expect("user is authenticated");
+	let sender_device = body.sender_device.as_ref().expect("user is authenticated");
+
+	if !services().globals.login_via_existing_session() {
+		return Err(Error::BadRequest(
+			ErrorKind::forbidden(),
+			"Login via an existing session is not enabled on this server.",
+		));
+	}
+
+	// UIAA
+	let mut uiaainfo = UiaaInfo {
+		flows: vec![AuthFlow {
+			stages: vec![AuthType::Password],
+		}],
+		completed: Vec::new(),
+		params: Box::default(),
+		session: None,
+		auth_error: None,
+	};
+
+	if let Some(auth) = &body.auth {
+		let (worked, uiaainfo) = services()
+			.uiaa
+			.try_auth(sender_user, sender_device, auth, &uiaainfo)?;
+		if !worked {
+			return Err(Error::Uiaa(uiaainfo));
+		}
+	// Success!
+	} else if let Some(json) = body.json_body {
+		uiaainfo.session = Some(utils::random_string(SESSION_ID_LENGTH));
+		services()
+			.uiaa
+			.create(sender_user, sender_device, &uiaainfo, &json)?;
+		return Err(Error::Uiaa(uiaainfo));
+	} else {
+		return Err(Error::BadRequest(ErrorKind::NotJson, "Not json."));
+	}
+
+	let login_token = services().users.create_login_token(sender_user)?;
+	info!("{sender_user} requested a login token for a new device");
+
+	Ok(get_login_token::v1::Response::new(
+		Duration::from_millis(services().globals.login_token_ttl()),
+		login_token,
+	))
+}
+
 /// # `POST /_matrix/client/v3/logout`
 ///
 /// Log out the current device.
 ///
-/// - Invalidates access token
+/// - Invalidates access token and refresh token
 /// - Deletes device metadata (device id, device display name, last seen ip,
 ///   last seen ts)
 /// - Forgets to-device events
+/// - Drops UIAA sessions and pushers registered by the device
 /// - Triggers device list updates
 pub(crate) async fn logout_route(body: Ruma<logout::v3::Request>) -> Result<logout::v3::Response> {
 	let sender_user = body.sender_user.as_ref().expect("user is authenticated");
@@ -217,9 +330,6 @@ pub(crate) async fn logout_route(body: Ruma<logout::v3::Request>) -> Result<logo
 
 	services().users.remove_device(sender_user, sender_device)?;
 
-	// send device list update for user after logout
-	services().users.mark_device_key_update(sender_user)?;
-
 	Ok(logout::v3::Response::new())
 }
 
@@ -227,11 +337,12 @@ pub(crate) async fn logout_route(body: Ruma<logout::v3::Request>) -> Result<logo
 ///
 /// Log out all devices of this user.
 ///
-/// - Invalidates all access tokens
+/// - Invalidates all access tokens and refresh tokens
 /// - Deletes all device metadata (device id, device display name, last seen ip,
 ///   last seen ts)
 /// - Forgets all to-device events
-/// - Triggers device list updates
+/// - Drops all UIAA sessions and pushers registered by the devices
+/// - Triggers a single device list update
 ///
 /// Note: This is equivalent to calling [`GET
 /// /_matrix/client/r0/logout`](fn.logout_route.html) from each device of this
@@ -239,12 +350,49 @@ pub(crate) async fn logout_route(body: Ruma<logout::v3::Request>) -> Result<logo
 pub(crate) async fn logout_all_route(body: Ruma<logout_all::v3::Request>) -> Result<logout_all::v3::Response> {
 	let sender_user = body.sender_user.as_ref().expect("user is authenticated");
 
-	for device_id in services().users.all_device_ids(sender_user).flatten() {
-		services().users.remove_device(sender_user, &device_id)?;
-	}
+	let device_ids: Vec<_> = services()
+		.users
+		.all_device_ids(sender_user)
+		.flatten()
+		.collect();
 
-	// send device list update for user after logout
-	services().users.mark_device_key_update(sender_user)?;
+	// one device list update for all the devices
+	services().users.remove_devices(sender_user, &device_ids)?;
 
 	Ok(logout_all::v3::Response::new())
 }
+
+#[cfg(test)]
+mod tests {
+	use jsonwebtoken::{encode, DecodingKey, EncodingKey, Header};
+	use ruma::{api::client::error::ErrorKind, server_name};
+	use serde_json::json;
+
+	use super::jwt_user;
+	use crate::Error;
+
+	fn jwt(sub: &str, secret: &[u8]) -> String {
+		let claims = json!({ "sub": sub, "exp": 4_102_444_800_u64 });
+		encode(&Header::default(), &claims, &EncodingKey::from_secret(secret)).unwrap()
+	}
+
+	#[test]
+	fn jwt_login() {
+		let key = DecodingKey::from_secret(b"secret");
+		let server_name = server_name!("example.com");
+
+		let user_id = jwt_user(&jwt("Alice", b"secret"), &key, server_name).unwrap();
+		assert_eq!(user_id.as_str(), "@alice:example.com");
+
+		for token in [
+			jwt("alice", b"other secret"),
+			jwt("not a user", b"secret"),
+			"garbage".to_owned(),
+		] {
+			let Error::BadRequest(kind, _) = jwt_user(&token, &key, server_name).unwrap_err() else {
+				panic!("expected the login to be rejected");
+			};
+			assert!(matches!(kind, ErrorKind::InvalidUsername));
+		}
+	}
+}
@@ -28,8 +28,9 @@ pub(crate) async fn get_hierarchy_route(body: Ruma<get_hierarchy::v1::Request>)
 
 	let key = body
 		.from
-		.as_ref()
-		.and_then(|s| PagnationToken::from_str(s).ok());
+		.as_deref()
+		.map(PagnationToken::from_str)
+		.transpose()?;
 
 	// Should prevent unexpeded behaviour in (bad) clients
 	if let Some(ref token) = key {
@@ -11,6 +11,7 @@ use ruma::{
 			canonical_alias::RoomCanonicalAliasEventContent,
 			history_visibility::{HistoryVisibility, RoomHistoryVisibilityEventContent},
 			join_rules::{JoinRule, RoomJoinRulesEventContent},
+			member::{MembershipState, RoomMemberEventContent},
 		},
 		AnyStateEventContent, StateEventType,
 	},
@@ -31,6 +32,8 @@ use crate::{
 /// - Tries to send the event into the room, auth rules will determine if it is
 ///   allowed
 /// - If event is new `canonical_alias`: Rejects if alias is incorrect
+/// - Guests may only join rooms allowing guest access and leave rooms through
+///   `m.room.member`
 pub(crate) async fn send_state_event_for_key_route(
 	body: Ruma<send_state_event::v3::Request>,
 ) -> Result<send_state_event::v3::Response> {
@@ -171,7 +174,7 @@ pub(crate) async fn get_state_events_for_empty_key_route(
 async fn send_state_event_for_key_helper(
 	sender: &UserId, room_id: &RoomId, event_type: &StateEventType, json: &Raw<AnyStateEventContent>, state_key: String,
 ) -> Result<Arc<EventId>> {
-	allowed_to_send_state_event(room_id, event_type, json).await?;
+	allowed_to_send_state_event(sender, room_id, event_type, json, &state_key).await?;
 	let state_lock = services().globals.roomid_mutex_state.lock(room_id).await;
 	let event_id = services()
 		.rooms
@@ -194,9 +197,23 @@ async fn send_state_event_for_key_helper(
 }
 
 async fn allowed_to_send_state_event(
-	room_id: &RoomId, event_type: &StateEventType, json: &Raw<AnyStateEventContent>,
+	sender: &UserId, room_id: &RoomId, event_type: &StateEventType, json: &Raw<AnyStateEventContent>, state_key: &str,
 ) -> Result<()> {
 	match event_type {
+		// guests are restricted the same as through the membership routes
+		StateEventType::RoomMember if services().users.is_guest(sender)? => {
+			let membership = serde_json::from_str::<RoomMemberEventContent>(json.json().get())
+				.map_err(|_| Error::BadRequest(ErrorKind::BadJson, "Invalid m.room.member content."))?
+				.membership;
+
+			let guest_can_join = services().rooms.state_accessor.guest_can_join(room_id)?;
+			if !guest_may_set_membership(sender, state_key, &membership, guest_can_join) {
+				return Err(Error::BadRequest(
+					ErrorKind::GuestAccessForbidden,
+					"Guests can only join rooms which allow guest access, and leave rooms.",
+				));
+			}
+		},
 		// Forbid m.room.encryption if encryption is disabled
 		StateEventType::RoomEncryption => {
 			if !services().globals.allow_encryption() {
@@ -265,3 +282,44 @@ async fn allowed_to_send_state_event(
 	}
 	Ok(())
 }
+
+/// Whether a guest may send the membership for `state_key`: only their own,
+/// joining rooms whose `m.room.guest_access` is `can_join` or leaving
+fn guest_may_set_membership(
+	sender: &UserId, state_key: &str, membership: &MembershipState, guest_can_join: bool,
+) -> bool {
+	sender.as_str() == state_key
+		&& match membership {
+			MembershipState::Join => guest_can_join,
+			MembershipState::Leave => true,
+			_ => false,
+		}
+}
+
+#[cfg(test)]
+mod tests {
+	use ruma::{events::room::member::MembershipState, user_id};
+
+	use super::guest_may_set_membership;
+
+	#[test]
+	fn guest_membership() {
+		let guest = user_id!("@guest:example.com");
+
+		assert!(guest_may_set_membership(guest, guest.as_str(), &MembershipState::Join, true));
+		assert!(!guest_may_set_membership(guest, guest.as_str(), &MembershipState::Join, false));
+		assert!(guest_may_set_membership(guest, guest.as_str(), &MembershipState::Leave, false));
+		assert!(!guest_may_set_membership(guest, guest.as_str(), &MembershipState::Knock, true));
+
+		// guests can't invite, kick or ban anyone
+		let other = "@other:example.com";
+		for membership in [
+			MembershipState::Invite,
+			MembershipState::Leave,
+			MembershipState::Ban,
+			MembershipState::Join,
+		] {
+			assert!(!guest_may_set_membership(guest, other, &membership, true));
+		}
+	}
+}
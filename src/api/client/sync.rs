@@ -1,6 +1,7 @@
 use std::{
 	cmp::Ordering,
 	collections::{hash_map::Entry, BTreeMap, BTreeSet, HashMap, HashSet},
+	future::Future,
 	time::Duration,
 };
 
@@ -21,15 +22,24 @@ use ruma::{
 	},
 	events::{
 		presence::PresenceEvent,
+		receipt::{Receipt, ReceiptEvent, ReceiptEventContent, ReceiptThread, ReceiptType},
 		room::member::{MembershipState, RoomMemberEventContent},
-		StateEventType, TimelineEventType,
+		AnyEphemeralRoomEvent, AnyRoomAccountDataEvent, AnySyncEphemeralRoomEvent, AnySyncStateEvent,
+		RoomAccountDataEventType, StateEventType, TimelineEventType,
 	},
 	serde::Raw,
 	uint, DeviceId, EventId, OwnedUserId, RoomId, UInt, UserId,
 };
 use tracing::{error, Instrument as _, Span};
 
-use crate::{service::pdu::EventHash, services, utils, Error, PduEvent, Result, Ruma, RumaResponse};
+use super::ignored_filter;
+use crate::{
+	service::{
+		account_data::TypeFilter,
+		pdu::{EventFormat, EventHash},
+	},
+	services, utils, Error, PduEvent, Result, Ruma, RumaResponse,
+};
 
 /// # `GET /_matrix/client/r0/sync`
 ///
@@ -82,6 +92,12 @@ pub(crate) async fn sync_events_route(
 
 	// Setup watchers, so if there's no response, we can wait for them
 	let watcher = services().globals.watch(&sender_user, &sender_device);
+	let sync_session = services()
+		.users
+		.sync_sessions
+		.connect(&sender_user, &sender_device, body.since.as_deref());
+	let kick = sync_session.kick();
+	let kicked = kick.notified();
 
 	let next_batch = services().globals.current_count()?;
 	let next_batchcount = PduCount::Normal(next_batch);
@@ -104,8 +120,22 @@ pub(crate) async fn sync_events_route(
 		LazyLoadOptions::Disabled => (false, false),
 	};
 
+	let event_format = EventFormat::from(&filter);
+	let room_account_data_filter = TypeFilter::from(&filter.room.account_data);
 	let full_state = body.full_state;
 
+	services()
+		.rooms
+		.lazy_loading
+		.lazy_load_sync(
+			&sender_user,
+			&sender_device,
+			lazy_load_enabled,
+			lazy_load_send_redundant,
+			full_state,
+		)
+		.await?;
+
 	let mut joined_rooms = BTreeMap::new();
 	let since = body
 		.since
@@ -140,12 +170,39 @@ pub(crate) async fn sync_events_route(
 	// Coalesce database writes for the remainder of this scope.
 	let _cork = services().globals.db.cork_and_flush();
 
+	// Initial syncs are capped in size; rooms past the cap are deferred to the
+	// following incremental syncs of this device.
+	let mut budget = ResponseBudget::new(if body.since.is_none() {
+		services().globals.config.sync.max_response_bytes
+	} else {
+		None
+	});
+	let deferred_rooms = services()
+		.rooms
+		.user
+		.sync_deferred_rooms(&sender_user, &sender_device)?;
+
 	for room_id in all_joined_rooms {
 		let room_id = room_id?;
+		if budget.exhausted() {
+			if let Ok(joined_room) =
+				load_deferred_joined_room(&sender_user, &sender_device, &room_id, &next_batch_string, &event_format)
+					.await
+			{
+				joined_rooms.insert(room_id.clone(), joined_room);
+			}
+			continue;
+		}
+
+		let deferred = deferred_rooms
+			.get(&room_id)
+			.map(|delivered_at| deferred_room_sync(*delivered_at, since));
+
 		if let Ok(joined_room) = load_joined_room(
 			&sender_user,
 			&sender_device,
 			&room_id,
+			deferred,
 			since,
 			sincecount,
 			next_batch,
@@ -153,17 +210,22 @@ pub(crate) async fn sync_events_route(
 			lazy_load_enabled,
 			lazy_load_send_redundant,
 			full_state,
+			&event_format,
+			&room_account_data_filter,
 			&mut device_list_updates,
 			&mut left_encrypted_users,
 		)
 		.await
 		{
 			if !joined_room.is_empty() {
+				budget.spend(joined_room_size(&joined_room));
 				joined_rooms.insert(room_id.clone(), joined_room);
 			}
 		}
 	}
 
+	forget_synced_leaves(&sender_user, since)?;
+
 	let mut left_rooms = BTreeMap::new();
 	let all_left_rooms: Vec<_> = services()
 		.rooms
@@ -171,14 +233,17 @@ pub(crate) async fn sync_events_route(
 		.rooms_left(&sender_user)
 		.collect();
 	for result in all_left_rooms {
+		let room_id = result?.0;
 		handle_left_room(
 			since,
-			&result?.0,
+			&room_id,
 			&sender_user,
 			&mut left_rooms,
 			&next_batch_string,
 			full_state,
 			lazy_load_enabled,
+			&event_format,
+			&room_account_data_filter,
 		)
 		.instrument(Span::current())
 		.await?;
@@ -263,7 +328,7 @@ pub(crate) async fn sync_events_route(
 		account_data: GlobalAccountData {
 			events: services()
 				.account_data
-				.changes_since(None, &sender_user, since)?
+				.changes_since_filtered(None, &sender_user, since, &TypeFilter::from(&filter.account_data))?
 				.into_iter()
 				.filter_map(|(_, v)| {
 					serde_json::from_str(v.json().get())
@@ -303,19 +368,24 @@ pub(crate) async fn sync_events_route(
 			duration = Duration::from_secs(30);
 		}
 
-		#[allow(clippy::let_underscore_must_use)]
-		{
-			_ = tokio::time::timeout(duration, watcher).await;
+		// Also stop when kicked by an admin
+		tokio::select! {
+			_ = tokio::time::timeout(duration, watcher) => {},
+			() = kicked => {},
 		}
+	} else {
+		sync_session.responded();
 	}
 
 	Ok(response)
 }
 
 #[tracing::instrument(skip_all, fields(user_id = %sender_user, room_id = %room_id), name = "left_room")]
+#[allow(clippy::too_many_arguments)]
 async fn handle_left_room(
 	since: u64, room_id: &RoomId, sender_user: &UserId, left_rooms: &mut BTreeMap<ruma::OwnedRoomId, LeftRoom>,
-	next_batch_string: &str, full_state: bool, lazy_load_enabled: bool,
+	next_batch_string: &str, full_state: bool, lazy_load_enabled: bool, event_format: &EventFormat,
+	account_data_filter: &TypeFilter<'_>,
 ) -> Result<()> {
 	// Get and drop the lock to wait for remaining operations to finish
 	let insert_lock = services().globals.roomid_mutex_insert.lock(room_id).await;
@@ -326,11 +396,29 @@ async fn handle_left_room(
 		.state_cache
 		.get_left_count(room_id, sender_user)?;
 
-	// Left before last sync
-	if Some(since) >= left_count {
-		return Ok(());
+	let left_room = left_room_since(
+		since,
+		left_count,
+		next_batch_string,
+		|| room_account_data_since(room_id, sender_user, since, account_data_filter),
+		|| left_state(since, room_id, sender_user, full_state, lazy_load_enabled, event_format),
+	)
+	.await?;
+
+	if let Some(left_room) = left_room {
+		left_rooms.insert(room_id.to_owned(), left_room);
 	}
 
+	Ok(())
+}
+
+/// The state of a left room for a sync from `since`: the state up to the
+/// leave which changed since, or only the leave of a rejected invite to a room
+/// unknown to us. `None` if the leave can't be found.
+async fn left_state(
+	since: u64, room_id: &RoomId, sender_user: &UserId, full_state: bool, lazy_load_enabled: bool,
+	event_format: &EventFormat,
+) -> Result<Option<Vec<Raw<AnySyncStateEvent>>>> {
 	if !services().rooms.metadata.exists(room_id)? {
 		// This is just a rejected invite, not a room we know
 		// Insert a leave event anyways
@@ -357,23 +445,7 @@ async fn handle_left_room(
 			signatures: None,
 		};
 
-		left_rooms.insert(
-			room_id.to_owned(),
-			LeftRoom {
-				account_data: RoomAccountData {
-					events: Vec::new(),
-				},
-				timeline: Timeline {
-					limited: false,
-					prev_batch: Some(next_batch_string.to_owned()),
-					events: Vec::new(),
-				},
-				state: State {
-					events: vec![event.to_sync_state_event()],
-				},
-			},
-		);
-		return Ok(());
+		return Ok(Some(vec![event.to_sync_state_event_formatted(event_format)]));
 	}
 
 	let mut left_state_events = Vec::new();
@@ -395,7 +467,7 @@ async fn handle_left_room(
 	)?
 	else {
 		error!("Left room but no left state event");
-		return Ok(());
+		return Ok(None);
 	};
 
 	let Some(left_shortstatehash) = services()
@@ -404,7 +476,7 @@ async fn handle_left_room(
 		.pdu_shortstatehash(&left_event_id)?
 	else {
 		error!(event_id = %left_event_id, "Leave event has no state");
-		return Ok(());
+		return Ok(None);
 	};
 
 	let mut left_state_ids = services()
@@ -436,7 +508,7 @@ async fn handle_left_room(
 					continue;
 				};
 
-				left_state_events.push(pdu.to_sync_state_event());
+				left_state_events.push(pdu.to_sync_state_event_formatted(event_format));
 
 				i = i.wrapping_add(1);
 				if i % 100 == 0 {
@@ -446,25 +518,156 @@ async fn handle_left_room(
 		}
 	}
 
-	left_rooms.insert(
-		room_id.to_owned(),
-		LeftRoom {
-			account_data: RoomAccountData {
-				events: Vec::new(),
-			},
-			timeline: Timeline {
-				limited: false,
-				prev_batch: Some(next_batch_string.to_owned()),
-				events: Vec::new(),
-			},
-			state: State {
-				events: left_state_events,
-			},
-		},
-	);
+	Ok(Some(left_state_events))
+}
+
+/// The `leave` entry of a room for a sync from `since`, or `None` if the leave
+/// was delivered before. The account data and state are only loaded for a
+/// new leave.
+async fn left_room_since<A, S, F>(
+	since: u64, left_count: Option<u64>, prev_batch: &str, account_data: A, state: S,
+) -> Result<Option<LeftRoom>>
+where
+	A: FnOnce() -> Result<Vec<Raw<AnyRoomAccountDataEvent>>>,
+	S: FnOnce() -> F,
+	F: Future<Output = Result<Option<Vec<Raw<AnySyncStateEvent>>>>>,
+{
+	// Left before last sync
+	if !left_since(since, left_count) {
+		return Ok(None);
+	}
+
+	let Some(state) = state().await? else {
+		return Ok(None);
+	};
+
+	Ok(Some(left_room(account_data()?, state, prev_batch)))
+}
+
+/// Whether the user left the room after `since`, so the leave is new to this
+/// sync
+fn left_since(since: u64, left_count: Option<u64>) -> bool { Some(since) < left_count }
+
+/// Whether the client synced past the leave: `since` was handed out by a sync
+/// which already carried it, so it was received rather than only sent
+fn leave_synced(since: u64, left_count: Option<u64>) -> bool { left_count.is_some_and(|left| left <= since) }
+
+/// Forgets the rooms left with `forget_forced_upon_leave` once the client came
+/// back with a token after the leave. Forgetting when the leave is sent would
+/// lose it with a response the client never got.
+fn forget_synced_leaves(sender_user: &UserId, since: u64) -> Result<()> {
+	if since == 0 || !services().globals.config.forget_forced_upon_leave {
+		return Ok(());
+	}
+
+	let left: Vec<_> = services()
+		.rooms
+		.state_cache
+		.rooms_left(sender_user)
+		.map(|room| room.map(|(room_id, _)| room_id))
+		.collect::<Result<_>>()?;
+
+	for room_id in left {
+		let left_count = services()
+			.rooms
+			.state_cache
+			.get_left_count(&room_id, sender_user)?;
+
+		if leave_synced(since, left_count) {
+			services()
+				.rooms
+				.state_cache
+				.forget_upon_leave(&room_id, sender_user)?;
+		}
+	}
+
 	Ok(())
 }
 
+/// The `leave` entry of a room, carrying the room account data changed in the
+/// sync window and the state up to the leave
+fn left_room(
+	account_data: Vec<Raw<AnyRoomAccountDataEvent>>, state: Vec<Raw<AnySyncStateEvent>>, prev_batch: &str,
+) -> LeftRoom {
+	LeftRoom {
+		account_data: RoomAccountData {
+			events: account_data,
+		},
+		timeline: Timeline {
+			limited: false,
+			prev_batch: Some(prev_batch.to_owned()),
+			events: Vec::new(),
+		},
+		state: State {
+			events: state,
+		},
+	}
+}
+
+/// The user's private read receipt, which only their own devices see, at the
+/// latest event up to their private read marker
+fn private_read_receipt(user_id: &UserId, room_id: &RoomId) -> Result<Option<Raw<AnySyncEphemeralRoomEvent>>> {
+	let Some(count) = services()
+		.rooms
+		.read_receipt
+		.private_read_get(room_id, user_id)?
+	else {
+		return Ok(None);
+	};
+
+	// the marker may be between events, e.g. when it was set by the user sending
+	let Some((_, pdu)) = services()
+		.rooms
+		.timeline
+		.pdus_until(user_id, room_id, PduCount::Normal(count.saturating_add(1)))?
+		.find_map(Result::ok)
+	else {
+		return Ok(None);
+	};
+
+	let receipt = Receipt {
+		ts: None,
+		thread: ReceiptThread::Unthreaded,
+	};
+	let receipts = BTreeMap::from([(ReceiptType::ReadPrivate, BTreeMap::from([(user_id.to_owned(), receipt)]))]);
+	let event = ReceiptEvent {
+		content: ReceiptEventContent(BTreeMap::from([((*pdu.event_id).to_owned(), receipts)])),
+		room_id: room_id.to_owned(),
+	};
+
+	Ok(Some(
+		serde_json::from_str(&serde_json::to_string(&event).expect("event is valid, we just created it"))
+			.expect("event is valid, we just created it"),
+	))
+}
+
+/// Room account data of the user changed after `since` which the filter
+/// matches, for both joined and left rooms
+fn room_account_data_since(
+	room_id: &RoomId, sender_user: &UserId, since: u64, filter: &TypeFilter<'_>,
+) -> Result<Vec<Raw<AnyRoomAccountDataEvent>>> {
+	Ok(room_account_data(services().account_data.changes_since_filtered(
+		Some(room_id),
+		sender_user,
+		since,
+		filter,
+	)?))
+}
+
+/// The room account data events of the changes, one per event type
+fn room_account_data(
+	changes: HashMap<RoomAccountDataEventType, Raw<AnyEphemeralRoomEvent>>,
+) -> Vec<Raw<AnyRoomAccountDataEvent>> {
+	changes
+		.into_values()
+		.filter_map(|v| {
+			serde_json::from_str(v.json().get())
+				.map_err(|_| Error::bad_database("Invalid account event in database."))
+				.ok()
+		})
+		.collect()
+}
+
 async fn process_presence_updates(
 	presence_updates: &mut HashMap<OwnedUserId, PresenceEvent>, since: u64, syncing_user: &UserId,
 ) -> Result<()> {
@@ -514,9 +717,10 @@ async fn process_presence_updates(
 
 #[allow(clippy::too_many_arguments)]
 async fn load_joined_room(
-	sender_user: &UserId, sender_device: &DeviceId, room_id: &RoomId, since: u64, sincecount: PduCount,
-	next_batch: u64, next_batchcount: PduCount, lazy_load_enabled: bool, lazy_load_send_redundant: bool,
-	full_state: bool, device_list_updates: &mut HashSet<OwnedUserId>, left_encrypted_users: &mut HashSet<OwnedUserId>,
+	sender_user: &UserId, sender_device: &DeviceId, room_id: &RoomId, deferred: Option<DeferredSync>, since: u64,
+	sincecount: PduCount, next_batch: u64, next_batchcount: PduCount, lazy_load_enabled: bool,
+	lazy_load_send_redundant: bool, full_state: bool, event_format: &EventFormat, account_data_filter: &TypeFilter<'_>,
+	device_list_updates: &mut HashSet<OwnedUserId>, left_encrypted_users: &mut HashSet<OwnedUserId>,
 ) -> Result<JoinedRoom> {
 	// Get and drop the lock to wait for remaining operations to finish
 	// This will make sure the we have all events until next_batch
@@ -550,18 +754,44 @@ async fn load_joined_room(
 		return Err(Error::BadDatabase("Room has no state"));
 	};
 
-	let since_shortstatehash = services()
-		.rooms
-		.user
-		.get_token_shortstatehash(room_id, since)?;
+	// A room that only got a placeholder in a size-capped initial sync is sent as
+	// if this were an initial sync for it, until the device received it.
+	let since_shortstatehash = match deferred {
+		Some(DeferredSync::Full) => {
+			services()
+				.rooms
+				.user
+				.mark_sync_delivered(sender_user, sender_device, room_id, next_batch)?;
+			None
+		},
+		Some(DeferredSync::Received) => {
+			services()
+				.rooms
+				.user
+				.clear_sync_deferred(sender_user, sender_device, room_id)?;
+			services()
+				.rooms
+				.user
+				.get_token_shortstatehash(room_id, since)?
+		},
+		None => services()
+			.rooms
+			.user
+			.get_token_shortstatehash(room_id, since)?,
+	};
 
 	let (heroes, joined_member_count, invited_member_count, joined_since_last_sync, state_events) =
 		if timeline_pdus.is_empty() && since_shortstatehash == Some(current_shortstatehash) {
 			// No state changes
 			(Vec::new(), None, None, false, Vec::new())
 		} else {
-			// Calculates joined_member_count, invited_member_count and heroes
+			// Calculates joined_member_count, invited_member_count and heroes, which are
+			// left out while the room's membership is incomplete
 			let calculate_counts = || {
+				if services().rooms.metadata.is_incomplete(room_id)? {
+					return Ok((None, None, Vec::new()));
+				}
+
 				let joined_member_count = services()
 					.rooms
 					.state_cache
@@ -918,21 +1148,11 @@ async fn load_joined_room(
 		None
 	};
 
-	let prev_batch = timeline_pdus
-		.first()
-		.map_or(Ok::<_, Error>(None), |(pdu_count, _)| {
-			Ok(Some(match pdu_count {
-				PduCount::Backfilled(_) => {
-					error!("timeline in backfill state?!");
-					"0".to_owned()
-				},
-				PduCount::Normal(c) => c.to_string(),
-			}))
-		})?;
+	let prev_batch = prev_batch_token(&timeline_pdus);
 
 	let room_events: Vec<_> = timeline_pdus
 		.iter()
-		.map(|(_, pdu)| pdu.to_sync_room_event())
+		.map(|(_, pdu)| pdu.to_sync_room_event_formatted(event_format))
 		.collect();
 
 	let mut edus: Vec<_> = services()
@@ -943,6 +1163,17 @@ async fn load_joined_room(
 		.map(|(_, _, v)| v)
 		.collect();
 
+	if services()
+		.rooms
+		.read_receipt
+		.last_privateread_update(sender_user, room_id)?
+		> since
+	{
+		if let Some(receipt) = private_read_receipt(sender_user, room_id)? {
+			edus.push(receipt);
+		}
+	}
+
 	if services().rooms.typing.last_typing_update(room_id).await? > since {
 		edus.push(
 			serde_json::from_str(
@@ -962,16 +1193,7 @@ async fn load_joined_room(
 
 	Ok(JoinedRoom {
 		account_data: RoomAccountData {
-			events: services()
-				.account_data
-				.changes_since(Some(room_id), sender_user, since)?
-				.into_iter()
-				.filter_map(|(_, v)| {
-					serde_json::from_str(v.json().get())
-						.map_err(|_| Error::bad_database("Invalid account event in database."))
-						.ok()
-				})
-				.collect(),
+			events: room_account_data_since(room_id, sender_user, since, account_data_filter)?,
 		},
 		summary: RoomSummary {
 			heroes,
@@ -990,7 +1212,7 @@ async fn load_joined_room(
 		state: State {
 			events: state_events
 				.iter()
-				.map(|pdu| pdu.to_sync_state_event())
+				.map(|pdu| pdu.to_sync_state_event_formatted(event_format))
 				.collect(),
 		},
 		ephemeral: Ephemeral {
@@ -1000,17 +1222,129 @@ async fn load_joined_room(
 	})
 }
 
+/// Placeholder for a joined room which did not fit in a size-capped initial
+/// sync: the timeline is limited and empty and only the state needed to
+/// render the room list is included. The room is marked so the device's next
+/// sync sends it in full.
+async fn load_deferred_joined_room(
+	sender_user: &UserId, sender_device: &DeviceId, room_id: &RoomId, next_batch_string: &str,
+	event_format: &EventFormat,
+) -> Result<JoinedRoom> {
+	// Get and drop the lock to wait for remaining operations to finish
+	let insert_lock = services().globals.roomid_mutex_insert.lock(room_id).await;
+	drop(insert_lock);
+
+	let mut state_events = Vec::new();
+	for (event_type, state_key) in [
+		(StateEventType::RoomCreate, ""),
+		(StateEventType::RoomName, ""),
+		(StateEventType::RoomAvatar, ""),
+		(StateEventType::RoomCanonicalAlias, ""),
+		(StateEventType::RoomEncryption, ""),
+		(StateEventType::RoomMember, sender_user.as_str()),
+	] {
+		if let Some(pdu) = services()
+			.rooms
+			.state_accessor
+			.room_state_get(room_id, &event_type, state_key)?
+		{
+			state_events.push(pdu.to_sync_state_event_formatted(event_format));
+		}
+	}
+
+	services()
+		.rooms
+		.user
+		.mark_sync_deferred(sender_user, sender_device, room_id)?;
+
+	Ok(JoinedRoom {
+		account_data: RoomAccountData {
+			events: Vec::new(),
+		},
+		summary: RoomSummary {
+			heroes: Vec::new(),
+			joined_member_count: None,
+			invited_member_count: None,
+		},
+		unread_notifications: UnreadNotificationsCount {
+			highlight_count: None,
+			notification_count: None,
+		},
+		timeline: Timeline {
+			limited: true,
+			prev_batch: Some(next_batch_string.to_owned()),
+			events: Vec::new(),
+		},
+		state: State {
+			events: state_events,
+		},
+		ephemeral: Ephemeral {
+			events: Vec::new(),
+		},
+		unread_thread_notifications: BTreeMap::new(),
+	})
+}
+
+/// What a sync does with a room deferred by a size-capped initial sync
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum DeferredSync {
+	/// The room is sent as if this were an initial sync for it
+	Full,
+
+	/// The device synced from the token of the response which sent the room in
+	/// full, so it is synced incrementally from now on
+	Received,
+}
+
+/// A deferred room is sent in full until the device syncs from the token of a
+/// response which did; a retry from an older token gets it in full again.
+fn deferred_room_sync(delivered_at: Option<u64>, since: u64) -> DeferredSync {
+	match delivered_at {
+		Some(next_batch) if since >= next_batch => DeferredSync::Received,
+		_ => DeferredSync::Full,
+	}
+}
+
+/// Tracks the estimated size of a /sync response against an optional cap.
+struct ResponseBudget {
+	max: Option<usize>,
+	used: usize,
+}
+
+impl ResponseBudget {
+	fn new(max: Option<usize>) -> Self {
+		Self {
+			max,
+			used: 0,
+		}
+	}
+
+	fn spend(&mut self, bytes: usize) { self.used = self.used.saturating_add(bytes); }
+
+	fn exhausted(&self) -> bool { self.max.is_some_and(|max| self.used >= max) }
+}
+
+/// Estimates the serialized size of a joined room from the raw JSON of the
+/// events it carries.
+fn joined_room_size(room: &JoinedRoom) -> usize {
+	fn raw_size<T>(events: &[Raw<T>]) -> usize { events.iter().map(|event| event.json().get().len()).sum() }
+
+	raw_size(&room.timeline.events)
+		.saturating_add(raw_size(&room.state.events))
+		.saturating_add(raw_size(&room.ephemeral.events))
+		.saturating_add(raw_size(&room.account_data.events))
+}
+
 fn load_timeline(
 	sender_user: &UserId, room_id: &RoomId, roomsincecount: PduCount, limit: u64,
 ) -> Result<(Vec<(PduCount, PduEvent)>, bool), Error> {
-	let timeline_pdus;
-	let limited = if services()
+	let (mut timeline_pdus, limited) = if services()
 		.rooms
 		.timeline
 		.last_timeline_count(sender_user, room_id)?
 		> roomsincecount
 	{
-		let mut non_timeline_pdus = services()
+		let pdus = services()
 			.rooms
 			.timeline
 			.pdus_until(sender_user, room_id, PduCount::max())?
@@ -1021,27 +1355,56 @@ fn load_timeline(
 				}
 				r.ok()
 			})
-			.take_while(|(pducount, _)| pducount > &roomsincecount);
-
-		// Take the last events for the timeline
-		timeline_pdus = non_timeline_pdus
-			.by_ref()
-			.take(limit as usize)
-			.collect::<Vec<_>>()
-			.into_iter()
-			.rev()
-			.collect::<Vec<_>>();
+			.filter(|(_, pdu)| ignored_filter(pdu, sender_user));
 
-		// They /sync response doesn't always return all messages, so we say the output
-		// is limited unless there are events in non_timeline_pdus
-		non_timeline_pdus.next().is_some()
+		take_timeline(pdus, roomsincecount, limit as usize)
 	} else {
-		timeline_pdus = Vec::new();
-		false
+		(Vec::new(), false)
 	};
+
+	for (_, pdu) in &mut timeline_pdus {
+		services()
+			.rooms
+			.threads
+			.add_bundled_aggregation(sender_user, pdu)?;
+	}
+
 	Ok((timeline_pdus, limited))
 }
 
+/// Takes the last `limit` events newer than `since` from `pdus`, which are
+/// ordered newest first, and returns them oldest first with whether older
+/// events were left out. An initial sync (`since` of zero) covers the whole
+/// room, backfilled history included, so it is limited whenever any event
+/// precedes the window.
+fn take_timeline<I, T>(pdus: I, since: PduCount, limit: usize) -> (Vec<(PduCount, T)>, bool)
+where
+	I: Iterator<Item = (PduCount, T)>,
+{
+	let since = if since == PduCount::Normal(0) {
+		PduCount::min()
+	} else {
+		since
+	};
+
+	let mut pdus = pdus.take_while(|(count, _)| *count > since);
+	let mut timeline: Vec<_> = pdus.by_ref().take(limit).collect();
+	timeline.reverse();
+
+	// The /sync response doesn't always return all messages, so we say the output
+	// is limited unless there are no more events
+	let limited = pdus.next().is_some();
+
+	(timeline, limited)
+}
+
+/// Token for paginating backwards from the first event of a timeline chunk.
+/// /messages continues strictly before the event, so it is not sent twice, and
+/// a backfilled event keeps its backfill position.
+fn prev_batch_token<T>(timeline: &[(PduCount, T)]) -> Option<String> {
+	timeline.first().map(|(count, _)| count.stringify())
+}
+
 fn share_encrypted_room(sender_user: &UserId, user_id: &UserId, ignore_room: &RoomId) -> Result<bool> {
 	Ok(services()
 		.rooms
@@ -1111,6 +1474,10 @@ pub(crate) async fn sync_events_v4_route(
 			.remove_to_device_events(&sender_user, &sender_device, globalsince)?;
 	}
 
+	// left rooms drop out of the lists of sliding sync once the leave is past
+	// `pos`
+	forget_synced_leaves(&sender_user, globalsince)?;
+
 	let mut left_encrypted_users = HashSet::new(); // Users that have left any encrypted rooms the sender was in
 	let mut device_list_changes = HashSet::new();
 	let mut device_list_left = HashSet::new();
@@ -1406,24 +1773,13 @@ pub(crate) async fn sync_events_v4_route(
 			continue;
 		}
 
-		let prev_batch = timeline_pdus
-			.first()
-			.map_or(Ok::<_, Error>(None), |(pdu_count, _)| {
-				Ok(Some(match pdu_count {
-					PduCount::Backfilled(_) => {
-						error!("timeline in backfill state?!");
-						"0".to_owned()
-					},
-					PduCount::Normal(c) => c.to_string(),
-				}))
-			})?
-			.or_else(|| {
-				if roomsince != &0 {
-					Some(roomsince.to_string())
-				} else {
-					None
-				}
-			});
+		let prev_batch = prev_batch_token(&timeline_pdus).or_else(|| {
+			if roomsince != &0 {
+				Some(roomsince.to_string())
+			} else {
+				None
+			}
+		});
 
 		let room_events: Vec<_> = timeline_pdus
 			.iter()
@@ -1484,6 +1840,9 @@ pub(crate) async fn sync_events_v4_route(
 			Ordering::Less => None,
 		};
 
+		// Member counts of rooms with incomplete membership are left out
+		let membership_complete = !services().rooms.metadata.is_incomplete(room_id)?;
+
 		let heroes_avatar = if heroes.len() == 1 {
 			heroes[0].1.clone()
 		} else {
@@ -1528,22 +1887,14 @@ pub(crate) async fn sync_events_v4_route(
 				required_state,
 				prev_batch,
 				limited,
-				joined_count: Some(
-					(services()
-						.rooms
-						.state_cache
-						.room_joined_count(room_id)?
-						.unwrap_or(0) as u32)
-						.into(),
-				),
-				invited_count: Some(
-					(services()
-						.rooms
-						.state_cache
-						.room_invited_count(room_id)?
-						.unwrap_or(0) as u32)
-						.into(),
-				),
+				joined_count: membership_complete
+					.then(|| services().rooms.state_cache.room_joined_count(room_id))
+					.transpose()?
+					.map(|count| (count.unwrap_or(0) as u32).into()),
+				invited_count: membership_complete
+					.then(|| services().rooms.state_cache.room_invited_count(room_id))
+					.transpose()?
+					.map(|count| (count.unwrap_or(0) as u32).into()),
 				num_live: None, // Count events in timeline greater than global sync counter
 				timestamp: None,
 				heroes: None,
@@ -1622,3 +1973,215 @@ pub(crate) async fn sync_events_v4_route(
 		delta_token: None,
 	})
 }
+
+#[cfg(test)]
+mod tests {
+	use std::collections::{BTreeMap, HashMap};
+
+	use conduit::PduCount;
+	use ruma::{
+		api::client::sync::sync_events::v3::JoinedRoom,
+		events::{AnyRoomAccountDataEvent, RoomAccountDataEventType},
+		serde::Raw,
+	};
+	use serde_json::{json, value::to_raw_value};
+
+	use super::{
+		deferred_room_sync, joined_room_size, leave_synced, left_room_since, left_since, prev_batch_token,
+		room_account_data, take_timeline, DeferredSync, ResponseBudget,
+	};
+
+	fn large_room(events: usize, body_len: usize) -> JoinedRoom {
+		let body = "x".repeat(body_len);
+		let mut room = JoinedRoom::default();
+		room.timeline.events = (0..events)
+			.map(|i| {
+				let event = json!({
+					"type": "m.room.message",
+					"event_id": format!("$event{i}"),
+					"sender": "@alice:example.com",
+					"origin_server_ts": 0,
+					"content": { "msgtype": "m.text", "body": body },
+				});
+				Raw::from_json(to_raw_value(&event).expect("event serializes"))
+			})
+			.collect();
+		room
+	}
+
+	#[test]
+	fn initial_sync_is_bounded_and_converges() {
+		const MAX: usize = 1024 * 1024;
+		let room = large_room(10, 16 * 1024);
+		let size = joined_room_size(&room);
+
+		// the rooms are admitted while the budget lasts, so the response
+		// overshoots the cap by at most one room
+		let mut budget = ResponseBudget::new(Some(MAX));
+		let mut sent = 0_usize;
+		while !budget.exhausted() {
+			budget.spend(size);
+			sent = sent.saturating_add(1);
+		}
+		assert!(budget.used < MAX.saturating_add(size));
+		assert!(sent < 200, "200 large rooms should not fit under the cap");
+
+		// incremental syncs are never capped
+		let mut budget = ResponseBudget::new(None);
+		budget.spend(200 * size);
+		assert!(!budget.exhausted());
+	}
+
+	#[test]
+	fn deferred_room_sent_until_received() {
+		// the capped initial sync hands out 10 with a placeholder
+		assert_eq!(deferred_room_sync(None, 10), DeferredSync::Full);
+
+		// the sync from 10 sends it in full and hands out 20; the response is
+		// lost and the retry from 10 sends it in full again, handing out 25
+		assert_eq!(deferred_room_sync(Some(20), 10), DeferredSync::Full);
+
+		// only the sync from 25 shows the full room was received
+		assert_eq!(deferred_room_sync(Some(25), 20), DeferredSync::Full);
+		assert_eq!(deferred_room_sync(Some(25), 25), DeferredSync::Received);
+		assert_eq!(deferred_room_sync(Some(25), 30), DeferredSync::Received);
+	}
+
+	/// Timeline counts of a room newest first, as `pdus_until` returns them:
+	/// normal events 100..=102, and the backfilled events before them, the
+	/// oldest one being the room's first event
+	fn room(backfilled: u64) -> Vec<(PduCount, ())> {
+		(100..=102)
+			.rev()
+			.map(PduCount::Normal)
+			.chain((1..=backfilled).map(PduCount::Backfilled))
+			.map(|count| (count, ()))
+			.collect()
+	}
+
+	fn counts(timeline: &[(PduCount, ())]) -> Vec<PduCount> { timeline.iter().map(|(count, ())| *count).collect() }
+
+	#[test]
+	fn limited_window_starting_at_backfill_boundary() {
+		let (timeline, limited) = take_timeline(room(5).into_iter(), PduCount::Normal(0), 3);
+		assert_eq!(
+			counts(&timeline),
+			[PduCount::Normal(100), PduCount::Normal(101), PduCount::Normal(102)]
+		);
+		assert!(limited, "backfilled history precedes the window");
+
+		// /messages continues with the newest backfilled event
+		let token = prev_batch_token(&timeline).expect("timeline is not empty");
+		assert_eq!(token, "100");
+		assert_eq!(PduCount::try_from_string(&token).expect("valid token"), PduCount::Normal(100));
+	}
+
+	#[test]
+	fn limited_window_reaching_into_backfill() {
+		let (timeline, limited) = take_timeline(room(5).into_iter(), PduCount::Normal(0), 5);
+		assert_eq!(counts(&timeline)[0], PduCount::Backfilled(2));
+		assert!(limited);
+
+		let token = prev_batch_token(&timeline).expect("timeline is not empty");
+		assert_eq!(token, "-2");
+		assert_eq!(PduCount::try_from_string(&token).expect("valid token"), PduCount::Backfilled(2));
+	}
+
+	#[test]
+	fn window_within_backfill() {
+		let pdus = (1..=10).map(|count| (PduCount::Backfilled(count), ()));
+		let (timeline, limited) = take_timeline(pdus, PduCount::Normal(0), 4);
+		assert_eq!(
+			counts(&timeline),
+			[
+				PduCount::Backfilled(4),
+				PduCount::Backfilled(3),
+				PduCount::Backfilled(2),
+				PduCount::Backfilled(1)
+			]
+		);
+		assert!(limited);
+		assert_eq!(prev_batch_token(&timeline).as_deref(), Some("-4"));
+	}
+
+	#[test]
+	fn window_starting_at_first_event() {
+		let (timeline, limited) = take_timeline(room(2).into_iter(), PduCount::Normal(0), 10);
+		assert_eq!(counts(&timeline)[0], PduCount::Backfilled(2));
+		assert_eq!(timeline.len(), 5);
+		assert!(!limited, "nothing precedes the room's first event");
+		assert_eq!(prev_batch_token(&timeline).as_deref(), Some("-2"));
+	}
+
+	#[test]
+	fn incremental_sync_leaves_out_backfill() {
+		let (timeline, limited) = take_timeline(room(5).into_iter(), PduCount::Normal(100), 10);
+		assert_eq!(counts(&timeline), [PduCount::Normal(101), PduCount::Normal(102)]);
+		assert!(!limited);
+
+		let (timeline, limited) = take_timeline(room(5).into_iter(), PduCount::Normal(100), 1);
+		assert_eq!(counts(&timeline), [PduCount::Normal(102)]);
+		assert!(limited);
+	}
+
+	#[tokio::test]
+	async fn tags_of_room_left_during_window() {
+		let since = 5;
+		let tags = json!({ "type": "m.tag", "content": { "tags": { "u.work": {} } } });
+		let changes = || {
+			HashMap::from([(
+				RoomAccountDataEventType::Tag,
+				Raw::from_json(to_raw_value(&tags).expect("event serializes")),
+			)])
+		};
+
+		// the earlier leave was already delivered, nothing is loaded for it
+		let before = left_room_since(
+			since,
+			Some(3),
+			"8",
+			|| unreachable!("account data of a delivered leave"),
+			|| async { unreachable!("state of a delivered leave") },
+		)
+		.await
+		.unwrap();
+		assert!(before.is_none());
+
+		let room = left_room_since(
+			since,
+			Some(7),
+			"8",
+			|| Ok(room_account_data(changes())),
+			|| async { Ok(Some(Vec::new())) },
+		)
+		.await
+		.unwrap()
+		.unwrap();
+		assert_eq!(room.timeline.prev_batch.as_deref(), Some("8"));
+		assert_eq!(room.account_data.events.len(), 1);
+		let AnyRoomAccountDataEvent::Tag(event) = room.account_data.events[0].deserialize().unwrap() else {
+			panic!("expected the tags of the room");
+		};
+		assert_eq!(event.content.tags.len(), 1);
+
+		// a leave without state is left out
+		let missing = left_room_since(since, Some(7), "8", || Ok(room_account_data(changes())), || async { Ok(None) })
+			.await
+			.unwrap();
+		assert!(missing.is_none());
+	}
+
+	#[test]
+	fn leave_forgotten_after_next_since() {
+		// the sync from 5 carries the leave at 7 and hands out 8; if that
+		// response is lost, the retry from 5 carries the leave again
+		assert!(left_since(5, Some(7)));
+		assert!(!leave_synced(5, Some(7)));
+
+		// the client only received it once it comes back with 8
+		assert!(leave_synced(8, Some(7)));
+		assert!(!left_since(8, Some(7)));
+
+		assert!(!leave_synced(8, None));
+	}
+}
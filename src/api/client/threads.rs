@@ -1,21 +1,13 @@
-use ruma::{
-	api::client::{error::ErrorKind, threads::get_threads},
-	uint,
-};
+use ruma::api::client::{error::ErrorKind, threads::get_threads};
 
+use super::pagination::Paginated;
 use crate::{services, Error, Result, Ruma};
 
 /// # `GET /_matrix/client/r0/rooms/{roomId}/threads`
 pub(crate) async fn get_threads_route(body: Ruma<get_threads::v1::Request>) -> Result<get_threads::v1::Response> {
 	let sender_user = body.sender_user.as_ref().expect("user is authenticated");
 
-	// Use limit or else 10, with maximum 100
-	let limit = body
-		.limit
-		.unwrap_or_else(|| uint!(10))
-		.try_into()
-		.unwrap_or(10)
-		.min(100);
+	let limit = Paginated::Threads.limit(body.limit);
 
 	let from = if let Some(from) = &body.from {
 		from.parse()
@@ -24,7 +16,7 @@ pub(crate) async fn get_threads_route(body: Ruma<get_threads::v1::Request>) -> R
 		u64::MAX
 	};
 
-	let threads = services()
+	let mut threads = services()
 		.rooms
 		.threads
 		.threads_until(sender_user, &body.room_id, from, &body.include)?
@@ -39,6 +31,13 @@ pub(crate) async fn get_threads_route(body: Ruma<get_threads::v1::Request>) -> R
 		})
 		.collect::<Vec<_>>();
 
+	for (_, pdu) in &mut threads {
+		services()
+			.rooms
+			.threads
+			.add_bundled_aggregation(sender_user, pdu)?;
+	}
+
 	let next_batch = threads.last().map(|(count, _)| count.to_string());
 
 	Ok(get_threads::v1::Response {
@@ -1,14 +1,11 @@
 use std::collections::BTreeMap;
 
-use ruma::{
-	api::{
-		client::{error::ErrorKind, to_device::send_event_to_device},
-		federation::{self, transactions::edu::DirectDeviceContent},
-	},
-	to_device::DeviceIdOrAllDevices,
+use ruma::api::{
+	client::to_device::send_event_to_device,
+	federation::{self, transactions::edu::DirectDeviceContent},
 };
 
-use crate::{services, user_is_local, Error, Result, Ruma};
+use crate::{services, user_is_local, Result, Ruma};
 
 /// # `PUT /_matrix/client/r0/sendToDevice/{eventType}/{txnId}`
 ///
@@ -19,6 +16,9 @@ pub(crate) async fn send_event_to_device_route(
 	let sender_user = body.sender_user.as_ref().expect("user is authenticated");
 	let sender_device = body.sender_device.as_deref();
 
+	// Messages of a sender are stored in the order of their requests
+	let to_device_lock = services().users.to_device.lock(sender_user).await;
+
 	// Check if this is a new transaction id
 	if services()
 		.transaction_ids
@@ -28,56 +28,36 @@ pub(crate) async fn send_event_to_device_route(
 		return Ok(send_event_to_device::v3::Response {});
 	}
 
-	for (target_user_id, map) in &body.messages {
-		for (target_device_id_maybe, event) in map {
-			if !user_is_local(target_user_id) {
-				let mut map = BTreeMap::new();
-				map.insert(target_device_id_maybe.clone(), event.clone());
-				let mut messages = BTreeMap::new();
-				messages.insert(target_user_id.clone(), map);
-				let count = services().globals.next_count()?;
+	services().users.add_to_device_events(
+		sender_user,
+		&body.event_type.to_string(),
+		body.messages
+			.iter()
+			.filter(|(target_user_id, _)| user_is_local(target_user_id)),
+	)?;
 
-				services().sending.send_edu_server(
-					target_user_id.server_name(),
-					serde_json::to_vec(&federation::transactions::edu::Edu::DirectToDevice(DirectDeviceContent {
-						sender: sender_user.clone(),
-						ev_type: body.event_type.clone(),
-						message_id: count.to_string().into(),
-						messages,
-					}))
-					.expect("DirectToDevice EDU can be serialized"),
-				)?;
-
-				continue;
-			}
+	for (target_user_id, map) in &body.messages {
+		if user_is_local(target_user_id) {
+			continue;
+		}
 
-			match target_device_id_maybe {
-				DeviceIdOrAllDevices::DeviceId(target_device_id) => {
-					services().users.add_to_device_event(
-						sender_user,
-						target_user_id,
-						target_device_id,
-						&body.event_type.to_string(),
-						event
-							.deserialize_as()
-							.map_err(|_| Error::BadRequest(ErrorKind::InvalidParam, "Event is invalid"))?,
-					)?;
-				},
+		for (target_device_id_maybe, event) in map {
+			let mut map = BTreeMap::new();
+			map.insert(target_device_id_maybe.clone(), event.clone());
+			let mut messages = BTreeMap::new();
+			messages.insert(target_user_id.clone(), map);
+			let count = services().globals.next_count()?;
 
-				DeviceIdOrAllDevices::AllDevices => {
-					for target_device_id in services().users.all_device_ids(target_user_id) {
-						services().users.add_to_device_event(
-							sender_user,
-							target_user_id,
-							&target_device_id?,
-							&body.event_type.to_string(),
-							event
-								.deserialize_as()
-								.map_err(|_| Error::BadRequest(ErrorKind::InvalidParam, "Event is invalid"))?,
-						)?;
-					}
-				},
-			}
+			services().sending.send_edu_server(
+				target_user_id.server_name(),
+				serde_json::to_vec(&federation::transactions::edu::Edu::DirectToDevice(DirectDeviceContent {
+					sender: sender_user.clone(),
+					ev_type: body.event_type.clone(),
+					message_id: count.to_string().into(),
+					messages,
+				}))
+				.expect("DirectToDevice EDU can be serialized"),
+			)?;
 		}
 	}
 
@@ -85,6 +65,18 @@ pub(crate) async fn send_event_to_device_route(
 	services()
 		.transaction_ids
 		.add_txnid(sender_user, sender_device, &body.txn_id, &[])?;
+	drop(to_device_lock);
+
+	for target_user_id in body
+		.messages
+		.keys()
+		.filter(|user_id| user_is_local(user_id))
+	{
+		services()
+			.appservice
+			.flush_ephemeral(target_user_id)
+			.await?;
+	}
 
 	Ok(send_event_to_device::v3::Response {})
 }
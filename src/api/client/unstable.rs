@@ -1,9 +1,12 @@
+use std::sync::Arc;
+
 use ruma::{
 	api::client::{error::ErrorKind, membership::mutual_rooms},
+	state_res::StateMap,
 	OwnedRoomId,
 };
 
-use crate::{services, Error, Result, Ruma};
+use crate::{services, user_is_local, Error, Result, Ruma};
 
 /// # `GET /_matrix/client/unstable/uk.half-shot.msc2666/user/mutual_rooms`
 ///
@@ -43,3 +46,255 @@ pub(crate) async fn get_mutual_rooms_route(
 		next_batch_token: None,
 	})
 }
+
+/// # `GET /_matrix/client/unstable/uk.half-shot.msc2666/user/shared_rooms/{userId}`
+///
+/// Gets all the rooms the sender shares with the specified user, as in the
+/// earlier revision of [MSC2666](https://github.com/matrix-org/matrix-spec-proposals/pull/2666).
+///
+/// - Only users sharing at least one room with the specified user, or admins,
+///   may ask, so this does not reveal whether the user exists
+pub(crate) async fn get_shared_rooms_route(body: Ruma<shared_rooms::Request>) -> Result<shared_rooms::Response> {
+	let sender_user = body.sender_user.as_ref().expect("user is authenticated");
+
+	if *sender_user == body.user_id {
+		return Err(Error::BadRequest(
+			ErrorKind::Unknown,
+			"You cannot request rooms in common with yourself.",
+		));
+	}
+
+	let joined: Vec<OwnedRoomId> = services()
+		.rooms
+		.user
+		.get_shared_rooms(vec![sender_user.clone(), body.user_id.clone()])?
+		.filter_map(Result::ok)
+		.collect();
+
+	if joined.is_empty() && !services().users.is_admin(sender_user)? {
+		return Err(Error::BadRequest(
+			ErrorKind::forbidden(),
+			"You do not share any rooms with this user.",
+		));
+	}
+
+	Ok(shared_rooms::Response {
+		joined,
+	})
+}
+
+/// # `POST /_matrix/client/unstable/org.conduwuit/rooms/{roomId}/batch_send`
+///
+/// Imports historical events into a room on behalf of an appservice.
+///
+/// - Only appservices may use this, and only for senders in their namespace
+/// - The events of the batch form a chain following `prev_event`, or the first
+///   event of the room, which is where they are inserted in the timeline
+/// - `state_events_at_start` (e.g. historical memberships) are stored as
+///   outliers and only used to authorize the events of this batch
+/// - `events` are inserted before every event already in the room's timeline,
+///   keeping their order, so batches should be sent from newest to oldest
+/// - Imported events don't send pushes or count as notifications, but are
+///   indexed for search
+pub(crate) async fn batch_send_route(body: Ruma<batch_send::Request>) -> Result<batch_send::Response> {
+	if !services().globals.config.allow_appservice_batch_import {
+		return Err(Error::BadRequest(
+			ErrorKind::forbidden(),
+			"Batch import is disabled on this server.",
+		));
+	}
+
+	let Some(appservice_info) = &body.appservice_info else {
+		return Err(Error::BadRequest(
+			ErrorKind::forbidden(),
+			"Only appservices can import history.",
+		));
+	};
+
+	for event in body.state_events_at_start.iter().chain(&body.events) {
+		if !user_is_local(&event.sender) || !appservice_info.is_user_match(&event.sender) {
+			return Err(Error::BadRequest(
+				ErrorKind::Exclusive,
+				"Sender is not in the appservice's namespace.",
+			));
+		}
+	}
+
+	let batch_send::Request {
+		room_id,
+		prev_event,
+		state_events_at_start,
+		events,
+	} = body.body;
+
+	let state_lock = services().globals.roomid_mutex_state.lock(&room_id).await;
+
+	let mut prev_event = match prev_event {
+		Some(event_id) => services()
+			.rooms
+			.timeline
+			.get_pdu(&event_id)?
+			.filter(|pdu| pdu.room_id == room_id)
+			.ok_or(Error::BadRequest(
+				ErrorKind::NotFound,
+				"prev_event is not an event of this room.",
+			))?,
+		None => services()
+			.rooms
+			.timeline
+			.first_pdu_in_room(&room_id)?
+			.ok_or(Error::BadRequest(ErrorKind::NotFound, "Room not found."))?,
+	};
+
+	let mut state_overlay = StateMap::new();
+	let mut state_event_ids = Vec::with_capacity(state_events_at_start.len());
+	for event in state_events_at_start {
+		prev_event = services().rooms.timeline.create_historical_state_pdu(
+			event.builder(),
+			&event.sender,
+			&room_id,
+			event.origin_server_ts,
+			&prev_event,
+			&mut state_overlay,
+			&state_lock,
+		)?;
+
+		state_event_ids.push((*prev_event.event_id).to_owned());
+	}
+
+	// Every event is signed before any is inserted, so a batch failing to be
+	// authorized leaves the timeline untouched
+	let mut pdus = Vec::with_capacity(events.len());
+	for event in events {
+		let (pdu, pdu_json) = services()
+			.rooms
+			.timeline
+			.create_hash_and_sign_historical_event(
+				event.builder(),
+				&event.sender,
+				&room_id,
+				event.origin_server_ts,
+				&prev_event,
+				&state_overlay,
+				&state_lock,
+			)?;
+
+		prev_event = Arc::new(pdu.clone());
+		pdus.push((pdu, pdu_json));
+	}
+
+	// Every event is prepended to the timeline, so insert the newest first to
+	// keep the batch in order.
+	for (pdu, pdu_json) in pdus.iter().rev() {
+		services()
+			.rooms
+			.timeline
+			.prepend_historical_pdu(pdu, pdu_json, &state_lock)
+			.await?;
+	}
+
+	drop(state_lock);
+
+	Ok(batch_send::Response {
+		state_event_ids,
+		event_ids: pdus
+			.into_iter()
+			.map(|(pdu, _)| (*pdu.event_id).to_owned())
+			.collect(),
+	})
+}
+
+pub(crate) mod shared_rooms {
+	use ruma::{
+		api::{request, response, Metadata},
+		metadata, OwnedRoomId, OwnedUserId,
+	};
+
+	const METADATA: Metadata = metadata! {
+		method: GET,
+		rate_limited: false,
+		authentication: AccessToken,
+		history: {
+			unstable => "/_matrix/client/unstable/uk.half-shot.msc2666/user/shared_rooms/:user_id",
+		}
+	};
+
+	#[request]
+	pub struct Request {
+		/// The user to find the rooms shared with
+		#[ruma_api(path)]
+		pub user_id: OwnedUserId,
+	}
+
+	#[response]
+	pub struct Response {
+		pub joined: Vec<OwnedRoomId>,
+	}
+}
+
+pub(crate) mod batch_send {
+	use ruma::{
+		api::{request, response, Metadata},
+		events::TimelineEventType,
+		metadata, MilliSecondsSinceUnixEpoch, OwnedEventId, OwnedRoomId, OwnedUserId,
+	};
+	use serde::{Deserialize, Serialize};
+	use serde_json::value::RawValue as RawJsonValue;
+	use service::pdu::PduBuilder;
+
+	const METADATA: Metadata = metadata! {
+		method: POST,
+		rate_limited: false,
+		authentication: AppserviceToken,
+		history: {
+			unstable => "/_matrix/client/unstable/org.conduwuit/rooms/:room_id/batch_send",
+		}
+	};
+
+	#[request]
+	pub struct Request {
+		/// The room to import the events into
+		#[ruma_api(path)]
+		pub room_id: OwnedRoomId,
+
+		/// The event the batch follows in the room's DAG, by default the first
+		/// event of the room
+		#[ruma_api(query)]
+		#[serde(skip_serializing_if = "Option::is_none")]
+		pub prev_event: Option<OwnedEventId>,
+
+		#[serde(default, skip_serializing_if = "Vec::is_empty")]
+		pub state_events_at_start: Vec<HistoricalEvent>,
+
+		pub events: Vec<HistoricalEvent>,
+	}
+
+	#[response]
+	pub struct Response {
+		pub state_event_ids: Vec<OwnedEventId>,
+		pub event_ids: Vec<OwnedEventId>,
+	}
+
+	#[derive(Deserialize, Serialize)]
+	pub struct HistoricalEvent {
+		#[serde(rename = "type")]
+		pub event_type: TimelineEventType,
+		pub sender: OwnedUserId,
+		pub origin_server_ts: MilliSecondsSinceUnixEpoch,
+		pub content: Box<RawJsonValue>,
+		#[serde(skip_serializing_if = "Option::is_none")]
+		pub state_key: Option<String>,
+	}
+
+	impl HistoricalEvent {
+		pub(super) fn builder(&self) -> PduBuilder {
+			PduBuilder {
+				event_type: self.event_type.clone(),
+				content: self.content.clone(),
+				unsigned: None,
+				state_key: self.state_key.clone(),
+				redacts: None,
+			}
+		}
+	}
+}
@@ -1,6 +1,9 @@
 use std::collections::BTreeMap;
 
-use axum::{response::IntoResponse, Json};
+use axum::{
+	response::{Html, IntoResponse},
+	Json,
+};
 use ruma::api::client::{
 	discovery::{
 		discover_homeserver::{self, HomeserverInfo, SlidingSyncProxyInfo},
@@ -10,7 +13,7 @@ use ruma::api::client::{
 	error::ErrorKind,
 };
 
-use crate::{services, Error, Result, Ruma};
+use crate::{service::globals::status::ServerStatus, services, Error, Result, Ruma};
 
 /// # `GET /_matrix/client/versions`
 ///
@@ -172,3 +175,100 @@ pub(crate) async fn conduwuit_local_user_count() -> Result<impl IntoResponse> {
 		"count": user_count
 	})))
 }
+
+/// # `GET /_conduwuit/status`
+///
+/// conduwuit-specific public status page, only served if `dashboard.enable` is
+/// set. The numbers are a snapshot refreshed at most every 30 seconds; those
+/// disabled in the config are left out.
+pub(crate) async fn conduwuit_status() -> Result<impl IntoResponse> {
+	let status = services().globals.status.get().await?;
+
+	Ok(Html(render_status(&status)))
+}
+
+/// # `GET /_conduwuit/status.json`
+///
+/// The numbers of the status page as JSON
+pub(crate) async fn conduwuit_status_json() -> Result<impl IntoResponse> {
+	let status = services().globals.status.get().await?;
+
+	Ok(Json(serde_json::to_value(&*status).expect("status serializes")))
+}
+
+fn render_status(status: &ServerStatus) -> String {
+	let uptime = status.uptime_secs;
+	let mut rows = vec![
+		("Version", status.version.clone()),
+		(
+			"Uptime",
+			format!(
+				"{} days, {} hours, {} minutes",
+				uptime / 86400,
+				(uptime % 86400) / 3600,
+				(uptime % 3600) / 60
+			),
+		),
+		(
+			"Registration",
+			if status.registration_open {
+				"open"
+			} else {
+				"closed"
+			}
+			.to_owned(),
+		),
+	];
+
+	if let Some(count) = status.user_count {
+		rows.push(("Users", count.to_string()));
+	}
+	if let Some(count) = status.room_count {
+		rows.push(("Rooms", count.to_string()));
+	}
+	if let Some(count) = status.federation_queue {
+		rows.push(("Federation send queue", count.to_string()));
+	}
+	if let Some(bytes) = status.media_bytes {
+		rows.push(("Media storage", format!("{} MiB", bytes / (1024 * 1024))));
+	}
+	if let Some(active) = &status.active_users {
+		rows.push((
+			"Active users (day / week / month)",
+			format!("{} / {} / {}", active.day, active.week, active.month),
+		));
+	}
+	if let Some(trees) = &status.db_activity {
+		let trees: Vec<_> = trees
+			.iter()
+			.map(|tree| {
+				format!(
+					"{}: {} writes, {} reads",
+					tree.tree,
+					tree.inserts.saturating_add(tree.removes),
+					tree.reads
+				)
+			})
+			.collect();
+		rows.push(("Busiest database trees", trees.join(", ")));
+	}
+
+	let rows: String = rows
+		.into_iter()
+		.map(|(name, value)| format!("<tr><th>{name}</th><td>{}</td></tr>", escape_html(&value)))
+		.collect();
+	let server_name = escape_html(&status.server_name);
+
+	format!(
+		"<!DOCTYPE html><html><head><meta charset=\"utf-8\"><title>{server_name} status</title><style>body {{ \
+		 font-family: sans-serif; margin: 2em; }} th {{ text-align: left; padding-right: 2em; \
+		 }}</style></head><body><h1>{server_name}</h1><table>{rows}</table></body></html>"
+	)
+}
+
+fn escape_html(s: &str) -> String {
+	s.replace('&', "&amp;")
+		.replace('<', "&lt;")
+		.replace('>', "&gt;")
+		.replace('"', "&quot;")
+}
@@ -4,92 +4,46 @@ use ruma::{
 		room::join_rules::{JoinRule, RoomJoinRulesEventContent},
 		StateEventType,
 	},
+	UserId,
 };
 
-use crate::{services, Result, Ruma};
+use super::pagination::Paginated;
+use crate::{services, user_is_local, Result, Ruma};
 
 /// # `POST /_matrix/client/r0/user_directory/search`
 ///
-/// Searches all known users for a match.
+/// Searches the user directory for users whose user ID or displayname contain
+/// every word of the search term.
 ///
-/// - Hides any local users that aren't in any public rooms (i.e. those that
-///   have the join rule set to public) and don't share a room with the sender
+/// - Hides users that aren't in any public rooms (i.e. those that have the join
+///   rule set to public) and don't share a room with the sender, unless
+///   `user_directory_search_all_local_users` makes all local users visible
 pub(crate) async fn search_users_route(body: Ruma<search_users::v3::Request>) -> Result<search_users::v3::Response> {
 	let sender_user = body.sender_user.as_ref().expect("user is authenticated");
-	let limit = usize::try_from(body.limit).unwrap_or(10); // default limit is 10
+	let limit = Paginated::UserDirectory.limit(Some(body.limit));
 
-	let mut users = services().users.iter().filter_map(|user_id| {
-		// Filter out buggy users (they should not exist, but you never know...)
-		let user_id = user_id.ok()?;
+	let Some(matches) = services().users.directory.search(&body.search_term)? else {
+		return Ok(search_users::v3::Response {
+			results: Vec::new(),
+			limited: false,
+		});
+	};
 
-		let user = search_users::v3::User {
-			user_id: user_id.clone(),
-			display_name: services().users.displayname(&user_id).ok()?,
-			avatar_url: services().users.avatar_url(&user_id).ok()?,
-		};
+	let search_all_local_users = services()
+		.globals
+		.config
+		.user_directory_search_all_local_users;
 
-		let user_id_matches = user
-			.user_id
-			.to_string()
-			.to_lowercase()
-			.contains(&body.search_term.to_lowercase());
-
-		let user_displayname_matches = user
-			.display_name
-			.as_ref()
-			.filter(|name| {
-				name.to_lowercase()
-					.contains(&body.search_term.to_lowercase())
+	let mut users = matches
+		.into_iter()
+		.filter(|user_id| user_is_visible(sender_user, user_id, search_all_local_users))
+		.filter_map(|user_id| {
+			Some(search_users::v3::User {
+				display_name: services().users.displayname(&user_id).ok()?,
+				avatar_url: services().users.avatar_url(&user_id).ok()?,
+				user_id,
 			})
-			.is_some();
-
-		if !user_id_matches && !user_displayname_matches {
-			return None;
-		}
-
-		// It's a matching user, but is the sender allowed to see them?
-		let mut user_visible = false;
-
-		let user_is_in_public_rooms = services()
-			.rooms
-			.state_cache
-			.rooms_joined(&user_id)
-			.filter_map(Result::ok)
-			.any(|room| {
-				services()
-					.rooms
-					.state_accessor
-					.room_state_get(&room, &StateEventType::RoomJoinRules, "")
-					.map_or(false, |event| {
-						event.map_or(false, |event| {
-							serde_json::from_str(event.content.get())
-								.map_or(false, |r: RoomJoinRulesEventContent| r.join_rule == JoinRule::Public)
-						})
-					})
-			});
-
-		if user_is_in_public_rooms {
-			user_visible = true;
-		} else {
-			let user_is_in_shared_rooms = services()
-				.rooms
-				.user
-				.get_shared_rooms(vec![sender_user.clone(), user_id])
-				.ok()?
-				.next()
-				.is_some();
-
-			if user_is_in_shared_rooms {
-				user_visible = true;
-			}
-		}
-
-		if !user_visible {
-			return None;
-		}
-
-		Some(user)
-	});
+		});
 
 	let results = users.by_ref().take(limit).collect();
 	let limited = users.next().is_some();
@@ -99,3 +53,38 @@ pub(crate) async fn search_users_route(body: Ruma<search_users::v3::Request>) ->
 		limited,
 	})
 }
+
+/// Whether the sender may find the user in the user directory
+fn user_is_visible(sender_user: &UserId, user_id: &UserId, search_all_local_users: bool) -> bool {
+	if search_all_local_users && user_is_local(user_id) {
+		return true;
+	}
+
+	let user_is_in_shared_rooms = services()
+		.rooms
+		.user
+		.get_shared_rooms(vec![sender_user.to_owned(), user_id.to_owned()])
+		.is_ok_and(|mut rooms| rooms.next().is_some());
+
+	if user_is_in_shared_rooms {
+		return true;
+	}
+
+	services()
+		.rooms
+		.state_cache
+		.rooms_joined(user_id)
+		.filter_map(Result::ok)
+		.any(|room| {
+			services()
+				.rooms
+				.state_accessor
+				.room_state_get(&room, &StateEventType::RoomJoinRules, "")
+				.map_or(false, |event| {
+					event.map_or(false, |event| {
+						serde_json::from_str(event.content.get())
+							.map_or(false, |r: RoomJoinRulesEventContent| r.join_rule == JoinRule::Public)
+					})
+				})
+		})
+}
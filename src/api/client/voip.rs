@@ -18,7 +18,7 @@ type HmacSha1 = Hmac<Sha1>;
 pub(crate) async fn turn_server_route(
 	body: Ruma<get_turn_server_info::v3::Request>,
 ) -> Result<get_turn_server_info::v3::Response> {
-	let turn_secret = services().globals.turn_secret().clone();
+	let turn_secret = services().globals.turn_secret();
 
 	let (username, password) = if !turn_secret.is_empty() {
 		let expiry = SecondsSinceUnixEpoch::from_system_time(
@@ -45,16 +45,13 @@ pub(crate) async fn turn_server_route(
 
 		(username, password)
 	} else {
-		(
-			services().globals.turn_username().clone(),
-			services().globals.turn_password().clone(),
-		)
+		(services().globals.turn_username(), services().globals.turn_password())
 	};
 
 	Ok(get_turn_server_info::v3::Response {
 		username,
 		password,
-		uris: services().globals.turn_uris().to_vec(),
+		uris: services().globals.turn_uris(),
 		ttl: Duration::from_secs(services().globals.turn_ttl()),
 	})
 }
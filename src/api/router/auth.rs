@@ -19,6 +19,7 @@ use crate::{service::appservice::RegistrationInfo, services, Error, Result};
 enum Token {
 	Appservice(Box<RegistrationInfo>),
 	User((OwnedUserId, OwnedDeviceId)),
+	Expired,
 	Invalid,
 	None,
 }
@@ -43,7 +44,15 @@ pub(super) async fn auth(
 		if let Some(reg_info) = services().appservice.find_from_token(token).await {
 			Token::Appservice(Box::new(reg_info))
 		} else if let Some((user_id, device_id)) = services().users.find_from_token(token)? {
-			Token::User((user_id, OwnedDeviceId::from(device_id)))
+			let device_id = OwnedDeviceId::from(device_id);
+			if services()
+				.users
+				.is_access_token_expired(&user_id, &device_id)?
+			{
+				Token::Expired
+			} else {
+				Token::User((user_id, device_id))
+			}
 		} else {
 			Token::Invalid
 		}
@@ -65,7 +74,7 @@ pub(super) async fn auth(
 							// we should have validated the token above
 							// already
 						},
-						Token::None | Token::Invalid => {
+						Token::None | Token::Expired | Token::Invalid => {
 							return Err(Error::BadRequest(ErrorKind::MissingToken, "Missing or invalid access token."));
 						},
 					}
@@ -82,6 +91,19 @@ pub(super) async fn auth(
 			},
 			"Unknown access token.",
 		)),
+		// e.g. /refresh, which clients may call with their expired access token
+		(AuthScheme::None, Token::Expired) => Ok(Auth {
+			origin: None,
+			sender_user: None,
+			sender_device: None,
+			appservice_info: None,
+		}),
+		(_, Token::Expired) => Err(Error::BadRequest(
+			ErrorKind::UnknownToken {
+				soft_logout: true,
+			},
+			"Access token has expired.",
+		)),
 		(AuthScheme::AccessToken, Token::Appservice(info)) => Ok(auth_appservice(request, info)?),
 		(AuthScheme::None | AuthScheme::AccessTokenOptional | AuthScheme::AppserviceToken, Token::Appservice(info)) => {
 			Ok(Auth {
@@ -110,12 +132,18 @@ pub(super) async fn auth(
 		(
 			AuthScheme::AccessToken | AuthScheme::AccessTokenOptional | AuthScheme::None,
 			Token::User((user_id, device_id)),
-		) => Ok(Auth {
-			origin: None,
-			sender_user: Some(user_id),
-			sender_device: Some(device_id),
-			appservice_info: None,
-		}),
+		) => {
+			if let Err(e) = services().users.activity.record(&user_id) {
+				warn!("Failed to record activity of {user_id}: {e}");
+			}
+
+			Ok(Auth {
+				origin: None,
+				sender_user: Some(user_id),
+				sender_device: Some(device_id),
+				appservice_info: None,
+			})
+		},
 		(AuthScheme::ServerSignatures, Token::None) => Ok(auth_server(request, json_body).await?),
 		(AuthScheme::None | AuthScheme::AppserviceToken | AuthScheme::AccessTokenOptional, Token::None) => Ok(Auth {
 			sender_user: None,
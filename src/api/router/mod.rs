@@ -1,9 +1,10 @@
 mod auth;
 mod handler;
+mod rate_limit;
 mod request;
 mod xmatrix;
 
-use std::{mem, ops::Deref};
+use std::{mem, net::IpAddr, ops::Deref};
 
 use axum::{async_trait, body::Body, extract::FromRequest};
 use bytes::{BufMut, BytesMut};
@@ -11,7 +12,7 @@ pub(super) use conduit::error::RumaResponse;
 use conduit::{debug, debug_warn, trace, warn};
 use ruma::{
 	api::{client::error::ErrorKind, IncomingRequest},
-	CanonicalJsonValue, OwnedDeviceId, OwnedServerName, OwnedUserId, UserId,
+	CanonicalJsonValue, OwnedDeviceId, OwnedServerName, OwnedUserId, ServerName, UserId,
 };
 
 pub(super) use self::handler::RouterExt;
@@ -42,6 +43,10 @@ pub(crate) struct Ruma<T> {
 	/// Parsed JSON content.
 	/// None when body is not a valid string
 	pub(crate) json_body: Option<CanonicalJsonValue>,
+
+	/// Address of the client, behind the trusted proxies if any.
+	/// Unspecified when the connection has no address.
+	pub(crate) client: IpAddr,
 }
 
 #[async_trait]
@@ -55,6 +60,9 @@ where
 		let mut request = request::from(request).await?;
 		let mut json_body = serde_json::from_slice::<CanonicalJsonValue>(&request.body).ok();
 		let auth = auth::auth(&mut request, &json_body, &T::METADATA).await?;
+		record_auth_span(&auth);
+		rate_limit::check(&request, &auth)?;
+		record_last_seen(&auth, request.client);
 		Ok(Self {
 			body: make_body::<T>(&mut request, &mut json_body, &auth)?,
 			origin: auth.origin,
@@ -62,6 +70,7 @@ where
 			sender_device: auth.sender_device,
 			appservice_info: auth.appservice_info,
 			json_body,
+			client: request.client,
 		})
 	}
 }
@@ -72,6 +81,29 @@ impl<T> Deref for Ruma<T> {
 	fn deref(&self) -> &Self::Target { &self.body }
 }
 
+/// Adds the authenticated user and appservice to the request's span
+fn record_auth_span(auth: &Auth) {
+	let span = tracing::Span::current();
+	if let Some(sender_user) = &auth.sender_user {
+		span.record("user", sender_user.as_str());
+	}
+
+	if let Some(appservice_info) = &auth.appservice_info {
+		span.record("appservice", appservice_info.registration.id.as_str());
+	}
+}
+
+fn record_last_seen(auth: &Auth, client: IpAddr) {
+	if let (Some(sender_user), Some(sender_device)) = (&auth.sender_user, &auth.sender_device) {
+		if let Err(e) = services()
+			.users
+			.update_device_last_seen(sender_user, sender_device, client)
+		{
+			debug_warn!(%sender_user, %sender_device, "Failed to record last seen address of device: {e}");
+		}
+	}
+}
+
 fn make_body<T>(request: &mut Request, json_body: &mut Option<CanonicalJsonValue>, auth: &Auth) -> Result<T>
 where
 	T: IncomingRequest,
@@ -123,8 +155,39 @@ where
 	let body = T::try_from_http_request(http_request, &request.path).map_err(|e| {
 		warn!("try_from_http_request failed: {e:?}",);
 		debug_warn!("JSON body: {:?}", json_body);
+		if let Some(server) = invalid_via(request.parts.uri.query().unwrap_or_default()) {
+			return Error::InvalidVia(server);
+		}
+
 		Error::BadRequest(ErrorKind::BadJson, "Failed to deserialize request.")
 	})?;
 
 	Ok(body)
 }
+
+/// The first `via` or `server_name` query parameter which is not a valid server
+/// name, so clients are told which one instead of getting `M_BAD_JSON`
+fn invalid_via(query: &str) -> Option<String> {
+	serde_html_form::from_str::<Vec<(String, String)>>(query)
+		.ok()?
+		.into_iter()
+		.filter(|(key, _)| key == "via" || key == "server_name")
+		.map(|(_, server)| server)
+		.find(|server| ServerName::parse(server).is_err())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::invalid_via;
+
+	#[test]
+	fn invalid_via_entries() {
+		assert_eq!(invalid_via("via=example.com&via=matrix.org:8448"), None);
+		assert_eq!(
+			invalid_via("server_name=example.com&server_name=bad%20name"),
+			Some("bad name".to_owned())
+		);
+		assert_eq!(invalid_via("via=example.com&via="), Some(String::new()));
+		assert_eq!(invalid_via("access_token=abc"), None);
+	}
+}
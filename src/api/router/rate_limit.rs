@@ -0,0 +1,51 @@
+use conduit::debug_warn;
+use ruma::api::client::error::{ErrorKind, RetryAfter};
+
+use super::{auth::Auth, request::Request};
+use crate::{
+	service::rate_limit::{Class, Key},
+	services, Error, Result,
+};
+
+/// Takes a token from the bucket of the requesting user or IP address for the
+/// endpoint's class. Federation requests, server admins and appservices which
+/// are not rate limited are exempt.
+pub(super) fn check(request: &Request, auth: &Auth) -> Result<()> {
+	if !services().globals.rate_limit().enabled || auth.origin.is_some() {
+		return Ok(());
+	}
+
+	let Some(class) = Class::of_path(request.parts.uri.path()) else {
+		return Ok(());
+	};
+
+	if auth
+		.appservice_info
+		.as_ref()
+		.is_some_and(|info| !info.is_rate_limited())
+	{
+		return Ok(());
+	}
+
+	let key = if let Some(user_id) = &auth.sender_user {
+		if services().users.is_admin(user_id)? {
+			return Ok(());
+		}
+
+		Key::User(user_id.clone())
+	} else {
+		Key::Ip(request.client.to_string())
+	};
+
+	if let Err(retry_after) = services().rate_limit.check(key.clone(), class)? {
+		debug_warn!(?key, %class, "Rate limited, retry after {retry_after:?}");
+		return Err(Error::BadRequest(
+			ErrorKind::LimitExceeded {
+				retry_after: Some(RetryAfter::Delay(retry_after)),
+			},
+			"Too many requests, try again later.",
+		));
+	}
+
+	Ok(())
+}
@@ -1,4 +1,7 @@
-use std::str;
+use std::{
+	net::{IpAddr, Ipv4Addr},
+	str,
+};
 
 use axum::{extract::Path, RequestExt, RequestPartsExt};
 use bytes::Bytes;
@@ -6,7 +9,7 @@ use http::request::Parts;
 use ruma::api::client::error::ErrorKind;
 use serde::Deserialize;
 
-use crate::{services, Error, Result};
+use crate::{services, utils::ClientIp, Error, Result};
 
 #[derive(Deserialize)]
 pub(super) struct QueryParams {
@@ -19,12 +22,19 @@ pub(super) struct Request {
 	pub(super) query: QueryParams,
 	pub(super) body: Bytes,
 	pub(super) parts: Parts,
+	pub(super) client: IpAddr,
 }
 
 pub(super) async fn from(request: hyper::Request<axum::body::Body>) -> Result<Request> {
 	let limited = request.with_limited_body();
 	let (mut parts, body) = limited.into_parts();
 
+	// resolved by the router from the connection and trusted proxies
+	let client = parts
+		.extensions
+		.get::<ClientIp>()
+		.map_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED), |ClientIp(client)| *client);
+
 	let path: Path<Vec<String>> = parts.extract().await?;
 	let query = serde_html_form::from_str(parts.uri.query().unwrap_or_default())
 		.map_err(|_| Error::BadRequest(ErrorKind::Unknown, "Failed to read query parameters"))?;
@@ -45,5 +55,6 @@ pub(super) async fn from(request: hyper::Request<axum::body::Body>) -> Result<Re
 		query,
 		body,
 		parts,
+		client,
 	})
 }
@@ -4,8 +4,6 @@ use axum::{
 	Router,
 };
 use conduit::{Error, Server};
-use http::Uri;
-use ruma::api::client::error::ErrorKind;
 
 use crate::{client, router::RouterExt, server};
 
@@ -17,9 +15,11 @@ pub fn build(router: Router, server: &Server) -> Router {
 		.ruma_route(client::register_route)
 		.ruma_route(client::get_login_types_route)
 		.ruma_route(client::login_route)
+		.ruma_route(client::get_login_token_route)
 		.ruma_route(client::whoami_route)
 		.ruma_route(client::logout_route)
 		.ruma_route(client::logout_all_route)
+		.ruma_route(client::refresh_token_route)
 		.ruma_route(client::change_password_route)
 		.ruma_route(client::deactivate_route)
 		.ruma_route(client::third_party_route)
@@ -127,6 +127,10 @@ pub fn build(router: Router, server: &Server) -> Router {
 		.ruma_route(client::search_events_route)
 		.ruma_route(client::turn_server_route)
 		.ruma_route(client::send_event_to_device_route)
+		.ruma_route(client::put_dehydrated_device_route)
+		.ruma_route(client::get_dehydrated_device_route)
+		.ruma_route(client::delete_dehydrated_device_route)
+		.ruma_route(client::get_dehydrated_events_route)
 		.ruma_route(client::get_media_config_route)
 		.ruma_route(client::get_media_preview_route)
 		.ruma_route(client::create_content_route)
@@ -171,6 +175,7 @@ pub fn build(router: Router, server: &Server) -> Router {
 		.ruma_route(client::get_key_changes_route)
 		.ruma_route(client::get_pushers_route)
 		.ruma_route(client::set_pushers_route)
+		.ruma_route(client::get_notifications_route)
 		// .ruma_route(client::third_party_route)
 		.ruma_route(client::upgrade_room_route)
 		.ruma_route(client::get_threads_route)
@@ -179,14 +184,23 @@ pub fn build(router: Router, server: &Server) -> Router {
 		.ruma_route(client::get_relating_events_route)
 		.ruma_route(client::get_hierarchy_route)
         .ruma_route(client::get_mutual_rooms_route)
+		.ruma_route(client::get_shared_rooms_route)
+		.ruma_route(client::room_initial_sync_route)
+		.ruma_route(client::batch_send_route)
         .ruma_route(client::well_known_support)
         .ruma_route(client::well_known_client)
         .route("/_conduwuit/server_version", get(client::conduwuit_server_version))
-		.route("/_matrix/client/r0/rooms/:room_id/initialSync", get(initial_sync))
-		.route("/_matrix/client/v3/rooms/:room_id/initialSync", get(initial_sync))
 		.route("/client/server.json", get(client::syncv3_client_server_json));
 
-	if config.allow_federation {
+	let router = if config.dashboard.enable {
+		router
+			.route("/_conduwuit/status", get(client::conduwuit_status))
+			.route("/_conduwuit/status.json", get(client::conduwuit_status_json))
+	} else {
+		router
+	};
+
+	if config.allow_federation && !config.rocksdb_read_only {
 		router
 			.ruma_route(server::get_server_version_route)
 			.route("/_matrix/key/v2/server", get(server::get_server_keys_route))
@@ -224,8 +238,4 @@ pub fn build(router: Router, server: &Server) -> Router {
 	}
 }
 
-async fn initial_sync(_uri: Uri) -> impl IntoResponse {
-	Error::BadRequest(ErrorKind::GuestAccessForbidden, "Guest access not implemented")
-}
-
 async fn federation_disabled() -> impl IntoResponse { Error::bad_config("Federation is disabled.") }
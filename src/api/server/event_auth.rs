@@ -48,6 +48,12 @@ pub(crate) async fn get_event_authorization_route(
 	let room_id =
 		<&RoomId>::try_from(room_id_str).map_err(|_| Error::bad_database("Invalid room_id in event in database."))?;
 
+	// the checks above were made for the requested room, so don't serve events
+	// from any other room
+	if room_id != body.room_id {
+		return Err(Error::BadRequest(ErrorKind::NotFound, "Event not found."));
+	}
+
 	let auth_chain_ids = services()
 		.rooms
 		.auth_chain
@@ -56,6 +62,7 @@ pub(crate) async fn get_event_authorization_route(
 
 	Ok(get_event_authorization::v1::Response {
 		auth_chain: auth_chain_ids
+			.filter(|id| **id != *body.event_id)
 			.filter_map(|id| services().rooms.timeline.get_pdu_json(&id).ok()?)
 			.map(PduEvent::convert_to_outgoing_federation_event)
 			.collect(),
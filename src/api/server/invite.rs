@@ -1,4 +1,3 @@
-use axum_client_ip::InsecureClientIp;
 use ruma::{
 	api::{client::error::ErrorKind, federation::membership::create_invite},
 	events::room::member::{MembershipState, RoomMemberEventContent},
@@ -8,6 +7,7 @@ use ruma::{
 use tracing::warn;
 
 use crate::{
+	debug_info,
 	service::server_is_ours,
 	services,
 	utils::{self},
@@ -17,10 +17,8 @@ use crate::{
 /// # `PUT /_matrix/federation/v2/invite/{roomId}/{eventId}`
 ///
 /// Invites a remote user to a room.
-#[tracing::instrument(skip_all, fields(%client), name = "invite")]
-pub(crate) async fn create_invite_route(
-	InsecureClientIp(client): InsecureClientIp, body: Ruma<create_invite::v2::Request>,
-) -> Result<create_invite::v2::Response> {
+#[tracing::instrument(skip_all, fields(client = %body.client), name = "invite")]
+pub(crate) async fn create_invite_route(body: Ruma<create_invite::v2::Request>) -> Result<create_invite::v2::Response> {
 	let origin = body.origin.as_ref().expect("server is authenticated");
 
 	// ACL check origin
@@ -131,6 +129,15 @@ pub(crate) async fn create_invite_route(
 	)
 	.map_err(|_| Error::BadRequest(ErrorKind::InvalidParam, "sender is not a user ID."))?;
 
+	if let Some(reason) = services()
+		.rooms
+		.state_cache
+		.invite_refusal(&sender, &invited_user)?
+	{
+		debug_info!(%sender, %invited_user, room_id = %body.room_id, "Refusing invite: {reason}");
+		return Err(Error::BadRequest(ErrorKind::forbidden(), reason));
+	}
+
 	if services().rooms.metadata.is_banned(&body.room_id)? && !services().users.is_admin(&invited_user)? {
 		return Err(Error::BadRequest(
 			ErrorKind::forbidden(),
@@ -157,12 +164,25 @@ pub(crate) async fn create_invite_route(
 
 	invite_state.push(pdu.to_stripped_state_event());
 
+	// Still succeed for invites the user already rejected, but don't show them
+	// again
+	let rejected = services().rooms.state_cache.invite_was_rejected(
+		&invited_user,
+		&body.room_id,
+		&event_id,
+		pdu.origin_server_ts.into(),
+	)?;
+	if rejected {
+		debug_info!(%invited_user, room_id = %body.room_id, "Ignoring invite the user already rejected");
+	}
+
 	// If we are active in the room, the remote server will notify us about the join
 	// via /send
-	if !services()
-		.rooms
-		.state_cache
-		.server_in_room(services().globals.server_name(), &body.room_id)?
+	if !rejected
+		&& !services()
+			.rooms
+			.state_cache
+			.server_in_room(services().globals.server_name(), &body.room_id)?
 	{
 		services().rooms.state_cache.update_membership(
 			&body.room_id,
@@ -173,6 +193,10 @@ pub(crate) async fn create_invite_route(
 			body.via.clone(),
 			true,
 		)?;
+		services()
+			.rooms
+			.state_cache
+			.mark_invite_event(&invited_user, &body.room_id, &event_id)?;
 	}
 
 	Ok(create_invite::v2::Response {
@@ -1,6 +1,5 @@
 use std::{collections::BTreeMap, time::Instant};
 
-use axum_client_ip::InsecureClientIp;
 use conduit::debug_warn;
 use ruma::{
 	api::{
@@ -11,10 +10,10 @@ use ruma::{
 		},
 	},
 	events::receipt::{ReceiptEvent, ReceiptEventContent, ReceiptType},
-	to_device::DeviceIdOrAllDevices,
+	OwnedRoomId, OwnedUserId,
 };
 use tokio::sync::RwLock;
-use tracing::{debug, error, trace, warn};
+use tracing::{debug, trace, warn};
 
 use crate::{
 	service::rooms::event_handler::parse_incoming_pdu,
@@ -23,12 +22,22 @@ use crate::{
 	Error, Result, Ruma,
 };
 
+/// Numbers of the EDUs of a transaction which were applied, by kind, and of
+/// those dropped as invalid
+#[derive(Default)]
+struct EduCounts {
+	receipts: usize,
+	typing: usize,
+	device_updates: usize,
+	dropped: u64,
+}
+
 /// # `PUT /_matrix/federation/v1/send/{txnId}`
 ///
 /// Push EDUs and PDUs to this server.
-#[tracing::instrument(skip_all, fields(%client), name = "send")]
+#[tracing::instrument(skip_all, fields(client = %body.client), name = "send")]
 pub(crate) async fn send_transaction_message_route(
-	InsecureClientIp(client): InsecureClientIp, body: Ruma<send_transaction_message::v1::Request>,
+	body: Ruma<send_transaction_message::v1::Request>,
 ) -> Result<send_transaction_message::v1::Response> {
 	let origin = body.origin.as_ref().expect("server is authenticated");
 
@@ -138,11 +147,14 @@ pub(crate) async fn send_transaction_message_route(
 		}
 	}
 
-	for edu in body
-		.edus
-		.iter()
-		.filter_map(|edu| serde_json::from_str::<Edu>(edu.json().get()).ok())
-	{
+	let mut receipts: BTreeMap<OwnedRoomId, Vec<(OwnedUserId, ReceiptEvent)>> = BTreeMap::new();
+	let mut counts = EduCounts::default();
+	for edu in &body.edus {
+		let Ok(edu) = serde_json::from_str::<Edu>(edu.json().get()) else {
+			counts.dropped = counts.dropped.saturating_add(1);
+			continue;
+		};
+
 		match edu {
 			Edu::Presence(presence) => {
 				if !services().globals.allow_incoming_presence() {
@@ -152,6 +164,7 @@ pub(crate) async fn send_transaction_message_route(
 				for update in presence.push {
 					if update.user_id.server_name() != origin {
 						debug_warn!(%update.user_id, %origin, "received presence EDU for user not belonging to origin");
+						counts.dropped = counts.dropped.saturating_add(1);
 						continue;
 					}
 
@@ -177,42 +190,43 @@ pub(crate) async fn send_transaction_message_route(
 						.is_err()
 					{
 						debug_warn!(%origin, %room_id, "received read receipt EDU from ACL'd server");
+						counts.dropped = counts.dropped.saturating_add(1);
+						continue;
+					}
+
+					if !services()
+						.rooms
+						.state_cache
+						.server_in_room(origin, &room_id)?
+					{
+						debug_warn!(%room_id, %origin, "received read receipt EDU from server who does not have a single member from their server in the room");
+						counts.dropped = counts.dropped.saturating_add(1);
 						continue;
 					}
 
 					for (user_id, user_updates) in room_updates.read {
 						if user_id.server_name() != origin {
 							debug_warn!(%user_id, %origin, "received read receipt EDU for user not belonging to origin");
+							counts.dropped = counts.dropped.saturating_add(1);
 							continue;
 						}
 
-						if services()
-							.rooms
-							.state_cache
-							.room_members(&room_id)
-							.filter_map(Result::ok)
-							.any(|member| member.server_name() == user_id.server_name())
-						{
-							for event_id in &user_updates.event_ids {
-								let user_receipts = BTreeMap::from([(user_id.clone(), user_updates.data.clone())]);
-
-								let receipts = BTreeMap::from([(ReceiptType::Read, user_receipts)]);
-
-								let receipt_content = BTreeMap::from([(event_id.to_owned(), receipts)]);
-
-								let event = ReceiptEvent {
-									content: ReceiptEventContent(receipt_content),
-									room_id: room_id.clone(),
-								};
-
-								services()
-									.rooms
-									.read_receipt
-									.readreceipt_update(&user_id, &room_id, &event)?;
-							}
-						} else {
-							debug_warn!(%user_id, %room_id, %origin, "received read receipt EDU from server who does not have a single member from their server in the room");
-							continue;
+						for event_id in &user_updates.event_ids {
+							let user_receipts = BTreeMap::from([(user_id.clone(), user_updates.data.clone())]);
+
+							let receipt = BTreeMap::from([(ReceiptType::Read, user_receipts)]);
+
+							let receipt_content = BTreeMap::from([(event_id.to_owned(), receipt)]);
+
+							let event = ReceiptEvent {
+								content: ReceiptEventContent(receipt_content),
+								room_id: room_id.clone(),
+							};
+
+							receipts
+								.entry(room_id.clone())
+								.or_default()
+								.push((user_id.clone(), event));
 						}
 					}
 				}
@@ -224,6 +238,17 @@ pub(crate) async fn send_transaction_message_route(
 
 				if typing.user_id.server_name() != origin {
 					debug_warn!(%typing.user_id, %origin, "received typing EDU for user not belonging to origin");
+					counts.dropped = counts.dropped.saturating_add(1);
+					continue;
+				}
+
+				if !services()
+					.rooms
+					.state_cache
+					.server_in_room(origin, &typing.room_id)?
+				{
+					debug_warn!(%typing.user_id, %typing.room_id, %origin, "received typing EDU for room the origin is not in");
+					counts.dropped = counts.dropped.saturating_add(1);
 					continue;
 				}
 
@@ -234,6 +259,7 @@ pub(crate) async fn send_transaction_message_route(
 					.is_err()
 				{
 					debug_warn!(%typing.user_id, %typing.room_id, %origin, "received typing EDU for ACL'd user's server");
+					counts.dropped = counts.dropped.saturating_add(1);
 					continue;
 				}
 
@@ -242,6 +268,7 @@ pub(crate) async fn send_transaction_message_route(
 					.state_cache
 					.is_joined(&typing.user_id, &typing.room_id)?
 				{
+					counts.typing = counts.typing.saturating_add(1);
 					if typing.typing {
 						let timeout = utils::millis_since_unix_epoch().saturating_add(
 							services()
@@ -264,6 +291,7 @@ pub(crate) async fn send_transaction_message_route(
 					}
 				} else {
 					debug_warn!(%typing.user_id, %typing.room_id, %origin, "received typing EDU for user not in room");
+					counts.dropped = counts.dropped.saturating_add(1);
 					continue;
 				}
 			},
@@ -273,10 +301,13 @@ pub(crate) async fn send_transaction_message_route(
 			}) => {
 				if user_id.server_name() != origin {
 					debug_warn!(%user_id, %origin, "received device list update EDU for user not belonging to origin");
+					counts.dropped = counts.dropped.saturating_add(1);
 					continue;
 				}
 
 				services().users.mark_device_key_update(&user_id)?;
+				services().appservice.flush_ephemeral(&user_id).await?;
+				counts.device_updates = counts.device_updates.saturating_add(1);
 			},
 			Edu::DirectToDevice(DirectDeviceContent {
 				sender,
@@ -286,9 +317,14 @@ pub(crate) async fn send_transaction_message_route(
 			}) => {
 				if sender.server_name() != origin {
 					debug_warn!(%sender, %origin, "received direct to device EDU for user not belonging to origin");
+					counts.dropped = counts.dropped.saturating_add(1);
 					continue;
 				}
 
+				// Messages of a sender are stored in the order their EDUs arrive, also
+				// when transactions of the origin are handled concurrently
+				let to_device_lock = services().users.to_device.lock(&sender).await;
+
 				// Check if this is a new transaction id
 				if services()
 					.transaction_ids
@@ -298,43 +334,27 @@ pub(crate) async fn send_transaction_message_route(
 					continue;
 				}
 
-				for (target_user_id, map) in &messages {
-					for (target_device_id_maybe, event) in map {
-						match target_device_id_maybe {
-							DeviceIdOrAllDevices::DeviceId(target_device_id) => {
-								services().users.add_to_device_event(
-									&sender,
-									target_user_id,
-									target_device_id,
-									&ev_type.to_string(),
-									event.deserialize_as().map_err(|e| {
-										error!("To-Device event is invalid: {event:?} {e}");
-										Error::BadRequest(ErrorKind::InvalidParam, "Event is invalid")
-									})?,
-								)?;
-							},
-
-							DeviceIdOrAllDevices::AllDevices => {
-								for target_device_id in services().users.all_device_ids(target_user_id) {
-									services().users.add_to_device_event(
-										&sender,
-										target_user_id,
-										&target_device_id?,
-										&ev_type.to_string(),
-										event.deserialize_as().map_err(|_| {
-											Error::BadRequest(ErrorKind::InvalidParam, "Event is invalid")
-										})?,
-									)?;
-								}
-							},
-						}
-					}
+				if let Err(e) = services()
+					.users
+					.add_to_device_events(&sender, &ev_type.to_string(), &messages)
+				{
+					debug_warn!(%sender, %origin, "Dropping invalid to-device EDU {message_id}: {e}");
+					counts.dropped = counts.dropped.saturating_add(1);
+					continue;
 				}
 
 				// Save transaction id with empty data
 				services()
 					.transaction_ids
 					.add_txnid(&sender, None, &message_id, &[])?;
+				drop(to_device_lock);
+
+				for target_user_id in messages.keys() {
+					services()
+						.appservice
+						.flush_ephemeral(target_user_id)
+						.await?;
+				}
 			},
 			Edu::SigningKeyUpdate(SigningKeyUpdateContent {
 				user_id,
@@ -343,6 +363,7 @@ pub(crate) async fn send_transaction_message_route(
 			}) => {
 				if user_id.server_name() != origin {
 					debug_warn!(%user_id, %origin, "received signing key update EDU from server that does not belong to user's server");
+					counts.dropped = counts.dropped.saturating_add(1);
 					continue;
 				}
 
@@ -358,6 +379,31 @@ pub(crate) async fn send_transaction_message_route(
 		}
 	}
 
+	for (room_id, room_receipts) in receipts {
+		counts.receipts = counts.receipts.saturating_add(room_receipts.len());
+		services()
+			.rooms
+			.read_receipt
+			.readreceipt_update_many(&room_id, &room_receipts)?;
+	}
+
+	if counts.dropped > 0 {
+		services()
+			.rooms
+			.event_handler
+			.edus
+			.record_dropped(counts.dropped);
+	}
+
+	debug!(
+		receipts = counts.receipts,
+		typing = counts.typing,
+		device_updates = counts.device_updates,
+		dropped = counts.dropped,
+		"Processed EDUs of txn {}",
+		body.transaction_id,
+	);
+
 	debug!(
 		pdus = ?body.pdus.len(),
 		edus = ?body.edus.len(),
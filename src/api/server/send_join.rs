@@ -1,6 +1,6 @@
 #![allow(deprecated)]
 
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, HashSet};
 
 use ruma::{
 	api::{client::error::ErrorKind, federation::membership::create_join_event},
@@ -13,9 +13,14 @@ use tracing::warn;
 
 use crate::{service::pdu::gen_event_id_canonical_json, services, Error, PduEvent, Result, Ruma};
 
-/// helper method for /send_join v1 and v2
+/// Members whose membership is kept in a partial state, as they name the room
+/// for the joining server
+const HEROES: usize = 5;
+
+/// helper method for /send_join v1 and v2. With `omit_members`, the state
+/// is the partial state of MSC3706.
 async fn create_join_event(
-	origin: &ServerName, room_id: &RoomId, pdu: &RawJsonValue,
+	origin: &ServerName, room_id: &RoomId, pdu: &RawJsonValue, omit_members: bool,
 ) -> Result<create_join_event::v1::RoomState> {
 	if !services().rooms.metadata.exists(room_id)? {
 		return Err(Error::BadRequest(ErrorKind::NotFound, "Room is unknown to this server."));
@@ -161,16 +166,44 @@ async fn create_join_event(
 		.ok_or_else(|| Error::BadRequest(ErrorKind::InvalidParam, "Could not accept as timeline event."))?;
 	drop(mutex_lock);
 
-	let state_ids = services()
+	let mut state_ids: Vec<_> = services()
 		.rooms
 		.state_accessor
 		.state_full_ids(shortstatehash)
-		.await?;
+		.await?
+		.into_values()
+		.collect();
+
+	if omit_members {
+		let heroes: Vec<_> = services()
+			.rooms
+			.state_cache
+			.room_members(room_id)
+			.filter_map(Result::ok)
+			.filter(|user_id| *user_id != sender)
+			.take(HEROES)
+			.collect();
+
+		let mut state = Vec::with_capacity(state_ids.len());
+		for event_id in state_ids {
+			let Some(pdu) = services().rooms.timeline.get_pdu(&event_id)? else {
+				continue;
+			};
+
+			state.push((pdu.kind.to_string(), pdu.state_key.unwrap_or_default(), event_id));
+		}
+
+		state_ids = partial_state(state, &heroes);
+	}
+
+	let state_set: HashSet<_> = state_ids.iter().cloned().collect();
 	let auth_chain_ids = services()
 		.rooms
 		.auth_chain
-		.event_ids_iter(room_id, state_ids.values().cloned().collect())
-		.await?;
+		.event_ids_iter(room_id, state_ids.clone())
+		.await?
+		// the partial state's auth chain leaves out the events of the state
+		.filter(|event_id| !omit_members || !state_set.contains(event_id));
 
 	services().sending.send_pdu_room(room_id, &pdu_id)?;
 
@@ -181,7 +214,7 @@ async fn create_join_event(
 			.collect(),
 		state: state_ids
 			.iter()
-			.filter_map(|(_, id)| services().rooms.timeline.get_pdu_json(id).ok().flatten())
+			.filter_map(|id| services().rooms.timeline.get_pdu_json(id).ok().flatten())
 			.map(PduEvent::convert_to_outgoing_federation_event)
 			.collect(),
 		// Event field is required if the room version supports restricted join rules.
@@ -235,7 +268,7 @@ pub(crate) async fn create_join_event_v1_route(
 		}
 	}
 
-	let room_state = create_join_event(origin, &body.room_id, &body.pdu).await?;
+	let room_state = create_join_event(origin, &body.room_id, &body.pdu, false).await?;
 
 	Ok(create_join_event::v1::Response {
 		room_state,
@@ -280,16 +313,117 @@ pub(crate) async fn create_join_event_v2_route(
 		auth_chain,
 		state,
 		event,
-	} = create_join_event(origin, &body.room_id, &body.pdu).await?;
+	} = create_join_event(origin, &body.room_id, &body.pdu, body.omit_members).await?;
+
+	let servers_in_room = if body.omit_members {
+		Some(
+			services()
+				.rooms
+				.state_cache
+				.room_servers(&body.room_id)
+				.filter_map(Result::ok)
+				.map(Into::into)
+				.collect(),
+		)
+	} else {
+		None
+	};
+
 	let room_state = create_join_event::v2::RoomState {
-		members_omitted: false,
+		members_omitted: body.omit_members,
 		auth_chain,
 		state,
 		event,
-		servers_in_room: None,
+		servers_in_room,
 	};
 
 	Ok(create_join_event::v2::Response {
 		room_state,
 	})
 }
+
+/// The state sent to a server joining with `omit_members` (MSC3706): all of
+/// it but the memberships, except those of the heroes. The memberships which
+/// authorize the state are still found in its auth chain.
+fn partial_state<T>(state: Vec<(String, String, T)>, heroes: &[OwnedUserId]) -> Vec<T> {
+	state
+		.into_iter()
+		.filter(|(kind, state_key, _)| kind != "m.room.member" || heroes.iter().any(|hero| hero.as_str() == state_key))
+		.map(|(.., event)| event)
+		.collect()
+}
+
+#[cfg(test)]
+mod tests {
+	use std::collections::BTreeSet;
+
+	use ruma::{api::federation::membership::create_join_event, owned_user_id, RoomVersionId};
+	use serde_json::value::RawValue as RawJsonValue;
+
+	use super::partial_state;
+	use crate::service::pdu::gen_event_id_canonical_json;
+
+	fn room_state(body: &str) -> create_join_event::v2::RoomState { serde_json::from_str(body).unwrap() }
+
+	fn events(pdus: &[Box<RawJsonValue>]) -> Vec<(String, String, String, Vec<String>)> {
+		pdus.iter()
+			.map(|pdu| {
+				let (event_id, value) = gen_event_id_canonical_json(pdu, &RoomVersionId::V10).unwrap();
+				let value = serde_json::to_value(value).unwrap();
+				let auth_events = value["auth_events"]
+					.as_array()
+					.unwrap()
+					.iter()
+					.map(|id| id.as_str().unwrap().to_owned())
+					.collect();
+
+				(
+					value["type"].as_str().unwrap().to_owned(),
+					value["state_key"].as_str().unwrap().to_owned(),
+					event_id.to_string(),
+					auth_events,
+				)
+			})
+			.collect()
+	}
+
+	#[test]
+	fn omitted_members_follow_msc3706() {
+		let full = room_state(include_str!("../client/fixtures/send_join_synapse.json"));
+		let partial = room_state(include_str!("../client/fixtures/send_join_partial.json"));
+		let full_state = events(&full.state);
+
+		let state = partial_state(
+			full_state
+				.iter()
+				.map(|(kind, state_key, event_id, _)| (kind.clone(), state_key.clone(), event_id.clone()))
+				.collect(),
+			&[owned_user_id!("@alice:synapse.example")],
+		);
+
+		// bob's membership is left out, alice's kept as she is a hero
+		let expected: Vec<_> = events(&partial.state)
+			.into_iter()
+			.map(|(.., event_id, _)| event_id)
+			.collect();
+		assert_eq!(state, expected);
+
+		// the auth chain of that state, without the events already in it, is
+		// bob's membership, which authorizes the room name he set
+		let in_state: BTreeSet<_> = state.iter().collect();
+		let auth_chain: BTreeSet<_> = full_state
+			.iter()
+			.filter(|(.., event_id, _)| in_state.contains(event_id))
+			.flat_map(|(.., auth_events)| auth_events)
+			.filter(|event_id| !in_state.contains(event_id))
+			.collect();
+		let expected: Vec<_> = events(&partial.auth_chain)
+			.into_iter()
+			.map(|(kind, state_key, event_id, _)| {
+				assert_eq!((kind.as_str(), state_key.as_str()), ("m.room.member", "@bob:synapse.example"));
+				event_id
+			})
+			.collect();
+		assert_eq!(auth_chain.into_iter().collect::<Vec<_>>(), expected.iter().collect::<Vec<_>>());
+	}
+}
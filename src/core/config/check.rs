@@ -1,6 +1,7 @@
 #[cfg(unix)]
 use std::path::Path; // not unix specific, just only for UNIX sockets stuff and *nix container checks
 
+use ruma::events::{room::power_levels::RoomPowerLevelsEventContent, TimelineEventType};
 use tracing::{debug, error, info, warn};
 
 use crate::{error::Error, Config};
@@ -13,6 +14,38 @@ pub fn check(config: &Config) -> Result<(), Error> {
 		return Err(Error::bad_config("Sentry cannot be enabled without an endpoint set"));
 	}
 
+	if let Some(template) = &config.room_creation.default_power_levels {
+		check_power_levels_template(template)?;
+	}
+
+	if config.limits.max_pagination == 0 {
+		return Err(Error::bad_config("limits.max_pagination must be at least 1."));
+	}
+
+	if let Some(cold_path) = &config.media_cold_path {
+		if cold_path.starts_with(config.database_path.join("media")) {
+			return Err(Error::bad_config("media_cold_path must not be inside the media directory."));
+		}
+
+		if config.media_cold_check_interval == 0 {
+			return Err(Error::bad_config("media_cold_check_interval must be at least 1."));
+		}
+	}
+
+	if let Some(source) = &config.conduit_import_path {
+		if config.rocksdb_read_only {
+			return Err(Error::bad_config(
+				"A Conduit database cannot be imported with rocksdb_read_only enabled.",
+			));
+		}
+
+		if source == &config.database_path {
+			return Err(Error::bad_config(
+				"conduit_import_path must be a different directory than database_path.",
+			));
+		}
+	}
+
 	if cfg!(feature = "hardened_malloc") && cfg!(feature = "jemalloc") {
 		warn!("hardened_malloc and jemalloc are both enabled, this causes jemalloc to be used.");
 	}
@@ -97,6 +130,22 @@ pub fn check(config: &Config) -> Result<(), Error> {
 		}
 	}
 
+	for cidr in &config.trusted_proxies {
+		if let Err(e) = ipaddress::IPAddress::parse(cidr) {
+			error!("Error parsing trusted proxy CIDR range from string: {e}");
+			return Err(Error::bad_config("Error parsing trusted_proxies CIDR ranges from strings"));
+		}
+	}
+
+	if !matches!(
+		config.trusted_proxy_header.to_ascii_lowercase().as_str(),
+		"x-forwarded-for" | "forwarded"
+	) {
+		return Err(Error::bad_config(
+			"trusted_proxy_header must be either \"x-forwarded-for\" or \"forwarded\"",
+		));
+	}
+
 	if config.allow_registration
 		&& !config.yes_i_am_very_very_sure_i_want_an_open_registration_server_prone_to_abuse
 		&& config.registration_token.is_none()
@@ -162,3 +211,50 @@ For security and safety reasons, conduwuit will shut down. If you are extra sure
 
 	Ok(())
 }
+
+/// The template must be valid power levels which leave the room creator able
+/// to change them. The creator always gets power level 100 after the template
+/// is applied, and changing the power levels needs 100 unless the template
+/// says otherwise.
+fn check_power_levels_template(template: &serde_json::Map<String, serde_json::Value>) -> Result<(), Error> {
+	let template = serde_json::from_value::<RoomPowerLevelsEventContent>(template.clone().into()).map_err(|e| {
+		Error::bad_config(&format!(
+			"room_creation.default_power_levels is not a valid power levels object: {e}"
+		))
+	})?;
+
+	if template
+		.events
+		.get(&TimelineEventType::RoomPowerLevels)
+		.is_some_and(|level| i64::from(*level) > 100)
+	{
+		return Err(Error::bad_config(
+			"room_creation.default_power_levels leaves the room creator unable to change power levels.",
+		));
+	}
+
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use serde_json::json;
+
+	use super::check_power_levels_template;
+
+	fn check(template: serde_json::Value) -> bool {
+		let serde_json::Value::Object(template) = template else {
+			panic!("template is an object");
+		};
+
+		check_power_levels_template(&template).is_ok()
+	}
+
+	#[test]
+	fn template_must_leave_creator_in_control() {
+		assert!(check(json!({ "kick": 75, "events": { "m.room.pinned_events": 50 } })));
+		assert!(check(json!({ "events": { "m.room.power_levels": 100 } })));
+		assert!(!check(json!({ "events": { "m.room.power_levels": 150 } })));
+		assert!(!check(json!({ "events": "none" })));
+	}
+}
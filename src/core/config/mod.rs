@@ -18,16 +18,20 @@ use regex::RegexSet;
 use ruma::{
 	api::client::discovery::discover_support::ContactRole, OwnedRoomId, OwnedServerName, OwnedUserId, RoomVersionId,
 };
-use serde::{de::IgnoredAny, Deserialize};
+use serde::{de::IgnoredAny, Deserialize, Serialize};
 use tracing::{debug, error, warn};
 use url::Url;
 
-pub use self::check::check;
 use self::proxy::ProxyConfig;
+pub use self::{
+	check::check,
+	reload::{ReloadReport, Reloadable},
+};
 use crate::error::Error;
 
 pub mod check;
 pub mod proxy;
+pub mod reload;
 
 #[derive(Deserialize, Clone, Debug)]
 #[serde(transparent)]
@@ -64,8 +68,11 @@ pub struct Config {
 	pub database_backup_path: Option<PathBuf>,
 	#[serde(default = "default_database_backups_to_keep")]
 	pub database_backups_to_keep: i16,
+	pub exports_path: Option<PathBuf>,
 	#[serde(default = "default_db_cache_capacity_mb")]
 	pub db_cache_capacity_mb: f64,
+	#[serde(default)]
+	pub database_activity_counters: bool,
 	#[serde(default = "default_new_user_displayname_suffix")]
 	pub new_user_displayname_suffix: String,
 	#[serde(default)]
@@ -93,6 +100,10 @@ pub struct Config {
 	pub stateinfo_cache_capacity: u32,
 	#[serde(default = "default_roomid_spacehierarchy_cache_capacity")]
 	pub roomid_spacehierarchy_cache_capacity: u32,
+	#[serde(default = "default_roomid_spacehierarchy_cache_ttl")]
+	pub roomid_spacehierarchy_cache_ttl: u64,
+	#[serde(default = "default_room_type_cache_capacity")]
+	pub room_type_cache_capacity: u32,
 
 	#[serde(default = "default_dns_cache_entries")]
 	pub dns_cache_entries: u32,
@@ -115,8 +126,16 @@ pub struct Config {
 
 	#[serde(default = "default_max_request_size")]
 	pub max_request_size: u32,
+	#[serde(default = "default_max_account_data_size")]
+	pub max_account_data_size: usize,
+	#[serde(default = "default_max_relation_depth")]
+	pub max_relation_depth: usize,
 	#[serde(default = "default_max_fetch_prev_events")]
 	pub max_fetch_prev_events: u16,
+	#[serde(default = "default_forward_extremities_soft_limit")]
+	pub forward_extremities_soft_limit: usize,
+	#[serde(default = "default_forward_extremities_dummy_interval")]
+	pub forward_extremities_dummy_interval: u64,
 
 	#[serde(default = "default_request_conn_timeout")]
 	pub request_conn_timeout: u64,
@@ -148,8 +167,12 @@ pub struct Config {
 	pub appservice_timeout: u64,
 	#[serde(default = "default_appservice_idle_timeout")]
 	pub appservice_idle_timeout: u64,
+	#[serde(default = "default_appservice_transaction_max_events")]
+	pub appservice_transaction_max_events: usize,
 	#[serde(default = "default_pusher_idle_timeout")]
 	pub pusher_idle_timeout: u64,
+	#[serde(default = "default_pusher_max_failures")]
+	pub pusher_max_failures: u32,
 
 	#[serde(default)]
 	pub allow_registration: bool,
@@ -164,11 +187,25 @@ pub struct Config {
 	pub allow_public_room_directory_over_federation: bool,
 	#[serde(default)]
 	pub allow_public_room_directory_without_auth: bool,
+	#[serde(default = "default_remote_alias_cache_ttl_secs")]
+	pub remote_alias_cache_ttl_secs: u64,
+	#[serde(default = "default_remote_alias_negative_cache_ttl_secs")]
+	pub remote_alias_negative_cache_ttl_secs: u64,
+	#[serde(default = "default_remote_profile_ttl_secs")]
+	pub remote_profile_ttl_secs: u64,
+	#[serde(default = "default_remote_profile_fetch_timeout_ms")]
+	pub remote_profile_fetch_timeout_ms: u64,
+	#[serde(default)]
+	pub allow_appservice_batch_import: bool,
+	#[serde(default)]
+	pub strict_message_validation: bool,
 	#[serde(default)]
 	pub turn_allow_guests: bool,
 	#[serde(default)]
 	pub lockdown_public_room_directory: bool,
 	#[serde(default)]
+	pub user_directory_search_all_local_users: bool,
+	#[serde(default)]
 	pub allow_device_name_federation: bool,
 	#[serde(default = "true_fn")]
 	pub allow_profile_lookup_federation_requests: bool,
@@ -181,6 +218,16 @@ pub struct Config {
 	#[serde(default)]
 	pub well_known: WellKnownConfig,
 	#[serde(default)]
+	pub sync: SyncConfig,
+	#[serde(default)]
+	pub rate_limit: RateLimitConfig,
+	#[serde(default)]
+	pub room_creation: RoomCreationConfig,
+	#[serde(default)]
+	pub limits: LimitsConfig,
+	#[serde(default)]
+	pub dashboard: DashboardConfig,
+	#[serde(default)]
 	#[cfg(feature = "perf_measurements")]
 	pub allow_jaeger: bool,
 	#[serde(default)]
@@ -195,6 +242,12 @@ pub struct Config {
 	#[serde(default)]
 	pub proxy: ProxyConfig,
 	pub jwt_secret: Option<String>,
+	#[serde(default)]
+	pub login_via_existing_session: bool,
+	#[serde(default = "default_login_token_ttl")]
+	pub login_token_ttl: u64,
+	#[serde(default = "default_access_token_ttl")]
+	pub access_token_ttl: u64,
 	#[serde(default = "default_trusted_servers")]
 	pub trusted_servers: Vec<OwnedServerName>,
 	#[serde(default = "true_fn")]
@@ -216,6 +269,10 @@ pub struct Config {
 	pub auto_join_rooms: Vec<OwnedRoomId>,
 	#[serde(default)]
 	pub auto_deactivate_banned_room_attempts: bool,
+	#[serde(default)]
+	pub forget_forced_upon_leave: bool,
+	#[serde(default)]
+	pub hide_incomplete_joined_rooms: bool,
 
 	#[serde(default = "default_rocksdb_log_level")]
 	pub rocksdb_log_level: String,
@@ -247,6 +304,7 @@ pub struct Config {
 	pub rocksdb_repair: bool,
 	#[serde(default)]
 	pub rocksdb_read_only: bool,
+	pub conduit_import_path: Option<PathBuf>,
 	#[serde(default)]
 	pub rocksdb_compaction_prio_idle: bool,
 	#[serde(default = "true_fn")]
@@ -269,6 +327,11 @@ pub struct Config {
 	pub presence_offline_timeout_s: u64,
 	#[serde(default = "true_fn")]
 	pub presence_timeout_remote_users: bool,
+	#[serde(default = "default_presence_federation_batch_window_ms")]
+	pub presence_federation_batch_window_ms: u64,
+	#[serde(default = "Vec::new")]
+	pub presence_federation_allowlist: Vec<OwnedServerName>,
+	pub presence_federation_max_room_members: Option<u64>,
 
 	#[serde(default = "true_fn")]
 	pub allow_incoming_read_receipts: bool,
@@ -299,11 +362,25 @@ pub struct Config {
 	pub log_guest_registrations: bool,
 	#[serde(default)]
 	pub allow_guests_auto_join_rooms: bool,
+	pub guest_account_max_age_days: Option<u64>,
 
 	#[serde(default = "true_fn")]
 	pub media_startup_check: bool,
 	#[serde(default = "true_fn")]
 	pub media_compat_file_link: bool,
+	#[serde(default)]
+	pub media_deduplicate: bool,
+	#[serde(default = "true_fn")]
+	pub media_pregenerate_thumbnails: bool,
+	#[serde(default = "default_media_thumbnail_queue_size")]
+	pub media_thumbnail_queue_size: usize,
+	pub media_cold_path: Option<PathBuf>,
+	#[serde(default = "default_media_cold_after_days")]
+	pub media_cold_after_days: u64,
+	#[serde(default)]
+	pub media_cold_promote_on_access: bool,
+	#[serde(default = "default_media_cold_check_interval")]
+	pub media_cold_check_interval: u64,
 	#[serde(default = "Vec::new")]
 	pub prevent_media_downloads_from: Vec<OwnedServerName>,
 
@@ -314,6 +391,10 @@ pub struct Config {
 
 	#[serde(default = "default_ip_range_denylist")]
 	pub ip_range_denylist: Vec<String>,
+	#[serde(default = "Vec::new")]
+	pub trusted_proxies: Vec<String>,
+	#[serde(default = "default_trusted_proxy_header")]
+	pub trusted_proxy_header: String,
 
 	#[serde(default = "Vec::new")]
 	pub url_preview_domain_contains_allowlist: Vec<String>,
@@ -343,8 +424,14 @@ pub struct Config {
 
 	#[serde(default)]
 	pub block_non_admin_invites: bool,
+	#[serde(default = "Vec::new")]
+	pub invite_blocked_servers: Vec<OwnedServerName>,
+	#[serde(default = "Vec::new")]
+	pub invite_allowed_servers: Vec<OwnedServerName>,
 	#[serde(default = "true_fn")]
 	pub admin_escape_commands: bool,
+	#[serde(default = "default_audit_log_max_age_days")]
+	pub audit_log_max_age_days: u64,
 
 	#[serde(default)]
 	pub sentry: bool,
@@ -384,6 +471,143 @@ pub struct WellKnownConfig {
 	pub support_mxid: Option<OwnedUserId>,
 }
 
+#[derive(Clone, Debug, Deserialize)]
+pub struct SyncConfig {
+	/// Soft cap on the estimated size of an initial /sync response in bytes.
+	/// Rooms past the cap are sent with a limited timeline and minimal state,
+	/// and are filled in by the device's following incremental syncs.
+	pub max_response_bytes: Option<usize>,
+
+	/// Days after which the record of which members were lazy-loaded to a
+	/// device is removed when the device stopped syncing
+	#[serde(default = "default_lazy_load_expiry_days")]
+	pub lazy_load_expiry_days: u64,
+}
+
+impl Default for SyncConfig {
+	fn default() -> Self {
+		Self {
+			max_response_bytes: None,
+			lazy_load_expiry_days: default_lazy_load_expiry_days(),
+		}
+	}
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct RateLimitConfig {
+	/// Whether client requests are rate limited at all
+	#[serde(default = "true_fn")]
+	pub enabled: bool,
+
+	#[serde(default = "default_rate_limit_login")]
+	pub login: RateLimitBucket,
+	#[serde(default = "default_rate_limit_registration")]
+	pub registration: RateLimitBucket,
+	#[serde(default = "default_rate_limit_message")]
+	pub message: RateLimitBucket,
+	#[serde(default = "default_rate_limit_join")]
+	pub join: RateLimitBucket,
+	#[serde(default = "default_rate_limit_media")]
+	pub media: RateLimitBucket,
+
+	/// Timeline events sent by one user to one room, besides redactions. Off
+	/// unless configured, admins can still throttle single rooms.
+	#[serde(default)]
+	pub room_send: Option<RateLimitBucket>,
+	/// Users with at least this power level in a room are not throttled by
+	/// `room_send` there
+	#[serde(default = "default_rate_limit_room_send_exempt_power_level")]
+	pub room_send_exempt_power_level: i64,
+}
+
+/// Token bucket: up to `burst` requests at once, refilled by `per_second`
+/// requests every second
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+pub struct RateLimitBucket {
+	pub burst: u32,
+	pub per_second: f64,
+}
+
+impl Default for RateLimitConfig {
+	fn default() -> Self {
+		Self {
+			enabled: true,
+			login: default_rate_limit_login(),
+			registration: default_rate_limit_registration(),
+			message: default_rate_limit_message(),
+			join: default_rate_limit_join(),
+			media: default_rate_limit_media(),
+			room_send: None,
+			room_send_exempt_power_level: default_rate_limit_room_send_exempt_power_level(),
+		}
+	}
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct RoomCreationConfig {
+	/// Partial power levels applied to rooms created on this server, beneath
+	/// the creator's power level, the preset and the client's
+	/// `power_level_content_override`
+	#[serde(default)]
+	pub default_power_levels: Option<serde_json::Map<String, serde_json::Value>>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct LimitsConfig {
+	/// Most events, notifications or users returned by one request to a
+	/// paginated client endpoint; larger `limit`s are clamped to it
+	#[serde(default = "default_max_pagination")]
+	pub max_pagination: usize,
+}
+
+impl Default for LimitsConfig {
+	fn default() -> Self {
+		Self {
+			max_pagination: default_max_pagination(),
+		}
+	}
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct DashboardConfig {
+	/// Whether the unauthenticated status page at `/_conduwuit/status` is
+	/// served
+	#[serde(default)]
+	pub enable: bool,
+
+	#[serde(default = "true_fn")]
+	pub show_user_count: bool,
+	#[serde(default = "true_fn")]
+	pub show_room_count: bool,
+	#[serde(default = "true_fn")]
+	pub show_federation_queue: bool,
+	#[serde(default = "true_fn")]
+	pub show_media_usage: bool,
+
+	/// Numbers of local users active within the last 1, 7 and 30 days
+	#[serde(default)]
+	pub show_active_users: bool,
+
+	/// Operations on the five busiest database trees, if
+	/// `database_activity_counters` is enabled
+	#[serde(default)]
+	pub show_db_activity: bool,
+}
+
+impl Default for DashboardConfig {
+	fn default() -> Self {
+		Self {
+			enable: false,
+			show_user_count: true,
+			show_room_count: true,
+			show_federation_queue: true,
+			show_media_usage: true,
+			show_active_users: false,
+			show_db_activity: false,
+		}
+	}
+}
+
 const DEPRECATED_KEYS: &[&str] = &[
 	"cache_capacity",
 	"max_concurrent_requests",
@@ -512,8 +736,10 @@ impl Config {
 	pub fn check(&self) -> Result<(), Error> { check(self) }
 }
 
-impl fmt::Display for Config {
-	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+impl Config {
+	/// The config values shown by `Display`, with their labels
+	#[must_use]
+	pub fn values(&self) -> Vec<(&'static str, String)> {
 		// Prepare a list of config values to show
 		let lines = [
 			("Server name", self.server_name.host()),
@@ -526,6 +752,13 @@ impl fmt::Display for Config {
 					.map_or("", |path| path.to_str().unwrap_or("")),
 			),
 			("Database backups to keep", &self.database_backups_to_keep.to_string()),
+			("Database activity counters", &self.database_activity_counters.to_string()),
+			(
+				"Exports path",
+				self.exports_path
+					.as_ref()
+					.map_or("", |path| path.to_str().unwrap_or("")),
+			),
 			("Database cache capacity (MB)", &self.db_cache_capacity_mb.to_string()),
 			("Cache capacity modifier", &self.conduit_cache_capacity_modifier.to_string()),
 			("PDU cache capacity", &self.pdu_cache_capacity.to_string()),
@@ -547,6 +780,11 @@ impl fmt::Display for Config {
 				"Roomid space hierarchy cache capacity",
 				&self.roomid_spacehierarchy_cache_capacity.to_string(),
 			),
+			(
+				"Roomid space hierarchy cache TTL",
+				&self.roomid_spacehierarchy_cache_ttl.to_string(),
+			),
+			("Room type cache capacity", &self.room_type_cache_capacity.to_string()),
 			("DNS cache entry limit", &self.dns_cache_entries.to_string()),
 			("DNS minimum TTL", &self.dns_min_ttl.to_string()),
 			("DNS minimum NXDOMAIN TTL", &self.dns_min_ttl_nxdomain.to_string()),
@@ -556,6 +794,19 @@ impl fmt::Display for Config {
 			("DNS query over TCP only", &self.query_over_tcp_only.to_string()),
 			("Query all nameservers", &self.query_all_nameservers.to_string()),
 			("Maximum request size (bytes)", &self.max_request_size.to_string()),
+			(
+				"Maximum account data event size (bytes)",
+				&self.max_account_data_size.to_string(),
+			),
+			("Maximum relation depth of new events", &self.max_relation_depth.to_string()),
+			(
+				"Forward extremities soft limit",
+				&self.forward_extremities_soft_limit.to_string(),
+			),
+			(
+				"Minimum interval between dummy events in a room",
+				&self.forward_extremities_dummy_interval.to_string(),
+			),
 			("Sender retry backoff limit", &self.sender_retry_backoff_limit.to_string()),
 			("Request connect timeout", &self.request_conn_timeout.to_string()),
 			("Request timeout", &self.request_timeout.to_string()),
@@ -571,7 +822,12 @@ impl fmt::Display for Config {
 			("Sender pool idle timeout", &self.sender_idle_timeout.to_string()),
 			("Appservice timeout", &self.appservice_timeout.to_string()),
 			("Appservice pool idle timeout", &self.appservice_idle_timeout.to_string()),
+			(
+				"Appservice transaction max events",
+				&self.appservice_transaction_max_events.to_string(),
+			),
 			("Pusher pool idle timeout", &self.pusher_idle_timeout.to_string()),
+			("Pusher max consecutive failures", &self.pusher_max_failures.to_string()),
 			("Allow registration", &self.allow_registration.to_string()),
 			(
 				"Registration token",
@@ -593,6 +849,12 @@ impl fmt::Display for Config {
 				"Allow guests to auto join rooms",
 				&self.allow_guests_auto_join_rooms.to_string(),
 			),
+			(
+				"Guest account maximum age (days)",
+				&self
+					.guest_account_max_age_days
+					.map_or_else(|| "unlimited".to_owned(), |days| days.to_string()),
+			),
 			("New user display name suffix", &self.new_user_displayname_suffix),
 			("Allow encryption", &self.allow_encryption.to_string()),
 			("Allow federation", &self.allow_federation.to_string()),
@@ -604,6 +866,23 @@ impl fmt::Display for Config {
 				"Allow outgoing federated presence requests (updates)",
 				&self.allow_outgoing_presence.to_string(),
 			),
+			(
+				"Outgoing presence batch window (ms)",
+				&self.presence_federation_batch_window_ms.to_string(),
+			),
+			("Outgoing presence server allowlist", {
+				let mut lst = vec![];
+				for domain in &self.presence_federation_allowlist {
+					lst.push(domain.host());
+				}
+				&lst.join(", ")
+			}),
+			(
+				"Outgoing presence maximum room members",
+				&self
+					.presence_federation_max_room_members
+					.map_or_else(|| "unlimited".to_owned(), |max| max.to_string()),
+			),
 			(
 				"Allow local presence requests (updates)",
 				&self.allow_local_presence.to_string(),
@@ -620,7 +899,22 @@ impl fmt::Display for Config {
 				"Block non-admin room invites (local and remote, admins can still send and receive invites)",
 				&self.block_non_admin_invites.to_string(),
 			),
+			("Invite blocked servers", {
+				let mut lst = vec![];
+				for domain in &self.invite_blocked_servers {
+					lst.push(domain.host());
+				}
+				&lst.join(", ")
+			}),
+			("Invite allowed servers", {
+				let mut lst = vec![];
+				for domain in &self.invite_allowed_servers {
+					lst.push(domain.host());
+				}
+				&lst.join(", ")
+			}),
 			("Enable admin escape commands", &self.admin_escape_commands.to_string()),
+			("Audit log maximum age (days)", &self.audit_log_max_age_days.to_string()),
 			("Allow outgoing federated typing", &self.allow_outgoing_typing.to_string()),
 			("Allow incoming federated typing", &self.allow_incoming_typing.to_string()),
 			(
@@ -648,10 +942,32 @@ impl fmt::Display for Config {
 				"Allow public room directory without authentication",
 				&self.allow_public_room_directory_without_auth.to_string(),
 			),
+			(
+				"Remote room alias cache TTL (seconds)",
+				&self.remote_alias_cache_ttl_secs.to_string(),
+			),
+			(
+				"Remote room alias negative cache TTL (seconds)",
+				&self.remote_alias_negative_cache_ttl_secs.to_string(),
+			),
+			(
+				"Remote profile refresh interval (seconds)",
+				&self.remote_profile_ttl_secs.to_string(),
+			),
+			(
+				"Remote profile fetch timeout (milliseconds)",
+				&self.remote_profile_fetch_timeout_ms.to_string(),
+			),
+			("Allow appservice batch import", &self.allow_appservice_batch_import.to_string()),
+			("Strict message validation", &self.strict_message_validation.to_string()),
 			(
 				"Lockdown public room directory (only allow admins to publish)",
 				&self.lockdown_public_room_directory.to_string(),
 			),
+			(
+				"User directory searches all local users",
+				&self.user_directory_search_all_local_users.to_string(),
+			),
 			(
 				"JWT secret",
 				match self.jwt_secret {
@@ -659,6 +975,9 @@ impl fmt::Display for Config {
 					None => "not set",
 				},
 			),
+			("Login via existing session", &self.login_via_existing_session.to_string()),
+			("Login token TTL (ms)", &self.login_token_ttl.to_string()),
+			("Refreshable access token TTL (ms)", &self.access_token_ttl.to_string()),
 			(
 				"Trusted key servers",
 				&self
@@ -709,6 +1028,11 @@ impl fmt::Display for Config {
 				}
 				&lst.into_iter().join(", ")
 			}),
+			("Forget rooms upon leave", &self.forget_forced_upon_leave.to_string()),
+			(
+				"Hide rooms with incomplete membership from joined rooms",
+				&self.hide_incomplete_joined_rooms.to_string(),
+			),
 			#[cfg(feature = "zstd_compression")]
 			("Zstd HTTP Compression", &self.zstd_compression.to_string()),
 			#[cfg(feature = "gzip_compression")]
@@ -742,6 +1066,12 @@ impl fmt::Display for Config {
 			("RocksDB Recovery Mode", &self.rocksdb_recovery_mode.to_string()),
 			("RocksDB Repair Mode", &self.rocksdb_repair.to_string()),
 			("RocksDB Read-only Mode", &self.rocksdb_read_only.to_string()),
+			(
+				"Conduit import path",
+				self.conduit_import_path
+					.as_ref()
+					.map_or("", |path| path.to_str().unwrap_or("")),
+			),
 			(
 				"RocksDB Compaction Idle Priority",
 				&self.rocksdb_compaction_prio_idle.to_string(),
@@ -752,6 +1082,24 @@ impl fmt::Display for Config {
 			),
 			("Media integrity checks on startup", &self.media_startup_check.to_string()),
 			("Media compatibility filesystem links", &self.media_compat_file_link.to_string()),
+			("Media deduplication", &self.media_deduplicate.to_string()),
+			("Pre-generate media thumbnails", &self.media_pregenerate_thumbnails.to_string()),
+			("Media thumbnail queue size", &self.media_thumbnail_queue_size.to_string()),
+			(
+				"Media cold storage path",
+				self.media_cold_path
+					.as_ref()
+					.map_or("", |path| path.to_str().unwrap_or("")),
+			),
+			(
+				"Media moved to cold storage after (days)",
+				&self.media_cold_after_days.to_string(),
+			),
+			("Promote cold media on access", &self.media_cold_promote_on_access.to_string()),
+			(
+				"Media cold storage check interval (seconds)",
+				&self.media_cold_check_interval.to_string(),
+			),
 			("Prevent Media Downloads From", {
 				let mut lst = vec![];
 				for domain in &self.prevent_media_downloads_from {
@@ -781,6 +1129,8 @@ impl fmt::Display for Config {
 				}
 				&lst.join(", ")
 			}),
+			("Trusted proxies", &self.trusted_proxies.join(", ")),
+			("Trusted proxy header", &self.trusted_proxy_header),
 			("Forbidden usernames", {
 				&self.forbidden_usernames.patterns().iter().join(", ")
 			}),
@@ -859,12 +1209,77 @@ impl fmt::Display for Config {
 					.map_or("", |url| url.as_str()),
 			),
 			("Enable the tokio-console", &self.tokio_console.to_string()),
+			(
+				"Initial sync response soft limit (bytes)",
+				&self
+					.sync
+					.max_response_bytes
+					.map_or_else(|| "unlimited".to_owned(), |max| max.to_string()),
+			),
+			(
+				"Lazy-loading records expiry (days)",
+				&self.sync.lazy_load_expiry_days.to_string(),
+			),
+			(
+				"Default power levels of new rooms",
+				&self
+					.room_creation
+					.default_power_levels
+					.as_ref()
+					.map_or_else(
+						|| "none".to_owned(),
+						|template| serde_json::Value::from(template.clone()).to_string(),
+					),
+			),
+			("Maximum pagination limit", &self.limits.max_pagination.to_string()),
+			("Rate limiting enabled", &self.rate_limit.enabled.to_string()),
+			(
+				"Rate limits (burst, per second)",
+				&format!(
+					"login: {:?}, registration: {:?}, message: {:?}, join: {:?}, media: {:?}, room send: {:?}",
+					(self.rate_limit.login.burst, self.rate_limit.login.per_second),
+					(self.rate_limit.registration.burst, self.rate_limit.registration.per_second),
+					(self.rate_limit.message.burst, self.rate_limit.message.per_second),
+					(self.rate_limit.join.burst, self.rate_limit.join.per_second),
+					(self.rate_limit.media.burst, self.rate_limit.media.per_second),
+					self.rate_limit
+						.room_send
+						.map(|room_send| (room_send.burst, room_send.per_second)),
+				),
+			),
+			(
+				"Room send throttle exempt power level",
+				&self.rate_limit.room_send_exempt_power_level.to_string(),
+			),
+			("Status page enabled", &self.dashboard.enable.to_string()),
+			(
+				"Status page numbers",
+				&format!(
+					"users: {}, rooms: {}, federation queue: {}, media usage: {}, active users: {}, database \
+					 activity: {}",
+					self.dashboard.show_user_count,
+					self.dashboard.show_room_count,
+					self.dashboard.show_federation_queue,
+					self.dashboard.show_media_usage,
+					self.dashboard.show_active_users,
+					self.dashboard.show_db_activity,
+				),
+			),
 		];
 
+		lines
+			.into_iter()
+			.map(|(label, value)| (label, value.to_owned()))
+			.collect()
+	}
+}
+
+impl fmt::Display for Config {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
 		let mut msg: String = "Active config values:\n\n".to_owned();
 
-		for line in lines.into_iter().enumerate() {
-			writeln!(msg, "{}: {}", line.1 .0, line.1 .1).expect("should be able to write to string buffer");
+		for (label, value) in self.values() {
+			writeln!(msg, "{label}: {value}").expect("should be able to write to string buffer");
 		}
 
 		write!(f, "{msg}")
@@ -915,6 +1330,10 @@ fn default_stateinfo_cache_capacity() -> u32 { 1000 * crate::utils::available_pa
 
 fn default_roomid_spacehierarchy_cache_capacity() -> u32 { 1000 * crate::utils::available_parallelism() as u32 }
 
+fn default_roomid_spacehierarchy_cache_ttl() -> u64 { 60 * 30 }
+
+fn default_room_type_cache_capacity() -> u32 { 1000 * crate::utils::available_parallelism() as u32 }
+
 fn default_dns_cache_entries() -> u32 { 32768 }
 
 fn default_dns_min_ttl() -> u64 { 60 * 180 }
@@ -927,6 +1346,10 @@ fn default_dns_timeout() -> u64 { 10 }
 
 fn default_ip_lookup_strategy() -> u8 { 5 }
 
+fn default_trusted_proxy_header() -> String { "x-forwarded-for".to_owned() }
+
+fn default_max_account_data_size() -> usize { 1024 * 1024 }
+
 fn default_max_request_size() -> u32 {
 	20 * 1024 * 1024 // Default to 20 MB
 }
@@ -961,8 +1384,12 @@ fn default_appservice_timeout() -> u64 { 35 }
 
 fn default_appservice_idle_timeout() -> u64 { 300 }
 
+fn default_appservice_transaction_max_events() -> usize { 100 }
+
 fn default_pusher_idle_timeout() -> u64 { 15 }
 
+fn default_pusher_max_failures() -> u32 { 5 }
+
 fn default_max_fetch_prev_events() -> u16 { 100_u16 }
 
 #[cfg(feature = "perf_measurements")]
@@ -986,10 +1413,16 @@ fn default_notification_push_path() -> String { "/_matrix/push/v1/notify".to_own
 
 fn default_turn_ttl() -> u64 { 60 * 60 * 24 }
 
+fn default_login_token_ttl() -> u64 { 2 * 60 * 1000 }
+
+fn default_access_token_ttl() -> u64 { 5 * 60 * 1000 }
+
 fn default_presence_idle_timeout_s() -> u64 { 5 * 60 }
 
 fn default_presence_offline_timeout_s() -> u64 { 30 * 60 }
 
+fn default_presence_federation_batch_window_ms() -> u64 { 5_000 }
+
 fn default_typing_federation_timeout_s() -> u64 { 30 }
 
 fn default_typing_client_timeout_min_s() -> u64 { 15 }
@@ -1066,3 +1499,66 @@ fn default_sentry_endpoint() -> Option<Url> {
 fn default_sentry_traces_sample_rate() -> f32 { 0.15 }
 
 fn default_startup_netburst_keep() -> i64 { 50 }
+
+fn default_audit_log_max_age_days() -> u64 { 90 }
+
+fn default_lazy_load_expiry_days() -> u64 { 30 }
+
+fn default_remote_alias_cache_ttl_secs() -> u64 { 300 }
+
+fn default_remote_alias_negative_cache_ttl_secs() -> u64 { 60 }
+
+fn default_remote_profile_ttl_secs() -> u64 { 3600 }
+
+fn default_remote_profile_fetch_timeout_ms() -> u64 { 2000 }
+
+fn default_rate_limit_login() -> RateLimitBucket {
+	RateLimitBucket {
+		burst: 3,
+		per_second: 0.17,
+	}
+}
+
+fn default_rate_limit_registration() -> RateLimitBucket {
+	RateLimitBucket {
+		burst: 3,
+		per_second: 0.17,
+	}
+}
+
+fn default_rate_limit_message() -> RateLimitBucket {
+	RateLimitBucket {
+		burst: 20,
+		per_second: 1.0,
+	}
+}
+
+fn default_rate_limit_join() -> RateLimitBucket {
+	RateLimitBucket {
+		burst: 10,
+		per_second: 0.1,
+	}
+}
+
+fn default_rate_limit_media() -> RateLimitBucket {
+	RateLimitBucket {
+		burst: 50,
+		per_second: 5.0,
+	}
+}
+
+fn default_rate_limit_room_send_exempt_power_level() -> i64 { 50 }
+
+fn default_media_thumbnail_queue_size() -> usize { 256 }
+
+fn default_max_relation_depth() -> usize { 10 }
+
+fn default_forward_extremities_soft_limit() -> usize { 10 }
+
+fn default_forward_extremities_dummy_interval() -> u64 { 300 }
+
+fn default_max_pagination() -> usize { 100 }
+
+fn default_media_cold_after_days() -> u64 { 90 }
+
+fn default_media_cold_check_interval() -> u64 { 3600 }
@@ -0,0 +1,240 @@
+use std::{fmt, path::PathBuf};
+
+use ruma::{OwnedRoomId, OwnedServerName};
+
+use super::RateLimitConfig;
+use crate::{error::Error, Config};
+
+/// Settings which take effect on a running server when the config file is
+/// reloaded; every other setting requires a restart.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Reloadable {
+	pub log: String,
+	pub allow_registration: bool,
+	pub allow_guest_registration: bool,
+	pub registration_token: Option<String>,
+	pub trusted_servers: Vec<OwnedServerName>,
+	pub auto_join_rooms: Vec<OwnedRoomId>,
+	pub turn_username: String,
+	pub turn_password: String,
+	pub turn_uris: Vec<String>,
+	pub turn_secret: String,
+	pub turn_ttl: u64,
+	pub rate_limit: RateLimitConfig,
+}
+
+/// Outcome of comparing a freshly loaded config with the running one
+#[derive(Debug, Default)]
+pub struct ReloadReport {
+	/// Hot-reloadable fields whose value changed
+	pub applied: Vec<&'static str>,
+
+	/// Settings which changed but only take effect after a restart, named as
+	/// in the config's `Display` output
+	pub restart_required: Vec<String>,
+}
+
+impl Reloadable {
+	/// Names of the fields which differ between `self` and `other`
+	#[must_use]
+	pub fn changed(&self, other: &Self) -> Vec<&'static str> {
+		let mut changed = Vec::new();
+		macro_rules! diff {
+			($($field:ident),* $(,)?) => {
+				$(
+					if self.$field != other.$field {
+						changed.push(stringify!($field));
+					}
+				)*
+			};
+		}
+
+		diff!(
+			log,
+			allow_registration,
+			allow_guest_registration,
+			registration_token,
+			trusted_servers,
+			auto_join_rooms,
+			turn_username,
+			turn_password,
+			turn_uris,
+			turn_secret,
+			turn_ttl,
+			rate_limit,
+		);
+
+		changed
+	}
+
+	/// Overwrites the matching fields of `config` with these values
+	pub fn apply_to(&self, config: &mut Config) {
+		let this = self.clone();
+		config.log = this.log;
+		config.allow_registration = this.allow_registration;
+		config.allow_guest_registration = this.allow_guest_registration;
+		config.registration_token = this.registration_token;
+		config.trusted_servers = this.trusted_servers;
+		config.auto_join_rooms = this.auto_join_rooms;
+		config.turn_username = this.turn_username;
+		config.turn_password = this.turn_password;
+		config.turn_uris = this.turn_uris;
+		config.turn_secret = this.turn_secret;
+		config.turn_ttl = this.turn_ttl;
+		config.rate_limit = this.rate_limit;
+	}
+}
+
+impl From<&Config> for Reloadable {
+	fn from(config: &Config) -> Self {
+		Self {
+			log: config.log.clone(),
+			allow_registration: config.allow_registration,
+			allow_guest_registration: config.allow_guest_registration,
+			registration_token: config.registration_token.clone(),
+			trusted_servers: config.trusted_servers.clone(),
+			auto_join_rooms: config.auto_join_rooms.clone(),
+			turn_username: config.turn_username.clone(),
+			turn_password: config.turn_password.clone(),
+			turn_uris: config.turn_uris.clone(),
+			turn_secret: config.turn_secret.clone(),
+			turn_ttl: config.turn_ttl,
+			rate_limit: config.rate_limit.clone(),
+		}
+	}
+}
+
+impl Config {
+	/// Loads and checks the config again from the same sources as at startup.
+	/// Nothing is applied; an invalid config is returned as an error.
+	pub fn reload(&self, path: Option<PathBuf>) -> Result<Self, Error> {
+		let mut config = Self::new(path)?;
+
+		// set from the command line rather than the config file
+		config.rocksdb_read_only = self.rocksdb_read_only;
+
+		config.check()?;
+
+		Ok(config)
+	}
+
+	/// Compares `new` against this config, assuming the current hot-reloadable
+	/// values are `current`. Settings that require a restart are detected
+	/// by comparing the values shown by `Display` one by one, so secrets whose
+	/// value is not displayed are not reported.
+	#[must_use]
+	pub fn diff_reload(&self, current: &Reloadable, new: &Self) -> ReloadReport {
+		let new_reloadable = Reloadable::from(new);
+
+		let mut old = self.clone();
+		current.apply_to(&mut old);
+		let mut unapplied = new.clone();
+		current.apply_to(&mut unapplied);
+
+		let restart_required = old
+			.values()
+			.into_iter()
+			.zip(unapplied.values())
+			.filter(|(old, new)| old != new)
+			.map(|((label, _), _)| label.to_owned())
+			.collect();
+
+		ReloadReport {
+			applied: current.changed(&new_reloadable),
+			restart_required,
+		}
+	}
+}
+
+impl fmt::Display for ReloadReport {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		if self.applied.is_empty() && self.restart_required.is_empty() {
+			return writeln!(f, "No config changes found.");
+		}
+
+		if !self.applied.is_empty() {
+			writeln!(f, "Applied changes to: {}", self.applied.join(", "))?;
+		}
+
+		if !self.restart_required.is_empty() {
+			writeln!(f, "Changed but requiring a restart: {}", self.restart_required.join(", "))?;
+		}
+
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use figment::{
+		providers::{Format, Toml},
+		Figment,
+	};
+
+	use super::Reloadable;
+	use crate::{config::RateLimitConfig, Config};
+
+	fn reloadable() -> Reloadable {
+		Reloadable {
+			log: "warn".to_owned(),
+			allow_registration: false,
+			allow_guest_registration: false,
+			registration_token: None,
+			trusted_servers: vec!["matrix.org".try_into().unwrap()],
+			auto_join_rooms: Vec::new(),
+			turn_username: String::new(),
+			turn_password: String::new(),
+			turn_uris: Vec::new(),
+			turn_secret: String::new(),
+			turn_ttl: 86400,
+			rate_limit: RateLimitConfig::default(),
+		}
+	}
+
+	#[test]
+	fn unchanged() {
+		assert!(reloadable().changed(&reloadable()).is_empty());
+	}
+
+	#[test]
+	fn changed_fields() {
+		let mut new = reloadable();
+		new.log = "debug".to_owned();
+		new.allow_registration = true;
+		new.turn_uris = vec!["turn:example.com".to_owned()];
+
+		assert_eq!(reloadable().changed(&new), ["log", "allow_registration", "turn_uris"]);
+	}
+
+	fn config(toml: &str) -> Config {
+		Figment::new()
+			.merge(Toml::string(&format!(
+				"server_name = \"example.com\"\ndatabase_path = \"/db\"\n{toml}"
+			)))
+			.extract()
+			.unwrap()
+	}
+
+	#[test]
+	fn restart_required_per_field() {
+		let old = config("trusted_proxies = [\"10.0.0.1\"]\nlog = \"warn\"");
+		let new = config(
+			"trusted_proxies = [\"10.0.0.1\", \"10.0.0.2\", \"10.0.0.3\"]\nlog = \
+			 \"debug\"\nnew_user_displayname_suffix = \"x\"",
+		);
+
+		let report = old.diff_reload(&Reloadable::from(&old), &new);
+		assert_eq!(report.applied, ["log"]);
+		assert_eq!(report.restart_required, ["New user display name suffix", "Trusted proxies"]);
+	}
+
+	#[test]
+	fn rate_limits_reload() {
+		let old = config("");
+		let new = config("[rate_limit]\nenabled = false\n[rate_limit.login]\nburst = 5\nper_second = 1.0");
+
+		let report = old.diff_reload(&Reloadable::from(&old), &new);
+		assert_eq!(report.applied, ["rate_limit"]);
+		assert!(report.restart_required.is_empty());
+	}
+}
@@ -8,9 +8,9 @@ use ruma::{
 	api::{
 		client::{
 			error::ErrorKind::{
-				Forbidden, GuestAccessForbidden, LimitExceeded, MissingToken, NotFound, ThreepidAuthFailed,
-				ThreepidDenied, TooLarge, Unauthorized, Unknown, UnknownToken, Unrecognized, UserDeactivated,
-				WrongRoomKeysVersion,
+				Forbidden, GuestAccessForbidden, InvalidParam, LimitExceeded, MissingToken, NotFound,
+				ThreepidAuthFailed, ThreepidDenied, TooLarge, Unauthorized, Unknown, UnknownToken, Unrecognized,
+				UserDeactivated, WrongRoomKeysVersion,
 			},
 			uiaa::{UiaaInfo, UiaaResponse},
 		},
@@ -54,6 +54,10 @@ pub enum Error {
 	Federation(OwnedServerName, ruma::api::client::error::Error),
 	#[error("{0} in {1}")]
 	InconsistentRoomState(&'static str, ruma::OwnedRoomId),
+	#[error("Room {0} does not exist anymore")]
+	RoomGone(ruma::OwnedRoomId),
+	#[error("Invalid server name {0:?} in via")]
+	InvalidVia(String),
 
 	// conduwuit
 	#[error("There was a problem with your configuration: {0}")]
@@ -64,6 +68,10 @@ pub enum Error {
 	Database(String),
 	#[error("{0}")]
 	BadServerResponse(&'static str),
+	/// The push gateway refused a notification with a 4xx status other than
+	/// 429; sending it again will not succeed
+	#[error("Push gateway rejected the notification with {0}")]
+	PushGatewayRejected(StatusCode),
 	#[error("{0}")]
 	Conflict(&'static str), // This is only needed for when a room alias already exists
 	#[error("uiaa")]
@@ -93,6 +101,7 @@ impl Error {
 
 		match self {
 			Self::BadRequest(kind, _) => kind.clone(),
+			Self::InvalidVia(_) => InvalidParam,
 			_ => Unknown,
 		}
 	}
@@ -176,6 +185,8 @@ impl Error {
 				},
 			),
 			Self::Conflict(_) => (Unknown, StatusCode::CONFLICT),
+			Self::RoomGone(_) => (NotFound, StatusCode::NOT_FOUND),
+			Self::InvalidVia(_) => (InvalidParam, StatusCode::BAD_REQUEST),
 			_ => (Unknown, StatusCode::INTERNAL_SERVER_ERROR),
 		};
 
@@ -201,3 +212,21 @@ impl<T: OutgoingResponse> IntoResponse for RumaResponse<T> {
 		}
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use http::StatusCode;
+	use ruma::{api::client::uiaa::UiaaResponse, owned_room_id};
+
+	use super::Error;
+
+	#[test]
+	fn purged_room_is_not_found() {
+		let error = Error::RoomGone(owned_room_id!("!purged:example.com"));
+		let UiaaResponse::MatrixError(error) = error.to_response().0 else {
+			panic!("expected a matrix error");
+		};
+
+		assert_eq!(error.status_code, StatusCode::NOT_FOUND);
+	}
+}
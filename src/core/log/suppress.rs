@@ -10,7 +10,7 @@ pub struct Suppress {
 
 impl Suppress {
 	pub fn new(server: &Arc<Server>) -> Self {
-		let config = &server.config.log;
+		let config = server.reloadable.read().expect("locked").log.clone();
 		Self::from_filters(server, EnvFilter::try_new(config).unwrap_or_default(), &EnvFilter::default())
 	}
 
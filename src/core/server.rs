@@ -1,17 +1,32 @@
 use std::{
-	sync::atomic::{AtomicBool, AtomicU32, Ordering},
+	path::PathBuf,
+	sync::{
+		atomic::{AtomicBool, AtomicU32, Ordering},
+		RwLock,
+	},
 	time::SystemTime,
 };
 
 use tokio::{runtime, sync::broadcast};
 
-use crate::{config::Config, log, Error, Result};
+use crate::{
+	config::{Config, Reloadable},
+	log, Error, Result,
+};
 
 /// Server runtime state; public portion
 pub struct Server {
 	/// Server-wide configuration instance
 	pub config: Config,
 
+	/// Config file path given on the command line, if any; used to read the
+	/// config again on reload.
+	pub config_path: Option<PathBuf>,
+
+	/// Current values of the settings which can be changed by reloading the
+	/// config. These take precedence over the same fields in `config`.
+	pub reloadable: RwLock<Reloadable>,
+
 	/// Timestamp server was started; used for uptime.
 	pub started: SystemTime,
 
@@ -45,9 +60,11 @@ pub struct Server {
 
 impl Server {
 	#[must_use]
-	pub fn new(config: Config, runtime: Option<runtime::Handle>, log: log::Log) -> Self {
+	pub fn new(config: Config, config_path: Option<PathBuf>, runtime: Option<runtime::Handle>, log: log::Log) -> Self {
 		Self {
+			reloadable: RwLock::new(Reloadable::from(&config)),
 			config,
+			config_path,
 			started: SystemTime::now(),
 			stopping: AtomicBool::new(false),
 			reloading: AtomicBool::new(false),
@@ -0,0 +1,266 @@
+use std::{
+	net::{IpAddr, SocketAddr},
+	sync::atomic::{AtomicBool, Ordering},
+};
+
+use http::{header::FORWARDED, HeaderMap};
+use ipaddress::IPAddress;
+
+use crate::{Error, Result};
+
+/// Address of the client which sent a request, resolved by the router from
+/// the socket address and the forwarding headers of trusted reverse proxies
+#[derive(Clone, Copy, Debug)]
+pub struct ClientIp(pub IpAddr);
+
+/// Reverse proxies whose forwarding header is believed, from the
+/// `trusted_proxies` and `trusted_proxy_header` config options
+pub struct TrustedProxies {
+	ranges: Vec<IPAddress>,
+	header: ForwardingHeader,
+	ignored_forwarding: AtomicBool,
+}
+
+/// The single header the trusted proxies set; a client can send the other one
+/// through them unchanged, so it is never read
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum ForwardingHeader {
+	XForwardedFor,
+	Forwarded,
+}
+
+impl TrustedProxies {
+	pub fn new(cidrs: &[String], header: &str) -> Result<Self> {
+		let header = match header.to_ascii_lowercase().as_str() {
+			"x-forwarded-for" => ForwardingHeader::XForwardedFor,
+			"forwarded" => ForwardingHeader::Forwarded,
+			_ => return Err(Error::Err(format!("Invalid trusted proxy header {header:?}"))),
+		};
+
+		let ranges = cidrs
+			.iter()
+			.map(|cidr| {
+				IPAddress::parse(cidr.as_str())
+					.map_err(|e| Error::Err(format!("Invalid trusted proxy range {cidr:?}: {e}")))
+			})
+			.collect::<Result<_>>()?;
+
+		Ok(Self {
+			ranges,
+			header,
+			ignored_forwarding: AtomicBool::new(false),
+		})
+	}
+
+	/// Whether the request has forwarding headers which are ignored because
+	/// no proxy is trusted, only returning true the first time, so this can
+	/// be warned about once.
+	#[must_use]
+	pub fn first_ignored_forwarding(&self, headers: &HeaderMap) -> bool {
+		self.ranges.is_empty()
+			&& !forwarded_chain(headers, self.header).is_empty()
+			&& !self.ignored_forwarding.swap(true, Ordering::Relaxed)
+	}
+
+	/// Resolves the address of the client. The forwarding chain is walked
+	/// from the right, starting at the socket address, for as long as the
+	/// addresses belong to trusted proxies; the first other address is the
+	/// client. Headers sent by untrusted peers are ignored.
+	#[must_use]
+	pub fn resolve(&self, peer: IpAddr, headers: &HeaderMap) -> IpAddr {
+		let mut client = canonical(peer);
+		if self.ranges.is_empty() || !self.contains(client) {
+			return client;
+		}
+
+		for hop in forwarded_chain(headers, self.header).into_iter().rev() {
+			// unknown or obfuscated identifiers end the chain at the last proxy
+			let Some(hop) = hop else {
+				break;
+			};
+
+			client = hop;
+			if !self.contains(client) {
+				break;
+			}
+		}
+
+		client
+	}
+
+	fn contains(&self, ip: IpAddr) -> bool {
+		let Ok(address) = IPAddress::parse(ip.to_string()) else {
+			return false;
+		};
+
+		self.ranges
+			.iter()
+			.any(|range| range.is_ipv4() == ip.is_ipv4() && range.includes(&address))
+	}
+}
+
+/// The addresses of the configured forwarding header, from the client to the
+/// last proxy. `None` stands for an element which is not an address.
+fn forwarded_chain(headers: &HeaderMap, header: ForwardingHeader) -> Vec<Option<IpAddr>> {
+	match header {
+		ForwardingHeader::Forwarded => headers
+			.get_all(FORWARDED)
+			.iter()
+			.filter_map(|value| value.to_str().ok())
+			.flat_map(|value| value.split(','))
+			.map(|element| {
+				element
+					.split(';')
+					.filter_map(|pair| pair.split_once('='))
+					.find(|(key, _)| key.trim().eq_ignore_ascii_case("for"))
+					.and_then(|(_, value)| parse_node(value.trim().trim_matches('"')))
+			})
+			.collect(),
+		ForwardingHeader::XForwardedFor => headers
+			.get_all("x-forwarded-for")
+			.iter()
+			.filter_map(|value| value.to_str().ok())
+			.flat_map(|value| value.split(','))
+			.map(|node| parse_node(node.trim()))
+			.collect(),
+	}
+}
+
+/// Parses an address which may have a port, with IPv6 addresses in brackets
+/// if so
+fn parse_node(node: &str) -> Option<IpAddr> {
+	node.parse::<IpAddr>()
+		.ok()
+		.or_else(|| node.parse::<SocketAddr>().ok().map(|addr| addr.ip()))
+		.or_else(|| {
+			node.strip_prefix('[')
+				.and_then(|node| node.strip_suffix(']'))
+				.and_then(|node| node.parse().ok())
+		})
+		.map(canonical)
+}
+
+/// IPv4 addresses mapped into IPv6, as seen on dual-stack sockets, are
+/// matched as IPv4
+fn canonical(ip: IpAddr) -> IpAddr {
+	match ip {
+		IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(IpAddr::V6(v6), IpAddr::V4),
+		IpAddr::V4(_) => ip,
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::net::IpAddr;
+
+	use http::{HeaderMap, HeaderValue};
+
+	use super::TrustedProxies;
+
+	fn proxies_with(header: &str) -> TrustedProxies {
+		TrustedProxies::new(&["10.0.0.0/8".to_owned(), "fd00::/8".to_owned()], header).expect("valid ranges")
+	}
+
+	fn proxies() -> TrustedProxies { proxies_with("x-forwarded-for") }
+
+	fn headers(name: &'static str, value: &'static str) -> HeaderMap {
+		let mut headers = HeaderMap::new();
+		headers.insert(name, HeaderValue::from_static(value));
+		headers
+	}
+
+	fn ip(ip: &str) -> IpAddr { ip.parse().expect("valid address") }
+
+	#[test]
+	fn untrusted_peer_headers_are_ignored() {
+		let headers = headers("x-forwarded-for", "1.2.3.4");
+		assert_eq!(proxies().resolve(ip("203.0.113.7"), &headers), ip("203.0.113.7"));
+		assert_eq!(proxies().resolve(ip("2001:db8::7"), &headers), ip("2001:db8::7"));
+	}
+
+	#[test]
+	fn no_trusted_proxies() {
+		let proxies = TrustedProxies::new(&[], "x-forwarded-for").expect("valid ranges");
+		let headers = headers("x-forwarded-for", "1.2.3.4");
+		assert_eq!(proxies.resolve(ip("10.0.0.1"), &headers), ip("10.0.0.1"));
+	}
+
+	#[test]
+	fn ignored_forwarding_reported_once() {
+		let none = TrustedProxies::new(&[], "forwarded").expect("valid ranges");
+		assert!(!none.first_ignored_forwarding(&HeaderMap::new()));
+		assert!(!none.first_ignored_forwarding(&headers("x-forwarded-for", "1.2.3.4")));
+		assert!(none.first_ignored_forwarding(&headers("forwarded", "for=192.0.2.60")));
+		assert!(!none.first_ignored_forwarding(&headers("forwarded", "for=192.0.2.60")));
+
+		assert!(!proxies().first_ignored_forwarding(&headers("x-forwarded-for", "1.2.3.4")));
+	}
+
+	#[test]
+	fn x_forwarded_for_from_the_right() {
+		// the leftmost address is made up by the client
+		let headers = headers("x-forwarded-for", "6.6.6.6, 198.51.100.2, 10.1.1.1");
+		assert_eq!(proxies().resolve(ip("10.0.0.1"), &headers), ip("198.51.100.2"));
+	}
+
+	#[test]
+	fn all_hops_trusted() {
+		let headers = headers("x-forwarded-for", "10.2.2.2, 10.1.1.1");
+		assert_eq!(proxies().resolve(ip("10.0.0.1"), &headers), ip("10.2.2.2"));
+	}
+
+	#[test]
+	fn forwarded_ipv6() {
+		let headers = headers(
+			"forwarded",
+			r#"for=192.0.2.60;proto=https, for="[2001:db8:cafe::17]:4711";by=fd00::1"#,
+		);
+		let proxies = proxies_with("forwarded");
+		assert_eq!(proxies.resolve(ip("fd00::1"), &headers), ip("2001:db8:cafe::17"));
+	}
+
+	#[test]
+	fn client_forwarded_ignored_with_x_forwarded_for() {
+		// the proxy only sets X-Forwarded-For and passes Forwarded through
+		let spoofed = headers("forwarded", "for=6.6.6.6");
+		assert_eq!(proxies().resolve(ip("10.0.0.1"), &spoofed), ip("10.0.0.1"));
+
+		let mut headers = spoofed;
+		headers.insert("x-forwarded-for", HeaderValue::from_static("198.51.100.2"));
+		assert_eq!(proxies().resolve(ip("10.0.0.1"), &headers), ip("198.51.100.2"));
+	}
+
+	#[test]
+	fn client_x_forwarded_for_ignored_with_forwarded() {
+		let mut headers = headers("x-forwarded-for", "6.6.6.6");
+		headers.insert("forwarded", HeaderValue::from_static("for=198.51.100.2"));
+		assert_eq!(proxies_with("forwarded").resolve(ip("10.0.0.1"), &headers), ip("198.51.100.2"));
+	}
+
+	#[test]
+	fn obfuscated_hop_stops_at_proxy() {
+		let headers = headers("forwarded", "for=192.0.2.60, for=_hidden");
+		let proxies = proxies_with("forwarded");
+		assert_eq!(proxies.resolve(ip("10.0.0.1"), &headers), ip("10.0.0.1"));
+	}
+
+	#[test]
+	fn invalid_header_rejected() {
+		TrustedProxies::new(&[], "x-real-ip").unwrap_err();
+		TrustedProxies::new(&[], "X-Forwarded-For").unwrap();
+	}
+
+	#[test]
+	fn ipv4_mapped_addresses() {
+		// dual-stack sockets report IPv4 peers as mapped IPv6 addresses
+		let headers = headers("x-forwarded-for", "::ffff:198.51.100.2, ::ffff:10.1.1.1");
+		assert_eq!(proxies().resolve(ip("::ffff:10.0.0.1"), &headers), ip("198.51.100.2"));
+		assert_eq!(proxies().resolve(ip("::ffff:203.0.113.7"), &headers), ip("203.0.113.7"));
+	}
+
+	#[test]
+	fn ports_are_stripped() {
+		let headers = headers("x-forwarded-for", "198.51.100.2:5555, [2001:db8::1]:443");
+		assert_eq!(proxies().resolve(ip("10.0.0.1"), &headers), ip("2001:db8::1"));
+	}
+}
@@ -0,0 +1,71 @@
+use std::{
+	borrow::Borrow,
+	collections::HashMap,
+	hash::Hash,
+	sync::{Arc, Mutex},
+};
+
+/// Map of blocking Mutexes, the counterpart of `MutexMap` for locks taken in
+/// synchronous code. An entry only lives while its lock is held or waited for.
+pub struct LockMap<Key> {
+	map: Mutex<HashMap<Key, Arc<Mutex<()>>>>,
+}
+
+impl<Key> LockMap<Key>
+where
+	Key: Hash + Eq,
+{
+	#[must_use]
+	pub fn new() -> Self {
+		Self {
+			map: Mutex::new(HashMap::new()),
+		}
+	}
+
+	/// Runs `f` holding the lock of `key`, removing its entry again when no one
+	/// else holds or waits for it
+	pub fn with_lock<Q, T, F>(&self, key: &Q, f: F) -> T
+	where
+		Key: Borrow<Q>,
+		Q: ?Sized + Eq + Hash + ToOwned<Owned = Key>,
+		F: FnOnce() -> T,
+	{
+		let lock = self
+			.map
+			.lock()
+			.expect("map mutex locked")
+			.entry(key.to_owned())
+			.or_default()
+			.clone();
+
+		let result = {
+			let _guard = lock.lock().expect("locked");
+			f()
+		};
+
+		let mut map = self.map.lock().expect("map mutex locked");
+		drop(lock);
+		if map
+			.get(key)
+			.is_some_and(|lock| Arc::strong_count(lock) == 1)
+		{
+			map.remove(key);
+		}
+
+		result
+	}
+
+	/// Number of keys whose lock is held or waited for
+	#[must_use]
+	pub fn len(&self) -> usize { self.map.lock().expect("map mutex locked").len() }
+
+	#[must_use]
+	pub fn is_empty(&self) -> bool { self.len() == 0 }
+}
+
+impl<Key> Default for LockMap<Key>
+where
+	Key: Hash + Eq,
+{
+	fn default() -> Self { Self::new() }
+}
@@ -1,9 +1,11 @@
+pub mod client_ip;
 pub mod content_disposition;
 pub mod debug;
 pub mod defer;
 pub mod hash;
 pub mod html;
 pub mod json;
+pub mod lock_map;
 pub mod mutex_map;
 pub mod sys;
 mod tests;
@@ -13,9 +15,11 @@ use std::{
 	time::{SystemTime, UNIX_EPOCH},
 };
 
+pub use client_ip::{ClientIp, TrustedProxies};
 pub use debug::slice_truncated as debug_slice_truncated;
 pub use html::Escape as HtmlEscape;
 pub use json::{deserialize_from_str, to_canonical_object};
+pub use lock_map::LockMap;
 pub use mutex_map::MutexMap;
 use rand::prelude::*;
 use ring::digest;
@@ -114,6 +118,28 @@ pub fn common_elements(
 	}))
 }
 
+/// Index of the iterator yielding the fewest items, or `None` if there are
+/// none. The iterators are advanced in turns, so none is read more than one
+/// item past the length of the shortest. Ties go to the first.
+pub fn shortest<T, I>(iterators: T) -> Option<usize>
+where
+	T: IntoIterator<Item = I>,
+	I: Iterator,
+{
+	let mut iterators: Vec<_> = iterators.into_iter().collect();
+	if iterators.is_empty() {
+		return None;
+	}
+
+	loop {
+		for (i, it) in iterators.iter_mut().enumerate() {
+			if it.next().is_none() {
+				return Some(i);
+			}
+		}
+	}
+}
+
 /// Boilerplate for wraps which are typed to never error.
 ///
 /// * <https://doc.rust-lang.org/std/convert/enum.Infallible.html>
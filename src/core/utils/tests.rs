@@ -1,5 +1,13 @@
 #![cfg(test)]
 
+use std::{
+	cell::Cell,
+	collections::BTreeSet,
+	sync::{mpsc, Barrier},
+	thread,
+	time::Duration,
+};
+
 use crate::utils;
 
 #[test]
@@ -35,3 +43,92 @@ fn increment_wrap() {
 	let res = u64::from_be_bytes(bytes);
 	assert_eq!(res, 0);
 }
+
+#[test]
+fn shortest_asymmetric() {
+	let pulled = Cell::new(0_usize);
+	let large = (0..10_000_u32).inspect(|_| pulled.set(pulled.get() + 1));
+	let small = [3_u32, 7, 11].into_iter();
+
+	let iterators: Vec<Box<dyn Iterator<Item = u32>>> = vec![Box::new(large), Box::new(small)];
+	assert_eq!(utils::shortest(iterators), Some(1));
+	assert_eq!(pulled.get(), 4, "the larger iterator is read one item past the shorter one");
+}
+
+#[test]
+fn shortest_ties_and_empty() {
+	assert_eq!(utils::shortest(Vec::<std::ops::Range<u8>>::new()), None);
+	assert_eq!(utils::shortest([0..2_u8, 0..2, 0..5]), Some(0));
+	assert_eq!(utils::shortest([0..2_u8, 0..0]), Some(1));
+}
+
+#[test]
+fn shared_by_probing_shortest() {
+	let rooms: [BTreeSet<u32>; 3] = [
+		(0..1000).collect(),
+		(0..1000).step_by(7).collect(),
+		[0, 14, 21, 500, 994, 2000].into_iter().collect(),
+	];
+
+	let smallest = utils::shortest(rooms.iter().map(BTreeSet::iter)).expect("not empty");
+	assert_eq!(smallest, 2);
+
+	let shared: Vec<u32> = rooms[smallest]
+		.iter()
+		.filter(|room| rooms.iter().all(|joined| joined.contains(room)))
+		.copied()
+		.collect();
+
+	let expected: Vec<u32> = utils::common_elements(
+		rooms
+			.iter()
+			.map(|joined| joined.iter().map(|room| room.to_be_bytes().to_vec())),
+		Ord::cmp,
+	)
+	.expect("not empty")
+	.map(|bytes| u32::from_be_bytes(bytes.try_into().expect("four bytes")))
+	.collect();
+
+	assert_eq!(shared, [0, 14, 21, 994]);
+	assert_eq!(shared, expected);
+}
+
+#[test]
+fn lock_map_evicts_released_locks() {
+	let locks = utils::LockMap::<String>::new();
+
+	// a waiter keeps the entry of the holder
+	let (held, release) = mpsc::channel::<()>();
+	thread::scope(|scope| {
+		let locks = &locks;
+		scope.spawn(move || {
+			locks.with_lock("alice", || {
+				held.send(()).unwrap();
+				thread::sleep(Duration::from_millis(50));
+			});
+		});
+		release.recv().unwrap();
+
+		locks.with_lock("alice", || assert_eq!(locks.len(), 1));
+	});
+	assert!(locks.is_empty());
+
+	assert_eq!(locks.with_lock("bob", || locks.len()), 1);
+	assert!(locks.is_empty());
+}
+
+#[test]
+fn lock_map_keys_do_not_block_each_other() {
+	let locks = utils::LockMap::<String>::new();
+	let both_held = Barrier::new(2);
+
+	// each thread holds its key until the other holds its own, which would
+	// deadlock if the keys shared a lock
+	thread::scope(|scope| {
+		for key in ["alice", "bob"] {
+			let (locks, both_held) = (&locks, &both_held);
+			scope.spawn(move || locks.with_lock(key, || both_held.wait()));
+		}
+	});
+	assert!(locks.is_empty());
+}
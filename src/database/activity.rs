@@ -0,0 +1,118 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Operations performed on a map, counted when `database_activity_counters`
+/// is enabled
+#[derive(Default)]
+pub(crate) struct Counters {
+	gets: AtomicU64,
+	inserts: AtomicU64,
+	removes: AtomicU64,
+}
+
+/// Number of operations performed on a map since startup or the last reset
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Activity {
+	pub gets: u64,
+	pub inserts: u64,
+	pub removes: u64,
+}
+
+impl Counters {
+	#[inline]
+	pub(crate) fn get(&self, n: usize) { add(&self.gets, n); }
+
+	#[inline]
+	pub(crate) fn insert(&self, n: usize) { add(&self.inserts, n); }
+
+	#[inline]
+	pub(crate) fn remove(&self, n: usize) { add(&self.removes, n); }
+
+	pub(crate) fn activity(&self) -> Activity {
+		Activity {
+			gets: self.gets.load(Ordering::Relaxed),
+			inserts: self.inserts.load(Ordering::Relaxed),
+			removes: self.removes.load(Ordering::Relaxed),
+		}
+	}
+
+	pub(crate) fn reset(&self) {
+		self.gets.store(0, Ordering::Relaxed);
+		self.inserts.store(0, Ordering::Relaxed);
+		self.removes.store(0, Ordering::Relaxed);
+	}
+}
+
+impl Activity {
+	/// Writes to the map, which is what amplification is measured in
+	#[must_use]
+	pub fn writes(&self) -> u64 { self.inserts.saturating_add(self.removes) }
+
+	#[must_use]
+	pub fn total(&self) -> u64 { self.writes().saturating_add(self.gets) }
+}
+
+/// Maps with any activity, the most written first, then the most used, then
+/// by name
+pub(crate) fn busiest_first<'a, I>(maps: I) -> Vec<(&'a str, Activity)>
+where
+	I: Iterator<Item = (&'a str, Activity)>,
+{
+	let mut activity: Vec<_> = maps.filter(|(_, activity)| activity.total() > 0).collect();
+	activity.sort_by(|(a_name, a), (b_name, b)| {
+		b.writes()
+			.cmp(&a.writes())
+			.then_with(|| b.total().cmp(&a.total()))
+			.then_with(|| a_name.cmp(b_name))
+	});
+
+	activity
+}
+
+#[inline]
+fn add(counter: &AtomicU64, n: usize) { counter.fetch_add(n.try_into().unwrap_or(u64::MAX), Ordering::Relaxed); }
+
+#[cfg(test)]
+mod tests {
+	use super::{busiest_first, Counters};
+
+	fn counters(gets: usize, inserts: usize, removes: usize) -> Counters {
+		let counters = Counters::default();
+		counters.get(gets);
+		counters.insert(inserts);
+		counters.remove(removes);
+		counters
+	}
+
+	#[test]
+	fn busiest_maps_first() {
+		let maps = [
+			("reads", counters(100, 0, 0)),
+			("idle", counters(0, 0, 0)),
+			("removes", counters(0, 0, 4)),
+			("b_writes", counters(1, 3, 2)),
+			("a_writes", counters(1, 3, 2)),
+			("writes", counters(0, 4, 1)),
+		];
+
+		let names: Vec<_> = busiest_first(maps.iter().map(|(name, c)| (*name, c.activity())))
+			.into_iter()
+			.map(|(name, _)| name)
+			.collect();
+
+		assert_eq!(names, ["a_writes", "b_writes", "writes", "removes", "reads"]);
+	}
+
+	#[test]
+	fn reset_activity() {
+		let counters = counters(1, 2, 3);
+		assert_eq!(counters.activity().writes(), 5);
+		assert_eq!(counters.activity().total(), 6);
+
+		counters.reset();
+		assert_eq!(counters.activity().total(), 0);
+		assert!(busiest_first([("map", counters.activity())].into_iter()).is_empty());
+
+		counters.insert(1);
+		assert_eq!(counters.activity().inserts, 1);
+	}
+}
@@ -2,13 +2,22 @@ use std::{
 	collections::{BTreeMap, HashMap},
 	ops::Index,
 	sync::{Arc, Mutex, RwLock},
+	time::Instant,
 };
 
-use conduit::{PduCount, Result, Server};
+use conduit::{Error, PduCount, Result, Server};
 use lru_cache::LruCache;
 use ruma::{CanonicalJsonValue, OwnedDeviceId, OwnedRoomId, OwnedUserId};
 
-use crate::{maps, maps::Maps, Engine, Map};
+use crate::{activity::busiest_first, maps, maps::Maps, Activity, Engine, Map};
+
+/// The current schema version.
+/// - If database is opened at greater version we reject with error. The
+///   software must be updated for backward-incompatible changes.
+/// - If database is opened at lesser version we apply migrations up to this.
+///   Note that named-feature migrations may also be performed when opening at
+///   equal or lesser version. These are expected to be backward-compatible.
+pub const DATABASE_VERSION: u64 = 13;
 
 pub struct Database {
 	pub db: Arc<Engine>,
@@ -19,6 +28,7 @@ pub struct Database {
 	pub auth_chain_cache: Mutex<LruCache<Vec<u64>, Arc<[u64]>>>,
 	pub appservice_in_room_cache: RwLock<HashMap<OwnedRoomId, HashMap<String, bool>>>,
 	pub lasttimelinecount_cache: Mutex<HashMap<OwnedRoomId, PduCount>>,
+	activity_since: Mutex<Instant>,
 }
 
 impl Database {
@@ -26,17 +36,51 @@ impl Database {
 	pub async fn open(server: &Arc<Server>) -> Result<Self> {
 		let config = &server.config;
 		let db = Engine::open(server)?;
-		Ok(Self {
+		let database = Self {
 			db: db.clone(),
 			map: maps::open(&db)?,
 
 			userdevicesessionid_uiaarequest: RwLock::new(BTreeMap::new()),
 			appservice_in_room_cache: RwLock::new(HashMap::new()),
 			lasttimelinecount_cache: Mutex::new(HashMap::new()),
+			activity_since: Mutex::new(Instant::now()),
 			auth_chain_cache: Mutex::new(LruCache::new(
 				(f64::from(config.auth_chain_cache_capacity) * config.conduit_cache_capacity_modifier) as usize,
 			)),
-		})
+		};
+
+		let Some(source) = config.conduit_import_path.clone() else {
+			return Ok(database);
+		};
+
+		// copying a whole database blocks for a long time
+		server
+			.runtime()
+			.spawn_blocking(move || crate::import::conduit(&database, &source).map(|()| database))
+			.await
+			.map_err(|e| Error::Err(format!("Importing the Conduit database failed: {e}")))?
+	}
+
+	/// Operations performed on each map, busiest first, and when counting
+	/// started. Empty unless `database_activity_counters` is enabled.
+	pub fn activity(&self) -> (Vec<(&str, Activity)>, Instant) {
+		let since = *self.activity_since.lock().expect("locked");
+		let activity = busiest_first(
+			self.map
+				.values()
+				.filter_map(|map| Some((map.name(), map.activity()?))),
+		);
+
+		(activity, since)
+	}
+
+	pub fn reset_activity(&self) {
+		let mut since = self.activity_since.lock().expect("locked");
+		for map in self.map.values() {
+			map.reset_activity();
+		}
+
+		*since = Instant::now();
 	}
 }
 
@@ -18,7 +18,7 @@ use crate::{
 };
 
 pub struct Engine {
-	server: Arc<Server>,
+	pub(crate) server: Arc<Server>,
 	row_cache: Cache,
 	col_cache: RwLock<HashMap<String, Cache>>,
 	opts: Options,
@@ -156,6 +156,29 @@ impl Engine {
 		Ok(res)
 	}
 
+	/// Bytes used by the row cache and the column caches
+	pub fn cache_usage(&self) -> usize {
+		self.col_cache
+			.read()
+			.expect("locked")
+			.values()
+			.map(Cache::get_usage)
+			.fold(self.row_cache.get_usage(), usize::saturating_add)
+	}
+
+	/// Size of the files in the database directory
+	pub fn size(&self) -> Result<u64> {
+		let mut size: u64 = 0;
+		for entry in std::fs::read_dir(&self.server.config.database_path)? {
+			let metadata = entry?.metadata()?;
+			if metadata.is_file() {
+				size = size.saturating_add(metadata.len());
+			}
+		}
+
+		Ok(size)
+	}
+
 	pub fn cleanup(&self) -> Result<()> {
 		debug!("Running flush_opt");
 		let flushoptions = rocksdb::FlushOptions::default();
@@ -223,6 +246,27 @@ impl Engine {
 		Ok(res)
 	}
 
+	/// When the most recent backup was made, in seconds since the epoch;
+	/// `None` without backups or without `database_backup_path`
+	pub fn last_backup(&self) -> Result<Option<i64>> {
+		let config = &self.server.config;
+		let Some(path) = config
+			.database_backup_path
+			.as_ref()
+			.filter(|path| !path.as_os_str().is_empty())
+		else {
+			return Ok(None);
+		};
+
+		let options = BackupEngineOptions::new(path).or_else(or_else)?;
+		let engine = BackupEngine::open(&options, &self.env).or_else(or_else)?;
+		Ok(engine
+			.get_backup_info()
+			.iter()
+			.map(|info| info.timestamp)
+			.max())
+	}
+
 	pub fn file_list(&self) -> Result<String> {
 		match self.db.live_files() {
 			Err(e) => Ok(String::from(e)),
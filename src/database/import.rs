@@ -0,0 +1,593 @@
+use std::{mem, path::Path};
+
+use conduit::{info, warn, Error, Result};
+use rocksdb::{Direction, IteratorMode, Options};
+
+use crate::{engine::Db, util::map_err, Database, DATABASE_VERSION};
+
+/// Prefix of the keys in `global` recording the progress of an import, so an
+/// interrupted one can be resumed
+const PROGRESS: &[u8] = b"conduit_import\xFF";
+
+/// Key in `global` recording that an import completed, so it is not attempted
+/// again while `conduit_import_path` is still set
+const IMPORTED: &[u8] = b"conduit_imported";
+
+/// Entries written per batch, after which the progress is recorded
+const BATCH_SIZE: usize = 10_000;
+
+/// Entries between two progress reports of a table
+const REPORT_INTERVAL: usize = 250_000;
+
+const GLOBAL: &str = "global";
+const VERSION: &[u8] = b"version";
+
+type KeyVal = (Vec<u8>, Vec<u8>);
+type Entries<'a> = Box<dyn Iterator<Item = Result<KeyVal>> + 'a>;
+
+/// The Conduit database being imported
+trait Source {
+	fn tables(&self) -> Vec<String>;
+
+	fn get(&self, table: &str, key: &[u8]) -> Result<Option<Vec<u8>>>;
+
+	/// Entries of the table in order, starting at `from` if given
+	fn entries(&self, table: &str, from: Option<&[u8]>) -> Result<Entries<'_>>;
+}
+
+/// The database the entries are imported into
+trait Target {
+	fn has_table(&self, table: &str) -> bool;
+
+	fn is_empty(&self) -> bool;
+
+	fn get(&self, table: &str, key: &[u8]) -> Result<Option<Vec<u8>>>;
+
+	fn insert(&self, table: &str, key: &[u8], val: &[u8]) -> Result<()>;
+
+	fn insert_batch(&self, table: &str, batch: Vec<KeyVal>) -> Result<()>;
+
+	fn remove_batch(&self, table: &str, keys: Vec<Vec<u8>>) -> Result<()>;
+
+	fn keys_with_prefix(&self, table: &str, prefix: &[u8]) -> Vec<Vec<u8>>;
+
+	fn count(&self, table: &str) -> usize;
+}
+
+/// Imports the database of an upstream Conduit server at `source`, which is
+/// opened read-only, into this empty database.
+///
+/// Conduit's trees use the formats conduwuit started from, so the entries of
+/// every tree known to both are copied as they are. Trees conduwuit does not
+/// know are only skipped when empty; the import fails listing them otherwise,
+/// rather than leaving their data behind. The schema version of the source is
+/// checked before anything is copied and written last, and the regular
+/// migrations then bring counters, PDU ids and account data keys of older
+/// schemas up to date on this startup.
+pub(crate) fn conduit(db: &Database, source: &Path) -> Result<()> {
+	if db[GLOBAL].get(IMPORTED)?.is_some() {
+		return import(&NoSource, db, DATABASE_VERSION);
+	}
+
+	let opts = Options::default();
+	let tables = Db::list_cf(&opts, source).map_err(map_err)?;
+	let db_source = Db::open_cf_for_read_only(&opts, source, &tables, false).map_err(map_err)?;
+
+	warn!("Importing the Conduit database at {source:?}, this may take a long time...");
+	import(
+		&RocksSource {
+			db: &db_source,
+			tables,
+		},
+		db,
+		DATABASE_VERSION,
+	)
+}
+
+fn import<S: Source, T: Target>(source: &S, target: &T, max_version: u64) -> Result<()> {
+	let progress = target.keys_with_prefix(GLOBAL, PROGRESS);
+	if target.get(GLOBAL, IMPORTED)?.is_some() {
+		// Left behind if the import was interrupted right after completing
+		target.remove_batch(GLOBAL, progress)?;
+		info!("The Conduit database was already imported, conduit_import_path can be removed from the config");
+		return Ok(());
+	}
+
+	if progress.is_empty() && !target.is_empty() {
+		return Err(Error::Err(
+			"Refusing to import a Conduit database into a database which is not empty.".to_owned(),
+		));
+	}
+
+	let version = source_version(source, max_version)?;
+	let (mut tables, unknown): (Vec<_>, Vec<_>) = source
+		.tables()
+		.into_iter()
+		.filter(|table| table != "default")
+		.partition(|table| target.has_table(table));
+
+	let mut unhandled = Vec::new();
+	for table in unknown {
+		if source.entries(&table, None)?.next().is_some() {
+			unhandled.push(table);
+		}
+	}
+
+	if !unhandled.is_empty() {
+		return Err(Error::Err(format!(
+			"The Conduit database has trees conduwuit does not know, so their data would be lost: {}. Use a version \
+			 of conduwuit which handles them.",
+			unhandled.join(", ")
+		)));
+	}
+
+	if !progress.is_empty() {
+		warn!("Resuming the interrupted import of the Conduit database");
+	}
+
+	// `global` holds the schema version and the import progress, so it goes last
+	tables.sort_by_key(|table| table == GLOBAL);
+	for table in &tables {
+		copy_table(source, target, table)?;
+	}
+
+	finish(source, target, &tables, version)?;
+
+	warn!(
+		tables = tables.len(),
+		version, "Imported the Conduit database; it is migrated to the current schema next"
+	);
+
+	Ok(())
+}
+
+/// Reads the schema version of the Conduit database, which must not be newer
+/// than the migrations know
+fn source_version<S: Source>(source: &S, max_version: u64) -> Result<u64> {
+	let version = source
+		.get(GLOBAL, VERSION)?
+		.ok_or_else(|| Error::Err("The Conduit database has no schema version.".to_owned()))?;
+	let version = <[u8; 8]>::try_from(version.as_slice())
+		.map(u64::from_be_bytes)
+		.map_err(|_| Error::bad_database("Invalid schema version in the Conduit database."))?;
+
+	if version > max_version {
+		return Err(Error::Err(format!(
+			"The Conduit database has schema version {version}, newer than {max_version} which this version of \
+			 conduwuit supports. Update conduwuit before importing it."
+		)));
+	}
+
+	Ok(version)
+}
+
+/// Copies a table in batches, recording the last key of each so an
+/// interrupted import continues after it
+fn copy_table<S: Source, T: Target>(source: &S, target: &T, table: &str) -> Result<()> {
+	let done_key = progress_key("done", table);
+	if target.get(GLOBAL, &done_key)?.is_some() {
+		info!(%table, "Already imported");
+		return Ok(());
+	}
+
+	let last_key = progress_key("last", table);
+	let resume_from = target.get(GLOBAL, &last_key)?;
+
+	let mut copied: usize = 0;
+	let mut batch = Vec::with_capacity(BATCH_SIZE);
+	for entry in source.entries(table, resume_from.as_deref())? {
+		let (key, val) = entry?;
+		if resume_from.as_deref() == Some(key.as_slice()) || (table == GLOBAL && key == VERSION) {
+			continue;
+		}
+
+		batch.push((key, val));
+		if batch.len() >= BATCH_SIZE {
+			let before = copied;
+			copied = copied.saturating_add(write_batch(target, table, &last_key, &mut batch)?);
+			if copied / REPORT_INTERVAL > before / REPORT_INTERVAL {
+				info!(%table, copied, "Importing table");
+			}
+		}
+	}
+
+	copied = copied.saturating_add(write_batch(target, table, &last_key, &mut batch)?);
+	target.insert(GLOBAL, &done_key, &[])?;
+	target.remove_batch(GLOBAL, vec![last_key])?;
+	info!(%table, copied, "Imported table");
+
+	Ok(())
+}
+
+fn write_batch<T: Target>(target: &T, table: &str, last_key: &[u8], batch: &mut Vec<KeyVal>) -> Result<usize> {
+	let Some((last, _)) = batch.last() else {
+		return Ok(0);
+	};
+
+	let last = last.clone();
+	let len = batch.len();
+	target.insert_batch(table, mem::replace(batch, Vec::with_capacity(BATCH_SIZE)))?;
+	target.insert(GLOBAL, last_key, &last)?;
+
+	Ok(len)
+}
+
+/// Writes the schema version and checks every table against the source. The
+/// progress is only removed once both succeeded, so the import is resumed
+/// and checked again if either fails.
+fn finish<S: Source, T: Target>(source: &S, target: &T, tables: &[String], version: u64) -> Result<()> {
+	target.insert(GLOBAL, VERSION, &version.to_be_bytes())?;
+
+	let mut mismatched = Vec::new();
+	for table in tables {
+		let mut source_count: usize = 0;
+		for entry in source.entries(table, None)? {
+			entry?;
+			source_count = source_count.saturating_add(1);
+		}
+
+		let mut target_count = target.count(table);
+		if table == GLOBAL {
+			target_count = target_count.saturating_sub(target.keys_with_prefix(GLOBAL, PROGRESS).len());
+		}
+
+		info!(%table, entries = target_count, "Checked imported table");
+		if source_count != target_count {
+			mismatched.push(format!("{table}: {source_count} in Conduit, {target_count} imported"));
+		}
+	}
+
+	if !mismatched.is_empty() {
+		return Err(Error::Err(format!(
+			"Imported tables do not match the Conduit database: {}",
+			mismatched.join(", ")
+		)));
+	}
+
+	// The marker goes first: should the removal be interrupted, the next start
+	// sees the import completed and removes the rest of the progress.
+	target.insert(GLOBAL, IMPORTED, &[])?;
+	target.remove_batch(GLOBAL, target.keys_with_prefix(GLOBAL, PROGRESS))
+}
+
+fn progress_key(kind: &str, table: &str) -> Vec<u8> {
+	let mut key = PROGRESS.to_vec();
+	key.extend_from_slice(kind.as_bytes());
+	key.push(0xFF);
+	key.extend_from_slice(table.as_bytes());
+	key
+}
+
+struct RocksSource<'a> {
+	db: &'a Db,
+	tables: Vec<String>,
+}
+
+impl Source for RocksSource<'_> {
+	fn tables(&self) -> Vec<String> { self.tables.clone() }
+
+	fn get(&self, table: &str, key: &[u8]) -> Result<Option<Vec<u8>>> {
+		let Some(cf) = self.db.cf_handle(table) else {
+			return Ok(None);
+		};
+
+		self.db.get_cf(&cf, key).map_err(map_err)
+	}
+
+	fn entries(&self, table: &str, from: Option<&[u8]>) -> Result<Entries<'_>> {
+		let cf = self
+			.db
+			.cf_handle(table)
+			.ok_or_else(|| Error::Err(format!("Column family {table} disappeared from the Conduit database")))?;
+		let mode = from.map_or(IteratorMode::Start, |key| IteratorMode::From(key, Direction::Forward));
+
+		Ok(Box::new(self.db.iterator_cf(&cf, mode).map(|entry| {
+			entry
+				.map(|(key, val)| (key.into_vec(), val.into_vec()))
+				.map_err(map_err)
+		})))
+	}
+}
+
+/// Stands in for the Conduit database once it was imported, when it is not
+/// opened anymore
+struct NoSource;
+
+impl Source for NoSource {
+	fn tables(&self) -> Vec<String> { Vec::new() }
+
+	fn get(&self, _table: &str, _key: &[u8]) -> Result<Option<Vec<u8>>> { Ok(None) }
+
+	fn entries(&self, _table: &str, _from: Option<&[u8]>) -> Result<Entries<'_>> { Ok(Box::new(std::iter::empty())) }
+}
+
+impl Target for Database {
+	fn has_table(&self, table: &str) -> bool { self.map.contains_key(table) }
+
+	fn is_empty(&self) -> bool { self.map.values().all(|map| map.iter().next().is_none()) }
+
+	fn get(&self, table: &str, key: &[u8]) -> Result<Option<Vec<u8>>> { self[table].get(key) }
+
+	fn insert(&self, table: &str, key: &[u8], val: &[u8]) -> Result<()> { self[table].insert(key, val) }
+
+	fn insert_batch(&self, table: &str, batch: Vec<KeyVal>) -> Result<()> {
+		self[table].insert_batch(&mut batch.into_iter())
+	}
+
+	fn remove_batch(&self, table: &str, keys: Vec<Vec<u8>>) -> Result<()> {
+		self[table].remove_batch(&mut keys.into_iter())
+	}
+
+	fn keys_with_prefix(&self, table: &str, prefix: &[u8]) -> Vec<Vec<u8>> {
+		self[table]
+			.scan_prefix(prefix.to_vec())
+			.map(|(key, _)| key)
+			.collect()
+	}
+
+	fn count(&self, table: &str) -> usize { self[table].iter().count() }
+}
+
+#[cfg(test)]
+mod tests {
+	use std::{collections::BTreeMap, sync::Mutex};
+
+	use conduit::{Error, Result};
+
+	use super::{
+		import, progress_key, Entries, KeyVal, Source, Target, BATCH_SIZE, GLOBAL, IMPORTED, PROGRESS, VERSION,
+	};
+
+	/// Tables held in memory, whose batch inserts can be made to fail
+	#[derive(Default)]
+	struct Memory {
+		tables: Mutex<BTreeMap<String, BTreeMap<Vec<u8>, Vec<u8>>>>,
+		batches_until_failure: Mutex<Option<usize>>,
+	}
+
+	impl Memory {
+		fn with_tables(tables: &[&str]) -> Self {
+			let memory = Self::default();
+			for table in tables {
+				memory.table(table, []);
+			}
+
+			memory
+		}
+
+		fn table<I: IntoIterator<Item = KeyVal>>(&self, table: &str, entries: I) {
+			self.tables
+				.lock()
+				.unwrap()
+				.entry(table.to_owned())
+				.or_default()
+				.extend(entries);
+		}
+
+		fn entries_of(&self, table: &str) -> BTreeMap<Vec<u8>, Vec<u8>> { self.tables.lock().unwrap()[table].clone() }
+	}
+
+	impl Source for Memory {
+		fn tables(&self) -> Vec<String> { self.tables.lock().unwrap().keys().cloned().collect() }
+
+		fn get(&self, table: &str, key: &[u8]) -> Result<Option<Vec<u8>>> { Target::get(self, table, key) }
+
+		fn entries(&self, table: &str, from: Option<&[u8]>) -> Result<Entries<'_>> {
+			let entries: Vec<_> = self.tables.lock().unwrap()[table]
+				.iter()
+				.filter(|(key, _)| from.map_or(true, |from| key.as_slice() >= from))
+				.map(|(key, val)| Ok((key.clone(), val.clone())))
+				.collect();
+
+			Ok(Box::new(entries.into_iter()))
+		}
+	}
+
+	impl Target for Memory {
+		fn has_table(&self, table: &str) -> bool { self.tables.lock().unwrap().contains_key(table) }
+
+		fn is_empty(&self) -> bool { self.tables.lock().unwrap().values().all(BTreeMap::is_empty) }
+
+		fn get(&self, table: &str, key: &[u8]) -> Result<Option<Vec<u8>>> {
+			Ok(self.tables.lock().unwrap()[table].get(key).cloned())
+		}
+
+		fn insert(&self, table: &str, key: &[u8], val: &[u8]) -> Result<()> {
+			self.table(table, [(key.to_vec(), val.to_vec())]);
+			Ok(())
+		}
+
+		fn insert_batch(&self, table: &str, batch: Vec<KeyVal>) -> Result<()> {
+			if let Some(remaining) = self.batches_until_failure.lock().unwrap().as_mut() {
+				if *remaining == 0 {
+					return Err(Error::Err("interrupted".to_owned()));
+				}
+
+				*remaining -= 1;
+			}
+
+			self.table(table, batch);
+			Ok(())
+		}
+
+		fn remove_batch(&self, table: &str, keys: Vec<Vec<u8>>) -> Result<()> {
+			let mut tables = self.tables.lock().unwrap();
+			for key in keys {
+				tables.get_mut(table).unwrap().remove(&key);
+			}
+
+			Ok(())
+		}
+
+		fn keys_with_prefix(&self, table: &str, prefix: &[u8]) -> Vec<Vec<u8>> {
+			self.tables.lock().unwrap()[table]
+				.keys()
+				.filter(|key| key.starts_with(prefix))
+				.cloned()
+				.collect()
+		}
+
+		fn count(&self, table: &str) -> usize { self.tables.lock().unwrap()[table].len() }
+	}
+
+	fn conduit_database(version: u64, rooms: usize) -> Memory {
+		let source = Memory::with_tables(&["default", "unknowntree"]);
+		source.table(
+			GLOBAL,
+			[
+				(VERSION.to_vec(), version.to_be_bytes().to_vec()),
+				(b"keypair".to_vec(), b"secret".to_vec()),
+			],
+		);
+		source.table(
+			"roomid_shortroomid",
+			(0..rooms).map(|i| (format!("!{i:08}:example.com").into_bytes(), i.to_be_bytes().to_vec())),
+		);
+
+		source
+	}
+
+	#[test]
+	fn interrupted_import_resumes() {
+		let rooms = 2 * BATCH_SIZE + 5;
+		let source = conduit_database(11, rooms);
+		let target = Memory::with_tables(&[GLOBAL, "roomid_shortroomid"]);
+
+		*target.batches_until_failure.lock().unwrap() = Some(1);
+		import(&source, &target, 13).unwrap_err();
+		assert_eq!(target.count("roomid_shortroomid"), BATCH_SIZE);
+		assert_eq!(
+			Target::get(&target, GLOBAL, &progress_key("last", "roomid_shortroomid")).unwrap(),
+			Some(format!("!{:08}:example.com", BATCH_SIZE - 1).into_bytes())
+		);
+		// No version until the import is complete, so migrations cannot run on it
+		assert_eq!(Target::get(&target, GLOBAL, VERSION).unwrap(), None);
+
+		*target.batches_until_failure.lock().unwrap() = None;
+		import(&source, &target, 13).unwrap();
+		assert_eq!(target.entries_of("roomid_shortroomid"), source.entries_of("roomid_shortroomid"));
+		assert_eq!(
+			Target::get(&target, GLOBAL, VERSION).unwrap(),
+			Some(11_u64.to_be_bytes().to_vec())
+		);
+		assert_eq!(Target::get(&target, GLOBAL, b"keypair").unwrap(), Some(b"secret".to_vec()));
+		assert!(!target.has_table("unknowntree"));
+	}
+
+	#[test]
+	fn completed_import_is_skipped() {
+		let source = conduit_database(13, 3);
+		let target = Memory::with_tables(&[GLOBAL, "roomid_shortroomid"]);
+
+		import(&source, &target, 13).unwrap();
+		assert!(target.keys_with_prefix(GLOBAL, PROGRESS).is_empty());
+		assert!(Target::get(&target, GLOBAL, IMPORTED).unwrap().is_some());
+
+		// Starting again with the import still configured neither fails nor copies
+		target.table("roomid_shortroomid", [(b"!new:example.com".to_vec(), vec![9])]);
+		import(&source, &target, 13).unwrap();
+		assert_eq!(target.count("roomid_shortroomid"), 4);
+	}
+
+	#[test]
+	fn mismatch_keeps_progress() {
+		let source = conduit_database(13, 3);
+		let target = Memory::with_tables(&["roomid_shortroomid"]);
+		target.table(GLOBAL, [(progress_key("done", "unknown"), Vec::new())]);
+		target.table("roomid_shortroomid", [(b"!extra:example.com".to_vec(), vec![9])]);
+
+		import(&source, &target, 13).unwrap_err();
+		assert!(!target.keys_with_prefix(GLOBAL, PROGRESS).is_empty());
+		assert_eq!(Target::get(&target, GLOBAL, IMPORTED).unwrap(), None);
+	}
+
+	#[test]
+	fn newer_schema_is_refused_before_copying() {
+		let source = conduit_database(14, 3);
+		let target = Memory::with_tables(&[GLOBAL, "roomid_shortroomid"]);
+
+		import(&source, &target, 13).unwrap_err();
+		assert!(target.is_empty());
+	}
+
+	#[test]
+	fn non_empty_target_is_refused() {
+		let source = conduit_database(13, 3);
+		let target = Memory::with_tables(&[GLOBAL, "roomid_shortroomid"]);
+		target.table("roomid_shortroomid", [(b"!room:example.com".to_vec(), vec![1])]);
+
+		import(&source, &target, 13).unwrap_err();
+		assert_eq!(target.count("roomid_shortroomid"), 1);
+	}
+
+	#[test]
+	fn unknown_trees_with_data_are_refused() {
+		let source = conduit_database(13, 3);
+		source.table(
+			"servernamemediaid_metadata",
+			[(b"example.com\xFFabcdef".to_vec(), b"{}".to_vec())],
+		);
+		let target = Memory::with_tables(&[GLOBAL, "roomid_shortroomid"]);
+
+		let Error::Err(e) = import(&source, &target, 13).unwrap_err() else {
+			panic!("unexpected error");
+		};
+		assert!(e.contains("servernamemediaid_metadata"));
+		assert!(!e.contains("unknowntree"));
+		assert!(target.is_empty());
+	}
+
+	#[test]
+	fn conduit_entries_are_kept() {
+		let pdu_id = [1_u64.to_be_bytes(), 7_u64.to_be_bytes()].concat();
+		let pdu = br#"{"type":"m.room.message","room_id":"!room:example.com","sender":"@alice:example.com","content":{"body":"hi","msgtype":"m.text"},"origin_server_ts":1,"depth":7,"prev_events":[],"auth_events":[],"hashes":{"sha256":"x"},"signatures":{}}"#;
+		let tables: [(&str, Vec<KeyVal>); 7] = [
+			(
+				GLOBAL,
+				vec![
+					(VERSION.to_vec(), 13_u64.to_be_bytes().to_vec()),
+					(b"c".to_vec(), 42_u64.to_be_bytes().to_vec()),
+					(b"keypair".to_vec(), b"ed25519abc\xFFsecret".to_vec()),
+				],
+			),
+			(
+				"userid_password",
+				vec![(
+					b"@alice:example.com".to_vec(),
+					b"$argon2id$v=19$m=19456,t=2,p=1$c2FsdA$aGFzaA".to_vec(),
+				)],
+			),
+			(
+				"userroomid_joined",
+				vec![(b"@alice:example.com\xFF!room:example.com".to_vec(), Vec::new())],
+			),
+			(
+				"roomid_shortroomid",
+				vec![(b"!room:example.com".to_vec(), 1_u64.to_be_bytes().to_vec())],
+			),
+			("pduid_pdu", vec![(pdu_id.clone(), pdu.to_vec())]),
+			("eventid_pduid", vec![(b"$event:example.com".to_vec(), pdu_id)]),
+			(
+				"roomusertype_roomuserdataid",
+				vec![(
+					b"\xFF@alice:example.com\xFFm.push_rules".to_vec(),
+					[&b"\xFF@alice:example.com\xFF"[..], &40_u64.to_be_bytes()].concat(),
+				)],
+			),
+		];
+
+		let source = Memory::with_tables(&["default"]);
+		let target = Memory::default();
+		for (table, entries) in &tables {
+			source.table(table, entries.clone());
+			target.table(table, []);
+		}
+
+		import(&source, &target, 13).unwrap();
+		for (table, entries) in tables {
+			let mut imported = target.entries_of(table);
+			imported.remove(IMPORTED);
+			assert_eq!(imported, entries.into_iter().collect(), "{table}");
+		}
+	}
+}
@@ -3,7 +3,12 @@ use std::{future::Future, pin::Pin, sync::Arc};
 use conduit::{utils, Result};
 use rocksdb::{BoundColumnFamily, Direction, IteratorMode, ReadOptions, WriteBatchWithTransaction, WriteOptions};
 
-use super::{or_else, result, watchers::Watchers, Engine};
+use super::{
+	activity::{Activity, Counters},
+	or_else, result,
+	watchers::Watchers,
+	Engine,
+};
 
 pub struct Map {
 	db: Arc<Engine>,
@@ -11,6 +16,7 @@ pub struct Map {
 	watchers: Watchers,
 	write_options: WriteOptions,
 	read_options: ReadOptions,
+	counters: Option<Counters>,
 }
 
 type Key = Vec<u8>;
@@ -26,10 +32,28 @@ impl Map {
 			watchers: Watchers::default(),
 			write_options: write_options_default(),
 			read_options: read_options_default(),
+			counters: db
+				.server
+				.config
+				.database_activity_counters
+				.then(Counters::default),
 		}))
 	}
 
+	pub fn name(&self) -> &str { &self.name }
+
+	/// Operations performed on the map since startup or the last reset, if
+	/// `database_activity_counters` is enabled
+	pub fn activity(&self) -> Option<Activity> { self.counters.as_ref().map(Counters::activity) }
+
+	pub fn reset_activity(&self) {
+		if let Some(counters) = &self.counters {
+			counters.reset();
+		}
+	}
+
 	pub fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+		self.count(|c| c.get(1));
 		let read_options = &self.read_options;
 		let res = self.db.db.get_cf_opt(&self.cf(), key, read_options);
 
@@ -41,6 +65,8 @@ impl Map {
 		// comparator**.
 		const SORTED: bool = false;
 
+		self.count(|c| c.get(keys.len()));
+
 		let mut ret: Vec<Option<Vec<u8>>> = Vec::with_capacity(keys.len());
 		let read_options = &self.read_options;
 		for res in self
@@ -59,6 +85,7 @@ impl Map {
 	}
 
 	pub fn insert(&self, key: &[u8], value: &[u8]) -> Result<()> {
+		self.count(|c| c.insert(1));
 		let write_options = &self.write_options;
 		self.db
 			.db
@@ -80,6 +107,8 @@ impl Map {
 			batch.put_cf(&self.cf(), key, value);
 		}
 
+		self.count(|c| c.insert(batch.len()));
+
 		let write_options = &self.write_options;
 		let res = self.db.db.write_opt(batch, write_options);
 
@@ -91,6 +120,7 @@ impl Map {
 	}
 
 	pub fn remove(&self, key: &[u8]) -> Result<()> {
+		self.count(|c| c.remove(1));
 		let write_options = &self.write_options;
 		let res = self.db.db.delete_cf_opt(&self.cf(), key, write_options);
 
@@ -107,6 +137,8 @@ impl Map {
 			batch.delete_cf(&self.cf(), key);
 		}
 
+		self.count(|c| c.remove(batch.len()));
+
 		let write_options = &self.write_options;
 		let res = self.db.db.write_opt(batch, write_options);
 
@@ -124,7 +156,8 @@ impl Map {
 			.db
 			.iterator_cf_opt(&self.cf(), read_options, IteratorMode::Start)
 			.map(Result::unwrap)
-			.map(|(k, v)| (Vec::from(k), Vec::from(v)));
+			.map(|(k, v)| (Vec::from(k), Vec::from(v)))
+			.inspect(|_| self.count(|c| c.get(1)));
 
 		Box::new(it)
 	}
@@ -142,7 +175,8 @@ impl Map {
 			.db
 			.iterator_cf_opt(&self.cf(), read_options, mode)
 			.map(Result::unwrap)
-			.map(|(k, v)| (Vec::from(k), Vec::from(v)));
+			.map(|(k, v)| (Vec::from(k), Vec::from(v)))
+			.inspect(|_| self.count(|c| c.get(1)));
 
 		Box::new(it)
 	}
@@ -156,12 +190,17 @@ impl Map {
 			.iterator_cf_opt(&self.cf(), read_options, mode)
 			.map(Result::unwrap)
 			.map(|(k, v)| (Vec::from(k), Vec::from(v)))
-			.take_while(move |(k, _)| k.starts_with(&prefix));
+			.take_while(move |(k, _)| k.starts_with(&prefix))
+			.inspect(|_| self.count(|c| c.get(1)));
 
 		Box::new(it)
 	}
 
 	pub fn increment(&self, key: &[u8]) -> Result<Vec<u8>> {
+		self.count(|c| {
+			c.get(1);
+			c.insert(1);
+		});
 		let read_options = &self.read_options;
 		let old = self
 			.db
@@ -198,6 +237,11 @@ impl Map {
 			batch.put_cf(&self.cf(), key, new);
 		}
 
+		self.count(|c| {
+			c.get(batch.len());
+			c.insert(batch.len());
+		});
+
 		let write_options = &self.write_options;
 		self.db
 			.db
@@ -216,6 +260,16 @@ impl Map {
 	}
 
 	fn cf(&self) -> Arc<BoundColumnFamily<'_>> { self.db.cf(&self.name) }
+
+	#[inline]
+	fn count<F>(&self, f: F)
+	where
+		F: FnOnce(&Counters),
+	{
+		if let Some(counters) = &self.counters {
+			f(counters);
+		}
+	}
 }
 
 impl<'a> IntoIterator for &'a Map {
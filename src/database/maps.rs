@@ -19,20 +19,34 @@ pub const MAPS: &[&str] = &[
 	"alias_roomid",
 	"alias_userid",
 	"aliasid_alias",
+	"appserviceuserid_ephemeral",
+	"auditid_auditentry",
 	"backupid_algorithm",
+	"backupid_count",
 	"backupid_etag",
 	"backupkeyid_backup",
 	"bannedroomids",
+	"directorytoken_roomid",
 	"disabledroomids",
 	"eventid_outlierpdu",
 	"eventid_pduid",
+	"eventid_redactionid",
 	"eventid_shorteventid",
 	"global",
 	"id_appserviceregistrations",
+	"incompleteroomids",
+	"inviteblockedservernames",
 	"keychangeid_userid",
 	"keyid_key",
 	"lazyloadedids",
+	"logintoken_expiresatuserid",
+	"mediablob_cold",
+	"mediablob_lastaccess",
+	"mediablob_refs",
+	"mediaid_blob",
+	"mediaid_created",
 	"mediaid_file",
+	"mediaid_thumbnail",
 	"mediaid_user",
 	"onetimekeyid_onetimekeys",
 	"pduid_pdu",
@@ -40,10 +54,15 @@ pub const MAPS: &[&str] = &[
 	"publicroomids",
 	"readreceiptid_readreceipt",
 	"referencedevents",
+	"refreshtoken_userdeviceid",
+	"registrationtoken_info",
+	"roomid_directoryroomtype",
+	"roomid_directorytokens",
 	"roomid_invitedcount",
 	"roomid_inviteviaservers",
 	"roomid_joinedcount",
 	"roomid_pduleaves",
+	"roomid_sendratelimit",
 	"roomid_shortroomid",
 	"roomid_shortstatehash",
 	"roomserverids",
@@ -57,11 +76,14 @@ pub const MAPS: &[&str] = &[
 	"roomuseroncejoinedids",
 	"roomusertype_roomuserdataid",
 	"senderkey_pusher",
+	"senderkey_pusherdevice",
+	"senderkey_pusherdisabled",
 	"server_signingkeys",
 	"servercurrentevent_data",
 	"servername_educount",
 	"servernameevent_data",
 	"serverroomids",
+	"sha256_mediablob",
 	"shorteventid_authchain",
 	"shorteventid_eventid",
 	"shorteventid_shortstatehash",
@@ -76,24 +98,40 @@ pub const MAPS: &[&str] = &[
 	"token_userdeviceid",
 	"tokenids",
 	"url_previews",
+	"userclass_ratelimitoverride",
+	"userdeviceid_lazyloadconnection",
 	"userdeviceid_metadata",
+	"userdeviceid_refreshtoken",
 	"userdeviceid_token",
+	"userdeviceid_tokenexpiresat",
+	"userdeviceroomid_syncdeferred",
 	"userdevicesessionid_uiaainfo",
 	"userdevicetxnid_response",
+	"userdirectorytoken_userid",
 	"userfilterid_filter",
+	"userid_dehydrateddevice",
 	"userid_avatarurl",
 	"userid_blurhash",
 	"userid_devicelistversion",
 	"userid_displayname",
+	"userid_guestsince",
+	"userid_lastactiveday",
 	"userid_lastonetimekeyupdate",
 	"userid_masterkeyid",
+	"userid_notificationlogsize",
 	"userid_password",
 	"userid_presenceid",
+	"userid_registrationtoken",
 	"userid_selfsigningkeyid",
+	"userid_userdirectorytokens",
 	"userid_usersigningkeyid",
+	"useridcount_notification",
 	"userroomid_highlightcount",
+	"userroomid_inviteeventid",
+	"userroomid_invitereject",
 	"userroomid_invitestate",
 	"userroomid_joined",
 	"userroomid_leftstate",
 	"userroomid_notificationcount",
+	"userroomidcount_unreadnotification",
 ];
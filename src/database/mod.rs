@@ -1,6 +1,8 @@
+mod activity;
 pub mod cork;
 mod database;
 mod engine;
+mod import;
 mod map;
 pub mod maps;
 mod opts;
@@ -10,8 +12,9 @@ mod watchers;
 extern crate conduit_core as conduit;
 extern crate rust_rocksdb as rocksdb;
 
+pub use activity::Activity;
 pub use cork::Cork;
-pub use database::Database;
+pub use database::{Database, DATABASE_VERSION};
 pub(crate) use engine::Engine;
 pub use map::Map;
 pub(crate) use util::{or_else, result};
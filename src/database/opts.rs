@@ -94,6 +94,7 @@ pub(crate) fn cf_options(cfg: &Config, name: &str, mut opts: Options, cache: &mu
 	// Columns with non-default compaction options
 	match name {
 		"backupid_algorithm"
+		| "backupid_count"
 		| "backupid_etag"
 		| "backupkeyid_backup"
 		| "roomid_shortroomid"
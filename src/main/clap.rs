@@ -11,6 +11,15 @@ pub(crate) struct Args {
 	#[arg(short, long)]
 	/// Optional argument to the path of a conduwuit config TOML file
 	pub(crate) config: Option<PathBuf>,
+
+	#[arg(long)]
+	/// Open the database read-only for safely inspecting a copy of it
+	pub(crate) read_only: bool,
+
+	#[arg(long, value_name = "PATH")]
+	/// Import the database of an upstream Conduit server into a new conduwuit
+	/// database on startup
+	pub(crate) import_conduit: Option<PathBuf>,
 }
 
 /// Parse commandline arguments into structured data
@@ -22,7 +22,13 @@ pub(crate) struct Server {
 
 impl Server {
 	pub(crate) fn build(args: Args, runtime: Option<&runtime::Handle>) -> Result<Arc<Self>, Error> {
-		let config = Config::new(args.config)?;
+		let mut config = Config::new(args.config.clone())?;
+		if args.read_only {
+			config.rocksdb_read_only = true;
+		}
+		if let Some(path) = args.import_conduit.clone() {
+			config.conduit_import_path = Some(path);
+		}
 
 		#[cfg(feature = "sentry_telemetry")]
 		let sentry_guard = crate::sentry::init(&config);
@@ -45,6 +51,7 @@ impl Server {
 		Ok(Arc::new(Self {
 			server: Arc::new(conduit::Server::new(
 				config,
+				args.config,
 				runtime.cloned(),
 				Log {
 					reload: tracing_reload_handle,
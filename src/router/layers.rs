@@ -4,8 +4,7 @@ use axum::{
 	extract::{DefaultBodyLimit, MatchedPath},
 	Router,
 };
-use axum_client_ip::SecureClientIpSource;
-use conduit::Server;
+use conduit::{utils::TrustedProxies, Server};
 use http::{
 	header::{self, HeaderName},
 	HeaderValue, Method, StatusCode,
@@ -27,6 +26,9 @@ const CONDUWUIT_CSP: &str = "sandbox; default-src 'none'; font-src 'none'; scrip
 const CONDUWUIT_PERMISSIONS_POLICY: &str = "interest-cohort=(),browsing-topics=()";
 
 pub(crate) fn build(server: &Arc<Server>) -> io::Result<Router> {
+	let trusted_proxies = TrustedProxies::new(&server.config.trusted_proxies, &server.config.trusted_proxy_header)
+		.map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e.to_string()))?;
+
 	let layers = ServiceBuilder::new();
 
 	#[cfg(feature = "sentry_telemetry")]
@@ -46,7 +48,10 @@ pub(crate) fn build(server: &Arc<Server>) -> io::Result<Router> {
 				.on_response(DefaultOnResponse::new().level(Level::DEBUG)),
 		)
 		.layer(axum::middleware::from_fn_with_state(Arc::clone(server), request::handle))
-		.layer(SecureClientIpSource::ConnectInfo.into_extension())
+		.layer(axum::middleware::from_fn_with_state(
+			Arc::new(trusted_proxies),
+			request::client_ip,
+		))
 		.layer(SetResponseHeaderLayer::if_not_present(
 			HeaderName::from_static("origin-agent-cluster"), // https://developer.mozilla.org/en-US/docs/Web/HTTP/Headers/Origin-Agent-Cluster
 			HeaderValue::from_static("?1"),
@@ -184,7 +189,13 @@ fn tracing_span<T>(request: &http::Request<T>) -> tracing::Span {
 		.get::<MatchedPath>()
 		.map_or_else(|| request.uri().path(), truncated_matched_path);
 
-	tracing::info_span!("router:", %path)
+	// user and appservice are recorded once the request is authenticated
+	tracing::info_span!(
+		"router:",
+		%path,
+		user = tracing::field::Empty,
+		appservice = tracing::field::Empty,
+	)
 }
 
 fn truncated_matched_path(path: &MatchedPath) -> &str {
@@ -1,13 +1,23 @@
-use std::sync::{atomic::Ordering, Arc};
+use std::{
+	net::SocketAddr,
+	sync::{atomic::Ordering, Arc},
+};
 
-use axum::{extract::State, response::IntoResponse};
-use conduit::{debug_error, debug_warn, defer, Result, RumaResponse, Server};
+use axum::{
+	extract::{ConnectInfo, State},
+	response::IntoResponse,
+};
+use conduit::{
+	debug_error, debug_warn, defer,
+	utils::{ClientIp, TrustedProxies},
+	Result, RumaResponse, Server,
+};
 use http::{Method, StatusCode, Uri};
 use ruma::api::client::{
 	error::{Error as RumaError, ErrorBody, ErrorKind},
 	uiaa::UiaaResponse,
 };
-use tracing::{debug, error, trace};
+use tracing::{debug, error, trace, warn};
 
 #[tracing::instrument(skip_all)]
 pub(crate) async fn spawn(
@@ -61,6 +71,28 @@ pub(crate) async fn handle(
 	handle_result(&method, &uri, result)
 }
 
+/// Resolves the address of the client behind the trusted proxies for the
+/// handlers
+pub(crate) async fn client_ip(
+	State(trusted_proxies): State<Arc<TrustedProxies>>, mut req: http::Request<axum::body::Body>,
+	next: axum::middleware::Next,
+) -> axum::response::Response {
+	if trusted_proxies.first_ignored_forwarding(req.headers()) {
+		warn!(
+			"Requests have the trusted_proxy_header header but trusted_proxies is empty, so all clients behind the \
+			 reverse proxy share its address, e.g. for rate limiting. Configure trusted_proxies if this server is \
+			 behind a reverse proxy."
+		);
+	}
+
+	if let Some(ConnectInfo(peer)) = req.extensions().get::<ConnectInfo<SocketAddr>>() {
+		let client = trusted_proxies.resolve(peer.ip(), req.headers());
+		req.extensions_mut().insert(ClientIp(client));
+	}
+
+	next.run(req).await
+}
+
 fn handle_result(
 	method: &Method, uri: &Uri, result: axum::response::Response,
 ) -> Result<axum::response::Response, StatusCode> {
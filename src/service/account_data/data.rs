@@ -1,4 +1,4 @@
-use std::{collections::HashMap, sync::Arc};
+use std::{mem::size_of, sync::Arc};
 
 use conduit::{utils, warn, Error, Result};
 use database::{Database, Map};
@@ -6,7 +6,7 @@ use ruma::{
 	api::client::error::ErrorKind,
 	events::{AnyEphemeralRoomEvent, RoomAccountDataEventType},
 	serde::Raw,
-	RoomId, UserId,
+	OwnedRoomId, RoomId, UserId,
 };
 
 use crate::services;
@@ -30,14 +30,7 @@ impl Data {
 		&self, room_id: Option<&RoomId>, user_id: &UserId, event_type: &RoomAccountDataEventType,
 		data: &serde_json::Value,
 	) -> Result<()> {
-		let mut prefix = room_id
-			.map(ToString::to_string)
-			.unwrap_or_default()
-			.as_bytes()
-			.to_vec();
-		prefix.push(0xFF);
-		prefix.extend_from_slice(user_id.as_bytes());
-		prefix.push(0xFF);
+		let prefix = room_user_prefix(room_id, user_id);
 
 		let mut roomuserdataid = prefix.clone();
 		roomuserdataid.extend_from_slice(&services().globals.next_count()?.to_be_bytes());
@@ -72,18 +65,22 @@ impl Data {
 		Ok(())
 	}
 
+	/// Removes all account data of the user in the room
+	pub(super) fn remove_room(&self, room_id: &RoomId, user_id: &UserId) -> Result<()> {
+		let prefix = room_user_prefix(Some(room_id), user_id);
+		for (key, roomuserdataid) in self.roomusertype_roomuserdataid.scan_prefix(prefix) {
+			self.roomuserdataid_accountdata.remove(&roomuserdataid)?;
+			self.roomusertype_roomuserdataid.remove(&key)?;
+		}
+
+		Ok(())
+	}
+
 	/// Searches the account data for a specific kind.
 	pub(super) fn get(
 		&self, room_id: Option<&RoomId>, user_id: &UserId, kind: &RoomAccountDataEventType,
 	) -> Result<Option<Box<serde_json::value::RawValue>>> {
-		let mut key = room_id
-			.map(ToString::to_string)
-			.unwrap_or_default()
-			.as_bytes()
-			.to_vec();
-		key.push(0xFF);
-		key.extend_from_slice(user_id.as_bytes());
-		key.push(0xFF);
+		let mut key = room_user_prefix(room_id, user_id);
 		key.extend_from_slice(kind.to_string().as_bytes());
 
 		self.roomusertype_roomuserdataid
@@ -98,50 +95,146 @@ impl Data {
 			.transpose()
 	}
 
-	/// Returns all changes to the account data that happened after `since`.
-	pub(super) fn changes_since(
-		&self, room_id: Option<&RoomId>, user_id: &UserId, since: u64,
-	) -> Result<HashMap<RoomAccountDataEventType, Raw<AnyEphemeralRoomEvent>>> {
-		let mut userdata = HashMap::new();
-
-		let mut prefix = room_id
-			.map(ToString::to_string)
-			.unwrap_or_default()
-			.as_bytes()
-			.to_vec();
-		prefix.push(0xFF);
-		prefix.extend_from_slice(user_id.as_bytes());
-		prefix.push(0xFF);
+	/// Every current account data entry of the user, with the room of room
+	/// account data. This scans the account data of all users.
+	pub(super) fn all(
+		&self, user_id: &UserId,
+	) -> Result<Vec<(Option<OwnedRoomId>, RoomAccountDataEventType, Vec<u8>)>> {
+		let mut entries = Vec::new();
+		for (key, roomuserdataid) in self.roomusertype_roomuserdataid.iter() {
+			let mut parts = key.splitn(3, |&b| b == 0xFF);
+			let (Some(room_id), Some(user), Some(kind)) = (parts.next(), parts.next(), parts.next()) else {
+				return Err(Error::bad_database("Key in roomusertype_roomuserdataid is invalid."));
+			};
+
+			if user != user_id.as_bytes() {
+				continue;
+			}
+
+			let room_id = if room_id.is_empty() {
+				None
+			} else {
+				Some(
+					utils::string_from_bytes(room_id)
+						.ok()
+						.and_then(|room_id| RoomId::parse(room_id).ok())
+						.ok_or_else(|| Error::bad_database("Room ID in roomusertype_roomuserdataid is invalid."))?,
+				)
+			};
+
+			let kind = RoomAccountDataEventType::from(
+				utils::string_from_bytes(kind)
+					.map_err(|_| Error::bad_database("Event type in roomusertype_roomuserdataid is invalid."))?,
+			);
+
+			if let Some(data) = self.roomuserdataid_accountdata.get(&roomuserdataid)? {
+				entries.push((room_id, kind, data));
+			}
+		}
+
+		Ok(entries)
+	}
+
+	/// Returns the account data entries changed after `since` with their
+	/// change count, oldest first. Only the event types `include` accepts are
+	/// returned; the data of other types is not parsed.
+	pub(super) fn changes_since<F>(
+		&self, room_id: Option<&RoomId>, user_id: &UserId, since: u64, include: F,
+	) -> Result<Vec<(u64, RoomAccountDataEventType, Raw<AnyEphemeralRoomEvent>)>>
+	where
+		F: Fn(&RoomAccountDataEventType) -> bool,
+	{
+		let prefix = room_user_prefix(room_id, user_id);
 
 		// Skip the data that's exactly at since, because we sent that last time
 		let mut first_possible = prefix.clone();
 		first_possible.extend_from_slice(&(since.saturating_add(1)).to_be_bytes());
 
-		for r in self
-			.roomuserdataid_accountdata
+		let prefix_len = prefix.len();
+		self.roomuserdataid_accountdata
 			.iter_from(&first_possible, false)
 			.take_while(move |(k, _)| k.starts_with(&prefix))
 			.map(|(k, v)| {
-				Ok::<_, Error>((
-					RoomAccountDataEventType::from(
-						utils::string_from_bytes(
-							k.rsplit(|&b| b == 0xFF)
-								.next()
-								.ok_or_else(|| Error::bad_database("RoomUserData ID in db is invalid."))?,
-						)
-						.map_err(|e| {
-							warn!("RoomUserData ID in database is invalid: {}", e);
-							Error::bad_database("RoomUserData ID in db is invalid.")
-						})?,
-					),
-					serde_json::from_slice::<Raw<AnyEphemeralRoomEvent>>(&v)
-						.map_err(|_| Error::bad_database("Database contains invalid account data."))?,
-				))
-			}) {
-			let (kind, data) = r?;
-			userdata.insert(kind, data);
-		}
+				let count = k
+					.get(prefix_len..prefix_len.saturating_add(size_of::<u64>()))
+					.map(utils::u64_from_bytes)
+					.transpose()
+					.ok()
+					.flatten()
+					.ok_or_else(|| Error::bad_database("RoomUserData ID in db is invalid."))?;
+
+				let kind = RoomAccountDataEventType::from(
+					utils::string_from_bytes(
+						k.rsplit(|&b| b == 0xFF)
+							.next()
+							.ok_or_else(|| Error::bad_database("RoomUserData ID in db is invalid."))?,
+					)
+					.map_err(|e| {
+						warn!("RoomUserData ID in database is invalid: {}", e);
+						Error::bad_database("RoomUserData ID in db is invalid.")
+					})?,
+				);
+
+				if !include(&kind) {
+					return Ok(None);
+				}
+
+				let data = serde_json::from_slice::<Raw<AnyEphemeralRoomEvent>>(&v)
+					.map_err(|_| Error::bad_database("Database contains invalid account data."))?;
+
+				Ok(Some((count, kind, data)))
+			})
+			.filter_map(Result::transpose)
+			.collect()
+	}
+}
+
+/// Prefix of the entries of the user in the room, or of the global account
+/// data without one, in both maps
+fn room_user_prefix(room_id: Option<&RoomId>, user_id: &UserId) -> Vec<u8> {
+	let mut prefix = room_id.map(RoomId::as_bytes).unwrap_or_default().to_vec();
+	prefix.push(0xFF);
+	prefix.extend_from_slice(user_id.as_bytes());
+	prefix.push(0xFF);
+	prefix
+}
+
+#[cfg(test)]
+mod tests {
+	use ruma::{room_id, user_id, RoomId, UserId};
+
+	use super::room_user_prefix;
+
+	fn type_key(room_id: Option<&RoomId>, user_id: &UserId, kind: &str) -> Vec<u8> {
+		let mut key = room_user_prefix(room_id, user_id);
+		key.extend_from_slice(kind.as_bytes());
+		key
+	}
+
+	fn data_key(room_id: Option<&RoomId>, user_id: &UserId, count: u64, kind: &str) -> Vec<u8> {
+		let mut key = room_user_prefix(room_id, user_id);
+		key.extend_from_slice(&count.to_be_bytes());
+		key.push(0xFF);
+		key.extend_from_slice(kind.as_bytes());
+		key
+	}
 
-		Ok(userdata)
+	#[test]
+	fn forgetting_a_room_covers_only_its_data() {
+		let (alice, bob) = (user_id!("@alice:example.org"), user_id!("@bob:example.org"));
+		let (room, other) = (room_id!("!room:example.org"), room_id!("!room:example.org2"));
+		let forgotten = room_user_prefix(Some(room), alice);
+
+		// tags and other room account data of alice in the room
+		assert!(type_key(Some(room), alice, "m.tag").starts_with(&forgotten));
+		assert!(type_key(Some(room), alice, "m.fully_read").starts_with(&forgotten));
+		assert!(data_key(Some(room), alice, 7, "m.tag").starts_with(&forgotten));
+
+		// but not her global data, other rooms or other users
+		assert!(!type_key(None, alice, "m.direct").starts_with(&forgotten));
+		assert!(!data_key(None, alice, 7, "m.direct").starts_with(&forgotten));
+		assert!(!type_key(Some(other), alice, "m.tag").starts_with(&forgotten));
+		assert!(!type_key(Some(room), bob, "m.tag").starts_with(&forgotten));
+		assert!(!data_key(Some(room), bob, 7, "m.tag").starts_with(&forgotten));
 	}
 }
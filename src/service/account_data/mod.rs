@@ -1,35 +1,299 @@
 mod data;
 
-use std::{collections::HashMap, sync::Arc};
+use std::{
+	collections::{HashMap, HashSet},
+	mem,
+	sync::{Arc, RwLock},
+};
 
-use conduit::{Result, Server};
+use conduit::{utils::LockMap, warn, Error, Result, Server};
 use data::Data;
 use database::Database;
 use ruma::{
-	events::{AnyEphemeralRoomEvent, RoomAccountDataEventType},
+	api::client::{
+		error::ErrorKind,
+		filter::{Filter, RoomEventFilter},
+	},
+	events::{
+		direct::DirectEvent, fully_read::FullyReadEvent, ignored_user_list::IgnoredUserListEvent,
+		push_rules::PushRulesEvent, tag::TagEvent, AnyEphemeralRoomEvent, GlobalAccountDataEventType,
+		RoomAccountDataEventType,
+	},
 	serde::Raw,
-	RoomId, UserId,
+	OwnedRoomId, OwnedUserId, RoomId, UserId,
 };
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
+
+use crate::services;
+
+/// Global account data type with which a user restricts who may invite them
+pub const INVITE_PERMISSIONS: &str = "im.conduwuit.invite_permissions";
+
+#[derive(Debug, Default, Deserialize)]
+pub struct InvitePermissions {
+	/// Only users sharing a room with the user may invite them
+	#[serde(default)]
+	pub require_shared_room: bool,
+}
+
+#[derive(Deserialize)]
+struct InvitePermissionsEvent {
+	content: InvitePermissions,
+}
+
+/// The account data event types a client asked for in a sync filter
+#[derive(Default)]
+pub struct TypeFilter<'a> {
+	/// Types to include, all if `None`
+	pub types: Option<&'a [String]>,
+	/// Types to leave out, taking precedence over `types`
+	pub not_types: &'a [String],
+	/// Most events to return, the most recently changed first
+	pub limit: Option<usize>,
+}
+
+impl TypeFilter<'_> {
+	/// Whether the type is requested. `*` in the filter matches any sequence
+	/// of characters.
+	#[must_use]
+	pub fn matches(&self, kind: &str) -> bool {
+		!self
+			.not_types
+			.iter()
+			.any(|pattern| type_matches(pattern, kind))
+			&& self
+				.types
+				.map_or(true, |types| types.iter().any(|pattern| type_matches(pattern, kind)))
+	}
+}
+
+impl<'a> From<&'a Filter> for TypeFilter<'a> {
+	fn from(filter: &'a Filter) -> Self {
+		Self {
+			types: filter.types.as_deref(),
+			not_types: &filter.not_types,
+			limit: filter
+				.limit
+				.map(|limit| usize::try_from(limit).unwrap_or(usize::MAX)),
+		}
+	}
+}
+
+impl<'a> From<&'a RoomEventFilter> for TypeFilter<'a> {
+	fn from(filter: &'a RoomEventFilter) -> Self {
+		Self {
+			types: filter.types.as_deref(),
+			not_types: &filter.not_types,
+			limit: filter
+				.limit
+				.map(|limit| usize::try_from(limit).unwrap_or(usize::MAX)),
+		}
+	}
+}
 
 pub struct Service {
 	db: Data,
+	pub ignored_users_cache: RwLock<HashMap<OwnedUserId, Arc<HashSet<OwnedUserId>>>>,
+
+	/// Held per user while their `m.direct` is read, modified and written back.
+	/// `update_membership` adds direct chats synchronously, so this can't be an
+	/// async `MutexMap`.
+	direct_mutex: LockMap<OwnedUserId>,
 }
 
 impl Service {
 	pub fn build(_server: &Arc<Server>, db: &Arc<Database>) -> Result<Self> {
 		Ok(Self {
 			db: Data::new(db),
+			ignored_users_cache: RwLock::new(HashMap::new()),
+			direct_mutex: LockMap::default(),
 		})
 	}
 
 	/// Places one event in the account data of the user and removes the
-	/// previous entry.
+	/// previous entry. Events larger than `max_account_data_size`, and events
+	/// of the types we read ourselves which are malformed, are rejected.
 	#[allow(clippy::needless_pass_by_value)]
 	pub fn update(
 		&self, room_id: Option<&RoomId>, user_id: &UserId, event_type: RoomAccountDataEventType,
 		data: &serde_json::Value,
 	) -> Result<()> {
-		self.db.update(room_id, user_id, &event_type, data)
+		if is_too_large(data) {
+			return Err(Error::BadRequest(ErrorKind::TooLarge, "Account data is too large."));
+		}
+
+		if let Err(e) = check_content(room_id.is_some(), &event_type, data) {
+			warn!("Rejecting invalid {event_type} account data of {user_id}: {e}");
+			return Err(Error::BadRequest(
+				ErrorKind::BadJson,
+				"Account data is not valid for its event type.",
+			));
+		}
+
+		self.db.update(room_id, user_id, &event_type, data)?;
+
+		if room_id.is_none() && event_type.to_string() == GlobalAccountDataEventType::IgnoredUserList.to_string() {
+			self.ignored_users_cache
+				.write()
+				.expect("locked")
+				.remove(user_id);
+		}
+
+		Ok(())
+	}
+
+	/// Removes the room account data of the user in the room, such as its
+	/// tags
+	pub fn remove_room(&self, room_id: &RoomId, user_id: &UserId) -> Result<()> {
+		self.db.remove_room(room_id, user_id)
+	}
+
+	/// The invite restrictions the user set in
+	/// `im.conduwuit.invite_permissions`
+	pub fn invite_permissions(&self, user_id: &UserId) -> Result<InvitePermissions> {
+		Ok(self
+			.get(None, user_id, INVITE_PERMISSIONS.into())?
+			.and_then(|event| {
+				serde_json::from_str::<InvitePermissionsEvent>(event.get())
+					.map_err(|e| warn!("Invalid invite permissions in db for user ID {user_id}: {e}"))
+					.ok()
+			})
+			.map(|event| event.content)
+			.unwrap_or_default())
+	}
+
+	/// Checks whether `sender` is in the m.ignored_user_list of `user_id`.
+	pub fn user_is_ignored(&self, sender: &UserId, user_id: &UserId) -> bool {
+		self.ignored_users(user_id).contains(sender)
+	}
+
+	/// Returns the users ignored by `user_id`, caching the parsed
+	/// m.ignored_user_list until it is next updated.
+	pub fn ignored_users(&self, user_id: &UserId) -> Arc<HashSet<OwnedUserId>> {
+		if let Some(ignored) = self
+			.ignored_users_cache
+			.read()
+			.expect("locked")
+			.get(user_id)
+		{
+			return ignored.clone();
+		}
+
+		// the write lock is held while loading so a concurrent update cannot be
+		// overwritten by a stale list
+		let mut cache = self.ignored_users_cache.write().expect("locked");
+		let ignored: Arc<HashSet<OwnedUserId>> = Arc::new(
+			self.get(
+				None,
+				user_id,
+				GlobalAccountDataEventType::IgnoredUserList
+					.to_string()
+					.into(),
+			)
+			.ok()
+			.flatten()
+			.and_then(|event| {
+				serde_json::from_str::<IgnoredUserListEvent>(event.get())
+					.map_err(|e| warn!("Invalid ignored user list in db for user ID {user_id}: {e}"))
+					.ok()
+			})
+			.map(|event| event.content.ignored_users.into_keys().collect())
+			.unwrap_or_default(),
+		);
+
+		cache.insert(user_id.to_owned(), ignored.clone());
+		ignored
+	}
+
+	/// Marks `room_id` as a direct chat with `other_user` in the `m.direct` of
+	/// `user_id`. Returns false when it already was.
+	pub fn add_direct(&self, user_id: &UserId, other_user: &UserId, room_id: &RoomId) -> Result<bool> {
+		self.update_direct(user_id, |direct| {
+			let rooms = direct
+				.entry(other_user.as_str())
+				.or_insert_with(|| json!([]));
+			if !rooms.is_array() {
+				*rooms = json!([]);
+			}
+
+			let rooms = rooms.as_array_mut().expect("rooms is an array");
+			if rooms
+				.iter()
+				.any(|room| room.as_str() == Some(room_id.as_str()))
+			{
+				return false;
+			}
+
+			rooms.push(room_id.as_str().into());
+			true
+		})
+	}
+
+	/// Removes `room_id` from the direct chats in the `m.direct` of `user_id`.
+	/// Returns false when it was not listed.
+	pub fn remove_direct(&self, user_id: &UserId, room_id: &RoomId) -> Result<bool> {
+		self.update_direct(user_id, |direct| {
+			let mut removed = false;
+			direct.retain(|_, rooms| {
+				if let Some(rooms) = rooms.as_array_mut() {
+					let len = rooms.len();
+					rooms.retain(|room| room.as_str() != Some(room_id.as_str()));
+					removed |= rooms.len() != len;
+					!rooms.is_empty()
+				} else {
+					true
+				}
+			});
+
+			removed
+		})
+	}
+
+	/// Read-modify-writes the content of the user's `m.direct`, starting from
+	/// an empty one if it is absent or invalid. `f` returns whether it changed
+	/// anything to write.
+	fn update_direct<F>(&self, user_id: &UserId, f: F) -> Result<bool>
+	where
+		F: FnOnce(&mut Map<String, Value>) -> bool,
+	{
+		self.direct_mutex.with_lock(user_id, || {
+			let event_type: RoomAccountDataEventType = GlobalAccountDataEventType::Direct.to_string().into();
+			let mut content = match self.get(None, user_id, event_type.clone())? {
+				None => Map::new(),
+				Some(event) => serde_json::from_str::<Value>(event.get())
+					.ok()
+					.and_then(|mut event| event.get_mut("content")?.as_object_mut().map(mem::take))
+					.unwrap_or_else(|| {
+						warn!("Invalid m.direct account data of {user_id}, replacing it");
+						Map::new()
+					}),
+			};
+
+			if !f(&mut content) {
+				return Ok(false);
+			}
+
+			self.update(
+				None,
+				user_id,
+				event_type,
+				&json!({
+					"type": GlobalAccountDataEventType::Direct.to_string(),
+					"content": content,
+				}),
+			)?;
+
+			Ok(true)
+		})
+	}
+
+	/// Replaces the user's `m.direct` with `data`, e.g. as sent by a client,
+	/// without interleaving with an update of `add_direct`
+	pub fn set_direct(&self, user_id: &UserId, data: &Value) -> Result<()> {
+		self.direct_mutex.with_lock(user_id, || {
+			self.update(None, user_id, GlobalAccountDataEventType::Direct.to_string().into(), data)
+		})
 	}
 
 	/// Searches the account data for a specific kind.
@@ -40,11 +304,226 @@ impl Service {
 		self.db.get(room_id, user_id, &event_type)
 	}
 
-	/// Returns all changes to the account data that happened after `since`.
+	/// Every current account data entry of the user, with the room of room
+	/// account data. This scans the account data of all users.
+	pub fn all(&self, user_id: &UserId) -> Result<Vec<(Option<OwnedRoomId>, RoomAccountDataEventType, Vec<u8>)>> {
+		self.db.all(user_id)
+	}
+
+	/// Returns all changes to the account data that happened after `since`,
+	/// only the most recent one of each event type.
 	#[tracing::instrument(skip_all, name = "since")]
 	pub fn changes_since(
 		&self, room_id: Option<&RoomId>, user_id: &UserId, since: u64,
 	) -> Result<HashMap<RoomAccountDataEventType, Raw<AnyEphemeralRoomEvent>>> {
-		self.db.changes_since(room_id, user_id, since)
+		self.changes_since_filtered(room_id, user_id, since, &TypeFilter::default())
+	}
+
+	/// Like `changes_since`, for the types the filter matches. Entries of
+	/// other types are skipped without being parsed.
+	#[tracing::instrument(skip_all, name = "since_filtered")]
+	pub fn changes_since_filtered(
+		&self, room_id: Option<&RoomId>, user_id: &UserId, since: u64, filter: &TypeFilter<'_>,
+	) -> Result<HashMap<RoomAccountDataEventType, Raw<AnyEphemeralRoomEvent>>> {
+		let changes = self
+			.db
+			.changes_since(room_id, user_id, since, |kind| filter.matches(&kind.to_string()))?;
+
+		Ok(latest_changes(changes, since, filter.limit))
+	}
+}
+
+/// Whether the serialized event exceeds `max_account_data_size`
+#[must_use]
+pub fn is_too_large(data: &Value) -> bool {
+	serde_json::to_vec(data)
+		.expect("to_vec always works on json values")
+		.len() > services().globals.config.max_account_data_size
+}
+
+/// Deserializes events of the well-known types we read ourselves through
+/// their ruma types, so they can't break sync or push rule evaluation later.
+/// Other types are accepted as they are.
+pub fn check_content(in_room: bool, event_type: &RoomAccountDataEventType, data: &Value) -> serde_json::Result<()> {
+	match (in_room, event_type.to_string().as_str()) {
+		(false, "m.push_rules") => PushRulesEvent::deserialize(data).map(drop),
+		(false, "m.ignored_user_list") => IgnoredUserListEvent::deserialize(data).map(drop),
+		(false, "m.direct") => DirectEvent::deserialize(data).map(drop),
+		(false, INVITE_PERMISSIONS) => InvitePermissionsEvent::deserialize(data).map(drop),
+		(true, "m.fully_read") => FullyReadEvent::deserialize(data).map(drop),
+		(true, "m.tag") => TagEvent::deserialize(data).map(drop),
+		_ => Ok(()),
+	}
+}
+
+/// Keeps the entries whose change count exceeds `since`, the one with the
+/// highest count for each event type, and at most `limit` of the most recently
+/// changed types.
+fn latest_changes<I, T>(changes: I, since: u64, limit: Option<usize>) -> HashMap<RoomAccountDataEventType, T>
+where
+	I: IntoIterator<Item = (u64, RoomAccountDataEventType, T)>,
+{
+	let mut latest = HashMap::<RoomAccountDataEventType, (u64, T)>::new();
+	for (count, kind, data) in changes {
+		if count <= since || latest.get(&kind).is_some_and(|(prev, _)| *prev > count) {
+			continue;
+		}
+
+		latest.insert(kind, (count, data));
+	}
+
+	let mut latest: Vec<_> = latest.into_iter().collect();
+	if let Some(limit) = limit {
+		latest.sort_unstable_by(|(_, (a, _)), (_, (b, _))| b.cmp(a));
+		latest.truncate(limit);
+	}
+
+	latest
+		.into_iter()
+		.map(|(kind, (_, data))| (kind, data))
+		.collect()
+}
+
+/// Matches an event type against a filter pattern where `*` stands for any
+/// sequence of characters
+fn type_matches(pattern: &str, kind: &str) -> bool {
+	let Some((first, rest)) = pattern.split_once('*') else {
+		return pattern == kind;
+	};
+
+	let Some(mut kind) = kind.strip_prefix(first) else {
+		return false;
+	};
+
+	let mut parts: Vec<&str> = rest.split('*').collect();
+	let last = parts.pop().unwrap_or_default();
+	for part in parts {
+		let Some(rest) = kind
+			.find(part)
+			.and_then(|start| kind.get(start.saturating_add(part.len())..))
+		else {
+			return false;
+		};
+
+		kind = rest;
+	}
+
+	kind.len() >= last.len() && kind.ends_with(last)
+}
+
+#[cfg(test)]
+mod tests {
+	use ruma::events::RoomAccountDataEventType;
+	use serde_json::json;
+
+	use super::{check_content, latest_changes, type_matches, TypeFilter, INVITE_PERMISSIONS};
+
+	#[test]
+	fn known_types_are_checked() {
+		let push_rules = RoomAccountDataEventType::from("m.push_rules");
+		let malformed = json!({
+			"type": "m.push_rules",
+			"content": { "global": { "override": [{ "rule_id": 1 }] } },
+		});
+		check_content(false, &push_rules, &malformed).unwrap_err();
+
+		let valid = json!({
+			"type": "m.push_rules",
+			"content": { "global": {} },
+		});
+		check_content(false, &push_rules, &valid).unwrap();
+
+		let custom = RoomAccountDataEventType::from("org.example.custom");
+		check_content(false, &custom, &json!({ "type": "org.example.custom", "content": 1 })).unwrap();
+
+		let tags = json!({ "type": "m.tag", "content": { "tags": [] } });
+		check_content(true, &RoomAccountDataEventType::Tag, &tags).unwrap_err();
+	}
+
+	#[test]
+	fn invite_permissions_are_checked() {
+		let kind = RoomAccountDataEventType::from(INVITE_PERMISSIONS);
+		let valid = json!({ "type": INVITE_PERMISSIONS, "content": { "require_shared_room": true } });
+		check_content(false, &kind, &valid).unwrap();
+
+		let malformed = json!({ "type": INVITE_PERMISSIONS, "content": { "require_shared_room": "yes" } });
+		check_content(false, &kind, &malformed).unwrap_err();
+	}
+
+	#[test]
+	fn only_changes_after_since() {
+		let changes = [
+			(3, RoomAccountDataEventType::Tag, "old tags"),
+			(5, RoomAccountDataEventType::FullyRead, "at since"),
+			(8, RoomAccountDataEventType::from("org.example.custom"), "custom"),
+		];
+
+		let latest = latest_changes(changes, 5, None);
+		assert_eq!(latest.len(), 1);
+		assert_eq!(latest[&RoomAccountDataEventType::from("org.example.custom")], "custom");
+	}
+
+	#[test]
+	fn tag_change_in_room_left_during_window() {
+		// tagged before the last sync, then re-tagged and left afterwards; the
+		// left room carries only the newest tags
+		let changes = [
+			(2, RoomAccountDataEventType::Tag, "favourite"),
+			(7, RoomAccountDataEventType::Tag, "low priority"),
+			(9, RoomAccountDataEventType::Tag, "archived"),
+		];
+
+		let latest = latest_changes(changes, 4, None);
+		assert_eq!(latest.len(), 1, "each event type is sent once");
+		assert_eq!(latest[&RoomAccountDataEventType::Tag], "archived");
+	}
+
+	#[test]
+	fn stale_duplicate_is_dropped() {
+		let changes = [
+			(12, RoomAccountDataEventType::Tag, "new"),
+			(10, RoomAccountDataEventType::Tag, "stale"),
+		];
+
+		assert_eq!(latest_changes(changes, 0, None)[&RoomAccountDataEventType::Tag], "new");
+	}
+
+	#[test]
+	fn filter_types() {
+		let not_types = ["m.push_rules".to_owned()];
+		let filter = TypeFilter {
+			not_types: &not_types,
+			..TypeFilter::default()
+		};
+		assert!(!filter.matches("m.push_rules"));
+		assert!(filter.matches("m.direct"));
+
+		let types = ["m.*".to_owned()];
+		let not_types = ["*.secret_storage.*".to_owned()];
+		let filter = TypeFilter {
+			types: Some(&types),
+			not_types: &not_types,
+			limit: None,
+		};
+		assert!(filter.matches("m.direct"));
+		assert!(!filter.matches("m.secret_storage.default_key"));
+		assert!(!filter.matches("org.example.custom"));
+
+		assert!(type_matches("*", ""));
+		assert!(type_matches("m.*.key", "m.cross_signing.key"));
+		assert!(!type_matches("m.*.key", "m.key"));
+	}
+
+	#[test]
+	fn limit_keeps_most_recent() {
+		let changes = [
+			(6, RoomAccountDataEventType::from("m.direct"), "direct"),
+			(7, RoomAccountDataEventType::from("m.push_rules"), "push rules"),
+			(9, RoomAccountDataEventType::from("org.example.custom"), "custom"),
+		];
+
+		let latest = latest_changes(changes, 5, Some(2));
+		assert_eq!(latest.len(), 2);
+		assert!(!latest.contains_key(&RoomAccountDataEventType::from("m.direct")));
 	}
 }
@@ -0,0 +1,110 @@
+use std::{fmt::Display, mem::size_of, sync::Arc};
+
+use conduit::{utils, warn, Error, Result};
+use database::{Database, Map};
+use serde::{Deserialize, Serialize};
+
+use crate::services;
+
+const MILLIS_PER_DAY: u64 = 24 * 60 * 60 * 1000;
+
+/// Append-only log of sensitive actions, kept in the database so it survives
+/// restarts and cache clears. Entries are keyed by their timestamp and removed
+/// once older than `audit_log_max_age_days`.
+pub struct AuditLog {
+	auditid_auditentry: Arc<Map>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct AuditEntry {
+	/// Milliseconds since the epoch
+	pub ts: u64,
+
+	/// User ID of whoever performed the action, or `console` for the admin
+	/// console
+	pub actor: String,
+
+	pub action: String,
+	pub target: String,
+
+	/// `ok`, or the error the action failed with
+	pub outcome: String,
+}
+
+impl AuditLog {
+	pub(super) fn new(db: &Arc<Database>) -> Self {
+		Self {
+			auditid_auditentry: db["auditid_auditentry"].clone(),
+		}
+	}
+
+	/// Records the outcome of an action. Failing to write the entry is logged
+	/// and does not fail the action.
+	pub fn record<T, E: Display>(&self, actor: &str, action: &str, target: &str, result: &Result<T, E>) {
+		let outcome = match result {
+			Ok(_) => "ok".to_owned(),
+			Err(e) => format!("error: {e}"),
+		};
+
+		let entry = AuditEntry {
+			ts: utils::millis_since_unix_epoch(),
+			actor: actor.to_owned(),
+			action: action.to_owned(),
+			target: target.to_owned(),
+			outcome,
+		};
+
+		if let Err(e) = self.append(&entry) {
+			warn!(?entry, "Failed to write audit log entry: {e}");
+		}
+	}
+
+	/// Most recent entries first, only those of `actor` if given
+	pub fn entries(&self, actor: Option<&str>, limit: usize) -> Result<Vec<AuditEntry>> {
+		self.auditid_auditentry
+			.iter_from(&[0xFF; 2 * size_of::<u64>()], true)
+			.map(|(_, entry)| {
+				serde_json::from_slice::<AuditEntry>(&entry)
+					.map_err(|_| Error::bad_database("Invalid entry in auditid_auditentry."))
+			})
+			.filter(|entry| !matches!(entry, Ok(entry) if actor.is_some_and(|actor| actor != entry.actor)))
+			.take(limit)
+			.collect()
+	}
+
+	fn append(&self, entry: &AuditEntry) -> Result<()> {
+		let mut key = entry.ts.to_be_bytes().to_vec();
+		key.extend_from_slice(&services().globals.next_count()?.to_be_bytes());
+
+		self.auditid_auditentry
+			.insert(&key, &serde_json::to_vec(entry).expect("audit entry serializes"))?;
+
+		self.expire(entry.ts)
+	}
+
+	/// Removes the entries which are older than the configured maximum age
+	fn expire(&self, now: u64) -> Result<()> {
+		let max_age = services()
+			.globals
+			.config
+			.audit_log_max_age_days
+			.saturating_mul(MILLIS_PER_DAY);
+		let cutoff = now.saturating_sub(max_age).to_be_bytes();
+
+		let expired: Vec<_> = self
+			.auditid_auditentry
+			.iter()
+			.map(|(key, _)| key)
+			.take_while(|key| {
+				key.get(..size_of::<u64>())
+					.is_some_and(|ts| ts < &cutoff[..])
+			})
+			.collect();
+
+		for key in expired {
+			self.auditid_auditentry.remove(&key)?;
+		}
+
+		Ok(())
+	}
+}
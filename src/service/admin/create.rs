@@ -3,24 +3,20 @@ use std::collections::BTreeMap;
 use conduit::{Error, Result};
 use ruma::{
 	api::client::error::ErrorKind,
-	events::{
-		room::{
-			canonical_alias::RoomCanonicalAliasEventContent,
-			create::RoomCreateEventContent,
-			guest_access::{GuestAccess, RoomGuestAccessEventContent},
-			history_visibility::{HistoryVisibility, RoomHistoryVisibilityEventContent},
-			join_rules::{JoinRule, RoomJoinRulesEventContent},
-			member::{MembershipState, RoomMemberEventContent},
-			name::RoomNameEventContent,
-			power_levels::RoomPowerLevelsEventContent,
-			preview_url::RoomPreviewUrlsEventContent,
-			topic::RoomTopicEventContent,
-		},
-		TimelineEventType,
+	events::room::{
+		canonical_alias::RoomCanonicalAliasEventContent,
+		create::RoomCreateEventContent,
+		guest_access::{GuestAccess, RoomGuestAccessEventContent},
+		history_visibility::{HistoryVisibility, RoomHistoryVisibilityEventContent},
+		join_rules::{JoinRule, RoomJoinRulesEventContent},
+		member::{MembershipState, RoomMemberEventContent},
+		name::RoomNameEventContent,
+		power_levels::RoomPowerLevelsEventContent,
+		preview_url::RoomPreviewUrlsEventContent,
+		topic::RoomTopicEventContent,
 	},
 	RoomId, RoomVersionId,
 };
-use serde_json::value::to_raw_value;
 use tracing::warn;
 
 use crate::{pdu::PduBuilder, services};
@@ -70,18 +66,7 @@ pub async fn create_admin_room() -> Result<()> {
 	services()
 		.rooms
 		.timeline
-		.build_and_append_pdu(
-			PduBuilder {
-				event_type: TimelineEventType::RoomCreate,
-				content: to_raw_value(&content).expect("event is valid, we just created it"),
-				unsigned: None,
-				state_key: Some(String::new()),
-				redacts: None,
-			},
-			server_user,
-			&room_id,
-			&state_lock,
-		)
+		.build_and_append_pdu(PduBuilder::state(String::new(), &content), server_user, &room_id, &state_lock)
 		.await?;
 
 	// 2. Make conduit bot join
@@ -89,9 +74,9 @@ pub async fn create_admin_room() -> Result<()> {
 		.rooms
 		.timeline
 		.build_and_append_pdu(
-			PduBuilder {
-				event_type: TimelineEventType::RoomMember,
-				content: to_raw_value(&RoomMemberEventContent {
+			PduBuilder::state(
+				server_user.to_string(),
+				&RoomMemberEventContent {
 					membership: MembershipState::Join,
 					displayname: None,
 					avatar_url: None,
@@ -100,12 +85,8 @@ pub async fn create_admin_room() -> Result<()> {
 					blurhash: None,
 					reason: None,
 					join_authorized_via_users_server: None,
-				})
-				.expect("event is valid, we just created it"),
-				unsigned: None,
-				state_key: Some(server_user.to_string()),
-				redacts: None,
-			},
+				},
+			),
 			server_user,
 			&room_id,
 			&state_lock,
@@ -120,17 +101,13 @@ pub async fn create_admin_room() -> Result<()> {
 		.rooms
 		.timeline
 		.build_and_append_pdu(
-			PduBuilder {
-				event_type: TimelineEventType::RoomPowerLevels,
-				content: to_raw_value(&RoomPowerLevelsEventContent {
+			PduBuilder::state(
+				String::new(),
+				&RoomPowerLevelsEventContent {
 					users,
 					..Default::default()
-				})
-				.expect("event is valid, we just created it"),
-				unsigned: None,
-				state_key: Some(String::new()),
-				redacts: None,
-			},
+				},
+			),
 			server_user,
 			&room_id,
 			&state_lock,
@@ -142,14 +119,7 @@ pub async fn create_admin_room() -> Result<()> {
 		.rooms
 		.timeline
 		.build_and_append_pdu(
-			PduBuilder {
-				event_type: TimelineEventType::RoomJoinRules,
-				content: to_raw_value(&RoomJoinRulesEventContent::new(JoinRule::Invite))
-					.expect("event is valid, we just created it"),
-				unsigned: None,
-				state_key: Some(String::new()),
-				redacts: None,
-			},
+			PduBuilder::state(String::new(), &RoomJoinRulesEventContent::new(JoinRule::Invite)),
 			server_user,
 			&room_id,
 			&state_lock,
@@ -161,14 +131,10 @@ pub async fn create_admin_room() -> Result<()> {
 		.rooms
 		.timeline
 		.build_and_append_pdu(
-			PduBuilder {
-				event_type: TimelineEventType::RoomHistoryVisibility,
-				content: to_raw_value(&RoomHistoryVisibilityEventContent::new(HistoryVisibility::Shared))
-					.expect("event is valid, we just created it"),
-				unsigned: None,
-				state_key: Some(String::new()),
-				redacts: None,
-			},
+			PduBuilder::state(
+				String::new(),
+				&RoomHistoryVisibilityEventContent::new(HistoryVisibility::Shared),
+			),
 			server_user,
 			&room_id,
 			&state_lock,
@@ -180,14 +146,7 @@ pub async fn create_admin_room() -> Result<()> {
 		.rooms
 		.timeline
 		.build_and_append_pdu(
-			PduBuilder {
-				event_type: TimelineEventType::RoomGuestAccess,
-				content: to_raw_value(&RoomGuestAccessEventContent::new(GuestAccess::Forbidden))
-					.expect("event is valid, we just created it"),
-				unsigned: None,
-				state_key: Some(String::new()),
-				redacts: None,
-			},
+			PduBuilder::state(String::new(), &RoomGuestAccessEventContent::new(GuestAccess::Forbidden)),
 			server_user,
 			&room_id,
 			&state_lock,
@@ -200,14 +159,7 @@ pub async fn create_admin_room() -> Result<()> {
 		.rooms
 		.timeline
 		.build_and_append_pdu(
-			PduBuilder {
-				event_type: TimelineEventType::RoomName,
-				content: to_raw_value(&RoomNameEventContent::new(room_name))
-					.expect("event is valid, we just created it"),
-				unsigned: None,
-				state_key: Some(String::new()),
-				redacts: None,
-			},
+			PduBuilder::state(String::new(), &RoomNameEventContent::new(room_name)),
 			server_user,
 			&room_id,
 			&state_lock,
@@ -218,16 +170,12 @@ pub async fn create_admin_room() -> Result<()> {
 		.rooms
 		.timeline
 		.build_and_append_pdu(
-			PduBuilder {
-				event_type: TimelineEventType::RoomTopic,
-				content: to_raw_value(&RoomTopicEventContent {
+			PduBuilder::state(
+				String::new(),
+				&RoomTopicEventContent {
 					topic: format!("Manage {}", services().globals.server_name()),
-				})
-				.expect("event is valid, we just created it"),
-				unsigned: None,
-				state_key: Some(String::new()),
-				redacts: None,
-			},
+				},
+			),
 			server_user,
 			&room_id,
 			&state_lock,
@@ -241,17 +189,13 @@ pub async fn create_admin_room() -> Result<()> {
 		.rooms
 		.timeline
 		.build_and_append_pdu(
-			PduBuilder {
-				event_type: TimelineEventType::RoomCanonicalAlias,
-				content: to_raw_value(&RoomCanonicalAliasEventContent {
+			PduBuilder::state(
+				String::new(),
+				&RoomCanonicalAliasEventContent {
 					alias: Some(alias.clone()),
 					alt_aliases: Vec::new(),
-				})
-				.expect("event is valid, we just created it"),
-				unsigned: None,
-				state_key: Some(String::new()),
-				redacts: None,
-			},
+				},
+			),
 			server_user,
 			&room_id,
 			&state_lock,
@@ -268,16 +212,12 @@ pub async fn create_admin_room() -> Result<()> {
 		.rooms
 		.timeline
 		.build_and_append_pdu(
-			PduBuilder {
-				event_type: TimelineEventType::RoomPreviewUrls,
-				content: to_raw_value(&RoomPreviewUrlsEventContent {
+			PduBuilder::state(
+				String::new(),
+				&RoomPreviewUrlsEventContent {
 					disabled: true,
-				})
-				.expect("event is valid we just created it"),
-				unsigned: None,
-				state_key: Some(String::new()),
-				redacts: None,
-			},
+				},
+			),
 			server_user,
 			&room_id,
 			&state_lock,
@@ -0,0 +1,148 @@
+use std::{
+	collections::BTreeMap,
+	future::Future,
+	sync::{
+		atomic::{AtomicBool, AtomicU64, Ordering},
+		Arc, Mutex,
+	},
+	time::{Duration, Instant},
+};
+
+use conduit::{debug, Result};
+use ruma::events::room::message::RoomMessageEventContent;
+
+use crate::services;
+
+/// Long-running admin command running in the background
+pub struct Job {
+	pub id: u64,
+	pub name: String,
+	started: Instant,
+	progress: Mutex<String>,
+	cancelled: AtomicBool,
+}
+
+/// Registry of the admin commands currently running as jobs
+#[derive(Default)]
+pub struct Jobs {
+	next_id: AtomicU64,
+	running: Mutex<BTreeMap<u64, Arc<Job>>>,
+}
+
+impl Job {
+	pub fn set_progress<P>(&self, progress: P)
+	where
+		P: Into<String>,
+	{
+		*self.progress.lock().expect("locked") = progress.into();
+	}
+
+	#[must_use]
+	pub fn progress(&self) -> String { self.progress.lock().expect("locked").clone() }
+
+	#[must_use]
+	pub fn elapsed(&self) -> Duration { self.started.elapsed() }
+
+	/// Requests cancellation; the job stops the next time it checks
+	/// `is_cancelled()`.
+	pub fn cancel(&self) { self.cancelled.store(true, Ordering::Relaxed); }
+
+	#[must_use]
+	pub fn is_cancelled(&self) -> bool { self.cancelled.load(Ordering::Relaxed) }
+}
+
+impl Jobs {
+	/// Runs the future built by `job` in the background and tracks it until it
+	/// completes, at which point its output is posted to the admin room along
+	/// with a summary.
+	pub fn spawn<N, F, Fut>(&self, name: N, job: F) -> Arc<Job>
+	where
+		N: Into<String>,
+		F: FnOnce(Arc<Job>) -> Fut,
+		Fut: Future<Output = Result<RoomMessageEventContent>> + Send + 'static,
+	{
+		let id = self
+			.next_id
+			.fetch_add(1, Ordering::Relaxed)
+			.saturating_add(1);
+		let handle = Arc::new(Job {
+			id,
+			name: name.into(),
+			started: Instant::now(),
+			progress: Mutex::new("starting".to_owned()),
+			cancelled: AtomicBool::new(false),
+		});
+
+		self.running
+			.lock()
+			.expect("locked")
+			.insert(id, Arc::clone(&handle));
+
+		let future = job(Arc::clone(&handle));
+		let job = Arc::clone(&handle);
+		services().server.runtime().spawn(async move {
+			let result = future.await;
+			services().admin.jobs.finish(&job, result).await;
+		});
+
+		handle
+	}
+
+	async fn finish(&self, job: &Job, result: Result<RoomMessageEventContent>) {
+		self.running.lock().expect("locked").remove(&job.id);
+
+		let status = if job.is_cancelled() {
+			"was cancelled"
+		} else if result.is_ok() {
+			"finished"
+		} else {
+			"failed"
+		};
+
+		let output = match &result {
+			Ok(content) => content.body().to_owned(),
+			Err(e) => e.to_string(),
+		};
+
+		debug!(id = job.id, name = ?job.name, "job {status}");
+		services()
+			.admin
+			.send_message(RoomMessageEventContent::notice_markdown(format!(
+				"Job {} (`{}`) {status} after {:?}:\n\n{output}",
+				job.id,
+				job.name,
+				job.elapsed(),
+			)))
+			.await;
+	}
+
+	/// Running jobs, oldest first
+	#[must_use]
+	pub fn list(&self) -> Vec<Arc<Job>> {
+		self.running
+			.lock()
+			.expect("locked")
+			.values()
+			.cloned()
+			.collect()
+	}
+
+	#[must_use]
+	pub fn get(&self, id: u64) -> Option<Arc<Job>> { self.running.lock().expect("locked").get(&id).cloned() }
+
+	/// Requests cancellation of every running job, used on shutdown so jobs
+	/// stop at a consistent point instead of being dropped mid-way.
+	pub fn cancel_all(&self) {
+		for job in self.running.lock().expect("locked").values() {
+			job.cancel();
+		}
+	}
+
+	/// Waits for the running jobs to complete, giving up after `timeout`.
+	pub async fn drain(&self, timeout: Duration) {
+		let started = Instant::now();
+		while !self.running.lock().expect("locked").is_empty() && started.elapsed() < timeout {
+			tokio::time::sleep(Duration::from_millis(100)).await;
+		}
+	}
+}
@@ -1,28 +1,31 @@
+pub mod audit;
 pub mod console;
 mod create;
 mod grant;
+pub mod jobs;
 
-use std::{future::Future, pin::Pin, sync::Arc};
+use std::{future::Future, pin::Pin, sync::Arc, time::Duration};
 
+use audit::AuditLog;
 use conduit::{error, utils::mutex_map, Error, Result, Server};
 pub use create::create_admin_room;
 use database::Database;
 pub use grant::make_user_admin;
+use jobs::Jobs;
 use loole::{Receiver, Sender};
 use ruma::{
-	events::{
-		room::message::{Relation, RoomMessageEventContent},
-		TimelineEventType,
-	},
-	OwnedEventId, OwnedRoomId, RoomId, UserId,
+	events::room::message::{Relation, RoomMessageEventContent},
+	OwnedEventId, OwnedRoomId, OwnedUserId, RoomId, UserId,
 };
-use serde_json::value::to_raw_value;
 use tokio::{sync::Mutex, task::JoinHandle};
 
 use crate::{pdu::PduBuilder, services, user_is_local, PduEvent};
 
 const COMMAND_QUEUE_LIMIT: usize = 512;
 
+/// How long shutdown waits for cancelled jobs to wind down
+const JOBS_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
+
 pub type CommandOutput = Option<RoomMessageEventContent>;
 pub type CommandResult = Result<CommandOutput, Error>;
 pub type HandlerResult = Pin<Box<dyn Future<Output = CommandResult> + Send>>;
@@ -33,6 +36,8 @@ pub struct Service {
 	receiver: Mutex<Receiver<Command>>,
 	handler_join: Mutex<Option<JoinHandle<()>>>,
 	pub handle: Mutex<Option<Handler>>,
+	pub jobs: Jobs,
+	pub audit: AuditLog,
 	#[cfg(feature = "console")]
 	pub console: Arc<console::Console>,
 }
@@ -41,16 +46,21 @@ pub struct Service {
 pub struct Command {
 	pub command: String,
 	pub reply_id: Option<OwnedEventId>,
+
+	/// User who issued the command, unset for the admin console
+	pub sender: Option<OwnedUserId>,
 }
 
 impl Service {
-	pub fn build(_server: &Arc<Server>, _db: &Arc<Database>) -> Result<Arc<Self>> {
+	pub fn build(_server: &Arc<Server>, db: &Arc<Database>) -> Result<Arc<Self>> {
 		let (sender, receiver) = loole::bounded(COMMAND_QUEUE_LIMIT);
 		Ok(Arc::new(Self {
 			sender,
 			receiver: Mutex::new(receiver),
 			handler_join: Mutex::new(None),
 			handle: Mutex::new(None),
+			jobs: Jobs::default(),
+			audit: AuditLog::new(db),
 			#[cfg(feature = "console")]
 			console: console::Console::new(),
 		}))
@@ -72,6 +82,8 @@ impl Service {
 		#[cfg(feature = "console")]
 		self.console.interrupt();
 
+		self.jobs.cancel_all();
+
 		if !self.sender.is_closed() {
 			self.sender.close();
 		}
@@ -83,6 +95,8 @@ impl Service {
 		#[cfg(feature = "console")]
 		self.console.close().await;
 
+		self.jobs.drain(JOBS_SHUTDOWN_TIMEOUT).await;
+
 		if let Some(handler_join) = self.handler_join.lock().await.take() {
 			if let Err(e) = handler_join.await {
 				error!("Failed to shutdown: {e:?}");
@@ -102,10 +116,11 @@ impl Service {
 		}
 	}
 
-	pub async fn command(&self, command: String, reply_id: Option<OwnedEventId>) {
+	pub async fn command(&self, command: String, reply_id: Option<OwnedEventId>, sender: Option<OwnedUserId>) {
 		self.send(Command {
 			command,
 			reply_id,
+			sender,
 		})
 		.await;
 	}
@@ -116,6 +131,7 @@ impl Service {
 		self.process_command(Command {
 			command,
 			reply_id,
+			sender: None,
 		})
 		.await
 	}
@@ -142,7 +158,19 @@ impl Service {
 		}
 	}
 
-	async fn handle_signal(&self, #[allow(unused_variables)] sig: &'static str) {
+	async fn handle_signal(&self, sig: &'static str) {
+		if sig == "SIGHUP" {
+			let report = match services().globals.reload_config() {
+				Ok(report) => report.to_string(),
+				Err(e) => format!("Failed to reload the config, keeping the current one: {e}"),
+			};
+
+			self.send_message(RoomMessageEventContent::notice_plain(format!(
+				"Received SIGHUP, reloading config.\n\n{report}"
+			)))
+			.await;
+		}
+
 		#[cfg(feature = "console")]
 		self.console.handle_signal(sig).await;
 	}
@@ -225,13 +253,7 @@ async fn respond_to_room(content: RoomMessageEventContent, room_id: &RoomId, use
 	);
 
 	let state_lock = services().globals.roomid_mutex_state.lock(room_id).await;
-	let response_pdu = PduBuilder {
-		event_type: TimelineEventType::RoomMessage,
-		content: to_raw_value(&content).expect("event is valid, we just created it"),
-		unsigned: None,
-		state_key: None,
-		redacts: None,
-	};
+	let response_pdu = PduBuilder::timeline(&content);
 
 	if let Err(e) = services()
 		.rooms
@@ -254,13 +276,7 @@ async fn handle_response_error(
 		 successfully, but we could not return the output."
 	));
 
-	let response_pdu = PduBuilder {
-		event_type: TimelineEventType::RoomMessage,
-		content: to_raw_value(&error_room_message).expect("event is valid, we just created it"),
-		unsigned: None,
-		state_key: None,
-		redacts: None,
-	};
+	let response_pdu = PduBuilder::timeline(&error_room_message);
 
 	services()
 		.rooms
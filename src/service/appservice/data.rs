@@ -4,6 +4,8 @@ use conduit::{utils, Error, Result};
 use database::{Database, Map};
 use ruma::api::appservice::Registration;
 
+use super::RegistrationOptions;
+
 pub struct Data {
 	id_appserviceregistrations: Arc<Map>,
 }
@@ -16,10 +18,18 @@ impl Data {
 	}
 
 	/// Registers an appservice and returns the ID to the caller
-	pub(super) fn register_appservice(&self, yaml: &Registration) -> Result<String> {
+	pub(super) fn register_appservice(&self, yaml: &Registration, options: &RegistrationOptions) -> Result<String> {
 		let id = yaml.id.as_str();
+		let mut value = serde_yaml::to_value(yaml).expect("registration can be serialized");
+		if let (Some(mapping), serde_yaml::Value::Mapping(options)) = (
+			value.as_mapping_mut(),
+			serde_yaml::to_value(options).expect("registration options can be serialized"),
+		) {
+			mapping.extend(options);
+		}
+
 		self.id_appserviceregistrations
-			.insert(id.as_bytes(), serde_yaml::to_string(&yaml).unwrap().as_bytes())?;
+			.insert(id.as_bytes(), serde_yaml::to_string(&value).unwrap().as_bytes())?;
 
 		Ok(id.to_owned())
 	}
@@ -45,6 +55,16 @@ impl Data {
 			.transpose()
 	}
 
+	pub fn get_registration_options(&self, id: &str) -> Result<Option<RegistrationOptions>> {
+		self.id_appserviceregistrations
+			.get(id.as_bytes())?
+			.map(|bytes| {
+				serde_yaml::from_slice(&bytes)
+					.map_err(|_| Error::bad_database("Invalid registration options in id_appserviceregistrations."))
+			})
+			.transpose()
+	}
+
 	pub(super) fn iter_ids<'a>(&'a self) -> Result<Box<dyn Iterator<Item = Result<String>> + 'a>> {
 		Ok(Box::new(self.id_appserviceregistrations.iter().map(|(id, _)| {
 			utils::string_from_bytes(&id)
@@ -2,15 +2,19 @@ mod data;
 
 use std::{collections::BTreeMap, sync::Arc};
 
-use conduit::{Result, Server};
+use conduit::{Error, Result, Server};
 use data::Data;
 use database::Database;
 use futures_util::Future;
 use regex::RegexSet;
 use ruma::{
-	api::appservice::{Namespace, Registration},
+	api::{
+		appservice::{Namespace, Registration},
+		client::error::ErrorKind,
+	},
 	RoomAliasId, RoomId, UserId,
 };
+use serde::{Deserialize, Serialize};
 use tokio::sync::RwLock;
 
 use crate::services;
@@ -60,6 +64,58 @@ impl RegistrationInfo {
 	pub fn is_exclusive_user_match(&self, user_id: &UserId) -> bool {
 		self.users.is_exclusive_match(user_id.as_str()) || self.registration.sender_localpart == user_id.localpart()
 	}
+
+	/// Whether the registration keeps the ephemeral events of `user_id`, a
+	/// user in its exclusive namespace, from being federated
+	#[must_use]
+	pub fn suppresses_ephemeral_federation(&self, user_id: &UserId) -> bool {
+		self.options.suppress_ephemeral_federation && self.is_exclusive_user_match(user_id)
+	}
+
+	/// Whether requests made with this appservice's token are subject to rate
+	/// limiting. Registrations are rate limited unless they opt out.
+	#[must_use]
+	pub fn is_rate_limited(&self) -> bool { self.registration.rate_limited.unwrap_or(true) }
+
+	/// Maximum number of events sent to this appservice in one transaction,
+	/// falling back to the server-wide default.
+	#[must_use]
+	pub fn transaction_max_events(&self) -> usize {
+		self.options
+			.transaction_max_events
+			.unwrap_or(services().globals.config.appservice_transaction_max_events)
+			.max(1)
+	}
+}
+
+/// Registration options understood by conduwuit in addition to the ones
+/// defined by the appservice specification. They are read from, and stored
+/// alongside, the same registration YAML.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct RegistrationOptions {
+	/// Overrides `appservice_transaction_max_events` for this appservice
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub transaction_max_events: Option<usize>,
+
+	/// Don't federate typing notifications, read receipts and presence of the
+	/// users in this appservice's exclusive namespace, e.g. for bridges whose
+	/// puppets would otherwise echo the remote side's own ephemeral events
+	#[serde(default, skip_serializing_if = "std::ops::Not::not")]
+	pub suppress_ephemeral_federation: bool,
+
+	/// MSC2409: include to-device messages for the users in this appservice's
+	/// namespace in transactions
+	#[serde(
+		default,
+		alias = "de.sorunome.msc2409.push_ephemeral",
+		skip_serializing_if = "std::ops::Not::not"
+	)]
+	pub receive_ephemeral: bool,
+
+	/// MSC3202: include device list changes and one-time key counts of the
+	/// users in this appservice's namespace in transactions
+	#[serde(default, rename = "org.matrix.msc3202", skip_serializing_if = "std::ops::Not::not")]
+	pub msc3202: bool,
 }
 
 impl TryFrom<Vec<Namespace>> for NamespaceRegex {
@@ -99,17 +155,27 @@ pub struct RegistrationInfo {
 	pub users: NamespaceRegex,
 	pub aliases: NamespaceRegex,
 	pub rooms: NamespaceRegex,
+	pub options: RegistrationOptions,
 }
 
 impl TryFrom<Registration> for RegistrationInfo {
 	type Error = regex::Error;
 
 	fn try_from(value: Registration) -> Result<Self, regex::Error> {
+		(value, RegistrationOptions::default()).try_into()
+	}
+}
+
+impl TryFrom<(Registration, RegistrationOptions)> for RegistrationInfo {
+	type Error = regex::Error;
+
+	fn try_from((value, options): (Registration, RegistrationOptions)) -> Result<Self, regex::Error> {
 		Ok(Self {
 			users: value.namespaces.users.clone().try_into()?,
 			aliases: value.namespaces.aliases.clone().try_into()?,
 			rooms: value.namespaces.rooms.clone().try_into()?,
 			registration: value,
+			options,
 		})
 	}
 }
@@ -124,11 +190,11 @@ impl Service {
 		let mut registration_info = BTreeMap::new();
 		let db = Data::new(db);
 		// Inserting registrations into cache
-		for appservice in iter_ids(&db)? {
+		for (id, registration) in iter_ids(&db)? {
+			let options = db.get_registration_options(&id)?.unwrap_or_default();
 			registration_info.insert(
-				appservice.0,
-				appservice
-					.1
+				id,
+				(registration, options)
 					.try_into()
 					.expect("Should be validated on registration"),
 			);
@@ -143,14 +209,14 @@ impl Service {
 	pub fn all(&self) -> Result<Vec<(String, Registration)>> { iter_ids(&self.db) }
 
 	/// Registers an appservice and returns the ID to the caller
-	pub async fn register_appservice(&self, yaml: Registration) -> Result<String> {
+	pub async fn register_appservice(&self, yaml: Registration, options: RegistrationOptions) -> Result<String> {
 		//TODO: Check for collisions between exclusive appservice namespaces
 		self.registration_info
 			.write()
 			.await
-			.insert(yaml.id.clone(), yaml.clone().try_into()?);
+			.insert(yaml.id.clone(), (yaml.clone(), options.clone()).try_into()?);
 
-		self.db.register_appservice(&yaml)
+		self.db.register_appservice(&yaml, &options)
 	}
 
 	/// Remove an appservice registration
@@ -185,6 +251,10 @@ impl Service {
 			.map(|info| info.registration)
 	}
 
+	pub async fn get_registration_info(&self, id: &str) -> Option<RegistrationInfo> {
+		self.registration_info.read().await.get(id).cloned()
+	}
+
 	pub async fn iter_ids(&self) -> Vec<String> {
 		self.registration_info
 			.read()
@@ -210,6 +280,63 @@ impl Service {
 			.any(|info| info.is_exclusive_user_match(user_id))
 	}
 
+	/// Makes the appservices which receive to-device messages or key updates
+	/// of `user_id` send a transaction with them. Device list changes are also
+	/// sent to the appservices with a user sharing a room with `user_id`.
+	pub async fn flush_ephemeral(&self, user_id: &UserId) -> Result<()> {
+		let mut ids = Vec::new();
+		for (id, info) in self.read().await.iter() {
+			let in_namespace = (info.options.receive_ephemeral || info.options.msc3202) && info.is_user_match(user_id);
+			if in_namespace || (info.options.msc3202 && shares_room(user_id, info)?) {
+				ids.push(id.clone());
+			}
+		}
+
+		services().sending.flush_appservices(ids.into_iter(), user_id)
+	}
+
+	/// Checks if ephemeral events of a given user id must not be federated
+	pub async fn is_ephemeral_federation_suppressed(&self, user_id: &UserId) -> bool {
+		self.read()
+			.await
+			.values()
+			.any(|info| info.suppresses_ephemeral_federation(user_id))
+	}
+
+	/// Checks that the user ID may be claimed by `appservice`, or by a client
+	/// which is not an appservice if none: an appservice is limited to its own
+	/// namespace, and IDs in the exclusive namespace of an appservice are
+	/// reserved for it.
+	pub async fn check_user_id(&self, user_id: &UserId, appservice: Option<&RegistrationInfo>) -> Result<()> {
+		if appservice.is_some_and(|info| !info.is_user_match(user_id)) {
+			return Err(Error::BadRequest(ErrorKind::Exclusive, "User is not in namespace."));
+		}
+
+		if is_reserved(self.read().await.values(), appservice, |info| {
+			info.is_exclusive_user_match(user_id)
+		}) {
+			return Err(Error::BadRequest(ErrorKind::Exclusive, "User ID reserved by appservice."));
+		}
+
+		Ok(())
+	}
+
+	/// Checks that the room alias may be claimed by `appservice`, or by a
+	/// client which is not an appservice if none, like `check_user_id`.
+	pub async fn check_alias(&self, alias: &RoomAliasId, appservice: Option<&RegistrationInfo>) -> Result<()> {
+		if appservice.is_some_and(|info| !info.aliases.is_match(alias.as_str())) {
+			return Err(Error::BadRequest(ErrorKind::Exclusive, "Room alias is not in namespace."));
+		}
+
+		if is_reserved(self.read().await.values(), appservice, |info| {
+			info.aliases.is_exclusive_match(alias.as_str())
+		}) {
+			return Err(Error::BadRequest(ErrorKind::Exclusive, "Room alias reserved by appservice."));
+		}
+
+		Ok(())
+	}
+
 	/// Checks if a given room alias matches any exclusive appservice regex
 	pub async fn is_exclusive_alias(&self, alias: &RoomAliasId) -> bool {
 		self.read()
@@ -234,6 +361,19 @@ impl Service {
 	}
 }
 
+/// Whether an ID is in the exclusive namespace of any of the registrations
+/// other than `appservice`
+fn is_reserved<'a, I, F>(registrations: I, appservice: Option<&RegistrationInfo>, is_exclusive_match: F) -> bool
+where
+	I: IntoIterator<Item = &'a RegistrationInfo>,
+	F: Fn(&RegistrationInfo) -> bool,
+{
+	registrations
+		.into_iter()
+		.filter(|info| !appservice.is_some_and(|own| own.registration.id == info.registration.id))
+		.any(is_exclusive_match)
+}
+
 fn iter_ids(db: &Data) -> Result<Vec<(String, Registration)>> {
 	db.iter_ids()?
 		.filter_map(Result::ok)
@@ -246,3 +386,99 @@ fn iter_ids(db: &Data) -> Result<Vec<(String, Registration)>> {
 		})
 		.collect()
 }
+
+/// Whether a user in the appservice's namespace is in one of the user's rooms
+fn shares_room(user_id: &UserId, info: &RegistrationInfo) -> Result<bool> {
+	for room_id in services()
+		.rooms
+		.state_cache
+		.rooms_joined(user_id)
+		.filter_map(Result::ok)
+	{
+		if services()
+			.rooms
+			.state_cache
+			.appservice_in_room(&room_id, info)?
+		{
+			return Ok(true);
+		}
+	}
+
+	Ok(false)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{is_reserved, RegistrationInfo};
+
+	fn registration(id: &str, exclusive: bool) -> RegistrationInfo {
+		let yaml = format!(
+			r#"
+id: {id}
+url: null
+as_token: {id}_as_token
+hs_token: {id}_hs_token
+sender_localpart: {id}bot
+namespaces:
+  users:
+    - exclusive: {exclusive}
+      regex: "@{id}_.*:example.com"
+  aliases:
+    - exclusive: {exclusive}
+      regex: "#{id}_.*:example.com"
+  rooms: []
+"#
+		);
+
+		serde_yaml::from_str::<ruma::api::appservice::Registration>(&yaml)
+			.unwrap()
+			.try_into()
+			.unwrap()
+	}
+
+	#[test]
+	fn exclusive_user_reserved_for_others() {
+		let registrations = [registration("bridge", true)];
+		let reserved = |appservice, user_id: &str| {
+			is_reserved(&registrations, appservice, |info| {
+				info.is_exclusive_user_match(user_id.try_into().unwrap())
+			})
+		};
+
+		assert!(reserved(None, "@bridge_alice:example.com"));
+		assert!(reserved(None, "@bridgebot:example.com"));
+		assert!(!reserved(None, "@alice:example.com"));
+		assert!(!reserved(Some(&registrations[0]), "@bridge_alice:example.com"));
+		assert!(reserved(
+			Some(&registration("other", false)),
+			"@bridge_alice:example.com"
+		));
+	}
+
+	#[test]
+	fn exclusive_alias_reserved_for_others() {
+		let registrations = [registration("bridge", true)];
+		let reserved = |appservice| {
+			is_reserved(&registrations, appservice, |info| {
+				info.aliases.is_exclusive_match("#bridge_room:example.com")
+			})
+		};
+
+		assert!(reserved(None));
+		assert!(!reserved(Some(&registrations[0])));
+		assert!(reserved(Some(&registration("other", true))));
+	}
+
+	#[test]
+	fn non_exclusive_namespace_unrestricted() {
+		let registrations = [registration("bridge", false)];
+
+		assert!(!is_reserved(&registrations, None, |info| {
+			info.is_exclusive_user_match("@bridge_alice:example.com".try_into().unwrap())
+		}));
+		assert!(!is_reserved(&registrations, None, |info| {
+			info.aliases.is_exclusive_match("#bridge_room:example.com")
+		}));
+	}
+}
+
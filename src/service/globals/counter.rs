@@ -0,0 +1,30 @@
+use std::time::{Duration, Instant};
+
+use tokio::{task::JoinHandle, time::interval};
+use tracing::warn;
+
+use crate::services;
+
+/// How often the global counter is sampled
+pub const COUNTER_SAMPLE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// How long samples of the global counter are kept around for
+pub const COUNTER_SAMPLE_WINDOW: Duration = Duration::from_secs(60 * 60);
+
+/// Samples of the global counter, oldest first
+pub type CounterSamples = std::sync::Mutex<std::collections::VecDeque<(Instant, u64)>>;
+
+#[tracing::instrument]
+pub fn start_counter_sampling_task() -> JoinHandle<()> {
+	services().server.runtime().spawn(async move {
+		let mut i = interval(COUNTER_SAMPLE_INTERVAL);
+
+		loop {
+			i.tick().await;
+
+			if let Err(e) = services().globals.sample_counter() {
+				warn!(%e, "Failed to sample the global counter");
+			}
+		}
+	})
+}
@@ -1,22 +1,24 @@
 use std::{
 	collections::{BTreeMap, HashMap},
 	sync::Arc,
+	time::Instant,
 };
 
-use conduit::{trace, utils, Error, Result};
-use database::{Cork, Database, Map};
+use conduit::{debug, trace, utils, warn, Error, Result};
+use database::{Activity, Cork, Database, Map};
 use futures_util::{stream::FuturesUnordered, StreamExt};
 use lru_cache::LruCache;
 use ruma::{
 	api::federation::discovery::{ServerSigningKeys, VerifyKey},
 	signatures::Ed25519KeyPair,
-	DeviceId, MilliSecondsSinceUnixEpoch, OwnedServerSigningKeyId, ServerName, UserId,
+	DeviceId, MilliSecondsSinceUnixEpoch, OwnedRoomId, OwnedServerSigningKeyId, RoomId, ServerName, UserId,
 };
 
 use crate::services;
 
 const COUNTER: &[u8] = b"c";
 const LAST_CHECK_FOR_UPDATES_COUNT: &[u8] = b"u";
+const LAST_CLOCK_SKEW: &[u8] = b"clock_skew";
 
 pub struct Data {
 	global: Arc<Map>,
@@ -82,6 +84,35 @@ impl Data {
 		Ok(())
 	}
 
+	/// Returns when the last clock skew measurement was taken (ms since unix
+	/// epoch) and the measured skew in milliseconds.
+	pub fn last_clock_skew(&self) -> Result<Option<(u64, i64)>> {
+		self.global
+			.get(LAST_CLOCK_SKEW)?
+			.map(|bytes| {
+				if bytes.len() != 16 {
+					return Err(Error::bad_database("Last clock skew measurement has invalid bytes."));
+				}
+
+				let (measured_at, skew) = bytes.split_at(8);
+				let measured_at = utils::u64_from_bytes(measured_at)
+					.map_err(|_| Error::bad_database("Last clock skew measurement has invalid bytes."))?;
+				let skew = i64::from_be_bytes(skew.try_into().expect("skew is 8 bytes"));
+
+				Ok((measured_at, skew))
+			})
+			.transpose()
+	}
+
+	pub fn update_last_clock_skew(&self, measured_at: u64, skew_ms: i64) -> Result<()> {
+		let mut value = measured_at.to_be_bytes().to_vec();
+		value.extend_from_slice(&skew_ms.to_be_bytes());
+
+		self.global.insert(LAST_CLOCK_SKEW, &value)?;
+
+		Ok(())
+	}
+
 	#[tracing::instrument(skip(self))]
 	pub async fn watch(&self, user_id: &UserId, device_id: &DeviceId) -> Result<()> {
 		let userid_bytes = user_id.as_bytes().to_vec();
@@ -108,21 +139,15 @@ impl Data {
 		futures.push(self.userroomid_highlightcount.watch_prefix(&userid_prefix));
 
 		// Events for rooms we are in
-		for room_id in services()
+		let rooms_joined = services()
 			.rooms
 			.state_cache
 			.rooms_joined(user_id)
-			.filter_map(Result::ok)
+			.filter_map(Result::ok);
+		for (room_id, short_roomid) in
+			watched_rooms(rooms_joined, |room_id| services().rooms.short.get_shortroomid(room_id))
 		{
-			let short_roomid = services()
-				.rooms
-				.short
-				.get_shortroomid(&room_id)
-				.ok()
-				.flatten()
-				.expect("room exists")
-				.to_be_bytes()
-				.to_vec();
+			let short_roomid = short_roomid.to_be_bytes().to_vec();
 
 			let roomid_bytes = room_id.as_bytes().to_vec();
 			let mut roomid_prefix = roomid_bytes.clone();
@@ -185,6 +210,8 @@ impl Data {
 
 	pub fn cleanup(&self) -> Result<()> { self.db.db.cleanup() }
 
+	pub fn sync(&self) -> Result<()> { self.db.db.sync() }
+
 	pub fn cork(&self) -> Cork { Cork::new(&self.db.db, false, false) }
 
 	pub fn cork_and_flush(&self) -> Cork { Cork::new(&self.db.db, true, false) }
@@ -223,11 +250,18 @@ lasttimelinecount_cache: {lasttimelinecount_cache} / {max_lasttimelinecount_cach
 		}
 	}
 
-	pub fn load_keypair(&self) -> Result<Ed25519KeyPair> {
+	/// Loads the server keypair, generating one if none exists yet. In
+	/// read-only mode a generated keypair is not persisted.
+	pub fn load_keypair(&self, read_only: bool) -> Result<Ed25519KeyPair> {
 		let keypair_bytes = self.global.get(b"keypair")?.map_or_else(
 			|| {
 				let keypair = utils::generate_keypair();
-				self.global.insert(b"keypair", &keypair)?;
+				if read_only {
+					warn!("No keypair in database; using a temporary one in read-only mode.");
+				} else {
+					self.global.insert(b"keypair", &keypair)?;
+				}
+
 				Ok::<_, Error>(keypair)
 			},
 			Ok,
@@ -330,4 +364,63 @@ lasttimelinecount_cache: {lasttimelinecount_cache} / {max_lasttimelinecount_cach
 	pub fn backup_list(&self) -> Result<String> { self.db.db.backup_list() }
 
 	pub fn file_list(&self) -> Result<String> { self.db.db.file_list() }
+
+	pub fn last_backup(&self) -> Result<Option<i64>> { self.db.db.last_backup() }
+
+	pub fn size(&self) -> Result<u64> { self.db.db.size() }
+
+	pub fn cache_usage(&self) -> usize { self.db.db.cache_usage() }
+
+	pub fn activity(&self) -> (Vec<(&str, Activity)>, Instant) { self.db.activity() }
+
+	pub fn reset_activity(&self) { self.db.reset_activity(); }
+}
+
+/// The joined rooms to watch with their short IDs, leaving out rooms which
+/// were purged since they were listed
+fn watched_rooms<I, F>(rooms: I, shortroomid: F) -> impl Iterator<Item = (OwnedRoomId, u64)>
+where
+	I: IntoIterator<Item = OwnedRoomId>,
+	F: Fn(&RoomId) -> Result<Option<u64>>,
+{
+	rooms.into_iter().filter_map(move |room_id| {
+		let Ok(Some(short_roomid)) = shortroomid(&room_id) else {
+			debug!(%room_id, "Not watching room which does not exist anymore");
+			return None;
+		};
+
+		Some((room_id, short_roomid))
+	})
+}
+
+#[cfg(test)]
+mod tests {
+	use std::{collections::HashMap, sync::RwLock, thread};
+
+	use ruma::{owned_room_id, OwnedRoomId};
+
+	use super::watched_rooms;
+
+	#[test]
+	fn rooms_purged_after_listing_are_not_watched() {
+		let shortroomids = RwLock::new(HashMap::from([
+			(owned_room_id!("!kept:example.com"), 1_u64),
+			(owned_room_id!("!purged:example.com"), 2),
+		]));
+		let joined: Vec<OwnedRoomId> = shortroomids.read().unwrap().keys().cloned().collect();
+
+		// purged by another task between listing and watching
+		thread::scope(|scope| {
+			scope.spawn(|| {
+				shortroomids
+					.write()
+					.unwrap()
+					.remove(&owned_room_id!("!purged:example.com"));
+			});
+		});
+
+		let watched: Vec<_> =
+			watched_rooms(joined, |room_id| Ok(shortroomids.read().unwrap().get(room_id).copied())).collect();
+		assert_eq!(watched, [(owned_room_id!("!kept:example.com"), 1)]);
+	}
 }
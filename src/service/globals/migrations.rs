@@ -10,7 +10,7 @@ use std::{
 };
 
 use conduit::{debug, debug_info, debug_warn, error, info, utils, warn, Config, Error, Result};
-use database::Database;
+use database::{Database, DATABASE_VERSION};
 use itertools::Itertools;
 use ruma::{
 	events::{push_rules::PushRulesEvent, room::member::MembershipState, GlobalAccountDataEventType},
@@ -18,15 +18,7 @@ use ruma::{
 	EventId, OwnedRoomId, RoomId, UserId,
 };
 
-use crate::services;
-
-/// The current schema version.
-/// - If database is opened at greater version we reject with error. The
-///   software must be updated for backward-incompatible changes.
-/// - If database is opened at lesser version we apply migrations up to this.
-///   Note that named-feature migrations may also be performed when opening at
-///   equal or lesser version. These are expected to be backward-compatible.
-const DATABASE_VERSION: u64 = 13;
+use crate::{services, user_is_local};
 
 pub(crate) async fn migrations(db: &Arc<Database>, config: &Config) -> Result<()> {
 	// Matrix resource ownership is based on the server name; changing it
@@ -42,6 +34,10 @@ pub(crate) async fn migrations(db: &Arc<Database>, config: &Config) -> Result<()
 		}
 	}
 
+	if config.rocksdb_read_only {
+		return read_only(config);
+	}
+
 	if services().users.count()? > 0 {
 		migrate(db, config).await
 	} else {
@@ -49,11 +45,36 @@ pub(crate) async fn migrations(db: &Arc<Database>, config: &Config) -> Result<()
 	}
 }
 
+/// Nothing can be migrated in read-only mode, so only a database already at
+/// the current schema version can be opened.
+fn read_only(config: &Config) -> Result<()> {
+	let version = services().globals.database_version()?;
+	if services().users.count()? == 0 || version != DATABASE_VERSION {
+		error!(
+			"Database schema version {version} does not match {DATABASE_VERSION} and cannot be migrated in read-only \
+			 mode."
+		);
+		return Err(Error::bad_database(
+			"Database must be opened read-write once to create or migrate it before it can be opened read-only.",
+		));
+	}
+
+	info!(
+		"Loaded {} database with schema version {DATABASE_VERSION} in read-only mode",
+		config.database_backend
+	);
+
+	Ok(())
+}
+
 async fn fresh(db: &Arc<Database>, config: &Config) -> Result<()> {
 	services().globals.bump_database_version(DATABASE_VERSION)?;
 
 	db["global"].insert(b"fix_bad_double_separator_in_state_cache", &[])?;
 	db["global"].insert(b"retroactively_fix_bad_data_from_roomuserid_joined", &[])?;
+	db["global"].insert(b"feat_user_directory_index", &[])?;
+	db["global"].insert(b"feat_guest_since", &[])?;
+	db["global"].insert(b"feat_search_cjk_tokens", &[])?;
 
 	// Create the admin room and server user on first run
 	crate::admin::create_admin_room().await?;
@@ -68,6 +89,14 @@ async fn fresh(db: &Arc<Database>, config: &Config) -> Result<()> {
 
 /// Apply any migrations
 async fn migrate(db: &Arc<Database>, config: &Config) -> Result<()> {
+	let version = services().globals.database_version()?;
+	if version > DATABASE_VERSION {
+		error!("Database schema version {version} is newer than {DATABASE_VERSION}, the latest this version knows.");
+		return Err(Error::bad_database(
+			"Database was created or imported from a newer version, please update conduwuit.",
+		));
+	}
+
 	if services().globals.database_version()? < 1 {
 		db_lt_1(db, config).await?;
 	}
@@ -142,6 +171,19 @@ async fn migrate(db: &Arc<Database>, config: &Config) -> Result<()> {
 		retroactively_fix_bad_data_from_roomuserid_joined(db, config).await?;
 	}
 
+	// guests are left out of the user directory, so they are flagged first
+	if db["global"].get(b"feat_guest_since")?.is_none() {
+		flag_guests(db, config).await?;
+	}
+
+	if db["global"].get(b"feat_user_directory_index")?.is_none() {
+		index_user_directory(db, config).await?;
+	}
+
+	if db["global"].get(b"feat_search_cjk_tokens")?.is_none() {
+		reindex_search_tokens(db, config).await?;
+	}
+
 	assert_eq!(
 		services().globals.database_version().unwrap(),
 		DATABASE_VERSION,
@@ -756,8 +798,15 @@ async fn checkup_sha256_media(db: &Arc<Database>, config: &Config) -> Result<()>
 		.collect();
 
 	for key in media.db.get_all_media_keys() {
-		let new_path = media.get_media_file_sha256(&key).into_os_string();
-		let old_path = media.get_media_file_b64(&key).into_os_string();
+		// deduplicated media is stored in the file of other media
+		let blob = media.blob_key(&key);
+		if media.is_cold(&blob) {
+			// stored in media_cold_path, outside the media directory
+			continue;
+		}
+
+		let new_path = media.get_media_file_sha256(&blob).into_os_string();
+		let old_path = media.get_media_file_b64(&blob).into_os_string();
 		if let Err(e) = handle_media_check(&dbs, config, &files, &key, &new_path, &old_path).await {
 			error!(
 				media_id = ?encode_key(&key), ?new_path, ?old_path,
@@ -927,3 +976,165 @@ async fn retroactively_fix_bad_data_from_roomuserid_joined(db: &Arc<Database>, _
 	info!("Finished fixing");
 	Ok(())
 }
+
+/// Flags the guest accounts registered before guests were told apart from
+/// deactivated accounts. The registration time of those is unknown, so they
+/// expire counting from now.
+async fn flag_guests(db: &Arc<Database>, _config: &Config) -> Result<()> {
+	warn!("Flagging guest accounts registered before they were recorded as guests");
+
+	let appservices = services().appservice.read().await;
+	let now = utils::millis_since_unix_epoch();
+	let mut flagged: usize = 0;
+	for user_id in services().users.iter() {
+		let user_id = user_id?;
+		if !user_is_local(&user_id)
+			|| user_id == services().globals.server_user
+			|| services().users.is_guest(&user_id)?
+		{
+			continue;
+		}
+
+		let has_password = !services().users.is_deactivated(&user_id)?;
+		let has_devices = services().users.all_device_ids(&user_id).next().is_some();
+		let appservice_user = appservices
+			.values()
+			.any(|info| info.is_user_match(&user_id));
+
+		if is_unflagged_guest(has_password, has_devices, appservice_user) {
+			db["userid_guestsince"].insert(user_id.as_bytes(), &now.to_be_bytes())?;
+			flagged = flagged.saturating_add(1);
+		}
+	}
+
+	db["global"].insert(b"feat_guest_since", &[])?;
+
+	info!("Finished flagging {flagged} guest accounts");
+	Ok(())
+}
+
+/// Guests have no password, as have deactivated accounts and appservice
+/// users. Deactivating removes the devices of an account while guests keep
+/// theirs, which tells them apart.
+fn is_unflagged_guest(has_password: bool, has_devices: bool, appservice_user: bool) -> bool {
+	!has_password && has_devices && !appservice_user
+}
+
+/// Indexes the users known before the user directory index existed: local
+/// users which are not guests, and remote users sharing a room with us. Local
+/// users without a password are skipped unless they are in a room, which
+/// leaves out deactivated accounts but keeps appservice users.
+async fn index_user_directory(db: &Arc<Database>, _config: &Config) -> Result<()> {
+	warn!("Indexing the user directory, this may take a while");
+
+	let mut indexed: usize = 0;
+	for user_id in services().users.iter() {
+		let user_id = user_id?;
+		let in_a_room = services()
+			.rooms
+			.state_cache
+			.rooms_joined(&user_id)
+			.next()
+			.is_some();
+
+		let searchable = if user_is_local(&user_id) {
+			!services().users.is_guest(&user_id)? && (in_a_room || !services().users.is_deactivated(&user_id)?)
+		} else {
+			in_a_room
+		};
+
+		if searchable {
+			services().users.directory.index(&user_id)?;
+			indexed = indexed.saturating_add(1);
+		}
+	}
+
+	db["global"].insert(b"feat_user_directory_index", &[])?;
+
+	info!("Finished indexing {indexed} users in the user directory");
+	Ok(())
+}
+
+/// Rebuilds the search index, whose tokens were split differently before
+/// scripts without spaces between words were split into bigrams. Messages
+/// indexed before could not be found by the tokens of a search otherwise.
+async fn reindex_search_tokens(db: &Arc<Database>, _config: &Config) -> Result<()> {
+	warn!("Rebuilding the search index, this may take a while");
+
+	let tokenids = &db["tokenids"];
+	tokenids.remove_batch(&mut tokenids.iter().map(|(key, _)| key))?;
+
+	let mut indexed: usize = 0;
+	for (pdu_id, pdu) in db["pduid_pdu"].iter() {
+		let Some(body) = message_body(&pdu) else {
+			continue;
+		};
+
+		let shortroomid = pdu_id
+			.get(..size_of::<u64>())
+			.map(utils::u64_from_bytes)
+			.and_then(Result::ok)
+			.ok_or_else(|| Error::bad_database("Invalid pdu id in pduid_pdu."))?;
+
+		services()
+			.rooms
+			.search
+			.index_pdu(shortroomid, &pdu_id, &body)?;
+		indexed = indexed.saturating_add(1);
+	}
+
+	db["global"].insert(b"feat_search_cjk_tokens", &[])?;
+
+	info!("Finished indexing {indexed} messages for search");
+	Ok(())
+}
+
+/// The body of a message, which is what the search index holds; redacted
+/// messages have none
+fn message_body(pdu: &[u8]) -> Option<String> {
+	let pdu: serde_json::Value = serde_json::from_slice(pdu).ok()?;
+	if pdu.get("type")?.as_str()? != "m.room.message" {
+		return None;
+	}
+
+	pdu.get("content")?
+		.get("body")?
+		.as_str()
+		.map(ToOwned::to_owned)
+}
+
+#[cfg(test)]
+mod tests {
+	use serde_json::json;
+
+	use super::{is_unflagged_guest, message_body};
+
+	#[test]
+	fn unflagged_guests() {
+		assert!(is_unflagged_guest(false, true, false));
+
+		// full, deactivated and appservice accounts
+		assert!(!is_unflagged_guest(true, true, false));
+		assert!(!is_unflagged_guest(false, false, false));
+		assert!(!is_unflagged_guest(false, true, true));
+	}
+
+	#[test]
+	fn message_bodies_are_reindexed() {
+		let pdu = |value: serde_json::Value| serde_json::to_vec(&value).unwrap();
+
+		let message = pdu(json!({
+			"type": "m.room.message",
+			"content": { "msgtype": "m.text", "body": "東京タワー" },
+		}));
+		assert_eq!(message_body(&message).as_deref(), Some("東京タワー"));
+
+		let redacted = pdu(json!({ "type": "m.room.message", "content": {} }));
+		assert_eq!(message_body(&redacted), None);
+
+		let topic = pdu(json!({ "type": "m.room.topic", "content": { "body": "topic" } }));
+		assert_eq!(message_body(&topic), None);
+
+		assert_eq!(message_body(b"not json"), None);
+	}
+}
@@ -1,17 +1,26 @@
 mod client;
+pub(super) mod counter;
 mod data;
 pub(super) mod emerg_access;
 pub(super) mod migrations;
 mod resolver;
+pub mod status;
 pub(super) mod updates;
 
 use std::{
-	collections::{BTreeMap, HashMap},
-	sync::Arc,
-	time::Instant,
+	collections::{BTreeMap, HashMap, VecDeque},
+	sync::{Arc, RwLockReadGuard},
+	time::{Duration, Instant},
 };
 
-use conduit::{error, trace, utils::MutexMap, Config, Result, Server};
+use conduit::{
+	config::{RateLimitConfig, ReloadReport, Reloadable},
+	error, info,
+	log::EnvFilter,
+	trace,
+	utils::MutexMap,
+	Config, Error, Result, Server,
+};
 use data::Data;
 use database::Database;
 use hickory_resolver::TokioAsyncResolver;
@@ -55,6 +64,9 @@ pub struct Service {
 	pub roomid_mutex_federation: MutexMap<OwnedRoomId, ()>,
 	pub roomid_federationhandletime: RwLock<HashMap<OwnedRoomId, (OwnedEventId, Instant)>>,
 	pub updates_handle: Mutex<Option<JoinHandle<()>>>,
+	pub counter_handle: Mutex<Option<JoinHandle<()>>>,
+	pub counter_samples: counter::CounterSamples,
+	pub status: status::StatusCache,
 	pub stateres_mutex: Arc<Mutex<()>>,
 	pub server_user: OwnedUserId,
 	pub admin_alias: OwnedRoomAliasId,
@@ -64,10 +76,14 @@ impl Service {
 	pub fn build(server: &Arc<Server>, db: &Arc<Database>) -> Result<Self> {
 		let config = &server.config;
 		let db = Data::new(db);
-		let keypair = db.load_keypair();
+		let keypair = db.load_keypair(config.rocksdb_read_only);
 
 		let keypair = match keypair {
 			Ok(k) => k,
+			Err(e) if config.rocksdb_read_only => {
+				error!("Keypair invalid and cannot be replaced in read-only mode.");
+				return Err(e);
+			},
 			Err(e) => {
 				error!("Keypair invalid. Deleting...");
 				db.remove_keypair()?;
@@ -119,6 +135,9 @@ impl Service {
 			roomid_mutex_federation: MutexMap::<OwnedRoomId, ()>::new(),
 			roomid_federationhandletime: RwLock::new(HashMap::new()),
 			updates_handle: Mutex::new(None),
+			counter_handle: Mutex::new(None),
+			counter_samples: std::sync::Mutex::new(VecDeque::new()),
+			status: status::StatusCache::default(),
 			stateres_mutex: Arc::new(Mutex::new(())),
 			admin_alias: RoomAliasId::parse(format!("#admins:{}", &config.server_name))
 				.expect("#admins:server_name is valid alias name"),
@@ -137,6 +156,35 @@ impl Service {
 		Ok(s)
 	}
 
+	/// Current values of the settings which can be reloaded at runtime; read
+	/// these through the accessors rather than from `config`.
+	fn reloadable(&self) -> RwLockReadGuard<'_, Reloadable> { services().server.reloadable.read().expect("locked") }
+
+	/// Reads the config file again and applies the hot-reloadable settings
+	/// which changed. An invalid config is rejected without changing anything.
+	pub fn reload_config(&self) -> Result<ReloadReport> {
+		let server = &services().server;
+		let new = self.config.reload(server.config_path.clone())?;
+		let new_reloadable = Reloadable::from(&new);
+
+		let mut current = server.reloadable.write().expect("locked");
+		let report = self.config.diff_reload(&current, &new);
+		if report.applied.contains(&"log") {
+			let filter = EnvFilter::try_new(&new_reloadable.log)
+				.map_err(|e| Error::BadConfig(format!("Invalid log level filter: {e}")))?;
+			server
+				.log
+				.reload
+				.reload(&filter)
+				.map_err(|e| Error::Err(format!("Failed to reload the log level: {e}")))?;
+		}
+
+		*current = new_reloadable;
+		info!(applied = ?report.applied, restart_required = ?report.restart_required, "Reloaded config");
+
+		Ok(report)
+	}
+
 	/// Returns this server's keypair.
 	pub fn keypair(&self) -> &ruma::signatures::Ed25519KeyPair { &self.keypair }
 
@@ -146,6 +194,44 @@ impl Service {
 	#[tracing::instrument(skip(self))]
 	pub fn current_count(&self) -> Result<u64> { self.db.current_count() }
 
+	/// Records the current value of the global counter so `counter_rate` can
+	/// be calculated
+	pub fn sample_counter(&self) -> Result<()> {
+		let count = self.current_count()?;
+		let mut samples = self.counter_samples.lock().expect("locked");
+
+		samples.push_back((Instant::now(), count));
+		while samples.front().is_some_and(|(sampled_at, _)| {
+			sampled_at.elapsed() > counter::COUNTER_SAMPLE_WINDOW + counter::COUNTER_SAMPLE_INTERVAL
+		}) {
+			samples.pop_front();
+		}
+
+		Ok(())
+	}
+
+	/// Returns how far the global counter advanced within the given window,
+	/// along with the time span actually covered by the samples taken so far
+	pub fn counter_rate(&self, window: Duration) -> Result<(u64, Duration)> {
+		let count = self.current_count()?;
+		let samples = self.counter_samples.lock().expect("locked");
+
+		Ok(samples
+			.iter()
+			.find(|(sampled_at, _)| sampled_at.elapsed() <= window)
+			.map_or((0, Duration::ZERO), |(sampled_at, sampled)| {
+				(count.saturating_sub(*sampled), sampled_at.elapsed())
+			}))
+	}
+
+	/// Returns when the last clock skew measurement was taken (ms since unix
+	/// epoch) and the measured skew in milliseconds.
+	pub fn last_clock_skew(&self) -> Result<Option<(u64, i64)>> { self.db.last_clock_skew() }
+
+	pub fn update_last_clock_skew(&self, measured_at: u64, skew_ms: i64) -> Result<()> {
+		self.db.update_last_clock_skew(measured_at, skew_ms)
+	}
+
 	#[tracing::instrument(skip(self))]
 	pub fn last_check_for_updates_id(&self) -> Result<u64> { self.db.last_check_for_updates_id() }
 
@@ -162,9 +248,17 @@ impl Service {
 
 	pub fn max_fetch_prev_events(&self) -> u16 { self.config.max_fetch_prev_events }
 
-	pub fn allow_registration(&self) -> bool { self.config.allow_registration }
+	pub fn log(&self) -> String { self.reloadable().log.clone() }
+
+	pub fn allow_registration(&self) -> bool { self.reloadable().allow_registration }
+
+	pub fn allow_guest_registration(&self) -> bool { self.reloadable().allow_guest_registration }
 
-	pub fn allow_guest_registration(&self) -> bool { self.config.allow_guest_registration }
+	pub fn registration_token(&self) -> Option<String> { self.reloadable().registration_token.clone() }
+
+	pub fn auto_join_rooms(&self) -> Vec<OwnedRoomId> { self.reloadable().auto_join_rooms.clone() }
+
+	pub fn rate_limit(&self) -> RateLimitConfig { self.reloadable().rate_limit.clone() }
 
 	pub fn allow_guests_auto_join_rooms(&self) -> bool { self.config.allow_guests_auto_join_rooms }
 
@@ -174,6 +268,8 @@ impl Service {
 
 	pub fn allow_federation(&self) -> bool { self.config.allow_federation }
 
+	pub fn read_only(&self) -> bool { self.config.rocksdb_read_only }
+
 	pub fn allow_public_room_directory_over_federation(&self) -> bool {
 		self.config.allow_public_room_directory_over_federation
 	}
@@ -190,7 +286,7 @@ impl Service {
 
 	pub fn allow_check_for_updates(&self) -> bool { self.config.allow_check_for_updates }
 
-	pub fn trusted_servers(&self) -> &[OwnedServerName] { &self.config.trusted_servers }
+	pub fn trusted_servers(&self) -> Vec<OwnedServerName> { self.reloadable().trusted_servers.clone() }
 
 	pub fn query_trusted_key_servers_first(&self) -> bool { self.config.query_trusted_key_servers_first }
 
@@ -200,15 +296,21 @@ impl Service {
 
 	pub fn jwt_decoding_key(&self) -> Option<&jsonwebtoken::DecodingKey> { self.jwt_decoding_key.as_ref() }
 
-	pub fn turn_password(&self) -> &String { &self.config.turn_password }
+	pub fn login_via_existing_session(&self) -> bool { self.config.login_via_existing_session }
+
+	pub fn login_token_ttl(&self) -> u64 { self.config.login_token_ttl }
+
+	pub fn access_token_ttl(&self) -> u64 { self.config.access_token_ttl }
+
+	pub fn turn_password(&self) -> String { self.reloadable().turn_password.clone() }
 
-	pub fn turn_ttl(&self) -> u64 { self.config.turn_ttl }
+	pub fn turn_ttl(&self) -> u64 { self.reloadable().turn_ttl }
 
-	pub fn turn_uris(&self) -> &[String] { &self.config.turn_uris }
+	pub fn turn_uris(&self) -> Vec<String> { self.reloadable().turn_uris.clone() }
 
-	pub fn turn_username(&self) -> &String { &self.config.turn_username }
+	pub fn turn_username(&self) -> String { self.reloadable().turn_username.clone() }
 
-	pub fn turn_secret(&self) -> &String { &self.config.turn_secret }
+	pub fn turn_secret(&self) -> String { self.reloadable().turn_secret.clone() }
 
 	pub fn allow_profile_lookup_federation_requests(&self) -> bool {
 		self.config.allow_profile_lookup_federation_requests
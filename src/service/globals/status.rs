@@ -0,0 +1,178 @@
+use std::{
+	path::Path,
+	sync::Arc,
+	time::{Duration, Instant},
+};
+
+use conduit::Result;
+use serde::Serialize;
+use tokio::sync::Mutex;
+
+use crate::{sending::Destination, services};
+
+/// How long a snapshot is served before the numbers are gathered again
+const SNAPSHOT_TTL: Duration = Duration::from_secs(30);
+
+/// Snapshot of the numbers shown on the public status page, gathered at most
+/// once per `SNAPSHOT_TTL` however often the page is requested. Numbers
+/// disabled in the `dashboard` config section are left out.
+#[derive(Default)]
+pub struct StatusCache {
+	snapshot: Mutex<Option<(Instant, Arc<ServerStatus>)>>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct ServerStatus {
+	pub server_name: String,
+	pub version: String,
+	pub uptime_secs: u64,
+	pub registration_open: bool,
+
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub user_count: Option<usize>,
+
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub room_count: Option<usize>,
+
+	/// Events being sent to other servers
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub federation_queue: Option<usize>,
+
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub media_bytes: Option<u64>,
+
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub active_users: Option<ActiveUsers>,
+
+	/// The five database trees with the most writes
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub db_activity: Option<Vec<TreeActivity>>,
+}
+
+/// Local users active within the last 1, 7 and 30 days
+#[derive(Clone, Debug, Serialize)]
+pub struct ActiveUsers {
+	pub day: usize,
+	pub week: usize,
+	pub month: usize,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct TreeActivity {
+	pub tree: String,
+	pub inserts: u64,
+	pub removes: u64,
+	pub reads: u64,
+}
+
+impl StatusCache {
+	/// The current snapshot, gathered again if it is older than
+	/// `SNAPSHOT_TTL`. Concurrent requests wait for one of them to gather it.
+	pub async fn get(&self) -> Result<Arc<ServerStatus>> {
+		let mut snapshot = self.snapshot.lock().await;
+		if let Some((taken, status)) = snapshot.as_ref() {
+			if taken.elapsed() < SNAPSHOT_TTL {
+				return Ok(Arc::clone(status));
+			}
+		}
+
+		let status = Arc::new(gather().await?);
+		*snapshot = Some((Instant::now(), Arc::clone(&status)));
+		Ok(status)
+	}
+}
+
+async fn gather() -> Result<ServerStatus> {
+	let config = &services().globals.config.dashboard;
+
+	let user_count = config
+		.show_user_count
+		.then(|| services().users.list_local_users().map(|users| users.len()))
+		.transpose()?;
+
+	let room_count = config
+		.show_room_count
+		.then(|| services().rooms.metadata.iter_ids().count());
+
+	let federation_queue = config.show_federation_queue.then(|| {
+		services()
+			.sending
+			.db
+			.active_requests()
+			.filter_map(Result::ok)
+			.filter(|(_, dest, _)| matches!(dest, Destination::Normal(_)))
+			.count()
+	});
+
+	let media_bytes = if config.show_media_usage {
+		Some(media_bytes().await?)
+	} else {
+		None
+	};
+
+	let active_users = if config.show_active_users {
+		let counts = services().users.activity.counts().await?;
+		Some(ActiveUsers {
+			day: counts.day,
+			week: counts.week,
+			month: counts.month,
+		})
+	} else {
+		None
+	};
+
+	let db_activity = (config.show_db_activity && services().globals.config.database_activity_counters).then(|| {
+		let (activity, _) = services().globals.db.activity();
+		activity
+			.into_iter()
+			.take(5)
+			.map(|(tree, activity)| TreeActivity {
+				tree: tree.to_owned(),
+				inserts: activity.inserts,
+				removes: activity.removes,
+				reads: activity.gets,
+			})
+			.collect()
+	});
+
+	Ok(ServerStatus {
+		server_name: services().globals.server_name().to_string(),
+		version: conduit::version().to_owned(),
+		uptime_secs: services()
+			.server
+			.started
+			.elapsed()
+			.unwrap_or_default()
+			.as_secs(),
+		registration_open: services().globals.allow_registration(),
+		user_count,
+		room_count,
+		federation_queue,
+		media_bytes,
+		active_users,
+		db_activity,
+	})
+}
+
+/// Size of the files in the media directory and the cold storage directory
+async fn media_bytes() -> Result<u64> {
+	let mut total = dir_bytes(&services().media.get_media_dir()).await?;
+	if let Some(cold_path) = &services().globals.config.media_cold_path {
+		total = total.saturating_add(dir_bytes(cold_path).await?);
+	}
+
+	Ok(total)
+}
+
+async fn dir_bytes(path: &Path) -> Result<u64> {
+	let mut total: u64 = 0;
+	let mut dir = tokio::fs::read_dir(path).await?;
+	while let Some(entry) = dir.next_entry().await? {
+		let metadata = entry.metadata().await?;
+		if metadata.is_file() {
+			total = total.saturating_add(metadata.len());
+		}
+	}
+
+	Ok(total)
+}
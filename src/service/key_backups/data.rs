@@ -15,6 +15,7 @@ use crate::services;
 
 pub(super) struct Data {
 	backupid_algorithm: Arc<Map>,
+	backupid_count: Arc<Map>,
 	backupid_etag: Arc<Map>,
 	backupkeyid_backup: Arc<Map>,
 }
@@ -23,6 +24,7 @@ impl Data {
 	pub(super) fn new(db: &Arc<Database>) -> Self {
 		Self {
 			backupid_algorithm: db["backupid_algorithm"].clone(),
+			backupid_count: db["backupid_count"].clone(),
 			backupid_etag: db["backupid_etag"].clone(),
 			backupkeyid_backup: db["backupkeyid_backup"].clone(),
 		}
@@ -39,6 +41,7 @@ impl Data {
 			&key,
 			&serde_json::to_vec(backup_metadata).expect("BackupAlgorithm::to_vec always works"),
 		)?;
+		self.backupid_count.insert(&key, &0_u64.to_be_bytes())?;
 		self.backupid_etag
 			.insert(&key, &services().globals.next_count()?.to_be_bytes())?;
 		Ok(version)
@@ -50,6 +53,7 @@ impl Data {
 		key.extend_from_slice(version.as_bytes());
 
 		self.backupid_algorithm.remove(&key)?;
+		self.backupid_count.remove(&key)?;
 		self.backupid_etag.remove(&key)?;
 
 		key.push(0xFF);
@@ -61,6 +65,27 @@ impl Data {
 		Ok(())
 	}
 
+	/// Deletes every backup of a user, returning how many there were
+	pub(super) fn delete_all_backups(&self, user_id: &UserId) -> Result<usize> {
+		let mut prefix = user_id.as_bytes().to_vec();
+		prefix.push(0xFF);
+
+		let versions = self
+			.backupid_algorithm
+			.scan_prefix(prefix.clone())
+			.map(|(key, _)| {
+				utils::string_from_bytes(&key[prefix.len()..])
+					.map_err(|_| Error::bad_database("backupid_algorithm key is invalid."))
+			})
+			.collect::<Result<Vec<_>>>()?;
+
+		for version in &versions {
+			self.delete_backup(user_id, version)?;
+		}
+
+		Ok(versions.len())
+	}
+
 	pub(super) fn update_backup(
 		&self, user_id: &UserId, version: &str, backup_metadata: &Raw<BackupAlgorithm>,
 	) -> Result<String> {
@@ -68,10 +93,23 @@ impl Data {
 		key.push(0xFF);
 		key.extend_from_slice(version.as_bytes());
 
-		if self.backupid_algorithm.get(&key)?.is_none() {
-			return Err(Error::BadRequest(ErrorKind::NotFound, "Tried to update nonexistent backup."));
+		let current = self
+			.backupid_algorithm
+			.get(&key)?
+			.ok_or(Error::BadRequest(ErrorKind::NotFound, "Tried to update nonexistent backup."))?;
+
+		let current: serde_json::Value = serde_json::from_slice(&current)
+			.map_err(|_| Error::bad_database("Algorithm in backupid_algorithm is invalid."))?;
+		let new: serde_json::Value = serde_json::from_str(backup_metadata.json().get())
+			.map_err(|_| Error::BadRequest(ErrorKind::BadJson, "Backup algorithm is invalid."))?;
+		if current.get("algorithm") != new.get("algorithm") {
+			return Err(Error::BadRequest(
+				ErrorKind::InvalidParam,
+				"The algorithm of an existing backup cannot be changed.",
+			));
 		}
 
+		// Only the auth_data is replaced; the stored keys are kept.
 		self.backupid_algorithm
 			.insert(&key, backup_metadata.json().get().as_bytes())?;
 		self.backupid_etag
@@ -151,18 +189,22 @@ impl Data {
 			return Err(Error::BadRequest(ErrorKind::NotFound, "Tried to update nonexistent backup."));
 		}
 
-		self.backupid_etag
-			.insert(&key, &services().globals.next_count()?.to_be_bytes())?;
+		let mut count = self.count_keys(user_id, version)?;
+		let backup_key = key.clone();
 
 		key.push(0xFF);
 		key.extend_from_slice(room_id.as_bytes());
 		key.push(0xFF);
 		key.extend_from_slice(session_id.as_bytes());
 
+		if self.backupkeyid_backup.get(&key)?.is_none() {
+			count = count.saturating_add(1);
+		}
+
 		self.backupkeyid_backup
 			.insert(&key, key_data.json().get().as_bytes())?;
 
-		Ok(())
+		self.keys_changed(&backup_key, count)
 	}
 
 	pub(super) fn count_keys(&self, user_id: &UserId, version: &str) -> Result<usize> {
@@ -170,9 +212,30 @@ impl Data {
 		prefix.push(0xFF);
 		prefix.extend_from_slice(version.as_bytes());
 
+		if let Some(bytes) = self.backupid_count.get(&prefix)? {
+			return utils::u64_from_bytes(&bytes)
+				.map(|count| count as usize)
+				.map_err(|_| Error::bad_database("Count in backupid_count is invalid."));
+		}
+
+		// Backups created before the count was tracked
+		prefix.push(0xFF);
 		Ok(self.backupkeyid_backup.scan_prefix(prefix).count())
 	}
 
+	/// Records the new key count of a backup and bumps its etag, so clients
+	/// notice the keys changed.
+	fn keys_changed(&self, backup_key: &[u8], count: usize) -> Result<()> {
+		if self.backupid_algorithm.get(backup_key)?.is_none() {
+			return Ok(());
+		}
+
+		self.backupid_count
+			.insert(backup_key, &(count as u64).to_be_bytes())?;
+		self.backupid_etag
+			.insert(backup_key, &services().globals.next_count()?.to_be_bytes())
+	}
+
 	pub(super) fn get_etag(&self, user_id: &UserId, version: &str) -> Result<String> {
 		let mut key = user_id.as_bytes().to_vec();
 		key.push(0xFF);
@@ -293,28 +356,33 @@ impl Data {
 		let mut key = user_id.as_bytes().to_vec();
 		key.push(0xFF);
 		key.extend_from_slice(version.as_bytes());
+		let backup_key = key.clone();
 		key.push(0xFF);
 
 		for (outdated_key, _) in self.backupkeyid_backup.scan_prefix(key) {
 			self.backupkeyid_backup.remove(&outdated_key)?;
 		}
 
-		Ok(())
+		self.keys_changed(&backup_key, 0)
 	}
 
 	pub(super) fn delete_room_keys(&self, user_id: &UserId, version: &str, room_id: &RoomId) -> Result<()> {
 		let mut key = user_id.as_bytes().to_vec();
 		key.push(0xFF);
 		key.extend_from_slice(version.as_bytes());
+		let backup_key = key.clone();
 		key.push(0xFF);
 		key.extend_from_slice(room_id.as_bytes());
 		key.push(0xFF);
 
+		let mut removed: usize = 0;
 		for (outdated_key, _) in self.backupkeyid_backup.scan_prefix(key) {
 			self.backupkeyid_backup.remove(&outdated_key)?;
+			removed = removed.saturating_add(1);
 		}
 
-		Ok(())
+		let count = self.count_keys(user_id, version)?.saturating_sub(removed);
+		self.keys_changed(&backup_key, count)
 	}
 
 	pub(super) fn delete_room_key(
@@ -323,15 +391,19 @@ impl Data {
 		let mut key = user_id.as_bytes().to_vec();
 		key.push(0xFF);
 		key.extend_from_slice(version.as_bytes());
+		let backup_key = key.clone();
 		key.push(0xFF);
 		key.extend_from_slice(room_id.as_bytes());
 		key.push(0xFF);
 		key.extend_from_slice(session_id.as_bytes());
 
-		for (outdated_key, _) in self.backupkeyid_backup.scan_prefix(key) {
-			self.backupkeyid_backup.remove(&outdated_key)?;
+		if self.backupkeyid_backup.get(&key)?.is_none() {
+			return Ok(());
 		}
 
-		Ok(())
+		self.backupkeyid_backup.remove(&key)?;
+
+		let count = self.count_keys(user_id, version)?.saturating_sub(1);
+		self.keys_changed(&backup_key, count)
 	}
 }
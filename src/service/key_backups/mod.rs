@@ -2,7 +2,10 @@ mod data;
 
 use std::{collections::BTreeMap, sync::Arc};
 
-use conduit::{Result, Server};
+use conduit::{
+	utils::{mutex_map, MutexMap},
+	Result, Server,
+};
 use data::Data;
 use database::Database;
 use ruma::{
@@ -13,12 +16,17 @@ use ruma::{
 
 pub struct Service {
 	db: Data,
+
+	/// Held while the keys of a backup change, so its key count stays
+	/// accurate
+	backups: MutexMap<Vec<u8>, ()>,
 }
 
 impl Service {
 	pub fn build(_server: &Arc<Server>, db: &Arc<Database>) -> Result<Self> {
 		Ok(Self {
 			db: Data::new(db),
+			backups: MutexMap::new(),
 		})
 	}
 
@@ -26,10 +34,14 @@ impl Service {
 		self.db.create_backup(user_id, backup_metadata)
 	}
 
-	pub fn delete_backup(&self, user_id: &UserId, version: &str) -> Result<()> {
+	pub async fn delete_backup(&self, user_id: &UserId, version: &str) -> Result<()> {
+		let _lock = self.lock(user_id, version).await;
 		self.db.delete_backup(user_id, version)
 	}
 
+	/// Deletes every backup of a user, returning how many there were
+	pub fn delete_all_backups(&self, user_id: &UserId) -> Result<usize> { self.db.delete_all_backups(user_id) }
+
 	pub fn update_backup(
 		&self, user_id: &UserId, version: &str, backup_metadata: &Raw<BackupAlgorithm>,
 	) -> Result<String> {
@@ -48,9 +60,10 @@ impl Service {
 		self.db.get_backup(user_id, version)
 	}
 
-	pub fn add_key(
+	pub async fn add_key(
 		&self, user_id: &UserId, version: &str, room_id: &RoomId, session_id: &str, key_data: &Raw<KeyBackupData>,
 	) -> Result<()> {
+		let _lock = self.lock(user_id, version).await;
 		self.db
 			.add_key(user_id, version, room_id, session_id, key_data)
 	}
@@ -75,16 +88,28 @@ impl Service {
 		self.db.get_session(user_id, version, room_id, session_id)
 	}
 
-	pub fn delete_all_keys(&self, user_id: &UserId, version: &str) -> Result<()> {
+	pub async fn delete_all_keys(&self, user_id: &UserId, version: &str) -> Result<()> {
+		let _lock = self.lock(user_id, version).await;
 		self.db.delete_all_keys(user_id, version)
 	}
 
-	pub fn delete_room_keys(&self, user_id: &UserId, version: &str, room_id: &RoomId) -> Result<()> {
+	pub async fn delete_room_keys(&self, user_id: &UserId, version: &str, room_id: &RoomId) -> Result<()> {
+		let _lock = self.lock(user_id, version).await;
 		self.db.delete_room_keys(user_id, version, room_id)
 	}
 
-	pub fn delete_room_key(&self, user_id: &UserId, version: &str, room_id: &RoomId, session_id: &str) -> Result<()> {
+	pub async fn delete_room_key(
+		&self, user_id: &UserId, version: &str, room_id: &RoomId, session_id: &str,
+	) -> Result<()> {
+		let _lock = self.lock(user_id, version).await;
 		self.db
 			.delete_room_key(user_id, version, room_id, session_id)
 	}
+
+	async fn lock(&self, user_id: &UserId, version: &str) -> mutex_map::Guard<()> {
+		let mut backup = user_id.as_bytes().to_vec();
+		backup.push(0xFF);
+		backup.extend_from_slice(version.as_bytes());
+		self.backups.lock(backup.as_slice()).await
+	}
 }
@@ -1,22 +1,40 @@
-use std::sync::Arc;
+use std::{mem::size_of, sync::Arc};
 
 use conduit::{debug, debug_info, Error, Result};
 use database::{Database, Map};
-use ruma::api::client::error::ErrorKind;
+use ruma::{api::client::error::ErrorKind, UserId};
 
-use crate::{media::UrlPreviewData, utils::string_from_bytes};
+use super::thumbnail::Variant;
+use crate::{
+	media::UrlPreviewData,
+	utils::{self, string_from_bytes},
+};
 
 pub(crate) struct Data {
+	mediablob_cold: Arc<Map>,
+	mediablob_lastaccess: Arc<Map>,
+	mediablob_refs: Arc<Map>,
+	mediaid_blob: Arc<Map>,
+	mediaid_created: Arc<Map>,
 	mediaid_file: Arc<Map>,
+	mediaid_thumbnail: Arc<Map>,
 	mediaid_user: Arc<Map>,
+	sha256_mediablob: Arc<Map>,
 	url_previews: Arc<Map>,
 }
 
 impl Data {
 	pub(super) fn new(db: &Arc<Database>) -> Self {
 		Self {
+			mediablob_cold: db["mediablob_cold"].clone(),
+			mediablob_lastaccess: db["mediablob_lastaccess"].clone(),
+			mediablob_refs: db["mediablob_refs"].clone(),
+			mediaid_blob: db["mediaid_blob"].clone(),
+			mediaid_created: db["mediaid_created"].clone(),
 			mediaid_file: db["mediaid_file"].clone(),
+			mediaid_thumbnail: db["mediaid_thumbnail"].clone(),
 			mediaid_user: db["mediaid_user"].clone(),
+			sha256_mediablob: db["sha256_mediablob"].clone(),
 			url_previews: db["url_previews"].clone(),
 		}
 	}
@@ -63,7 +81,7 @@ impl Data {
 
 		debug!("MXC db prefix: {prefix:?}");
 
-		for (key, _) in self.mediaid_file.scan_prefix(prefix) {
+		for (key, _) in self.mediaid_file.scan_prefix(prefix.clone()) {
 			debug!("Deleting key: {:?}", key);
 			self.mediaid_file.remove(&key)?;
 		}
@@ -77,9 +95,41 @@ impl Data {
 			}
 		}
 
+		self.mediaid_created.remove(mxc.as_bytes())?;
+
+		for (key, _) in self.mediaid_thumbnail.scan_prefix(prefix) {
+			self.mediaid_thumbnail.remove(&key)?;
+		}
+
 		Ok(())
 	}
 
+	/// Records when the media was first stored on this server (milliseconds
+	/// since the unix epoch).
+	pub(super) fn set_created(&self, mxc: &str, timestamp: u64) -> Result<()> {
+		self.mediaid_created
+			.insert(mxc.as_bytes(), &timestamp.to_be_bytes())
+	}
+
+	/// Media stored before creation times were recorded has no entry.
+	pub(super) fn get_created(&self, mxc: &str) -> Result<Option<u64>> {
+		self.mediaid_created
+			.get(mxc.as_bytes())?
+			.map(|bytes| {
+				utils::u64_from_bytes(&bytes).map_err(|_| Error::bad_database("Invalid timestamp in mediaid_created."))
+			})
+			.transpose()
+	}
+
+	/// Gets the MXC URIs of all media uploaded by the given local user
+	pub(super) fn get_all_user_mxcs(&self, user_id: &UserId) -> Vec<String> {
+		self.mediaid_user
+			.iter()
+			.filter(|(_, user)| user.as_slice() == user_id.as_bytes())
+			.filter_map(|(mxc, _)| string_from_bytes(&mxc).ok())
+			.collect()
+	}
+
 	/// Searches for all files with the given MXC
 	pub(super) fn search_mxc_metadata_prefix(&self, mxc: &str) -> Result<Vec<Vec<u8>>> {
 		debug!("MXC URI: {:?}", mxc);
@@ -144,6 +194,167 @@ impl Data {
 		Ok((content_disposition, content_type, key))
 	}
 
+	/// Key of the thumbnail of the media with the given size in
+	/// `mediaid_thumbnail`, which is also the file key of animated thumbnails
+	pub(super) fn thumbnail_key(mxc: &str, width: u32, height: u32, animated: bool) -> Vec<u8> {
+		let mut key = mxc.as_bytes().to_vec();
+		key.push(0xFF);
+		key.extend_from_slice(&width.to_be_bytes());
+		key.extend_from_slice(&height.to_be_bytes());
+		key.push(u8::from(animated));
+		key
+	}
+
+	/// Which file is served for the thumbnail of the media with the given size,
+	/// `None` when it was not generated yet
+	pub(super) fn get_thumbnail_variant(
+		&self, mxc: &str, width: u32, height: u32, animated: bool,
+	) -> Result<Option<Variant>> {
+		self.mediaid_thumbnail
+			.get(&Self::thumbnail_key(mxc, width, height, animated))?
+			.map(|value| {
+				value
+					.first()
+					.copied()
+					.and_then(Variant::from_byte)
+					.ok_or_else(|| Error::bad_database("Invalid thumbnail variant in mediaid_thumbnail."))
+			})
+			.transpose()
+	}
+
+	pub(super) fn set_thumbnail_variant(
+		&self, mxc: &str, width: u32, height: u32, animated: bool, variant: Variant,
+	) -> Result<()> {
+		self.mediaid_thumbnail
+			.insert(&Self::thumbnail_key(mxc, width, height, animated), &[variant.to_byte()])
+	}
+
+	/// Keys of the animated thumbnails of the media
+	pub(super) fn animated_thumbnail_keys(&self, mxc: &str) -> Vec<Vec<u8>> {
+		let mut prefix = mxc.as_bytes().to_vec();
+		prefix.push(0xFF);
+
+		self.mediaid_thumbnail
+			.scan_prefix(prefix)
+			.filter(|(_, value)| value.first().copied().and_then(Variant::from_byte) == Some(Variant::Animated))
+			.map(|(key, _)| key)
+			.collect()
+	}
+
+	/// Key of the media whose file holds the content of the given media, which
+	/// is the media itself unless it was deduplicated
+	pub(super) fn get_blob_key(&self, key: &[u8]) -> Result<Vec<u8>> {
+		Ok(self.mediaid_blob.get(key)?.unwrap_or_else(|| key.to_vec()))
+	}
+
+	/// Whether the media takes part in deduplication, either holding content
+	/// or referring to the content of other media
+	pub(super) fn is_deduplicated(&self, key: &[u8]) -> Result<bool> {
+		Ok(self.mediaid_blob.get(key)?.is_some() || self.mediablob_refs.get(key)?.is_some())
+	}
+
+	/// Finds the media holding content with the given SHA-256
+	pub(super) fn find_blob(&self, sha256: &[u8]) -> Result<Option<Vec<u8>>> { self.sha256_mediablob.get(sha256) }
+
+	/// Records that the media holds content with the given SHA-256, referred to
+	/// by itself only.
+	pub(super) fn add_blob(&self, blob: &[u8], sha256: &[u8]) -> Result<()> {
+		self.set_blob_refs(blob, 1, sha256)?;
+		self.sha256_mediablob.insert(sha256, blob)
+	}
+
+	/// Makes the media refer to the content held by `blob` instead of its own
+	/// file.
+	pub(super) fn add_blob_reference(&self, key: &[u8], blob: &[u8]) -> Result<()> {
+		let (refs, sha256) = self
+			.get_blob_refs(blob)?
+			.ok_or_else(|| Error::bad_database("Deduplicated media has no reference count."))?;
+
+		self.set_blob_refs(blob, refs.saturating_add(1), &sha256)?;
+		self.mediaid_blob.insert(key, blob)
+	}
+
+	/// Drops the reference of the media to its content. Returns whether the
+	/// content is not referred to anymore, so its file is to be removed.
+	pub(super) fn remove_blob_reference(&self, key: &[u8]) -> Result<bool> {
+		let blob = self.get_blob_key(key)?;
+		self.mediaid_blob.remove(key)?;
+
+		let Some((refs, sha256)) = self.get_blob_refs(&blob)? else {
+			return Ok(true);
+		};
+
+		if refs > 1 {
+			self.set_blob_refs(&blob, refs.saturating_sub(1), &sha256)?;
+			return Ok(false);
+		}
+
+		self.mediablob_refs.remove(&blob)?;
+		if self.sha256_mediablob.get(&sha256)?.as_deref() == Some(blob.as_slice()) {
+			self.sha256_mediablob.remove(&sha256)?;
+		}
+
+		Ok(true)
+	}
+
+	fn get_blob_refs(&self, blob: &[u8]) -> Result<Option<(u64, Vec<u8>)>> {
+		self.mediablob_refs
+			.get(blob)?
+			.map(|value| {
+				let refs = value
+					.get(..size_of::<u64>())
+					.map(utils::u64_from_bytes)
+					.transpose()
+					.ok()
+					.flatten()
+					.ok_or_else(|| Error::bad_database("Invalid reference count in mediablob_refs."))?;
+				let sha256 = value.get(size_of::<u64>()..).unwrap_or_default();
+
+				Ok((refs, sha256.to_vec()))
+			})
+			.transpose()
+	}
+
+	fn set_blob_refs(&self, blob: &[u8], refs: u64, sha256: &[u8]) -> Result<()> {
+		let mut value = refs.to_be_bytes().to_vec();
+		value.extend_from_slice(sha256);
+		self.mediablob_refs.insert(blob, &value)
+	}
+
+	/// Whether the file of the media is stored in the cold storage directory
+	pub(super) fn is_cold(&self, blob: &[u8]) -> Result<bool> { Ok(self.mediablob_cold.get(blob)?.is_some()) }
+
+	pub(super) fn set_cold(&self, blob: &[u8], cold: bool) -> Result<()> {
+		if cold {
+			self.mediablob_cold.insert(blob, &[])
+		} else {
+			self.mediablob_cold.remove(blob)
+		}
+	}
+
+	/// When the file of the media was last read (milliseconds since the unix
+	/// epoch). Media not read since access times were recorded has no entry.
+	pub(super) fn get_last_access(&self, blob: &[u8]) -> Result<Option<u64>> {
+		self.mediablob_lastaccess
+			.get(blob)?
+			.map(|bytes| {
+				utils::u64_from_bytes(&bytes)
+					.map_err(|_| Error::bad_database("Invalid timestamp in mediablob_lastaccess."))
+			})
+			.transpose()
+	}
+
+	pub(super) fn set_last_access(&self, blob: &[u8], timestamp: u64) -> Result<()> {
+		self.mediablob_lastaccess
+			.insert(blob, &timestamp.to_be_bytes())
+	}
+
+	/// Forgets the storage tier and access time of a removed file
+	pub(super) fn remove_tier(&self, blob: &[u8]) -> Result<()> {
+		self.mediablob_cold.remove(blob)?;
+		self.mediablob_lastaccess.remove(blob)
+	}
+
 	/// Gets all the media keys in our database (this includes all the metadata
 	/// associated with it such as width, height, content-type, etc)
 	pub(crate) fn get_all_media_keys(&self) -> Vec<Vec<u8>> { self.mediaid_file.iter().map(|(key, _)| key).collect() }
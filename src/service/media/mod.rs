@@ -1,22 +1,32 @@
 mod data;
 mod tests;
-
-use std::{collections::HashMap, io::Cursor, path::PathBuf, sync::Arc, time::SystemTime};
+mod thumbnail;
+mod tiering;
+
+use std::{
+	collections::{BTreeSet, HashMap},
+	path::{Path, PathBuf},
+	sync::{Arc, Mutex as StdMutex},
+	time::{Duration, SystemTime, UNIX_EPOCH},
+};
 
 use base64::{engine::general_purpose, Engine as _};
-use conduit::{debug, debug_error, error, utils, Error, Result, Server};
+use conduit::{debug, debug_error, debug_warn, error, utils, Error, Result, Server};
 use data::Data;
 use database::Database;
-use image::imageops::FilterType;
-use ruma::{OwnedMxcUri, OwnedUserId};
+use loole::{Receiver, Sender};
+use ruma::{OwnedMxcUri, OwnedUserId, UserId};
 use serde::Serialize;
 use tokio::{
 	fs,
-	io::{AsyncReadExt, AsyncWriteExt, BufReader},
+	io::AsyncWriteExt,
 	sync::{Mutex, RwLock},
+	task::JoinHandle,
 };
 
-use crate::services;
+pub use self::tiering::TierStatus;
+use self::{thumbnail::Variant, tiering::file_name};
+use crate::{admin::jobs::Job, services};
 
 #[derive(Debug)]
 pub struct FileMeta {
@@ -46,14 +56,37 @@ pub struct Service {
 	server: Arc<Server>,
 	pub(crate) db: Data,
 	pub url_preview_mutex: RwLock<HashMap<String, Arc<Mutex<()>>>>,
+
+	/// Serializes changes to the references of deduplicated media
+	dedup_mutex: Mutex<()>,
+
+	/// Serializes moves of files between the storage tiers
+	tiering_mutex: Mutex<()>,
+
+	/// Uploaded media whose thumbnails are to be pre-generated
+	thumbnail_sender: Sender<String>,
+	thumbnail_receiver: Mutex<Receiver<String>>,
+	thumbnail_handler_join: Mutex<Option<JoinHandle<()>>>,
+
+	/// Moves media not read for a while to `media_cold_path`
+	tiering_handler_join: Mutex<Option<JoinHandle<()>>>,
+	tiering_progress: StdMutex<Option<String>>,
 }
 
 impl Service {
 	pub fn build(server: &Arc<Server>, db: &Arc<Database>) -> Result<Self> {
+		let (thumbnail_sender, thumbnail_receiver) = loole::bounded(server.config.media_thumbnail_queue_size);
 		Ok(Self {
 			server: server.clone(),
 			db: Data::new(db),
 			url_preview_mutex: RwLock::new(HashMap::new()),
+			dedup_mutex: Mutex::new(()),
+			tiering_mutex: Mutex::new(()),
+			thumbnail_sender,
+			thumbnail_receiver: Mutex::new(thumbnail_receiver),
+			thumbnail_handler_join: Mutex::new(None),
+			tiering_handler_join: Mutex::new(None),
+			tiering_progress: StdMutex::new(None),
 		})
 	}
 
@@ -63,6 +96,7 @@ impl Service {
 		content_type: Option<&str>, file: &[u8],
 	) -> Result<()> {
 		// Width, Height = 0 if it's not a thumbnail
+		let uploaded = sender_user.is_some();
 		let key = if let Some(user) = sender_user {
 			self.db
 				.create_file_metadata(Some(user.as_str()), mxc, 0, 0, content_disposition, content_type)?
@@ -71,16 +105,44 @@ impl Service {
 				.create_file_metadata(None, mxc, 0, 0, content_disposition, content_type)?
 		};
 
-		//TODO: Dangling metadata in database if creation fails
-		let mut f = self.create_media_file(&key).await?;
-		f.write_all(file).await?;
+		self.db.set_created(mxc, utils::millis_since_unix_epoch())?;
+
+		self.store_file(mxc, &key, file).await?;
+		if uploaded {
+			self.queue_thumbnails(mxc);
+		}
 
 		Ok(())
 	}
 
+	async fn store_file(&self, mxc: &str, key: &[u8], file: &[u8]) -> Result<()> {
+		if !self.server.config.media_deduplicate {
+			//TODO: Dangling metadata in database if creation fails
+			let mut f = self.create_media_file(key).await?;
+			f.write_all(file).await?;
+
+			return Ok(());
+		}
+
+		let sha256 = <sha2::Sha256 as sha2::Digest>::digest(file);
+		let _lock = self.dedup_mutex.lock().await;
+		if let Some(blob) = self.db.find_blob(&sha256)?.filter(|blob| *blob != key) {
+			debug!(?mxc, blob = ?encode_key(&blob), "Storing a reference to identical media");
+			return self.db.add_blob_reference(key, &blob);
+		}
+
+		let mut f = self.create_media_file(key).await?;
+		f.write_all(file).await?;
+		self.db.add_blob(key, &sha256)
+	}
+
 	/// Deletes a file in the database and from the media directory via an MXC
 	pub async fn delete(&self, mxc: &str) -> Result<()> {
 		if let Ok(keys) = self.db.search_mxc_metadata_prefix(mxc) {
+			for key in self.db.animated_thumbnail_keys(mxc) {
+				self.remove_media_file(&key).await?;
+			}
+
 			for key in keys {
 				self.remove_media_file(&key).await?;
 
@@ -121,11 +183,8 @@ impl Service {
 	/// Downloads a file.
 	pub async fn get(&self, mxc: &str) -> Result<Option<FileMeta>> {
 		if let Ok((content_disposition, content_type, key)) = self.db.search_file_metadata(mxc, 0, 0) {
-			let mut file = Vec::new();
-			let path = self.get_media_file(&key);
-			BufReader::new(fs::File::open(path).await?)
-				.read_to_end(&mut file)
-				.await?;
+			let file = self.read_media_file(&key).await?;
+			self.accessed(&key);
 
 			Ok(Some(FileMeta {
 				content_disposition,
@@ -239,6 +298,74 @@ impl Service {
 		Ok(deletion_count)
 	}
 
+	/// Returns when the media was first stored on this server. Media stored
+	/// before this was recorded falls back to the file's timestamps.
+	pub async fn created_at(&self, mxc: &str) -> Result<Option<SystemTime>> {
+		if let Some(created) = self.db.get_created(mxc)? {
+			return Ok(UNIX_EPOCH.checked_add(Duration::from_millis(created)));
+		}
+
+		let Ok((_, _, key)) = self.db.search_file_metadata(mxc, 0, 0) else {
+			return Ok(None);
+		};
+
+		let file_metadata = fs::metadata(self.get_media_file(&key)).await?;
+		match file_metadata.created() {
+			Ok(created) => Ok(Some(created)),
+			Err(err) if err.kind() == std::io::ErrorKind::Unsupported => Ok(Some(file_metadata.modified()?)),
+			Err(err) => Err(err.into()),
+		}
+	}
+
+	/// Returns the total size in bytes of a media file and its thumbnails on
+	/// the filesystem.
+	pub async fn file_size(&self, mxc: &str) -> Result<u64> {
+		let mut size: u64 = 0;
+		for key in self.db.search_mxc_metadata_prefix(mxc)? {
+			if let Ok(file_metadata) = fs::metadata(self.get_media_file(&key)).await {
+				size = size.saturating_add(file_metadata.len());
+			}
+		}
+
+		Ok(size)
+	}
+
+	/// Lists the MXC URIs of all media uploaded by a local user.
+	pub fn list_user_media(&self, user_id: &UserId) -> Vec<OwnedMxcUri> {
+		self.db
+			.get_all_user_mxcs(user_id)
+			.into_iter()
+			.map(OwnedMxcUri::from)
+			.collect()
+	}
+
+	/// Lists the MXC URIs of all remote media first stored on this server
+	/// before the given time.
+	pub async fn remote_media_before(&self, before: SystemTime) -> Result<Vec<OwnedMxcUri>> {
+		let remote_mxcs = self
+			.db
+			.get_all_media_keys()
+			.iter()
+			.filter_map(|key| key.split(|&b| b == 0xFF).next())
+			.filter_map(|bytes| utils::string_from_bytes(bytes).ok())
+			.collect::<BTreeSet<_>>();
+
+		let mut mxcs = Vec::new();
+		for mxc in remote_mxcs.into_iter().map(OwnedMxcUri::from) {
+			if mxc.server_name() == Ok(services().globals.server_name()) {
+				continue;
+			}
+
+			match self.created_at(mxc.as_str()).await {
+				Ok(Some(created)) if created < before => mxcs.push(mxc),
+				Ok(_) => {},
+				Err(e) => debug_error!(%mxc, "Failed to get media creation time: {e}"),
+			}
+		}
+
+		Ok(mxcs)
+	}
+
 	/// Returns width, height of the thumbnail and whether it should be cropped.
 	/// Returns None when the server should send the original file.
 	pub fn thumbnail_properties(&self, width: u32, height: u32) -> Option<(u32, u32, bool)> {
@@ -264,112 +391,218 @@ impl Service {
 	/// - Server creates the thumbnail and sends it to the user
 	///
 	/// For width,height <= 96 the server uses another thumbnailing algorithm
-	/// which crops the image afterwards.
-	pub async fn get_thumbnail(&self, mxc: &str, width: u32, height: u32) -> Result<Option<FileMeta>> {
-		let (width, height, crop) = self
-			.thumbnail_properties(width, height)
-			.unwrap_or((0, 0, false)); // 0, 0 because that's the original file
-
-		if let Ok((content_disposition, content_type, key)) = self.db.search_file_metadata(mxc, width, height) {
-			// Using saved thumbnail
-			let mut file = Vec::new();
-			let path = self.get_media_file(&key);
-			fs::File::open(path).await?.read_to_end(&mut file).await?;
+	/// which crops the image afterwards. Which file was served for a size is
+	/// recorded, so later requests neither decode the original again nor
+	/// look for thumbnails which were never generated.
+	pub async fn get_thumbnail(&self, mxc: &str, width: u32, height: u32, animated: bool) -> Result<Option<FileMeta>> {
+		let Some((width, height, crop)) = self.thumbnail_properties(width, height) else {
+			return self.get(mxc).await;
+		};
 
-			Ok(Some(FileMeta {
+		match self
+			.db
+			.get_thumbnail_variant(mxc, width, height, animated)?
+		{
+			Some(Variant::Original) => return self.get(mxc).await,
+			Some(Variant::Static) => {
+				if let Some(thumbnail) = self.get_static_thumbnail(mxc, width, height).await? {
+					return Ok(Some(thumbnail));
+				}
+			},
+			Some(Variant::Animated) => {
+				if let Some(thumbnail) = self.get_animated_thumbnail(mxc, width, height).await? {
+					return Ok(Some(thumbnail));
+				}
+			},
+			None if !animated => {
+				// thumbnails generated before variants were recorded, and those of remote
+				// media fetched over federation
+				if let Some(thumbnail) = self.get_static_thumbnail(mxc, width, height).await? {
+					self.db
+						.set_thumbnail_variant(mxc, width, height, false, Variant::Static)?;
+					return Ok(Some(thumbnail));
+				}
+			},
+			None => {},
+		}
+
+		let Some(original) = self.get(mxc).await? else {
+			return Ok(None);
+		};
+
+		let FileMeta {
+			content_disposition,
+			content_type,
+			file,
+		} = original;
+
+		let (thumbnail, file) =
+			tokio::task::spawn_blocking(move || (thumbnail::generate(&file, width, height, crop, animated), file))
+				.await
+				.map_err(|e| Error::Err(format!("Thumbnail generation panicked: {e}")))?;
+
+		let Some(thumbnail) = thumbnail? else {
+			self.db
+				.set_thumbnail_variant(mxc, width, height, animated, Variant::Original)?;
+			return Ok(Some(FileMeta {
 				content_disposition,
 				content_type,
-				file: file.clone(),
-			}))
-		} else if let Ok((content_disposition, content_type, key)) = self.db.search_file_metadata(mxc, 0, 0) {
-			// Generate a thumbnail
-			let mut file = Vec::new();
-			let path = self.get_media_file(&key);
-			fs::File::open(path).await?.read_to_end(&mut file).await?;
-
-			if let Ok(image) = image::load_from_memory(&file) {
-				let original_width = image.width();
-				let original_height = image.height();
-				if width > original_width || height > original_height {
-					return Ok(Some(FileMeta {
-						content_disposition,
-						content_type,
-						file: file.clone(),
-					}));
-				}
+				file,
+			}));
+		};
+
+		if thumbnail.animated {
+			let key = Data::thumbnail_key(mxc, width, height, true);
+			let mut f = self.create_media_file(&key).await?;
+			f.write_all(&thumbnail.file).await?;
+			self.db
+				.set_thumbnail_variant(mxc, width, height, true, Variant::Animated)?;
+
+			return Ok(Some(FileMeta {
+				content_disposition,
+				content_type: Some(thumbnail::ANIMATED_CONTENT_TYPE.to_owned()),
+				file: thumbnail.file,
+			}));
+		}
+
+		// Save thumbnail in database so we don't have to generate it again next time
+		let thumbnail_key = self.db.create_file_metadata(
+			None,
+			mxc,
+			width,
+			height,
+			content_disposition.as_deref(),
+			content_type.as_deref(),
+		)?;
+
+		let mut f = self.create_media_file(&thumbnail_key).await?;
+		f.write_all(&thumbnail.file).await?;
+		self.db
+			.set_thumbnail_variant(mxc, width, height, false, Variant::Static)?;
+		if animated {
+			// the original is not animated
+			self.db
+				.set_thumbnail_variant(mxc, width, height, true, Variant::Static)?;
+		}
 
-				let thumbnail = if crop {
-					image.resize_to_fill(width, height, FilterType::CatmullRom)
-				} else {
-					let (exact_width, exact_height) = {
-						// Copied from image::dynimage::resize_dimensions
-						//
-						// https://github.com/image-rs/image/blob/6edf8ae492c4bb1dacb41da88681ea74dab1bab3/src/math/utils.rs#L5-L11
-						// Calculates the width and height an image should be
-						// resized to. This preserves aspect ratio, and based
-						// on the `fill` parameter will either fill the
-						// dimensions to fit inside the smaller constraint
-						// (will overflow the specified bounds on one axis to
-						// preserve aspect ratio), or will shrink so that both
-						// dimensions are completely contained within the given
-						// `width` and `height`, with empty space on one axis.
-						let ratio = u64::from(original_width) * u64::from(height);
-						let nratio = u64::from(width) * u64::from(original_height);
-
-						let use_width = nratio <= ratio;
-						let intermediate = if use_width {
-							u64::from(original_height) * u64::from(width) / u64::from(original_width)
-						} else {
-							u64::from(original_width) * u64::from(height) / u64::from(original_height)
-						};
-						if use_width {
-							if u32::try_from(intermediate).is_ok() {
-								(width, intermediate as u32)
-							} else {
-								((u64::from(width) * u64::from(u32::MAX) / intermediate) as u32, u32::MAX)
-							}
-						} else if u32::try_from(intermediate).is_ok() {
-							(intermediate as u32, height)
-						} else {
-							(u32::MAX, (u64::from(height) * u64::from(u32::MAX) / intermediate) as u32)
-						}
-					};
-
-					image.thumbnail_exact(exact_width, exact_height)
-				};
-
-				let mut thumbnail_bytes = Vec::new();
-				thumbnail.write_to(&mut Cursor::new(&mut thumbnail_bytes), image::ImageFormat::Png)?;
-
-				// Save thumbnail in database so we don't have to generate it again next time
-				let thumbnail_key = self.db.create_file_metadata(
-					None,
-					mxc,
-					width,
-					height,
-					content_disposition.as_deref(),
-					content_type.as_deref(),
-				)?;
-
-				let mut f = self.create_media_file(&thumbnail_key).await?;
-				f.write_all(&thumbnail_bytes).await?;
-
-				Ok(Some(FileMeta {
-					content_disposition,
-					content_type,
-					file: thumbnail_bytes.clone(),
-				}))
-			} else {
-				// Couldn't parse file to generate thumbnail, send original
-				Ok(Some(FileMeta {
-					content_disposition,
-					content_type,
-					file: file.clone(),
-				}))
+		Ok(Some(FileMeta {
+			content_disposition,
+			content_type,
+			file: thumbnail.file,
+		}))
+	}
+
+	async fn get_static_thumbnail(&self, mxc: &str, width: u32, height: u32) -> Result<Option<FileMeta>> {
+		let Ok((content_disposition, content_type, key)) = self.db.search_file_metadata(mxc, width, height) else {
+			return Ok(None);
+		};
+
+		let file = self.read_media_file(&key).await?;
+		self.accessed(&key);
+
+		Ok(Some(FileMeta {
+			content_disposition,
+			content_type,
+			file,
+		}))
+	}
+
+	async fn get_animated_thumbnail(&self, mxc: &str, width: u32, height: u32) -> Result<Option<FileMeta>> {
+		let Ok((content_disposition, ..)) = self.db.search_file_metadata(mxc, 0, 0) else {
+			return Ok(None);
+		};
+
+		let key = Data::thumbnail_key(mxc, width, height, true);
+		let file = match self.read_media_file(&key).await {
+			Ok(file) => file,
+			Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+			Err(e) => return Err(e.into()),
+		};
+		self.accessed(&key);
+
+		Ok(Some(FileMeta {
+			content_disposition,
+			content_type: Some(thumbnail::ANIMATED_CONTENT_TYPE.to_owned()),
+			file,
+		}))
+	}
+
+	/// Queues generating the thumbnails of an uploaded image in the spec's
+	/// sizes. Nothing is queued when the queue is full; those thumbnails are
+	/// generated when first requested instead.
+	fn queue_thumbnails(&self, mxc: &str) {
+		if !self.server.config.media_pregenerate_thumbnails {
+			return;
+		}
+
+		if self.thumbnail_sender.try_send(mxc.to_owned()).is_err() {
+			debug!(?mxc, "Thumbnail queue is full, not pre-generating thumbnails");
+		}
+	}
+
+	pub async fn start_thumbnail_handler(&self) {
+		let handle = services().server.runtime().spawn(async move {
+			services().media.thumbnail_handler().await;
+		});
+
+		_ = self.thumbnail_handler_join.lock().await.insert(handle);
+	}
+
+	pub fn interrupt(&self) {
+		if !self.thumbnail_sender.is_closed() {
+			self.thumbnail_sender.close();
+		}
+	}
+
+	pub async fn close(&self) {
+		self.interrupt();
+		if let Some(handler_join) = self.thumbnail_handler_join.lock().await.take() {
+			if let Err(e) = handler_join.await {
+				error!("Failed to shutdown: {e:?}");
 			}
-		} else {
-			Ok(None)
 		}
+
+		if let Some(handler_join) = self.tiering_handler_join.lock().await.take() {
+			handler_join.abort();
+			_ = handler_join.await;
+		}
+	}
+
+	/// Generates queued thumbnails one media at a time, so bursts of uploads
+	/// only keep a single core busy
+	async fn thumbnail_handler(&self) {
+		let receiver = self.thumbnail_receiver.lock().await;
+		while let Ok(mxc) = receiver.recv_async().await {
+			if let Err(e) = self.pregenerate_thumbnails(&mxc).await {
+				debug_warn!(?mxc, "Failed to pre-generate thumbnails: {e}");
+			}
+		}
+	}
+
+	async fn pregenerate_thumbnails(&self, mxc: &str) -> Result<()> {
+		let Ok((_, content_type, _)) = self.db.search_file_metadata(mxc, 0, 0) else {
+			return Ok(());
+		};
+
+		if !content_type
+			.as_deref()
+			.is_some_and(|content_type| content_type.starts_with("image/"))
+		{
+			return Ok(());
+		}
+
+		for (width, height) in thumbnail::PREGENERATED_SIZES {
+			if self
+				.db
+				.get_thumbnail_variant(mxc, width, height, false)?
+				.is_none()
+			{
+				self.get_thumbnail(mxc, width, height, false).await?;
+			}
+		}
+
+		debug!(?mxc, "Pre-generated thumbnails");
+		Ok(())
 	}
 
 	pub async fn get_url_preview(&self, url: &str) -> Option<UrlPreviewData> { self.db.get_url_preview(url) }
@@ -390,11 +623,87 @@ impl Service {
 
 	pub async fn create_media_dir(&self) -> Result<()> {
 		let dir = self.get_media_dir();
-		Ok(fs::create_dir_all(dir).await?)
+		fs::create_dir_all(dir).await?;
+		if let Some(cold_dir) = &self.server.config.media_cold_path {
+			fs::create_dir_all(cold_dir).await?;
+		}
+
+		Ok(())
 	}
 
+	/// Deduplicates media stored before `media_deduplicate` was enabled: files
+	/// with the same content as another one are removed, their media
+	/// referring to the remaining copy. Returns the number of files removed
+	/// and the bytes reclaimed.
+	pub async fn dedup_existing(&self, job: &Job) -> Result<(usize, u64)> {
+		// thumbnails are not deduplicated
+		let keys: Vec<_> = self
+			.db
+			.get_all_media_keys()
+			.into_iter()
+			.filter(|key| {
+				key.iter()
+					.position(|&b| b == 0xFF)
+					.and_then(|sep| key.get(sep.saturating_add(1)..sep.saturating_add(9)))
+					.is_some_and(|dimensions| dimensions.iter().all(|&b| b == 0))
+			})
+			.collect();
+
+		let mut removed: usize = 0;
+		let mut reclaimed: u64 = 0;
+		for (i, key) in keys.iter().enumerate() {
+			if job.is_cancelled() {
+				break;
+			}
+
+			job.set_progress(format!(
+				"checked {i} of {} media files, removed {removed} duplicates ({reclaimed} bytes)",
+				keys.len()
+			));
+
+			let _lock = self.dedup_mutex.lock().await;
+			if self.db.is_deduplicated(key)? {
+				continue;
+			}
+
+			let file = match fs::read(self.blob_file(key)).await {
+				Ok(file) => file,
+				Err(e) => {
+					debug_error!(key = ?encode_key(key), "Failed to read media file: {e}");
+					continue;
+				},
+			};
+
+			let sha256 = <sha2::Sha256 as sha2::Digest>::digest(&file);
+			match self.db.find_blob(&sha256)? {
+				Some(blob) => {
+					self.db.add_blob_reference(key, &blob)?;
+					self.remove_blob_file(key).await?;
+					removed = removed.saturating_add(1);
+					reclaimed = reclaimed.saturating_add(file.len() as u64);
+				},
+				None => self.db.add_blob(key, &sha256)?,
+			}
+		}
+
+		Ok((removed, reclaimed))
+	}
+
+	/// Removes the file of the media, unless other media with the same
+	/// content still refer to it.
 	async fn remove_media_file(&self, key: &[u8]) -> Result<()> {
-		let path = self.get_media_file(key);
+		let _lock = self.dedup_mutex.lock().await;
+		let blob = self.db.get_blob_key(key)?;
+		if !self.db.remove_blob_reference(key)? {
+			debug!(key = ?encode_key(key), "Keeping media file referred to by other media");
+			return Ok(());
+		}
+
+		self.remove_blob_file(&blob).await
+	}
+
+	async fn remove_blob_file(&self, key: &[u8]) -> Result<()> {
+		let path = self.blob_file(key);
 		let legacy = self.get_media_file_b64(key);
 		debug!(?key, ?path, ?legacy, "Removing media file");
 
@@ -407,40 +716,54 @@ impl Service {
 			}
 		}
 
+		self.db.remove_tier(key)?;
 		Ok(file_rm?)
 	}
 
 	async fn create_media_file(&self, key: &[u8]) -> Result<fs::File> {
-		let path = self.get_media_file(key);
+		if self.is_cold(key) {
+			// replaced thumbnails are written to the media directory again
+			if let Some(cold) = self.get_media_file_cold(key) {
+				_ = fs::remove_file(&cold).await;
+			}
+			_ = fs::remove_file(self.get_media_file_b64(key)).await;
+			self.db.remove_tier(key)?;
+		}
+
+		let path = self.get_media_file_sha256(key);
 		debug!(?key, ?path, "Creating media file");
 
 		let file = fs::File::create(&path).await?;
 		if self.server.config.media_compat_file_link {
-			let legacy = self.get_media_file_b64(key);
-			if let Err(e) = fs::symlink(&path, &legacy).await {
-				debug_error!(
-					key = ?encode_key(key), ?path, ?legacy,
-					"Failed to create legacy media symlink: {e}"
-				);
-			}
+			self.create_legacy_link(key, &path).await;
 		}
 
 		Ok(file)
 	}
 
-	#[inline]
-	pub fn get_media_file(&self, key: &[u8]) -> PathBuf { self.get_media_file_sha256(key) }
+	async fn create_legacy_link(&self, key: &[u8], path: &Path) {
+		let legacy = self.get_media_file_b64(key);
+		if let Err(e) = fs::symlink(path, &legacy).await {
+			debug_error!(
+				key = ?encode_key(key), ?path, ?legacy,
+				"Failed to create legacy media symlink: {e}"
+			);
+		}
+	}
+
+	/// Path of the file holding the content of the media, which is the file of
+	/// other media with the same content if it was deduplicated, in the cold
+	/// storage directory if it was moved there
+	pub fn get_media_file(&self, key: &[u8]) -> PathBuf { self.blob_file(&self.blob_key(key)) }
+
+	/// Key of the media whose file holds the content of the given media
+	pub fn blob_key(&self, key: &[u8]) -> Vec<u8> { self.db.get_blob_key(key).unwrap_or_else(|_| key.to_vec()) }
 
 	/// new SHA256 file name media function. requires database migrated. uses
 	/// SHA256 hash of the base64 key as the file name
 	pub fn get_media_file_sha256(&self, key: &[u8]) -> PathBuf {
 		let mut r = self.get_media_dir();
-		// Using the hash of the base64 key as the filename
-		// This is to prevent the total length of the path from exceeding the maximum
-		// length in most filesystems
-		let digest = <sha2::Sha256 as sha2::Digest>::digest(key);
-		let encoded = encode_key(&digest);
-		r.push(encoded);
+		r.push(file_name(key));
 		r
 	}
 
@@ -0,0 +1,310 @@
+use std::io::Cursor;
+
+use conduit::{Error, Result};
+use image::{
+	codecs::{gif::GifDecoder, webp::WebPDecoder},
+	imageops::FilterType,
+	io::Reader as ImgReader,
+	AnimationDecoder, DynamicImage, Frame, ImageFormat,
+};
+use webp_animation::Encoder as WebPEncoder;
+
+/// Thumbnail sizes recommended by the spec, generated in the background for
+/// uploaded images
+pub(super) const PREGENERATED_SIZES: [(u32, u32); 5] = [(32, 32), (96, 96), (320, 240), (640, 480), (800, 600)];
+
+/// Animations with more frames are thumbnailed as still images
+const MAX_ANIMATED_FRAMES: u64 = 500;
+
+/// Images with more pixels are not decoded, and served as they are
+const MAX_PIXELS: u64 = 64 * 1024 * 1024;
+
+/// Animations whose frames have more pixels in total are thumbnailed as still
+/// images, without decoding the frames over the limit
+const MAX_ANIMATED_PIXELS: u64 = 32 * 1024 * 1024;
+
+pub(super) const ANIMATED_CONTENT_TYPE: &str = "image/webp";
+
+/// Which file is served for a thumbnail size of a media, as recorded in
+/// `mediaid_thumbnail`
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(super) enum Variant {
+	/// The media is not an image or is smaller than the thumbnail
+	Original,
+
+	/// The still thumbnail stored in `mediaid_file` with the size
+	Static,
+
+	/// An animated WebP stored under the `mediaid_thumbnail` key
+	Animated,
+}
+
+pub(super) struct Thumbnail {
+	pub(super) file: Vec<u8>,
+	pub(super) animated: bool,
+}
+
+impl Variant {
+	pub(super) fn to_byte(self) -> u8 {
+		match self {
+			Self::Original => 0,
+			Self::Static => 1,
+			Self::Animated => 2,
+		}
+	}
+
+	pub(super) fn from_byte(byte: u8) -> Option<Self> {
+		match byte {
+			0 => Some(Self::Original),
+			1 => Some(Self::Static),
+			2 => Some(Self::Animated),
+			_ => None,
+		}
+	}
+}
+
+/// Generates a thumbnail of the image. Animated GIF and WebP images are
+/// thumbnailed as animated WebPs when `animated` is set, and as a still of
+/// their first frame otherwise. Returns `None` when the original should be
+/// served, because it is not an image we can decode, is too large to decode
+/// or is smaller than the thumbnail.
+pub(super) fn generate(file: &[u8], width: u32, height: u32, crop: bool, animated: bool) -> Result<Option<Thumbnail>> {
+	let Some(pixels) = pixels(file) else {
+		return Ok(None);
+	};
+
+	if pixels > MAX_PIXELS {
+		return Ok(None);
+	}
+
+	if animated {
+		if let Some(frames) = animation_frames(file, pixels) {
+			return generate_animated(frames, width, height, crop);
+		}
+	}
+
+	let Ok(image) = image::load_from_memory(file) else {
+		return Ok(None);
+	};
+
+	if width > image.width() || height > image.height() {
+		return Ok(None);
+	}
+
+	let thumbnail = if crop {
+		image.resize_to_fill(width, height, FilterType::CatmullRom)
+	} else {
+		let (exact_width, exact_height) = resize_dimensions(image.width(), image.height(), width, height);
+		image.thumbnail_exact(exact_width, exact_height)
+	};
+
+	let mut thumbnail_bytes = Vec::new();
+	thumbnail.write_to(&mut Cursor::new(&mut thumbnail_bytes), ImageFormat::Png)?;
+
+	Ok(Some(Thumbnail {
+		file: thumbnail_bytes,
+		animated: false,
+	}))
+}
+
+fn generate_animated(frames: Vec<Frame>, width: u32, height: u32, crop: bool) -> Result<Option<Thumbnail>> {
+	let Some(first) = frames.first() else {
+		return Ok(None);
+	};
+
+	let (original_width, original_height) = first.buffer().dimensions();
+	if width > original_width || height > original_height {
+		return Ok(None);
+	}
+
+	let (exact_width, exact_height) = if crop {
+		(width, height)
+	} else {
+		resize_dimensions(original_width, original_height, width, height)
+	};
+
+	let mut encoder = WebPEncoder::new((exact_width, exact_height)).map_err(encoder_error)?;
+	let mut timestamp: i32 = 0;
+	for frame in frames {
+		let (numer, denom) = frame.delay().numer_denom_ms();
+		let image = DynamicImage::ImageRgba8(frame.into_buffer());
+		let resized = if crop {
+			image.resize_to_fill(width, height, FilterType::Triangle)
+		} else {
+			image.resize_exact(exact_width, exact_height, FilterType::Triangle)
+		};
+
+		encoder
+			.add_frame(resized.to_rgba8().as_raw(), timestamp)
+			.map_err(encoder_error)?;
+
+		let delay = i32::try_from(numer.checked_div(denom).unwrap_or(0)).unwrap_or(i32::MAX);
+		timestamp = timestamp.saturating_add(delay);
+	}
+
+	let thumbnail_bytes = encoder.finalize(timestamp).map_err(encoder_error)?;
+
+	Ok(Some(Thumbnail {
+		file: thumbnail_bytes.to_vec(),
+		animated: true,
+	}))
+}
+
+fn encoder_error(e: webp_animation::Error) -> Error { Error::Err(format!("Failed to encode animated WebP: {e:?}")) }
+
+/// Number of pixels of the image, read from its header without decoding it
+fn pixels(file: &[u8]) -> Option<u64> {
+	let (width, height) = ImgReader::new(Cursor::new(file))
+		.with_guessed_format()
+		.ok()?
+		.into_dimensions()
+		.ok()?;
+
+	Some(u64::from(width).saturating_mul(u64::from(height)))
+}
+
+/// The frames of an animated GIF or WebP image of `pixels` per frame, `None`
+/// for still images and animations with too many frames or pixels, which are
+/// not decoded past the limit
+fn animation_frames(file: &[u8], pixels: u64) -> Option<Vec<Frame>> {
+	// one frame more than allowed is decoded to tell the animation is too long
+	let max_frames = (MAX_ANIMATED_PIXELS / pixels.max(1))
+		.saturating_sub(1)
+		.min(MAX_ANIMATED_FRAMES);
+	if max_frames < 2 {
+		return None;
+	}
+
+	let frames = match image::guess_format(file).ok()? {
+		ImageFormat::Gif => GifDecoder::new(Cursor::new(file)).ok()?.into_frames(),
+		ImageFormat::WebP => {
+			let decoder = WebPDecoder::new(Cursor::new(file)).ok()?;
+			if !decoder.has_animation() {
+				return None;
+			}
+
+			decoder.into_frames()
+		},
+		_ => return None,
+	};
+
+	let max_frames = usize::try_from(max_frames).ok()?;
+	let frames = frames
+		.take(max_frames.saturating_add(1))
+		.collect::<Result<Vec<_>, _>>()
+		.ok()?;
+
+	(frames.len() > 1 && frames.len() <= max_frames).then_some(frames)
+}
+
+/// Calculates the width and height an image should be resized to, preserving
+/// its aspect ratio, so that it fills the given width or height and overflows
+/// the other.
+///
+/// Copied from image::dynimage::resize_dimensions
+///
+/// <https://github.com/image-rs/image/blob/6edf8ae492c4bb1dacb41da88681ea74dab1bab3/src/math/utils.rs#L5-L11>
+fn resize_dimensions(original_width: u32, original_height: u32, width: u32, height: u32) -> (u32, u32) {
+	let ratio = u64::from(original_width) * u64::from(height);
+	let nratio = u64::from(width) * u64::from(original_height);
+
+	let use_width = nratio <= ratio;
+	let intermediate = if use_width {
+		u64::from(original_height) * u64::from(width) / u64::from(original_width)
+	} else {
+		u64::from(original_width) * u64::from(height) / u64::from(original_height)
+	};
+	if use_width {
+		if u32::try_from(intermediate).is_ok() {
+			(width, intermediate as u32)
+		} else {
+			((u64::from(width) * u64::from(u32::MAX) / intermediate) as u32, u32::MAX)
+		}
+	} else if u32::try_from(intermediate).is_ok() {
+		(intermediate as u32, height)
+	} else {
+		(u32::MAX, (u64::from(height) * u64::from(u32::MAX) / intermediate) as u32)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::io::Cursor;
+
+	use image::{
+		codecs::gif::{GifEncoder, Repeat},
+		Delay, Frame, ImageFormat, RgbaImage,
+	};
+
+	use super::{animation_frames, generate, pixels, MAX_ANIMATED_PIXELS};
+
+	fn gif(frames: u32) -> Vec<u8> {
+		let mut file = Vec::new();
+		{
+			let mut encoder = GifEncoder::new(&mut file);
+			encoder.set_repeat(Repeat::Infinite).expect("repeat set");
+			encoder
+				.encode_frames((0..frames).map(|i| {
+					let image = RgbaImage::from_pixel(200, 100, image::Rgba([i as u8, 0, 0, 255]));
+					Frame::from_parts(image, 0, 0, Delay::from_numer_denom_ms(100, 1))
+				}))
+				.expect("gif encodes");
+		}
+
+		file
+	}
+
+	#[test]
+	fn animated_thumbnails() {
+		let thumbnail = generate(&gif(3), 96, 96, true, true)
+			.expect("thumbnail generates")
+			.expect("thumbnail is smaller than the original");
+		assert!(thumbnail.animated);
+		assert_eq!(image::guess_format(&thumbnail.file).ok(), Some(ImageFormat::WebP));
+
+		let thumbnail = generate(&gif(3), 96, 96, true, false)
+			.expect("thumbnail generates")
+			.expect("thumbnail is smaller than the original");
+		assert!(!thumbnail.animated);
+		assert_eq!(image::guess_format(&thumbnail.file).ok(), Some(ImageFormat::Png));
+
+		// still images are thumbnailed as such
+		let thumbnail = generate(&gif(1), 96, 96, true, true)
+			.expect("thumbnail generates")
+			.expect("thumbnail is smaller than the original");
+		assert!(!thumbnail.animated);
+	}
+
+	#[test]
+	fn pixel_budget() {
+		// 200x100 frames fit the budget about 1600 times
+		assert_eq!(animation_frames(&gif(3), 200 * 100).map(|frames| frames.len()), Some(3));
+		// frames this large only fit the budget once, so none are decoded
+		assert!(animation_frames(&gif(3), MAX_ANIMATED_PIXELS / 2).is_none());
+
+		let mut png = Vec::new();
+		image::DynamicImage::ImageRgba8(RgbaImage::new(10, 10))
+			.write_to(&mut Cursor::new(&mut png), ImageFormat::Png)
+			.expect("png encodes");
+		assert_eq!(pixels(&png), Some(100));
+		assert_eq!(pixels(b"not an image"), None);
+	}
+
+	#[test]
+	fn originals_served_when_small_or_not_images() {
+		assert!(generate(&gif(3), 320, 240, false, true)
+			.expect("no error")
+			.is_none());
+		assert!(generate(b"not an image", 32, 32, true, false)
+			.expect("no error")
+			.is_none());
+
+		let mut png = Vec::new();
+		image::DynamicImage::ImageRgba8(RgbaImage::new(10, 10))
+			.write_to(&mut Cursor::new(&mut png), ImageFormat::Png)
+			.expect("png encodes");
+		assert!(generate(&png, 32, 32, true, false)
+			.expect("no error")
+			.is_none());
+	}
+}
@@ -0,0 +1,418 @@
+use std::{
+	collections::BTreeSet,
+	path::{Path, PathBuf},
+	time::{Duration, UNIX_EPOCH},
+};
+
+use conduit::{debug, debug_info, debug_warn, utils, warn, Error, Result};
+use tokio::{fs, io::AsyncWriteExt, time::interval};
+
+use super::{encode_key, Service};
+use crate::services;
+
+const MILLIS_PER_DAY: u64 = 86_400_000;
+
+/// Access times are only rewritten once they are this old, so popular media
+/// does not cost a database write per download
+const ACCESS_RESOLUTION: u64 = 3_600_000;
+
+/// Number and total size of the media files in each storage tier
+#[derive(Default)]
+pub struct TierStatus {
+	pub hot_files: usize,
+	pub hot_bytes: u64,
+	pub cold_files: usize,
+	pub cold_bytes: u64,
+	/// Progress of the running pass moving media to cold storage
+	pub progress: Option<String>,
+}
+
+impl Service {
+	/// Path of the file holding the content of a blob, in whichever tier it
+	/// is stored
+	pub(super) fn blob_file(&self, blob: &[u8]) -> PathBuf {
+		if self.is_cold(blob) {
+			if let Some(path) = self.get_media_file_cold(blob) {
+				return path;
+			}
+		}
+
+		self.get_media_file_sha256(blob)
+	}
+
+	/// Reads the file holding the content of the media. A move committed
+	/// between looking up the tier of the file and opening it removes the
+	/// file, so the other tier is tried when it is missing.
+	pub(super) async fn read_media_file(&self, key: &[u8]) -> std::io::Result<Vec<u8>> {
+		let blob = self.blob_key(key);
+		let path = self.blob_file(&blob);
+		let hot = self.get_media_file_sha256(&blob);
+		let other = if path == hot {
+			self.get_media_file_cold(&blob)
+		} else {
+			Some(hot)
+		};
+
+		read_either(&path, other.as_deref()).await
+	}
+
+	/// Whether the file of the blob was moved to cold storage
+	pub fn is_cold(&self, blob: &[u8]) -> bool { self.db.is_cold(blob).unwrap_or(false) }
+
+	/// Path of the file of the blob in the cold storage directory, if one is
+	/// configured
+	pub fn get_media_file_cold(&self, blob: &[u8]) -> Option<PathBuf> {
+		let mut r = self.server.config.media_cold_path.clone()?;
+		r.push(file_name(blob));
+		Some(r)
+	}
+
+	/// Records that the media was read, promoting its file back to the media
+	/// directory if it is cold and `media_cold_promote_on_access` is enabled
+	pub(super) fn accessed(&self, key: &[u8]) {
+		if services().globals.read_only() {
+			return;
+		}
+
+		let blob = self.blob_key(key);
+		let now = utils::millis_since_unix_epoch();
+		match self.db.get_last_access(&blob) {
+			Ok(Some(last)) if now.saturating_sub(last) < ACCESS_RESOLUTION => {},
+			_ => {
+				if let Err(e) = self.db.set_last_access(&blob, now) {
+					debug_warn!(key = ?encode_key(&blob), "Failed to record media access: {e}");
+				}
+			},
+		}
+
+		if self.server.config.media_cold_promote_on_access && self.is_cold(&blob) {
+			services().server.runtime().spawn(async move {
+				match services().media.move_blob(&blob, false).await {
+					Ok(true) => debug!(key = ?encode_key(&blob), "Promoted media file from cold storage"),
+					Ok(false) => {},
+					Err(e) => debug_warn!(key = ?encode_key(&blob), "Failed to promote media file: {e}"),
+				}
+			});
+		}
+	}
+
+	pub async fn start_tiering_handler(&self) {
+		if self.server.config.media_cold_path.is_none() {
+			return;
+		}
+
+		let period = Duration::from_secs(self.server.config.media_cold_check_interval);
+		let handle = services().server.runtime().spawn(async move {
+			let mut i = interval(period);
+			loop {
+				i.tick().await;
+				if let Err(e) = services().media.migrate_to_cold().await {
+					warn!("Failed to move media to cold storage: {e}");
+				}
+			}
+		});
+
+		_ = self.tiering_handler_join.lock().await.insert(handle);
+	}
+
+	/// Counts the media files and their sizes in each storage tier
+	pub async fn tier_status(&self) -> Result<TierStatus> {
+		let mut status = TierStatus {
+			progress: self.tiering_progress.lock().expect("locked").clone(),
+			..TierStatus::default()
+		};
+
+		for blob in self.blobs() {
+			let cold = self.is_cold(&blob);
+			let Ok(metadata) = fs::metadata(self.blob_file(&blob)).await else {
+				continue;
+			};
+
+			if cold {
+				status.cold_files = status.cold_files.saturating_add(1);
+				status.cold_bytes = status.cold_bytes.saturating_add(metadata.len());
+			} else {
+				status.hot_files = status.hot_files.saturating_add(1);
+				status.hot_bytes = status.hot_bytes.saturating_add(metadata.len());
+			}
+		}
+
+		Ok(status)
+	}
+
+	/// Moves the files of media not read for `media_cold_after_days` to the
+	/// cold storage directory
+	async fn migrate_to_cold(&self) -> Result<()> {
+		let max_age = self
+			.server
+			.config
+			.media_cold_after_days
+			.saturating_mul(MILLIS_PER_DAY);
+		let cutoff = utils::millis_since_unix_epoch().saturating_sub(max_age);
+
+		let blobs = self.blobs();
+		let mut moved: usize = 0;
+		for (i, blob) in blobs.iter().enumerate() {
+			if !self.server.running() {
+				break;
+			}
+
+			*self.tiering_progress.lock().expect("locked") = Some(format!(
+				"checked {i} of {} media files, moved {moved} to cold storage",
+				blobs.len()
+			));
+
+			if self.is_cold(blob) {
+				self.remove_leftover_hot(blob).await?;
+				continue;
+			}
+
+			if self
+				.last_access(blob)
+				.await?
+				.is_some_and(|last| last >= cutoff)
+			{
+				continue;
+			}
+
+			match self.move_blob(blob, true).await {
+				Ok(true) => moved = moved.saturating_add(1),
+				Ok(false) => {},
+				Err(e) => debug_warn!(key = ?encode_key(blob), "Failed to move media file to cold storage: {e}"),
+			}
+		}
+
+		*self.tiering_progress.lock().expect("locked") = None;
+		if moved > 0 {
+			debug_info!(moved, "Moved media files to cold storage");
+		}
+
+		Ok(())
+	}
+
+	/// Removes the file of a cold blob left in the media directory when a move
+	/// was interrupted after the cold copy took over
+	async fn remove_leftover_hot(&self, blob: &[u8]) -> Result<()> {
+		let hot = self.get_media_file_sha256(blob);
+		if !fs::try_exists(&hot).await.unwrap_or(false) {
+			return Ok(());
+		}
+
+		let _lock = self.dedup_mutex.lock().await;
+		// the blob may have been promoted since it was checked, in which case the
+		// file is its only copy
+		if !self.is_cold(blob) {
+			return Ok(());
+		}
+
+		match fs::remove_file(&hot).await {
+			Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+			_ => Ok(()),
+		}
+	}
+
+	/// When the file of the blob was last read, falling back to its
+	/// modification time for media not read since access times were recorded
+	async fn last_access(&self, blob: &[u8]) -> Result<Option<u64>> {
+		if let Some(last) = self.db.get_last_access(blob)? {
+			return Ok(Some(last));
+		}
+
+		let Ok(metadata) = fs::metadata(self.get_media_file_sha256(blob)).await else {
+			return Ok(None);
+		};
+
+		Ok(metadata
+			.modified()?
+			.duration_since(UNIX_EPOCH)
+			.ok()
+			.and_then(|since| u64::try_from(since.as_millis()).ok()))
+	}
+
+	/// Moves the file of a blob into (`cold`) or out of the cold storage
+	/// directory. The file is copied, synced and verified before the database
+	/// refers to the copy, and only once that is persisted is the original
+	/// removed, so the media stays readable should the move fail at any step.
+	/// Returns whether the file was moved.
+	///
+	/// Uploads and deletions only wait for the move while it is committed; the
+	/// copy is made without holding the dedup lock.
+	pub(super) async fn move_blob(&self, blob: &[u8], cold: bool) -> Result<bool> {
+		let Some(cold_path) = self.get_media_file_cold(blob) else {
+			return Ok(false);
+		};
+
+		let hot_path = self.get_media_file_sha256(blob);
+		let (from, to) = if cold {
+			(hot_path, cold_path)
+		} else {
+			(cold_path, hot_path)
+		};
+
+		let _moving = self.tiering_mutex.lock().await;
+		if self.is_cold(blob) == cold {
+			return Ok(false);
+		}
+
+		let tmp = copy_to_temp(&from, &to).await?;
+
+		let _lock = self.dedup_mutex.lock().await;
+		// the media may have been deleted or its file replaced while it was copied
+		if self.is_cold(blob) == cold || !fs::try_exists(&from).await.unwrap_or(false) {
+			fs::remove_file(&tmp).await?;
+			return Ok(false);
+		}
+
+		rename_into_place(&tmp, &to).await?;
+		self.db.set_cold(blob, cold)?;
+		services().globals.db.sync()?;
+
+		if self.server.config.media_compat_file_link {
+			let legacy = self.get_media_file_b64(blob);
+			_ = fs::remove_file(&legacy).await;
+			self.create_legacy_link(blob, &to).await;
+		}
+
+		fs::remove_file(&from).await?;
+		Ok(true)
+	}
+
+	/// Keys of all media holding the content of a file
+	fn blobs(&self) -> BTreeSet<Vec<u8>> {
+		self.db
+			.get_all_media_keys()
+			.iter()
+			.map(|key| self.blob_key(key))
+			.collect()
+	}
+}
+
+/// Name of the file of a blob, the hash of its key so the length of the path
+/// does not exceed the maximum of most filesystems
+pub(super) fn file_name(key: &[u8]) -> String { encode_key(&sha256(key)) }
+
+/// Reads the file at `path`, or at `other` if there is none at `path`
+async fn read_either(path: &Path, other: Option<&Path>) -> std::io::Result<Vec<u8>> {
+	match (fs::read(path).await, other) {
+		(Err(e), Some(other)) if e.kind() == std::io::ErrorKind::NotFound => fs::read(other).await,
+		(result, _) => result,
+	}
+}
+
+/// Copies a file to a temporary file next to `to`, which is synced and
+/// verified against the original. Returns the path of the copy.
+async fn copy_to_temp(from: &Path, to: &Path) -> Result<PathBuf> {
+	let file = fs::read(from).await?;
+	let tmp = temp_path(to);
+	let mut f = fs::File::create(&tmp).await?;
+	f.write_all(&file).await?;
+	f.sync_all().await?;
+	drop(f);
+
+	let copy = fs::read(&tmp).await?;
+	if sha256(&copy) != sha256(&file) {
+		fs::remove_file(&tmp).await?;
+		return Err(Error::Err(format!(
+			"Copy of media file {from:?} at {tmp:?} does not match the original"
+		)));
+	}
+
+	Ok(tmp)
+}
+
+/// Renames the copy made by `copy_to_temp` into place, then syncs the
+/// directory so the rename survives a crash
+async fn rename_into_place(tmp: &Path, to: &Path) -> Result<()> {
+	fs::rename(tmp, to).await?;
+	if let Some(dir) = to.parent() {
+		fs::File::open(dir).await?.sync_all().await?;
+	}
+
+	Ok(())
+}
+
+fn temp_path(path: &Path) -> PathBuf {
+	let mut tmp = path.to_owned().into_os_string();
+	tmp.push(".tmp");
+	tmp.into()
+}
+
+fn sha256(bytes: &[u8]) -> Vec<u8> { <sha2::Sha256 as sha2::Digest>::digest(bytes).to_vec() }
+
+#[cfg(test)]
+mod tests {
+	use std::path::{Path, PathBuf};
+
+	use conduit::Result;
+	use tokio::fs;
+
+	use super::{copy_to_temp, read_either, rename_into_place, temp_path};
+
+	async fn copy_file(from: &Path, to: &Path) -> Result<()> {
+		rename_into_place(&copy_to_temp(from, to).await?, to).await
+	}
+
+	async fn dirs(name: &str) -> (PathBuf, PathBuf) {
+		let root = std::env::temp_dir().join(format!("conduwuit-tiering-{name}-{}", std::process::id()));
+		_ = fs::remove_dir_all(&root).await;
+		let (hot, cold) = (root.join("media"), root.join("cold"));
+		fs::create_dir_all(&hot).await.unwrap();
+		fs::create_dir_all(&cold).await.unwrap();
+		(hot, cold)
+	}
+
+	#[tokio::test]
+	async fn move_and_promote() {
+		let (hot, cold) = dirs("move").await;
+		let (hot, cold) = (hot.join("blob"), cold.join("blob"));
+		fs::write(&hot, b"content").await.unwrap();
+
+		// the original stays in place until the database refers to the copy
+		copy_file(&hot, &cold).await.unwrap();
+		assert_eq!(fs::read(&cold).await.unwrap(), b"content");
+		assert_eq!(fs::read(&hot).await.unwrap(), b"content");
+		assert!(!fs::try_exists(temp_path(&cold)).await.unwrap());
+
+		fs::remove_file(&hot).await.unwrap();
+		copy_file(&cold, &hot).await.unwrap();
+		assert_eq!(fs::read(&hot).await.unwrap(), b"content");
+		assert_eq!(fs::read(&cold).await.unwrap(), b"content");
+	}
+
+	#[tokio::test]
+	async fn interrupted_copy_is_replaced() {
+		let (hot, cold) = dirs("interrupted").await;
+		let (hot, cold) = (hot.join("blob"), cold.join("blob"));
+		fs::write(&hot, b"content").await.unwrap();
+		fs::write(temp_path(&cold), b"partial").await.unwrap();
+
+		copy_file(&hot, &cold).await.unwrap();
+		assert_eq!(fs::read(&cold).await.unwrap(), b"content");
+		assert!(!fs::try_exists(temp_path(&cold)).await.unwrap());
+	}
+
+	#[tokio::test]
+	async fn failed_copy_keeps_original() {
+		let (hot, cold) = dirs("failed").await;
+		let (hot, cold) = (hot.join("blob"), cold.join("missing").join("blob"));
+		fs::write(&hot, b"content").await.unwrap();
+
+		copy_file(&hot, &cold).await.unwrap_err();
+		assert_eq!(fs::read(&hot).await.unwrap(), b"content");
+		assert!(!fs::try_exists(&cold).await.unwrap());
+	}
+
+	#[tokio::test]
+	async fn read_falls_back_to_the_other_tier() {
+		let (hot, cold) = dirs("read").await;
+		let (hot, cold) = (hot.join("blob"), cold.join("blob"));
+		fs::write(&cold, b"content").await.unwrap();
+
+		// the file was moved to cold storage after its tier was looked up
+		assert_eq!(read_either(&hot, Some(&cold)).await.unwrap(), b"content");
+		read_either(&hot, None).await.unwrap_err();
+
+		fs::write(&hot, b"hot").await.unwrap();
+		assert_eq!(read_either(&hot, Some(&cold)).await.unwrap(), b"hot");
+	}
+}
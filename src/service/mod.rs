@@ -9,6 +9,7 @@ pub mod key_backups;
 pub mod media;
 pub mod presence;
 pub mod pusher;
+pub mod rate_limit;
 pub mod rooms;
 pub mod sending;
 pub mod transaction_ids;
@@ -2,12 +2,14 @@ use std::{cmp::Ordering, collections::BTreeMap, sync::Arc};
 
 use conduit::{warn, Error};
 use ruma::{
+	api::client::filter::{self, FilterDefinition},
 	canonical_json::redact_content_in_place,
 	events::{
 		room::{member::RoomMemberEventContent, redaction::RoomRedactionEventContent},
 		space::child::HierarchySpaceChildEvent,
 		AnyEphemeralRoomEvent, AnyMessageLikeEvent, AnyStateEvent, AnyStrippedStateEvent, AnySyncStateEvent,
-		AnySyncTimelineEvent, AnyTimelineEvent, StateEvent, TimelineEventType,
+		AnySyncTimelineEvent, AnyTimelineEvent, MessageLikeEventContent, StateEvent, StateEventContent,
+		TimelineEventType,
 	},
 	serde::Raw,
 	state_res, CanonicalJsonObject, CanonicalJsonValue, EventId, MilliSecondsSinceUnixEpoch, OwnedEventId, OwnedRoomId,
@@ -17,6 +19,7 @@ use serde::{Deserialize, Serialize};
 use serde_json::{
 	json,
 	value::{to_raw_value, RawValue as RawJsonValue},
+	Map as JsonObject, Value as JsonValue,
 };
 
 use crate::services;
@@ -33,6 +36,18 @@ pub struct EventHash {
 	pub sha256: String,
 }
 
+/// How events are serialized for a client, from the `event_format` and
+/// `event_fields` of its filter.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct EventFormat {
+	/// Events are sent as PDUs, with their hashes and signatures
+	pub federation: bool,
+
+	/// Paths of the fields to keep, split on unescaped dots; `None` keeps all
+	/// fields
+	pub fields: Option<Vec<Vec<String>>>,
+}
+
 #[derive(Clone, Deserialize, Serialize, Debug)]
 pub struct PduEvent {
 	pub event_id: Arc<EventId>,
@@ -118,6 +133,58 @@ impl PduEvent {
 		Ok(())
 	}
 
+	/// Sets a bundled aggregation of the event under `m.relations` in its
+	/// unsigned data, replacing any earlier one of the same relation type.
+	pub fn add_relation<T>(&mut self, rel_type: &str, aggregation: &T) -> crate::Result<()>
+	where
+		T: Serialize,
+	{
+		let mut unsigned: BTreeMap<String, JsonValue> = self
+			.unsigned
+			.as_ref()
+			.map_or_else(|| Ok(BTreeMap::new()), |u| serde_json::from_str(u.get()))
+			.map_err(|_| Error::bad_database("Invalid unsigned in pdu event"))?;
+
+		let relations = unsigned
+			.entry("m.relations".to_owned())
+			.or_insert_with(|| JsonValue::Object(JsonObject::new()));
+		if !relations.is_object() {
+			*relations = JsonValue::Object(JsonObject::new());
+		}
+
+		relations[rel_type] = serde_json::to_value(aggregation).expect("aggregation serializes");
+		self.unsigned = Some(to_raw_value(&unsigned).expect("unsigned is valid"));
+
+		Ok(())
+	}
+
+	/// Removes the bundled aggregation of a relation type from the unsigned
+	/// data, such as one stored with the event by an earlier version.
+	pub fn remove_relation(&mut self, rel_type: &str) -> crate::Result<()> {
+		let Some(unsigned) = &self.unsigned else {
+			return Ok(());
+		};
+
+		let mut unsigned: BTreeMap<String, JsonValue> =
+			serde_json::from_str(unsigned.get()).map_err(|_| Error::bad_database("Invalid unsigned in pdu event"))?;
+
+		let Some(JsonValue::Object(relations)) = unsigned.get_mut("m.relations") else {
+			return Ok(());
+		};
+
+		if relations.remove(rel_type).is_none() {
+			return Ok(());
+		}
+
+		if relations.is_empty() {
+			unsigned.remove("m.relations");
+		}
+
+		self.unsigned = Some(to_raw_value(&unsigned).expect("unsigned is valid"));
+
+		Ok(())
+	}
+
 	/// Copies the `redacts` property of the event to the `content` dict and
 	/// vice-versa.
 	///
@@ -291,6 +358,26 @@ impl PduEvent {
 		serde_json::from_value(json).expect("Raw::from_value always works")
 	}
 
+	/// `to_sync_room_event` in the format requested by a client's filter
+	#[must_use]
+	pub fn to_sync_room_event_formatted(&self, format: &EventFormat) -> Raw<AnySyncTimelineEvent> {
+		if format.is_default() {
+			return self.to_sync_room_event();
+		}
+
+		format.apply(self, self.to_sync_room_event().cast())
+	}
+
+	/// `to_sync_state_event` in the format requested by a client's filter
+	#[must_use]
+	pub fn to_sync_state_event_formatted(&self, format: &EventFormat) -> Raw<AnySyncStateEvent> {
+		if format.is_default() {
+			return self.to_sync_state_event();
+		}
+
+		format.apply(self, self.to_sync_state_event().cast())
+	}
+
 	#[tracing::instrument(skip(self))]
 	pub fn to_stripped_state_event(&self) -> Raw<AnyStrippedStateEvent> {
 		let json = json!({
@@ -439,6 +526,123 @@ pub fn gen_event_id_canonical_json(
 	Ok((event_id, value))
 }
 
+impl EventFormat {
+	#[must_use]
+	pub fn is_default(&self) -> bool { !self.federation && self.fields.is_none() }
+
+	fn apply<T>(&self, pdu: &PduEvent, event: Raw<JsonValue>) -> Raw<T> {
+		let mut json = if self.federation {
+			federation_event_json(pdu)
+		} else {
+			None
+		}
+		.or_else(|| event.deserialize().ok())
+		.unwrap_or(JsonValue::Null);
+
+		if let (Some(fields), JsonValue::Object(event)) = (&self.fields, &json) {
+			json = JsonValue::Object(only_fields(event, fields));
+		}
+
+		serde_json::from_value(json).expect("Raw::from_value always works")
+	}
+}
+
+impl From<&FilterDefinition> for EventFormat {
+	fn from(filter: &FilterDefinition) -> Self {
+		Self {
+			federation: matches!(filter.event_format, filter::EventFormat::Federation),
+			fields: filter
+				.event_fields
+				.as_ref()
+				.map(|fields| fields.iter().map(|field| split_field_path(field)).collect()),
+		}
+	}
+}
+
+/// The PDU as stored, with the client-facing `unsigned` of `pdu`, or `None`
+/// for events which were never persisted
+fn federation_event_json(pdu: &PduEvent) -> Option<JsonValue> {
+	let pdu_json = services()
+		.rooms
+		.timeline
+		.get_pdu_json(&pdu.event_id)
+		.ok()
+		.flatten()?;
+
+	let mut json = serde_json::to_value(pdu_json).ok()?;
+	json["event_id"] = json!(pdu.event_id);
+	match &pdu.unsigned {
+		Some(unsigned) => json["unsigned"] = json!(unsigned),
+		None => _ = json.as_object_mut()?.remove("unsigned"),
+	}
+
+	Some(json)
+}
+
+/// Splits a filter's `event_fields` entry on dots. A literal `.` or `\` in a
+/// field name is escaped with a `\`.
+fn split_field_path(field: &str) -> Vec<String> {
+	let mut path = vec![String::new()];
+	let mut chars = field.chars();
+	while let Some(c) = chars.next() {
+		let part = path.last_mut().expect("path is never empty");
+		match c {
+			'\\' => match chars.next() {
+				Some(escaped @ ('.' | '\\')) => part.push(escaped),
+				Some(other) => {
+					part.push('\\');
+					part.push(other);
+				},
+				None => part.push('\\'),
+			},
+			'.' => path.push(String::new()),
+			_ => part.push(c),
+		}
+	}
+
+	path
+}
+
+/// Copies the fields at `paths` from `event`, keeping the nesting of objects.
+/// Paths leading to nothing, or through a non-object value, are ignored.
+fn only_fields(event: &JsonObject<String, JsonValue>, paths: &[Vec<String>]) -> JsonObject<String, JsonValue> {
+	let mut filtered = JsonObject::new();
+	for path in paths {
+		copy_field(event, &mut filtered, path);
+	}
+
+	filtered
+}
+
+fn copy_field(src: &JsonObject<String, JsonValue>, dst: &mut JsonObject<String, JsonValue>, path: &[String]) {
+	let Some((key, rest)) = path.split_first() else {
+		return;
+	};
+
+	let Some(value) = src.get(key) else {
+		return;
+	};
+
+	if rest.is_empty() {
+		dst.insert(key.clone(), value.clone());
+		return;
+	}
+
+	let JsonValue::Object(src) = value else {
+		return;
+	};
+
+	let mut nested = match dst.remove(key) {
+		Some(JsonValue::Object(nested)) => nested,
+		_ => JsonObject::new(),
+	};
+
+	copy_field(src, &mut nested, rest);
+	if !nested.is_empty() {
+		dst.insert(key.clone(), JsonValue::Object(nested));
+	}
+}
+
 /// Build the start of a PDU in order to add it to the Database.
 #[derive(Debug, Deserialize)]
 pub struct PduBuilder {
@@ -449,3 +653,276 @@ pub struct PduBuilder {
 	pub state_key: Option<String>,
 	pub redacts: Option<Arc<EventId>>,
 }
+
+impl PduBuilder {
+	/// Builds a state event, taking the event type from the content
+	pub fn state<S, T>(state_key: S, content: &T) -> Self
+	where
+		S: Into<String>,
+		T: StateEventContent,
+	{
+		Self {
+			event_type: content.event_type().into(),
+			content: to_raw_value(content).expect("event content serializes to json"),
+			unsigned: None,
+			state_key: Some(state_key.into()),
+			redacts: None,
+		}
+	}
+
+	/// Builds a state event with the type, state key and content of an
+	/// existing one, keeping any content keys unknown to us
+	pub fn copy_state(pdu: &PduEvent) -> Self {
+		Self {
+			event_type: pdu.kind.clone(),
+			content: pdu.content.clone(),
+			unsigned: None,
+			state_key: Some(pdu.state_key.clone().unwrap_or_default()),
+			redacts: None,
+		}
+	}
+
+	/// Builds a message-like event, taking the event type from the content
+	pub fn timeline<T>(content: &T) -> Self
+	where
+		T: MessageLikeEventContent,
+	{
+		Self {
+			event_type: content.event_type().into(),
+			content: to_raw_value(content).expect("event content serializes to json"),
+			unsigned: None,
+			state_key: None,
+			redacts: None,
+		}
+	}
+
+	/// Builds a redaction of `redacts`. The redacted event is named in the
+	/// content, as room version 11 requires, and in the `redacts` field read by
+	/// earlier room versions, so the builder is valid in every room.
+	pub fn redaction(redacts: &EventId, reason: Option<String>) -> Self {
+		Self {
+			redacts: Some(redacts.into()),
+			..Self::timeline(&RoomRedactionEventContent {
+				redacts: Some(redacts.to_owned()),
+				reason,
+			})
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use ruma::{
+		event_id,
+		events::{
+			room::{
+				member::{MembershipState, RoomMemberEventContent},
+				message::RoomMessageEventContent,
+				redaction::RoomRedactionEventContent,
+				topic::RoomTopicEventContent,
+			},
+			TimelineEventType,
+		},
+		RoomVersionId,
+	};
+	use serde_json::json;
+
+	use super::{only_fields, split_field_path, PduBuilder, PduEvent};
+
+	fn filter(fields: &[&str]) -> serde_json::Value {
+		let event = json!({
+			"type": "m.room.message",
+			"sender": "@alice:example.com",
+			"content": {
+				"body": "hello",
+				"msgtype": "m.text",
+				"m.relates_to": { "rel_type": "m.thread" },
+				"a.b": { "c": 1 },
+			},
+		});
+
+		let paths: Vec<_> = fields.iter().map(|field| split_field_path(field)).collect();
+		serde_json::Value::Object(only_fields(event.as_object().unwrap(), &paths))
+	}
+
+	#[test]
+	fn split_escapes() {
+		assert_eq!(split_field_path("content.body"), ["content", "body"]);
+		assert_eq!(split_field_path(r"content.m\.relates_to"), ["content", "m.relates_to"]);
+		assert_eq!(split_field_path(r"a\\.b"), [r"a\", "b"]);
+		assert_eq!(split_field_path(r"a\b\"), [r"a\b\"]);
+		assert_eq!(split_field_path("content."), ["content", ""]);
+	}
+
+	#[test]
+	fn nested_fields() {
+		assert_eq!(
+			filter(&["type", "content.body"]),
+			json!({ "type": "m.room.message", "content": { "body": "hello" } })
+		);
+		assert_eq!(
+			filter(&[r"content.m\.relates_to.rel_type", r"content.a\.b"]),
+			json!({ "content": { "m.relates_to": { "rel_type": "m.thread" }, "a.b": { "c": 1 } } })
+		);
+	}
+
+	#[test]
+	fn overlapping_fields() {
+		let whole = json!({ "content": filter(&["content"])["content"].clone() });
+		assert_eq!(filter(&["content", "content.body"]), whole);
+		assert_eq!(filter(&["content.body", "content"]), whole);
+	}
+
+	#[test]
+	fn missing_fields() {
+		assert_eq!(filter(&["content.missing", "sender.nested", "unsigned"]), json!({}));
+		assert_eq!(filter(&[]), json!({}));
+	}
+
+	fn pdu(event: serde_json::Value) -> PduEvent { serde_json::from_value(event).expect("valid pdu") }
+
+	#[test]
+	fn redaction_reason_kept() {
+		let mut message = pdu(json!({
+			"event_id": "$message",
+			"room_id": "!room:example.com",
+			"sender": "@alice:example.com",
+			"origin_server_ts": 1,
+			"type": "m.room.message",
+			"content": { "body": "hello", "msgtype": "m.text" },
+			"prev_events": [],
+			"depth": 1,
+			"auth_events": [],
+			"unsigned": { "age": 1 },
+			"hashes": { "sha256": "" },
+		}));
+		let redaction = pdu(json!({
+			"event_id": "$redaction",
+			"room_id": "!room:example.com",
+			"sender": "@mod:remote.example.com",
+			"origin_server_ts": 2,
+			"type": "m.room.redaction",
+			"content": { "reason": "spam" },
+			"redacts": "$message",
+			"prev_events": [],
+			"depth": 2,
+			"auth_events": [],
+			"hashes": { "sha256": "" },
+		}));
+
+		message.redact(RoomVersionId::V10, &redaction).unwrap();
+		assert!(message.is_redacted());
+
+		let unsigned: serde_json::Value = serde_json::from_str(message.unsigned.unwrap().get()).unwrap();
+		assert_eq!(unsigned["redacted_because"]["event_id"], "$redaction");
+		assert_eq!(unsigned["redacted_because"]["content"]["reason"], "spam");
+		assert_eq!(unsigned.get("age"), None);
+		assert_eq!(message.content.get(), "{}");
+	}
+
+	#[test]
+	fn builders_derive_event_type() {
+		let topic = PduBuilder::state("", &RoomTopicEventContent::new("hello".to_owned()));
+		assert_eq!(topic.event_type, TimelineEventType::RoomTopic);
+		assert_eq!(topic.state_key.as_deref(), Some(""));
+		assert_eq!(
+			serde_json::from_str::<serde_json::Value>(topic.content.get()).unwrap(),
+			json!({ "topic": "hello" })
+		);
+		assert!(topic.redacts.is_none());
+
+		let member = PduBuilder::state("@alice:example.com", &RoomMemberEventContent::new(MembershipState::Join));
+		assert_eq!(member.event_type, TimelineEventType::RoomMember);
+		assert_eq!(member.state_key.as_deref(), Some("@alice:example.com"));
+
+		let message = PduBuilder::timeline(&RoomMessageEventContent::text_plain("hello"));
+		assert_eq!(message.event_type, TimelineEventType::RoomMessage);
+		assert!(message.state_key.is_none());
+		assert!(message.redacts.is_none());
+	}
+
+	#[test]
+	fn rich_topic_survives_upgrade() {
+		let content = json!({
+			"topic": "Rich topic",
+			"m.topic": [
+				{ "mimetype": "text/html", "body": "<b>Rich</b> topic" },
+				{ "mimetype": "text/plain", "body": "Rich topic" },
+			],
+		});
+		let topic = pdu(json!({
+			"event_id": "$topic",
+			"room_id": "!room:example.com",
+			"sender": "@alice:example.com",
+			"origin_server_ts": 1,
+			"type": "m.room.topic",
+			"state_key": "",
+			"content": content,
+			"prev_events": [],
+			"depth": 1,
+			"auth_events": [],
+			"hashes": { "sha256": "" },
+		}));
+
+		let copy = PduBuilder::copy_state(&topic);
+		assert_eq!(copy.event_type, TimelineEventType::RoomTopic);
+		assert_eq!(copy.state_key.as_deref(), Some(""));
+		assert_eq!(serde_json::from_str::<serde_json::Value>(copy.content.get()).unwrap(), content);
+
+		let stripped = serde_json::from_str::<serde_json::Value>(topic.to_stripped_state_event().json().get()).unwrap();
+		assert_eq!(stripped["content"], content);
+	}
+
+	#[test]
+	fn redaction_builder_names_target_for_all_room_versions() {
+		let redaction = PduBuilder::redaction(event_id!("$message"), Some("spam".to_owned()));
+		assert_eq!(redaction.event_type, TimelineEventType::RoomRedaction);
+		assert!(redaction.state_key.is_none());
+
+		// Room versions before 11 read the top-level field
+		assert_eq!(redaction.redacts.as_deref(), Some(event_id!("$message")));
+
+		// Room version 11 reads the content
+		let content: RoomRedactionEventContent = serde_json::from_str(redaction.content.get()).unwrap();
+		assert_eq!(content.redacts.as_deref(), Some(event_id!("$message")));
+		assert_eq!(content.reason.as_deref(), Some("spam"));
+	}
+
+	#[test]
+	fn stale_relation_removed() {
+		let mut root = pdu(json!({
+			"event_id": "$root",
+			"room_id": "!room:example.com",
+			"sender": "@alice:example.com",
+			"origin_server_ts": 1,
+			"type": "m.room.message",
+			"content": { "body": "hello", "msgtype": "m.text" },
+			"prev_events": [],
+			"depth": 1,
+			"auth_events": [],
+			"unsigned": {
+				"age": 1,
+				"m.relations": {
+					"m.thread": { "count": 1, "current_user_participated": true },
+					"m.reference": { "chunk": [] },
+				},
+			},
+			"hashes": { "sha256": "" },
+		}));
+		let unsigned = |pdu: &PduEvent| -> serde_json::Value {
+			serde_json::from_str(pdu.unsigned.as_ref().unwrap().get()).unwrap()
+		};
+
+		root.remove_relation("m.thread").unwrap();
+		assert_eq!(
+			unsigned(&root),
+			json!({ "age": 1, "m.relations": { "m.reference": { "chunk": [] } } })
+		);
+
+		root.remove_relation("m.reference").unwrap();
+		assert_eq!(unsigned(&root), json!({ "age": 1 }));
+
+		root.remove_relation("m.thread").unwrap();
+		assert_eq!(unsigned(&root), json!({ "age": 1 }));
+	}
+}
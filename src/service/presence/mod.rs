@@ -135,6 +135,10 @@ impl Service {
 	pub fn ping_presence(&self, user_id: &UserId, new_state: &PresenceState) -> Result<()> {
 		const REFRESH_TIMEOUT: u64 = 60 * 25 * 1000;
 
+		if services().globals.read_only() {
+			return Ok(());
+		}
+
 		let last_presence = self.db.get_presence(user_id)?;
 		let state_changed = match last_presence {
 			None => true,
@@ -4,21 +4,27 @@ use conduit::{utils, Error, Result};
 use database::{Database, Map};
 use ruma::{
 	api::client::push::{set_pusher, Pusher},
-	UserId,
+	DeviceId, UserId,
 };
 
 pub(super) struct Data {
 	senderkey_pusher: Arc<Map>,
+	senderkey_pusherdevice: Arc<Map>,
+	senderkey_pusherdisabled: Arc<Map>,
 }
 
 impl Data {
 	pub(super) fn new(db: &Arc<Database>) -> Self {
 		Self {
 			senderkey_pusher: db["senderkey_pusher"].clone(),
+			senderkey_pusherdevice: db["senderkey_pusherdevice"].clone(),
+			senderkey_pusherdisabled: db["senderkey_pusherdisabled"].clone(),
 		}
 	}
 
-	pub(super) fn set_pusher(&self, sender: &UserId, pusher: &set_pusher::v3::PusherAction) -> Result<()> {
+	pub(super) fn set_pusher(
+		&self, sender: &UserId, sender_device: Option<&DeviceId>, pusher: &set_pusher::v3::PusherAction,
+	) -> Result<()> {
 		match pusher {
 			set_pusher::v3::PusherAction::Post(data) => {
 				let mut key = sender.as_bytes().to_vec();
@@ -26,17 +32,47 @@ impl Data {
 				key.extend_from_slice(data.pusher.ids.pushkey.as_bytes());
 				self.senderkey_pusher
 					.insert(&key, &serde_json::to_vec(pusher).expect("Pusher is valid JSON value"))?;
+				if let Some(device_id) = sender_device {
+					self.senderkey_pusherdevice
+						.insert(&key, device_id.as_bytes())?;
+				} else {
+					self.senderkey_pusherdevice.remove(&key)?;
+				}
+				self.senderkey_pusherdisabled.remove(&key)?;
 				Ok(())
 			},
-			set_pusher::v3::PusherAction::Delete(ids) => {
-				let mut key = sender.as_bytes().to_vec();
-				key.push(0xFF);
-				key.extend_from_slice(ids.pushkey.as_bytes());
-				self.senderkey_pusher.remove(&key).map_err(Into::into)
-			},
+			set_pusher::v3::PusherAction::Delete(ids) => self.delete_pusher(sender, &ids.pushkey),
 		}
 	}
 
+	pub(super) fn delete_pusher(&self, sender: &UserId, pushkey: &str) -> Result<()> {
+		let mut key = sender.as_bytes().to_vec();
+		key.push(0xFF);
+		key.extend_from_slice(pushkey.as_bytes());
+		self.senderkey_pusher.remove(&key)?;
+		self.senderkey_pusherdevice.remove(&key)?;
+		self.senderkey_pusherdisabled.remove(&key)?;
+		Ok(())
+	}
+
+	/// Pushkeys of the pushers registered by a device of the user
+	pub(super) fn get_device_pushkeys(&self, sender: &UserId, device_id: &DeviceId) -> Result<Vec<String>> {
+		let mut prefix = sender.as_bytes().to_vec();
+		prefix.push(0xFF);
+
+		self.senderkey_pusherdevice
+			.scan_prefix(prefix.clone())
+			.filter(|(_, device)| device == device_id.as_bytes())
+			.map(|(key, _)| {
+				let pushkey = key
+					.get(prefix.len()..)
+					.ok_or_else(|| Error::bad_database("Invalid senderkey_pusherdevice in db"))?;
+				utils::string_from_bytes(pushkey)
+					.map_err(|_| Error::bad_database("Invalid pushkey bytes in senderkey_pusherdevice"))
+			})
+			.collect()
+	}
+
 	pub(super) fn get_pusher(&self, sender: &UserId, pushkey: &str) -> Result<Option<Pusher>> {
 		let mut senderkey = sender.as_bytes().to_vec();
 		senderkey.push(0xFF);
@@ -74,4 +110,26 @@ impl Data {
 			Ok(push_key_string)
 		}))
 	}
+
+	pub(super) fn set_disabled(&self, sender: &UserId, pushkey: &str, disabled: bool) -> Result<()> {
+		let mut key = sender.as_bytes().to_vec();
+		key.push(0xFF);
+		key.extend_from_slice(pushkey.as_bytes());
+
+		if disabled {
+			self.senderkey_pusherdisabled.insert(&key, &[])?;
+		} else {
+			self.senderkey_pusherdisabled.remove(&key)?;
+		}
+
+		Ok(())
+	}
+
+	pub(super) fn is_disabled(&self, sender: &UserId, pushkey: &str) -> Result<bool> {
+		let mut key = sender.as_bytes().to_vec();
+		key.push(0xFF);
+		key.extend_from_slice(pushkey.as_bytes());
+
+		Ok(self.senderkey_pusherdisabled.get(&key)?.is_some())
+	}
 }
@@ -1,6 +1,12 @@
 mod data;
 
-use std::{fmt::Debug, mem, sync::Arc};
+use std::{
+	collections::HashMap,
+	fmt::Debug,
+	mem,
+	sync::{Arc, Mutex},
+	time::{Duration, Instant},
+};
 
 use bytes::BytesMut;
 use conduit::{debug_info, info, trace, warn, Error, Result, Server};
@@ -17,28 +23,97 @@ use ruma::{
 		IncomingResponse, MatrixVersion, OutgoingRequest, SendAccessToken,
 	},
 	events::{
-		room::power_levels::RoomPowerLevelsEventContent, AnySyncTimelineEvent, StateEventType, TimelineEventType,
+		room::{message::RoomMessageEventContent, power_levels::RoomPowerLevelsEventContent},
+		AnySyncTimelineEvent, StateEventType, TimelineEventType,
 	},
+	int,
 	push::{Action, PushConditionPowerLevelsCtx, PushConditionRoomCtx, PushFormat, Ruleset, Tweak},
 	serde::Raw,
-	uint, RoomId, UInt, UserId,
+	uint, DeviceId, OwnedUserId, RoomId, UInt, UserId,
 };
 
-use crate::{services, PduEvent};
+use crate::{sending, services, PduEvent};
+
+/// Delay between a room being read and the badge update, so a burst of read
+/// receipts results in a single push
+const BADGE_UPDATE_DELAY: Duration = Duration::from_secs(5);
 
 pub struct Service {
 	db: Data,
+	health: Mutex<HashMap<(OwnedUserId, String), PusherHealth>>,
+	badges: Mutex<HashMap<OwnedUserId, BadgeState>>,
+}
+
+/// Badge updates of a user
+#[derive(Default)]
+struct BadgeState {
+	/// An update is scheduled
+	pending: bool,
+
+	/// Unread count of the last badge update, unless an event was pushed since
+	last_sent: Option<u64>,
+}
+
+/// Failures of a pusher since its last successful notification
+#[derive(Clone, Debug, Default)]
+pub struct PusherHealth {
+	/// Failures of any kind, which the sender retries with backoff
+	pub failures: u32,
+
+	/// Consecutive rejections by the push gateway; the pusher is disabled
+	/// once these reach `pusher_max_failures`
+	pub rejections: u32,
+
+	/// When the last failure happened and its error
+	pub last_failure: Option<(Instant, String)>,
+}
+
+impl PusherHealth {
+	/// Time the sender waits after the last failure before retrying, the same
+	/// exponential backoff as for federation destinations
+	#[must_use]
+	pub fn backoff(&self) -> Duration { sending::retry_backoff(self.failures) }
 }
 
 impl Service {
 	pub fn build(_server: &Arc<Server>, db: &Arc<Database>) -> Result<Self> {
 		Ok(Self {
 			db: Data::new(db),
+			health: Mutex::new(HashMap::new()),
+			badges: Mutex::new(HashMap::new()),
 		})
 	}
 
-	pub fn set_pusher(&self, sender: &UserId, pusher: &set_pusher::v3::PusherAction) -> Result<()> {
-		self.db.set_pusher(sender, pusher)
+	/// Adds, replaces or deletes a pusher. Registering a pusher again
+	/// re-enables it and resets its failures.
+	pub fn set_pusher(
+		&self, sender: &UserId, sender_device: Option<&DeviceId>, pusher: &set_pusher::v3::PusherAction,
+	) -> Result<()> {
+		let pushkey = match pusher {
+			set_pusher::v3::PusherAction::Post(data) => &data.pusher.ids.pushkey,
+			set_pusher::v3::PusherAction::Delete(ids) => &ids.pushkey,
+		};
+
+		self.health
+			.lock()
+			.expect("locked")
+			.remove(&(sender.to_owned(), pushkey.clone()));
+
+		self.db.set_pusher(sender, sender_device, pusher)
+	}
+
+	/// Deletes the pushers registered by a device, so a device which logged
+	/// out doesn't keep receiving pushes.
+	pub fn remove_device_pushers(&self, sender: &UserId, device_id: &DeviceId) -> Result<()> {
+		for pushkey in self.db.get_device_pushkeys(sender, device_id)? {
+			self.health
+				.lock()
+				.expect("locked")
+				.remove(&(sender.to_owned(), pushkey.clone()));
+			self.db.delete_pusher(sender, &pushkey)?;
+		}
+
+		Ok(())
 	}
 
 	pub fn get_pusher(&self, sender: &UserId, pushkey: &str) -> Result<Option<Pusher>> {
@@ -52,6 +127,163 @@ impl Service {
 		self.db.get_pushkeys(sender)
 	}
 
+	/// Whether the pusher was disabled after being rejected by its push
+	/// gateway too often
+	pub fn is_disabled(&self, sender: &UserId, pushkey: &str) -> Result<bool> { self.db.is_disabled(sender, pushkey) }
+
+	/// Re-enables a disabled pusher and resets its failures
+	pub fn enable_pusher(&self, sender: &UserId, pushkey: &str) -> Result<()> {
+		self.health
+			.lock()
+			.expect("locked")
+			.remove(&(sender.to_owned(), pushkey.to_owned()));
+
+		self.db.set_disabled(sender, pushkey, false)
+	}
+
+	#[must_use]
+	pub fn health(&self, sender: &UserId, pushkey: &str) -> PusherHealth {
+		self.health
+			.lock()
+			.expect("locked")
+			.get(&(sender.to_owned(), pushkey.to_owned()))
+			.cloned()
+			.unwrap_or_default()
+	}
+
+	/// Whether `error` means the push gateway rejected the notification, so it
+	/// should be dropped instead of retried
+	#[must_use]
+	pub fn is_rejection(error: &Error) -> bool { matches!(error, Error::PushGatewayRejected(_)) }
+
+	/// Number of notifications of the user across all their joined rooms
+	pub fn total_unread(&self, user: &UserId) -> Result<UInt> {
+		let mut total: u64 = 0;
+		for room_id in services().rooms.state_cache.rooms_joined(user) {
+			total = total.saturating_add(services().rooms.user.notification_count(user, &room_id?)?);
+		}
+
+		Ok(UInt::try_from(total).unwrap_or(UInt::MAX))
+	}
+
+	/// Updates the badge on the user's devices after `BADGE_UPDATE_DELAY`,
+	/// unless an update is already scheduled.
+	pub fn schedule_badge_update(&self, user: &UserId) {
+		if !mark_badge_pending(&self.badges, user) {
+			return;
+		}
+
+		let user = user.to_owned();
+		services().server.runtime().spawn(async move {
+			tokio::time::sleep(BADGE_UPDATE_DELAY).await;
+			if let Err(e) = services().pusher.queue_badge_update(&user) {
+				warn!("Failed to queue badge update for {user}: {e}");
+			}
+		});
+	}
+
+	/// Queues a badge-only push to each pusher of the user, unless the unread
+	/// count is the same as in the last badge update.
+	fn queue_badge_update(&self, user: &UserId) -> Result<()> {
+		if take_badge_update(&self.badges, user, || self.total_unread(user))?.is_none() {
+			return Ok(());
+		}
+
+		for pushkey in self.get_pushkeys(user) {
+			let pushkey = pushkey?;
+			if self.is_disabled(user, &pushkey)? {
+				continue;
+			}
+
+			services().sending.send_badge_push(user, pushkey)?;
+		}
+
+		Ok(())
+	}
+
+	/// Sends a notification without an event, only updating the unread count
+	/// on the device
+	#[tracing::instrument(skip(self, user, unread, pusher))]
+	pub async fn send_badge(&self, user: &UserId, unread: UInt, pusher: &Pusher) -> Result<()> {
+		let PusherKind::Http(http) = &pusher.kind else {
+			return Ok(());
+		};
+
+		let pushkey = &pusher.ids.pushkey;
+		if self.is_disabled(user, pushkey)? {
+			return Ok(());
+		}
+
+		let mut device = Device::new(pusher.ids.app_id.clone(), pushkey.clone());
+		device.data.default_payload = http.default_payload.clone();
+		device.data.format.clone_from(&http.format);
+
+		let mut notifi = Notification::new(vec![device]);
+		notifi.prio = NotificationPriority::Low;
+		notifi.counts = NotificationCounts::new(unread, uint!(0));
+
+		let result = self
+			.send_request(&http.url, send_event_notification::v1::Request::new(notifi))
+			.await
+			.map(|_| ());
+
+		self.record_result(user, pushkey, result).await
+	}
+
+	async fn record_result(&self, sender: &UserId, pushkey: &str, result: Result<()>) -> Result<()> {
+		match result {
+			Ok(()) => {
+				self.record_success(sender, pushkey);
+				Ok(())
+			},
+			Err(e) => {
+				self.record_failure(sender, pushkey, &e).await?;
+				Err(e)
+			},
+		}
+	}
+
+	fn record_success(&self, sender: &UserId, pushkey: &str) {
+		self.health
+			.lock()
+			.expect("locked")
+			.remove(&(sender.to_owned(), pushkey.to_owned()));
+	}
+
+	async fn record_failure(&self, sender: &UserId, pushkey: &str, error: &Error) -> Result<()> {
+		let rejections = {
+			let mut health = self.health.lock().expect("locked");
+			let health = health
+				.entry((sender.to_owned(), pushkey.to_owned()))
+				.or_default();
+
+			health.failures = health.failures.saturating_add(1);
+			health.rejections = if Self::is_rejection(error) {
+				health.rejections.saturating_add(1)
+			} else {
+				0
+			};
+			health.last_failure = Some((Instant::now(), error.to_string()));
+			health.rejections
+		};
+
+		if rejections < services().globals.config.pusher_max_failures {
+			return Ok(());
+		}
+
+		warn!("Disabling pusher {pushkey:?} of {sender} after {rejections} rejections by its push gateway");
+		self.db.set_disabled(sender, pushkey, true)?;
+		services()
+			.admin
+			.send_message(RoomMessageEventContent::notice_plain(format!(
+				"Disabled pusher {pushkey:?} of {sender} after its push gateway rejected {rejections} notifications \
+				 in a row. It is re-enabled when the client registers it again, or with `users enable-pusher`."
+			)))
+			.await;
+
+		Ok(())
+	}
+
 	#[tracing::instrument(skip(self, dest, request))]
 	pub async fn send_request<T>(&self, dest: &str, request: T) -> Result<T::IncomingResponse>
 	where
@@ -118,6 +350,10 @@ impl Service {
 					info!("Push gateway {dest} returned unsuccessful HTTP response ({status})");
 					debug_info!("Push gateway response body: {:?}", crate::utils::string_from_bytes(&body));
 
+					if status.is_client_error() && status != http::StatusCode::TOO_MANY_REQUESTS {
+						return Err(Error::PushGatewayRejected(status));
+					}
+
 					return Err(Error::BadServerResponse("Push gateway returned unsuccessful response"));
 				}
 
@@ -145,16 +381,7 @@ impl Service {
 		let mut notify = None;
 		let mut tweaks = Vec::new();
 
-		let power_levels: RoomPowerLevelsEventContent = services()
-			.rooms
-			.state_accessor
-			.room_state_get(&pdu.room_id, &StateEventType::RoomPowerLevels, "")?
-			.map(|ev| {
-				serde_json::from_str(ev.content.get())
-					.map_err(|_| Error::bad_database("invalid m.room.power_levels event"))
-			})
-			.transpose()?
-			.unwrap_or_default();
+		let power_levels = self.power_levels(&pdu.room_id)?;
 
 		for action in self.get_actions(user, &ruleset, &power_levels, &pdu.to_sync_room_event(), &pdu.room_id)? {
 			let n = match action {
@@ -176,43 +403,56 @@ impl Service {
 		}
 
 		if notify == Some(true) {
-			self.send_notice(unread, pusher, tweaks, pdu).await?;
+			let result = self.send_notice(unread, pusher, tweaks, pdu).await;
+			if result.is_ok() {
+				// The notice replaced the badge on the device
+				if let Some(badge) = self.badges.lock().expect("locked").get_mut(user) {
+					badge.last_sent = None;
+				}
+			}
+
+			self.record_result(user, &pusher.ids.pushkey, result)
+				.await?;
 		}
 		// Else the event triggered no actions
 
 		Ok(())
 	}
 
+	/// Power levels the `sender_notification_permission` condition of push
+	/// rules is evaluated against. Without an m.room.power_levels event the
+	/// room creator has power level 100, as in the authorization rules.
+	pub fn power_levels(&self, room_id: &RoomId) -> Result<RoomPowerLevelsEventContent> {
+		let state_accessor = &services().rooms.state_accessor;
+		if let Some(event) = state_accessor.room_state_get(room_id, &StateEventType::RoomPowerLevels, "")? {
+			return serde_json::from_str(event.content.get())
+				.map_err(|_| Error::bad_database("invalid m.room.power_levels event"));
+		}
+
+		let mut power_levels = RoomPowerLevelsEventContent::default();
+		if let Some(create) = state_accessor.room_state_get(room_id, &StateEventType::RoomCreate, "")? {
+			power_levels.users.insert(create.sender.clone(), int!(100));
+		}
+
+		Ok(power_levels)
+	}
+
 	#[tracing::instrument(skip(self, user, ruleset, pdu))]
 	pub fn get_actions<'a>(
 		&self, user: &UserId, ruleset: &'a Ruleset, power_levels: &RoomPowerLevelsEventContent,
 		pdu: &Raw<AnySyncTimelineEvent>, room_id: &RoomId,
 	) -> Result<&'a [Action]> {
-		let power_levels = PushConditionPowerLevelsCtx {
-			users: power_levels.users.clone(),
-			users_default: power_levels.users_default,
-			notifications: power_levels.notifications.clone(),
-		};
-
-		let ctx = PushConditionRoomCtx {
-			room_id: room_id.to_owned(),
-			member_count: UInt::try_from(
-				services()
-					.rooms
-					.state_cache
-					.room_joined_count(room_id)?
-					.unwrap_or(1),
-			)
-			.unwrap_or_else(|_| uint!(0)),
-			user_id: user.to_owned(),
-			user_display_name: services()
-				.users
-				.displayname(user)?
-				.unwrap_or_else(|| user.localpart().to_owned()),
-			power_levels: Some(power_levels),
-		};
-
-		Ok(ruleset.get_actions(pdu, &ctx))
+		let member_count = services()
+			.rooms
+			.state_cache
+			.room_joined_count(room_id)?
+			.unwrap_or(1);
+		let display_name = services()
+			.users
+			.displayname(user)?
+			.unwrap_or_else(|| user.localpart().to_owned());
+
+		Ok(ruleset.get_actions(pdu, &room_ctx(user, room_id, member_count, display_name, power_levels)))
 	}
 
 	#[tracing::instrument(skip(self, unread, pusher, tweaks, event))]
@@ -281,3 +521,157 @@ impl Service {
 		}
 	}
 }
+
+/// Marks a badge update of the user as scheduled, returning false when one
+/// already was
+fn mark_badge_pending(badges: &Mutex<HashMap<OwnedUserId, BadgeState>>, user: &UserId) -> bool {
+	let mut badges = badges.lock().expect("locked");
+	let badge = badges.entry(user.to_owned()).or_default();
+	!mem::replace(&mut badge.pending, true)
+}
+
+/// Ends the scheduled badge update of the user, also when counting the unread
+/// notifications fails so later updates aren't suppressed, and returns the
+/// count to send unless it is the same as in the last badge update
+fn take_badge_update<F>(
+	badges: &Mutex<HashMap<OwnedUserId, BadgeState>>, user: &UserId, total_unread: F,
+) -> Result<Option<u64>>
+where
+	F: FnOnce() -> Result<UInt>,
+{
+	let unread = total_unread();
+
+	let mut badges = badges.lock().expect("locked");
+	let badge = badges.entry(user.to_owned()).or_default();
+	badge.pending = false;
+
+	let unread: u64 = unread?.into();
+	if badge.last_sent == Some(unread) {
+		return Ok(None);
+	}
+	badge.last_sent = Some(unread);
+
+	Ok(Some(unread))
+}
+
+/// The room the push rule conditions of `user` are evaluated in
+#[must_use]
+pub fn room_ctx(
+	user: &UserId, room_id: &RoomId, member_count: u64, display_name: String,
+	power_levels: &RoomPowerLevelsEventContent,
+) -> PushConditionRoomCtx {
+	PushConditionRoomCtx {
+		room_id: room_id.to_owned(),
+		member_count: UInt::try_from(member_count).unwrap_or_else(|_| uint!(0)),
+		user_id: user.to_owned(),
+		user_display_name: display_name,
+		power_levels: Some(power_levels_ctx(power_levels)),
+	}
+}
+
+/// The power levels in the form push rule conditions read them: the sender's
+/// level from `users` or `users_default`, compared to `notifications`
+fn power_levels_ctx(power_levels: &RoomPowerLevelsEventContent) -> PushConditionPowerLevelsCtx {
+	PushConditionPowerLevelsCtx {
+		users: power_levels.users.clone(),
+		users_default: power_levels.users_default,
+		notifications: power_levels.notifications.clone(),
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::sync::Mutex;
+
+	use ruma::{
+		events::{room::power_levels::RoomPowerLevelsEventContent, AnySyncTimelineEvent},
+		int, owned_room_id, owned_user_id,
+		push::{Action, PushConditionRoomCtx, Ruleset, Tweak},
+		serde::Raw,
+		uint, user_id, OwnedUserId,
+	};
+	use serde_json::json;
+
+	use super::{mark_badge_pending, power_levels_ctx, take_badge_update, Error, Service};
+
+	fn room_mention_highlights(sender: &OwnedUserId, content: serde_json::Value) -> bool {
+		let user = owned_user_id!("@alice:example.com");
+		let mut power_levels = RoomPowerLevelsEventContent::default();
+		power_levels
+			.users
+			.insert(owned_user_id!("@mod:example.com"), int!(50));
+		power_levels
+			.users
+			.insert(owned_user_id!("@admin:example.com"), int!(100));
+		power_levels.notifications.room = int!(50);
+
+		let ctx = PushConditionRoomCtx {
+			room_id: owned_room_id!("!room:example.com"),
+			member_count: uint!(3),
+			user_id: user.clone(),
+			user_display_name: "Alice".to_owned(),
+			power_levels: Some(power_levels_ctx(&power_levels)),
+		};
+
+		let event: Raw<AnySyncTimelineEvent> = serde_json::from_value(json!({
+			"type": "m.room.message",
+			"event_id": "$event:example.com",
+			"sender": sender,
+			"origin_server_ts": 1,
+			"content": content,
+		}))
+		.unwrap();
+
+		Ruleset::server_default(&user)
+			.get_actions(&event, &ctx)
+			.iter()
+			.any(|action| matches!(action, Action::SetTweak(Tweak::Highlight(true))))
+	}
+
+	#[test]
+	fn room_mention_needs_notifications_room_level() {
+		let intentional = json!({
+			"msgtype": "m.text",
+			"body": "@room meeting now",
+			"m.mentions": { "room": true },
+		});
+		let legacy = json!({ "msgtype": "m.text", "body": "@room meeting now" });
+
+		let moderator = owned_user_id!("@mod:example.com");
+		assert!(room_mention_highlights(&moderator, intentional.clone()));
+		assert!(room_mention_highlights(&moderator, legacy.clone()));
+
+		let user = owned_user_id!("@bob:example.com");
+		assert!(!room_mention_highlights(&user, intentional));
+		assert!(!room_mention_highlights(&user, legacy));
+	}
+
+	#[test]
+	fn badge_updates_end_on_every_path() {
+		let badges = Mutex::default();
+		let user = user_id!("@alice:example.com");
+
+		assert!(mark_badge_pending(&badges, user));
+		assert!(!mark_badge_pending(&badges, user));
+
+		// a failed count ends the update, so the next one is scheduled
+		take_badge_update(&badges, user, || Err(Error::bad_database("unreadable count"))).unwrap_err();
+		assert!(mark_badge_pending(&badges, user));
+
+		assert_eq!(take_badge_update(&badges, user, || Ok(uint!(3))).unwrap(), Some(3));
+		assert!(mark_badge_pending(&badges, user));
+
+		// the same count is pushed only once
+		assert_eq!(take_badge_update(&badges, user, || Ok(uint!(3))).unwrap(), None);
+		assert!(mark_badge_pending(&badges, user));
+		assert_eq!(take_badge_update(&badges, user, || Ok(uint!(0))).unwrap(), Some(0));
+	}
+
+	#[test]
+	fn only_rejections_disable() {
+		assert!(Service::is_rejection(&Error::PushGatewayRejected(http::StatusCode::GONE)));
+		assert!(!Service::is_rejection(&Error::BadServerResponse(
+			"Push gateway returned unsuccessful response"
+		)));
+	}
+}
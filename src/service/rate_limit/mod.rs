@@ -0,0 +1,306 @@
+mod room;
+
+use std::{
+	collections::HashMap,
+	fmt,
+	str::FromStr,
+	sync::{Arc, Mutex},
+	time::{Duration, Instant},
+};
+
+use conduit::{config::RateLimitBucket, Error, Result, Server};
+use database::{Database, Map};
+use ruma::{OwnedUserId, UserId};
+
+pub use self::room::RoomSendLimits;
+use crate::services;
+
+/// Number of buckets above which idle ones are dropped
+const PRUNE_THRESHOLD: usize = 10_000;
+
+/// Buckets unused for this long are dropped once there are many; the default
+/// ones are full again by then
+const PRUNE_IDLE: Duration = Duration::from_secs(60 * 60);
+
+/// Longest retry delay given to clients, for buckets which barely refill
+const MAX_RETRY_AFTER: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Token bucket rate limiting of client requests per user, or per IP address
+/// for unauthenticated requests, and class of endpoints. Buckets are kept in
+/// memory; per-user overrides set by admins are kept in the database.
+pub struct Service {
+	pub rooms: RoomSendLimits,
+	userclass_ratelimitoverride: Arc<Map>,
+	buckets: Mutex<HashMap<(Key, Class), Bucket>>,
+}
+
+/// Whose requests are counted
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub enum Key {
+	User(OwnedUserId),
+	Ip(String),
+}
+
+/// Classes of endpoints sharing a bucket
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum Class {
+	Login,
+	Registration,
+	Message,
+	Join,
+	Media,
+}
+
+#[derive(Clone, Debug)]
+pub struct Bucket {
+	pub tokens: f64,
+	pub updated: Instant,
+}
+
+impl Class {
+	pub const ALL: [Self; 5] = [Self::Login, Self::Registration, Self::Message, Self::Join, Self::Media];
+
+	/// The class of a client API request by its path, if it is limited
+	#[must_use]
+	pub fn of_path(path: &str) -> Option<Self> {
+		if path.starts_with("/_matrix/media/") || path.starts_with("/_matrix/client/v1/media/") {
+			return Some(Self::Media);
+		}
+
+		let path = path.strip_prefix("/_matrix/client/")?;
+		let (_, endpoint) = path.split_once('/')?;
+		let segments: Vec<_> = endpoint.split('/').collect();
+		match segments.as_slice() {
+			["login"] => Some(Self::Login),
+			["register"] => Some(Self::Registration),
+			["join" | "knock", _] | ["rooms", _, "join" | "invite"] => Some(Self::Join),
+			["rooms", _, "send" | "state" | "redact", ..] | ["rooms", _, "messages"] => Some(Self::Message),
+			_ => None,
+		}
+	}
+
+	#[must_use]
+	pub fn as_str(&self) -> &'static str {
+		match self {
+			Self::Login => "login",
+			Self::Registration => "registration",
+			Self::Message => "message",
+			Self::Join => "join",
+			Self::Media => "media",
+		}
+	}
+
+	fn default_bucket(self) -> RateLimitBucket {
+		let config = services().globals.rate_limit();
+		match self {
+			Self::Login => config.login,
+			Self::Registration => config.registration,
+			Self::Message => config.message,
+			Self::Join => config.join,
+			Self::Media => config.media,
+		}
+	}
+}
+
+impl fmt::Display for Class {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result { f.write_str(self.as_str()) }
+}
+
+impl FromStr for Class {
+	type Err = String;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		Self::ALL
+			.into_iter()
+			.find(|class| class.as_str() == s)
+			.ok_or_else(|| format!("unknown rate limit class {s:?}, expected one of login, registration, message, join, media"))
+	}
+}
+
+impl Bucket {
+	fn full(limit: &RateLimitBucket, now: Instant) -> Self {
+		Self {
+			tokens: f64::from(limit.burst),
+			updated: now,
+		}
+	}
+
+	/// Tokens available now
+	fn refilled(&self, limit: &RateLimitBucket) -> f64 { self.refilled_at(limit, Instant::now()) }
+
+	fn refilled_at(&self, limit: &RateLimitBucket, now: Instant) -> f64 {
+		let refill = now.saturating_duration_since(self.updated).as_secs_f64() * limit.per_second;
+		(self.tokens + refill).min(f64::from(limit.burst))
+	}
+
+	/// Takes a token at `now`, or returns how long to wait for the next one
+	fn take(&mut self, limit: &RateLimitBucket, now: Instant) -> Result<(), Duration> {
+		self.tokens = self.refilled_at(limit, now);
+		self.updated = now;
+		if self.tokens >= 1.0 {
+			self.tokens -= 1.0;
+			return Ok(());
+		}
+
+		Err(Duration::try_from_secs_f64((1.0 - self.tokens) / limit.per_second)
+			.map_or(MAX_RETRY_AFTER, |retry_after| retry_after.min(MAX_RETRY_AFTER)))
+	}
+}
+
+impl Service {
+	pub fn build(_server: &Arc<Server>, db: &Arc<Database>) -> Result<Self> {
+		Ok(Self {
+			rooms: RoomSendLimits::new(db),
+			userclass_ratelimitoverride: db["userclass_ratelimitoverride"].clone(),
+			buckets: Mutex::new(HashMap::new()),
+		})
+	}
+
+	/// Takes a token from the bucket of `key` for `class`. Returns how long to
+	/// wait before retrying when the bucket is empty.
+	pub fn check(&self, key: Key, class: Class) -> Result<Result<(), Duration>> {
+		let limit = match &key {
+			Key::User(user_id) => self.limit(user_id, class)?,
+			Key::Ip(_) => class.default_bucket(),
+		};
+
+		let mut buckets = self.buckets.lock().expect("locked");
+		if buckets.len() > PRUNE_THRESHOLD {
+			buckets.retain(|_, bucket| bucket.updated.elapsed() < PRUNE_IDLE);
+		}
+
+		let now = Instant::now();
+		Ok(buckets
+			.entry((key, class))
+			.or_insert_with(|| Bucket::full(&limit, now))
+			.take(&limit, now))
+	}
+
+	/// The bucket size and refill rate applying to the user for `class`
+	pub fn limit(&self, user_id: &UserId, class: Class) -> Result<RateLimitBucket> {
+		Ok(self
+			.get_override(user_id, class)?
+			.unwrap_or_else(|| class.default_bucket()))
+	}
+
+	/// The tokens currently left in the user's bucket for `class`, `None` if it
+	/// is full
+	pub fn tokens(&self, user_id: &UserId, class: Class) -> Result<Option<f64>> {
+		let limit = self.limit(user_id, class)?;
+		Ok(self
+			.buckets
+			.lock()
+			.expect("locked")
+			.get(&(Key::User(user_id.to_owned()), class))
+			.map(|bucket| bucket.refilled(&limit))
+			.filter(|tokens| *tokens < f64::from(limit.burst)))
+	}
+
+	pub fn get_override(&self, user_id: &UserId, class: Class) -> Result<Option<RateLimitBucket>> {
+		self.userclass_ratelimitoverride
+			.get(&override_key(user_id, class))?
+			.map(|limit| {
+				serde_json::from_slice(&limit)
+					.map_err(|_| Error::bad_database("Invalid rate limit in userclass_ratelimitoverride."))
+			})
+			.transpose()
+	}
+
+	/// Replaces the user's bucket for `class`, `None` returns to the configured
+	/// default
+	pub fn set_override(&self, user_id: &UserId, class: Class, limit: Option<RateLimitBucket>) -> Result<()> {
+		let key = override_key(user_id, class);
+		match limit {
+			Some(limit) => self.userclass_ratelimitoverride.insert(
+				&key,
+				&serde_json::to_vec(&limit).expect("rate limit bucket serializes"),
+			)?,
+			None => self.userclass_ratelimitoverride.remove(&key)?,
+		}
+
+		// start over with the new burst
+		self.buckets
+			.lock()
+			.expect("locked")
+			.remove(&(Key::User(user_id.to_owned()), class));
+
+		Ok(())
+	}
+}
+
+fn override_key(user_id: &UserId, class: Class) -> Vec<u8> {
+	let mut key = user_id.as_bytes().to_vec();
+	key.push(0xFF);
+	key.extend_from_slice(class.as_str().as_bytes());
+	key
+}
+
+#[cfg(test)]
+mod tests {
+	use std::time::{Duration, Instant};
+
+	use conduit::config::RateLimitBucket;
+
+	use super::{Bucket, Class, MAX_RETRY_AFTER};
+
+	#[test]
+	fn token_bucket() {
+		let limit = RateLimitBucket {
+			burst: 3,
+			per_second: 0.5,
+		};
+		let start = Instant::now();
+		let mut bucket = Bucket::full(&limit, start);
+
+		for _ in 0..3 {
+			bucket.take(&limit, start).unwrap();
+		}
+		assert_eq!(bucket.take(&limit, start), Err(Duration::from_secs(2)));
+
+		// half a token refilled after a second
+		let later = start + Duration::from_secs(1);
+		assert_eq!(bucket.take(&limit, later), Err(Duration::from_secs(1)));
+		bucket.take(&limit, later + Duration::from_secs(1)).unwrap();
+
+		// refilled up to the burst only
+		let idle = later + Duration::from_secs(3600);
+		assert!((bucket.refilled_at(&limit, idle) - 3.0).abs() < f64::EPSILON);
+
+		let barely = RateLimitBucket {
+			burst: 1,
+			per_second: 1e-9,
+		};
+		let mut bucket = Bucket::full(&barely, start);
+		bucket.take(&barely, start).unwrap();
+		assert_eq!(bucket.take(&barely, start), Err(MAX_RETRY_AFTER));
+	}
+
+	#[test]
+	fn classes_by_path() {
+		assert_eq!(Class::of_path("/_matrix/client/v3/login"), Some(Class::Login));
+		assert_eq!(Class::of_path("/_matrix/client/r0/register"), Some(Class::Registration));
+		assert_eq!(
+			Class::of_path("/_matrix/client/v3/rooms/!a:example.com/send/m.room.message/1"),
+			Some(Class::Message)
+		);
+		assert_eq!(
+			Class::of_path("/_matrix/client/v3/rooms/!a:example.com/messages"),
+			Some(Class::Message)
+		);
+		assert_eq!(Class::of_path("/_matrix/client/v3/join/#a:example.com"), Some(Class::Join));
+		assert_eq!(Class::of_path("/_matrix/client/v3/rooms/!a:example.com/invite"), Some(Class::Join));
+		assert_eq!(Class::of_path("/_matrix/media/v3/upload"), Some(Class::Media));
+		assert_eq!(Class::of_path("/_matrix/client/v1/media/download/a/b"), Some(Class::Media));
+		assert_eq!(Class::of_path("/_matrix/client/v3/sync"), None);
+		assert_eq!(Class::of_path("/_matrix/federation/v1/send/1"), None);
+	}
+
+	#[test]
+	fn class_names_round_trip() {
+		for class in Class::ALL {
+			assert_eq!(class.as_str().parse::<Class>(), Ok(class));
+		}
+
+		"messages".parse::<Class>().unwrap_err();
+	}
+}
@@ -0,0 +1,222 @@
+use std::{
+	collections::HashMap,
+	sync::{Arc, Mutex},
+	time::Instant,
+};
+
+use conduit::{config::RateLimitBucket, Error, Result};
+use database::{Database, Map};
+use ruma::{
+	api::client::error::{ErrorKind, RetryAfter},
+	events::{
+		room::power_levels::{RoomPowerLevels, RoomPowerLevelsEventContent},
+		StateEventType, TimelineEventType,
+	},
+	OwnedRoomId, OwnedUserId, RoomId, UserId,
+};
+
+use super::{Bucket, PRUNE_IDLE, PRUNE_THRESHOLD};
+use crate::{services, user_is_local, PduEvent};
+
+/// Throttle of events sent by local users, per user and room, against fresh
+/// accounts spamming rooms. Only timeline events besides redactions are
+/// throttled, so state changes such as creating rooms, bans and kicks are not,
+/// and neither are users with at least `room_send_exempt_power_level` in the
+/// room. Off unless `room_send` is configured or an admin set an override for
+/// the room. Per-room overrides are kept in the database; the numbers of
+/// throttled sends are kept in memory.
+pub struct RoomSendLimits {
+	roomid_sendratelimit: Arc<Map>,
+	buckets: Mutex<HashMap<(OwnedUserId, OwnedRoomId), Bucket>>,
+	throttled: Mutex<HashMap<OwnedRoomId, u64>>,
+}
+
+impl RoomSendLimits {
+	pub(super) fn new(db: &Arc<Database>) -> Self {
+		Self {
+			roomid_sendratelimit: db["roomid_sendratelimit"].clone(),
+			buckets: Mutex::new(HashMap::new()),
+			throttled: Mutex::new(HashMap::new()),
+		}
+	}
+
+	/// Takes a token from the sender's bucket in the room for the signed
+	/// event, failing with `M_LIMIT_EXCEEDED` when it is empty.
+	pub async fn check(&self, sender: &UserId, room_id: &RoomId, pdu: &PduEvent) -> Result<()> {
+		if !services().globals.rate_limit().enabled
+			|| !is_throttled(pdu)
+			|| !user_is_local(sender)
+			|| sender == services().globals.server_user
+			|| services().users.is_admin(sender)?
+			|| self.is_exempt(sender, room_id)?
+			|| services()
+				.appservice
+				.read()
+				.await
+				.values()
+				.any(|info| !info.is_rate_limited() && info.is_user_match(sender))
+		{
+			return Ok(());
+		}
+
+		let Some(limit) = self.limit(room_id)? else {
+			return Ok(());
+		};
+
+		let mut buckets = self.buckets.lock().expect("locked");
+		if buckets.len() > PRUNE_THRESHOLD {
+			buckets.retain(|_, bucket| bucket.updated.elapsed() < PRUNE_IDLE);
+		}
+
+		let now = Instant::now();
+		let Err(retry_after) = buckets
+			.entry((sender.to_owned(), room_id.to_owned()))
+			.or_insert_with(|| Bucket::full(&limit, now))
+			.take(&limit, now)
+		else {
+			return Ok(());
+		};
+		drop(buckets);
+
+		let mut throttled = self.throttled.lock().expect("locked");
+		let count = throttled.entry(room_id.to_owned()).or_default();
+		*count = count.saturating_add(1);
+
+		Err(Error::BadRequest(
+			ErrorKind::LimitExceeded {
+				retry_after: Some(RetryAfter::Delay(retry_after)),
+			},
+			"You are sending too many events to this room, try again later.",
+		))
+	}
+
+	/// The bucket applying to senders in the room, `None` when they aren't
+	/// throttled
+	pub fn limit(&self, room_id: &RoomId) -> Result<Option<RateLimitBucket>> {
+		Ok(self
+			.get_override(room_id)?
+			.or(services().globals.rate_limit().room_send))
+	}
+
+	pub fn get_override(&self, room_id: &RoomId) -> Result<Option<RateLimitBucket>> {
+		self.roomid_sendratelimit
+			.get(room_id.as_bytes())?
+			.map(|limit| {
+				serde_json::from_slice(&limit)
+					.map_err(|_| Error::bad_database("Invalid rate limit in roomid_sendratelimit."))
+			})
+			.transpose()
+	}
+
+	/// Replaces the bucket of senders in the room, `None` returns to the
+	/// configured default
+	pub fn set_override(&self, room_id: &RoomId, limit: Option<RateLimitBucket>) -> Result<()> {
+		match limit {
+			Some(limit) => self.roomid_sendratelimit.insert(
+				room_id.as_bytes(),
+				&serde_json::to_vec(&limit).expect("rate limit bucket serializes"),
+			)?,
+			None => self.roomid_sendratelimit.remove(room_id.as_bytes())?,
+		}
+
+		// start over with the new burst
+		self.buckets
+			.lock()
+			.expect("locked")
+			.retain(|(_, bucket_room_id), _| bucket_room_id != room_id);
+
+		Ok(())
+	}
+
+	/// Rooms with the number of sends throttled since startup, most throttled
+	/// first
+	#[must_use]
+	pub fn throttled(&self) -> Vec<(OwnedRoomId, u64)> {
+		let mut throttled: Vec<_> = self
+			.throttled
+			.lock()
+			.expect("locked")
+			.iter()
+			.map(|(room_id, count)| (room_id.clone(), *count))
+			.collect();
+
+		throttled.sort_by(|(a_room, a), (b_room, b)| b.cmp(a).then_with(|| a_room.cmp(b_room)));
+		throttled
+	}
+
+	/// Whether the sender's power level in the room exempts them
+	fn is_exempt(&self, sender: &UserId, room_id: &RoomId) -> Result<bool> {
+		let threshold = services().globals.rate_limit().room_send_exempt_power_level;
+
+		let Some(event) =
+			services()
+				.rooms
+				.state_accessor
+				.room_state_get(room_id, &StateEventType::RoomPowerLevels, "")?
+		else {
+			// without power levels the creator has 100, everyone else 0
+			let is_creator = services()
+				.rooms
+				.state_accessor
+				.room_state_get(room_id, &StateEventType::RoomCreate, "")?
+				.is_some_and(|pdu| pdu.sender == sender);
+			let power_level = if is_creator {
+				100
+			} else {
+				0
+			};
+			return Ok(power_level >= threshold);
+		};
+
+		let power_levels = RoomPowerLevels::from(
+			serde_json::from_str::<RoomPowerLevelsEventContent>(event.content.get())
+				.map_err(|_| Error::bad_database("Invalid m.room.power_levels event in database"))?,
+		);
+
+		let power_level = power_levels
+			.users
+			.get(sender)
+			.copied()
+			.unwrap_or(power_levels.users_default);
+
+		Ok(i64::from(power_level) >= threshold)
+	}
+}
+
+/// Whether the event counts against the throttle; redactions and state events
+/// are needed for moderation and are never throttled
+fn is_throttled(pdu: &PduEvent) -> bool { pdu.state_key.is_none() && pdu.kind != TimelineEventType::RoomRedaction }
+
+#[cfg(test)]
+mod tests {
+	use ruma::events::TimelineEventType;
+
+	use super::is_throttled;
+	use crate::PduEvent;
+
+	fn pdu(event_type: TimelineEventType, state_key: Option<&str>) -> PduEvent {
+		serde_json::from_value(serde_json::json!({
+			"event_id": "$event",
+			"room_id": "!room:example.com",
+			"sender": "@sender:example.com",
+			"origin_server_ts": 1,
+			"type": event_type,
+			"content": {},
+			"state_key": state_key,
+			"prev_events": [],
+			"depth": 1,
+			"auth_events": [],
+			"hashes": { "sha256": "" },
+		}))
+		.expect("valid pdu")
+	}
+
+	#[test]
+	fn throttled_events() {
+		assert!(is_throttled(&pdu(TimelineEventType::RoomMessage, None)));
+		assert!(is_throttled(&pdu(TimelineEventType::Reaction, None)));
+		assert!(!is_throttled(&pdu(TimelineEventType::RoomRedaction, None)));
+		assert!(!is_throttled(&pdu(TimelineEventType::RoomMember, Some("@spam:example.com"))));
+		assert!(!is_throttled(&pdu(TimelineEventType::RoomTopic, Some(""))));
+	}
+}
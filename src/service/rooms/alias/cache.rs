@@ -0,0 +1,104 @@
+use std::{
+	collections::HashMap,
+	sync::Mutex,
+	time::{Duration, Instant},
+};
+
+use ruma::{OwnedRoomAliasId, OwnedRoomId, OwnedServerName, RoomAliasId};
+
+/// Results of resolving room aliases of other servers over federation. Both
+/// resolved aliases and failures are kept until their TTL passes, so joins and
+/// directory lookups of the same alias share one federation query. These are
+/// only kept in memory.
+#[derive(Default)]
+pub struct RemoteAliasCache {
+	aliases: Mutex<HashMap<OwnedRoomAliasId, CachedAlias>>,
+}
+
+#[derive(Clone, Debug)]
+pub struct CachedAlias {
+	pub result: Resolution,
+	pub cached_at: Instant,
+	pub ttl: Duration,
+}
+
+#[derive(Clone, Debug)]
+pub enum Resolution {
+	Resolved {
+		room_id: OwnedRoomId,
+		servers: Vec<OwnedServerName>,
+	},
+
+	/// The alias could not be resolved when asking the alias server and the
+	/// given servers
+	Failed {
+		error: String,
+		servers: Vec<OwnedServerName>,
+	},
+}
+
+impl CachedAlias {
+	#[must_use]
+	pub fn is_expired(&self) -> bool { self.cached_at.elapsed() >= self.ttl }
+}
+
+impl RemoteAliasCache {
+	/// The cached result of `alias` if it has not expired. Failures are only
+	/// returned when every server in `servers` was asked already.
+	pub(super) fn get(&self, alias: &RoomAliasId, servers: Option<&Vec<OwnedServerName>>) -> Option<Resolution> {
+		let mut aliases = self.aliases.lock().expect("locked");
+		let cached = aliases.get(alias)?;
+		if cached.is_expired() {
+			aliases.remove(alias);
+			return None;
+		}
+
+		match &cached.result {
+			Resolution::Failed {
+				servers: tried,
+				..
+			} if servers.is_some_and(|servers| servers.iter().any(|server| !tried.contains(server))) => None,
+			result => Some(result.clone()),
+		}
+	}
+
+	pub(super) fn insert(&self, alias: &RoomAliasId, result: Resolution, ttl: Duration) {
+		let mut aliases = self.aliases.lock().expect("locked");
+		aliases.retain(|_, cached| !cached.is_expired());
+		aliases.insert(
+			alias.to_owned(),
+			CachedAlias {
+				result,
+				cached_at: Instant::now(),
+				ttl,
+			},
+		);
+	}
+
+	/// Forgets the cached result of `alias`. Returns whether there was one.
+	pub fn remove(&self, alias: &RoomAliasId) -> bool { self.aliases.lock().expect("locked").remove(alias).is_some() }
+
+	/// Forgets every cached result. Returns the number of entries removed.
+	pub fn clear(&self) -> usize {
+		let mut aliases = self.aliases.lock().expect("locked");
+		let len = aliases.len();
+		aliases.clear();
+		len
+	}
+
+	/// Unexpired entries, by alias
+	#[must_use]
+	pub fn all(&self) -> Vec<(OwnedRoomAliasId, CachedAlias)> {
+		let mut all: Vec<_> = self
+			.aliases
+			.lock()
+			.expect("locked")
+			.iter()
+			.filter(|(_, cached)| !cached.is_expired())
+			.map(|(alias, cached)| (alias.clone(), cached.clone()))
+			.collect();
+
+		all.sort_by(|(a, _), (b, _)| a.cmp(b));
+		all
+	}
+}
@@ -42,8 +42,11 @@ impl Data {
 			let mut prefix = room_id;
 			prefix.push(0xFF);
 
-			for (key, _) in self.aliasid_alias.scan_prefix(prefix) {
-				self.aliasid_alias.remove(&key)?;
+			for (key, value) in self.aliasid_alias.scan_prefix(prefix) {
+				// other aliases of the room are indexed under the same prefix
+				if value == alias.as_bytes() {
+					self.aliasid_alias.remove(&key)?;
+				}
 			}
 
 			self.alias_roomid.remove(alias.alias().as_bytes())?;
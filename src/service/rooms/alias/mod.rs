@@ -1,9 +1,11 @@
+pub mod cache;
 mod data;
 mod remote;
 
-use std::sync::Arc;
+use std::{sync::Arc, time::Duration};
 
-use conduit::{Error, Result, Server};
+use cache::{RemoteAliasCache, Resolution};
+use conduit::{debug, Error, Result, Server};
 use data::Data;
 use database::Database;
 use ruma::{
@@ -12,19 +14,21 @@ use ruma::{
 		room::power_levels::{RoomPowerLevels, RoomPowerLevelsEventContent},
 		StateEventType,
 	},
-	OwnedRoomAliasId, OwnedRoomId, OwnedServerName, RoomAliasId, RoomId, RoomOrAliasId, UserId,
+	OwnedRoomAliasId, OwnedRoomId, OwnedServerName, OwnedUserId, RoomAliasId, RoomId, RoomOrAliasId, UserId,
 };
 
 use crate::{appservice::RegistrationInfo, server_is_ours, services};
 
 pub struct Service {
 	db: Data,
+	pub remote_cache: RemoteAliasCache,
 }
 
 impl Service {
 	pub fn build(_server: &Arc<Server>, db: &Arc<Database>) -> Result<Self> {
 		Ok(Self {
 			db: Data::new(db),
+			remote_cache: RemoteAliasCache::default(),
 		})
 	}
 
@@ -36,6 +40,7 @@ impl Service {
 				"Only the server user can set this alias",
 			))
 		} else {
+			self.remote_cache.remove(alias);
 			self.db.set_alias(alias, room_id, user_id)
 		}
 	}
@@ -43,6 +48,7 @@ impl Service {
 	#[tracing::instrument(skip(self))]
 	pub async fn remove_alias(&self, alias: &RoomAliasId, user_id: &UserId) -> Result<()> {
 		if self.user_can_remove_alias(alias, user_id).await? {
+			self.remote_cache.remove(alias);
 			self.db.remove_alias(alias)
 		} else {
 			Err(Error::BadRequest(
@@ -72,7 +78,7 @@ impl Service {
 				.is_some_and(|servers| servers.contains(&services().globals.server_name().to_owned()))
 				|| servers.as_ref().is_none())
 		{
-			return remote::resolve(room_alias, servers).await;
+			return self.resolve_remote_alias(room_alias, servers).await;
 		}
 
 		let room_id: Option<OwnedRoomId> = match self.resolve_local_alias(room_alias)? {
@@ -86,11 +92,68 @@ impl Service {
 		)
 	}
 
+	/// Resolves an alias of another server over federation, or from the cache
+	/// of recent results
+	async fn resolve_remote_alias(
+		&self, room_alias: &RoomAliasId, servers: Option<&Vec<OwnedServerName>>,
+	) -> Result<(OwnedRoomId, Option<Vec<OwnedServerName>>)> {
+		match self.remote_cache.get(room_alias, servers) {
+			Some(Resolution::Resolved {
+				room_id,
+				servers,
+			}) => return Ok((room_id, Some(servers))),
+			Some(Resolution::Failed {
+				error,
+				..
+			}) => {
+				debug!(?room_alias, "Resolving the alias failed recently: {error}");
+				return Err(Error::BadRequest(
+					ErrorKind::NotFound,
+					"No servers could assist in resolving the room alias",
+				));
+			},
+			None => {},
+		}
+
+		let result = remote::resolve(room_alias, servers).await;
+		let config = &services().globals.config;
+		match &result {
+			Ok((room_id, resolved_servers)) => self.remote_cache.insert(
+				room_alias,
+				Resolution::Resolved {
+					room_id: room_id.clone(),
+					servers: resolved_servers.clone().unwrap_or_default(),
+				},
+				Duration::from_secs(config.remote_alias_cache_ttl_secs),
+			),
+			Err(e) => {
+				let mut tried = servers.cloned().unwrap_or_default();
+				tried.push(room_alias.server_name().to_owned());
+				self.remote_cache.insert(
+					room_alias,
+					Resolution::Failed {
+						error: e.to_string(),
+						servers: tried,
+					},
+					Duration::from_secs(config.remote_alias_negative_cache_ttl_secs),
+				);
+			},
+		}
+
+		result
+	}
+
 	#[tracing::instrument(skip(self))]
 	pub fn resolve_local_alias(&self, alias: &RoomAliasId) -> Result<Option<OwnedRoomId>> {
 		self.db.resolve_local_alias(alias)
 	}
 
+	/// Returns the user who created a local alias, if recorded
+	#[tracing::instrument(skip(self))]
+	pub fn who_created_alias(&self, alias: &RoomAliasId) -> Result<Option<OwnedUserId>> {
+		self.db.who_created_alias(alias)
+	}
+
 	#[tracing::instrument(skip(self))]
 	pub fn local_aliases_for_room<'a>(
 		&'a self, room_id: &RoomId,
@@ -182,13 +245,8 @@ pub async fn appservice_checks(room_alias: &RoomAliasId, appservice_info: &Optio
 		return Err(Error::BadRequest(ErrorKind::InvalidParam, "Alias is from another server."));
 	}
 
-	if let Some(ref info) = appservice_info {
-		if !info.aliases.is_match(room_alias.as_str()) {
-			return Err(Error::BadRequest(ErrorKind::Exclusive, "Room alias is not in namespace."));
-		}
-	} else if services().appservice.is_exclusive_alias(room_alias).await {
-		return Err(Error::BadRequest(ErrorKind::Exclusive, "Room alias reserved by appservice."));
-	}
-
-	Ok(())
+	services()
+		.appservice
+		.check_alias(room_alias, appservice_info.as_ref())
+		.await
 }
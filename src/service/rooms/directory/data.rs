@@ -1,17 +1,23 @@
-use std::sync::Arc;
+use std::{collections::BTreeSet, sync::Arc};
 
 use conduit::{utils, Error, Result};
 use database::{Database, Map};
-use ruma::{OwnedRoomId, RoomId};
+use ruma::{room::RoomType, OwnedRoomId, RoomId};
 
 pub(super) struct Data {
 	publicroomids: Arc<Map>,
+	directorytoken_roomid: Arc<Map>,
+	roomid_directorytokens: Arc<Map>,
+	roomid_directoryroomtype: Arc<Map>,
 }
 
 impl Data {
 	pub(super) fn new(db: &Arc<Database>) -> Self {
 		Self {
 			publicroomids: db["publicroomids"].clone(),
+			directorytoken_roomid: db["directorytoken_roomid"].clone(),
+			roomid_directorytokens: db["roomid_directorytokens"].clone(),
+			roomid_directoryroomtype: db["roomid_directoryroomtype"].clone(),
 		}
 	}
 
@@ -20,7 +26,28 @@ impl Data {
 	}
 
 	pub(super) fn set_not_public(&self, room_id: &RoomId) -> Result<()> {
-		self.publicroomids.remove(room_id.as_bytes())
+		self.publicroomids.remove(room_id.as_bytes())?;
+		self.roomid_directoryroomtype.remove(room_id.as_bytes())
+	}
+
+	/// The recorded type of the room, `None` when it was not recorded yet
+	pub(super) fn room_type(&self, room_id: &RoomId) -> Result<Option<Option<RoomType>>> {
+		self.roomid_directoryroomtype
+			.get(room_id.as_bytes())?
+			.map(|room_type| {
+				let room_type = utils::string_from_bytes(&room_type)
+					.map_err(|_| Error::bad_database("Room type in roomid_directoryroomtype is invalid unicode."))?;
+
+				// rooms without a type are recorded with an empty string
+				Ok((!room_type.is_empty()).then(|| RoomType::from(room_type.as_str())))
+			})
+			.transpose()
+	}
+
+	pub(super) fn set_room_type(&self, room_id: &RoomId, room_type: Option<&RoomType>) -> Result<()> {
+		let room_type = room_type.map(ToString::to_string).unwrap_or_default();
+		self.roomid_directoryroomtype
+			.insert(room_id.as_bytes(), room_type.as_bytes())
 	}
 
 	pub(super) fn is_public_room(&self, room_id: &RoomId) -> Result<bool> {
@@ -36,4 +63,67 @@ impl Data {
 			.map_err(|_| Error::bad_database("Room ID in publicroomids is invalid."))
 		}))
 	}
+
+	pub(super) fn is_indexed(&self, room_id: &RoomId) -> Result<bool> {
+		Ok(self
+			.roomid_directorytokens
+			.get(room_id.as_bytes())?
+			.is_some())
+	}
+
+	/// Replaces the search tokens of the room
+	pub(super) fn index(&self, room_id: &RoomId, tokens: &BTreeSet<String>) -> Result<()> {
+		self.remove_index(room_id)?;
+
+		for token in tokens {
+			self.directorytoken_roomid
+				.insert(&token_key(token, room_id), &[])?;
+		}
+
+		// tokens are alphanumeric, so they are stored separated by spaces
+		let tokens: Vec<_> = tokens.iter().map(String::as_str).collect();
+		self.roomid_directorytokens
+			.insert(room_id.as_bytes(), tokens.join(" ").as_bytes())
+	}
+
+	pub(super) fn remove_index(&self, room_id: &RoomId) -> Result<()> {
+		let Some(tokens) = self.roomid_directorytokens.get(room_id.as_bytes())? else {
+			return Ok(());
+		};
+
+		let tokens = utils::string_from_bytes(&tokens)
+			.map_err(|_| Error::bad_database("Tokens in roomid_directorytokens are invalid unicode."))?;
+		for token in tokens.split_whitespace() {
+			self.directorytoken_roomid
+				.remove(&token_key(token, room_id))?;
+		}
+
+		self.roomid_directorytokens.remove(room_id.as_bytes())
+	}
+
+	/// Rooms having a token starting with `prefix`
+	pub(super) fn rooms_with_token_prefix(&self, prefix: &str) -> Result<BTreeSet<OwnedRoomId>> {
+		self.directorytoken_roomid
+			.scan_prefix(prefix.as_bytes().to_vec())
+			.map(|(key, _)| {
+				let room_id = key
+					.rsplit(|&b| b == 0xFF)
+					.next()
+					.ok_or_else(|| Error::bad_database("Invalid key in directorytoken_roomid."))?;
+
+				RoomId::parse(
+					utils::string_from_bytes(room_id)
+						.map_err(|_| Error::bad_database("Room ID in directorytoken_roomid is invalid unicode."))?,
+				)
+				.map_err(|_| Error::bad_database("Room ID in directorytoken_roomid is invalid."))
+			})
+			.collect()
+	}
+}
+
+fn token_key(token: &str, room_id: &RoomId) -> Vec<u8> {
+	let mut key = token.as_bytes().to_vec();
+	key.push(0xFF);
+	key.extend_from_slice(room_id.as_bytes());
+	key
 }
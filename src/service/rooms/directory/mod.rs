@@ -1,34 +1,216 @@
 mod data;
 
-use std::sync::Arc;
+use std::{
+	collections::BTreeSet,
+	sync::{
+		atomic::{AtomicBool, Ordering},
+		Arc,
+	},
+};
 
-use conduit::Server;
+use conduit::{warn, Server};
 use data::Data;
 use database::Database;
-use ruma::{OwnedRoomId, RoomId};
+use ruma::{room::RoomType, OwnedRoomId, RoomId};
 
-use crate::Result;
+use crate::{rooms::search::tokenize, services, Result};
 
 pub struct Service {
 	db: Data,
+
+	/// Set while the rooms published before their type and search index were
+	/// recorded are backfilled
+	backfilling: AtomicBool,
 }
 
 impl Service {
 	pub fn build(_server: &Arc<Server>, db: &Arc<Database>) -> Result<Self> {
 		Ok(Self {
 			db: Data::new(db),
+			backfilling: AtomicBool::new(false),
 		})
 	}
 
 	#[tracing::instrument(skip(self))]
-	pub fn set_public(&self, room_id: &RoomId) -> Result<()> { self.db.set_public(room_id) }
+	pub fn set_public(&self, room_id: &RoomId) -> Result<()> {
+		self.db.set_public(room_id)?;
+		self.db
+			.set_room_type(room_id, services().rooms.metadata.room_type(room_id)?.as_ref())?;
+		self.index(room_id)
+	}
 
 	#[tracing::instrument(skip(self))]
-	pub fn set_not_public(&self, room_id: &RoomId) -> Result<()> { self.db.set_not_public(room_id) }
+	pub fn set_not_public(&self, room_id: &RoomId) -> Result<()> {
+		self.db.set_not_public(room_id)?;
+		self.db.remove_index(room_id)
+	}
 
 	#[tracing::instrument(skip(self))]
 	pub fn is_public_room(&self, room_id: &RoomId) -> Result<bool> { self.db.is_public_room(room_id) }
 
 	#[tracing::instrument(skip(self))]
 	pub fn public_rooms(&self) -> impl Iterator<Item = Result<OwnedRoomId>> + '_ { self.db.public_rooms() }
+
+	/// Type of a published room from its create event, as recorded when it was
+	/// published. Rooms published before types were recorded are queued to
+	/// have it recorded.
+	#[tracing::instrument(skip(self))]
+	pub fn room_type(&self, room_id: &RoomId) -> Result<Option<RoomType>> {
+		if let Some(room_type) = self.db.room_type(room_id)? {
+			return Ok(room_type);
+		}
+
+		self.queue_backfill();
+		services().rooms.metadata.room_type(room_id)
+	}
+
+	/// Records the type of a published room when its create event is appended
+	#[tracing::instrument(skip(self))]
+	pub fn update_room_type(&self, room_id: &RoomId, room_type: Option<&RoomType>) -> Result<()> {
+		if !self.db.is_public_room(room_id)? {
+			return Ok(());
+		}
+
+		self.db.set_room_type(room_id, room_type)
+	}
+
+	/// Updates the search index of a published room after its name, topic or
+	/// canonical alias changed.
+	#[tracing::instrument(skip(self))]
+	pub fn update_index(&self, room_id: &RoomId) -> Result<()> {
+		if !self.db.is_public_room(room_id)? {
+			return Ok(());
+		}
+
+		self.index(room_id)
+	}
+
+	/// Published rooms whose canonical alias, name or topic contain words
+	/// starting with every word of `search_term`, case-insensitively. Returns
+	/// `None` when the term has no words, so every room matches.
+	#[tracing::instrument(skip(self))]
+	pub fn search(&self, search_term: &str) -> Result<Option<BTreeSet<OwnedRoomId>>> {
+		let words: BTreeSet<_> = tokenize(search_term).collect();
+		if words.is_empty() {
+			return Ok(None);
+		}
+
+		let mut matches = self.indexed_matches(&words)?;
+
+		// rooms published before the index existed are matched without it
+		// until they are indexed in the background
+		for room_id in self.db.public_rooms() {
+			let room_id = room_id?;
+			if self.db.is_indexed(&room_id)? {
+				continue;
+			}
+
+			self.queue_backfill();
+			if matches_words(&words, &self.tokens(&room_id)?) {
+				matches.insert(room_id);
+			}
+		}
+
+		Ok(Some(matches))
+	}
+
+	fn indexed_matches(&self, words: &BTreeSet<String>) -> Result<BTreeSet<OwnedRoomId>> {
+		let mut matches: Option<BTreeSet<OwnedRoomId>> = None;
+		for word in words {
+			let rooms = self.db.rooms_with_token_prefix(word)?;
+			let rooms = match matches {
+				Some(matches) => matches.intersection(&rooms).cloned().collect(),
+				None => rooms,
+			};
+
+			if rooms.is_empty() {
+				return Ok(rooms);
+			}
+
+			matches = Some(rooms);
+		}
+
+		Ok(matches.unwrap_or_default())
+	}
+
+	/// Records the type and search index of the rooms published before they
+	/// were recorded, in a background task so requests reading the directory
+	/// do not write
+	fn queue_backfill(&self) {
+		if services().globals.read_only() || self.backfilling.swap(true, Ordering::AcqRel) {
+			return;
+		}
+
+		services().server.runtime().spawn(async move {
+			let directory = &services().rooms.directory;
+			if let Err(e) = directory.backfill() {
+				warn!("Failed to backfill the room directory: {e}");
+			}
+
+			directory.backfilling.store(false, Ordering::Release);
+		});
+	}
+
+	fn backfill(&self) -> Result<()> {
+		for room_id in self.db.public_rooms() {
+			let room_id = room_id?;
+			if self.db.room_type(&room_id)?.is_none() {
+				let room_type = services().rooms.metadata.room_type(&room_id)?;
+				self.update_room_type(&room_id, room_type.as_ref())?;
+			}
+
+			if !self.db.is_indexed(&room_id)? {
+				self.update_index(&room_id)?;
+			}
+		}
+
+		Ok(())
+	}
+
+	fn index(&self, room_id: &RoomId) -> Result<()> { self.db.index(room_id, &self.tokens(room_id)?) }
+
+	/// Words of the canonical alias, name and topic of the room
+	fn tokens(&self, room_id: &RoomId) -> Result<BTreeSet<String>> {
+		let state_accessor = &services().rooms.state_accessor;
+		let alias = state_accessor
+			.get_canonical_alias(room_id)?
+			.map(|alias| alias.to_string());
+		let name = state_accessor.get_name(room_id)?;
+		let topic = state_accessor
+			.get_room_topic_summary(room_id)
+			.unwrap_or(None);
+
+		Ok([alias, name, topic]
+			.iter()
+			.flatten()
+			.flat_map(|text| tokenize(text).collect::<Vec<_>>())
+			.collect())
+	}
+}
+
+/// Whether every word starts one of the tokens, as the index is searched
+fn matches_words(words: &BTreeSet<String>, tokens: &BTreeSet<String>) -> bool {
+	words
+		.iter()
+		.all(|word| tokens.iter().any(|token| token.starts_with(word.as_str())))
+}
+
+#[cfg(test)]
+mod tests {
+	use std::collections::BTreeSet;
+
+	use super::matches_words;
+	use crate::rooms::search::tokenize;
+
+	#[test]
+	fn unindexed_rooms_match_like_indexed_ones() {
+		let tokens: BTreeSet<_> = tokenize("Rust Programming #rust:example.org").collect();
+		let words = |term: &str| tokenize(term).collect::<BTreeSet<_>>();
+
+		assert!(matches_words(&words("rust"), &tokens));
+		assert!(matches_words(&words("PROG rus"), &tokens));
+		assert!(matches_words(&words("example"), &tokens));
+		assert!(!matches_words(&words("rust python"), &tokens));
+		assert!(!matches_words(&words("gramming"), &tokens));
+	}
 }
@@ -1,9 +1,12 @@
 mod parse_incoming_pdu;
+mod resync_state;
 mod signing_keys;
+pub mod stats;
 
 use std::{
 	cmp,
 	collections::{hash_map, BTreeMap, HashMap, HashSet},
+	iter,
 	pin::Pin,
 	sync::Arc,
 	time::{Duration, Instant},
@@ -11,8 +14,9 @@ use std::{
 
 use conduit::{debug_error, debug_info, Error, Result, Server};
 use database::Database;
-use futures_util::Future;
+use futures_util::{stream, Future, StreamExt};
 pub use parse_incoming_pdu::parse_incoming_pdu;
+pub use resync_state::StateResync;
 use ruma::{
 	api::{
 		client::error::ErrorKind,
@@ -27,15 +31,32 @@ use ruma::{
 	int,
 	serde::Base64,
 	state_res::{self, RoomVersion, StateMap},
-	uint, CanonicalJsonValue, EventId, MilliSecondsSinceUnixEpoch, OwnedUserId, RoomId, RoomVersionId, ServerName,
+	uint, CanonicalJsonValue, EventId, MilliSecondsSinceUnixEpoch, OwnedEventId, OwnedUserId, RoomId, RoomVersionId,
+	ServerName,
 };
 use tokio::sync::RwLock;
 use tracing::{debug, error, info, trace, warn};
 
 use super::state_compressor::CompressedStateEvent;
-use crate::{pdu, services, PduEvent};
+use crate::{pdu, server_is_ours, services, PduEvent};
 
-pub struct Service;
+/// Number of missing events fetched over federation at the same time
+const FETCH_CONCURRENCY: usize = 8;
+
+/// Number of other servers in the room asked for an event the origin fails to
+/// return
+const FETCH_FALLBACK_SERVERS: usize = 3;
+
+pub struct Service {
+	/// PDUs received per origin, for the admin `federation incoming-stats`
+	pub stats: stats::IncomingStats,
+
+	/// Rounds of fetching missing prev events
+	pub prev_events: stats::PrevEventStats,
+
+	/// Invalid EDUs dropped from incoming transactions
+	pub edus: stats::EduStats,
+}
 
 // We use some AsyncRecursiveType hacks here so we can call async funtion
 // recursively.
@@ -46,7 +67,13 @@ type AsyncRecursiveCanonicalJsonResult<'a> =
 	AsyncRecursiveType<'a, Result<(Arc<PduEvent>, BTreeMap<String, CanonicalJsonValue>)>>;
 
 impl Service {
-	pub fn build(_server: &Arc<Server>, _db: &Arc<Database>) -> Result<Self> { Ok(Self {}) }
+	pub fn build(_server: &Arc<Server>, _db: &Arc<Database>) -> Result<Self> {
+		Ok(Self {
+			stats: stats::IncomingStats::default(),
+			prev_events: stats::PrevEventStats::default(),
+			edus: stats::EduStats::default(),
+		})
+	}
 
 	/// When receiving an event one needs to:
 	/// 0. Check the server is in the room
@@ -81,6 +108,8 @@ impl Service {
 		value: BTreeMap<String, CanonicalJsonValue>, is_timeline_event: bool,
 		pub_key_map: &'a RwLock<BTreeMap<String, BTreeMap<String, Base64>>>,
 	) -> Result<Option<Vec<u8>>> {
+		self.stats.record_pdu(origin);
+
 		// 1. Skip the PDU if we already have it as a timeline event
 		if let Some(pdu_id) = services().rooms.timeline.get_pdu_id(event_id)? {
 			return Ok(Some(pdu_id));
@@ -1031,130 +1060,34 @@ impl Service {
 	///
 	/// a. Look in the main timeline (pduid_pdu tree)
 	/// b. Look at outlier pdu tree
-	/// c. Ask origin server over federation
-	/// d. TODO: Ask other servers over federation?
+	/// c. Ask origin server over federation, `FETCH_CONCURRENCY` events at once
+	/// d. Ask other servers in the room if the origin fails
 	pub fn fetch_and_handle_outliers<'a>(
 		&'a self, origin: &'a ServerName, events: &'a [Arc<EventId>], create_event: &'a PduEvent, room_id: &'a RoomId,
 		room_version_id: &'a RoomVersionId, pub_key_map: &'a RwLock<BTreeMap<String, BTreeMap<String, Base64>>>,
 	) -> AsyncRecursiveCanonicalJsonVec<'a> {
 		Box::pin(async move {
-			let back_off = |id| async {
-				match services()
-					.globals
-					.bad_event_ratelimiter
-					.write()
-					.await
-					.entry(id)
-				{
-					hash_map::Entry::Vacant(e) => {
-						e.insert((Instant::now(), 1));
-					},
-					hash_map::Entry::Occupied(mut e) => *e.get_mut() = (Instant::now(), e.get().1.saturating_add(1)),
-				}
-			};
-
-			let mut events_with_auth_events = Vec::with_capacity(events.len());
-			for id in events {
-				// a. Look in the main timeline (pduid_pdu tree)
-				// b. Look at outlier pdu tree
-				// (get_pdu_json checks both)
-				if let Ok(Some(local_pdu)) = services().rooms.timeline.get_pdu(id) {
-					trace!("Found {} in db", id);
-					events_with_auth_events.push((id, Some(local_pdu), vec![]));
-					continue;
-				}
-
-				// c. Ask origin server over federation
-				// We also handle its auth chain here so we don't get a stack overflow in
-				// handle_outlier_pdu.
-				let mut todo_auth_events = vec![Arc::clone(id)];
-				let mut events_in_reverse_order = Vec::with_capacity(todo_auth_events.len());
-				let mut events_all = HashSet::with_capacity(todo_auth_events.len());
-				let mut i = 0;
-				while let Some(next_id) = todo_auth_events.pop() {
-					if let Some((time, tries)) = services()
-						.globals
-						.bad_event_ratelimiter
-						.read()
-						.await
-						.get(&*next_id)
-					{
-						// Exponential backoff
-						const MAX_DURATION: Duration = Duration::from_secs(60 * 60 * 24);
-						let min_elapsed_duration =
-							cmp::min(MAX_DURATION, Duration::from_secs(5 * 60) * (*tries) * (*tries));
-
-						if time.elapsed() < min_elapsed_duration {
-							info!("Backing off from {}", next_id);
-							continue;
-						}
-					}
-
-					if events_all.contains(&next_id) {
-						continue;
-					}
-
-					i += 1;
-					if i % 100 == 0 {
-						tokio::task::yield_now().await;
+			// a. Look in the main timeline (pduid_pdu tree)
+			// b. Look at outlier pdu tree
+			// (get_pdu_json checks both)
+			// c. Ask origin server over federation, for several events at once
+			let events_with_auth_events: Vec<_> = stream::iter(events)
+				.map(|id| async move {
+					if let Ok(Some(local_pdu)) = services().rooms.timeline.get_pdu(id) {
+						trace!("Found {} in db", id);
+						return (id, Some(local_pdu), vec![]);
 					}
 
-					if let Ok(Some(_)) = services().rooms.timeline.get_pdu(&next_id) {
-						trace!("Found {} in db", next_id);
-						continue;
-					}
-
-					debug!("Fetching {} over federation.", next_id);
-					match services()
-						.sending
-						.send_federation_request(
-							origin,
-							get_event::v1::Request {
-								event_id: (*next_id).to_owned(),
-							},
-						)
-						.await
-					{
-						Ok(res) => {
-							debug!("Got {} over federation", next_id);
-							let Ok((calculated_event_id, value)) =
-								pdu::gen_event_id_canonical_json(&res.pdu, room_version_id)
-							else {
-								back_off((*next_id).to_owned()).await;
-								continue;
-							};
-
-							if calculated_event_id != *next_id {
-								warn!(
-									"Server didn't return event id we requested: requested: {}, we got {}. Event: {:?}",
-									next_id, calculated_event_id, &res.pdu
-								);
-							}
-
-							if let Some(auth_events) = value.get("auth_events").and_then(|c| c.as_array()) {
-								for auth_event in auth_events {
-									if let Ok(auth_event) = serde_json::from_value(auth_event.clone().into()) {
-										let a: Arc<EventId> = auth_event;
-										todo_auth_events.push(a);
-									} else {
-										warn!("Auth event id is not valid");
-									}
-								}
-							} else {
-								warn!("Auth event list invalid");
-							}
-
-							events_in_reverse_order.push((next_id.clone(), value));
-							events_all.insert(next_id);
-						},
-						Err(e) => {
-							debug_error!("Failed to fetch event {next_id}: {e}");
-							back_off((*next_id).to_owned()).await;
-						},
-					}
-				}
-				events_with_auth_events.push((id, None, events_in_reverse_order));
-			}
+					(
+						id,
+						None,
+						self.fetch_with_auth_chain(origin, id, room_id, room_version_id)
+							.await,
+					)
+				})
+				.buffered(FETCH_CONCURRENCY)
+				.collect()
+				.await;
 
 			// We go through all the signatures we see on the PDUs and their unresolved
 			// dependencies and fetch the corresponding signing keys
@@ -1170,6 +1103,8 @@ impl Service {
 				warn!("Could not fetch all signatures for PDUs from {}: {:?}", origin, e);
 			});
 
+			// Auth chains fetched concurrently overlap, each event is handled once
+			let mut handled = HashSet::new();
 			let mut pdus = Vec::with_capacity(events_with_auth_events.len());
 			for (id, local_pdu, events_in_reverse_order) in events_with_auth_events {
 				// a. Look in the main timeline (pduid_pdu tree)
@@ -1180,6 +1115,10 @@ impl Service {
 					pdus.push((local_pdu, None));
 				}
 				for (next_id, value) in events_in_reverse_order.iter().rev() {
+					if next_id != id && !handled.insert(next_id.clone()) {
+						continue;
+					}
+
 					if let Some((time, tries)) = services()
 						.globals
 						.bad_event_ratelimiter
@@ -1218,6 +1157,128 @@ impl Service {
 		})
 	}
 
+	/// Fetches an event and the auth events it depends on which we don't have
+	/// yet, in reverse order. We handle its auth chain here so we don't get a
+	/// stack overflow in handle_outlier_pdu.
+	async fn fetch_with_auth_chain(
+		&self, origin: &ServerName, id: &Arc<EventId>, room_id: &RoomId, room_version_id: &RoomVersionId,
+	) -> Vec<(Arc<EventId>, BTreeMap<String, CanonicalJsonValue>)> {
+		let mut todo_auth_events = vec![Arc::clone(id)];
+		let mut events_in_reverse_order = Vec::with_capacity(todo_auth_events.len());
+		let mut events_all = HashSet::with_capacity(todo_auth_events.len());
+		let mut i: u64 = 0;
+		while let Some(next_id) = todo_auth_events.pop() {
+			if let Some((time, tries)) = services()
+				.globals
+				.bad_event_ratelimiter
+				.read()
+				.await
+				.get(&*next_id)
+			{
+				// Exponential backoff
+				const MAX_DURATION: Duration = Duration::from_secs(60 * 60 * 24);
+				let min_elapsed_duration = cmp::min(MAX_DURATION, Duration::from_secs(5 * 60) * (*tries) * (*tries));
+
+				if time.elapsed() < min_elapsed_duration {
+					info!("Backing off from {}", next_id);
+					continue;
+				}
+			}
+
+			if events_all.contains(&next_id) {
+				continue;
+			}
+
+			i = i.saturating_add(1);
+			if i % 100 == 0 {
+				tokio::task::yield_now().await;
+			}
+
+			if let Ok(Some(_)) = services().rooms.timeline.get_pdu(&next_id) {
+				trace!("Found {} in db", next_id);
+				continue;
+			}
+
+			match self.fetch_event(origin, room_id, &next_id).await {
+				Ok(res) => {
+					debug!("Got {} over federation", next_id);
+					let Ok((calculated_event_id, value)) = pdu::gen_event_id_canonical_json(&res.pdu, room_version_id)
+					else {
+						back_off((*next_id).to_owned()).await;
+						continue;
+					};
+
+					if calculated_event_id != *next_id {
+						warn!(
+							"Server didn't return event id we requested: requested: {}, we got {}. Event: {:?}",
+							next_id, calculated_event_id, &res.pdu
+						);
+					}
+
+					if let Some(auth_events) = value.get("auth_events").and_then(|c| c.as_array()) {
+						for auth_event in auth_events {
+							if let Ok(auth_event) = serde_json::from_value(auth_event.clone().into()) {
+								let a: Arc<EventId> = auth_event;
+								todo_auth_events.push(a);
+							} else {
+								warn!("Auth event id is not valid");
+							}
+						}
+					} else {
+						warn!("Auth event list invalid");
+					}
+
+					events_in_reverse_order.push((next_id.clone(), value));
+					events_all.insert(next_id);
+				},
+				Err(e) => {
+					debug_error!("Failed to fetch event {next_id}: {e}");
+					back_off((*next_id).to_owned()).await;
+				},
+			}
+		}
+
+		events_in_reverse_order
+	}
+
+	/// Asks the origin for an event, then up to `FETCH_FALLBACK_SERVERS` other
+	/// servers in the room if the origin fails to return it
+	async fn fetch_event(
+		&self, origin: &ServerName, room_id: &RoomId, event_id: &EventId,
+	) -> Result<get_event::v1::Response> {
+		let fallback = services()
+			.rooms
+			.state_cache
+			.room_servers(room_id)
+			.filter_map(Result::ok)
+			.filter(|server| server != origin && !server_is_ours(server))
+			.take(FETCH_FALLBACK_SERVERS)
+			.collect::<Vec<_>>();
+
+		let mut last_error = None;
+		for server in iter::once(origin.to_owned()).chain(fallback) {
+			debug!("Fetching {event_id} over federation from {server}.");
+			match services()
+				.sending
+				.send_federation_request(
+					&server,
+					get_event::v1::Request {
+						event_id: event_id.to_owned(),
+					},
+				)
+				.await
+			{
+				Ok(res) => return Ok(res),
+				Err(e) => {
+					debug_error!("Failed to fetch event {event_id} from {server}: {e}");
+					last_error = Some(e);
+				},
+			}
+		}
+
+		Err(last_error.expect("the origin was asked"))
+	}
+
 	#[allow(clippy::type_complexity)]
 	#[tracing::instrument(skip_all)]
 	async fn fetch_prev(
@@ -1237,32 +1298,49 @@ impl Service {
 			.first_pdu_in_room(room_id)?
 			.ok_or_else(|| Error::bad_database("Failed to find first pdu in db."))?;
 
-		let mut amount = 0;
+		let mut amount: u16 = 0;
+		let mut waves: u64 = 0;
 
-		while let Some(prev_event_id) = todo_outlier_stack.pop() {
-			if let Some((pdu, json_opt)) = self
-				.fetch_and_handle_outliers(
-					origin,
-					&[prev_event_id.clone()],
-					create_event,
-					room_id,
-					room_version_id,
-					pub_key_map,
-				)
+		// Events found missing in one wave are fetched together in the next, so
+		// the sequential round-trips are bounded by the depth of the missing part
+		// of the DAG rather than its size
+		loop {
+			let limit = services()
+				.globals
+				.max_fetch_prev_events()
+				.saturating_sub(amount);
+			let (wave, over_limit) = take_wave(&mut todo_outlier_stack, &graph, limit.into());
+			if !over_limit.is_empty() {
+				debug!(
+					"Max prev event limit reached! Limit: {}",
+					services().globals.max_fetch_prev_events()
+				);
+			}
+
+			for prev_event_id in over_limit {
+				graph.insert(prev_event_id, HashSet::new());
+			}
+
+			if wave.is_empty() {
+				break;
+			}
+
+			waves = waves.saturating_add(1);
+			let mut fetched: HashMap<_, _> = self
+				.fetch_and_handle_outliers(origin, &wave, create_event, room_id, room_version_id, pub_key_map)
 				.await
-				.pop()
-			{
-				Self::check_room_id(room_id, &pdu)?;
+				.into_iter()
+				.map(|(pdu, json)| (pdu.event_id.clone(), (pdu, json)))
+				.collect();
 
-				if amount > services().globals.max_fetch_prev_events() {
-					// Max limit reached
-					debug!(
-						"Max prev event limit reached! Limit: {}",
-						services().globals.max_fetch_prev_events()
-					);
+			for prev_event_id in wave {
+				let Some((pdu, json_opt)) = fetched.remove(&prev_event_id) else {
+					// Fetch and handle failed
 					graph.insert(prev_event_id.clone(), HashSet::new());
 					continue;
-				}
+				};
+
+				Self::check_room_id(room_id, &pdu)?;
 
 				if let Some(json) = json_opt.or_else(|| {
 					services()
@@ -1291,12 +1369,12 @@ impl Service {
 					// Get json failed, so this was not fetched over federation
 					graph.insert(prev_event_id.clone(), HashSet::new());
 				}
-			} else {
-				// Fetch and handle failed
-				graph.insert(prev_event_id.clone(), HashSet::new());
 			}
 		}
 
+		self.prev_events
+			.record(waves, eventid_info.len().try_into().unwrap_or(u64::MAX));
+
 		let sorted = state_res::lexicographical_topological_sort(&graph, |event_id| {
 			// This return value is the key used for sorting events,
 			// events are then sorted by power level, time,
@@ -1382,3 +1460,100 @@ impl Service {
 		RoomVersion::new(room_version_id).expect("room version is supported")
 	}
 }
+
+/// Distinct events of `todo` which are not in `graph` yet, to be fetched
+/// together: at most `limit` of them, followed by the ones over the limit
+fn take_wave<T>(
+	todo: &mut Vec<Arc<EventId>>, graph: &HashMap<Arc<EventId>, T>, limit: usize,
+) -> (Vec<Arc<EventId>>, Vec<Arc<EventId>>) {
+	let mut seen = HashSet::with_capacity(todo.len());
+	let mut wave: Vec<_> = todo
+		.drain(..)
+		.filter(|id| !graph.contains_key(id) && seen.insert(id.clone()))
+		.collect();
+
+	let over_limit = wave.split_off(limit.min(wave.len()));
+	(wave, over_limit)
+}
+
+async fn back_off(id: OwnedEventId) {
+	match services()
+		.globals
+		.bad_event_ratelimiter
+		.write()
+		.await
+		.entry(id)
+	{
+		hash_map::Entry::Vacant(e) => {
+			e.insert((Instant::now(), 1));
+		},
+		hash_map::Entry::Occupied(mut e) => *e.get_mut() = (Instant::now(), e.get().1.saturating_add(1)),
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::{collections::HashMap, sync::Arc};
+
+	use ruma::EventId;
+
+	use super::take_wave;
+
+	#[test]
+	fn waves_follow_depth_not_size() {
+		// `depth` layers of `width` events, each referencing every event of the
+		// layer below it
+		let (depth, width) = (10, 4);
+		let id = |layer: usize, i: usize| -> Arc<EventId> {
+			Arc::from(&*EventId::parse(format!("${layer}_{i}:example.com")).expect("valid event id"))
+		};
+		let layer_of: HashMap<_, _> = (0..depth)
+			.flat_map(|layer| (0..width).map(move |i| (layer, i)))
+			.map(|(layer, i)| (id(layer, i), layer))
+			.collect();
+
+		let mut graph = HashMap::new();
+		let mut todo: Vec<_> = (0..width).map(|i| id(0, i)).collect();
+		let mut waves = 0;
+		loop {
+			let (wave, over_limit) = take_wave(&mut todo, &graph, usize::MAX);
+			assert!(over_limit.is_empty());
+			if wave.is_empty() {
+				break;
+			}
+
+			waves += 1;
+			assert!(wave.len() <= width, "events are fetched once per wave");
+			for event_id in wave {
+				let layer = layer_of[&event_id];
+				if layer + 1 < depth {
+					todo.extend((0..width).map(|i| id(layer + 1, i)));
+				}
+				graph.insert(event_id, ());
+			}
+		}
+
+		assert_eq!(graph.len(), depth * width);
+		assert_eq!(waves, depth);
+	}
+
+	#[test]
+	fn waves_stop_at_limit() {
+		let ids: Vec<Arc<EventId>> = (0..4)
+			.map(|i| Arc::from(&*EventId::parse(format!("$event_{i}:example.com")).expect("valid event id")))
+			.collect();
+		let mut graph = HashMap::new();
+		graph.insert(ids[0].clone(), ());
+
+		let mut todo = vec![ids[0].clone(), ids[1].clone(), ids[1].clone(), ids[2].clone(), ids[3].clone()];
+		let (wave, over_limit) = take_wave(&mut todo, &graph, 2);
+		assert_eq!(wave, [ids[1].clone(), ids[2].clone()]);
+		assert_eq!(over_limit, [ids[3].clone()]);
+		assert!(todo.is_empty());
+
+		let mut todo = ids.clone();
+		let (wave, over_limit) = take_wave(&mut todo, &graph, 0);
+		assert!(wave.is_empty());
+		assert_eq!(over_limit.len(), 3);
+	}
+}
@@ -0,0 +1,146 @@
+use std::{
+	collections::{BTreeMap, HashSet},
+	sync::Arc,
+};
+
+use conduit::{debug, info, Error, Result};
+use ruma::{events::StateEventType, EventId, RoomId, ServerName};
+use tokio::sync::RwLock;
+
+use crate::{rooms::state_compressor::CompressedStateEvent, services};
+
+/// Outcome of replacing the state of a room with the state of a remote server
+pub struct StateResync {
+	/// Forward extremity the state was fetched at
+	pub event_id: Arc<EventId>,
+
+	pub added: Vec<(StateEventType, String, Arc<EventId>)>,
+	pub removed: Vec<(StateEventType, String, Arc<EventId>)>,
+}
+
+impl super::Service {
+	/// Replaces the current state of the room with the state `origin` has at
+	/// our latest forward extremity. The state events are fetched and checked
+	/// like those of an incoming PDU, but the result is not resolved against
+	/// our current state, so this recovers rooms whose state diverged.
+	///
+	/// Fails while PDUs of the room are being handled.
+	pub async fn resync_state(&self, room_id: &RoomId, origin: &ServerName) -> Result<StateResync> {
+		if !services().rooms.metadata.exists(room_id)? {
+			return Err(Error::Err("Room is unknown to this server.".to_owned()));
+		}
+
+		let federation_lock = services()
+			.globals
+			.roomid_mutex_federation
+			.lock(room_id)
+			.await;
+
+		if services()
+			.globals
+			.roomid_federationhandletime
+			.read()
+			.await
+			.contains_key(room_id)
+		{
+			return Err(Error::Err(
+				"Incoming PDUs of this room are being handled, try again once they are done.".to_owned(),
+			));
+		}
+
+		let create_event = services()
+			.rooms
+			.state_accessor
+			.room_state_get(room_id, &StateEventType::RoomCreate, "")?
+			.ok_or_else(|| Error::bad_database("Failed to find create event in db."))?;
+		let room_version_id = Self::get_room_version_id(&create_event)?;
+
+		let mut extremities = Vec::new();
+		for event_id in services().rooms.state.get_forward_extremities(room_id)? {
+			if let Some(count) = services().rooms.timeline.get_pdu_count(&event_id)? {
+				extremities.push((count, event_id));
+			}
+		}
+		let (_, event_id) = extremities
+			.into_iter()
+			.max_by_key(|(count, _)| *count)
+			.ok_or_else(|| Error::Err("Room has no forward extremities in the timeline.".to_owned()))?;
+		let extremity = services()
+			.rooms
+			.timeline
+			.get_pdu(&event_id)?
+			.ok_or_else(|| Error::bad_database("Forward extremity not found."))?;
+
+		info!("Fetching the state of {room_id} at {event_id} from {origin}");
+		let pub_key_map = RwLock::new(BTreeMap::new());
+		let mut state = self
+			.fetch_state(origin, &create_event, room_id, &room_version_id, &pub_key_map, &event_id)
+			.await?
+			.ok_or_else(|| Error::Err(format!("{origin} returned no state for {event_id}.")))?;
+
+		// /state_ids is the state before the event
+		if let Some(state_key) = &extremity.state_key {
+			let shortstatekey = services()
+				.rooms
+				.short
+				.get_or_create_shortstatekey(&extremity.kind.to_string().into(), state_key)?;
+			state.insert(shortstatekey, Arc::clone(&event_id));
+		}
+
+		let new_room_state = state
+			.iter()
+			.map(|(shortstatekey, id)| {
+				services()
+					.rooms
+					.state_compressor
+					.compress_state_event(*shortstatekey, id)
+			})
+			.collect::<Result<HashSet<_>>>()?;
+
+		let state_lock = services().globals.roomid_mutex_state.lock(room_id).await;
+
+		debug!("Forcing the fetched room state");
+		let (sstatehash, new, removed) = services()
+			.rooms
+			.state_compressor
+			.save_state(room_id, Arc::new(new_room_state))?;
+
+		services()
+			.rooms
+			.state
+			.force_state(room_id, sstatehash, new.clone(), removed.clone(), &state_lock)
+			.await?;
+
+		drop(state_lock);
+		drop(federation_lock);
+
+		Ok(StateResync {
+			event_id,
+			added: describe(&new)?,
+			removed: describe(&removed)?,
+		})
+	}
+}
+
+/// Type, state key and event ID of state events, sorted
+fn describe(events: &HashSet<CompressedStateEvent>) -> Result<Vec<(StateEventType, String, Arc<EventId>)>> {
+	let mut events = events
+		.iter()
+		.map(|compressed| {
+			let (shortstatekey, event_id) = services()
+				.rooms
+				.state_compressor
+				.parse_compressed_state_event(compressed)?;
+			let (kind, state_key) = services()
+				.rooms
+				.short
+				.get_statekey_from_short(shortstatekey)?;
+			Ok((kind, state_key, event_id))
+		})
+		.collect::<Result<Vec<_>>>()?;
+
+	events.sort_by(|(a_kind, a_key, _), (b_kind, b_key, _)| {
+		(a_kind.to_string(), a_key).cmp(&(b_kind.to_string(), b_key))
+	});
+	Ok(events)
+}
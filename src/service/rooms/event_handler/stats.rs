@@ -0,0 +1,168 @@
+use std::{
+	collections::{HashMap, VecDeque},
+	sync::{
+		atomic::{AtomicU64, Ordering},
+		Mutex,
+	},
+	time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use ruma::{OwnedServerName, ServerName};
+
+/// Width of the time buckets PDUs received are counted in
+const BUCKET_WIDTH: Duration = Duration::from_secs(10 * 60);
+
+/// Number of buckets making up a day, after which counts are dropped
+const BUCKETS_PER_DAY: u64 = 24 * 6;
+
+/// Number of buckets making up an hour
+const BUCKETS_PER_HOUR: u64 = 6;
+
+/// Counts of the PDUs received from each origin over the last day. These
+/// are only kept in memory; the counts are approximate to `BUCKET_WIDTH`.
+#[derive(Default)]
+pub struct IncomingStats {
+	origins: Mutex<HashMap<OwnedServerName, OriginStats>>,
+}
+
+struct OriginStats {
+	last_seen: SystemTime,
+
+	/// PDUs received per bucket, oldest first
+	buckets: VecDeque<(u64, u64)>,
+}
+
+#[derive(Clone, Debug)]
+pub struct OriginSummary {
+	pub origin: OwnedServerName,
+	pub last_hour: u64,
+	pub last_day: u64,
+	pub last_seen: SystemTime,
+}
+
+/// Missing prev events fetched since startup. Each wave fetches the events
+/// found missing in the previous one concurrently, so waves are the
+/// sequential federation round-trips.
+#[derive(Default)]
+pub struct PrevEventStats {
+	fetches: AtomicU64,
+	waves: AtomicU64,
+	events: AtomicU64,
+	max_waves: AtomicU64,
+}
+
+/// EDUs of incoming transactions dropped since startup because they could not
+/// be parsed or were not valid for their origin
+#[derive(Default)]
+pub struct EduStats {
+	dropped: AtomicU64,
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct PrevEventSummary {
+	/// Incoming PDUs which had prev events to look for
+	pub fetches: u64,
+	pub waves: u64,
+	pub events: u64,
+
+	/// Most waves a single incoming PDU needed
+	pub max_waves: u64,
+}
+
+impl PrevEventStats {
+	pub fn record(&self, waves: u64, events: u64) {
+		self.fetches.fetch_add(1, Ordering::Relaxed);
+		self.waves.fetch_add(waves, Ordering::Relaxed);
+		self.events.fetch_add(events, Ordering::Relaxed);
+		self.max_waves.fetch_max(waves, Ordering::Relaxed);
+	}
+
+	#[must_use]
+	pub fn summary(&self) -> PrevEventSummary {
+		PrevEventSummary {
+			fetches: self.fetches.load(Ordering::Relaxed),
+			waves: self.waves.load(Ordering::Relaxed),
+			events: self.events.load(Ordering::Relaxed),
+			max_waves: self.max_waves.load(Ordering::Relaxed),
+		}
+	}
+}
+
+impl EduStats {
+	pub fn record_dropped(&self, count: u64) { self.dropped.fetch_add(count, Ordering::Relaxed); }
+
+	#[must_use]
+	pub fn dropped(&self) -> u64 { self.dropped.load(Ordering::Relaxed) }
+}
+
+impl IncomingStats {
+	pub fn record_pdu(&self, origin: &ServerName) {
+		let now = SystemTime::now();
+		let bucket = bucket_of(now);
+
+		let mut origins = self.origins.lock().expect("locked");
+		let stats = origins
+			.entry(origin.to_owned())
+			.or_insert_with(|| OriginStats {
+				last_seen: now,
+				buckets: VecDeque::new(),
+			});
+
+		stats.last_seen = now;
+		match stats.buckets.back_mut() {
+			Some((last, count)) if *last == bucket => *count = count.saturating_add(1),
+			_ => stats.buckets.push_back((bucket, 1)),
+		}
+
+		stats.prune(bucket);
+	}
+
+	/// Origins we received PDUs from during the last day, busiest first
+	#[must_use]
+	pub fn summary(&self) -> Vec<OriginSummary> {
+		let bucket = bucket_of(SystemTime::now());
+
+		let mut origins = self.origins.lock().expect("locked");
+		origins.retain(|_, stats| {
+			stats.prune(bucket);
+			!stats.buckets.is_empty()
+		});
+
+		let mut summary: Vec<_> = origins
+			.iter()
+			.map(|(origin, stats)| OriginSummary {
+				origin: origin.clone(),
+				last_hour: stats.count_since(bucket.saturating_sub(BUCKETS_PER_HOUR)),
+				last_day: stats.count_since(0),
+				last_seen: stats.last_seen,
+			})
+			.collect();
+
+		summary.sort_by(|a, b| b.last_day.cmp(&a.last_day));
+		summary
+	}
+}
+
+impl OriginStats {
+	fn prune(&mut self, bucket: u64) {
+		let oldest = bucket.saturating_sub(BUCKETS_PER_DAY);
+		while self.buckets.front().is_some_and(|(b, _)| *b <= oldest) {
+			self.buckets.pop_front();
+		}
+	}
+
+	fn count_since(&self, oldest: u64) -> u64 {
+		self.buckets
+			.iter()
+			.filter(|(b, _)| *b > oldest)
+			.map(|(_, count)| count)
+			.sum()
+	}
+}
+
+fn bucket_of(time: SystemTime) -> u64 {
+	time.duration_since(UNIX_EPOCH)
+		.unwrap_or_default()
+		.as_secs()
+		/ BUCKET_WIDTH.as_secs()
+}
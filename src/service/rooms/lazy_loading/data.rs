@@ -1,17 +1,21 @@
 use std::sync::Arc;
 
-use conduit::Result;
+use conduit::{Error, Result};
 use database::{Database, Map};
 use ruma::{DeviceId, RoomId, UserId};
 
+use super::LazyLoadConnection;
+
 pub(super) struct Data {
 	lazyloadedids: Arc<Map>,
+	userdeviceid_lazyloadconnection: Arc<Map>,
 }
 
 impl Data {
 	pub(super) fn new(db: &Arc<Database>) -> Self {
 		Self {
 			lazyloadedids: db["lazyloadedids"].clone(),
+			userdeviceid_lazyloadconnection: db["userdeviceid_lazyloadconnection"].clone(),
 		}
 	}
 
@@ -62,4 +66,68 @@ impl Data {
 
 		Ok(())
 	}
+
+	pub(super) fn connection(&self, user_id: &UserId, device_id: &DeviceId) -> Result<Option<LazyLoadConnection>> {
+		self.userdeviceid_lazyloadconnection
+			.get(&userdevice_key(user_id, device_id))?
+			.map(|value| {
+				serde_json::from_slice(&value)
+					.map_err(|_| Error::bad_database("Invalid entry in userdeviceid_lazyloadconnection."))
+			})
+			.transpose()
+	}
+
+	pub(super) fn set_connection(
+		&self, user_id: &UserId, device_id: &DeviceId, connection: &LazyLoadConnection,
+	) -> Result<()> {
+		self.userdeviceid_lazyloadconnection.insert(
+			&userdevice_key(user_id, device_id),
+			&serde_json::to_vec(connection).expect("lazy load connection serializes"),
+		)
+	}
+
+	/// Removes what was sent to the device in any room
+	pub(super) fn lazy_load_reset_device(&self, user_id: &UserId, device_id: &DeviceId) -> Result<()> {
+		let mut prefix = userdevice_key(user_id, device_id);
+		prefix.push(0xFF);
+
+		for (key, _) in self.lazyloadedids.scan_prefix(prefix) {
+			self.lazyloadedids.remove(&key)?;
+		}
+
+		Ok(())
+	}
+
+	/// Removes the records of devices which last synced before `cutoff`, in
+	/// milliseconds since the epoch. Returns the number of devices removed.
+	pub(super) fn expire_connections(&self, cutoff: u64) -> Result<usize> {
+		let expired: Vec<_> = self
+			.userdeviceid_lazyloadconnection
+			.iter()
+			.filter(|(_, value)| {
+				!serde_json::from_slice::<LazyLoadConnection>(value)
+					.is_ok_and(|connection| connection.last_sync >= cutoff)
+			})
+			.map(|(key, _)| key)
+			.collect();
+
+		for key in &expired {
+			let mut prefix = key.clone();
+			prefix.push(0xFF);
+			for (key, _) in self.lazyloadedids.scan_prefix(prefix) {
+				self.lazyloadedids.remove(&key)?;
+			}
+
+			self.userdeviceid_lazyloadconnection.remove(key)?;
+		}
+
+		Ok(expired.len())
+	}
+}
+
+fn userdevice_key(user_id: &UserId, device_id: &DeviceId) -> Vec<u8> {
+	let mut key = user_id.as_bytes().to_vec();
+	key.push(0xFF);
+	key.extend_from_slice(device_id.as_bytes());
+	key
 }
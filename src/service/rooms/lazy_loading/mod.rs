@@ -3,21 +3,57 @@ mod data;
 use std::{
 	collections::{HashMap, HashSet},
 	sync::Arc,
+	time::Duration,
 };
 
-use conduit::Server;
+use conduit::{debug_info, utils, warn, Server};
 use data::Data;
 use database::Database;
 use ruma::{DeviceId, OwnedDeviceId, OwnedRoomId, OwnedUserId, RoomId, UserId};
-use tokio::sync::Mutex;
+use serde::{Deserialize, Serialize};
+use tokio::{sync::Mutex, task::JoinHandle, time::interval};
 
-use crate::{PduCount, Result};
+use crate::{services, PduCount, Result};
+
+/// How often devices are checked for expired lazy-loading records
+const EXPIRE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Sync times are only rewritten once they are this old, so a syncing device
+/// does not cost a database write per sync
+const LAST_SYNC_RESOLUTION: u64 = 3_600_000;
 
 pub struct Service {
 	db: Data,
 
 	#[allow(clippy::type_complexity)]
 	pub lazy_load_waiting: Mutex<HashMap<(OwnedUserId, OwnedDeviceId, OwnedRoomId, PduCount), HashSet<OwnedUserId>>>,
+
+	expiry_handler_join: Mutex<Option<JoinHandle<()>>>,
+}
+
+/// Lazy-loading settings of the last sync of a device. What was sent is only
+/// valid for syncs with the same settings.
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+pub(super) struct LazyLoadConnection {
+	enabled: bool,
+	send_redundant: bool,
+
+	/// Milliseconds since the epoch
+	last_sync: u64,
+}
+
+impl LazyLoadConnection {
+	fn same_settings(&self, other: &Self) -> bool {
+		self.enabled == other.enabled && self.send_redundant == other.send_redundant
+	}
+
+	/// Whether this sync has to be stored over the `previous` one: on new
+	/// settings, or once the stored sync time is `LAST_SYNC_RESOLUTION` old
+	fn replaces(&self, previous: Option<&Self>) -> bool {
+		!previous.is_some_and(|previous| {
+			self.same_settings(previous) && self.last_sync.saturating_sub(previous.last_sync) < LAST_SYNC_RESOLUTION
+		})
+	}
 }
 
 impl Service {
@@ -25,9 +61,86 @@ impl Service {
 		Ok(Self {
 			db: Data::new(db),
 			lazy_load_waiting: Mutex::new(HashMap::new()),
+			expiry_handler_join: Mutex::new(None),
 		})
 	}
 
+	/// Called at the start of each sync of the device. Forgets which members
+	/// were sent to it when the client asks for the full state or syncs with
+	/// different lazy-loading settings than before.
+	#[tracing::instrument(skip(self))]
+	pub async fn lazy_load_sync(
+		&self, user_id: &UserId, device_id: &DeviceId, enabled: bool, send_redundant: bool, full_state: bool,
+	) -> Result<()> {
+		let previous = self.db.connection(user_id, device_id)?;
+		let connection = LazyLoadConnection {
+			enabled,
+			send_redundant,
+			last_sync: utils::millis_since_unix_epoch(),
+		};
+
+		if full_state
+			|| previous
+				.as_ref()
+				.is_some_and(|previous| !previous.same_settings(&connection))
+		{
+			self.db.lazy_load_reset_device(user_id, device_id)?;
+			self.lazy_load_waiting
+				.lock()
+				.await
+				.retain(|(user, device, ..), _| user != user_id || device != device_id);
+		}
+
+		if connection.replaces(previous.as_ref()) {
+			self.db.set_connection(user_id, device_id, &connection)?;
+		}
+
+		Ok(())
+	}
+
+	pub async fn start_expiry_handler(&self) {
+		let handle = services().server.runtime().spawn(async move {
+			let mut i = interval(EXPIRE_INTERVAL);
+			loop {
+				i.tick().await;
+				if let Err(e) = services().rooms.lazy_loading.expire_devices() {
+					warn!("Failed to expire lazy-loading records: {e}");
+				}
+			}
+		});
+
+		_ = self.expiry_handler_join.lock().await.insert(handle);
+	}
+
+	pub async fn close(&self) {
+		if let Some(handler_join) = self.expiry_handler_join.lock().await.take() {
+			handler_join.abort();
+			_ = handler_join.await;
+		}
+	}
+
+	/// Removes the records of devices which did not sync for
+	/// `sync.lazy_load_expiry_days`
+	fn expire_devices(&self) -> Result<()> {
+		let max_age = Duration::from_secs(
+			services()
+				.globals
+				.config
+				.sync
+				.lazy_load_expiry_days
+				.saturating_mul(24 * 60 * 60),
+		);
+		let cutoff =
+			utils::millis_since_unix_epoch().saturating_sub(max_age.as_millis().try_into().unwrap_or(u64::MAX));
+
+		let expired = self.db.expire_connections(cutoff)?;
+		if expired > 0 {
+			debug_info!("Removed lazy-loading records of {expired} devices which stopped syncing");
+		}
+
+		Ok(())
+	}
+
 	#[tracing::instrument(skip(self))]
 	pub fn lazy_load_was_sent_before(
 		&self, user_id: &UserId, device_id: &DeviceId, room_id: &RoomId, ll_user: &UserId,
@@ -71,3 +184,27 @@ impl Service {
 		self.db.lazy_load_reset(user_id, device_id, room_id)
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use super::{LazyLoadConnection, LAST_SYNC_RESOLUTION};
+
+	fn connection(enabled: bool, last_sync: u64) -> LazyLoadConnection {
+		LazyLoadConnection {
+			enabled,
+			send_redundant: false,
+			last_sync,
+		}
+	}
+
+	#[test]
+	fn syncs_write_only_on_changes() {
+		let stored = connection(true, 1_000);
+
+		assert!(connection(true, 1_000).replaces(None));
+		assert!(!connection(true, 2_000).replaces(Some(&stored)));
+		assert!(!connection(true, 500).replaces(Some(&stored)));
+		assert!(connection(false, 2_000).replaces(Some(&stored)));
+		assert!(connection(true, 1_000 + LAST_SYNC_RESOLUTION).replaces(Some(&stored)));
+	}
+}
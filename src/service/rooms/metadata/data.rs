@@ -9,6 +9,7 @@ use crate::services;
 pub(super) struct Data {
 	disabledroomids: Arc<Map>,
 	bannedroomids: Arc<Map>,
+	incompleteroomids: Arc<Map>,
 	roomid_shortroomid: Arc<Map>,
 	pduid_pdu: Arc<Map>,
 }
@@ -18,6 +19,7 @@ impl Data {
 		Self {
 			disabledroomids: db["disabledroomids"].clone(),
 			bannedroomids: db["bannedroomids"].clone(),
+			incompleteroomids: db["incompleteroomids"].clone(),
 			roomid_shortroomid: db["roomid_shortroomid"].clone(),
 			pduid_pdu: db["pduid_pdu"].clone(),
 		}
@@ -94,4 +96,27 @@ impl Data {
 			},
 		))
 	}
+
+	pub(super) fn is_incomplete(&self, room_id: &RoomId) -> Result<bool> {
+		Ok(self.incompleteroomids.get(room_id.as_bytes())?.is_some())
+	}
+
+	pub(super) fn set_incomplete(&self, room_id: &RoomId, incomplete: bool) -> Result<()> {
+		if incomplete {
+			self.incompleteroomids.insert(room_id.as_bytes(), &[])?;
+		} else {
+			self.incompleteroomids.remove(room_id.as_bytes())?;
+		}
+
+		Ok(())
+	}
+
+	pub(super) fn list_incomplete_rooms<'a>(&'a self) -> Box<dyn Iterator<Item = Result<OwnedRoomId>> + 'a> {
+		Box::new(self.incompleteroomids.iter().map(|(room_id_bytes, _)| {
+			utils::string_from_bytes(&room_id_bytes)
+				.map_err(|_| Error::bad_database("Invalid room_id bytes in incompleteroomids."))?
+				.try_into()
+				.map_err(|_| Error::bad_database("Invalid room_id in incompleteroomids."))
+		}))
+	}
 }
@@ -1,20 +1,36 @@
 mod data;
+mod purge;
 
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 
-use conduit::{Result, Server};
+use conduit::{error, Error, Result, Server};
 use data::Data;
 use database::Database;
-use ruma::{OwnedRoomId, RoomId};
+use lru_cache::LruCache;
+use ruma::{
+	events::{room::create::RoomCreateEventContent, StateEventType},
+	room::RoomType,
+	OwnedRoomId, RoomId,
+};
+
+pub use self::purge::PurgeReport;
+use crate::services;
 
 pub struct Service {
 	db: Data,
+
+	/// The `type` of each room's create event, which never changes
+	pub room_type_cache: Mutex<LruCache<OwnedRoomId, Option<RoomType>>>,
 }
 
 impl Service {
-	pub fn build(_server: &Arc<Server>, db: &Arc<Database>) -> Result<Self> {
+	pub fn build(server: &Arc<Server>, db: &Arc<Database>) -> Result<Self> {
+		let config = &server.config;
 		Ok(Self {
 			db: Data::new(db),
+			room_type_cache: Mutex::new(LruCache::new(
+				(f64::from(config.room_type_cache_capacity) * config.conduit_cache_capacity_modifier) as usize,
+			)),
 		})
 	}
 
@@ -39,4 +55,57 @@ impl Service {
 	pub fn list_banned_rooms<'a>(&'a self) -> Box<dyn Iterator<Item = Result<OwnedRoomId>> + 'a> {
 		self.db.list_banned_rooms()
 	}
+
+	/// Whether our view of the room's membership is known to be incomplete,
+	/// e.g. because a join did not finish applying the room state.
+	pub fn is_incomplete(&self, room_id: &RoomId) -> Result<bool> { self.db.is_incomplete(room_id) }
+
+	pub fn set_incomplete(&self, room_id: &RoomId, incomplete: bool) -> Result<()> {
+		self.db.set_incomplete(room_id, incomplete)
+	}
+
+	#[must_use]
+	pub fn list_incomplete_rooms<'a>(&'a self) -> Box<dyn Iterator<Item = Result<OwnedRoomId>> + 'a> {
+		self.db.list_incomplete_rooms()
+	}
+
+	/// The room's type from its create event, `None` for an ordinary room or
+	/// one whose create event we don't have.
+	pub fn room_type(&self, room_id: &RoomId) -> Result<Option<RoomType>> {
+		if let Some(room_type) = self
+			.room_type_cache
+			.lock()
+			.expect("locked")
+			.get_mut(room_id)
+		{
+			return Ok(room_type.clone());
+		}
+
+		let Some(create) = services()
+			.rooms
+			.state_accessor
+			.room_state_get(room_id, &StateEventType::RoomCreate, "")?
+		else {
+			return Ok(None);
+		};
+
+		let room_type = serde_json::from_str::<RoomCreateEventContent>(create.content.get())
+			.map_err(|e| {
+				error!("Invalid room create event in database: {e}");
+				Error::BadDatabase("Invalid room create event in database.")
+			})?
+			.room_type;
+
+		self.room_type_cache
+			.lock()
+			.expect("locked")
+			.insert(room_id.to_owned(), room_type.clone());
+
+		Ok(room_type)
+	}
+
+	/// Whether the room is a space rather than a room for messages
+	pub fn is_space(&self, room_id: &RoomId) -> Result<bool> {
+		Ok(matches!(self.room_type(room_id)?, Some(RoomType::Space)))
+	}
 }
@@ -0,0 +1,313 @@
+use std::{
+	collections::{BTreeMap, HashSet},
+	mem::size_of,
+};
+
+use conduit::{utils, Error, Result};
+use ruma::RoomId;
+
+use super::Service;
+use crate::{admin::jobs::Job, services};
+
+/// Number of removed keys after which a purge yields and reports progress
+const PURGE_YIELD_INTERVAL: usize = 1000;
+
+/// Keys removed per table by a room purge
+pub type PurgeReport = BTreeMap<&'static str, usize>;
+
+struct Purge<'a> {
+	job: &'a Job,
+	removed: PurgeReport,
+	since_yield: usize,
+}
+
+impl Service {
+	/// Deletes a banned room from the database: its timeline including
+	/// backfilled events, state snapshots, aliases, search index, threads,
+	/// receipts and lazy-loading records. The room stays banned, and its short
+	/// ID is kept so it is not reused.
+	///
+	/// Refused unless the room is banned and none of our users are joined.
+	/// Stops early when `job` is cancelled; the purge can be run again.
+	pub async fn purge_room(&self, room_id: &RoomId, job: &Job) -> Result<PurgeReport> {
+		if !self.is_banned(room_id)? {
+			return Err(Error::Err("Only banned rooms can be purged.".to_owned()));
+		}
+
+		if services()
+			.rooms
+			.state_cache
+			.local_users_in_room(room_id)
+			.next()
+			.is_some()
+		{
+			return Err(Error::Err("Local users are still joined to the room.".to_owned()));
+		}
+
+		let mut purge = Purge {
+			job,
+			removed: PurgeReport::new(),
+			since_yield: 0,
+		};
+
+		let mut shortstatehashes = HashSet::new();
+		if let Some(shortroomid) = services().rooms.short.get_shortroomid(room_id)? {
+			let prefix = shortroomid.to_be_bytes().to_vec();
+			purge.timeline(&prefix, &mut shortstatehashes).await?;
+			if job.is_cancelled() {
+				return Ok(purge.removed);
+			}
+
+			for (_, shortstatehash) in services().db["roomsynctoken_shortstatehash"].scan_prefix(prefix.clone()) {
+				shortstatehashes.insert(shortstatehash);
+			}
+
+			for table in ["roomsynctoken_shortstatehash", "threadid_userids", "tokenids"] {
+				purge.remove_prefix(table, &prefix).await?;
+			}
+		}
+
+		if let Some(shortstatehash) = services().db["roomid_shortstatehash"].get(room_id.as_bytes())? {
+			shortstatehashes.insert(shortstatehash);
+			purge
+				.remove("roomid_shortstatehash", room_id.as_bytes())
+				.await?;
+		}
+
+		purge.state(shortstatehashes).await?;
+		purge.aliases(room_id).await?;
+
+		let mut prefix = room_id.as_bytes().to_vec();
+		prefix.push(0xFF);
+		for table in [
+			"readreceiptid_readreceipt",
+			"roomid_pduleaves",
+			"roomuserid_lastprivatereadupdate",
+			"roomuserid_privateread",
+		] {
+			purge.remove_prefix(table, &prefix).await?;
+		}
+
+		// keyed by the room ID directly followed by the event ID
+		let mut prefix = room_id.as_bytes().to_vec();
+		prefix.push(b'$');
+		purge.remove_prefix("referencedevents", &prefix).await?;
+
+		if services().db["publicroomids"]
+			.get(room_id.as_bytes())?
+			.is_some()
+		{
+			purge.remove("publicroomids", room_id.as_bytes()).await?;
+		}
+
+		if services().db["roomid_directoryroomtype"]
+			.get(room_id.as_bytes())?
+			.is_some()
+		{
+			purge
+				.remove("roomid_directoryroomtype", room_id.as_bytes())
+				.await?;
+		}
+
+		purge.lazy_loading(room_id).await?;
+		self.forget_cached(room_id).await;
+
+		Ok(purge.removed)
+	}
+
+	async fn forget_cached(&self, room_id: &RoomId) {
+		self.room_type_cache.lock().expect("locked").remove(room_id);
+
+		services()
+			.rooms
+			.timeline
+			.lasttimelinecount_cache
+			.lock()
+			.await
+			.remove(room_id);
+
+		services()
+			.rooms
+			.lazy_loading
+			.lazy_load_waiting
+			.lock()
+			.await
+			.retain(|(_, _, room, _), _| room != room_id);
+
+		services()
+			.rooms
+			.spaces
+			.roomid_spacehierarchy_cache
+			.lock()
+			.await
+			.remove(room_id);
+
+		// keyed by state hashes which are gone now
+		services()
+			.rooms
+			.state_compressor
+			.stateinfo_cache
+			.lock()
+			.expect("locked")
+			.clear();
+	}
+}
+
+impl Purge<'_> {
+	/// Removes the PDUs of the room with what is indexed by their event ID,
+	/// collecting the state hashes they refer to.
+	async fn timeline(&mut self, prefix: &[u8], shortstatehashes: &mut HashSet<Vec<u8>>) -> Result<()> {
+		let pdus: Vec<_> = services().db["pduid_pdu"]
+			.scan_prefix(prefix.to_vec())
+			.map(|(pduid, pdu)| {
+				let event_id = serde_json::from_slice::<serde_json::Value>(&pdu)
+					.ok()
+					.and_then(|pdu| pdu.get("event_id")?.as_str().map(ToOwned::to_owned));
+				(pduid, event_id)
+			})
+			.collect();
+
+		for (pduid, event_id) in pdus {
+			if let Some(event_id) = event_id {
+				let event_id = event_id.as_bytes();
+				self.remove("eventid_pduid", event_id).await?;
+
+				if services().db["softfailedeventids"].get(event_id)?.is_some() {
+					self.remove("softfailedeventids", event_id).await?;
+				}
+
+				if let Some(shorteventid) = services().db["eventid_shorteventid"].get(event_id)? {
+					if let Some(shortstatehash) = services().db["shorteventid_shortstatehash"].get(&shorteventid)? {
+						shortstatehashes.insert(shortstatehash);
+						self.remove("shorteventid_shortstatehash", &shorteventid)
+							.await?;
+					}
+
+					if services().db["shorteventid_authchain"]
+						.get(&shorteventid)?
+						.is_some()
+					{
+						self.remove("shorteventid_authchain", &shorteventid).await?;
+					}
+
+					self.remove_prefix("tofrom_relation", &shorteventid).await?;
+				}
+			}
+
+			self.remove("pduid_pdu", &pduid).await?;
+			if self.job.is_cancelled() {
+				return Ok(());
+			}
+		}
+
+		Ok(())
+	}
+
+	/// Removes the compressed state of the given state hashes and of every
+	/// state they were built upon.
+	async fn state(&mut self, mut pending: HashSet<Vec<u8>>) -> Result<()> {
+		let mut shortstatehashes = HashSet::new();
+		while let Some(shortstatehash) = pending.iter().next().cloned() {
+			pending.remove(&shortstatehash);
+			if !shortstatehashes.insert(shortstatehash.clone()) {
+				continue;
+			}
+
+			let Some(diff) = services().db["shortstatehash_statediff"].get(&shortstatehash)? else {
+				continue;
+			};
+
+			let parent = diff
+				.get(..size_of::<u64>())
+				.map(utils::u64_from_bytes)
+				.transpose()
+				.map_err(|_| Error::bad_database("Invalid parent in shortstatehash_statediff."))?;
+			if let Some(parent) = parent.filter(|&parent| parent != 0) {
+				pending.insert(parent.to_be_bytes().to_vec());
+			}
+
+			self.remove("shortstatehash_statediff", &shortstatehash)
+				.await?;
+		}
+
+		let hashes: Vec<_> = services().db["statehash_shortstatehash"]
+			.iter()
+			.filter(|(_, shortstatehash)| shortstatehashes.contains(shortstatehash))
+			.map(|(statehash, _)| statehash)
+			.collect();
+
+		for statehash in hashes {
+			self.remove("statehash_shortstatehash", &statehash).await?;
+		}
+
+		Ok(())
+	}
+
+	async fn aliases(&mut self, room_id: &RoomId) -> Result<()> {
+		let aliases: Vec<_> = services()
+			.rooms
+			.alias
+			.local_aliases_for_room(room_id)
+			.filter_map(Result::ok)
+			.collect();
+
+		for alias in aliases {
+			let alias = alias.alias().as_bytes();
+			self.remove("alias_roomid", alias).await?;
+			if services().db["alias_userid"].get(alias)?.is_some() {
+				self.remove("alias_userid", alias).await?;
+			}
+		}
+
+		let mut prefix = room_id.as_bytes().to_vec();
+		prefix.push(0xFF);
+		self.remove_prefix("aliasid_alias", &prefix).await
+	}
+
+	/// Removes the lazy-loading records of the room, which are keyed by user
+	/// and device first.
+	async fn lazy_loading(&mut self, room_id: &RoomId) -> Result<()> {
+		let keys: Vec<_> = services().db["lazyloadedids"]
+			.iter()
+			.map(|(key, _)| key)
+			.filter(|key| key.split(|&b| b == 0xFF).nth(2) == Some(room_id.as_bytes()))
+			.collect();
+
+		for key in keys {
+			self.remove("lazyloadedids", &key).await?;
+		}
+
+		Ok(())
+	}
+
+	async fn remove_prefix(&mut self, table: &'static str, prefix: &[u8]) -> Result<()> {
+		let keys: Vec<_> = services().db[table]
+			.scan_prefix(prefix.to_vec())
+			.map(|(key, _)| key)
+			.collect();
+
+		for key in keys {
+			self.remove(table, &key).await?;
+		}
+
+		Ok(())
+	}
+
+	async fn remove(&mut self, table: &'static str, key: &[u8]) -> Result<()> {
+		services().db[table].remove(key)?;
+
+		let removed = self.removed.entry(table).or_default();
+		*removed = removed.saturating_add(1);
+
+		self.since_yield = self.since_yield.saturating_add(1);
+		if self.since_yield >= PURGE_YIELD_INTERVAL {
+			self.since_yield = 0;
+			let total: usize = self.removed.values().sum();
+			self.job
+				.set_progress(format!("removed {total} keys, now from {table}"));
+			tokio::task::yield_now().await;
+		}
+
+		Ok(())
+	}
+}
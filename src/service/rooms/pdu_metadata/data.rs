@@ -31,25 +31,11 @@ impl Data {
 		Ok(())
 	}
 
-	pub(super) fn relations_until<'a>(
-		&'a self, user_id: &'a UserId, shortroomid: u64, target: u64, until: PduCount,
-	) -> Result<PdusIterator<'a>> {
-		let prefix = target.to_be_bytes().to_vec();
-		let mut current = prefix.clone();
-
-		let count_raw = match until {
-			PduCount::Normal(x) => x.saturating_sub(1),
-			PduCount::Backfilled(x) => {
-				current.extend_from_slice(&0_u64.to_be_bytes());
-				u64::MAX.saturating_sub(x).saturating_sub(1)
-			},
-		};
-		current.extend_from_slice(&count_raw.to_be_bytes());
-
-		Ok(Box::new(
+	/// The events relating to the event with the count `target`, oldest first
+	pub(super) fn relations<'a>(&'a self, user_id: &'a UserId, shortroomid: u64, target: u64) -> PdusIterator<'a> {
+		Box::new(
 			self.tofrom_relation
-				.iter_from(&current, true)
-				.take_while(move |(k, _)| k.starts_with(&prefix))
+				.scan_prefix(target.to_be_bytes().to_vec())
 				.map(move |(tofrom, _data)| {
 					let from = utils::u64_from_bytes(&tofrom[(size_of::<u64>())..])
 						.map_err(|_| Error::bad_database("Invalid count in tofrom_relation."))?;
@@ -67,7 +53,7 @@ impl Data {
 					}
 					Ok((PduCount::Normal(from), pdu))
 				}),
-		))
+		)
 	}
 
 	pub(super) fn mark_as_referenced(&self, room_id: &RoomId, event_ids: &[Arc<EventId>]) -> Result<()> {
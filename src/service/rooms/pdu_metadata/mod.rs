@@ -1,6 +1,6 @@
 mod data;
 
-use std::sync::Arc;
+use std::{collections::HashSet, sync::Arc};
 
 use conduit::{Result, Server};
 use data::Data;
@@ -8,12 +8,16 @@ use database::Database;
 use ruma::{
 	api::{client::relations::get_relating_events, Direction},
 	events::{relation::RelationType, TimelineEventType},
-	uint, EventId, RoomId, UInt, UserId,
+	EventId, RoomId, UserId,
 };
 use serde::Deserialize;
 
 use crate::{services, PduCount, PduEvent};
 
+/// How deep relations of relations are followed when recursing, the spec
+/// recommends at least 3
+const MAX_RECURSION_DEPTH: u8 = 3;
+
 pub struct Service {
 	db: Data,
 }
@@ -47,10 +51,14 @@ impl Service {
 		}
 	}
 
+	/// Relations of `target` for the `/relations` endpoints. With `recurse`,
+	/// relations of relations are included up to `MAX_RECURSION_DEPTH`
+	/// (MSC3981). Filters apply to the returned events only; recursion follows
+	/// every relation. Tokens are the `PduCount` of the last returned event.
 	#[allow(clippy::too_many_arguments)]
 	pub fn paginate_relations_with_filter(
 		&self, sender_user: &UserId, room_id: &RoomId, target: &EventId, filter_event_type: &Option<TimelineEventType>,
-		filter_rel_type: &Option<RelationType>, from: &Option<String>, to: &Option<String>, limit: &Option<UInt>,
+		filter_rel_type: &Option<RelationType>, from: &Option<String>, to: &Option<String>, limit: usize,
 		recurse: bool, dir: Direction,
 	) -> Result<get_relating_events::v1::Response> {
 		let from = match from {
@@ -63,156 +71,79 @@ impl Service {
 
 		let to = to.as_ref().and_then(|t| PduCount::try_from_string(t).ok());
 
-		// Use limit or else 10, with maximum 100
-		let limit = limit
-			.unwrap_or_else(|| uint!(10))
-			.try_into()
-			.unwrap_or(10)
-			.min(100);
-
-		let next_token;
-
-		// Spec (v1.10) recommends depth of at least 3
-		let depth: u8 = if recurse {
-			3
+		let depth = if recurse {
+			MAX_RECURSION_DEPTH
 		} else {
 			1
 		};
 
-		match dir {
-			Direction::Forward => {
-				let relations_until = &self.relations_until(sender_user, room_id, target, from, depth)?;
-				let events_after: Vec<_> = relations_until // TODO: should be relations_after
-                    .iter()
-                    .filter(|(_, pdu)| {
-							filter_event_type.as_ref().map_or(true, |t| &pdu.kind == t)
-								&& if let Ok(content) =
-                                serde_json::from_str::<ExtractRelatesToEventId>(pdu.content.get())
-								{
-									filter_rel_type
-										.as_ref()
-										.map_or(true, |r| &content.relates_to.rel_type == r)
-								} else {
-									false
-								}
-						})
-					.take(limit)
-					.filter(|(_, pdu)| {
-						services()
-							.rooms
-							.state_accessor
-							.user_can_see_event(sender_user, room_id, &pdu.event_id)
-							.unwrap_or(false)
-					})
-                    .take_while(|(k, _)| Some(k) != to.as_ref()) // Stop at `to`
-					.collect();
-
-				next_token = events_after.last().map(|(count, _)| count).copied();
-
-				let events_after: Vec<_> = events_after
-					.into_iter()
-					.rev() // relations are always most recent first
-					.map(|(_, pdu)| pdu.to_message_like_event())
-					.collect();
-
-				Ok(get_relating_events::v1::Response {
-					chunk: events_after,
-					next_batch: next_token.map(|t| t.stringify()),
-					prev_batch: Some(from.stringify()),
-					recursion_depth: if recurse {
-						Some(depth.into())
-					} else {
-						None
-					},
-				})
-			},
-			Direction::Backward => {
-				let relations_until = &self.relations_until(sender_user, room_id, target, from, depth)?;
-				let events_before: Vec<_> = relations_until
-                    .iter()
-                    .filter(|(_, pdu)| {
-							filter_event_type.as_ref().map_or(true, |t| &pdu.kind == t)
-								&& if let Ok(content) =
-                                serde_json::from_str::<ExtractRelatesToEventId>(pdu.content.get())
-								{
-									filter_rel_type
-										.as_ref()
-										.map_or(true, |r| &content.relates_to.rel_type == r)
-								} else {
-									false
-								}
-						})
-					.take(limit)
-					.filter(|(_, pdu)| {
-						services()
-							.rooms
-							.state_accessor
-							.user_can_see_event(sender_user, room_id, &pdu.event_id)
-							.unwrap_or(false)
+		let mut relations = self.relations(sender_user, room_id, target, depth)?;
+		if matches!(dir, Direction::Backward) {
+			relations.reverse();
+		}
+
+		let mut events = relations
+			.into_iter()
+			.filter(|(count, _)| match dir {
+				Direction::Forward => *count > from,
+				Direction::Backward => *count < from,
+			})
+			.take_while(|(count, _)| Some(count) != to.as_ref()) // Stop at `to`
+			.filter(|(_, pdu)| {
+				filter_event_type.as_ref().map_or(true, |t| &pdu.kind == t)
+					&& filter_rel_type.as_ref().map_or(true, |r| {
+						serde_json::from_str::<ExtractRelatesToEventId>(pdu.content.get())
+							.is_ok_and(|content| &content.relates_to.rel_type == r)
 					})
-                    .take_while(|&(k, _)| Some(k) != to.as_ref()) // Stop at `to`
-					.collect();
-
-				next_token = events_before.last().map(|(count, _)| count).copied();
-
-				let events_before: Vec<_> = events_before
-					.into_iter()
-					.map(|(_, pdu)| pdu.to_message_like_event())
-					.collect();
-
-				Ok(get_relating_events::v1::Response {
-					chunk: events_before,
-					next_batch: next_token.map(|t| t.stringify()),
-					prev_batch: Some(from.stringify()),
-					recursion_depth: if recurse {
-						Some(depth.into())
-					} else {
-						None
-					},
-				})
-			},
+			})
+			.filter(|(_, pdu)| {
+				services()
+					.rooms
+					.state_accessor
+					.user_can_see_event(sender_user, room_id, &pdu.event_id)
+					.unwrap_or(false)
+			});
+
+		let mut chunk: Vec<_> = events.by_ref().take(limit).collect();
+		let next_batch = events
+			.next()
+			.and(chunk.last())
+			.map(|(count, _)| count.stringify());
+
+		for (_, pdu) in &mut chunk {
+			services()
+				.rooms
+				.threads
+				.add_bundled_aggregation(sender_user, pdu)?;
 		}
+
+		Ok(get_relating_events::v1::Response {
+			chunk: chunk
+				.into_iter()
+				.map(|(_, pdu)| pdu.to_message_like_event())
+				.collect(),
+			next_batch,
+			prev_batch: Some(from.stringify()),
+			recursion_depth: recurse.then(|| depth.into()),
+		})
 	}
 
-	pub fn relations_until<'a>(
-		&'a self, user_id: &'a UserId, room_id: &'a RoomId, target: &'a EventId, until: PduCount, max_depth: u8,
+	/// Events relating to `target` and, below `max_depth`, to those events,
+	/// oldest first
+	pub fn relations(
+		&self, user_id: &UserId, room_id: &RoomId, target: &EventId, max_depth: u8,
 	) -> Result<Vec<(PduCount, PduEvent)>> {
-		let room_id = services().rooms.short.get_or_create_shortroomid(room_id)?;
-		#[allow(unknown_lints)]
-		#[allow(clippy::manual_unwrap_or_default)]
-		let target = match services().rooms.timeline.get_pdu_count(target)? {
-			Some(PduCount::Normal(c)) => c,
-			// TODO: Support backfilled relations
-			_ => 0, // This will result in an empty iterator
+		// TODO: Support backfilled relations
+		let Some(PduCount::Normal(target)) = services().rooms.timeline.get_pdu_count(target)? else {
+			return Ok(Vec::new());
 		};
 
-		self.db
-			.relations_until(user_id, room_id, target, until)
-			.map(|mut relations| {
-				let mut pdus: Vec<_> = (*relations).into_iter().filter_map(Result::ok).collect();
-				let mut stack: Vec<_> = pdus.clone().iter().map(|pdu| (pdu.to_owned(), 1)).collect();
-
-				while let Some(stack_pdu) = stack.pop() {
-					let target = match stack_pdu.0 .0 {
-						PduCount::Normal(c) => c,
-						// TODO: Support backfilled relations
-						PduCount::Backfilled(_) => 0, // This will result in an empty iterator
-					};
-
-					if let Ok(relations) = self.db.relations_until(user_id, room_id, target, until) {
-						for relation in relations.flatten() {
-							if stack_pdu.1 < max_depth {
-								stack.push((relation.clone(), stack_pdu.1 + 1));
-							}
-
-							pdus.push(relation);
-						}
-					}
-				}
-
-				pdus.sort_by(|a, b| a.0.partial_cmp(&b.0).expect("u64s can always be compared"));
-				pdus
-			})
+		let shortroomid = services().rooms.short.get_or_create_shortroomid(room_id)?;
+		walk_relations(target, max_depth, |target| {
+			self.db
+				.relations(user_id, shortroomid, target)
+				.collect::<Result<Vec<_>>>()
+		})
 	}
 
 	#[tracing::instrument(skip(self, room_id, event_ids))]
@@ -231,3 +162,78 @@ impl Service {
 	#[tracing::instrument(skip(self))]
 	pub fn is_event_soft_failed(&self, event_id: &EventId) -> Result<bool> { self.db.is_event_soft_failed(event_id) }
 }
+
+/// Walks the relation graph below the event with the count `root` breadth
+/// first, `children` giving the events relating to an event. Events are
+/// visited once however many paths lead to them, which also ends cycles.
+fn walk_relations<T, F>(root: u64, max_depth: u8, mut children: F) -> Result<Vec<(PduCount, T)>>
+where
+	F: FnMut(u64) -> Result<Vec<(PduCount, T)>>,
+{
+	let mut visited = HashSet::from([root]);
+	let mut level = vec![root];
+	let mut related = Vec::new();
+	for _ in 0..max_depth {
+		let mut next_level = Vec::new();
+		for target in level {
+			for (count, event) in children(target)? {
+				// TODO: Support backfilled relations
+				let PduCount::Normal(c) = count else {
+					continue;
+				};
+
+				if visited.insert(c) {
+					next_level.push(c);
+					related.push((count, event));
+				}
+			}
+		}
+
+		if next_level.is_empty() {
+			break;
+		}
+		level = next_level;
+	}
+
+	related.sort_by_key(|(count, _)| *count);
+	Ok(related)
+}
+
+#[cfg(test)]
+mod tests {
+	use std::collections::HashMap;
+
+	use super::{walk_relations, PduCount};
+
+	fn walk(graph: &HashMap<u64, Vec<u64>>, root: u64, max_depth: u8) -> Vec<u64> {
+		walk_relations(root, max_depth, |target| {
+			Ok(graph
+				.get(&target)
+				.into_iter()
+				.flatten()
+				.map(|&from| (PduCount::Normal(from), from))
+				.collect())
+		})
+		.expect("walk succeeds")
+		.into_iter()
+		.map(|(_, event)| event)
+		.collect()
+	}
+
+	#[test]
+	fn relations_by_depth() {
+		// 1 <- 2 <- 4 <- 5 <- 6, 1 <- 3
+		let graph = HashMap::from([(1, vec![2, 3]), (2, vec![4]), (4, vec![5]), (5, vec![6])]);
+		assert_eq!(walk(&graph, 1, 1), vec![2, 3]);
+		assert_eq!(walk(&graph, 1, 3), vec![2, 3, 4, 5]);
+		assert!(walk(&graph, 6, 3).is_empty());
+	}
+
+	#[test]
+	fn relation_cycles_end() {
+		// 1 <- 2 <- 3 <- 1
+		let graph = HashMap::from([(1, vec![2]), (2, vec![3]), (3, vec![1])]);
+		assert_eq!(walk(&graph, 1, 3), vec![2, 3]);
+		assert_eq!(walk(&graph, 2, 3), vec![1, 3]);
+	}
+}
@@ -1,4 +1,8 @@
-use std::{mem::size_of, sync::Arc};
+use std::{
+	collections::{HashMap, HashSet},
+	mem::size_of,
+	sync::Arc,
+};
 
 use conduit::{utils, Error, Result};
 use database::{Database, Map};
@@ -40,12 +44,8 @@ impl Data {
 			.readreceiptid_readreceipt
 			.iter_from(&last_possible_key, true)
 			.take_while(|(key, _)| key.starts_with(&prefix))
-			.find(|(key, _)| {
-				key.rsplit(|&b| b == 0xFF)
-					.next()
-					.expect("rsplit always returns an element")
-					== user_id.as_bytes()
-			}) {
+			.find(|(key, _)| receipt_user(key) == user_id.as_bytes())
+		{
 			// This is the old room_latest
 			self.readreceiptid_readreceipt.remove(&old)?;
 		}
@@ -63,6 +63,43 @@ impl Data {
 		Ok(())
 	}
 
+	/// Replaces the previous read receipts of the users with a single scan of
+	/// the room's receipts and one batch of writes. Only the last receipt of a
+	/// user is kept.
+	pub(super) fn readreceipt_update_many(
+		&self, room_id: &RoomId, receipts: &[(OwnedUserId, ReceiptEvent)],
+	) -> Result<()> {
+		let mut prefix = room_id.as_bytes().to_vec();
+		prefix.push(0xFF);
+
+		// Remove old entries
+		let (latest, old) = replaced_receipts(
+			receipts,
+			self.readreceiptid_readreceipt
+				.scan_prefix(prefix.clone())
+				.map(|(key, _)| key),
+		);
+		self.readreceiptid_readreceipt
+			.remove_batch(&mut old.into_iter())?;
+
+		let mut batch = Vec::with_capacity(latest.len());
+		for (user_id, event) in latest {
+			let mut room_latest_id = prefix.clone();
+			room_latest_id.extend_from_slice(&services().globals.next_count()?.to_be_bytes());
+			room_latest_id.push(0xFF);
+			room_latest_id.extend_from_slice(user_id.as_bytes());
+
+			batch.push((
+				room_latest_id,
+				serde_json::to_vec(event).expect("EduEvent::to_string always works"),
+			));
+		}
+		self.readreceiptid_readreceipt
+			.insert_batch(&mut batch.into_iter())?;
+
+		Ok(())
+	}
+
 	pub(super) fn readreceipts_since<'a>(&'a self, room_id: &RoomId, since: u64) -> AnySyncEphemeralRoomEventIter<'a> {
 		let mut prefix = room_id.as_bytes().to_vec();
 		prefix.push(0xFF);
@@ -97,6 +134,28 @@ impl Data {
 		)
 	}
 
+	/// Removes the read receipt and private read marker of the user in the room
+	pub(super) fn remove_user_receipts(&self, user_id: &UserId, room_id: &RoomId) -> Result<()> {
+		let mut prefix = room_id.as_bytes().to_vec();
+		prefix.push(0xFF);
+
+		let receipts: Vec<_> = self
+			.readreceiptid_readreceipt
+			.scan_prefix(prefix.clone())
+			.map(|(key, _)| key)
+			.filter(|key| receipt_user(key) == user_id.as_bytes())
+			.collect();
+
+		for key in receipts {
+			self.readreceiptid_readreceipt.remove(&key)?;
+		}
+
+		let mut key = prefix;
+		key.extend_from_slice(user_id.as_bytes());
+		self.roomuserid_privateread.remove(&key)?;
+		self.roomuserid_lastprivatereadupdate.remove(&key)
+	}
+
 	pub(super) fn private_read_set(&self, room_id: &RoomId, user_id: &UserId, count: u64) -> Result<()> {
 		let mut key = room_id.as_bytes().to_vec();
 		key.push(0xFF);
@@ -139,3 +198,116 @@ impl Data {
 			.unwrap_or(0))
 	}
 }
+
+/// The user a key of `readreceiptid_readreceipt` is for
+fn receipt_user(key: &[u8]) -> &[u8] {
+	key.rsplit(|&b| b == 0xFF)
+		.next()
+		.expect("rsplit always returns an element")
+}
+
+/// The last receipt of each user in `receipts`, and the keys among `keys` of
+/// the receipts they replace
+fn replaced_receipts<'a, K>(
+	receipts: &'a [(OwnedUserId, ReceiptEvent)], keys: K,
+) -> (HashMap<&'a UserId, &'a ReceiptEvent>, Vec<Vec<u8>>)
+where
+	K: Iterator<Item = Vec<u8>>,
+{
+	let mut latest: HashMap<&UserId, &ReceiptEvent> = HashMap::with_capacity(receipts.len());
+	for (user_id, event) in receipts {
+		latest.insert(user_id, event);
+	}
+
+	let user_ids: HashSet<&[u8]> = latest.keys().map(|user_id| user_id.as_bytes()).collect();
+	let old = keys
+		.filter(|key| user_ids.contains(receipt_user(key)))
+		.collect();
+
+	(latest, old)
+}
+
+#[cfg(test)]
+mod tests {
+	use std::{collections::BTreeMap, ptr};
+
+	use ruma::{
+		event_id,
+		events::receipt::{ReceiptEvent, ReceiptEventContent},
+		room_id, user_id, EventId, RoomId, UserId,
+	};
+
+	use super::{receipt_user, replaced_receipts};
+
+	fn receipt_key(room_id: &RoomId, count: u64, user_id: &UserId) -> Vec<u8> {
+		let mut key = room_id.as_bytes().to_vec();
+		key.push(0xFF);
+		key.extend_from_slice(&count.to_be_bytes());
+		key.push(0xFF);
+		key.extend_from_slice(user_id.as_bytes());
+		key
+	}
+
+	#[test]
+	fn forgetting_removes_only_the_users_receipts() {
+		let (alice, bob) = (user_id!("@alice:example.org"), user_id!("@bob:example.org"));
+		let (room, other) = (room_id!("!room:example.org"), room_id!("!room:example.org2"));
+		let keys = [
+			receipt_key(room, 3, alice),
+			receipt_key(room, 4, bob),
+			// a count ending in 0xFF doesn't cut the user short
+			receipt_key(room, 0xFF, bob),
+			receipt_key(other, 5, alice),
+		];
+
+		let mut prefix = room.as_bytes().to_vec();
+		prefix.push(0xFF);
+		let removed: Vec<_> = keys
+			.iter()
+			.filter(|key| key.starts_with(&prefix) && receipt_user(key) == alice.as_bytes())
+			.collect();
+
+		assert_eq!(removed, [&keys[0]]);
+		assert_eq!(receipt_user(&keys[2]), bob.as_bytes());
+	}
+
+	fn receipt(room_id: &RoomId, event_id: &EventId) -> ReceiptEvent {
+		ReceiptEvent {
+			content: ReceiptEventContent(BTreeMap::from([(event_id.to_owned(), BTreeMap::new())])),
+			room_id: room_id.to_owned(),
+		}
+	}
+
+	#[test]
+	fn updating_many_replaces_each_users_receipt() {
+		let (alice, bob, carol) = (
+			user_id!("@alice:example.org"),
+			user_id!("@bob:example.org"),
+			user_id!("@carol:example.org"),
+		);
+		let room = room_id!("!room:example.org");
+		let receipts = [
+			(alice.to_owned(), receipt(room, event_id!("$first"))),
+			(bob.to_owned(), receipt(room, event_id!("$first"))),
+			(alice.to_owned(), receipt(room, event_id!("$second"))),
+		];
+
+		// the room's receipts, as scanned by its prefix
+		let keys = [
+			receipt_key(room, 3, alice),
+			receipt_key(room, 4, bob),
+			receipt_key(room, 5, carol),
+			receipt_key(room, 0xFF, bob),
+		];
+		let (latest, old) = replaced_receipts(&receipts, keys.iter().cloned());
+
+		// one receipt per user, the last one of alice
+		assert_eq!(latest.len(), 2);
+		assert!(ptr::eq(latest[alice], &receipts[2].1));
+		assert!(ptr::eq(latest[bob], &receipts[1].1));
+		assert!(!latest.contains_key(carol));
+
+		// carol's receipt is kept
+		assert_eq!(old, [keys[0].clone(), keys[1].clone(), keys[3].clone()]);
+	}
+}
@@ -28,6 +28,19 @@ impl Service {
 		Ok(())
 	}
 
+	/// Replaces the previous read receipts of many users of a room at once,
+	/// e.g. those of a federation transaction.
+	pub fn readreceipt_update_many(&self, room_id: &RoomId, receipts: &[(OwnedUserId, ReceiptEvent)]) -> Result<()> {
+		if receipts.is_empty() {
+			return Ok(());
+		}
+
+		self.db.readreceipt_update_many(room_id, receipts)?;
+		services().sending.flush_room(room_id)?;
+
+		Ok(())
+	}
+
 	/// Returns an iterator over the most recent read_receipts in a room that
 	/// happened after the event with id `since`.
 	#[tracing::instrument(skip(self))]
@@ -37,6 +50,11 @@ impl Service {
 		self.db.readreceipts_since(room_id, since)
 	}
 
+	/// Removes the read receipt and private read marker of the user in the room
+	pub fn remove_user_receipts(&self, user_id: &UserId, room_id: &RoomId) -> Result<()> {
+		self.db.remove_user_receipts(user_id, room_id)
+	}
+
 	/// Sets a private read marker at `count`.
 	#[tracing::instrument(skip(self))]
 	pub fn private_read_set(&self, room_id: &RoomId, user_id: &UserId, count: u64) -> Result<()> {
@@ -51,8 +51,7 @@ impl Data {
 		let prefix = services()
 			.rooms
 			.short
-			.get_shortroomid(room_id)?
-			.expect("room exists")
+			.get_existing_shortroomid(room_id)?
 			.to_be_bytes()
 			.to_vec();
 
@@ -87,10 +86,84 @@ impl Data {
 /// Splits a string into tokens used as keys in the search inverted index
 ///
 /// This may be used to tokenize both message bodies (for indexing) or search
-/// queries (for querying).
-fn tokenize(body: &str) -> impl Iterator<Item = String> + '_ {
+/// queries (for querying). Scripts written without spaces between words are
+/// split into overlapping bigrams by `split_cjk`.
+pub(crate) fn tokenize(body: &str) -> impl Iterator<Item = String> + '_ {
 	body.split_terminator(|c: char| !c.is_alphanumeric())
 		.filter(|s| !s.is_empty())
+		.flat_map(split_cjk)
 		.filter(|word| word.len() <= 50)
-		.map(str::to_lowercase)
+		.map(|word| word.to_lowercase())
+}
+
+/// Splits the runs of CJK characters in a word into overlapping bigrams, so a
+/// search for any two or more consecutive characters finds the message. The
+/// rest of the word is kept whole.
+fn split_cjk(word: &str) -> Vec<String> {
+	let mut tokens = Vec::new();
+	let mut run: Vec<char> = Vec::new();
+	let mut other = String::new();
+	for c in word.chars().chain(std::iter::once(' ')) {
+		if is_cjk(c) {
+			if !other.is_empty() {
+				tokens.push(std::mem::take(&mut other));
+			}
+			run.push(c);
+			continue;
+		}
+
+		match run.len() {
+			0 => {},
+			1 => tokens.push(run.iter().collect()),
+			_ => tokens.extend(run.windows(2).map(|bigram| bigram.iter().collect())),
+		}
+		run.clear();
+
+		if c != ' ' {
+			other.push(c);
+		}
+	}
+
+	if !other.is_empty() {
+		tokens.push(other);
+	}
+
+	tokens
+}
+
+fn is_cjk(c: char) -> bool {
+	matches!(c,
+		'\u{3040}'..='\u{30FF}' // Hiragana, Katakana
+		| '\u{3400}'..='\u{4DBF}' // CJK Unified Ideographs Extension A
+		| '\u{4E00}'..='\u{9FFF}' // CJK Unified Ideographs
+		| '\u{AC00}'..='\u{D7AF}' // Hangul Syllables
+		| '\u{F900}'..='\u{FAFF}' // CJK Compatibility Ideographs
+		| '\u{20000}'..='\u{2FA1F}' // CJK Unified Ideographs Extension B and later
+	)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::tokenize;
+
+	fn tokens(body: &str) -> Vec<String> { tokenize(body).collect() }
+
+	#[test]
+	fn latin() {
+		assert_eq!(tokens("Hello, World! it's"), ["hello", "world", "it", "s"]);
+		assert_eq!(tokens("Größe café"), ["größe", "café"]);
+	}
+
+	#[test]
+	fn cjk_bigrams() {
+		assert_eq!(tokens("東京タワー"), ["東京", "京タ", "タワ", "ワー"]);
+		assert_eq!(tokens("猫"), ["猫"]);
+		assert_eq!(tokens("안녕하세요 세계"), ["안녕", "녕하", "하세", "세요", "세계"]);
+	}
+
+	#[test]
+	fn mixed_scripts() {
+		assert_eq!(tokens("Matrix服务器v2"), ["matrix", "服务", "务器", "v2"]);
+		assert_eq!(tokens("hello 世界"), ["hello", "世界"]);
+	}
 }
@@ -2,10 +2,22 @@ mod data;
 
 use std::sync::Arc;
 
-use conduit::{Result, Server};
+use conduit::{utils, Result, Server};
 use data::Data;
 use database::Database;
 use ruma::RoomId;
+use serde::Deserialize;
+
+pub(crate) use self::data::tokenize;
+use crate::PduEvent;
+
+/// Age after which a search result's rank is halved
+const RANK_HALF_LIFE_MS: f64 = 30.0 * 24.0 * 60.0 * 60.0 * 1000.0;
+
+#[derive(Deserialize)]
+struct ExtractBody {
+	body: Option<String>,
+}
 
 pub struct Service {
 	db: Data,
@@ -34,4 +46,24 @@ impl Service {
 	) -> Result<Option<(impl Iterator<Item = Vec<u8>> + 'a, Vec<String>)>> {
 		self.db.search_pdus(room_id, search_string)
 	}
+
+	/// Relevance of a message to the searched `words`: how often they occur in
+	/// its body, halved for every 30 days since it was sent.
+	#[must_use]
+	#[allow(clippy::cast_precision_loss)]
+	pub fn rank(&self, pdu: &PduEvent, words: &[String]) -> f64 {
+		let Some(body) = serde_json::from_str::<ExtractBody>(pdu.content.get())
+			.ok()
+			.and_then(|content| content.body)
+		else {
+			return 0.0;
+		};
+
+		let frequency = data::tokenize(&body)
+			.filter(|token| words.contains(token))
+			.count();
+
+		let age = utils::millis_since_unix_epoch().saturating_sub(pdu.origin_server_ts.into());
+		frequency as f64 * (-(age as f64) / RANK_HALF_LIFE_MS).exp2()
+	}
 }
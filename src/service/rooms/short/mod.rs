@@ -2,7 +2,7 @@ mod data;
 
 use std::sync::Arc;
 
-use conduit::{Result, Server};
+use conduit::{Error, Result, Server};
 use data::Data;
 use database::Database;
 use ruma::{events::StateEventType, EventId, RoomId};
@@ -49,7 +49,66 @@ impl Service {
 
 	pub fn get_shortroomid(&self, room_id: &RoomId) -> Result<Option<u64>> { self.db.get_shortroomid(room_id) }
 
+	/// The short ID of a room we are writing to or reading from, failing with
+	/// `Error::RoomGone` when it was purged in the meantime
+	pub fn get_existing_shortroomid(&self, room_id: &RoomId) -> Result<u64> {
+		existing_shortroomid(room_id, self.get_shortroomid(room_id))
+	}
+
 	pub fn get_or_create_shortroomid(&self, room_id: &RoomId) -> Result<u64> {
 		self.db.get_or_create_shortroomid(room_id)
 	}
 }
+
+/// The looked up short ID of a room, which is gone when it wasn't found
+fn existing_shortroomid(room_id: &RoomId, shortroomid: Result<Option<u64>>) -> Result<u64> {
+	shortroomid?.ok_or_else(|| Error::RoomGone(room_id.to_owned()))
+}
+
+#[cfg(test)]
+mod tests {
+	use std::{
+		collections::HashMap,
+		sync::{Barrier, RwLock},
+		thread,
+	};
+
+	use conduit::Error;
+	use ruma::{owned_room_id, room_id};
+
+	use super::existing_shortroomid;
+
+	#[test]
+	fn purge_during_lookups() {
+		let room_id = room_id!("!purged:example.com");
+		let shortroomids = RwLock::new(HashMap::from([(owned_room_id!("!purged:example.com"), 7_u64)]));
+		let start = Barrier::new(2);
+
+		let results: Vec<_> = thread::scope(|scope| {
+			scope.spawn(|| {
+				start.wait();
+				shortroomids.write().unwrap().remove(room_id);
+			});
+
+			start.wait();
+			(0..1000)
+				.map(|_| {
+					let shortroomid = shortroomids.read().unwrap().get(room_id).copied();
+					existing_shortroomid(room_id, Ok(shortroomid))
+				})
+				.collect()
+		});
+
+		// the room is found until the purge, and gone for good afterwards
+		let found = results.iter().take_while(|result| result.is_ok()).count();
+		assert!(results[..found]
+			.iter()
+			.all(|result| matches!(result, Ok(7))));
+		assert!(results[found..]
+			.iter()
+			.all(|result| matches!(result, Err(Error::RoomGone(gone)) if gone.as_str() == room_id.as_str())));
+
+		let purged = shortroomids.read().unwrap().get(room_id).copied();
+		assert!(matches!(existing_shortroomid(room_id, Ok(purged)), Err(Error::RoomGone(_))));
+	}
+}
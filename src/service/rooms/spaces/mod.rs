@@ -1,10 +1,12 @@
 use std::{
+	cmp::Ordering,
 	fmt::{Display, Formatter},
 	str::FromStr,
 	sync::Arc,
+	time::{Duration, Instant},
 };
 
-use conduit::{debug_info, Error, Result, Server};
+use conduit::{debug_info, debug_warn, Error, Result, Server};
 use database::Database;
 use lru_cache::LruCache;
 use ruma::{
@@ -24,6 +26,7 @@ use ruma::{
 		space::child::{HierarchySpaceChildEvent, SpaceChildEventContent},
 		StateEventType,
 	},
+	room::RoomType,
 	serde::Raw,
 	space::SpaceRoomJoinRule,
 	OwnedRoomId, OwnedServerName, RoomId, ServerName, UInt, UserId,
@@ -37,6 +40,13 @@ pub struct CachedSpaceHierarchySummary {
 	summary: SpaceHierarchyParentSummary,
 }
 
+/// Entry of the space hierarchy cache; the summary is `None` if the room could
+/// not be found
+pub struct CachedSpaceHierarchyEntry {
+	summary: Option<CachedSpaceHierarchySummary>,
+	cached_at: Instant,
+}
+
 enum SummaryAccessibility {
 	Accessible(Box<SpaceHierarchyParentSummary>),
 	Inaccessible,
@@ -294,7 +304,8 @@ enum Identifier<'a> {
 }
 
 pub struct Service {
-	pub roomid_spacehierarchy_cache: Mutex<LruCache<OwnedRoomId, Option<CachedSpaceHierarchySummary>>>,
+	pub roomid_spacehierarchy_cache: Mutex<LruCache<OwnedRoomId, CachedSpaceHierarchyEntry>>,
+	cache_ttl: Duration,
 }
 
 // Here because cannot implement `From` across ruma-federation-api and
@@ -340,9 +351,62 @@ impl Service {
 				(f64::from(config.roomid_spacehierarchy_cache_capacity) * config.conduit_cache_capacity_modifier)
 					as usize,
 			)),
+			cache_ttl: Duration::from_secs(config.roomid_spacehierarchy_cache_ttl),
 		})
 	}
 
+	/// Caches the summary of a room, or that it could not be found, until the
+	/// cache TTL passes
+	async fn cache_summary(&self, room_id: &RoomId, summary: Option<SpaceHierarchyParentSummary>) {
+		self.roomid_spacehierarchy_cache.lock().await.insert(
+			room_id.to_owned(),
+			CachedSpaceHierarchyEntry {
+				summary: summary.map(|summary| CachedSpaceHierarchySummary {
+					summary,
+				}),
+				cached_at: Instant::now(),
+			},
+		);
+	}
+
+	/// Gets the cached summary of a room, evicting the entry if it has expired.
+	/// The outer `None` means nothing is cached for the room.
+	async fn get_cached_summary(&self, room_id: &RoomId) -> Option<Option<SpaceHierarchyParentSummary>> {
+		let room_id = room_id.to_owned();
+		let mut cache = self.roomid_spacehierarchy_cache.lock().await;
+		let entry = cache.get_mut(&room_id)?;
+		if entry.cached_at.elapsed() > self.cache_ttl {
+			cache.remove(&room_id);
+			return None;
+		}
+
+		Some(entry.summary.as_ref().map(|cached| cached.summary.clone()))
+	}
+
+	/// Lists the rooms in the space hierarchy cache, whether a summary is
+	/// cached for each and the age of the entry
+	pub async fn cached_rooms(&self) -> Vec<(OwnedRoomId, bool, Duration)> {
+		self.roomid_spacehierarchy_cache
+			.lock()
+			.await
+			.iter()
+			.map(|(room_id, entry)| (room_id.clone(), entry.summary.is_some(), entry.cached_at.elapsed()))
+			.collect()
+	}
+
+	/// Removes the cached summary of a room, or of all rooms, returning the
+	/// number of entries removed
+	pub async fn clear_cache(&self, room_id: Option<&RoomId>) -> usize {
+		let mut cache = self.roomid_spacehierarchy_cache.lock().await;
+		if let Some(room_id) = room_id {
+			usize::from(cache.remove(&room_id.to_owned()).is_some())
+		} else {
+			let len = cache.len();
+			cache.clear();
+			len
+		}
+	}
+
 	///Gets the response for the space hierarchy over federation request
 	///
 	///Panics if the room does not exist, so a check if the room exists should
@@ -354,22 +418,25 @@ impl Service {
 			.get_summary_and_children_local(&room_id.to_owned(), Identifier::None)
 			.await?
 		{
-			Some(SummaryAccessibility::Accessible(room)) => {
+			Some(SummaryAccessibility::Accessible(mut room)) => {
 				let mut children = Vec::new();
 				let mut inaccessible_children = Vec::new();
 
+				room.children_state = get_sorted_children_state(&room.children_state, suggested_only);
+
 				for (child, _via) in get_parent_children_via(&room, suggested_only) {
 					match self
 						.get_summary_and_children_local(&child, Identifier::ServerName(server_name))
-						.await?
+						.await
 					{
-						Some(SummaryAccessibility::Accessible(summary)) => {
+						Ok(Some(SummaryAccessibility::Accessible(summary))) => {
 							children.push((*summary).into());
 						},
-						Some(SummaryAccessibility::Inaccessible) => {
+						Ok(Some(SummaryAccessibility::Inaccessible)) => {
 							inaccessible_children.push(child);
 						},
-						None => (),
+						Ok(None) => (),
+						Err(e) => debug_warn!("Failed to get summary of space child {child}: {e}"),
 					}
 				}
 
@@ -389,21 +456,10 @@ impl Service {
 	async fn get_summary_and_children_local(
 		&self, current_room: &OwnedRoomId, identifier: Identifier<'_>,
 	) -> Result<Option<SummaryAccessibility>> {
-		if let Some(cached) = self
-			.roomid_spacehierarchy_cache
-			.lock()
-			.await
-			.get_mut(&current_room.to_owned())
-			.as_ref()
-		{
-			return Ok(if let Some(cached) = cached {
-				if is_accessable_child(
-					current_room,
-					&cached.summary.join_rule,
-					&identifier,
-					&cached.summary.allowed_room_ids,
-				)? {
-					Some(SummaryAccessibility::Accessible(Box::new(cached.summary.clone())))
+		if let Some(cached) = self.get_cached_summary(current_room).await {
+			return Ok(if let Some(summary) = cached {
+				if is_accessable_child(current_room, &summary.join_rule, &identifier, &summary.allowed_room_ids)? {
+					Some(SummaryAccessibility::Accessible(Box::new(summary)))
 				} else {
 					Some(SummaryAccessibility::Inaccessible)
 				}
@@ -416,12 +472,8 @@ impl Service {
 			if let Some(children_pdus) = get_stripped_space_child_events(current_room).await? {
 				let summary = Self::get_room_summary(current_room, children_pdus, &identifier);
 				if let Ok(summary) = summary {
-					self.roomid_spacehierarchy_cache.lock().await.insert(
-						current_room.clone(),
-						Some(CachedSpaceHierarchySummary {
-							summary: summary.clone(),
-						}),
-					);
+					self.cache_summary(current_room, Some(summary.clone()))
+						.await;
 
 					Some(SummaryAccessibility::Accessible(Box::new(summary)))
 				} else {
@@ -440,7 +492,7 @@ impl Service {
 
 		for server in via {
 			debug_info!("Asking {server} for /hierarchy");
-			if let Ok(response) = services()
+			let Ok(response) = services()
 				.sending
 				.send_federation_request(
 					server,
@@ -450,77 +502,75 @@ impl Service {
 					},
 				)
 				.await
-			{
-				debug_info!("Got response from {server} for /hierarchy\n{response:?}");
-				let summary = response.room.clone();
-
-				self.roomid_spacehierarchy_cache.lock().await.insert(
-					current_room.clone(),
-					Some(CachedSpaceHierarchySummary {
-						summary: summary.clone(),
-					}),
-				);
-
-				for child in response.children {
-					let mut guard = self.roomid_spacehierarchy_cache.lock().await;
-					if !guard.contains_key(current_room) {
-						guard.insert(
-							current_room.clone(),
-							Some(CachedSpaceHierarchySummary {
-								summary: {
-									let SpaceHierarchyChildSummary {
-										canonical_alias,
-										name,
-										num_joined_members,
-										room_id,
-										topic,
-										world_readable,
-										guest_can_join,
-										avatar_url,
-										join_rule,
-										room_type,
-										allowed_room_ids,
-									} = child;
-
-									SpaceHierarchyParentSummary {
-										canonical_alias,
-										name,
-										num_joined_members,
-										room_id: room_id.clone(),
-										topic,
-										world_readable,
-										guest_can_join,
-										avatar_url,
-										join_rule,
-										room_type,
-										children_state: get_stripped_space_child_events(&room_id).await?.unwrap(),
-										allowed_room_ids,
-									}
-								},
-							}),
-						);
-					}
-				}
-				if is_accessable_child(
-					current_room,
-					&response.room.join_rule,
-					&Identifier::UserId(user_id),
-					&response.room.allowed_room_ids,
-				)? {
-					return Ok(Some(SummaryAccessibility::Accessible(Box::new(summary.clone()))));
-				}
+			else {
+				continue;
+			};
+
+			debug_info!("Got response from {server} for /hierarchy\n{response:?}");
+			let summary = response.room.clone();
+			self.cache_summary(current_room, Some(summary.clone()))
+				.await;
 
-				return Ok(Some(SummaryAccessibility::Inaccessible));
+			for child in response.children {
+				self.cache_federation_child(child).await;
 			}
 
-			self.roomid_spacehierarchy_cache
-				.lock()
-				.await
-				.insert(current_room.clone(), None);
+			if is_accessable_child(
+				current_room,
+				&response.room.join_rule,
+				&Identifier::UserId(user_id),
+				&response.room.allowed_room_ids,
+			)? {
+				return Ok(Some(SummaryAccessibility::Accessible(Box::new(summary))));
+			}
+
+			return Ok(Some(SummaryAccessibility::Inaccessible));
 		}
+
+		self.cache_summary(current_room, None).await;
 		Ok(None)
 	}
 
+	/// Caches a child summary received over federation. These do not include
+	/// the children of the child, so only rooms which are not spaces are
+	/// cached; child spaces are fetched on demand when traversed.
+	async fn cache_federation_child(&self, child: SpaceHierarchyChildSummary) {
+		if matches!(child.room_type, Some(RoomType::Space)) || self.get_cached_summary(&child.room_id).await.is_some() {
+			return;
+		}
+
+		let SpaceHierarchyChildSummary {
+			canonical_alias,
+			name,
+			num_joined_members,
+			room_id,
+			topic,
+			world_readable,
+			guest_can_join,
+			avatar_url,
+			join_rule,
+			room_type,
+			allowed_room_ids,
+		} = child;
+
+		let summary = SpaceHierarchyParentSummary {
+			canonical_alias,
+			name,
+			num_joined_members,
+			room_id: room_id.clone(),
+			topic,
+			world_readable,
+			guest_can_join,
+			avatar_url,
+			join_rule,
+			room_type,
+			children_state: Vec::new(),
+			allowed_room_ids,
+		};
+
+		self.cache_summary(&room_id, Some(summary)).await;
+	}
+
 	async fn get_summary_and_children_client(
 		&self, current_room: &OwnedRoomId, suggested_only: bool, user_id: &UserId, via: &[OwnedServerName],
 	) -> Result<Option<SummaryAccessibility>> {
@@ -585,7 +635,7 @@ impl Service {
 			topic: services()
 				.rooms
 				.state_accessor
-				.get_room_topic(room_id)
+				.get_room_topic_summary(room_id)
 				.unwrap_or(None),
 			world_readable: services().rooms.state_accessor.is_world_readable(room_id)?,
 			guest_can_join: services().rooms.state_accessor.guest_can_join(room_id)?,
@@ -674,7 +724,7 @@ impl Service {
 				if left_to_skip > 0 {
 					left_to_skip -= 1;
 				} else {
-					results.push(summary_to_chunk(*summary.clone()));
+					results.push(summary_to_chunk(*summary.clone(), suggested_only));
 				}
 
 				while let Some(current_room) = arena.first_untraversed() {
@@ -682,18 +732,22 @@ impl Service {
 						let node = arena
 							.get(current_room)
 							.expect("We added this node, it must exist");
-						if let Some(SummaryAccessibility::Accessible(summary)) = self
+						match self
 							.get_summary_and_children_client(&node.room_id, suggested_only, sender_user, &node.via)
-							.await?
+							.await
 						{
-							let children = get_parent_children_via(&summary, suggested_only);
-							arena.push(current_room, children);
-
-							if left_to_skip > 0 {
-								left_to_skip -= 1;
-							} else {
-								results.push(summary_to_chunk(*summary.clone()));
-							}
+							Ok(Some(SummaryAccessibility::Accessible(summary))) => {
+								let children = get_parent_children_via(&summary, suggested_only);
+								arena.push(current_room, children);
+
+								if left_to_skip > 0 {
+									left_to_skip -= 1;
+								} else {
+									results.push(summary_to_chunk(*summary.clone(), suggested_only));
+								}
+							},
+							Ok(_) => (),
+							Err(e) => debug_warn!("Failed to get summary of space child {}: {e}", node.room_id),
 						}
 					} else {
 						break;
@@ -709,7 +763,7 @@ impl Service {
 						skip.map(|skip| {
 							PagnationToken {
 								skip,
-								limit: UInt::new(max_depth as u64)
+								limit: UInt::new(limit as u64)
 									.expect("When sent in request it must have been valid UInt"),
 								max_depth: UInt::new(max_depth as u64)
 									.expect("When sent in request it must have been valid UInt"),
@@ -864,7 +918,7 @@ fn get_join_rule(current_room: &RoomId) -> Result<(SpaceRoomJoinRule, Vec<OwnedR
 
 // Here because cannot implement `From` across ruma-federation-api and
 // ruma-client-api types
-fn summary_to_chunk(summary: SpaceHierarchyParentSummary) -> SpaceHierarchyRoomsChunk {
+fn summary_to_chunk(summary: SpaceHierarchyParentSummary, suggested_only: bool) -> SpaceHierarchyRoomsChunk {
 	let SpaceHierarchyParentSummary {
 		canonical_alias,
 		name,
@@ -891,7 +945,7 @@ fn summary_to_chunk(summary: SpaceHierarchyParentSummary) -> SpaceHierarchyRooms
 		avatar_url,
 		join_rule,
 		room_type,
-		children_state,
+		children_state: get_sorted_children_state(&children_state, suggested_only),
 	}
 }
 
@@ -916,19 +970,66 @@ fn allowed_room_ids(join_rule: JoinRule) -> Vec<OwnedRoomId> {
 fn get_parent_children_via(
 	parent: &SpaceHierarchyParentSummary, suggested_only: bool,
 ) -> Vec<(OwnedRoomId, Vec<OwnedServerName>)> {
-	parent
-		.children_state
+	get_sorted_children(&parent.children_state, suggested_only)
+		.into_iter()
+		.map(|(_, ce)| (ce.state_key, ce.content.via))
+		.collect()
+}
+
+/// Returns the given m.space.child events, without the non-suggested ones if
+/// `suggested_only` is set, in the order the spec defines for space children
+fn get_sorted_children_state(
+	children_state: &[Raw<HierarchySpaceChildEvent>], suggested_only: bool,
+) -> Vec<Raw<HierarchySpaceChildEvent>> {
+	get_sorted_children(children_state, suggested_only)
+		.into_iter()
+		.map(|(raw_ce, _)| raw_ce)
+		.collect()
+}
+
+fn get_sorted_children(
+	children_state: &[Raw<HierarchySpaceChildEvent>], suggested_only: bool,
+) -> Vec<(Raw<HierarchySpaceChildEvent>, HierarchySpaceChildEvent)> {
+	let mut children: Vec<_> = children_state
 		.iter()
 		.filter_map(|raw_ce| {
-			raw_ce.deserialize().map_or(None, |ce| {
-				if suggested_only && !ce.content.suggested {
-					None
-				} else {
-					Some((ce.state_key, ce.content.via))
-				}
-			})
+			raw_ce
+				.deserialize()
+				.ok()
+				.filter(|ce| !suggested_only || ce.content.suggested)
+				.map(|ce| (raw_ce.clone(), ce))
 		})
-		.collect()
+		.collect();
+
+	children.sort_by(|(_, a), (_, b)| compare_space_children(a, b));
+	children
+}
+
+/// Children with a valid `order` come first, sorted lexicographically by it.
+/// Ties and children without one are sorted by the `origin_server_ts` of the
+/// m.space.child event, and then by room ID.
+fn compare_space_children(a: &HierarchySpaceChildEvent, b: &HierarchySpaceChildEvent) -> Ordering {
+	let (a_order, b_order) = (valid_space_child_order(a), valid_space_child_order(b));
+
+	match (a_order, b_order) {
+		(Some(a_order), Some(b_order)) => a_order.cmp(b_order),
+		(Some(_), None) => Ordering::Less,
+		(None, Some(_)) => Ordering::Greater,
+		(None, None) => Ordering::Equal,
+	}
+	.then_with(|| a.origin_server_ts.cmp(&b.origin_server_ts))
+	.then_with(|| a.state_key.cmp(&b.state_key))
+}
+
+/// Returns the `order` of a m.space.child event, unless it is not made up of
+/// printable ASCII characters or is longer than 50 characters, in which case
+/// it is treated as absent
+fn valid_space_child_order(child: &HierarchySpaceChildEvent) -> Option<&str> {
+	child
+		.content
+		.order
+		.as_deref()
+		.filter(|order| order.len() <= 50 && order.bytes().all(|b| (0x20..=0x7E).contains(&b)))
 }
 
 #[cfg(test)]
@@ -1155,6 +1256,72 @@ mod tests {
 		);
 	}
 
+	#[test]
+	fn get_summary_children_ordered() {
+		fn child(state_key: &str, order: Option<&str>, ts: u64, suggested: bool) -> Raw<HierarchySpaceChildEvent> {
+			let mut content = serde_json::json!({
+				"via": ["example.org"],
+				"suggested": suggested,
+			});
+			if let Some(order) = order {
+				content["order"] = order.into();
+			}
+
+			serde_json::from_value(serde_json::json!({
+				"content": content,
+				"origin_server_ts": ts,
+				"sender": "@alice:example.org",
+				"state_key": state_key,
+				"type": "m.space.child",
+			}))
+			.unwrap()
+		}
+
+		let summary: SpaceHierarchyParentSummary = SpaceHierarchyParentSummaryInit {
+			num_joined_members: UInt::from(1_u32),
+			room_id: owned_room_id!("!root:example.org"),
+			world_readable: true,
+			guest_can_join: true,
+			join_rule: SpaceRoomJoinRule::Public,
+			children_state: vec![
+				child("!unordered_late:example.org", None, 30, true),
+				child("!second:example.org", Some("b"), 50, false),
+				child("!unordered_early:example.org", None, 10, false),
+				child("!too_long:example.org", Some(&"a".repeat(51)), 20, true),
+				child("!non_ascii:example.org", Some("\u{e9}"), 40, false),
+				child("!first:example.org", Some("a"), 60, true),
+				child("!first_tie:example.org", Some("a"), 70, false),
+			],
+			allowed_room_ids: vec![],
+		}
+		.into();
+
+		let via = vec![owned_server_name!("example.org")];
+		assert_eq!(
+			get_parent_children_via(&summary, false),
+			vec![
+				(owned_room_id!("!first:example.org"), via.clone()),
+				(owned_room_id!("!first_tie:example.org"), via.clone()),
+				(owned_room_id!("!second:example.org"), via.clone()),
+				(owned_room_id!("!unordered_early:example.org"), via.clone()),
+				(owned_room_id!("!too_long:example.org"), via.clone()),
+				(owned_room_id!("!unordered_late:example.org"), via.clone()),
+				(owned_room_id!("!non_ascii:example.org"), via.clone()),
+			]
+		);
+		assert_eq!(
+			get_parent_children_via(&summary, true),
+			vec![
+				(owned_room_id!("!first:example.org"), via.clone()),
+				(owned_room_id!("!too_long:example.org"), via.clone()),
+				(owned_room_id!("!unordered_late:example.org"), via),
+			]
+		);
+
+		let chunk = summary_to_chunk(summary, true);
+		assert_eq!(chunk.children_state.len(), 3, "non-suggested children should be filtered out");
+	}
+
 	#[test]
 	fn allowed_room_ids_from_join_rule() {
 		let restricted_join_rule = JoinRule::Restricted(Restricted {
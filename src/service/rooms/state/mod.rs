@@ -19,7 +19,7 @@ use ruma::{
 	},
 	serde::Raw,
 	state_res::{self, StateMap},
-	EventId, OwnedEventId, RoomId, RoomVersionId, UserId,
+	EventId, OwnedEventId, OwnedUserId, RoomId, RoomVersionId, UserId,
 };
 
 use super::state_compressor::CompressedStateEvent;
@@ -66,15 +66,7 @@ impl Service {
 
 			match pdu.kind {
 				TimelineEventType::RoomMember => {
-					let Ok(membership_event) = serde_json::from_str::<RoomMemberEventContent>(pdu.content.get()) else {
-						continue;
-					};
-
-					let Some(state_key) = pdu.state_key else {
-						continue;
-					};
-
-					let Ok(user_id) = UserId::parse(state_key) else {
+					let Some((user_id, membership_event)) = membership_change(&pdu) else {
 						continue;
 					};
 
@@ -296,6 +288,14 @@ impl Service {
 		{
 			state.push(e.to_stripped_state_event());
 		}
+		if let Some(e) =
+			services()
+				.rooms
+				.state_accessor
+				.room_state_get(&invite_event.room_id, &StateEventType::RoomTopic, "")?
+		{
+			state.push(e.to_stripped_state_event());
+		}
 		if let Some(e) = services().rooms.state_accessor.room_state_get(
 			&invite_event.room_id,
 			&StateEventType::RoomMember,
@@ -415,3 +415,61 @@ impl Service {
 			.collect())
 	}
 }
+
+/// The member and membership a state event sets. Only member events change
+/// memberships; other state such as the join rules leaves the pending invites
+/// of the room as they are.
+fn membership_change(pdu: &PduEvent) -> Option<(OwnedUserId, RoomMemberEventContent)> {
+	if pdu.kind != TimelineEventType::RoomMember {
+		return None;
+	}
+
+	let content = serde_json::from_str(pdu.content.get()).ok()?;
+	let user_id = UserId::parse(pdu.state_key.as_deref()?).ok()?;
+
+	Some((user_id, content))
+}
+
+#[cfg(test)]
+mod tests {
+	use ruma::{events::room::member::MembershipState, user_id};
+	use serde_json::json;
+
+	use super::membership_change;
+	use crate::PduEvent;
+
+	fn state_event(kind: &str, state_key: &str, content: serde_json::Value) -> PduEvent {
+		serde_json::from_value(json!({
+			"event_id": "$event",
+			"room_id": "!room:example.com",
+			"sender": "@alice:example.com",
+			"origin_server_ts": 1,
+			"type": kind,
+			"state_key": state_key,
+			"content": content,
+			"prev_events": [],
+			"depth": 1,
+			"auth_events": [],
+			"hashes": { "sha256": "" },
+		}))
+		.unwrap()
+	}
+
+	#[test]
+	fn join_rule_changes_keep_pending_invites() {
+		let invite = state_event("m.room.member", "@bob:example.com", json!({ "membership": "invite" }));
+		let (user_id, content) = membership_change(&invite).unwrap();
+		assert_eq!(user_id, user_id!("@bob:example.com"));
+		assert_eq!(content.membership, MembershipState::Invite);
+
+		// knock -> invite and back write no membership, so bob's invite stays
+		// the single one recorded for him
+		for join_rule in ["invite", "knock", "public"] {
+			let join_rules = state_event("m.room.join_rules", "", json!({ "join_rule": join_rule }));
+			assert!(membership_change(&join_rules).is_none());
+		}
+
+		let invalid = state_event("m.room.member", "bob", json!({ "membership": "invite" }));
+		assert!(membership_change(&invalid).is_none());
+	}
+}
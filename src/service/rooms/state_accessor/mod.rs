@@ -21,18 +21,61 @@ use ruma::{
 			power_levels::{RoomPowerLevels, RoomPowerLevelsEventContent},
 			topic::RoomTopicEventContent,
 		},
-		StateEventType,
+		MessageLikeEventType, StateEventType,
 	},
 	EventId, OwnedRoomAliasId, OwnedServerName, OwnedUserId, RoomId, ServerName, UserId,
 };
+use serde::Deserialize;
 use serde_json::value::to_raw_value;
 
 use crate::{pdu::PduBuilder, services, PduEvent};
 
+/// Longest topic shown in room summaries and the room directory, in characters
+const MAX_SUMMARY_TOPIC_CHARS: usize = 1024;
+
+/// The parts of an `m.room.topic` content needed for its plain text, without
+/// rejecting topics which only have rich representations (MSC3765)
+#[derive(Deserialize)]
+struct ExtractTopic {
+	/// Not required to be a string, so a malformed one still leaves `m.topic`
+	topic: Option<serde_json::Value>,
+	/// Parsed on its own, so a malformed one still leaves the `topic` fallback
+	#[serde(rename = "m.topic")]
+	rich: Option<serde_json::Value>,
+}
+
+/// `m.topic` as first proposed in MSC3765, and as a content block of `m.text`
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RichTopic {
+	Representations(Vec<TextRepresentation>),
+	Text {
+		#[serde(rename = "m.text")]
+		text: Vec<TextRepresentation>,
+	},
+}
+
+#[derive(Deserialize)]
+struct TextRepresentation {
+	mimetype: Option<String>,
+	body: String,
+}
+
 pub struct Service {
 	db: Data,
 	pub server_visibility_cache: Mutex<LruCache<(OwnedServerName, u64), bool>>,
-	pub user_visibility_cache: Mutex<LruCache<(OwnedUserId, u64), bool>>,
+	pub user_visibility_cache: Mutex<LruCache<(OwnedUserId, u64), UserVisibility>>,
+}
+
+/// Visibility of an event to a user, as far as the state at the event decides
+/// it. The user's current membership is only needed for `shared` history, so
+/// it is left out and this can be cached per state.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum UserVisibility {
+	Visible,
+	Hidden,
+	/// Visible as long as the user is currently joined to the room
+	IfJoined,
 }
 
 impl Service {
@@ -87,20 +130,22 @@ impl Service {
 			})
 	}
 
-	/// The user was a joined member at this state (potentially in the past)
-	fn user_was_joined(&self, shortstatehash: u64, user_id: &UserId) -> bool {
-		self.user_membership(shortstatehash, user_id)
-			.is_ok_and(|s| s == MembershipState::Join)
-		// Return sensible default, i.e.
-		// false
-	}
-
-	/// The user was an invited or joined room member at this state (potentially
-	/// in the past)
-	fn user_was_invited(&self, shortstatehash: u64, user_id: &UserId) -> bool {
-		self.user_membership(shortstatehash, user_id)
-			.is_ok_and(|s| s == MembershipState::Join || s == MembershipState::Invite)
-		// Return sensible default, i.e. false
+	/// The room's history_visibility at this state
+	fn history_visibility(&self, shortstatehash: u64, room_id: &RoomId) -> Result<HistoryVisibility> {
+		Ok(self
+			.state_get(shortstatehash, &StateEventType::RoomHistoryVisibility, "")?
+			.map_or(Ok(HistoryVisibility::Shared), |s| {
+				serde_json::from_str(s.content.get())
+					.map(|c: RoomHistoryVisibilityEventContent| c.history_visibility)
+					.map_err(|e| {
+						error!(
+							"Invalid history visibility event in database for room {room_id}, assuming is \"shared\": \
+							 {e}"
+						);
+						Error::bad_database("Invalid history visibility event in database.")
+					})
+			})
+			.unwrap_or(HistoryVisibility::Shared))
 	}
 
 	/// Whether a server is allowed to see an event through federation, based on
@@ -120,20 +165,7 @@ impl Service {
 			return Ok(*visibility);
 		}
 
-		let history_visibility = self
-			.state_get(shortstatehash, &StateEventType::RoomHistoryVisibility, "")?
-			.map_or(Ok(HistoryVisibility::Shared), |s| {
-				serde_json::from_str(s.content.get())
-					.map(|c: RoomHistoryVisibilityEventContent| c.history_visibility)
-					.map_err(|e| {
-						error!(
-							"Invalid history visibility event in database for room {room_id}, assuming is \"shared\": \
-							 {e}"
-						);
-						Error::bad_database("Invalid history visibility event in database.")
-					})
-			})
-			.unwrap_or(HistoryVisibility::Shared);
+		let history_visibility = self.history_visibility(shortstatehash, room_id)?;
 
 		let mut current_server_members = services()
 			.rooms
@@ -142,20 +174,16 @@ impl Service {
 			.filter_map(Result::ok)
 			.filter(|member| member.server_name() == origin);
 
+		// A server sees what any of its users could see; it is in the room, so
+		// its users' current membership doesn't restrict `shared` history.
 		let visibility = match history_visibility {
 			HistoryVisibility::WorldReadable | HistoryVisibility::Shared => true,
-			HistoryVisibility::Invited => {
-				// Allow if any member on requesting server was AT LEAST invited, else deny
-				current_server_members.any(|member| self.user_was_invited(shortstatehash, &member))
-			},
-			HistoryVisibility::Joined => {
-				// Allow if any member on requested server was joined, else deny
-				current_server_members.any(|member| self.user_was_joined(shortstatehash, &member))
-			},
-			_ => {
-				error!("Unknown history visibility {history_visibility}");
-				false
-			},
+			_ => current_server_members.any(|member| {
+				self.user_membership(shortstatehash, &member)
+					.is_ok_and(|membership| {
+						user_visibility(&history_visibility, &membership) == UserVisibility::Visible
+					})
+			}),
 		};
 
 		self.server_visibility_cache
@@ -174,55 +202,35 @@ impl Service {
 			return Ok(true);
 		};
 
-		if let Some(visibility) = self
+		let cached = self
 			.user_visibility_cache
 			.lock()
 			.unwrap()
 			.get_mut(&(user_id.to_owned(), shortstatehash))
-		{
-			return Ok(*visibility);
-		}
-
-		let currently_member = services().rooms.state_cache.is_joined(user_id, room_id)?;
-
-		let history_visibility = self
-			.state_get(shortstatehash, &StateEventType::RoomHistoryVisibility, "")?
-			.map_or(Ok(HistoryVisibility::Shared), |s| {
-				serde_json::from_str(s.content.get())
-					.map(|c: RoomHistoryVisibilityEventContent| c.history_visibility)
-					.map_err(|e| {
-						error!(
-							"Invalid history visibility event in database for room {room_id}, assuming is \"shared\": \
-							 {e}"
-						);
-						Error::bad_database("Invalid history visibility event in database.")
-					})
-			})
-			.unwrap_or(HistoryVisibility::Shared);
-
-		let visibility = match history_visibility {
-			HistoryVisibility::WorldReadable => true,
-			HistoryVisibility::Shared => currently_member,
-			HistoryVisibility::Invited => {
-				// Allow if any member on requesting server was AT LEAST invited, else deny
-				self.user_was_invited(shortstatehash, user_id)
-			},
-			HistoryVisibility::Joined => {
-				// Allow if any member on requested server was joined, else deny
-				self.user_was_joined(shortstatehash, user_id)
-			},
-			_ => {
-				error!("Unknown history visibility {history_visibility}");
-				false
-			},
+			.copied();
+
+		let visibility = if let Some(visibility) = cached {
+			visibility
+		} else {
+			let history_visibility = self.history_visibility(shortstatehash, room_id)?;
+			let membership = self
+				.user_membership(shortstatehash, user_id)
+				.unwrap_or(MembershipState::Leave);
+			let visibility = user_visibility(&history_visibility, &membership);
+
+			self.user_visibility_cache
+				.lock()
+				.unwrap()
+				.insert((user_id.to_owned(), shortstatehash), visibility);
+
+			visibility
 		};
 
-		self.user_visibility_cache
-			.lock()
-			.unwrap()
-			.insert((user_id.to_owned(), shortstatehash), visibility);
-
-		Ok(visibility)
+		match visibility {
+			UserVisibility::Visible => Ok(true),
+			UserVisibility::Hidden => Ok(false),
+			UserVisibility::IfJoined => services().rooms.state_cache.is_joined(user_id, room_id),
+		}
 	}
 
 	/// Whether a user is allowed to see an event, based on
@@ -371,6 +379,58 @@ impl Service {
 			})
 	}
 
+	/// Gets the plain text topic shown in room summaries and the room
+	/// directory, capped in length
+	pub fn get_room_topic_summary(&self, room_id: &RoomId) -> Result<Option<String>> {
+		Ok(self
+			.room_state_get(room_id, &StateEventType::RoomTopic, "")?
+			.and_then(|s| summary_topic(&s)))
+	}
+
+	/// Checks if a given user has the power level to send a state event of
+	/// `event_type`. Without power levels only the room creator can.
+	pub fn user_can_send_state(&self, sender: &UserId, room_id: &RoomId, event_type: StateEventType) -> Result<bool> {
+		let Some(event) = self.room_state_get(room_id, &StateEventType::RoomPowerLevels, "")? else {
+			return Ok(self
+				.room_state_get(room_id, &StateEventType::RoomCreate, "")?
+				.is_some_and(|pdu| pdu.sender == sender));
+		};
+
+		serde_json::from_str(event.content.get())
+			.map(|content: RoomPowerLevelsEventContent| {
+				RoomPowerLevels::from(content).user_can_send_state(sender, event_type)
+			})
+			.map_err(|_| Error::bad_database("Invalid m.room.power_levels event in database"))
+	}
+
+	/// Checks if a given user has the power level to send a message-like
+	/// event of `event_type`. Without power levels every member can.
+	pub fn user_can_send_message(
+		&self, sender: &UserId, room_id: &RoomId, event_type: MessageLikeEventType,
+	) -> Result<bool> {
+		let Some(event) = self.room_state_get(room_id, &StateEventType::RoomPowerLevels, "")? else {
+			return Ok(true);
+		};
+
+		serde_json::from_str(event.content.get())
+			.map(|content: RoomPowerLevelsEventContent| {
+				RoomPowerLevels::from(content).user_can_send_message(sender, event_type)
+			})
+			.map_err(|_| Error::bad_database("Invalid m.room.power_levels event in database"))
+	}
+
+	/// Checks if a given user has the power level to invite users. Without
+	/// power levels every member can.
+	pub fn user_has_invite_power(&self, sender: &UserId, room_id: &RoomId) -> Result<bool> {
+		let Some(event) = self.room_state_get(room_id, &StateEventType::RoomPowerLevels, "")? else {
+			return Ok(true);
+		};
+
+		serde_json::from_str(event.content.get())
+			.map(|content: RoomPowerLevelsEventContent| RoomPowerLevels::from(content).user_can_invite(sender))
+			.map_err(|_| Error::bad_database("Invalid m.room.power_levels event in database"))
+	}
+
 	/// Checks if a given user can redact a given event
 	///
 	/// If federation is true, it allows redaction events from any user of the
@@ -416,3 +476,185 @@ impl Service {
 			)
 	}
 }
+
+/// Applies the history visibility rules to a user whose membership at the
+/// event was `membership`. Joined members always see the events sent while
+/// they were joined.
+fn user_visibility(history_visibility: &HistoryVisibility, membership: &MembershipState) -> UserVisibility {
+	if *membership == MembershipState::Join {
+		return UserVisibility::Visible;
+	}
+
+	match history_visibility {
+		HistoryVisibility::WorldReadable => UserVisibility::Visible,
+		HistoryVisibility::Shared => UserVisibility::IfJoined,
+		HistoryVisibility::Invited if *membership == MembershipState::Invite => UserVisibility::Visible,
+		HistoryVisibility::Invited | HistoryVisibility::Joined => UserVisibility::Hidden,
+		_ => {
+			error!("Unknown history visibility {history_visibility}");
+			UserVisibility::Hidden
+		},
+	}
+}
+
+/// The topic of an `m.room.topic` event as shown in room summaries and the room
+/// directory
+#[must_use]
+pub fn summary_topic(pdu: &PduEvent) -> Option<String> { plain_topic(pdu.content.get(), MAX_SUMMARY_TOPIC_CHARS) }
+
+/// The plain text of a topic's content, preferring the `text/plain`
+/// representation of a rich topic over the `topic` fallback
+fn plain_topic(content: &str, max_chars: usize) -> Option<String> {
+	let content: ExtractTopic = serde_json::from_str(content).ok()?;
+	let representations = match content.rich.map(serde_json::from_value) {
+		Some(Ok(
+			RichTopic::Representations(representations)
+			| RichTopic::Text {
+				text: representations,
+			},
+		)) => representations,
+		Some(Err(_)) | None => Vec::new(),
+	};
+
+	let topic = representations
+		.into_iter()
+		.find(|r| r.mimetype.as_deref().map_or(true, |m| m == "text/plain"))
+		.map(|r| r.body)
+		.or_else(|| content.topic?.as_str().map(ToOwned::to_owned))?;
+
+	Some(match topic.char_indices().nth(max_chars) {
+		Some((end, _)) => topic[..end].to_owned(),
+		None => topic,
+	})
+}
+
+#[cfg(test)]
+mod tests {
+	use ruma::events::room::{history_visibility::HistoryVisibility, member::MembershipState};
+	use serde_json::json;
+
+	use super::{plain_topic, user_visibility, UserVisibility};
+
+	#[test]
+	fn rich_topic_prefers_plain_text() {
+		let content = json!({
+			"topic": "fallback",
+			"m.topic": [
+				{ "mimetype": "text/html", "body": "<b>Rich</b> topic" },
+				{ "mimetype": "text/plain", "body": "Rich topic" },
+			],
+		});
+		assert_eq!(plain_topic(&content.to_string(), 1024).as_deref(), Some("Rich topic"));
+
+		let content = json!({ "m.topic": { "m.text": [{ "body": "No mimetype is plain" }] } });
+		assert_eq!(plain_topic(&content.to_string(), 1024).as_deref(), Some("No mimetype is plain"));
+
+		let content = json!({ "topic": "fallback", "m.topic": [{ "mimetype": "text/html", "body": "<i>x</i>" }] });
+		assert_eq!(plain_topic(&content.to_string(), 1024).as_deref(), Some("fallback"));
+	}
+
+	#[test]
+	fn summary_topic_is_truncated() {
+		let content = json!({
+			"topic": "fallback",
+			"m.topic": [
+				{ "mimetype": "text/html", "body": "<p>ééééé</p>" },
+				{ "mimetype": "text/plain", "body": "ééééé" },
+			],
+		});
+		assert_eq!(plain_topic(&content.to_string(), 3).as_deref(), Some("ééé"));
+		assert_eq!(plain_topic(&content.to_string(), 5).as_deref(), Some("ééééé"));
+		assert_eq!(plain_topic(r#"{"m.topic":"invalid"}"#, 3), None);
+		assert_eq!(
+			plain_topic(r#"{"topic":"fallback","m.topic":"invalid"}"#, 3).as_deref(),
+			Some("fal")
+		);
+		assert_eq!(
+			plain_topic(r#"{"topic":5,"m.topic":[{"body":"rich"}]}"#, 3).as_deref(),
+			Some("ric")
+		);
+		assert_eq!(plain_topic(r#"{"topic":5}"#, 3), None);
+	}
+
+	#[test]
+	fn shared_history_needs_current_membership() {
+		// Before joining, shared history is visible once the user is in the room
+		assert_eq!(
+			user_visibility(&HistoryVisibility::Shared, &MembershipState::Leave),
+			UserVisibility::IfJoined
+		);
+		// Events sent while the user was joined stay visible after leaving
+		assert_eq!(
+			user_visibility(&HistoryVisibility::Shared, &MembershipState::Join),
+			UserVisibility::Visible
+		);
+	}
+
+	#[test]
+	fn invited_history_starts_at_invite() {
+		assert_eq!(
+			user_visibility(&HistoryVisibility::Invited, &MembershipState::Leave),
+			UserVisibility::Hidden
+		);
+		assert_eq!(
+			user_visibility(&HistoryVisibility::Invited, &MembershipState::Invite),
+			UserVisibility::Visible
+		);
+		assert_eq!(
+			user_visibility(&HistoryVisibility::Invited, &MembershipState::Join),
+			UserVisibility::Visible
+		);
+	}
+
+	#[test]
+	fn joined_history_starts_at_join() {
+		assert_eq!(
+			user_visibility(&HistoryVisibility::Joined, &MembershipState::Leave),
+			UserVisibility::Hidden
+		);
+		assert_eq!(
+			user_visibility(&HistoryVisibility::Joined, &MembershipState::Invite),
+			UserVisibility::Hidden
+		);
+		assert_eq!(
+			user_visibility(&HistoryVisibility::Joined, &MembershipState::Join),
+			UserVisibility::Visible
+		);
+	}
+
+	#[test]
+	fn world_readable_history_is_visible_to_anyone() {
+		for membership in [
+			MembershipState::Leave,
+			MembershipState::Invite,
+			MembershipState::Ban,
+			MembershipState::Join,
+		] {
+			assert_eq!(
+				user_visibility(&HistoryVisibility::WorldReadable, &membership),
+				UserVisibility::Visible
+			);
+		}
+	}
+
+	#[test]
+	fn visibility_changes_apply_from_their_event() {
+		// A room going from world_readable to joined hides the later events from
+		// a user who joins afterwards, but not the earlier ones.
+		let before_join = MembershipState::Leave;
+		assert_eq!(
+			user_visibility(&HistoryVisibility::WorldReadable, &before_join),
+			UserVisibility::Visible
+		);
+		assert_eq!(
+			user_visibility(&HistoryVisibility::Joined, &before_join),
+			UserVisibility::Hidden
+		);
+
+		// Switching from joined to shared exposes later events to members only
+		assert_eq!(
+			user_visibility(&HistoryVisibility::Shared, &before_join),
+			UserVisibility::IfJoined
+		);
+	}
+}
@@ -1,4 +1,4 @@
-use std::{collections::HashSet, sync::Arc};
+use std::{collections::HashSet, mem::size_of, sync::Arc};
 
 use conduit::{utils, Error, Result};
 use database::{Database, Map};
@@ -6,9 +6,10 @@ use itertools::Itertools;
 use ruma::{
 	events::{AnyStrippedStateEvent, AnySyncStateEvent},
 	serde::Raw,
-	OwnedRoomId, OwnedServerName, OwnedUserId, RoomId, ServerName, UserId,
+	EventId, OwnedEventId, OwnedRoomId, OwnedServerName, OwnedUserId, RoomId, ServerName, UserId,
 };
 
+use super::RejectedInvite;
 use crate::{appservice::RegistrationInfo, services, user_is_local};
 
 type StrippedStateEventIter<'a> = Box<dyn Iterator<Item = Result<(OwnedRoomId, Vec<Raw<AnyStrippedStateEvent>>)>> + 'a>;
@@ -18,6 +19,9 @@ pub(super) struct Data {
 	userroomid_joined: Arc<Map>,
 	roomuserid_joined: Arc<Map>,
 	userroomid_invitestate: Arc<Map>,
+	userroomid_inviteeventid: Arc<Map>,
+	userroomid_invitereject: Arc<Map>,
+	inviteblockedservernames: Arc<Map>,
 	roomuserid_invitecount: Arc<Map>,
 	userroomid_leftstate: Arc<Map>,
 	roomuserid_leftcount: Arc<Map>,
@@ -36,6 +40,9 @@ impl Data {
 			userroomid_joined: db["userroomid_joined"].clone(),
 			roomuserid_joined: db["roomuserid_joined"].clone(),
 			userroomid_invitestate: db["userroomid_invitestate"].clone(),
+			userroomid_inviteeventid: db["userroomid_inviteeventid"].clone(),
+			userroomid_invitereject: db["userroomid_invitereject"].clone(),
+			inviteblockedservernames: db["inviteblockedservernames"].clone(),
 			roomuserid_invitecount: db["roomuserid_invitecount"].clone(),
 			userroomid_leftstate: db["userroomid_leftstate"].clone(),
 			roomuserid_leftcount: db["roomuserid_leftcount"].clone(),
@@ -70,15 +77,117 @@ impl Data {
 		self.userroomid_joined.insert(&userroom_id, &[])?;
 		self.roomuserid_joined.insert(&roomuser_id, &[])?;
 		self.userroomid_invitestate.remove(&userroom_id)?;
+		self.userroomid_inviteeventid.remove(&userroom_id)?;
 		self.roomuserid_invitecount.remove(&roomuser_id)?;
 		self.userroomid_leftstate.remove(&userroom_id)?;
 		self.roomuserid_leftcount.remove(&roomuser_id)?;
+		self.userroomid_invitereject.remove(&userroom_id)?;
 
 		self.roomid_inviteviaservers.remove(&roomid)?;
 
 		Ok(())
 	}
 
+	/// Records the event of the user's invite to the room, as received over
+	/// federation
+	pub(super) fn mark_invite_event(&self, user_id: &UserId, room_id: &RoomId, event_id: &EventId) -> Result<()> {
+		let mut userroom_id = user_id.as_bytes().to_vec();
+		userroom_id.push(0xFF);
+		userroom_id.extend_from_slice(room_id.as_bytes());
+
+		self.userroomid_inviteeventid
+			.insert(&userroom_id, event_id.as_bytes())
+	}
+
+	pub(super) fn invite_event_id(&self, user_id: &UserId, room_id: &RoomId) -> Result<Option<OwnedEventId>> {
+		let mut userroom_id = user_id.as_bytes().to_vec();
+		userroom_id.push(0xFF);
+		userroom_id.extend_from_slice(room_id.as_bytes());
+
+		self.userroomid_inviteeventid
+			.get(&userroom_id)?
+			.map(|bytes| {
+				EventId::parse(
+					utils::string_from_bytes(&bytes)
+						.map_err(|_| Error::bad_database("Invalid event ID bytes in userroomid_inviteeventid."))?,
+				)
+				.map_err(|_| Error::bad_database("Invalid event ID in userroomid_inviteeventid."))
+			})
+			.transpose()
+	}
+
+	/// Records the user's rejection of their invite to the room: the
+	/// `origin_server_ts` of the leave event, in milliseconds, followed by the
+	/// invite's event ID if we know it
+	pub(super) fn mark_invite_rejected(
+		&self, user_id: &UserId, room_id: &RoomId, rejected: &RejectedInvite,
+	) -> Result<()> {
+		let mut userroom_id = user_id.as_bytes().to_vec();
+		userroom_id.push(0xFF);
+		userroom_id.extend_from_slice(room_id.as_bytes());
+
+		let mut value = rejected.rejected_at.to_be_bytes().to_vec();
+		if let Some(event_id) = &rejected.event_id {
+			value.extend_from_slice(event_id.as_bytes());
+		}
+
+		self.userroomid_invitereject.insert(&userroom_id, &value)
+	}
+
+	pub(super) fn rejected_invite(&self, user_id: &UserId, room_id: &RoomId) -> Result<Option<RejectedInvite>> {
+		let mut userroom_id = user_id.as_bytes().to_vec();
+		userroom_id.push(0xFF);
+		userroom_id.extend_from_slice(room_id.as_bytes());
+
+		self.userroomid_invitereject
+			.get(&userroom_id)?
+			.map(|bytes| {
+				let (rejected_at, event_id) = bytes.split_at(size_of::<u64>().min(bytes.len()));
+				let rejected_at = utils::u64_from_bytes(rejected_at)
+					.map_err(|_| Error::bad_database("Invalid timestamp in userroomid_invitereject."))?;
+				let event_id =
+					(!event_id.is_empty())
+						.then(|| {
+							EventId::parse(utils::string_from_bytes(event_id).map_err(|_| {
+								Error::bad_database("Invalid event ID bytes in userroomid_invitereject.")
+							})?)
+							.map_err(|_| Error::bad_database("Invalid event ID in userroomid_invitereject."))
+						})
+						.transpose()?;
+
+				Ok(RejectedInvite {
+					rejected_at,
+					event_id,
+				})
+			})
+			.transpose()
+	}
+
+	pub(super) fn block_invites(&self, server: &ServerName, blocked: bool) -> Result<()> {
+		if blocked {
+			self.inviteblockedservernames.insert(server.as_bytes(), &[])
+		} else {
+			self.inviteblockedservernames.remove(server.as_bytes())
+		}
+	}
+
+	pub(super) fn invites_blocked(&self, server: &ServerName) -> Result<bool> {
+		Ok(self
+			.inviteblockedservernames
+			.get(server.as_bytes())?
+			.is_some())
+	}
+
+	pub(super) fn invite_blocked_servers<'a>(&'a self) -> Box<dyn Iterator<Item = Result<OwnedServerName>> + 'a> {
+		Box::new(self.inviteblockedservernames.iter().map(|(key, _)| {
+			ServerName::parse(
+				utils::string_from_bytes(&key)
+					.map_err(|_| Error::bad_database("Server name in inviteblockedservernames is invalid unicode."))?,
+			)
+			.map_err(|_| Error::bad_database("Server name in inviteblockedservernames is invalid."))
+		}))
+	}
+
 	pub(super) fn mark_as_invited(
 		&self, user_id: &UserId, room_id: &RoomId, last_state: Option<Vec<Raw<AnyStrippedStateEvent>>>,
 		invite_via: Option<Vec<OwnedServerName>>,
@@ -95,6 +204,7 @@ impl Data {
 			&userroom_id,
 			&serde_json::to_vec(&last_state.unwrap_or_default()).expect("state to bytes always works"),
 		)?;
+		self.userroomid_inviteeventid.remove(&userroom_id)?;
 		self.roomuserid_invitecount
 			.insert(&roomuser_id, &services().globals.next_count()?.to_be_bytes())?;
 		self.userroomid_joined.remove(&userroom_id)?;
@@ -144,6 +254,7 @@ impl Data {
 		self.userroomid_joined.remove(&userroom_id)?;
 		self.roomuserid_joined.remove(&roomuser_id)?;
 		self.userroomid_invitestate.remove(&userroom_id)?;
+		self.userroomid_inviteeventid.remove(&userroom_id)?;
 		self.roomuserid_invitecount.remove(&roomuser_id)?;
 
 		self.roomid_inviteviaservers.remove(&roomid)?;
@@ -0,0 +1,176 @@
+use conduit::{Config, Result};
+use ruma::{OwnedServerName, ServerName, UserId};
+
+use super::Service;
+use crate::services;
+
+/// The invite settings of the config
+struct InviteRules<'a> {
+	server_name: &'a ServerName,
+	allowed: &'a [OwnedServerName],
+	blocked: &'a [OwnedServerName],
+}
+
+impl Service {
+	/// Why an invite from `sender` to the local user `user_id` is refused, if
+	/// it is: the sender's server may not invite our users, or the user only
+	/// accepts invites from users they share a room with.
+	pub fn invite_refusal(&self, sender: &UserId, user_id: &UserId) -> Result<Option<&'static str>> {
+		check_invite(
+			&InviteRules::from_config(&services().globals.config),
+			sender.server_name(),
+			|server| self.db.invites_blocked(server),
+			|| {
+				services()
+					.account_data
+					.invite_permissions(user_id)
+					.map(|permissions| permissions.require_shared_room)
+			},
+			|| {
+				Ok(services()
+					.rooms
+					.user
+					.get_shared_rooms(vec![sender.to_owned(), user_id.to_owned()])?
+					.filter_map(Result::ok)
+					.next()
+					.is_some())
+			},
+		)
+	}
+
+	/// Whether users of the server may invite our users, according to
+	/// `invite_allowed_servers`, `invite_blocked_servers` and the servers
+	/// blocked with `federation block-invites`
+	pub fn server_may_invite(&self, server: &ServerName) -> Result<bool> {
+		check_server(&InviteRules::from_config(&services().globals.config), server, |server| {
+			self.db.invites_blocked(server)
+		})
+	}
+
+	/// Blocks or unblocks invites from the server, in addition to
+	/// `invite_blocked_servers`
+	pub fn block_invites(&self, server: &ServerName, blocked: bool) -> Result<()> {
+		self.db.block_invites(server, blocked)
+	}
+
+	/// Servers blocked with `federation block-invites`
+	pub fn invite_blocked_servers(&self) -> impl Iterator<Item = Result<OwnedServerName>> + '_ {
+		self.db.invite_blocked_servers()
+	}
+}
+
+impl<'a> InviteRules<'a> {
+	fn from_config(config: &'a Config) -> Self {
+		Self {
+			server_name: &config.server_name,
+			allowed: &config.invite_allowed_servers,
+			blocked: &config.invite_blocked_servers,
+		}
+	}
+}
+
+/// Decides on an invite from a user of `server`, only looking up the
+/// invitee's settings and shared rooms when they are needed
+fn check_invite<B, R, S>(
+	rules: &InviteRules<'_>, server: &ServerName, invites_blocked: B, require_shared_room: R, shares_room: S,
+) -> Result<Option<&'static str>>
+where
+	B: FnOnce(&ServerName) -> Result<bool>,
+	R: FnOnce() -> Result<bool>,
+	S: FnOnce() -> Result<bool>,
+{
+	if !check_server(rules, server, invites_blocked)? {
+		return Ok(Some("Invites from this server are not accepted."));
+	}
+
+	if require_shared_room()? && !shares_room()? {
+		return Ok(Some("This user only accepts invites from users they share a room with."));
+	}
+
+	Ok(None)
+}
+
+fn check_server<B>(rules: &InviteRules<'_>, server: &ServerName, invites_blocked: B) -> Result<bool>
+where
+	B: FnOnce(&ServerName) -> Result<bool>,
+{
+	if server == rules.server_name {
+		return Ok(true);
+	}
+
+	let blocked = rules.blocked.iter().any(|s| s == server) || invites_blocked(server)?;
+
+	Ok(server_permitted(server, rules.allowed, blocked))
+}
+
+/// With an allow list only its servers are permitted, blocked ones never are
+fn server_permitted(server: &ServerName, allowed: &[OwnedServerName], blocked: bool) -> bool {
+	!blocked && (allowed.is_empty() || allowed.iter().any(|s| s == server))
+}
+
+#[cfg(test)]
+mod tests {
+	use conduit::Result;
+	use ruma::{owned_server_name, server_name, ServerName};
+
+	use super::{check_invite, check_server, server_permitted, InviteRules};
+
+	fn not_blocked(_: &ServerName) -> Result<bool> { Ok(false) }
+
+	#[test]
+	fn blocked_servers() {
+		assert!(server_permitted(server_name!("example.org"), &[], false));
+		assert!(!server_permitted(server_name!("spam.example"), &[], true));
+	}
+
+	#[test]
+	fn allow_only_mode() {
+		let allowed = [owned_server_name!("example.org")];
+		assert!(server_permitted(server_name!("example.org"), &allowed, false));
+		assert!(!server_permitted(server_name!("example.com"), &allowed, false));
+		assert!(!server_permitted(server_name!("example.org"), &allowed, true));
+	}
+
+	#[test]
+	fn server_rules_combine() {
+		let allowed = [owned_server_name!("example.org"), owned_server_name!("spam.example")];
+		let blocked = [owned_server_name!("spam.example")];
+		let rules = InviteRules {
+			server_name: server_name!("ours.example"),
+			allowed: &allowed,
+			blocked: &blocked,
+		};
+
+		// Our own users are never refused, nor is the database consulted for them
+		assert!(check_server(&rules, server_name!("ours.example"), |_| unreachable!()).unwrap());
+		assert!(check_server(&rules, server_name!("example.org"), not_blocked).unwrap());
+		assert!(!check_server(&rules, server_name!("example.org"), |_| Ok(true)).unwrap());
+		assert!(!check_server(&rules, server_name!("spam.example"), not_blocked).unwrap());
+		assert!(!check_server(&rules, server_name!("example.com"), not_blocked).unwrap());
+	}
+
+	#[test]
+	fn invite_refusals() {
+		let rules = InviteRules {
+			server_name: server_name!("ours.example"),
+			allowed: &[],
+			blocked: &[],
+		};
+		let server = server_name!("example.org");
+
+		let refusal = check_invite(&rules, server, |_| Ok(true), || unreachable!(), || unreachable!()).unwrap();
+		assert_eq!(refusal, Some("Invites from this server are not accepted."));
+
+		let refusal = check_invite(&rules, server, not_blocked, || Ok(false), || unreachable!()).unwrap();
+		assert_eq!(refusal, None);
+
+		let refusal = check_invite(&rules, server, not_blocked, || Ok(true), || Ok(false)).unwrap();
+		assert_eq!(
+			refusal,
+			Some("This user only accepts invites from users they share a room with.")
+		);
+
+		let refusal = check_invite(&rules, server, not_blocked, || Ok(true), || Ok(true)).unwrap();
+		assert_eq!(refusal, None);
+	}
+}
@@ -1,15 +1,15 @@
 mod data;
+mod invites;
 
 use std::sync::Arc;
 
-use conduit::{error, warn, Error, Result, Server};
+use conduit::{debug_info, error, warn, Error, Result, Server};
 use data::Data;
 use database::Database;
 use itertools::Itertools;
 use ruma::{
 	events::{
 		direct::DirectEvent,
-		ignored_user_list::IgnoredUserListEvent,
 		room::{
 			create::RoomCreateEventContent,
 			member::{MembershipState, RoomMemberEventContent},
@@ -19,7 +19,7 @@ use ruma::{
 	},
 	int,
 	serde::Raw,
-	OwnedRoomId, OwnedServerName, OwnedUserId, RoomId, ServerName, UserId,
+	EventId, OwnedEventId, OwnedRoomId, OwnedServerName, OwnedUserId, RoomId, ServerName, UserId,
 };
 
 use crate::{appservice::RegistrationInfo, services, user_is_local};
@@ -28,6 +28,16 @@ pub struct Service {
 	db: Data,
 }
 
+/// An invite the user rejected while we were not in the room
+#[derive(Debug)]
+pub struct RejectedInvite {
+	/// `origin_server_ts` of the leave event, in milliseconds
+	pub rejected_at: u64,
+
+	/// The invite event, if it was received over federation
+	pub event_id: Option<OwnedEventId>,
+}
+
 impl Service {
 	pub fn build(_server: &Arc<Server>, db: &Arc<Database>) -> Result<Self> {
 		Ok(Self {
@@ -168,43 +178,50 @@ impl Service {
 					}
 				}
 
+				// Accepting an invite to a direct chat makes it one for the invitee too
+				if user_is_local(user_id) {
+					if let Some(inviter) = self.direct_inviter(user_id, room_id)? {
+						if let Err(e) = services()
+							.account_data
+							.add_direct(user_id, &inviter, room_id)
+						{
+							warn!("Failed to add {room_id} to the direct chats of {user_id}: {e}");
+						}
+					}
+				}
+
 				self.db.mark_as_joined(user_id, room_id)?;
+
+				// Remote users are searchable in the user directory while they share a room
+				// with us
+				if !user_is_local(user_id) && !services().users.directory.is_indexed(user_id)? {
+					services().users.directory.index(user_id)?;
+				}
 			},
 			MembershipState::Invite => {
 				// We want to know if the sender is ignored by the receiver
-				let is_ignored = services()
-					.account_data
-					.get(
-						None,    // Ignored users are in global account data
-						user_id, // Receiver
-						GlobalAccountDataEventType::IgnoredUserList
-							.to_string()
-							.into(),
-					)?
-					.map(|event| {
-						serde_json::from_str::<IgnoredUserListEvent>(event.get()).map_err(|e| {
-							warn!("Invalid account data event in db: {e:?}");
-							Error::BadDatabase("Invalid account data event in db.")
-						})
-					})
-					.transpose()?
-					.map_or(false, |ignored| {
-						ignored
-							.content
-							.ignored_users
-							.iter()
-							.any(|(user, _details)| user == sender)
-					});
+				let is_ignored = services().account_data.user_is_ignored(sender, user_id);
 
 				if is_ignored {
 					return Ok(());
 				}
 
+				if user_is_local(user_id) {
+					if let Some(reason) = self.invite_refusal(sender, user_id)? {
+						debug_info!(%sender, %user_id, %room_id, "Not storing refused invite: {reason}");
+						return Ok(());
+					}
+				}
+
 				self.db
 					.mark_as_invited(user_id, room_id, last_state, invite_via)?;
 			},
 			MembershipState::Leave | MembershipState::Ban => {
 				self.db.mark_as_left(user_id, room_id)?;
+
+				if !user_is_local(user_id) && self.rooms_joined(user_id).next().is_none() {
+					services().users.directory.remove(user_id)?;
+				}
 			},
 			_ => {},
 		}
@@ -216,6 +233,24 @@ impl Service {
 		Ok(())
 	}
 
+	/// Sender of the user's pending invite to the room if the invite was for a
+	/// direct chat
+	fn direct_inviter(&self, user_id: &UserId, room_id: &RoomId) -> Result<Option<OwnedUserId>> {
+		Ok(self
+			.invite_state(user_id, room_id)?
+			.unwrap_or_default()
+			.iter()
+			.filter_map(|event| event.deserialize().ok())
+			.find_map(|event| match event {
+				AnyStrippedStateEvent::RoomMember(member)
+					if *member.state_key == *user_id && member.content.is_direct == Some(true) =>
+				{
+					Some(member.sender)
+				},
+				_ => None,
+			}))
+	}
+
 	#[tracing::instrument(skip(self, room_id))]
 	pub fn update_joined_count(&self, room_id: &RoomId) -> Result<()> { self.db.update_joined_count(room_id) }
 
@@ -240,9 +275,60 @@ impl Service {
 		self.db.mark_as_joined(user_id, room_id)
 	}
 
-	/// Makes a user forget a room.
+	/// Makes a user forget a room: it is no longer listed among the rooms
+	/// they left, and their tags, room account data, notification counts and
+	/// receipts in it are deleted. Rejoining starts from a clean slate.
 	#[tracing::instrument(skip(self))]
-	pub fn forget(&self, room_id: &RoomId, user_id: &UserId) -> Result<()> { self.db.forget(room_id, user_id) }
+	pub fn forget(&self, room_id: &RoomId, user_id: &UserId) -> Result<()> {
+		self.db.forget(room_id, user_id)?;
+		services().account_data.remove_room(room_id, user_id)?;
+		services()
+			.rooms
+			.user
+			.remove_notification_counts(user_id, room_id)?;
+		services()
+			.rooms
+			.read_receipt
+			.remove_user_receipts(user_id, room_id)
+	}
+
+	/// Forgets a room the user left with `forget_forced_upon_leave`, called by
+	/// sync once it delivered the leave so clients still learn about it. Rooms
+	/// they are banned from are kept so clients can show the ban.
+	pub fn forget_upon_leave(&self, room_id: &RoomId, user_id: &UserId) -> Result<()> {
+		let membership = services()
+			.rooms
+			.state_accessor
+			.get_member(room_id, user_id)?
+			.map(|member| member.membership);
+
+		if forgotten_upon_leave(services().globals.config.forget_forced_upon_leave, membership.as_ref()) {
+			self.forget(room_id, user_id)?;
+		}
+
+		Ok(())
+	}
+
+	/// Forgets every room the user left, except those they are banned from so
+	/// clients can still show the ban. Returns the number of rooms forgotten.
+	pub fn forget_all_left(&self, user_id: &UserId) -> Result<usize> {
+		let left: Vec<_> = self
+			.rooms_left(user_id)
+			.map(|room| room.map(|(room_id, _)| room_id))
+			.collect::<Result<_>>()?;
+
+		let mut forgotten: usize = 0;
+		for room_id in left {
+			if is_banned(&room_id, user_id)? {
+				continue;
+			}
+
+			self.forget(&room_id, user_id)?;
+			forgotten = forgotten.saturating_add(1);
+		}
+
+		Ok(forgotten)
+	}
 
 	/// Returns an iterator of all servers participating in this room.
 	#[tracing::instrument(skip(self))]
@@ -380,6 +466,37 @@ impl Service {
 		self.db.is_invited(user_id, room_id)
 	}
 
+	/// Remembers the event of the user's invite to the room received over
+	/// federation, to recognize it if it is sent again after a rejection
+	pub fn mark_invite_event(&self, user_id: &UserId, room_id: &RoomId, event_id: &EventId) -> Result<()> {
+		self.db.mark_invite_event(user_id, room_id, event_id)
+	}
+
+	/// Remembers that the user rejected their current invite to the room at
+	/// `rejected_at`, so the same invite sent again is not stored
+	pub fn mark_invite_rejected(&self, user_id: &UserId, room_id: &RoomId, rejected_at: u64) -> Result<()> {
+		let rejected = RejectedInvite {
+			rejected_at,
+			event_id: self.db.invite_event_id(user_id, room_id)?,
+		};
+
+		self.db.mark_invite_rejected(user_id, room_id, &rejected)
+	}
+
+	pub fn rejected_invite(&self, user_id: &UserId, room_id: &RoomId) -> Result<Option<RejectedInvite>> {
+		self.db.rejected_invite(user_id, room_id)
+	}
+
+	/// Whether the invite `event_id` sent at `invite_ts` is one the user
+	/// already rejected, which remote servers may send again
+	pub fn invite_was_rejected(
+		&self, user_id: &UserId, room_id: &RoomId, event_id: &EventId, invite_ts: u64,
+	) -> Result<bool> {
+		Ok(self
+			.rejected_invite(user_id, room_id)?
+			.is_some_and(|rejected| rejected.covers(event_id, invite_ts)))
+	}
+
 	#[tracing::instrument(skip(self))]
 	pub fn is_left(&self, user_id: &UserId, room_id: &RoomId) -> Result<bool> { self.db.is_left(user_id, room_id) }
 
@@ -440,3 +557,103 @@ impl Service {
 		Ok(servers)
 	}
 }
+
+impl RejectedInvite {
+	/// Whether the invite `event_id` sent at `invite_ts` is the rejected one.
+	/// Invites are matched by event ID when it is known, as the other server's
+	/// clock may be skewed against ours; otherwise any invite sent no later
+	/// than the rejection is.
+	#[must_use]
+	pub fn covers(&self, event_id: &EventId, invite_ts: u64) -> bool {
+		match &self.event_id {
+			Some(rejected) => &**rejected == event_id,
+			None => invite_ts <= self.rejected_at,
+		}
+	}
+}
+
+/// Rejected invites to rooms we don't know have no membership
+fn forgotten_upon_leave(enabled: bool, membership: Option<&MembershipState>) -> bool {
+	enabled && matches!(membership, None | Some(MembershipState::Leave))
+}
+
+fn is_banned(room_id: &RoomId, user_id: &UserId) -> Result<bool> {
+	Ok(services()
+		.rooms
+		.state_accessor
+		.get_member(room_id, user_id)?
+		.is_some_and(|member| member.membership == MembershipState::Ban))
+}
+
+#[cfg(test)]
+mod tests {
+	use ruma::{events::room::member::MembershipState, OwnedEventId, RoomVersionId};
+	use serde_json::{json, value::to_raw_value};
+
+	use super::{forgotten_upon_leave, RejectedInvite};
+	use crate::pdu::gen_event_id_canonical_json;
+
+	/// Event ID of an invite sent over federation at `origin_server_ts`
+	fn invite_id(origin_server_ts: u64) -> OwnedEventId {
+		let event = to_raw_value(&json!({
+			"type": "m.room.member",
+			"room_id": "!room:remote.example",
+			"sender": "@alice:remote.example",
+			"state_key": "@bob:ours.example",
+			"origin": "remote.example",
+			"origin_server_ts": origin_server_ts,
+			"content": { "membership": "invite" },
+			"depth": 10,
+			"prev_events": ["$prev"],
+			"auth_events": ["$create", "$power_levels"],
+			"hashes": { "sha256": "" },
+			"signatures": {},
+		}))
+		.expect("event serializes");
+
+		gen_event_id_canonical_json(&event, &RoomVersionId::V10)
+			.expect("event is valid")
+			.0
+	}
+
+	#[test]
+	fn forget_upon_leave() {
+		assert!(forgotten_upon_leave(true, Some(&MembershipState::Leave)));
+		assert!(forgotten_upon_leave(true, None));
+
+		// the ban stays visible
+		assert!(!forgotten_upon_leave(true, Some(&MembershipState::Ban)));
+
+		// rejoined or invited again before the leave was synced
+		assert!(!forgotten_upon_leave(true, Some(&MembershipState::Join)));
+		assert!(!forgotten_upon_leave(true, Some(&MembershipState::Invite)));
+
+		assert!(!forgotten_upon_leave(false, Some(&MembershipState::Leave)));
+	}
+
+	#[test]
+	fn replayed_invite_after_rejection() {
+		let invite_ts = 1_700_000_000_000;
+
+		// the inviting server's clock is ahead of ours
+		let rejected = RejectedInvite {
+			rejected_at: invite_ts - 60_000,
+			event_id: Some(invite_id(invite_ts)),
+		};
+
+		// the same invite sent again
+		assert!(rejected.covers(&invite_id(invite_ts), invite_ts));
+
+		// new invites, even one sent before the rejection by our clock
+		assert!(!rejected.covers(&invite_id(invite_ts + 1), invite_ts + 1));
+		assert!(!rejected.covers(&invite_id(invite_ts - 120_000), invite_ts - 120_000));
+
+		// without the invite's event ID only invites sent after the rejection are new
+		let rejected = RejectedInvite {
+			rejected_at: invite_ts + 60_000,
+			event_id: None,
+		};
+		assert!(rejected.covers(&invite_id(invite_ts), invite_ts));
+		assert!(!rejected.covers(&invite_id(invite_ts + 120_000), invite_ts + 120_000));
+	}
+}
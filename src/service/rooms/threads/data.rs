@@ -25,8 +25,7 @@ impl Data {
 		let prefix = services()
 			.rooms
 			.short
-			.get_shortroomid(room_id)?
-			.expect("room exists")
+			.get_existing_shortroomid(room_id)?
 			.to_be_bytes()
 			.to_vec();
 
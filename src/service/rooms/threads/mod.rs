@@ -1,27 +1,70 @@
 mod data;
 
-use std::{collections::BTreeMap, sync::Arc};
+use std::{
+	collections::HashMap,
+	sync::{Arc, Mutex},
+};
 
 use conduit::{Error, Result, Server};
 use data::Data;
 use database::Database;
 use ruma::{
 	api::client::{error::ErrorKind, threads::get_threads::v1::IncludeThreads},
-	events::relation::BundledThread,
-	uint, CanonicalJsonValue, EventId, RoomId, UserId,
+	events::{relation::RelationType, AnySyncTimelineEvent},
+	serde::Raw,
+	EventId, OwnedEventId, OwnedUserId, RoomId, UserId,
 };
-use serde_json::json;
+use serde::{Deserialize, Serialize};
 
 use crate::{services, PduEvent};
 
+/// Number of cached thread summaries above which the cache is cleared
+const SUMMARY_CACHE_CAPACITY: usize = 10_000;
+
 pub struct Service {
 	db: Data,
+
+	/// Replies and participants of thread roots, by root event ID. Entries are
+	/// removed when a reply is added to or redacted from the thread.
+	summary_cache: Mutex<HashMap<OwnedEventId, Arc<CachedThread>>>,
+}
+
+/// The `m.thread` bundled aggregation of a thread root, as seen by one user
+#[derive(Clone, Debug, Serialize)]
+pub struct ThreadSummary {
+	/// The latest reply the user is allowed to see
+	pub latest_event: Raw<AnySyncTimelineEvent>,
+
+	/// Number of replies which are not redacted
+	pub count: usize,
+
+	/// Whether the user sent the root or one of the replies
+	pub current_user_participated: bool,
+}
+
+struct CachedThread {
+	/// Event IDs of the replies which are not redacted, latest first
+	replies: Vec<OwnedEventId>,
+	participants: Vec<OwnedUserId>,
+}
+
+#[derive(Deserialize)]
+struct ExtractThreadRelation {
+	rel_type: RelationType,
+	event_id: OwnedEventId,
+}
+
+#[derive(Deserialize)]
+struct ExtractRelatesTo {
+	#[serde(rename = "m.relates_to")]
+	relates_to: ExtractThreadRelation,
 }
 
 impl Service {
 	pub fn build(_server: &Arc<Server>, db: &Arc<Database>) -> Result<Self> {
 		Ok(Self {
 			db: Data::new(db),
+			summary_cache: Mutex::new(HashMap::new()),
 		})
 	}
 
@@ -32,78 +75,145 @@ impl Service {
 	}
 
 	pub fn add_to_thread(&self, root_event_id: &EventId, pdu: &PduEvent) -> Result<()> {
-		let root_id = &services()
+		let (root_id, root_pdu) = services()
 			.rooms
 			.timeline
-			.get_pdu_id(root_event_id)?
+			.get_pdu_with_id(root_event_id)?
 			.ok_or_else(|| Error::BadRequest(ErrorKind::InvalidParam, "Invalid event id in thread message"))?;
 
-		let root_pdu = services()
-			.rooms
-			.timeline
-			.get_pdu_from_id(root_id)?
-			.ok_or_else(|| Error::BadRequest(ErrorKind::InvalidParam, "Thread root pdu not found"))?;
+		let mut users = self
+			.db
+			.get_participants(&root_id)?
+			.unwrap_or_else(|| vec![root_pdu.sender]);
+		if !users.contains(&pdu.sender) {
+			users.push(pdu.sender.clone());
+		}
 
-		let mut root_pdu_json = services()
-			.rooms
-			.timeline
-			.get_pdu_json_from_id(root_id)?
-			.ok_or_else(|| Error::BadRequest(ErrorKind::InvalidParam, "Thread root pdu not found"))?;
+		self.db.update_participants(&root_id, &users)?;
+		self.invalidate_summary(root_event_id);
 
-		if let CanonicalJsonValue::Object(unsigned) = root_pdu_json
-			.entry("unsigned".to_owned())
-			.or_insert_with(|| CanonicalJsonValue::Object(BTreeMap::default()))
-		{
-			if let Some(mut relations) = unsigned
-				.get("m.relations")
-				.and_then(|r| r.as_object())
-				.and_then(|r| r.get("m.thread"))
-				.and_then(|relations| serde_json::from_value::<BundledThread>(relations.clone().into()).ok())
-			{
-				// Thread already existed
-				relations.count += uint!(1);
-				relations.latest_event = pdu.to_message_like_event();
-
-				let content = serde_json::to_value(relations).expect("to_value always works");
-
-				unsigned.insert(
-					"m.relations".to_owned(),
-					json!({ "m.thread": content })
-						.try_into()
-						.expect("thread is valid json"),
-				);
-			} else {
-				// New thread
-				let relations = BundledThread {
-					latest_event: pdu.to_message_like_event(),
-					count: uint!(1),
-					current_user_participated: true,
-				};
-
-				let content = serde_json::to_value(relations).expect("to_value always works");
-
-				unsigned.insert(
-					"m.relations".to_owned(),
-					json!({ "m.thread": content })
-						.try_into()
-						.expect("thread is valid json"),
-				);
+		Ok(())
+	}
+
+	/// The `m.thread` bundled aggregation of `root_event_id` for `user_id`, or
+	/// `None` if it is not a thread root or the user can see none of its
+	/// replies.
+	pub fn get_thread_summary(&self, root_event_id: &EventId, user_id: &UserId) -> Result<Option<ThreadSummary>> {
+		let Some(thread) = self.cached_thread(root_event_id)? else {
+			return Ok(None);
+		};
+
+		let Some(mut latest) = self.latest_visible_reply(&thread, user_id)? else {
+			return Ok(None);
+		};
+
+		if latest.sender != user_id {
+			latest.remove_transaction_id()?;
+		}
+
+		Ok(Some(ThreadSummary {
+			latest_event: latest.to_sync_room_event(),
+			count: thread.replies.len(),
+			current_user_participated: thread.participants.iter().any(|user| user == user_id),
+		}))
+	}
+
+	/// Sets the `m.thread` bundled aggregation of `pdu` for `user_id` in its
+	/// unsigned data if it is a thread root, and removes any other otherwise,
+	/// as earlier versions stored one with the root for every user.
+	pub fn add_bundled_aggregation(&self, user_id: &UserId, pdu: &mut PduEvent) -> Result<()> {
+		match self.get_thread_summary(&pdu.event_id, user_id)? {
+			Some(summary) => pdu.add_relation("m.thread", &summary),
+			None => pdu.remove_relation("m.thread"),
+		}
+	}
+
+	/// Forgets the cached summary of the thread `pdu` replies to, if any.
+	/// Called before `pdu` is redacted.
+	pub fn remove_from_thread(&self, pdu: &PduEvent) {
+		if let Ok(content) = serde_json::from_str::<ExtractRelatesTo>(pdu.content.get()) {
+			if content.relates_to.rel_type == RelationType::Thread {
+				self.invalidate_summary(&content.relates_to.event_id);
 			}
+		}
+	}
 
-			services()
-				.rooms
-				.timeline
-				.replace_pdu(root_id, &root_pdu_json, &root_pdu)?;
+	fn invalidate_summary(&self, root_event_id: &EventId) {
+		self.summary_cache
+			.lock()
+			.expect("locked")
+			.remove(root_event_id);
+	}
+
+	fn cached_thread(&self, root_event_id: &EventId) -> Result<Option<Arc<CachedThread>>> {
+		if let Some(thread) = self
+			.summary_cache
+			.lock()
+			.expect("locked")
+			.get(root_event_id)
+		{
+			return Ok(Some(Arc::clone(thread)));
 		}
 
-		let mut users = Vec::new();
-		if let Some(userids) = self.db.get_participants(root_id)? {
-			users.extend_from_slice(&userids);
-		} else {
-			users.push(root_pdu.sender);
+		let Some(root_id) = services().rooms.timeline.get_pdu_id(root_event_id)? else {
+			return Ok(None);
+		};
+
+		let Some(participants) = self.db.get_participants(&root_id)? else {
+			return Ok(None);
+		};
+
+		// looked up on its own rather than with `get_pdu_with_id`, which errors
+		// when the root is missing and would fail the whole bundling request
+		let Some(root_pdu) = services().rooms.timeline.get_pdu_from_id(&root_id)? else {
+			return Ok(None);
+		};
+
+		let replies = services()
+			.rooms
+			.pdu_metadata
+			.relations(&root_pdu.sender, &root_pdu.room_id, root_event_id, 1)?
+			.into_iter()
+			.rev()
+			.filter(|(_, pdu)| !pdu.is_redacted())
+			.filter(|(_, pdu)| {
+				serde_json::from_str::<ExtractRelatesTo>(pdu.content.get()).is_ok_and(|content| {
+					content.relates_to.rel_type == RelationType::Thread
+						&& *content.relates_to.event_id == *root_event_id
+				})
+			})
+			.map(|(_, pdu)| (*pdu.event_id).to_owned())
+			.collect();
+
+		let thread = Arc::new(CachedThread {
+			replies,
+			participants,
+		});
+
+		let mut cache = self.summary_cache.lock().expect("locked");
+		if cache.len() >= SUMMARY_CACHE_CAPACITY {
+			cache.clear();
+		}
+		cache.insert(root_event_id.to_owned(), Arc::clone(&thread));
+
+		Ok(Some(thread))
+	}
+
+	fn latest_visible_reply(&self, thread: &CachedThread, user_id: &UserId) -> Result<Option<PduEvent>> {
+		for event_id in &thread.replies {
+			let Some(pdu) = services().rooms.timeline.get_pdu(event_id)? else {
+				continue;
+			};
+
+			if services()
+				.rooms
+				.state_accessor
+				.user_can_see_event(user_id, &pdu.room_id, &pdu.event_id)?
+			{
+				return Ok(Some((*pdu).clone()));
+			}
 		}
-		users.push(pdu.sender.clone());
 
-		self.db.update_participants(root_id, &users)
+		Ok(None)
 	}
 }
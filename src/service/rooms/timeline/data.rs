@@ -2,7 +2,7 @@ use std::{collections::hash_map, mem::size_of, sync::Arc};
 
 use conduit::{error, utils, Error, Result};
 use database::{Database, Map};
-use ruma::{api::client::error::ErrorKind, CanonicalJsonObject, EventId, OwnedUserId, RoomId, UserId};
+use ruma::{api::client::error::ErrorKind, CanonicalJsonObject, EventId, OwnedEventId, OwnedUserId, RoomId, UserId};
 
 use crate::{services, PduCount, PduEvent};
 
@@ -10,6 +10,7 @@ pub(super) struct Data {
 	eventid_pduid: Arc<Map>,
 	pduid_pdu: Arc<Map>,
 	eventid_outlierpdu: Arc<Map>,
+	eventid_redactionid: Arc<Map>,
 	userroomid_notificationcount: Arc<Map>,
 	userroomid_highlightcount: Arc<Map>,
 	db: Arc<Database>,
@@ -24,6 +25,7 @@ impl Data {
 			eventid_pduid: db["eventid_pduid"].clone(),
 			pduid_pdu: db["pduid_pdu"].clone(),
 			eventid_outlierpdu: db["eventid_outlierpdu"].clone(),
+			eventid_redactionid: db["eventid_redactionid"].clone(),
 			userroomid_notificationcount: db["userroomid_notificationcount"].clone(),
 			userroomid_highlightcount: db["userroomid_highlightcount"].clone(),
 			db: db.clone(),
@@ -97,6 +99,36 @@ impl Data {
 		self.eventid_pduid.get(event_id.as_bytes())
 	}
 
+	/// Returns the pdu's id along with the pdu.
+	///
+	/// This does __NOT__ check the outliers `Tree`.
+	pub(super) fn get_pdu_with_id(&self, event_id: &EventId) -> Result<Option<(Vec<u8>, PduEvent)>> {
+		let Some(pdu_id) = self.get_pdu_id(event_id)? else {
+			return Ok(None);
+		};
+
+		let pdu = self
+			.get_pdu_from_id(&pdu_id)?
+			.ok_or_else(|| Error::bad_database("Invalid pduid in eventid_pduid."))?;
+
+		Ok(Some((pdu_id, pdu)))
+	}
+
+	/// Returns the pdu's id along with the json of the pdu.
+	///
+	/// This does __NOT__ check the outliers `Tree`.
+	pub(super) fn get_pdu_json_with_id(&self, event_id: &EventId) -> Result<Option<(Vec<u8>, CanonicalJsonObject)>> {
+		let Some(pdu_id) = self.get_pdu_id(event_id)? else {
+			return Ok(None);
+		};
+
+		let pdu_json = self
+			.get_pdu_json_from_id(&pdu_id)?
+			.ok_or_else(|| Error::bad_database("Invalid pduid in eventid_pduid."))?;
+
+		Ok(Some((pdu_id, pdu_json)))
+	}
+
 	/// Returns the pdu.
 	pub(super) fn get_non_outlier_pdu(&self, event_id: &EventId) -> Result<Option<PduEvent>> {
 		self.eventid_pduid
@@ -202,6 +234,26 @@ impl Data {
 		Ok(())
 	}
 
+	/// Records the redaction of an event, which may not have arrived yet. It
+	/// is kept per room, so only events of the redaction's room find it.
+	pub(super) fn set_redaction(&self, shortroomid: u64, event_id: &EventId, redaction_id: &EventId) -> Result<()> {
+		self.eventid_redactionid
+			.insert(&redaction_key(shortroomid, event_id), redaction_id.as_bytes())
+	}
+
+	pub(super) fn get_redaction(&self, shortroomid: u64, event_id: &EventId) -> Result<Option<OwnedEventId>> {
+		self.eventid_redactionid
+			.get(&redaction_key(shortroomid, event_id))?
+			.map(|redaction_id| {
+				EventId::parse(
+					utils::string_from_bytes(&redaction_id)
+						.map_err(|_| Error::bad_database("Invalid redaction event ID bytes in eventid_redactionid."))?,
+				)
+				.map_err(|_| Error::bad_database("Invalid redaction event ID in eventid_redactionid."))
+			})
+			.transpose()
+	}
+
 	/// Returns an iterator over all events and their tokens in a room that
 	/// happened before the event with id `until` in reverse-chronological
 	/// order.
@@ -323,3 +375,11 @@ pub(super) fn count_to_id(
 
 	Ok((prefix, pdu_id))
 }
+
+/// Key of `eventid_redactionid`: the room of the redaction, then the event it
+/// redacts
+pub(super) fn redaction_key(shortroomid: u64, event_id: &EventId) -> Vec<u8> {
+	let mut key = shortroomid.to_be_bytes().to_vec();
+	key.extend_from_slice(event_id.as_bytes());
+	key
+}
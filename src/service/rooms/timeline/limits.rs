@@ -0,0 +1,235 @@
+use std::collections::BTreeMap;
+
+use conduit::{Error, Result};
+use ruma::{api::client::error::ErrorKind, CanonicalJsonObject, OwnedEventId};
+use serde::Deserialize;
+use serde_json::value::RawValue as RawJsonValue;
+
+/// Largest PDU in canonical JSON the spec allows, in bytes
+const MAX_PDU_SIZE: usize = 65_536;
+
+/// Longest event type and state key the spec allows, in bytes
+const MAX_TYPE_LENGTH: usize = 255;
+const MAX_STATE_KEY_LENGTH: usize = 255;
+
+/// Most prev_events and auth_events other servers accept
+const MAX_PREV_EVENTS: usize = 20;
+const MAX_AUTH_EVENTS: usize = 10;
+
+/// Largest unsigned data of a new PDU in bytes. The `prev_content` copied
+/// from the replaced state event is left out when it would be larger.
+const MAX_UNSIGNED_SIZE: usize = 16 * 1024;
+
+#[derive(Deserialize)]
+struct ExtractRelatesTo {
+	#[serde(rename = "m.relates_to")]
+	relates_to: Option<ExtractRelatedEventId>,
+}
+
+#[derive(Deserialize)]
+struct ExtractRelatedEventId {
+	event_id: Option<OwnedEventId>,
+}
+
+/// Checks the parts of a new event which are known before any state is read
+pub(super) fn check_builder(event_type: &str, state_key: Option<&str>, content: &RawJsonValue) -> Result<()> {
+	if event_type.len() > MAX_TYPE_LENGTH {
+		return Err(Error::BadRequest(ErrorKind::BadJson, "Event type is longer than 255 bytes."));
+	}
+
+	if state_key.is_some_and(|state_key| state_key.len() > MAX_STATE_KEY_LENGTH) {
+		return Err(Error::BadRequest(ErrorKind::BadJson, "State key is longer than 255 bytes."));
+	}
+
+	if content.get().len() > MAX_PDU_SIZE {
+		return Err(Error::BadRequest(
+			ErrorKind::TooLarge,
+			"Event content is larger than the maximum event size of 65536 bytes.",
+		));
+	}
+
+	Ok(())
+}
+
+pub(super) fn check_references(prev_events: usize, auth_events: usize) -> Result<()> {
+	if prev_events > MAX_PREV_EVENTS {
+		return Err(Error::BadRequest(ErrorKind::BadJson, "Event has more than 20 prev_events."));
+	}
+
+	if auth_events > MAX_AUTH_EVENTS {
+		return Err(Error::BadRequest(ErrorKind::BadJson, "Event has more than 10 auth_events."));
+	}
+
+	Ok(())
+}
+
+/// Checks the size of the unsigned PDU; signing adds little to it
+pub(super) fn check_size(pdu_json: &CanonicalJsonObject) -> Result<()> {
+	let size = serde_json::to_vec(pdu_json)
+		.expect("canonical json is valid json")
+		.len();
+
+	if size > MAX_PDU_SIZE {
+		return Err(Error::BadRequest(
+			ErrorKind::TooLarge,
+			"Event is larger than the maximum event size of 65536 bytes.",
+		));
+	}
+
+	Ok(())
+}
+
+/// Leaves `prev_content` out of the unsigned data if it would be too large,
+/// failing if it still is
+pub(super) fn cap_unsigned(unsigned: &mut BTreeMap<String, serde_json::Value>) -> Result<()> {
+	let size = |unsigned: &BTreeMap<String, serde_json::Value>| {
+		serde_json::to_vec(unsigned)
+			.expect("unsigned is valid json")
+			.len()
+	};
+
+	if size(unsigned) <= MAX_UNSIGNED_SIZE {
+		return Ok(());
+	}
+
+	unsigned.remove("prev_content");
+	if size(unsigned) > MAX_UNSIGNED_SIZE {
+		return Err(Error::BadRequest(
+			ErrorKind::TooLarge,
+			"Unsigned data of the event is larger than 16384 bytes.",
+		));
+	}
+
+	Ok(())
+}
+
+/// Checks that following `m.relates_to` from the content takes at most
+/// `max_depth` steps. `related` returns the content of an event we have.
+pub(super) fn check_relation_depth<F>(content: &RawJsonValue, max_depth: usize, related: F) -> Result<()>
+where
+	F: Fn(&OwnedEventId) -> Option<Box<RawJsonValue>>,
+{
+	let mut event_id = related_event_id(content);
+	let mut depth: usize = 0;
+	while let Some(current) = event_id {
+		depth = depth.saturating_add(1);
+		if depth > max_depth {
+			return Err(Error::BadRequest(
+				ErrorKind::BadJson,
+				"Event relates to a chain of related events deeper than max_relation_depth.",
+			));
+		}
+
+		event_id = related(&current).and_then(|content| related_event_id(&content));
+	}
+
+	Ok(())
+}
+
+fn related_event_id(content: &RawJsonValue) -> Option<OwnedEventId> {
+	serde_json::from_str::<ExtractRelatesTo>(content.get())
+		.ok()?
+		.relates_to?
+		.event_id
+}
+
+#[cfg(test)]
+mod tests {
+	use std::collections::{BTreeMap, HashMap};
+
+	use ruma::{api::client::error::ErrorKind, owned_event_id, CanonicalJsonObject, CanonicalJsonValue};
+	use serde_json::{json, value::to_raw_value};
+
+	use super::*;
+
+	fn kind(result: Result<()>) -> Option<ErrorKind> {
+		match result {
+			Ok(()) => None,
+			Err(Error::BadRequest(kind, _)) => Some(kind),
+			Err(e) => panic!("unexpected error {e}"),
+		}
+	}
+
+	#[test]
+	fn type_and_state_key_lengths() {
+		let content = to_raw_value(&json!({})).unwrap();
+		let at_limit = "a".repeat(MAX_TYPE_LENGTH);
+		let over_limit = "a".repeat(MAX_TYPE_LENGTH + 1);
+
+		assert_eq!(kind(check_builder(&at_limit, Some(&at_limit), &content)), None);
+		assert_eq!(kind(check_builder(&over_limit, None, &content)), Some(ErrorKind::BadJson));
+		assert_eq!(
+			kind(check_builder("m.room.topic", Some(&over_limit), &content)),
+			Some(ErrorKind::BadJson)
+		);
+	}
+
+	#[test]
+	fn content_size() {
+		// `{"a":""}` is 8 bytes
+		let at_limit = to_raw_value(&json!({ "a": "a".repeat(MAX_PDU_SIZE - 8) })).unwrap();
+		let over_limit = to_raw_value(&json!({ "a": "a".repeat(MAX_PDU_SIZE - 7) })).unwrap();
+
+		assert_eq!(kind(check_builder("m.room.message", None, &at_limit)), None);
+		assert_eq!(
+			kind(check_builder("m.room.message", None, &over_limit)),
+			Some(ErrorKind::TooLarge)
+		);
+	}
+
+	#[test]
+	fn reference_counts() {
+		assert_eq!(kind(check_references(MAX_PREV_EVENTS, MAX_AUTH_EVENTS)), None);
+		assert_eq!(kind(check_references(MAX_PREV_EVENTS + 1, 0)), Some(ErrorKind::BadJson));
+		assert_eq!(kind(check_references(1, MAX_AUTH_EVENTS + 1)), Some(ErrorKind::BadJson));
+	}
+
+	#[test]
+	fn pdu_size() {
+		let pdu = |len: usize| {
+			let mut pdu = CanonicalJsonObject::new();
+			pdu.insert("a".to_owned(), CanonicalJsonValue::String("a".repeat(len)));
+			pdu
+		};
+
+		// `{"a":""}` is 8 bytes
+		assert_eq!(kind(check_size(&pdu(MAX_PDU_SIZE - 8))), None);
+		assert_eq!(kind(check_size(&pdu(MAX_PDU_SIZE - 7))), Some(ErrorKind::TooLarge));
+	}
+
+	#[test]
+	fn unsigned_size() {
+		let mut unsigned = BTreeMap::from([
+			("prev_content".to_owned(), json!({ "a": "a".repeat(MAX_UNSIGNED_SIZE) })),
+			("prev_sender".to_owned(), json!("@alice:example.com")),
+		]);
+		assert_eq!(kind(cap_unsigned(&mut unsigned)), None);
+		assert!(!unsigned.contains_key("prev_content"));
+		assert!(unsigned.contains_key("prev_sender"));
+
+		// `{"a":""}` is 8 bytes
+		let mut at_limit = BTreeMap::from([("a".to_owned(), json!("a".repeat(MAX_UNSIGNED_SIZE - 8)))]);
+		assert_eq!(kind(cap_unsigned(&mut at_limit)), None);
+		assert!(at_limit.contains_key("a"));
+
+		let mut over_limit = BTreeMap::from([("a".to_owned(), json!("a".repeat(MAX_UNSIGNED_SIZE - 7)))]);
+		assert_eq!(kind(cap_unsigned(&mut over_limit)), Some(ErrorKind::TooLarge));
+	}
+
+	#[test]
+	fn relation_depth() {
+		let relates_to = |event_id: &str| to_raw_value(&json!({ "m.relates_to": { "event_id": event_id } })).unwrap();
+		let events = HashMap::from([
+			(owned_event_id!("$a"), relates_to("$b")),
+			(owned_event_id!("$b"), to_raw_value(&json!({})).unwrap()),
+		]);
+		let related = |event_id: &OwnedEventId| events.get(event_id).cloned();
+
+		let content = relates_to("$a");
+		assert_eq!(kind(check_relation_depth(&content, 2, related)), None);
+		assert_eq!(kind(check_relation_depth(&content, 1, related)), Some(ErrorKind::BadJson));
+
+		let unrelated = to_raw_value(&json!({ "body": "hi" })).unwrap();
+		assert_eq!(kind(check_relation_depth(&unrelated, 0, related)), None);
+	}
+}
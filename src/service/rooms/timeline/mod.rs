@@ -1,8 +1,10 @@
 mod data;
+mod limits;
 
 use std::{
 	collections::{BTreeMap, HashMap, HashSet},
 	sync::Arc,
+	time::{Duration, Instant},
 };
 
 use conduit::{debug, error, info, utils, utils::mutex_map, warn, Error, Result, Server};
@@ -19,16 +21,17 @@ use ruma::{
 			create::RoomCreateEventContent,
 			encrypted::Relation,
 			member::{MembershipState, RoomMemberEventContent},
-			power_levels::RoomPowerLevelsEventContent,
+			message::RoomMessageEventContent,
+			power_levels::{RoomPowerLevels, RoomPowerLevelsEventContent},
 			redaction::RoomRedactionEventContent,
 		},
 		GlobalAccountDataEventType, StateEventType, TimelineEventType,
 	},
 	push::{Action, Ruleset, Tweak},
 	serde::Base64,
-	state_res::{self, Event, RoomVersion},
-	uint, user_id, CanonicalJsonObject, CanonicalJsonValue, EventId, OwnedEventId, OwnedRoomId, OwnedServerName,
-	RoomId, RoomVersionId, ServerName, UserId,
+	state_res::{self, Event, RoomVersion, StateMap},
+	uint, user_id, CanonicalJsonObject, CanonicalJsonValue, EventId, MilliSecondsSinceUnixEpoch, OwnedEventId,
+	OwnedRoomId, OwnedServerName, RoomId, RoomVersionId, ServerName, UserId,
 };
 use serde::Deserialize;
 use serde_json::value::{to_raw_value, RawValue as RawJsonValue};
@@ -68,6 +71,10 @@ pub struct Service {
 	db: Data,
 
 	pub lasttimelinecount_cache: Mutex<HashMap<OwnedRoomId, PduCount>>,
+
+	/// When a dummy event was last sent to collapse the forward extremities of
+	/// each room
+	last_dummy_event: Mutex<HashMap<OwnedRoomId, Instant>>,
 }
 
 impl Service {
@@ -75,6 +82,7 @@ impl Service {
 		Ok(Self {
 			db: Data::new(db),
 			lasttimelinecount_cache: Mutex::new(HashMap::new()),
+			last_dummy_event: Mutex::new(HashMap::new()),
 		})
 	}
 
@@ -161,6 +169,21 @@ impl Service {
 	/// Returns the pdu's id.
 	pub fn get_pdu_id(&self, event_id: &EventId) -> Result<Option<Vec<u8>>> { self.db.get_pdu_id(event_id) }
 
+	/// Returns the pdu's id along with the pdu, for callers which need both
+	/// without looking the event up twice.
+	///
+	/// This does __NOT__ check the outliers `Tree`.
+	pub fn get_pdu_with_id(&self, event_id: &EventId) -> Result<Option<(Vec<u8>, PduEvent)>> {
+		self.db.get_pdu_with_id(event_id)
+	}
+
+	/// Returns the pdu's id along with the json of the pdu.
+	///
+	/// This does __NOT__ check the outliers `Tree`.
+	pub fn get_pdu_json_with_id(&self, event_id: &EventId) -> Result<Option<(Vec<u8>, CanonicalJsonObject)>> {
+		self.db.get_pdu_json_with_id(event_id)
+	}
+
 	/// Returns the pdu.
 	///
 	/// Checks the `eventid_outlierpdu` Tree if not found in the timeline.
@@ -188,8 +211,17 @@ impl Service {
 
 	/// Removes a pdu and creates a new one with the same id.
 	#[tracing::instrument(skip(self))]
+	///
+	/// The `redacted_because` of a redacted event is kept, so clients can still
+	/// see who redacted it and why.
 	pub fn replace_pdu(&self, pdu_id: &[u8], pdu_json: &CanonicalJsonObject, pdu: &PduEvent) -> Result<()> {
-		self.db.replace_pdu(pdu_id, pdu_json, pdu)
+		let Some(old) = self.get_pdu_json_from_id(pdu_id)? else {
+			return self.db.replace_pdu(pdu_id, pdu_json, pdu);
+		};
+
+		let mut pdu_json = pdu_json.clone();
+		keep_redacted_because(&old, &mut pdu_json);
+		self.db.replace_pdu(pdu_id, &pdu_json, pdu)
 	}
 
 	/// Creates a new persisted data unit and adds it to a room.
@@ -212,8 +244,7 @@ impl Service {
 		let shortroomid = services()
 			.rooms
 			.short
-			.get_shortroomid(&pdu.room_id)?
-			.expect("room exists");
+			.get_existing_shortroomid(&pdu.room_id)?;
 
 		// Make unsigned fields correct. This is not properly documented in the spec,
 		// but state events need to have previous content in the unsigned field, so
@@ -285,7 +316,11 @@ impl Service {
 		services()
 			.rooms
 			.user
-			.reset_notification_counts(&pdu.sender, &pdu.room_id)?;
+			.reset_notification_counts(&pdu.sender, &pdu.room_id, PduCount::Normal(count1))?;
+		services()
+			.rooms
+			.user
+			.mark_notifications_read(&pdu.sender, &pdu.room_id, count1)?;
 
 		let count2 = services().globals.next_count()?;
 		let mut pdu_id = shortroomid.to_be_bytes().to_vec();
@@ -294,19 +329,13 @@ impl Service {
 		// Insert pdu
 		self.db.append_pdu(&pdu_id, pdu, &pdu_json, count2)?;
 
+		// the redaction of the event may have arrived before it
+		self.apply_pending_redaction(pdu, shortroomid)?;
+
 		drop(insert_lock);
 
 		// See if the event matches any known pushers
-		let power_levels: RoomPowerLevelsEventContent = services()
-			.rooms
-			.state_accessor
-			.room_state_get(&pdu.room_id, &StateEventType::RoomPowerLevels, "")?
-			.map(|ev| {
-				serde_json::from_str(ev.content.get())
-					.map_err(|_| Error::bad_database("invalid m.room.power_levels event"))
-			})
-			.transpose()?
-			.unwrap_or_default();
+		let power_levels = services().pusher.power_levels(&pdu.room_id)?;
 
 		let sync_pdu = pdu.to_sync_room_event();
 
@@ -335,6 +364,11 @@ impl Service {
 				continue;
 			}
 
+			// Don't notify the user of events from users they ignore
+			if services().account_data.user_is_ignored(&pdu.sender, user) {
+				continue;
+			}
+
 			let rules_for_user = services()
 				.account_data
 				.get(None, user, GlobalAccountDataEventType::PushRules.to_string().into())?
@@ -347,22 +381,14 @@ impl Service {
 				.transpose()?
 				.map_or_else(|| Ruleset::server_default(user), |ev: PushRulesEvent| ev.content.global);
 
-			let mut highlight = false;
-			let mut notify = false;
-
-			for action in
+			let actions =
 				services()
 					.pusher
-					.get_actions(user, &rules_for_user, &power_levels, &sync_pdu, &pdu.room_id)?
-			{
-				match action {
-					Action::Notify => notify = true,
-					Action::SetTweak(Tweak::Highlight(true)) => {
-						highlight = true;
-					},
-					_ => {},
-				};
-			}
+					.get_actions(user, &rules_for_user, &power_levels, &sync_pdu, &pdu.room_id)?;
+
+			// Rooms muted by the push rules neither count towards notifications nor
+			// trigger pushes, unless another rule such as a mention notifies
+			let (notify, highlight) = notify_and_highlight(actions);
 
 			if notify {
 				notifies.push(user.clone());
@@ -372,8 +398,22 @@ impl Service {
 				highlights.push(user.clone());
 			}
 
+			if !notify {
+				continue;
+			}
+
+			services()
+				.rooms
+				.user
+				.add_notification(user, pdu, count2, actions, highlight)?;
+
 			for push_key in services().pusher.get_pushkeys(user) {
-				services().sending.send_pdu_push(&pdu_id, user, push_key?)?;
+				let push_key = push_key?;
+				if services().pusher.is_disabled(user, &push_key)? {
+					continue;
+				}
+
+				services().sending.send_pdu_push(&pdu_id, user, push_key)?;
 			}
 		}
 
@@ -381,57 +421,7 @@ impl Service {
 			.increment_notification_counts(&pdu.room_id, notifies, highlights)?;
 
 		match pdu.kind {
-			TimelineEventType::RoomRedaction => {
-				let room_version_id = services().rooms.state.get_room_version(&pdu.room_id)?;
-				match room_version_id {
-					RoomVersionId::V1
-					| RoomVersionId::V2
-					| RoomVersionId::V3
-					| RoomVersionId::V4
-					| RoomVersionId::V5
-					| RoomVersionId::V6
-					| RoomVersionId::V7
-					| RoomVersionId::V8
-					| RoomVersionId::V9
-					| RoomVersionId::V10 => {
-						if let Some(redact_id) = &pdu.redacts {
-							if services().rooms.state_accessor.user_can_redact(
-								redact_id,
-								&pdu.sender,
-								&pdu.room_id,
-								false,
-							)? {
-								self.redact_pdu(redact_id, pdu, shortroomid)?;
-							}
-						}
-					},
-					RoomVersionId::V11 => {
-						let content =
-							serde_json::from_str::<RoomRedactionEventContent>(pdu.content.get()).map_err(|e| {
-								warn!("Invalid content in redaction pdu: {e}");
-								Error::bad_database("Invalid content in redaction pdu")
-							})?;
-
-						if let Some(redact_id) = &content.redacts {
-							if services().rooms.state_accessor.user_can_redact(
-								redact_id,
-								&pdu.sender,
-								&pdu.room_id,
-								false,
-							)? {
-								self.redact_pdu(redact_id, pdu, shortroomid)?;
-							}
-						}
-					},
-					_ => {
-						warn!("Unexpected or unsupported room version {room_version_id}");
-						return Err(Error::BadRequest(
-							ErrorKind::BadJson,
-							"Unexpected or unsupported room version found",
-						));
-					},
-				};
-			},
+			TimelineEventType::RoomRedaction => self.apply_redaction(pdu, shortroomid)?,
 			TimelineEventType::SpaceChild => {
 				if let Some(_state_key) = &pdu.state_key {
 					services()
@@ -443,6 +433,19 @@ impl Service {
 						.remove(&pdu.room_id);
 				}
 			},
+			TimelineEventType::RoomCreate => {
+				if let Ok(content) = serde_json::from_str::<RoomCreateEventContent>(pdu.content.get()) {
+					services()
+						.rooms
+						.directory
+						.update_room_type(&pdu.room_id, content.room_type.as_ref())?;
+				}
+			},
+			TimelineEventType::RoomName | TimelineEventType::RoomTopic | TimelineEventType::RoomCanonicalAlias => {
+				if pdu.state_key.as_deref() == Some("") {
+					services().rooms.directory.update_index(&pdu.room_id)?;
+				}
+			},
 			TimelineEventType::RoomMember => {
 				if let Some(state_key) = &pdu.state_key {
 					// if the state_key fails
@@ -488,7 +491,7 @@ impl Service {
 					if admin::is_admin_command(pdu, &body).await {
 						services()
 							.admin
-							.command(body, Some((*pdu.event_id).into()))
+							.command(body, Some((*pdu.event_id).into()), Some(pdu.sender.clone()))
 							.await;
 					}
 				}
@@ -595,6 +598,36 @@ impl Service {
 		sender: &UserId,
 		room_id: &RoomId,
 		_mutex_lock: &mutex_map::Guard<()>, // Take mutex guard to make sure users get the room state mutex
+	) -> Result<(PduEvent, CanonicalJsonObject)> {
+		self.hash_and_sign_event(pdu_builder, sender, room_id, None)
+	}
+
+	/// Like `create_hash_and_sign_event`, but the event carries the given
+	/// historical `origin_server_ts`, follows `prev_event` instead of the
+	/// forward extremities and is authorized against the current room state
+	/// with `state_overlay` applied on top of it.
+	#[allow(clippy::too_many_arguments)]
+	pub fn create_hash_and_sign_historical_event(
+		&self,
+		pdu_builder: PduBuilder,
+		sender: &UserId,
+		room_id: &RoomId,
+		origin_server_ts: MilliSecondsSinceUnixEpoch,
+		prev_event: &PduEvent,
+		state_overlay: &StateMap<Arc<PduEvent>>,
+		_mutex_lock: &mutex_map::Guard<()>, // Take mutex guard to make sure users get the room state mutex
+	) -> Result<(PduEvent, CanonicalJsonObject)> {
+		let historical = Historical {
+			origin_server_ts,
+			prev_event,
+			state_overlay,
+		};
+
+		self.hash_and_sign_event(pdu_builder, sender, room_id, Some(historical))
+	}
+
+	fn hash_and_sign_event(
+		&self, pdu_builder: PduBuilder, sender: &UserId, room_id: &RoomId, historical: Option<Historical<'_>>,
 	) -> Result<(PduEvent, CanonicalJsonObject)> {
 		let PduBuilder {
 			event_type,
@@ -604,13 +637,24 @@ impl Service {
 			redacts,
 		} = pdu_builder;
 
-		let prev_events: Vec<_> = services()
-			.rooms
-			.state
-			.get_forward_extremities(room_id)?
-			.into_iter()
-			.take(20)
-			.collect();
+		limits::check_builder(&event_type.to_string(), state_key.as_deref(), &content)?;
+		limits::check_relation_depth(&content, services().globals.config.max_relation_depth, |event_id| {
+			self.get_pdu(event_id)
+				.ok()
+				.flatten()
+				.map(|pdu| pdu.content.clone())
+		})?;
+
+		let prev_events: Vec<_> = match historical {
+			Some(historical) => vec![Arc::clone(&historical.prev_event.event_id)],
+			None => services()
+				.rooms
+				.state
+				.get_forward_extremities(room_id)?
+				.into_iter()
+				.take(20)
+				.collect(),
+		};
 
 		// If there was no create event yet, assume we are creating a room
 		let room_version_id = services()
@@ -632,23 +676,37 @@ impl Service {
 
 		let room_version = RoomVersion::new(&room_version_id).expect("room version is supported");
 
-		let auth_events =
+		let mut auth_events =
 			services()
 				.rooms
 				.state
 				.get_auth_events(room_id, &event_type, sender, state_key.as_deref(), &content)?;
 
+		if let Some(historical) = historical {
+			let auth_types = state_res::auth_types_for_event(&event_type, sender, state_key.as_deref(), &content)
+				.expect("content is a valid JSON object");
+			for key in auth_types {
+				if let Some(pdu) = historical.state_overlay.get(&key) {
+					auth_events.insert(key, Arc::clone(pdu));
+				}
+			}
+		}
+
+		limits::check_references(prev_events.len(), auth_events.len())?;
+
 		// Our depth is the maximum depth of prev_events + 1
-		let depth = prev_events
-			.iter()
-			.filter_map(|event_id| Some(self.get_pdu(event_id).ok()??.depth))
-			.max()
-			.unwrap_or_else(|| uint!(0))
-			+ uint!(1);
+		let depth = match historical {
+			Some(historical) => historical.prev_event.depth,
+			None => prev_events
+				.iter()
+				.filter_map(|event_id| Some(self.get_pdu(event_id).ok()??.depth))
+				.max()
+				.unwrap_or_else(|| uint!(0)),
+		} + uint!(1);
 
 		let mut unsigned = unsigned.unwrap_or_default();
 
-		if let Some(state_key) = &state_key {
+		if let (Some(state_key), None) = (&state_key, historical) {
 			if let Some(prev_pdu) =
 				services()
 					.rooms
@@ -670,14 +728,21 @@ impl Service {
 			}
 		}
 
+		limits::cap_unsigned(&mut unsigned)?;
+
 		let mut pdu = PduEvent {
 			event_id: ruma::event_id!("$thiswillbefilledinlater").into(),
 			room_id: room_id.to_owned(),
 			sender: sender.to_owned(),
 			origin: None,
-			origin_server_ts: utils::millis_since_unix_epoch()
-				.try_into()
-				.expect("time is valid"),
+			origin_server_ts: historical.map_or_else(
+				|| {
+					utils::millis_since_unix_epoch()
+						.try_into()
+						.expect("time is valid")
+				},
+				|historical| historical.origin_server_ts.0,
+			),
 			kind: event_type,
 			content,
 			state_key,
@@ -699,6 +764,14 @@ impl Service {
 			signatures: None,
 		};
 
+		// Other servers measure the size of the canonical JSON
+		let mut pdu_json = utils::to_canonical_object(&pdu).map_err(|e| {
+			error!("Failed to convert PDU to canonical JSON: {e}");
+			Error::bad_database("Failed to convert PDU to canonical JSON.")
+		})?;
+
+		limits::check_size(&pdu_json)?;
+
 		let auth_check = state_res::auth_check(
 			&room_version,
 			&pdu,
@@ -715,11 +788,6 @@ impl Service {
 		}
 
 		// Hash and sign
-		let mut pdu_json = utils::to_canonical_object(&pdu).map_err(|e| {
-			error!("Failed to convert PDU to canonical JSON: {e}");
-			Error::bad_database("Failed to convert PDU to canonical JSON.")
-		})?;
-
 		// room v3 and above removed the "event_id" field from remote PDU format
 		match room_version_id {
 			RoomVersionId::V1 | RoomVersionId::V2 => {},
@@ -784,6 +852,14 @@ impl Service {
 		state_lock: &mutex_map::Guard<()>, // Take mutex guard to make sure users get the room state mutex
 	) -> Result<Arc<EventId>> {
 		let (pdu, pdu_json) = self.create_hash_and_sign_event(pdu_builder, sender, room_id, state_lock)?;
+
+		// only events which could be sent take a token
+		services()
+			.rate_limit
+			.rooms
+			.check(sender, room_id, &pdu)
+			.await?;
+
 		if let Some(admin_room) = admin::Service::get_admin_room()? {
 			if admin_room == room_id {
 				match pdu.event_type() {
@@ -859,6 +935,10 @@ impl Service {
 			}
 		}
 
+		if pdu.kind == TimelineEventType::RoomPowerLevels && pdu.state_key.as_deref() == Some("") {
+			check_power_levels_change(&pdu, sender, room_id).await?;
+		}
+
 		// If redaction event is not authorized, do not append it to the timeline
 		if pdu.kind == TimelineEventType::RoomRedaction {
 			match services().rooms.state.get_room_version(&pdu.room_id)? {
@@ -881,6 +961,13 @@ impl Service {
 						)? {
 							return Err(Error::BadRequest(ErrorKind::forbidden(), "User cannot redact this event."));
 						}
+
+						if self
+							.get_pdu(redact_id)?
+							.is_some_and(|target| !same_room(&target, &pdu))
+						{
+							return Err(Error::BadRequest(ErrorKind::forbidden(), "Event is not in this room."));
+						}
 					};
 				},
 				_ => {
@@ -896,6 +983,13 @@ impl Service {
 						)? {
 							return Err(Error::BadRequest(ErrorKind::forbidden(), "User cannot redact this event."));
 						}
+
+						if self
+							.get_pdu(redact_id)?
+							.is_some_and(|target| !same_room(&target, &pdu))
+						{
+							return Err(Error::BadRequest(ErrorKind::forbidden(), "Event is not in this room."));
+						}
 					}
 				},
 			}
@@ -974,6 +1068,7 @@ impl Service {
 			.state
 			.set_event_state(&pdu.event_id, &pdu.room_id, state_ids_compressed)?;
 
+		let extremities = new_room_leaves.len();
 		if soft_fail {
 			services()
 				.rooms
@@ -983,16 +1078,109 @@ impl Service {
 				.rooms
 				.state
 				.set_forward_extremities(&pdu.room_id, new_room_leaves, state_lock)?;
+			self.collapse_extremities_later(&pdu.room_id, extremities)
+				.await;
 			return Ok(None);
 		}
 
 		let pdu_id = self
 			.append_pdu(pdu, pdu_json, new_room_leaves, state_lock)
 			.await?;
+		self.collapse_extremities_later(&pdu.room_id, extremities)
+			.await;
 
 		Ok(Some(pdu_id))
 	}
 
+	/// Sends a dummy event in the background referencing the forward
+	/// extremities of the room if there are more than
+	/// `forward_extremities_soft_limit` of them, at most once per
+	/// `forward_extremities_dummy_interval` in each room.
+	async fn collapse_extremities_later(&self, room_id: &RoomId, extremities: usize) {
+		let config = &services().globals.config;
+		let limit = config.forward_extremities_soft_limit;
+		if limit == 0 || extremities <= limit {
+			return;
+		}
+
+		let interval = Duration::from_secs(config.forward_extremities_dummy_interval);
+		let now = Instant::now();
+		{
+			let mut last_dummy_event = self.last_dummy_event.lock().await;
+			if last_dummy_event
+				.get(room_id)
+				.is_some_and(|last| now.duration_since(*last) < interval)
+			{
+				return;
+			}
+
+			// rooms whose interval passed are collapsed again once needed
+			last_dummy_event.retain(|_, last| now.duration_since(*last) < interval);
+			last_dummy_event.insert(room_id.to_owned(), now);
+		}
+
+		let room_id = room_id.to_owned();
+		services().server.runtime().spawn(async move {
+			if let Err(e) = services().rooms.timeline.send_dummy_event(&room_id).await {
+				warn!("Failed to send dummy event to collapse forward extremities of {room_id}: {e}");
+			}
+		});
+	}
+
+	/// Sends an `org.matrix.dummy_event` as the server user so that its
+	/// prev_events reference the forward extremities of the room. Rooms the
+	/// server user is not joined to or not allowed to send in are skipped.
+	pub async fn send_dummy_event(&self, room_id: &RoomId) -> Result<()> {
+		let server_user = &services().globals.server_user;
+		if !services()
+			.rooms
+			.state_cache
+			.is_joined(server_user, room_id)?
+		{
+			debug!("Not collapsing forward extremities of {room_id}, server user is not joined");
+			return Ok(());
+		}
+
+		let state_lock = services().globals.roomid_mutex_state.lock(room_id).await;
+		if !services().rooms.state_accessor.user_can_send_message(
+			server_user,
+			room_id,
+			"org.matrix.dummy_event".into(),
+		)? {
+			debug!("Not collapsing forward extremities of {room_id}, server user is not allowed to send");
+			return Ok(());
+		}
+
+		let before = services()
+			.rooms
+			.state
+			.get_forward_extremities(room_id)?
+			.len();
+
+		self.build_and_append_pdu(
+			PduBuilder {
+				event_type: "org.matrix.dummy_event".into(),
+				content: to_raw_value(&serde_json::json!({})).expect("empty object is valid json"),
+				unsigned: None,
+				state_key: None,
+				redacts: None,
+			},
+			server_user,
+			room_id,
+			&state_lock,
+		)
+		.await?;
+
+		let after = services()
+			.rooms
+			.state
+			.get_forward_extremities(room_id)?
+			.len();
+		info!("Sent dummy event to {room_id}, forward extremities went from {before} to {after}");
+
+		Ok(())
+	}
+
 	/// Returns an iterator over all PDUs in a room.
 	pub fn all_pdus<'a>(
 		&'a self, user_id: &UserId, room_id: &RoomId,
@@ -1023,11 +1211,7 @@ impl Service {
 	#[tracing::instrument(skip(self, reason))]
 	pub fn redact_pdu(&self, event_id: &EventId, reason: &PduEvent, shortroomid: u64) -> Result<()> {
 		// TODO: Don't reserialize, keep original json
-		if let Some(pdu_id) = self.get_pdu_id(event_id)? {
-			let mut pdu = self
-				.get_pdu_from_id(&pdu_id)?
-				.ok_or_else(|| Error::bad_database("PDU ID points to invalid PDU."))?;
-
+		if let Some((pdu_id, mut pdu)) = self.get_pdu_with_id(event_id)? {
 			if let Ok(content) = serde_json::from_str::<ExtractBody>(pdu.content.get()) {
 				if let Some(body) = content.body {
 					services()
@@ -1037,6 +1221,8 @@ impl Service {
 				}
 			}
 
+			services().rooms.threads.remove_from_thread(&pdu);
+
 			let room_version_id = services().rooms.state.get_room_version(&pdu.room_id)?;
 
 			pdu.redact(room_version_id, reason)?;
@@ -1054,6 +1240,61 @@ impl Service {
 		Ok(())
 	}
 
+	/// Redacts the target of a redaction event if its sender may redact it. The
+	/// redaction is recorded, so a target we do not have yet is redacted once
+	/// it arrives, e.g. when it is backfilled.
+	fn apply_redaction(&self, redaction: &PduEvent, shortroomid: u64) -> Result<()> {
+		let Some(redacts) = redaction_target(redaction)? else {
+			return Ok(());
+		};
+
+		self.db
+			.set_redaction(shortroomid, &redacts, &redaction.event_id)?;
+		if let Some((_, target)) = self.get_pdu_with_id(&redacts)? {
+			self.redact_if_allowed(&target, redaction, shortroomid)?;
+		}
+
+		Ok(())
+	}
+
+	/// Redacts an event which was just added to the timeline if its redaction
+	/// arrived before it
+	fn apply_pending_redaction(&self, pdu: &PduEvent, shortroomid: u64) -> Result<()> {
+		let Some(redaction_id) = self.db.get_redaction(shortroomid, &pdu.event_id)? else {
+			return Ok(());
+		};
+
+		let Some(redaction) = self.get_pdu(&redaction_id)? else {
+			return Ok(());
+		};
+
+		self.redact_if_allowed(pdu, &redaction, shortroomid)
+	}
+
+	/// The power levels of the redaction's room only apply to events of that
+	/// room, so redactions of events in other rooms are ignored
+	fn redact_if_allowed(&self, target: &PduEvent, redaction: &PduEvent, shortroomid: u64) -> Result<()> {
+		if !same_room(target, redaction) {
+			warn!(
+				redaction = %redaction.event_id,
+				target = %target.event_id,
+				"Ignoring redaction of an event in another room"
+			);
+			return Ok(());
+		}
+
+		if services().rooms.state_accessor.user_can_redact(
+			&target.event_id,
+			&redaction.sender,
+			&redaction.room_id,
+			false,
+		)? {
+			self.redact_pdu(&target.event_id, redaction, shortroomid)?;
+		}
+
+		Ok(())
+	}
+
 	#[tracing::instrument(skip(self, room_id))]
 	pub async fn backfill_if_required(&self, room_id: &RoomId, from: PduCount) -> Result<()> {
 		let first_pdu = self
@@ -1204,24 +1445,97 @@ impl Service {
 		let value = self.get_pdu_json(&event_id)?.expect("We just created it");
 		let pdu = self.get_pdu(&event_id)?.expect("We just created it");
 
+		self.prepend_pdu(&pdu, &value).await?;
+		drop(mutex_lock);
+
+		debug!("Prepended backfill pdu");
+		Ok(())
+	}
+
+	/// Creates historical state events on behalf of an appservice importing
+	/// history, following `prev_event`. They are stored as outliers, leaving
+	/// the room state untouched, and are added to `state_overlay` so that the
+	/// following historical events of the batch are authorized against them.
+	#[allow(clippy::too_many_arguments)]
+	pub fn create_historical_state_pdu(
+		&self,
+		pdu_builder: PduBuilder,
+		sender: &UserId,
+		room_id: &RoomId,
+		origin_server_ts: MilliSecondsSinceUnixEpoch,
+		prev_event: &PduEvent,
+		state_overlay: &mut StateMap<Arc<PduEvent>>,
+		state_lock: &mutex_map::Guard<()>, // Take mutex guard to make sure users get the room state mutex
+	) -> Result<Arc<PduEvent>> {
+		let Some(state_key) = pdu_builder.state_key.clone() else {
+			return Err(Error::BadRequest(
+				ErrorKind::InvalidParam,
+				"State events must have a state_key.",
+			));
+		};
+
+		let (pdu, pdu_json) = self.create_hash_and_sign_historical_event(
+			pdu_builder,
+			sender,
+			room_id,
+			origin_server_ts,
+			prev_event,
+			state_overlay,
+			state_lock,
+		)?;
+
+		services()
+			.rooms
+			.outlier
+			.add_pdu_outlier(&pdu.event_id, &pdu_json)?;
+
+		let pdu = Arc::new(pdu);
+		state_overlay.insert((pdu.kind.to_string().into(), state_key), Arc::clone(&pdu));
+
+		Ok(pdu)
+	}
+
+	/// Inserts a historical event created with
+	/// `create_hash_and_sign_historical_event` before every other event in the
+	/// room's timeline, the same way backfilled events are. No pushes are sent
+	/// and no notification counts are updated for it.
+	pub async fn prepend_historical_pdu(
+		&self,
+		pdu: &PduEvent,
+		pdu_json: &CanonicalJsonObject,
+		_state_lock: &mutex_map::Guard<()>, // Take mutex guard to make sure users get the room state mutex
+	) -> Result<()> {
+		self.prepend_pdu(pdu, pdu_json).await
+	}
+
+	/// Inserts a pdu before every other event in its room's timeline and
+	/// indexes it for search.
+	async fn prepend_pdu(&self, pdu: &PduEvent, pdu_json: &CanonicalJsonObject) -> Result<()> {
 		let shortroomid = services()
 			.rooms
 			.short
-			.get_shortroomid(&room_id)?
-			.expect("room exists");
+			.get_existing_shortroomid(&pdu.room_id)?;
 
-		let insert_lock = services().globals.roomid_mutex_insert.lock(&room_id).await;
+		let insert_lock = services()
+			.globals
+			.roomid_mutex_insert
+			.lock(&pdu.room_id)
+			.await;
 
-		let count = services().globals.next_count()?;
-		let mut pdu_id = shortroomid.to_be_bytes().to_vec();
-		pdu_id.extend_from_slice(&0_u64.to_be_bytes());
-		pdu_id.extend_from_slice(&(u64::MAX - count).to_be_bytes());
+		let pdu_id = backfill_pdu_id(shortroomid, services().globals.next_count()?);
 
 		// Insert pdu
-		self.db.prepend_backfill_pdu(&pdu_id, &event_id, &value)?;
+		self.db
+			.prepend_backfill_pdu(&pdu_id, &pdu.event_id, pdu_json)?;
 
 		drop(insert_lock);
 
+		if pdu.kind == TimelineEventType::RoomRedaction {
+			self.apply_redaction(pdu, shortroomid)?;
+		}
+
+		self.apply_pending_redaction(pdu, shortroomid)?;
+
 		if pdu.kind == TimelineEventType::RoomMessage {
 			let content = serde_json::from_str::<ExtractBody>(pdu.content.get())
 				.map_err(|_| Error::bad_database("Invalid content in pdu."))?;
@@ -1233,15 +1547,186 @@ impl Service {
 					.index_pdu(shortroomid, &pdu_id, &body)?;
 			}
 		}
-		drop(mutex_lock);
 
-		debug!("Prepended backfill pdu");
 		Ok(())
 	}
 }
 
+fn same_room(target: &PduEvent, redaction: &PduEvent) -> bool { target.room_id == redaction.room_id }
+
+/// The event a redaction event redacts, which moved into its content in room
+/// version 11
+fn redaction_target(redaction: &PduEvent) -> Result<Option<OwnedEventId>> {
+	let room_version_id = services()
+		.rooms
+		.state
+		.get_room_version(&redaction.room_id)?;
+	match room_version_id {
+		RoomVersionId::V1
+		| RoomVersionId::V2
+		| RoomVersionId::V3
+		| RoomVersionId::V4
+		| RoomVersionId::V5
+		| RoomVersionId::V6
+		| RoomVersionId::V7
+		| RoomVersionId::V8
+		| RoomVersionId::V9
+		| RoomVersionId::V10 => Ok(redaction.redacts.as_deref().map(ToOwned::to_owned)),
+		RoomVersionId::V11 => {
+			let content = serde_json::from_str::<RoomRedactionEventContent>(redaction.content.get()).map_err(|e| {
+				warn!("Invalid content in redaction pdu: {e}");
+				Error::bad_database("Invalid content in redaction pdu")
+			})?;
+
+			Ok(content.redacts)
+		},
+		_ => {
+			warn!("Unexpected or unsupported room version {room_version_id}");
+			Err(Error::BadRequest(
+				ErrorKind::BadJson,
+				"Unexpected or unsupported room version found",
+			))
+		},
+	}
+}
+
+/// Copies `unsigned.redacted_because` of the stored event into its
+/// replacement if the replacement lacks it
+fn keep_redacted_because(old: &CanonicalJsonObject, new: &mut CanonicalJsonObject) {
+	let Some(redacted_because) = old
+		.get("unsigned")
+		.and_then(CanonicalJsonValue::as_object)
+		.and_then(|unsigned| unsigned.get("redacted_because"))
+	else {
+		return;
+	};
+
+	let unsigned = new
+		.entry("unsigned".to_owned())
+		.or_insert_with(|| CanonicalJsonValue::Object(CanonicalJsonObject::new()));
+	if let CanonicalJsonValue::Object(unsigned) = unsigned {
+		unsigned
+			.entry("redacted_because".to_owned())
+			.or_insert_with(|| redacted_because.clone());
+	}
+}
+
+/// Unstable power levels content field which allows a user to knowingly give
+/// up their own ability to change the power levels of a room
+const ALLOW_SELF_LOCKOUT_FIELD: &str = "org.conduwuit.allow_self_lockout";
+
+/// Rejects locally created power levels changes which would leave the sender
+/// unable to change the power levels again, unless they opted in with
+/// `ALLOW_SELF_LOCKOUT_FIELD`, and tells the admin room when a change leaves
+/// nobody who can administer the room. The server user is exempt.
+async fn check_power_levels_change(pdu: &PduEvent, sender: &UserId, room_id: &RoomId) -> Result<()> {
+	if sender == services().globals.server_user {
+		return Ok(());
+	}
+
+	let new_power_levels = new_power_levels(&pdu.content, sender)?;
+
+	let Some(current) =
+		services()
+			.rooms
+			.state_accessor
+			.room_state_get(room_id, &StateEventType::RoomPowerLevels, "")?
+	else {
+		return Ok(());
+	};
+
+	let current_power_levels = RoomPowerLevels::from(
+		serde_json::from_str::<RoomPowerLevelsEventContent>(current.content.get())
+			.map_err(|_| Error::bad_database("Invalid power levels event in database."))?,
+	);
+
+	if room_administrable(&current_power_levels) && !room_administrable(&new_power_levels) {
+		warn!("{sender} removed the last user able to change power levels in {room_id}");
+		services()
+			.admin
+			.send_message(RoomMessageEventContent::text_plain(format!(
+				"{sender} removed the last user able to change power levels in {room_id}. The room can no longer be \
+				 administered."
+			)))
+			.await;
+	}
+
+	Ok(())
+}
+
+/// The power levels of a locally created power levels event, rejecting a
+/// change which leaves the sender unable to change them again unless they
+/// opted in with `ALLOW_SELF_LOCKOUT_FIELD`
+fn new_power_levels(content: &RawJsonValue, sender: &UserId) -> Result<RoomPowerLevels> {
+	let power_levels = serde_json::from_str::<RoomPowerLevelsEventContent>(content.get())
+		.map_err(|_| Error::BadRequest(ErrorKind::BadJson, "Invalid power levels event content."))?;
+	let allow_self_lockout = serde_json::from_str::<serde_json::Value>(content.get())
+		.ok()
+		.and_then(|content| content.get(ALLOW_SELF_LOCKOUT_FIELD)?.as_bool())
+		.unwrap_or(false);
+
+	let power_levels = RoomPowerLevels::from(power_levels);
+	if !allow_self_lockout && !power_levels.user_can_send_state(sender, StateEventType::RoomPowerLevels) {
+		return Err(Error::BadRequest(
+			ErrorKind::forbidden(),
+			"This power levels change would prevent you from changing power levels in this room again. Set \
+			 \"org.conduwuit.allow_self_lockout\": true in the event content if this is intended.",
+		));
+	}
+
+	Ok(power_levels)
+}
+
+/// Placement of an event imported into the history of a room
+#[derive(Clone, Copy)]
+struct Historical<'a> {
+	origin_server_ts: MilliSecondsSinceUnixEpoch,
+	prev_event: &'a PduEvent,
+	state_overlay: &'a StateMap<Arc<PduEvent>>,
+}
+
+/// Whether the actions of the first matching push rule notify the user, and
+/// whether they highlight the event
+fn notify_and_highlight(actions: &[Action]) -> (bool, bool) {
+	let notify = actions
+		.iter()
+		.any(|action| matches!(action, Action::Notify));
+	let highlight = actions
+		.iter()
+		.any(|action| matches!(action, Action::SetTweak(Tweak::Highlight(true))));
+
+	(notify, highlight)
+}
+
+/// Whether anyone can change the power levels, either a listed user or every
+/// member through `users_default`
+fn room_administrable(power_levels: &RoomPowerLevels) -> bool {
+	let required = power_levels
+		.events
+		.get(&TimelineEventType::RoomPowerLevels)
+		.copied()
+		.unwrap_or(power_levels.state_default);
+
+	power_levels.users_default >= required
+		|| power_levels
+			.users
+			.keys()
+			.any(|user_id| power_levels.user_can_send_state(user_id, StateEventType::RoomPowerLevels))
+}
+
+/// The ID of a backfilled pdu, which sorts before the room's other pdus; the
+/// later it was backfilled, the earlier.
+fn backfill_pdu_id(shortroomid: u64, count: u64) -> Vec<u8> {
+	let mut pdu_id = shortroomid.to_be_bytes().to_vec();
+	pdu_id.extend_from_slice(&0_u64.to_be_bytes());
+	pdu_id.extend_from_slice(&(u64::MAX - count).to_be_bytes());
+	pdu_id
+}
+
 #[cfg(test)]
 mod tests {
+	use ruma::{event_id, events::AnySyncTimelineEvent, int, owned_room_id, owned_user_id, serde::Raw};
+
 	use super::*;
 
 	#[test]
@@ -1251,4 +1736,222 @@ mod tests {
 		assert!(PduCount::Normal(1) > PduCount::Backfilled(1));
 		assert!(PduCount::Backfilled(1) < PduCount::Normal(1));
 	}
+
+	#[test]
+	fn backfill_into_read_room() {
+		// the user read up to the latest event, then history was backfilled,
+		// its counts allocated after the receipt's
+		let mut read_id = 1_u64.to_be_bytes().to_vec();
+		read_id.extend_from_slice(&10_u64.to_be_bytes());
+		let read = data::pdu_count(&read_id).unwrap();
+		assert_eq!(read, PduCount::Normal(10));
+
+		for count in [11, 12, u64::MAX / 2] {
+			let backfilled = data::pdu_count(&backfill_pdu_id(1, count)).unwrap();
+			assert!(backfilled < read);
+
+			// receipts on backfilled events leave the counts of newer events
+			assert!(!crate::rooms::user::moves_read_marker(backfilled, Some(read)));
+		}
+
+		let first = data::pdu_count(&backfill_pdu_id(1, 11)).unwrap();
+		let second = data::pdu_count(&backfill_pdu_id(1, 12)).unwrap();
+		assert!(second < first);
+
+		assert!(crate::rooms::user::moves_read_marker(read, Some(read)));
+		assert!(crate::rooms::user::moves_read_marker(first, None));
+	}
+
+	#[test]
+	fn redacted_because_kept() {
+		let CanonicalJsonValue::Object(old) = to_canonical_value(serde_json::json!({
+			"content": {},
+			"unsigned": { "redacted_because": { "event_id": "$redaction" } },
+		}))
+		.unwrap() else {
+			panic!("object expected");
+		};
+
+		let CanonicalJsonValue::Object(mut new) = to_canonical_value(serde_json::json!({
+			"content": {},
+			"unsigned": { "age": 1 },
+		}))
+		.unwrap() else {
+			panic!("object expected");
+		};
+
+		keep_redacted_because(&old, &mut new);
+		let unsigned = new["unsigned"].as_object().unwrap();
+		assert!(unsigned.contains_key("redacted_because"));
+		assert!(unsigned.contains_key("age"));
+
+		let mut unredacted = CanonicalJsonObject::new();
+		keep_redacted_because(&CanonicalJsonObject::new(), &mut unredacted);
+		assert!(unredacted.is_empty());
+	}
+
+	fn pdu(event_id: &str, room_id: &str, kind: &str) -> PduEvent {
+		serde_json::from_value(serde_json::json!({
+			"event_id": event_id,
+			"room_id": room_id,
+			"sender": "@moderator:example.org",
+			"origin_server_ts": 1,
+			"type": kind,
+			"content": {},
+			"redacts": "$target",
+			"prev_events": [],
+			"depth": 1,
+			"auth_events": [],
+			"hashes": { "sha256": "" },
+		}))
+		.expect("valid pdu")
+	}
+
+	#[test]
+	fn redactions_stay_in_their_room() {
+		let target = pdu("$target", "!victim:example.org", "m.room.message");
+		let foreign = pdu("$foreign", "!moderated:example.org", "m.room.redaction");
+		let local = pdu("$local", "!victim:example.org", "m.room.redaction");
+
+		assert!(!same_room(&target, &foreign));
+		assert!(same_room(&target, &local));
+
+		// A redaction waiting for its target is only found from its own room, so a
+		// backfilled event of another room never picks it up
+		let target_id = event_id!("$target");
+		assert_ne!(data::redaction_key(1, target_id), data::redaction_key(2, target_id));
+	}
+
+	#[test]
+	fn power_levels_administrators() {
+		let mut content = RoomPowerLevelsEventContent::default();
+		content
+			.users
+			.insert(owned_user_id!("@admin:example.org"), int!(100));
+		content
+			.users
+			.insert(owned_user_id!("@moderator:example.org"), int!(50));
+		assert!(room_administrable(&RoomPowerLevels::from(content.clone())));
+
+		content
+			.events
+			.insert(TimelineEventType::RoomPowerLevels, int!(101));
+		assert!(!room_administrable(&RoomPowerLevels::from(content.clone())));
+
+		// every member may change them
+		content.users.clear();
+		content.users_default = int!(101);
+		assert!(room_administrable(&RoomPowerLevels::from(content)));
+	}
+
+	#[test]
+	fn power_levels_self_lockout() {
+		let sender = owned_user_id!("@admin:example.org");
+		let content = |users_default: i64, allow: bool| {
+			to_raw_value(&serde_json::json!({
+				"users": { "@other:example.org": 100 },
+				"users_default": users_default,
+				ALLOW_SELF_LOCKOUT_FIELD: allow,
+			}))
+			.unwrap()
+		};
+
+		let Error::BadRequest(kind, _) = new_power_levels(&content(0, false), &sender).unwrap_err() else {
+			panic!("expected the change to be rejected");
+		};
+		assert!(matches!(kind, ErrorKind::Forbidden { .. }));
+
+		new_power_levels(&content(0, true), &sender).unwrap();
+		new_power_levels(&content(50, false), &sender).unwrap();
+	}
+
+	/// Whether an event notifies and highlights `@user`, evaluated as in
+	/// `append_pdu` with the room context `pusher::Service::get_actions` uses
+	fn actions_in(rules: &serde_json::Value, room_id: &RoomId, content: serde_json::Value) -> (bool, bool) {
+		let rules: Ruleset = serde_json::from_value(rules.clone()).expect("valid ruleset");
+		let mut power_levels = RoomPowerLevelsEventContent::default();
+		power_levels
+			.users
+			.insert(owned_user_id!("@moderator:example.org"), int!(50));
+
+		let ctx = crate::pusher::room_ctx(user_id!("@user:example.org"), room_id, 3, "User".to_owned(), &power_levels);
+		let event: Raw<AnySyncTimelineEvent> = serde_json::from_value(serde_json::json!({
+			"type": "m.room.message",
+			"event_id": "$event:example.org",
+			"sender": "@moderator:example.org",
+			"origin_server_ts": 1,
+			"content": content,
+		}))
+		.expect("valid event");
+
+		notify_and_highlight(rules.get_actions(&event, &ctx))
+	}
+
+	fn text(body: &str) -> serde_json::Value { serde_json::json!({ "msgtype": "m.text", "body": body }) }
+
+	#[test]
+	fn muted_room_still_notifies_keywords() {
+		let muted = owned_room_id!("!muted:example.org");
+		let other = owned_room_id!("!other:example.org");
+		let mut rules = serde_json::json!({
+			"override": [],
+			"content": [{
+				"rule_id": "cake",
+				"pattern": "cake",
+				"actions": ["notify", { "set_tweak": "highlight" }],
+				"default": false,
+				"enabled": true,
+			}],
+			"room": [{
+				"rule_id": muted,
+				"actions": [],
+				"default": false,
+				"enabled": true,
+			}],
+			"sender": [],
+			"underride": [{
+				"rule_id": ".m.rule.message",
+				"conditions": [{ "kind": "event_match", "key": "type", "pattern": "m.room.message" }],
+				"actions": ["notify"],
+				"default": true,
+				"enabled": true,
+			}],
+		});
+
+		assert_eq!(actions_in(&rules, &muted, text("hello")), (false, false));
+		assert_eq!(actions_in(&rules, &other, text("hello")), (true, false));
+		assert_eq!(actions_in(&rules, &muted, text("want some cake?")), (true, true));
+
+		// The master rule disables notifications before any other rule is evaluated
+		rules["override"] = serde_json::json!([{
+			"rule_id": ".m.rule.master",
+			"conditions": [],
+			"actions": [],
+			"default": true,
+			"enabled": true,
+		}]);
+		assert_eq!(actions_in(&rules, &other, text("want some cake?")), (false, false));
+	}
+
+	#[test]
+	fn muted_room_still_notifies_room_mentions() {
+		let muted = owned_room_id!("!muted:example.org");
+		let mut rules = serde_json::to_value(Ruleset::server_default(user_id!("@user:example.org"))).unwrap();
+		rules["room"] = serde_json::json!([{
+			"rule_id": muted,
+			"actions": [],
+			"default": false,
+			"enabled": true,
+		}]);
+
+		assert_eq!(actions_in(&rules, &muted, text("hello")), (false, false));
+
+		// the mention override needs the sender's power level from the room context
+		let mention = serde_json::json!({
+			"msgtype": "m.text",
+			"body": "@room meeting now",
+			"m.mentions": { "room": true },
+		});
+		assert_eq!(actions_in(&rules, &muted, mention), (true, true));
+	}
 }
@@ -49,7 +49,7 @@ impl Service {
 
 		// update federation
 		if user_is_local(user_id) {
-			Self::federation_send(room_id, user_id, true)?;
+			Self::federation_send(room_id, user_id, true).await?;
 		}
 
 		Ok(())
@@ -75,7 +75,7 @@ impl Service {
 
 		// update federation
 		if user_is_local(user_id) {
-			Self::federation_send(room_id, user_id, false)?;
+			Self::federation_send(room_id, user_id, false).await?;
 		}
 
 		Ok(())
@@ -131,7 +131,7 @@ impl Service {
 			// update federation
 			for user in removable {
 				if user_is_local(&user) {
-					Self::federation_send(room_id, &user, false)?;
+					Self::federation_send(room_id, &user, false).await?;
 				}
 			}
 		}
@@ -168,12 +168,20 @@ impl Service {
 		})
 	}
 
-	fn federation_send(room_id: &RoomId, user_id: &UserId, typing: bool) -> Result<()> {
+	async fn federation_send(room_id: &RoomId, user_id: &UserId, typing: bool) -> Result<()> {
 		debug_assert!(user_is_local(user_id), "tried to broadcast typing status of remote user",);
 		if !services().globals.config.allow_outgoing_typing {
 			return Ok(());
 		}
 
+		if services()
+			.appservice
+			.is_ephemeral_federation_suppressed(user_id)
+			.await
+		{
+			return Ok(());
+		}
+
 		let edu = Edu::Typing(TypingContent::new(room_id.to_owned(), user_id.to_owned(), typing));
 
 		services()
@@ -1,17 +1,29 @@
-use std::sync::Arc;
+use std::{collections::HashMap, sync::Arc};
 
-use conduit::{utils, Error, Result};
+use conduit::{utils, utils::LockMap, Error, Result};
 use database::{Database, Map};
-use ruma::{OwnedRoomId, OwnedUserId, RoomId, UserId};
+use ruma::{DeviceId, OwnedRoomId, OwnedUserId, RoomId, UserId};
 
+use super::Notification;
 use crate::services;
 
+/// Number of notifications kept per user, beyond which the oldest are dropped
+const MAX_NOTIFICATIONS_PER_USER: u64 = 500;
+
 pub(super) struct Data {
 	userroomid_notificationcount: Arc<Map>,
 	userroomid_highlightcount: Arc<Map>,
 	roomuserid_lastnotificationread: Arc<Map>,
 	roomsynctoken_shortstatehash: Arc<Map>,
 	userroomid_joined: Arc<Map>,
+	userdeviceroomid_syncdeferred: Arc<Map>,
+	useridcount_notification: Arc<Map>,
+	userroomidcount_unreadnotification: Arc<Map>,
+	userid_notificationlogsize: Arc<Map>,
+
+	/// Held per user while a notification is added, so the size of their log
+	/// stays accurate
+	notification_lock: LockMap<OwnedUserId>,
 }
 
 impl Data {
@@ -22,16 +34,17 @@ impl Data {
 			roomuserid_lastnotificationread: db["userroomid_highlightcount"].clone(), //< NOTE: known bug from conduit
 			roomsynctoken_shortstatehash: db["roomsynctoken_shortstatehash"].clone(),
 			userroomid_joined: db["userroomid_joined"].clone(),
+			userdeviceroomid_syncdeferred: db["userdeviceroomid_syncdeferred"].clone(),
+			useridcount_notification: db["useridcount_notification"].clone(),
+			userroomidcount_unreadnotification: db["userroomidcount_unreadnotification"].clone(),
+			userid_notificationlogsize: db["userid_notificationlogsize"].clone(),
+			notification_lock: LockMap::new(),
 		}
 	}
 
 	pub(super) fn reset_notification_counts(&self, user_id: &UserId, room_id: &RoomId) -> Result<()> {
-		let mut userroom_id = user_id.as_bytes().to_vec();
-		userroom_id.push(0xFF);
-		userroom_id.extend_from_slice(room_id.as_bytes());
-		let mut roomuser_id = room_id.as_bytes().to_vec();
-		roomuser_id.push(0xFF);
-		roomuser_id.extend_from_slice(user_id.as_bytes());
+		let userroom_id = userroom_key(user_id, room_id);
+		let roomuser_id = roomuser_key(room_id, user_id);
 
 		self.userroomid_notificationcount
 			.insert(&userroom_id, &0_u64.to_be_bytes())?;
@@ -44,10 +57,20 @@ impl Data {
 		Ok(())
 	}
 
+	pub(super) fn remove_notification_counts(&self, user_id: &UserId, room_id: &RoomId) -> Result<()> {
+		let userroom_id = userroom_key(user_id, room_id);
+		let roomuser_id = roomuser_key(room_id, user_id);
+
+		self.userroomid_notificationcount.remove(&userroom_id)?;
+		self.userroomid_highlightcount.remove(&userroom_id)?;
+		self.roomuserid_lastnotificationread.remove(&roomuser_id)?;
+
+		// a rejoin doesn't bring back the unread notifications of before
+		self.mark_notifications_read(user_id, room_id, u64::MAX)
+	}
+
 	pub(super) fn notification_count(&self, user_id: &UserId, room_id: &RoomId) -> Result<u64> {
-		let mut userroom_id = user_id.as_bytes().to_vec();
-		userroom_id.push(0xFF);
-		userroom_id.extend_from_slice(room_id.as_bytes());
+		let userroom_id = userroom_key(user_id, room_id);
 
 		self.userroomid_notificationcount
 			.get(&userroom_id)?
@@ -57,9 +80,7 @@ impl Data {
 	}
 
 	pub(super) fn highlight_count(&self, user_id: &UserId, room_id: &RoomId) -> Result<u64> {
-		let mut userroom_id = user_id.as_bytes().to_vec();
-		userroom_id.push(0xFF);
-		userroom_id.extend_from_slice(room_id.as_bytes());
+		let userroom_id = userroom_key(user_id, room_id);
 
 		self.userroomid_highlightcount
 			.get(&userroom_id)?
@@ -69,9 +90,7 @@ impl Data {
 	}
 
 	pub(super) fn last_notification_read(&self, user_id: &UserId, room_id: &RoomId) -> Result<u64> {
-		let mut key = room_id.as_bytes().to_vec();
-		key.push(0xFF);
-		key.extend_from_slice(user_id.as_bytes());
+		let key = roomuser_key(room_id, user_id);
 
 		Ok(self
 			.roomuserid_lastnotificationread
@@ -87,11 +106,7 @@ impl Data {
 	pub(super) fn associate_token_shortstatehash(
 		&self, room_id: &RoomId, token: u64, shortstatehash: u64,
 	) -> Result<()> {
-		let shortroomid = services()
-			.rooms
-			.short
-			.get_shortroomid(room_id)?
-			.expect("room exists");
+		let shortroomid = services().rooms.short.get_existing_shortroomid(room_id)?;
 
 		let mut key = shortroomid.to_be_bytes().to_vec();
 		key.extend_from_slice(&token.to_be_bytes());
@@ -101,11 +116,7 @@ impl Data {
 	}
 
 	pub(super) fn get_token_shortstatehash(&self, room_id: &RoomId, token: u64) -> Result<Option<u64>> {
-		let shortroomid = services()
-			.rooms
-			.short
-			.get_shortroomid(room_id)?
-			.expect("room exists");
+		let shortroomid = services().rooms.short.get_existing_shortroomid(room_id)?;
 
 		let mut key = shortroomid.to_be_bytes().to_vec();
 		key.extend_from_slice(&token.to_be_bytes());
@@ -119,43 +130,467 @@ impl Data {
 			.transpose()
 	}
 
+	pub(super) fn mark_sync_deferred(&self, user_id: &UserId, device_id: &DeviceId, room_id: &RoomId) -> Result<()> {
+		let key = userdeviceroom_key(user_id, device_id, room_id);
+		self.userdeviceroomid_syncdeferred.insert(&key, &[])
+	}
+
+	pub(super) fn mark_sync_delivered(
+		&self, user_id: &UserId, device_id: &DeviceId, room_id: &RoomId, next_batch: u64,
+	) -> Result<()> {
+		let key = userdeviceroom_key(user_id, device_id, room_id);
+		self.userdeviceroomid_syncdeferred
+			.insert(&key, &next_batch.to_be_bytes())
+	}
+
+	pub(super) fn clear_sync_deferred(&self, user_id: &UserId, device_id: &DeviceId, room_id: &RoomId) -> Result<()> {
+		let key = userdeviceroom_key(user_id, device_id, room_id);
+		self.userdeviceroomid_syncdeferred.remove(&key)
+	}
+
+	/// The deferred rooms of the device with the token of the sync which sent
+	/// them in full, if one did
+	pub(super) fn sync_deferred_rooms(
+		&self, user_id: &UserId, device_id: &DeviceId,
+	) -> Result<HashMap<OwnedRoomId, Option<u64>>> {
+		let mut prefix = user_prefix(user_id);
+		prefix.extend_from_slice(device_id.as_bytes());
+		prefix.push(0xFF);
+
+		self.userdeviceroomid_syncdeferred
+			.scan_prefix(prefix.clone())
+			.map(|(key, value)| {
+				let room_id = utils::string_from_bytes(&key[prefix.len()..])
+					.ok()
+					.and_then(|room_id| RoomId::parse(room_id).ok())
+					.ok_or_else(|| Error::bad_database("Invalid room ID in userdeviceroomid_syncdeferred."))?;
+
+				let delivered_at = if value.is_empty() {
+					None
+				} else {
+					Some(
+						utils::u64_from_bytes(&value)
+							.map_err(|_| Error::bad_database("Invalid count in userdeviceroomid_syncdeferred."))?,
+					)
+				};
+
+				Ok((room_id, delivered_at))
+			})
+			.collect()
+	}
+
+	/// Appends the notification to the user's log and indexes it as unread by
+	/// room and event count. Once the log is full, its oldest entry is dropped.
+	pub(super) fn add_notification(&self, user_id: &UserId, notification: &Notification) -> Result<()> {
+		self.notification_lock
+			.with_lock(user_id, || self.append_notification(user_id, notification))
+	}
+
+	fn append_notification(&self, user_id: &UserId, notification: &Notification) -> Result<()> {
+		let mut key = user_prefix(user_id);
+		key.extend_from_slice(&services().globals.next_count()?.to_be_bytes());
+		self.useridcount_notification.insert(
+			&key,
+			&serde_json::to_vec(notification).expect("Notification::to_vec always works"),
+		)?;
+		self.userroomidcount_unreadnotification
+			.insert(&unread_key(user_id, &notification.room_id, notification.pdu_count), &key)?;
+
+		let size = self
+			.userid_notificationlogsize
+			.get(user_id.as_bytes())?
+			.map(|bytes| {
+				utils::u64_from_bytes(&bytes)
+					.map_err(|_| Error::bad_database("Invalid size in userid_notificationlogsize."))
+			})
+			.transpose()?
+			.unwrap_or(0);
+
+		if let Some(size) = grown_log_size(size) {
+			return self
+				.userid_notificationlogsize
+				.insert(user_id.as_bytes(), &size.to_be_bytes());
+		}
+
+		let oldest = self
+			.useridcount_notification
+			.scan_prefix(user_prefix(user_id))
+			.next();
+
+		if let Some((key, value)) = oldest {
+			self.useridcount_notification.remove(&key)?;
+			if let Ok(notification) = serde_json::from_slice::<Notification>(&value) {
+				if !notification.read {
+					self.userroomidcount_unreadnotification.remove(&unread_key(
+						user_id,
+						&notification.room_id,
+						notification.pdu_count,
+					))?;
+				}
+			}
+		}
+
+		Ok(())
+	}
+
+	/// Notifications of the user older than `until`, most recent first
+	pub(super) fn notifications_until<'a>(
+		&'a self, user_id: &UserId, until: u64,
+	) -> Box<dyn Iterator<Item = Result<(u64, Notification)>> + 'a> {
+		let mut prefix = user_id.as_bytes().to_vec();
+		prefix.push(0xFF);
+
+		let mut current = prefix.clone();
+		current.extend_from_slice(&until.saturating_sub(1).to_be_bytes());
+
+		Box::new(
+			self.useridcount_notification
+				.iter_from(&current, true)
+				.take_while(move |(key, _)| key.starts_with(&prefix))
+				.map(|(key, value)| {
+					let count = key
+						.get(key.len().saturating_sub(8)..)
+						.map(utils::u64_from_bytes)
+						.transpose()
+						.ok()
+						.flatten()
+						.ok_or_else(|| Error::bad_database("Invalid count in useridcount_notification."))?;
+
+					let notification = serde_json::from_slice(&value)
+						.map_err(|_| Error::bad_database("Invalid notification in useridcount_notification."))?;
+
+					Ok((count, notification))
+				}),
+		)
+	}
+
+	/// Marks the unread notifications of the user in the room up to the event
+	/// count `until` as read, only visiting those through the unread index.
+	pub(super) fn mark_notifications_read(&self, user_id: &UserId, room_id: &RoomId, until: u64) -> Result<()> {
+		let read: Vec<_> = self
+			.userroomidcount_unreadnotification
+			.scan_prefix(unread_prefix(user_id, room_id))
+			.take_while(|(key, _)| is_read_up_to(key, until))
+			.collect();
+
+		for (unread_key, key) in read {
+			self.userroomidcount_unreadnotification
+				.remove(&unread_key)?;
+
+			// dropped from the log in the meantime
+			let Some(value) = self.useridcount_notification.get(&key)? else {
+				continue;
+			};
+
+			let mut notification: Notification = serde_json::from_slice(&value)
+				.map_err(|_| Error::bad_database("Invalid notification in useridcount_notification."))?;
+			notification.read = true;
+			self.useridcount_notification.insert(
+				&key,
+				&serde_json::to_vec(&notification).expect("Notification::to_vec always works"),
+			)?;
+		}
+
+		Ok(())
+	}
+
+	/// Rooms every user in `users` is joined to. The rooms of the user with
+	/// the fewest are read, and the membership of the others is looked up for
+	/// each of them, so large room lists are not loaded.
 	pub(super) fn get_shared_rooms<'a>(
 		&'a self, users: Vec<OwnedUserId>,
 	) -> Result<Box<dyn Iterator<Item = Result<OwnedRoomId>> + 'a>> {
-		let iterators = users.into_iter().map(move |user_id| {
-			let mut prefix = user_id.as_bytes().to_vec();
-			prefix.push(0xFF);
-
-			self.userroomid_joined
-				.scan_prefix(prefix)
-				.map(|(key, _)| {
-					let roomid_index = key
-						.iter()
-						.enumerate()
-						.find(|(_, &b)| b == 0xFF)
-						.ok_or_else(|| Error::bad_database("Invalid userroomid_joined in db."))?
-						.0
-						.saturating_add(1); // +1 because the room id starts AFTER the separator
-
-					let room_id = key[roomid_index..].to_vec();
-
-					Ok::<_, Error>(room_id)
-				})
-				.filter_map(Result::ok)
-		});
-
-		// We use the default compare function because keys are sorted correctly (not
-		// reversed)
-		Ok(Box::new(
-			utils::common_elements(iterators, Ord::cmp)
-				.expect("users is not empty")
-				.map(|bytes| {
-					RoomId::parse(
-						utils::string_from_bytes(&bytes)
-							.map_err(|_| Error::bad_database("Invalid RoomId bytes in userroomid_joined"))?,
-					)
-					.map_err(|_| Error::bad_database("Invalid RoomId in userroomid_joined."))
-				}),
+		Ok(shared_rooms(
+			users,
+			|user_id| self.rooms_joined(user_id),
+			|user_id, room_id| {
+				let mut key = user_prefix(user_id);
+				key.extend_from_slice(room_id.as_bytes());
+				Ok(self.userroomid_joined.get(&key)?.is_some())
+			},
 		))
 	}
+
+	fn rooms_joined<'a>(&'a self, user_id: &UserId) -> impl Iterator<Item = Result<OwnedRoomId>> + 'a {
+		let prefix = user_prefix(user_id);
+		let prefix_len = prefix.len();
+
+		self.userroomid_joined
+			.scan_prefix(prefix)
+			.map(move |(key, _)| {
+				RoomId::parse(
+					utils::string_from_bytes(&key[prefix_len..])
+						.map_err(|_| Error::bad_database("Invalid RoomId bytes in userroomid_joined"))?,
+				)
+				.map_err(|_| Error::bad_database("Invalid RoomId in userroomid_joined."))
+			})
+	}
+}
+
+/// Rooms of the user in `users` with the fewest which `is_joined` holds for
+/// with each of the other users
+fn shared_rooms<'a, R, I, J>(
+	users: Vec<OwnedUserId>, rooms_of: R, is_joined: J,
+) -> Box<dyn Iterator<Item = Result<OwnedRoomId>> + 'a>
+where
+	R: Fn(&UserId) -> I,
+	I: Iterator<Item = Result<OwnedRoomId>> + 'a,
+	J: Fn(&UserId, &RoomId) -> Result<bool> + 'a,
+{
+	let smallest = utils::shortest(users.iter().map(|user_id| rooms_of(user_id))).expect("users is not empty");
+
+	let mut others = users;
+	let user_id = others.swap_remove(smallest);
+
+	Box::new(
+		rooms_of(&user_id)
+			.map(move |room_id| {
+				let room_id = room_id?;
+				for other in &others {
+					if !is_joined(other, &room_id)? {
+						return Ok(None);
+					}
+				}
+
+				Ok(Some(room_id))
+			})
+			.filter_map(Result::transpose),
+	)
+}
+
+fn userroom_key(user_id: &UserId, room_id: &RoomId) -> Vec<u8> {
+	let mut key = user_prefix(user_id);
+	key.extend_from_slice(room_id.as_bytes());
+	key
+}
+
+fn roomuser_key(room_id: &RoomId, user_id: &UserId) -> Vec<u8> {
+	let mut key = room_id.as_bytes().to_vec();
+	key.push(0xFF);
+	key.extend_from_slice(user_id.as_bytes());
+	key
+}
+
+fn user_prefix(user_id: &UserId) -> Vec<u8> {
+	let mut prefix = user_id.as_bytes().to_vec();
+	prefix.push(0xFF);
+	prefix
+}
+
+fn unread_prefix(user_id: &UserId, room_id: &RoomId) -> Vec<u8> {
+	let mut prefix = user_prefix(user_id);
+	prefix.extend_from_slice(room_id.as_bytes());
+	prefix.push(0xFF);
+	prefix
+}
+
+fn unread_key(user_id: &UserId, room_id: &RoomId, pdu_count: u64) -> Vec<u8> {
+	let mut key = unread_prefix(user_id, room_id);
+	key.extend_from_slice(&pdu_count.to_be_bytes());
+	key
+}
+
+/// Whether the unread notification at `key` is for an event up to `until`;
+/// the keys of a room are ordered by event count.
+fn is_read_up_to(key: &[u8], until: u64) -> bool {
+	key.get(key.len().saturating_sub(8)..)
+		.and_then(|count| utils::u64_from_bytes(count).ok())
+		.is_some_and(|count| count <= until)
+}
+
+fn userdeviceroom_key(user_id: &UserId, device_id: &DeviceId, room_id: &RoomId) -> Vec<u8> {
+	let mut key = user_id.as_bytes().to_vec();
+	key.push(0xFF);
+	key.extend_from_slice(device_id.as_bytes());
+	key.push(0xFF);
+	key.extend_from_slice(room_id.as_bytes());
+	key
+}
+
+/// Size of a log of `size` notifications after one is appended, or `None` when
+/// it is full and the oldest is dropped instead
+fn grown_log_size(size: u64) -> Option<u64> { (size < MAX_NOTIFICATIONS_PER_USER).then(|| size.saturating_add(1)) }
+
+#[cfg(test)]
+mod tests {
+	use std::{
+		cell::Cell,
+		collections::{BTreeMap, BTreeSet},
+	};
+
+	use conduit::Error;
+	use ruma::{owned_room_id, owned_user_id, room_id, user_id, OwnedRoomId, OwnedUserId};
+
+	use super::{
+		grown_log_size, is_read_up_to, roomuser_key, shared_rooms, unread_key, unread_prefix, userroom_key,
+		MAX_NOTIFICATIONS_PER_USER,
+	};
+
+	fn joined(rooms: &[(&str, &[&str])]) -> BTreeMap<OwnedUserId, BTreeSet<OwnedRoomId>> {
+		rooms
+			.iter()
+			.map(|(user_id, rooms)| {
+				let rooms = rooms
+					.iter()
+					.map(|room_id| (*room_id).try_into().unwrap())
+					.collect();
+				((*user_id).try_into().unwrap(), rooms)
+			})
+			.collect()
+	}
+
+	#[test]
+	fn shared_by_probing_shortest() {
+		let joined = joined(&[
+			("@alice:example.org", &["!a:x", "!b:x", "!c:x", "!d:x", "!e:x"]),
+			("@bob:example.org", &["!b:x", "!d:x"]),
+			("@carol:example.org", &["!a:x", "!b:x", "!d:x", "!e:x"]),
+		]);
+		let (scanned, probes) = (Cell::new(0_usize), Cell::new(0_usize));
+
+		let shared: Vec<_> = shared_rooms(
+			joined.keys().cloned().collect(),
+			|user_id| {
+				joined[user_id].iter().cloned().map(|room_id| {
+					scanned.set(scanned.get() + 1);
+					Ok(room_id)
+				})
+			},
+			|user_id, room_id| {
+				probes.set(probes.get() + 1);
+				Ok(joined[user_id].contains(room_id))
+			},
+		)
+		.collect::<Result<_, _>>()
+		.unwrap();
+
+		assert_eq!(shared, [owned_room_id!("!b:x"), owned_room_id!("!d:x")]);
+
+		// the lists are read in turns until bob's ends, then only bob's rooms
+		// are listed and probed for alice and carol
+		assert_eq!(scanned.get(), 3 + 2 + 2 + 2);
+		assert_eq!(probes.get(), 4);
+	}
+
+	#[test]
+	fn shared_rooms_errors_propagate() {
+		let joined = joined(&[
+			("@alice:example.org", &["!a:x", "!b:x"]),
+			("@bob:example.org", &["!a:x", "!b:x", "!c:x"]),
+		]);
+		let users = vec![owned_user_id!("@alice:example.org"), owned_user_id!("@bob:example.org")];
+
+		let results: Vec<_> = shared_rooms(
+			users.clone(),
+			|user_id| {
+				joined[user_id].iter().cloned().map(|room_id| {
+					if room_id == owned_room_id!("!a:x") {
+						Err(Error::bad_database("Invalid RoomId in userroomid_joined."))
+					} else {
+						Ok(room_id)
+					}
+				})
+			},
+			|user_id, room_id| Ok(joined[user_id].contains(room_id)),
+		)
+		.collect();
+		assert_eq!(results.len(), 2);
+		results[0].as_ref().unwrap_err();
+		assert_eq!(*results[1].as_ref().unwrap(), owned_room_id!("!b:x"));
+
+		let results: Vec<_> = shared_rooms(
+			users,
+			|user_id| joined[user_id].iter().cloned().map(Ok),
+			|_, _| Err(Error::bad_database("unreadable")),
+		)
+		.collect();
+		assert_eq!(results.len(), 2);
+		assert!(results.iter().all(Result::is_err));
+	}
+
+	#[test]
+	fn unread_index_is_scanned_up_to_the_receipt() {
+		let (alice, bob) = (user_id!("@alice:example.org"), user_id!("@bob:example.org"));
+		let (room, other) = (room_id!("!room:example.org"), room_id!("!room:example.org2"));
+
+		let index: BTreeMap<_, _> = [
+			(unread_key(alice, room, 3), 3),
+			(unread_key(alice, room, 300), 300),
+			(unread_key(alice, room, 7), 7),
+			(unread_key(alice, other, 5), 0),
+			(unread_key(bob, room, 5), 0),
+		]
+		.into_iter()
+		.collect();
+
+		let scan = |until| -> Vec<_> {
+			let prefix = unread_prefix(alice, room);
+			index
+				.range(prefix.clone()..)
+				.take_while(|(key, _)| key.starts_with(&prefix))
+				.take_while(|(key, _)| is_read_up_to(key, until))
+				.map(|(_, count)| *count)
+				.collect()
+		};
+
+		assert_eq!(scan(2), Vec::<u64>::new());
+		assert_eq!(scan(7), vec![3, 7]);
+		assert_eq!(scan(u64::MAX), vec![3, 7, 300]);
+	}
+
+	#[test]
+	fn forgotten_counts_read_zero_on_rejoin() {
+		let (alice, bob) = (user_id!("@alice:example.org"), user_id!("@bob:example.org"));
+		let (room, other) = (room_id!("!room:example.org"), room_id!("!room:example.org2"));
+
+		let mut counts: BTreeMap<_, u64> = [
+			(userroom_key(alice, room), 4),
+			(userroom_key(alice, other), 2),
+			(userroom_key(bob, room), 1),
+		]
+		.into_iter()
+		.collect();
+		let mut read: BTreeMap<_, u64> = [(roomuser_key(room, alice), 9), (roomuser_key(room, bob), 9)]
+			.into_iter()
+			.collect();
+		let mut unread: BTreeMap<_, ()> = [
+			(unread_key(alice, room, 3), ()),
+			(unread_key(alice, room, u64::MAX - 1), ()),
+			(unread_key(alice, other, 3), ()),
+			(unread_key(bob, room, 3), ()),
+		]
+		.into_iter()
+		.collect();
+
+		// what remove_notification_counts removes
+		counts.remove(&userroom_key(alice, room));
+		read.remove(&roomuser_key(room, alice));
+		let prefix = unread_prefix(alice, room);
+		unread.retain(|key, ()| !(key.starts_with(&prefix) && is_read_up_to(key, u64::MAX)));
+
+		let count = |user_id, room_id| {
+			counts
+				.get(&userroom_key(user_id, room_id))
+				.copied()
+				.unwrap_or(0)
+		};
+		assert_eq!(count(alice, room), 0);
+		assert!(!read.contains_key(&roomuser_key(room, alice)));
+		assert!(!unread.keys().any(|key| key.starts_with(&prefix)));
+
+		assert_eq!(count(alice, other), 2);
+		assert_eq!(count(bob, room), 1);
+		assert!(read.contains_key(&roomuser_key(room, bob)));
+		assert_eq!(unread.len(), 2);
+	}
+
+	#[test]
+	fn notification_log_is_capped() {
+		assert_eq!(grown_log_size(0), Some(1));
+		assert_eq!(
+			grown_log_size(MAX_NOTIFICATIONS_PER_USER.saturating_sub(1)),
+			Some(MAX_NOTIFICATIONS_PER_USER)
+		);
+		assert_eq!(grown_log_size(MAX_NOTIFICATIONS_PER_USER), None);
+	}
 }
@@ -1,16 +1,37 @@
 mod data;
 
-use std::sync::Arc;
+use std::{collections::HashMap, sync::Arc};
 
 use conduit::{Result, Server};
 use data::Data;
 use database::Database;
-use ruma::{OwnedRoomId, OwnedUserId, RoomId, UserId};
+use ruma::{
+	push::Action, DeviceId, MilliSecondsSinceUnixEpoch, OwnedEventId, OwnedRoomId, OwnedUserId, RoomId, UserId,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{services, PduCount, PduEvent};
 
 pub struct Service {
 	db: Data,
 }
 
+/// An event the user was notified of, as listed by `/notifications`
+#[derive(Deserialize, Serialize)]
+pub struct Notification {
+	pub room_id: OwnedRoomId,
+	pub event_id: OwnedEventId,
+
+	/// Count of the event in the timeline, which read receipts are compared
+	/// against
+	pub pdu_count: u64,
+
+	pub ts: MilliSecondsSinceUnixEpoch,
+	pub actions: Vec<Action>,
+	pub highlight: bool,
+	pub read: bool,
+}
+
 impl Service {
 	pub fn build(_server: &Arc<Server>, db: &Arc<Database>) -> Result<Self> {
 		Ok(Self {
@@ -18,8 +39,39 @@ impl Service {
 		})
 	}
 
-	pub fn reset_notification_counts(&self, user_id: &UserId, room_id: &RoomId) -> Result<()> {
-		self.db.reset_notification_counts(user_id, room_id)
+	/// Marks the room as read by the user up to the event at `read_up_to`; if
+	/// it had notifications, the badge on the user's devices is updated
+	/// through their pushers. Receipts older than the user's read marker, like
+	/// those of backfilled events, leave the counts alone.
+	pub fn reset_notification_counts(&self, user_id: &UserId, room_id: &RoomId, read_up_to: PduCount) -> Result<()> {
+		if !moves_read_marker(read_up_to, self.read_position(user_id, room_id)?) {
+			return Ok(());
+		}
+
+		let had_notifications = self.db.notification_count(user_id, room_id)? > 0;
+		self.db.reset_notification_counts(user_id, room_id)?;
+
+		if had_notifications {
+			services().pusher.schedule_badge_update(user_id);
+		}
+
+		Ok(())
+	}
+
+	/// The user's private read marker, which is moved by their read receipts
+	/// and their own events
+	fn read_position(&self, user_id: &UserId, room_id: &RoomId) -> Result<Option<PduCount>> {
+		Ok(services()
+			.rooms
+			.read_receipt
+			.private_read_get(room_id, user_id)?
+			.map(PduCount::Normal))
+	}
+
+	/// Removes the notification and highlight counts of the user in the room,
+	/// which start from zero again should they rejoin
+	pub fn remove_notification_counts(&self, user_id: &UserId, room_id: &RoomId) -> Result<()> {
+		self.db.remove_notification_counts(user_id, room_id)
 	}
 
 	pub fn notification_count(&self, user_id: &UserId, room_id: &RoomId) -> Result<u64> {
@@ -34,7 +86,44 @@ impl Service {
 		self.db.last_notification_read(user_id, room_id)
 	}
 
+	/// Records that the user was notified of the event, dropping their oldest
+	/// notifications beyond the limit kept per user.
+	pub fn add_notification(
+		&self, user_id: &UserId, pdu: &PduEvent, pdu_count: u64, actions: &[Action], highlight: bool,
+	) -> Result<()> {
+		self.db.add_notification(
+			user_id,
+			&Notification {
+				room_id: pdu.room_id.clone(),
+				event_id: (*pdu.event_id).to_owned(),
+				pdu_count,
+				ts: MilliSecondsSinceUnixEpoch::now(),
+				actions: actions.to_vec(),
+				highlight,
+				read: false,
+			},
+		)
+	}
+
+	/// Notifications of the user older than the `until` token, most recent
+	/// first, each with its token
+	pub fn notifications_until<'a>(
+		&'a self, user_id: &UserId, until: u64,
+	) -> impl Iterator<Item = Result<(u64, Notification)>> + 'a {
+		self.db.notifications_until(user_id, until)
+	}
+
+	/// Marks the notifications of the user for events in the room up to the
+	/// event with the given count as read.
+	pub fn mark_notifications_read(&self, user_id: &UserId, room_id: &RoomId, until: u64) -> Result<()> {
+		self.db.mark_notifications_read(user_id, room_id, until)
+	}
+
 	pub fn associate_token_shortstatehash(&self, room_id: &RoomId, token: u64, shortstatehash: u64) -> Result<()> {
+		if services().globals.read_only() {
+			return Ok(());
+		}
+
 		self.db
 			.associate_token_shortstatehash(room_id, token, shortstatehash)
 	}
@@ -43,7 +132,54 @@ impl Service {
 		self.db.get_token_shortstatehash(room_id, token)
 	}
 
+	/// Records that a size-capped initial sync only sent a placeholder for this
+	/// room, so the device's next sync has to deliver it in full.
+	pub fn mark_sync_deferred(&self, user_id: &UserId, device_id: &DeviceId, room_id: &RoomId) -> Result<()> {
+		if services().globals.read_only() {
+			return Ok(());
+		}
+
+		self.db.mark_sync_deferred(user_id, device_id, room_id)
+	}
+
+	/// Records that the device's sync with the token `next_batch` sent a
+	/// deferred room in full. The room is sent in full again until the device
+	/// syncs from that token, which shows the response was received.
+	pub fn mark_sync_delivered(
+		&self, user_id: &UserId, device_id: &DeviceId, room_id: &RoomId, next_batch: u64,
+	) -> Result<()> {
+		if services().globals.read_only() {
+			return Ok(());
+		}
+
+		self.db
+			.mark_sync_delivered(user_id, device_id, room_id, next_batch)
+	}
+
+	pub fn clear_sync_deferred(&self, user_id: &UserId, device_id: &DeviceId, room_id: &RoomId) -> Result<()> {
+		if services().globals.read_only() {
+			return Ok(());
+		}
+
+		self.db.clear_sync_deferred(user_id, device_id, room_id)
+	}
+
+	/// The rooms deferred for the device, read once per sync, with the token
+	/// of the sync which sent them in full if one did
+	pub fn sync_deferred_rooms(
+		&self, user_id: &UserId, device_id: &DeviceId,
+	) -> Result<HashMap<OwnedRoomId, Option<u64>>> {
+		self.db.sync_deferred_rooms(user_id, device_id)
+	}
+
 	pub fn get_shared_rooms(&self, users: Vec<OwnedUserId>) -> Result<impl Iterator<Item = Result<OwnedRoomId>> + '_> {
 		self.db.get_shared_rooms(users)
 	}
 }
+
+/// Whether a receipt at `read_up_to` is at or after the user's read marker,
+/// so it resets their counts. Backfilled events are older than any event the
+/// user could have read.
+pub(crate) fn moves_read_marker(read_up_to: PduCount, read: Option<PduCount>) -> bool {
+	read.map_or(true, |read| read_up_to >= read)
+}
@@ -13,6 +13,18 @@ use crate::{debug_error, services, utils, Error, Result};
 pub(crate) async fn send_request<T>(registration: Registration, request: T) -> Result<Option<T::IncomingResponse>>
 where
 	T: OutgoingRequest + Debug + Send,
+{
+	send_request_with(registration, request, |_| {}).await
+}
+
+/// Sends a request to an appservice after `edit` changed its body, for fields
+/// ruma does not know about
+pub(crate) async fn send_request_with<T, F>(
+	registration: Registration, request: T, edit: F,
+) -> Result<Option<T::IncomingResponse>>
+where
+	T: OutgoingRequest + Debug + Send,
+	F: FnOnce(&mut BytesMut) + Send,
 {
 	const VERSIONS: [MatrixVersion; 1] = [MatrixVersion::V1_0];
 
@@ -29,7 +41,10 @@ where
 			warn!("Failed to find destination {dest}: {e}");
 			Error::BadServerResponse("Invalid appservice destination")
 		})?
-		.map(BytesMut::freeze);
+		.map(|mut body| {
+			edit(&mut body);
+			body.freeze()
+		});
 
 	let mut parts = http_request.uri().clone().into_parts();
 	let old_path_and_query = parts.path_and_query.unwrap().as_str().to_owned();
@@ -0,0 +1,213 @@
+use std::{
+	collections::HashMap,
+	sync::Mutex,
+	time::{Duration, Instant, SystemTime},
+};
+
+use conduit::warn;
+use ruma::{OwnedServerName, ServerName};
+
+use crate::services;
+
+/// How long a capture keeps recording and its transactions are kept
+pub const CAPTURE_TTL: Duration = Duration::from_secs(60 * 60);
+
+/// Maximum number of transactions one capture records
+pub const MAX_CAPTURE_COUNT: usize = 50;
+
+/// Outgoing transactions recorded for debugging, per destination server.
+/// Captures only exist in memory and expire after `CAPTURE_TTL`, when the
+/// media uploaded with their dumps is deleted too.
+#[derive(Default)]
+pub struct Captures {
+	captures: Mutex<HashMap<OwnedServerName, Capture>>,
+}
+
+#[derive(Clone, Debug)]
+pub struct Capture {
+	started: Instant,
+
+	/// Number of transactions still to be recorded
+	pub remaining: usize,
+
+	pub transactions: Vec<CapturedTransaction>,
+
+	/// MXC URIs of the dumps uploaded for this capture
+	uploads: Vec<String>,
+}
+
+#[derive(Clone, Debug)]
+pub struct CapturedTransaction {
+	pub sent: SystemTime,
+	pub txn_id: String,
+
+	/// Body of the transaction as sent
+	pub request: String,
+
+	/// Response of the remote server, or the error sending to it
+	pub response: String,
+}
+
+impl Captures {
+	/// Starts recording the next `count` transactions sent to `server`,
+	/// replacing any previous capture for it.
+	pub fn start(&self, server: &ServerName, count: usize) {
+		let replaced = self.captures.lock().expect("locked").insert(
+			server.to_owned(),
+			Capture {
+				started: Instant::now(),
+				remaining: count.min(MAX_CAPTURE_COUNT),
+				transactions: Vec::new(),
+				uploads: Vec::new(),
+			},
+		);
+
+		if let Some(replaced) = replaced {
+			delete_uploads_after(Duration::ZERO, replaced.uploads);
+		}
+	}
+
+	/// Attaches the media a dump of the capture for `server` was uploaded
+	/// as, which is deleted once the capture expires
+	pub fn add_upload(&self, server: &ServerName, mxc: String) {
+		let mut captures = self.captures.lock().expect("locked");
+		let Some(capture) = captures
+			.get_mut(server)
+			.filter(|capture| !capture.is_expired())
+		else {
+			delete_uploads_after(Duration::ZERO, vec![mxc]);
+			return;
+		};
+
+		capture.uploads.push(mxc);
+		delete_uploads_after(capture.expires_in(), Vec::new());
+	}
+
+	/// Forgets the expired captures, returning the media uploaded for them
+	fn take_expired_uploads(&self) -> Vec<String> {
+		let mut uploads = Vec::new();
+		self.captures.lock().expect("locked").retain(|_, capture| {
+			let expired = capture.is_expired();
+			if expired {
+				uploads.append(&mut capture.uploads);
+			}
+
+			!expired
+		});
+
+		uploads
+	}
+
+	/// Forgets every capture, returning the media uploaded for them; called on
+	/// shutdown as captures do not outlive the process.
+	#[must_use]
+	pub fn take_uploads(&self) -> Vec<String> {
+		self.captures
+			.lock()
+			.expect("locked")
+			.drain()
+			.flat_map(|(_, capture)| capture.uploads)
+			.collect()
+	}
+
+	/// Whether the next transaction sent to `server` is to be recorded
+	#[must_use]
+	pub fn is_capturing(&self, server: &ServerName) -> bool {
+		self.captures
+			.lock()
+			.expect("locked")
+			.get(server)
+			.is_some_and(|capture| capture.remaining > 0 && !capture.is_expired())
+	}
+
+	pub fn record(&self, server: &ServerName, transaction: CapturedTransaction) {
+		let mut captures = self.captures.lock().expect("locked");
+		if let Some(capture) = captures
+			.get_mut(server)
+			.filter(|capture| capture.remaining > 0 && !capture.is_expired())
+		{
+			capture.remaining = capture.remaining.saturating_sub(1);
+			capture.transactions.push(transaction);
+		}
+	}
+
+	/// The capture for `server`, unless there is none or it expired
+	#[must_use]
+	pub fn get(&self, server: &ServerName) -> Option<Capture> {
+		self.captures
+			.lock()
+			.expect("locked")
+			.get(server)
+			.filter(|capture| !capture.is_expired())
+			.cloned()
+	}
+}
+
+impl Capture {
+	#[must_use]
+	pub fn expires_in(&self) -> Duration { CAPTURE_TTL.saturating_sub(self.started.elapsed()) }
+
+	fn is_expired(&self) -> bool { self.started.elapsed() >= CAPTURE_TTL }
+}
+
+/// Deletes `uploads` and the media of the captures expired by then, after
+/// `delay`
+fn delete_uploads_after(delay: Duration, uploads: Vec<String>) {
+	services().server.runtime().spawn(async move {
+		tokio::time::sleep(delay).await;
+
+		let expired = services().sending.captures.take_expired_uploads();
+		delete_uploads(uploads.into_iter().chain(expired)).await;
+	});
+}
+
+/// Deletes the media of capture dumps, logging failures
+pub async fn delete_uploads<I>(uploads: I)
+where
+	I: IntoIterator<Item = String>,
+{
+	for mxc in uploads {
+		if let Err(e) = services().media.delete(&mxc).await {
+			warn!("Failed to delete the capture dump {mxc}: {e}");
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::time::{Duration, Instant};
+
+	use ruma::owned_server_name;
+
+	use super::{Capture, Captures, CAPTURE_TTL};
+
+	fn capture(age: Duration, uploads: &[&str]) -> Capture {
+		Capture {
+			started: Instant::now().checked_sub(age).unwrap(),
+			remaining: 1,
+			transactions: Vec::new(),
+			uploads: uploads.iter().map(ToString::to_string).collect(),
+		}
+	}
+
+	#[test]
+	fn expired_uploads_are_taken() {
+		let captures = Captures::default();
+		captures.captures.lock().unwrap().extend([
+			(
+				owned_server_name!("expired.example"),
+				capture(CAPTURE_TTL, &["mxc://a/1", "mxc://a/2"]),
+			),
+			(owned_server_name!("live.example"), capture(Duration::ZERO, &["mxc://a/3"])),
+		]);
+
+		assert!(captures
+			.get(&owned_server_name!("expired.example"))
+			.is_none());
+		assert_eq!(captures.take_expired_uploads(), ["mxc://a/1", "mxc://a/2"]);
+		assert!(captures.take_expired_uploads().is_empty());
+
+		assert!(captures.get(&owned_server_name!("live.example")).is_some());
+		assert_eq!(captures.take_uploads(), ["mxc://a/3"]);
+	}
+}
@@ -2,7 +2,7 @@ use std::sync::Arc;
 
 use conduit::{utils, Error, Result};
 use database::{Database, Map};
-use ruma::{ServerName, UserId};
+use ruma::{OwnedUserId, ServerName, UserId};
 
 use super::{Destination, SendingEvent};
 use crate::services;
@@ -11,6 +11,7 @@ type OutgoingSendingIter<'a> = Box<dyn Iterator<Item = Result<(Vec<u8>, Destinat
 type SendingEventIter<'a> = Box<dyn Iterator<Item = Result<(Vec<u8>, SendingEvent)>> + 'a>;
 
 pub struct Data {
+	appserviceuserid_ephemeral: Arc<Map>,
 	servercurrentevent_data: Arc<Map>,
 	servernameevent_data: Arc<Map>,
 	servername_educount: Arc<Map>,
@@ -20,6 +21,7 @@ pub struct Data {
 impl Data {
 	pub(super) fn new(db: Arc<Database>) -> Self {
 		Self {
+			appserviceuserid_ephemeral: db["appserviceuserid_ephemeral"].clone(),
 			servercurrentevent_data: db["servercurrentevent_data"].clone(),
 			servernameevent_data: db["servernameevent_data"].clone(),
 			servername_educount: db["servername_educount"].clone(),
@@ -46,6 +48,25 @@ impl Data {
 
 	pub(super) fn delete_active_request(&self, key: &[u8]) -> Result<()> { self.servercurrentevent_data.remove(key) }
 
+	/// Push requests of all users, both in flight and queued
+	pub fn push_requests(&self) -> OutgoingSendingIter<'_> {
+		Box::new(
+			self.servercurrentevent_data
+				.scan_prefix(b"$".to_vec())
+				.chain(self.servernameevent_data.scan_prefix(b"$".to_vec()))
+				.map(|(key, v)| parse_servercurrentevent(&key, v).map(|(k, e)| (key, k, e))),
+		)
+	}
+
+	/// Push requests waiting for the previous request to the same pusher
+	pub(super) fn queued_push_requests(&self) -> OutgoingSendingIter<'_> {
+		Box::new(
+			self.servernameevent_data
+				.scan_prefix(b"$".to_vec())
+				.map(|(key, v)| parse_servercurrentevent(&key, v).map(|(k, e)| (key, k, e))),
+		)
+	}
+
 	pub(super) fn delete_all_active_requests_for(&self, destination: &Destination) -> Result<()> {
 		let prefix = destination.get_prefix();
 		for (key, _) in self.servercurrentevent_data.scan_prefix(prefix) {
@@ -91,6 +112,9 @@ impl Data {
 		Ok(keys)
 	}
 
+	/// Number of events queued for all destinations, besides the active ones
+	pub fn queued_total(&self) -> usize { self.servernameevent_data.iter().count() }
+
 	pub fn queued_requests<'a>(
 		&'a self, destination: &Destination,
 	) -> Box<dyn Iterator<Item = Result<(SendingEvent, Vec<u8>)>> + 'a> {
@@ -125,6 +149,61 @@ impl Data {
 			.insert(server_name.as_bytes(), &last_count.to_be_bytes())
 	}
 
+	/// Count up to which ephemeral data was queued for the appservice, `None`
+	/// before the first transaction with such data
+	pub fn get_latest_appservice_educount(&self, appservice_id: &str) -> Result<Option<u64>> {
+		self.servername_educount
+			.get(&appservice_educount_key(appservice_id))?
+			.map(|bytes| {
+				utils::u64_from_bytes(&bytes).map_err(|_| Error::bad_database("Invalid u64 in servername_educount."))
+			})
+			.transpose()
+	}
+
+	pub(super) fn set_latest_appservice_educount(&self, appservice_id: &str, last_count: u64) -> Result<()> {
+		self.servername_educount
+			.insert(&appservice_educount_key(appservice_id), &last_count.to_be_bytes())
+	}
+
+	/// Marks the ephemeral data of the user as pending for the appservice
+	pub(super) fn mark_ephemeral_pending(&self, appservice_id: &str, user_id: &UserId) -> Result<()> {
+		let count = services().globals.next_count()?;
+		self.appserviceuserid_ephemeral
+			.insert(&appservice_user_key(appservice_id, user_id), &count.to_be_bytes())
+	}
+
+	/// Users with ephemeral data pending for the appservice, with the count as
+	/// of which it is pending
+	pub(super) fn pending_ephemeral<'a>(
+		&'a self, appservice_id: &str,
+	) -> impl Iterator<Item = Result<(OwnedUserId, u64)>> + 'a {
+		let mut prefix = appservice_id.as_bytes().to_vec();
+		prefix.push(0xFF);
+		let prefix_len = prefix.len();
+
+		self.appserviceuserid_ephemeral
+			.scan_prefix(prefix)
+			.map(move |(key, value)| {
+				let user_id = utils::string_from_bytes(&key[prefix_len..])
+					.ok()
+					.and_then(|user_id| UserId::parse(user_id).ok())
+					.ok_or_else(|| Error::bad_database("Invalid user ID in appserviceuserid_ephemeral."))?;
+				let count = utils::u64_from_bytes(&value)
+					.map_err(|_| Error::bad_database("Invalid count in appserviceuserid_ephemeral."))?;
+
+				Ok((user_id, count))
+			})
+	}
+
+	pub(super) fn has_pending_ephemeral(&self, appservice_id: &str) -> bool {
+		self.pending_ephemeral(appservice_id).next().is_some()
+	}
+
+	pub(super) fn clear_pending_ephemeral(&self, appservice_id: &str, user_id: &UserId) -> Result<()> {
+		self.appserviceuserid_ephemeral
+			.remove(&appservice_user_key(appservice_id, user_id))
+	}
+
 	pub fn get_latest_educount(&self, server_name: &ServerName) -> Result<u64> {
 		self.servername_educount
 			.get(server_name.as_bytes())?
@@ -134,6 +213,21 @@ impl Data {
 	}
 }
 
+fn appservice_user_key(appservice_id: &str, user_id: &UserId) -> Vec<u8> {
+	let mut key = appservice_id.as_bytes().to_vec();
+	key.push(0xFF);
+	key.extend_from_slice(user_id.as_bytes());
+	key
+}
+
+/// Appservices are stored with a plus like in the request queues, which never
+/// starts a server name
+fn appservice_educount_key(appservice_id: &str) -> Vec<u8> {
+	let mut key = b"+".to_vec();
+	key.extend_from_slice(appservice_id.as_bytes());
+	key
+}
+
 #[tracing::instrument(skip(key))]
 fn parse_servercurrentevent(key: &[u8], value: Vec<u8>) -> Result<(Destination, SendingEvent)> {
 	// Appservices start with a plus
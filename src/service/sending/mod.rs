@@ -1,10 +1,13 @@
 mod appservice;
+pub mod capture;
 mod data;
+pub mod presence;
 pub mod resolve;
 mod send;
 mod sender;
+pub mod stats;
 
-use std::{fmt::Debug, sync::Arc};
+use std::{cmp, fmt::Debug, sync::Arc, time::Duration};
 
 use conduit::{Error, Result, Server};
 use data::Data;
@@ -28,6 +31,9 @@ pub struct Service {
 	handler_join: Mutex<Option<JoinHandle<()>>>,
 	startup_netburst: bool,
 	startup_netburst_keep: i64,
+	pub captures: capture::Captures,
+	pub stats: stats::DestinationStats,
+	pub presence: presence::PresenceBatches,
 }
 
 #[derive(Clone, Debug, PartialEq, Eq)]
@@ -63,6 +69,9 @@ impl Service {
 			handler_join: Mutex::new(None),
 			startup_netburst: config.startup_netburst,
 			startup_netburst_keep: config.startup_netburst_keep,
+			captures: capture::Captures::default(),
+			stats: stats::DestinationStats::default(),
+			presence: presence::PresenceBatches::default(),
 		}))
 	}
 
@@ -94,6 +103,18 @@ impl Service {
 		})
 	}
 
+	/// Sends a notification without an event to a pusher, updating the
+	/// unread count shown on the device. It is not persisted, so it is lost on
+	/// restart.
+	#[tracing::instrument(skip(self, user, pushkey))]
+	pub fn send_badge_push(&self, user: &UserId, pushkey: String) -> Result<()> {
+		self.dispatch(Msg {
+			dest: Destination::Push(user.to_owned(), pushkey),
+			event: SendingEvent::Flush,
+			queue_id: Vec::<u8>::new(),
+		})
+	}
+
 	#[tracing::instrument(skip(self))]
 	pub fn send_pdu_appservice(&self, appservice_id: String, pdu_id: Vec<u8>) -> Result<()> {
 		let dest = Destination::Appservice(appservice_id);
@@ -219,13 +240,40 @@ impl Service {
 		Ok(())
 	}
 
+	/// Makes the appservices send a transaction with the new ephemeral data of
+	/// the user, even without new events
+	#[tracing::instrument(skip(self, appservice_ids))]
+	pub fn flush_appservices<I: Iterator<Item = String>>(&self, appservice_ids: I, user_id: &UserId) -> Result<()> {
+		for id in appservice_ids {
+			self.db.mark_ephemeral_pending(&id, user_id)?;
+			self.dispatch(Msg {
+				dest: Destination::Appservice(id),
+				event: SendingEvent::Flush,
+				queue_id: Vec::<u8>::new(),
+			})?;
+		}
+
+		Ok(())
+	}
+
 	#[tracing::instrument(skip(self, request), name = "request")]
 	pub async fn send_federation_request<T>(&self, dest: &ServerName, request: T) -> Result<T::IncomingResponse>
 	where
 		T: OutgoingRequest + Debug + Send,
 	{
 		let client = &services().globals.client.federation;
-		send::send(client, dest, request).await
+		send::send(client, dest, request, true).await
+	}
+
+	/// Like `send_federation_request`, but never retries, for callers which
+	/// rather fail fast or measure the request
+	#[tracing::instrument(skip(self, request), name = "request")]
+	pub async fn send_federation_request_once<T>(&self, dest: &ServerName, request: T) -> Result<T::IncomingResponse>
+	where
+		T: OutgoingRequest + Debug + Send,
+	{
+		let client = &services().globals.client.federation;
+		send::send(client, dest, request, false).await
 	}
 
 	/// Sends a request to an appservice
@@ -258,6 +306,17 @@ impl Service {
 	}
 }
 
+/// Time to wait before sending to a destination again after `tries` failures
+/// in a row, growing quadratically from `sender_timeout` up to
+/// `sender_retry_backoff_limit`
+#[must_use]
+pub fn retry_backoff(tries: u32) -> Duration {
+	let config = &services().globals.config;
+	let max_duration = Duration::from_secs(config.sender_retry_backoff_limit);
+	let min_duration = Duration::from_secs(config.sender_timeout);
+	cmp::min(min_duration * tries * tries, max_duration)
+}
+
 impl Destination {
 	#[tracing::instrument(skip(self))]
 	pub fn get_prefix(&self) -> Vec<u8> {
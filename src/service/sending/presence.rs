@@ -0,0 +1,104 @@
+use std::{
+	collections::HashMap,
+	sync::Mutex,
+	time::{Duration, Instant, SystemTime},
+};
+
+use ruma::{OwnedServerName, ServerName};
+
+/// Outgoing presence batches per destination server. Presence updates are
+/// held back until the batch window since the last presence EDU to the server
+/// has passed, so updates within it are coalesced into one EDU carrying the
+/// latest state of each user. These are only kept in memory; after a restart
+/// presence resumes from the destination's EDU count.
+#[derive(Default)]
+pub struct PresenceBatches {
+	destinations: Mutex<HashMap<OwnedServerName, Batch>>,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Batch {
+	/// Count of the last presence update considered for the server
+	pub since: u64,
+
+	pub last_sent: Option<SystemTime>,
+
+	/// Number of users in the last presence EDU
+	pub last_size: usize,
+
+	/// Users sent presence of since startup
+	pub total: u64,
+
+	sent_at: Option<Instant>,
+	flush_scheduled: bool,
+}
+
+pub(super) enum Due {
+	/// A batch can be sent, starting after the given count
+	Now(u64),
+
+	/// The window is still open for the given duration
+	Later(Duration),
+}
+
+impl PresenceBatches {
+	/// Whether presence can be sent to `server` now; `since` is used for
+	/// servers no batch was sent to yet.
+	pub(super) fn due(&self, server: &ServerName, window: Duration, since: u64) -> Due {
+		let destinations = self.destinations.lock().expect("locked");
+		let Some(batch) = destinations.get(server) else {
+			return Due::Now(since);
+		};
+
+		match batch.sent_at.map(|sent_at| sent_at.elapsed()) {
+			Some(elapsed) if elapsed < window => Due::Later(window.saturating_sub(elapsed)),
+			_ => Due::Now(batch.since),
+		}
+	}
+
+	/// Records the presence EDU composed for `server`, which covered updates
+	/// up to `count`.
+	pub(super) fn record(&self, server: &ServerName, count: u64, size: usize) {
+		let mut destinations = self.destinations.lock().expect("locked");
+		let batch = destinations.entry(server.to_owned()).or_default();
+
+		batch.since = count;
+		if size > 0 {
+			batch.last_sent = Some(SystemTime::now());
+			batch.last_size = size;
+			batch.total = batch.total.saturating_add(size as u64);
+			batch.sent_at = Some(Instant::now());
+		}
+	}
+
+	/// Marks a flush of `server` as scheduled for the end of the window.
+	/// Returns false when one already is.
+	pub(super) fn schedule_flush(&self, server: &ServerName) -> bool {
+		let mut destinations = self.destinations.lock().expect("locked");
+		let batch = destinations.entry(server.to_owned()).or_default();
+
+		!std::mem::replace(&mut batch.flush_scheduled, true)
+	}
+
+	pub(super) fn flushed(&self, server: &ServerName) {
+		if let Some(batch) = self.destinations.lock().expect("locked").get_mut(server) {
+			batch.flush_scheduled = false;
+		}
+	}
+
+	/// Batches of every server presence was sent to, by server name
+	#[must_use]
+	pub fn all(&self) -> Vec<(OwnedServerName, Batch)> {
+		let mut all: Vec<_> = self
+			.destinations
+			.lock()
+			.expect("locked")
+			.iter()
+			.filter(|(_, batch)| batch.last_sent.is_some())
+			.map(|(server, batch)| (server.clone(), batch.clone()))
+			.collect();
+
+		all.sort_by(|(a, _), (b, _)| a.cmp(b));
+		all
+	}
+}
@@ -1,7 +1,8 @@
-use std::{fmt::Debug, mem};
+use std::{fmt::Debug, mem, time::Duration};
 
 use http::{header::AUTHORIZATION, HeaderValue};
 use ipaddress::IPAddress;
+use rand::Rng;
 use reqwest::{Client, Method, Request, Response, Url};
 use ruma::{
 	api::{
@@ -15,8 +16,18 @@ use tracing::{debug, trace};
 use super::{resolve, resolve::ActualDest};
 use crate::{debug_error, debug_warn, services, Error, Result};
 
+/// Attempts of a request which may be retried, the first one included
+const RETRY_ATTEMPTS: u32 = 3;
+
+/// Delay before the first retry, doubled for each further one and with up to
+/// as much random jitter added
+const RETRY_BASE_DELAY: Duration = Duration::from_millis(250);
+
+/// Sends a request to another server. With `retry`, GET requests failing to
+/// connect or with a server error are attempted again; other requests may not
+/// be idempotent, and transactions and joins are retried by their callers.
 #[tracing::instrument(skip_all, name = "send")]
-pub async fn send<T>(client: &Client, dest: &ServerName, req: T) -> Result<T::IncomingResponse>
+pub async fn send<T>(client: &Client, dest: &ServerName, req: T, retry: bool) -> Result<T::IncomingResponse>
 where
 	T: OutgoingRequest + Debug + Send,
 {
@@ -25,8 +36,49 @@ where
 	}
 
 	let actual = resolve::get_actual_dest(dest).await?;
-	let request = prepare::<T>(dest, &actual, req).await?;
-	execute::<T>(client, dest, &actual, request).await
+	let mut request = prepare::<T>(dest, &actual, req).await?;
+	let attempts = if retry && T::METADATA.method == Method::GET {
+		RETRY_ATTEMPTS
+	} else {
+		1
+	};
+
+	let mut attempt = 1;
+	loop {
+		let next = if attempt < attempts {
+			request.try_clone()
+		} else {
+			None
+		};
+
+		let result = execute::<T>(client, dest, &actual, request).await;
+		let Some(next) = next.filter(|_| result.as_ref().is_err_and(is_transient)) else {
+			return result;
+		};
+
+		let delay = retry_delay(attempt);
+		debug_warn!("Retrying request to {dest} in {delay:?} after attempt {attempt} failed");
+		services().sending.stats.record_retry(dest);
+		tokio::time::sleep(delay).await;
+
+		request = next;
+		attempt = attempt.saturating_add(1);
+	}
+}
+
+/// Whether the request may succeed when sent again
+fn is_transient(e: &Error) -> bool {
+	match e {
+		Error::Reqwest(e) => e.is_connect(),
+		Error::Federation(_, e) => e.status_code.is_server_error(),
+		_ => false,
+	}
+}
+
+fn retry_delay(attempt: u32) -> Duration {
+	let delay = RETRY_BASE_DELAY.saturating_mul(2_u32.saturating_pow(attempt.saturating_sub(1)));
+	let jitter = rand::thread_rng().gen_range(Duration::ZERO..=delay);
+	delay.saturating_add(jitter)
 }
 
 async fn execute<T>(
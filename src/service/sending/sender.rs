@@ -1,12 +1,13 @@
 use std::{
 	cmp,
-	collections::{BTreeMap, HashMap, HashSet},
+	collections::{BTreeMap, BTreeSet, HashMap, HashSet},
 	fmt::Debug,
 	sync::Arc,
-	time::{Duration, Instant},
+	time::{Duration, Instant, SystemTime},
 };
 
 use base64::{engine::general_purpose, Engine as _};
+use bytes::{BufMut, BytesMut};
 use federation::transactions::send_transaction_message;
 use futures_util::{future::BoxFuture, stream::FuturesUnordered, StreamExt};
 use ruma::{
@@ -18,12 +19,19 @@ use ruma::{
 	},
 	device_id,
 	events::{push_rules::PushRulesEvent, receipt::ReceiptType, AnySyncEphemeralRoomEvent, GlobalAccountDataEventType},
-	push, uint, MilliSecondsSinceUnixEpoch, OwnedServerName, OwnedUserId, RoomId, ServerName, UInt,
+	push,
+	serde::Raw,
+	uint, DeviceKeyAlgorithm, MilliSecondsSinceUnixEpoch, OwnedDeviceId, OwnedServerName, OwnedUserId, RoomId,
+	ServerName, UInt, UserId,
 };
+use serde::{Deserialize, Serialize};
 use tracing::{debug, error, warn};
 
-use super::{appservice, send, Destination, Msg, SendingEvent, Service};
-use crate::{presence::Presence, services, user_is_local, utils::calculate_hash, Error, PduEvent, Result};
+use super::{appservice, capture::CapturedTransaction, presence::Due, send, Destination, Msg, SendingEvent, Service};
+use crate::{
+	appservice::RegistrationInfo, presence::Presence, pusher, services, user_is_local, utils::calculate_hash, Error,
+	PduEvent, Result,
+};
 
 #[derive(Debug)]
 enum TransactionStatus {
@@ -40,6 +48,30 @@ type CurTransactionStatus = HashMap<Destination, TransactionStatus>;
 
 const DEQUEUE_LIMIT: usize = 48;
 const SELECT_EDU_LIMIT: usize = 16;
+const SELECT_APPSERVICE_TO_DEVICE_LIMIT: usize = 100;
+
+/// Ephemeral data for an appservice, queued as `SendingEvent::Edu` with its
+/// transaction
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum AppserviceEdu {
+	/// To-device message with its recipient and its count in the recipient's
+	/// inbox, where it stays until the appservice acknowledged it (MSC2409)
+	ToDevice {
+		user_id: OwnedUserId,
+		device_id: OwnedDeviceId,
+		count: u64,
+		event: serde_json::Value,
+	},
+
+	/// Users whose devices changed (MSC3202)
+	DeviceLists {
+		changed: Vec<OwnedUserId>,
+	},
+
+	/// One-time key counts by user and device (MSC3202)
+	OneTimeKeysCount(BTreeMap<OwnedUserId, BTreeMap<OwnedDeviceId, BTreeMap<DeviceKeyAlgorithm, UInt>>>),
+}
 
 impl Service {
 	pub async fn start_handler(self: &Arc<Self>) {
@@ -65,7 +97,7 @@ impl Service {
 			debug_assert!(!receiver.is_closed(), "channel error");
 			tokio::select! {
 				request = receiver.recv_async() => match request {
-					Ok(request) => self.handle_request(request, &futures, &mut statuses),
+					Ok(request) => self.handle_request(request, &futures, &mut statuses).await,
 					Err(_) => return Ok(()),
 				},
 				Some(response) = futures.next() => {
@@ -122,12 +154,32 @@ impl Service {
 			futures.push(Box::pin(send_events(dest.clone(), new_events_vec)));
 		} else {
 			statuses.remove(dest);
+
+			// ephemeral data which arrived during the transaction or did not fit in it
+			if let Destination::Appservice(id) = dest {
+				if self.db.has_pending_ephemeral(id) {
+					let msg = Msg {
+						dest: dest.clone(),
+						event: SendingEvent::Flush,
+						queue_id: Vec::new(),
+					};
+
+					if let Err(e) = self.dispatch(msg) {
+						warn!("Failed to flush ephemeral data to appservice {id}: {e}");
+					}
+				}
+			}
 		}
 	}
 
-	fn handle_request(&self, msg: Msg, futures: &SendingFutures<'_>, statuses: &mut CurTransactionStatus) {
+	async fn handle_request(&self, msg: Msg, futures: &SendingFutures<'_>, statuses: &mut CurTransactionStatus) {
 		let iv = vec![(msg.event, msg.queue_id)];
-		if let Ok(Some(events)) = self.select_events(&msg.dest, iv, statuses) {
+		let appservices = services().appservice.read().await;
+		let appservice = match &msg.dest {
+			Destination::Appservice(id) => appservices.get(id),
+			_ => None,
+		};
+		if let Ok(Some(events)) = self.select_events(&msg.dest, iv, statuses, &appservices, appservice) {
 			if !events.is_empty() {
 				futures.push(Box::pin(send_events(msg.dest, events)));
 			} else {
@@ -141,7 +193,8 @@ impl Service {
 		let mut txns = HashMap::<Destination, Vec<SendingEvent>>::new();
 		for (key, dest, event) in self.db.active_requests().filter_map(Result::ok) {
 			let entry = txns.entry(dest.clone()).or_default();
-			if self.startup_netburst_keep >= 0 && entry.len() >= keep {
+			// pushes are kept until the gateway acknowledged them
+			if self.startup_netburst_keep >= 0 && entry.len() >= keep && !matches!(dest, Destination::Push(..)) {
 				warn!("Dropping unsent event {:?} {:?}", dest, String::from_utf8_lossy(&key));
 				self.db
 					.delete_active_request(&key)
@@ -151,8 +204,25 @@ impl Service {
 			}
 		}
 
+		// Pushes which were still queued behind another request to their pusher
+		let mut queued = HashMap::<Destination, Vec<(SendingEvent, Vec<u8>)>>::new();
+		for (key, dest, event) in self.db.queued_push_requests().filter_map(Result::ok) {
+			if !txns.contains_key(&dest) {
+				let entry = queued.entry(dest).or_default();
+				if entry.len() < DEQUEUE_LIMIT {
+					entry.push((event, key));
+				}
+			}
+		}
+
+		for (dest, events) in queued {
+			self.db.mark_as_active(&events).expect("marked as active");
+			txns.insert(dest, events.into_iter().map(|(event, _)| event).collect());
+		}
+
 		for (dest, events) in txns {
-			if self.startup_netburst && !events.is_empty() {
+			let resume = self.startup_netburst || matches!(dest, Destination::Push(..));
+			if resume && !events.is_empty() {
 				statuses.insert(dest.clone(), TransactionStatus::Running);
 				futures.push(Box::pin(send_events(dest.clone(), events)));
 			}
@@ -165,6 +235,8 @@ impl Service {
 		dest: &Destination,
 		new_events: Vec<(SendingEvent, Vec<u8>)>, // Events we want to send: event and full key
 		statuses: &mut CurTransactionStatus,
+		appservices: &BTreeMap<String, RegistrationInfo>, // Registered appservices
+		appservice: Option<&RegistrationInfo>,            // Registration of the destination appservice
 	) -> Result<Option<Vec<SendingEvent>>> {
 		let (allow, retry) = self.select_events_current(dest.clone(), statuses)?;
 
@@ -191,13 +263,46 @@ impl Service {
 		if !new_events.is_empty() {
 			self.db.mark_as_active(&new_events)?;
 			for (e, _) in new_events {
-				events.push(e);
+				// appservices are only flushed for their ephemeral data below
+				if !matches!((dest, &e), (Destination::Appservice(_), SendingEvent::Flush)) {
+					events.push(e);
+				}
+			}
+		}
+
+		// Ephemeral data is persisted with the transaction, so it is retried with
+		// it until the appservice acknowledged it
+		if let (Destination::Appservice(id), Some(info)) = (dest, appservice) {
+			let (edus, last_count) = if self.db.has_pending_ephemeral(id) {
+				self.select_edus_appservice(id, info)?
+			} else {
+				(Vec::new(), None)
+			};
+
+			if !edus.is_empty() {
+				let requests: Vec<_> = edus
+					.into_iter()
+					.map(|edu| (dest, SendingEvent::Edu(edu)))
+					.collect();
+				let keys = self.db.queue_requests(&requests)?;
+				let requests: Vec<_> = requests
+					.into_iter()
+					.map(|(_, event)| event)
+					.zip(keys)
+					.collect();
+
+				self.db.mark_as_active(&requests)?;
+				events.extend(requests.into_iter().map(|(event, _)| event));
+			}
+
+			if let Some(last_count) = last_count {
+				self.db.set_latest_appservice_educount(id, last_count)?;
 			}
 		}
 
 		// Add EDU's into the transaction
 		if let Destination::Normal(server_name) = dest {
-			if let Ok((select_edus, last_count)) = self.select_edus(server_name) {
+			if let Ok((select_edus, last_count)) = self.select_edus(server_name, appservices) {
 				events.extend(select_edus.into_iter().map(SendingEvent::Edu));
 				self.db.set_latest_educount(server_name, last_count)?;
 			}
@@ -214,11 +319,7 @@ impl Service {
 			.and_modify(|e| match e {
 				TransactionStatus::Failed(tries, time) => {
 					// Fail if a request has failed recently (exponential backoff)
-					let max_duration = Duration::from_secs(services().globals.config.sender_retry_backoff_limit);
-					let min_duration = Duration::from_secs(services().globals.config.sender_timeout);
-					let min_elapsed_duration = min_duration * (*tries) * (*tries);
-					let min_elapsed_duration = cmp::min(min_elapsed_duration, max_duration);
-					if time.elapsed() < min_elapsed_duration {
+					if time.elapsed() < super::retry_backoff(*tries) {
 						allow = false;
 					} else {
 						retry = true;
@@ -235,7 +336,9 @@ impl Service {
 	}
 
 	#[tracing::instrument(skip_all)]
-	fn select_edus(&self, server_name: &ServerName) -> Result<(Vec<Vec<u8>>, u64)> {
+	fn select_edus(
+		&self, server_name: &ServerName, appservices: &BTreeMap<String, RegistrationInfo>,
+	) -> Result<(Vec<Vec<u8>>, u64)> {
 		// u64: count of last edu
 		let since = self.db.get_latest_educount(server_name)?;
 		let mut events = Vec::new();
@@ -254,7 +357,7 @@ impl Service {
 			);
 
 			if services().globals.allow_outgoing_read_receipts()
-				&& !select_edus_receipts(&room_id, since, &mut max_edu_count, &mut events)?
+				&& !select_edus_receipts(&room_id, since, &mut max_edu_count, &mut events, appservices)?
 			{
 				break;
 			}
@@ -276,61 +379,318 @@ impl Service {
 			events.push(serde_json::to_vec(&edu).expect("json can be serialized"));
 		}
 
-		if services().globals.allow_outgoing_presence() {
-			select_edus_presence(server_name, since, &mut max_edu_count, &mut events)?;
+		if services().globals.allow_outgoing_presence() && presence_allowed_to(server_name) {
+			self.select_edus_presence(server_name, since, &mut max_edu_count, &mut events, appservices)?;
 		}
 
 		Ok((events, max_edu_count))
 	}
-}
 
-/// Look for presence
-fn select_edus_presence(
-	server_name: &ServerName, since: u64, max_edu_count: &mut u64, events: &mut Vec<Vec<u8>>,
-) -> Result<bool> {
-	// Look for presence updates for this server
-	let mut presence_updates = Vec::new();
-	for (user_id, count, presence_bytes) in services().presence.presence_since(since) {
-		*max_edu_count = cmp::max(count, *max_edu_count);
+	/// Ephemeral data of the users marked pending for the appservice since the
+	/// last transaction: to-device messages with MSC2409, device list changes
+	/// and one-time key counts with MSC3202. Returns the count to continue
+	/// from, `None` if the appservice opted in to neither.
+	#[tracing::instrument(skip(self, info))]
+	fn select_edus_appservice(&self, id: &str, info: &RegistrationInfo) -> Result<(Vec<Vec<u8>>, Option<u64>)> {
+		let pending: Vec<_> = self
+			.db
+			.pending_ephemeral(id)
+			.filter_map(Result::ok)
+			.collect();
 
-		if !user_is_local(&user_id) {
-			continue;
+		if !info.options.receive_ephemeral && !info.options.msc3202 {
+			for (user_id, _) in &pending {
+				self.db.clear_pending_ephemeral(id, user_id)?;
+			}
+
+			return Ok((Vec::new(), None));
+		}
+
+		let since = self.db.get_latest_appservice_educount(id)?;
+		let selected_at = services().globals.current_count()?;
+		let mut last_count = selected_at;
+
+		// only users in the namespace receive their to-device messages and key
+		// counts, device list changes are also sent for the users they share a
+		// room with
+		let users: Vec<_> = pending
+			.iter()
+			.map(|(user_id, _)| user_id)
+			.filter(|user_id| user_is_local(user_id) && info.is_user_match(user_id))
+			.collect();
+
+		// users with more data than fits in this transaction stay pending
+		let mut remaining = HashSet::new();
+
+		let mut edus = Vec::new();
+		if info.options.receive_ephemeral {
+			let mut to_device = Vec::new();
+			let mut capped = None;
+			for user_id in &users {
+				for device_id in services()
+					.users
+					.all_device_ids(user_id)
+					.filter_map(Result::ok)
+				{
+					let events = services().users.get_to_device_events_after(
+						user_id,
+						&device_id,
+						since.unwrap_or(0),
+						SELECT_APPSERVICE_TO_DEVICE_LIMIT,
+					)?;
+
+					// the messages of this device after the last one fetched are unseen, so
+					// the next selection must continue from below them
+					if events.len() >= SELECT_APPSERVICE_TO_DEVICE_LIMIT {
+						remaining.insert((*user_id).clone());
+						if let Some((count, _)) = events.last() {
+							capped = Some(capped.map_or(*count, |capped: u64| capped.min(*count)));
+						}
+					}
+
+					to_device.extend(
+						events
+							.into_iter()
+							.map(|(count, event)| (count, ((*user_id).clone(), device_id.clone(), event))),
+					);
+				}
+			}
+
+			// the rest follows with the next transaction
+			let (rest, next_count) = split_to_device(&mut to_device, capped, selected_at);
+			last_count = next_count;
+			remaining.extend(rest.into_iter().map(|(user_id, ..)| user_id));
+
+			for (count, (user_id, device_id, event)) in to_device {
+				let event = serde_json::from_str(event.json().get())
+					.map_err(|_| Error::bad_database("Event in todeviceid_events is invalid."))?;
+
+				edus.push(
+					serde_json::to_vec(&AppserviceEdu::ToDevice {
+						user_id,
+						device_id,
+						count,
+						event,
+					})
+					.expect("json can be serialized"),
+				);
+			}
 		}
 
-		if !services()
+		if info.options.msc3202 {
+			// device lists are only tracked from the first transaction on; changes up
+			// to the selection are sent, repeating some when to-device messages were
+			// left for the next transaction rather than missing them
+			if let Some(since) = since {
+				let changed: Vec<_> = pending
+					.iter()
+					.map(|(user_id, _)| user_id)
+					.filter(|user_id| {
+						services()
+							.users
+							.keys_changed(user_id.as_str(), since, Some(selected_at))
+							.filter_map(Result::ok)
+							.next()
+							.is_some()
+					})
+					.cloned()
+					.collect();
+
+				if !changed.is_empty() {
+					edus.push(
+						serde_json::to_vec(&AppserviceEdu::DeviceLists {
+							changed,
+						})
+						.expect("json can be serialized"),
+					);
+				}
+			}
+
+			let mut counts = BTreeMap::new();
+			for user_id in &users {
+				if let Some(since) = since {
+					if services().users.last_one_time_keys_update(user_id)? <= since {
+						continue;
+					}
+				}
+
+				let mut devices = BTreeMap::new();
+				for device_id in services()
+					.users
+					.all_device_ids(user_id)
+					.filter_map(Result::ok)
+				{
+					let count = services().users.count_one_time_keys(user_id, &device_id)?;
+					devices.insert(device_id, count);
+				}
+
+				if !devices.is_empty() {
+					counts.insert((*user_id).clone(), devices);
+				}
+			}
+
+			if !counts.is_empty() {
+				edus.push(
+					serde_json::to_vec(&AppserviceEdu::OneTimeKeysCount(counts)).expect("json can be serialized"),
+				);
+			}
+		}
+
+		// users marked again since the selection started are kept for the next one
+		for (user_id, marked) in &pending {
+			if *marked <= selected_at && !remaining.contains(user_id) {
+				self.db.clear_pending_ephemeral(id, user_id)?;
+			}
+		}
+
+		Ok((edus, Some(last_count)))
+	}
+
+	/// Look for presence, unless the batch window for this server is still
+	/// open. Since only the latest presence of each user is stored, updates
+	/// within the window are coalesced.
+	fn select_edus_presence(
+		&self, server_name: &ServerName, since: u64, max_edu_count: &mut u64, events: &mut Vec<Vec<u8>>,
+		appservices: &BTreeMap<String, RegistrationInfo>,
+	) -> Result<bool> {
+		let window = Duration::from_millis(
+			services()
+				.globals
+				.config
+				.presence_federation_batch_window_ms,
+		);
+		let since = match self.presence.due(server_name, window, since) {
+			Due::Now(since) => since,
+			Due::Later(remaining) => {
+				self.flush_presence_after(server_name, remaining);
+				return Ok(true);
+			},
+		};
+
+		// Look for presence updates for this server
+		let mut presence_updates = Vec::new();
+		let mut last_count = since;
+		let mut limited = false;
+		for (user_id, count, presence_bytes) in services().presence.presence_since(since) {
+			if presence_updates.len() >= SELECT_EDU_LIMIT {
+				limited = true;
+				break;
+			}
+
+			last_count = cmp::max(count, last_count);
+			*max_edu_count = cmp::max(count, *max_edu_count);
+
+			if !user_is_local(&user_id) || is_suppressed(appservices, &user_id) {
+				continue;
+			}
+
+			if !server_sees_presence(server_name, &user_id)? {
+				continue;
+			}
+
+			let presence_event = Presence::from_json_bytes_to_event(&presence_bytes, &user_id)?;
+			presence_updates.push(PresenceUpdate {
+				user_id,
+				presence: presence_event.content.presence,
+				currently_active: presence_event.content.currently_active.unwrap_or(false),
+				last_active_ago: presence_event
+					.content
+					.last_active_ago
+					.unwrap_or_else(|| uint!(0)),
+				status_msg: presence_event.content.status_msg,
+			});
+		}
+
+		self.presence
+			.record(server_name, last_count, presence_updates.len());
+
+		// The rest goes out with the next batch
+		if limited {
+			self.flush_presence_after(server_name, window);
+		}
+
+		if presence_updates.is_empty() {
+			return Ok(true);
+		}
+
+		let presence_content = Edu::Presence(PresenceContent::new(presence_updates));
+		events.push(serde_json::to_vec(&presence_content).expect("PresenceEvent can be serialized"));
+
+		Ok(true)
+	}
+
+	/// Flushes `server_name` once the presence batch window closed, so held
+	/// back presence is sent even without other traffic to the server.
+	fn flush_presence_after(&self, server_name: &ServerName, delay: Duration) {
+		if !self.presence.schedule_flush(server_name) {
+			return;
+		}
+
+		let server_name = server_name.to_owned();
+		services().server.runtime().spawn(async move {
+			tokio::time::sleep(delay).await;
+			services().sending.presence.flushed(&server_name);
+			if let Err(e) = services()
+				.sending
+				.flush_servers(std::iter::once(server_name))
+			{
+				debug!("Failed to flush presence: {e}");
+			}
+		});
+	}
+}
+
+/// Whether outgoing presence to `server_name` is permitted by the allowlist
+fn presence_allowed_to(server_name: &ServerName) -> bool {
+	let allowlist = &services().globals.config.presence_federation_allowlist;
+	allowlist.is_empty() || allowlist.iter().any(|allowed| allowed == server_name)
+}
+
+/// Whether `server_name` shares a room with `user_id` it is to receive their
+/// presence through, considering the room size limit
+fn server_sees_presence(server_name: &ServerName, user_id: &UserId) -> Result<bool> {
+	let Some(max_members) = services()
+		.globals
+		.config
+		.presence_federation_max_room_members
+	else {
+		return services()
 			.rooms
 			.state_cache
-			.server_sees_user(server_name, &user_id)?
-		{
+			.server_sees_user(server_name, user_id);
+	};
+
+	for room_id in services().rooms.state_cache.server_rooms(server_name) {
+		let room_id = room_id?;
+		if !services().rooms.state_cache.is_joined(user_id, &room_id)? {
 			continue;
 		}
 
-		let presence_event = Presence::from_json_bytes_to_event(&presence_bytes, &user_id)?;
-		presence_updates.push(PresenceUpdate {
-			user_id,
-			presence: presence_event.content.presence,
-			currently_active: presence_event.content.currently_active.unwrap_or(false),
-			last_active_ago: presence_event
-				.content
-				.last_active_ago
-				.unwrap_or_else(|| uint!(0)),
-			status_msg: presence_event.content.status_msg,
-		});
-
-		if presence_updates.len() >= SELECT_EDU_LIMIT {
-			break;
+		if services()
+			.rooms
+			.state_cache
+			.room_joined_count(&room_id)?
+			.is_some_and(|count| count <= max_members)
+		{
+			return Ok(true);
 		}
 	}
 
-	let presence_content = Edu::Presence(PresenceContent::new(presence_updates));
-	events.push(serde_json::to_vec(&presence_content).expect("PresenceEvent can be serialized"));
+	Ok(false)
+}
 
-	Ok(true)
+/// Whether the EDUs of a local user are kept from federation by their
+/// appservice's registration
+fn is_suppressed(appservices: &BTreeMap<String, RegistrationInfo>, user_id: &UserId) -> bool {
+	appservices
+		.values()
+		.any(|info| info.suppresses_ephemeral_federation(user_id))
 }
 
 /// Look for read receipts in this room
 fn select_edus_receipts(
 	room_id: &RoomId, since: u64, max_edu_count: &mut u64, events: &mut Vec<Vec<u8>>,
+	appservices: &BTreeMap<String, RegistrationInfo>,
 ) -> Result<bool> {
 	for r in services()
 		.rooms
@@ -340,7 +700,7 @@ fn select_edus_receipts(
 		let (user_id, count, read_receipt) = r?;
 		*max_edu_count = cmp::max(count, *max_edu_count);
 
-		if !user_is_local(&user_id) {
+		if !user_is_local(&user_id) || is_suppressed(appservices, &user_id) {
 			continue;
 		}
 
@@ -405,63 +765,178 @@ async fn send_events(dest: Destination, events: Vec<SendingEvent>) -> SendingRes
 
 #[tracing::instrument(skip(dest, events))]
 async fn send_events_dest_appservice(dest: &Destination, id: &str, events: Vec<SendingEvent>) -> SendingResult {
-	let mut pdu_jsons = Vec::new();
+	let info = services()
+		.appservice
+		.get_registration_info(id)
+		.await
+		.ok_or_else(|| {
+			(
+				dest.clone(),
+				Error::bad_database("[Appservice] Could not load registration from db."),
+			)
+		})?;
+
+	// Large batches are split into several transactions so no single request
+	// exceeds what the appservice is configured to accept.
+	for chunk in events.chunks(info.transaction_max_events()) {
+		let mut pdu_jsons = Vec::with_capacity(chunk.len());
+		let mut ephemeral = AppserviceEphemeral::default();
+		let mut delivered = HashMap::new();
+		for event in chunk {
+			match event {
+				SendingEvent::Pdu(pdu_id) => {
+					pdu_jsons.push(
+						services()
+							.rooms
+							.timeline
+							.get_pdu_from_id(pdu_id)
+							.map_err(|e| (dest.clone(), e))?
+							.ok_or_else(|| {
+								(
+									dest.clone(),
+									Error::bad_database("[Appservice] Event in servernameevent_data not found in db."),
+								)
+							})?
+							.to_room_event(),
+					);
+				},
+				SendingEvent::Edu(edu) => ephemeral
+					.add(edu, &mut delivered)
+					.map_err(|e| (dest.clone(), e))?,
+				SendingEvent::Flush => {
+					// flush only; no new content
+				},
+			}
+		}
 
-	for event in &events {
-		match event {
-			SendingEvent::Pdu(pdu_id) => {
-				pdu_jsons.push(
-					services()
-						.rooms
-						.timeline
-						.get_pdu_from_id(pdu_id)
-						.map_err(|e| (dest.clone(), e))?
-						.ok_or_else(|| {
-							(
-								dest.clone(),
-								Error::bad_database("[Appservice] Event in servernameevent_data not found in db."),
-							)
-						})?
-						.to_room_event(),
-				);
+		//debug_assert!(!pdu_jsons.is_empty(), "sending empty transaction");
+		appservice::send_request_with(
+			info.registration.clone(),
+			ruma::api::appservice::event::push_events::v1::Request {
+				events: pdu_jsons,
+				txn_id: (&*general_purpose::URL_SAFE_NO_PAD.encode(calculate_hash(
+					&chunk
+						.iter()
+						.map(|e| match e {
+							SendingEvent::Edu(b) | SendingEvent::Pdu(b) => &**b,
+							SendingEvent::Flush => &[],
+						})
+						.collect::<Vec<_>>(),
+				)))
+					.into(),
+			},
+			|body| ephemeral.write_into(body),
+		)
+		.await
+		.map_err(|e| (dest.clone(), e))?;
+
+		// acknowledged by the appservice, so its devices don't sync them again
+		for ((user_id, device_id), count) in delivered {
+			services()
+				.users
+				.remove_to_device_events(&user_id, &device_id, count)
+				.map_err(|e| (dest.clone(), e))?;
+		}
+	}
+
+	Ok(dest.clone())
+}
+
+/// Keeps the to-device messages selected for one appservice transaction,
+/// sorted by count, and returns the others with the count the next selection
+/// continues from. That count is below the first message not fetched of each
+/// device which had more than the limit and below the messages which arrived
+/// after the selection started, so every message after it is selected again.
+fn split_to_device<T>(to_device: &mut Vec<(u64, T)>, capped: Option<u64>, selected_at: u64) -> (Vec<T>, u64) {
+	to_device.sort_by_key(|(count, _)| *count);
+
+	let mut last_count = capped.map_or(selected_at, |capped| capped.min(selected_at));
+	let mut split = to_device.partition_point(|(count, _)| *count <= last_count);
+	if split > SELECT_APPSERVICE_TO_DEVICE_LIMIT {
+		split = SELECT_APPSERVICE_TO_DEVICE_LIMIT;
+		last_count = to_device[split - 1].0;
+	}
+
+	let rest = to_device.drain(split..).map(|(_, rest)| rest).collect();
+	(rest, last_count)
+}
+
+/// Ephemeral data of one appservice transaction, added to the body under
+/// both the unstable and the stable field names
+#[derive(Default)]
+struct AppserviceEphemeral {
+	to_device: Vec<serde_json::Value>,
+	changed: BTreeSet<OwnedUserId>,
+	counts: BTreeMap<OwnedUserId, BTreeMap<OwnedDeviceId, BTreeMap<DeviceKeyAlgorithm, UInt>>>,
+}
+
+impl AppserviceEphemeral {
+	/// Adds an EDU from the queue, recording the last count of the to-device
+	/// messages of each device in `delivered`
+	fn add(&mut self, edu: &[u8], delivered: &mut HashMap<(OwnedUserId, OwnedDeviceId), u64>) -> Result<()> {
+		match serde_json::from_slice(edu)
+			.map_err(|_| Error::bad_database("Invalid appservice EDU in sending queue."))?
+		{
+			AppserviceEdu::ToDevice {
+				user_id,
+				device_id,
+				count,
+				mut event,
+			} => {
+				if let serde_json::Value::Object(event) = &mut event {
+					event.insert("to_user_id".to_owned(), user_id.as_str().into());
+					event.insert("to_device_id".to_owned(), device_id.as_str().into());
+				}
+
+				self.to_device.push(event);
+				let last = delivered.entry((user_id, device_id)).or_default();
+				*last = (*last).max(count);
 			},
-			SendingEvent::Edu(_) | SendingEvent::Flush => {
-				// Appservices don't need EDUs (?) and flush only;
-				// no new content
+			AppserviceEdu::DeviceLists {
+				changed,
+			} => self.changed.extend(changed),
+			AppserviceEdu::OneTimeKeysCount(counts) => {
+				for (user_id, devices) in counts {
+					self.counts.entry(user_id).or_default().extend(devices);
+				}
 			},
 		}
+
+		Ok(())
 	}
 
-	//debug_assert!(!pdu_jsons.is_empty(), "sending empty transaction");
-	match appservice::send_request(
-		services()
-			.appservice
-			.get_registration(id)
-			.await
-			.ok_or_else(|| {
-				(
-					dest.clone(),
-					Error::bad_database("[Appservice] Could not load registration from db."),
-				)
-			})?,
-		ruma::api::appservice::event::push_events::v1::Request {
-			events: pdu_jsons,
-			txn_id: (&*general_purpose::URL_SAFE_NO_PAD.encode(calculate_hash(
-				&events
-					.iter()
-					.map(|e| match e {
-						SendingEvent::Edu(b) | SendingEvent::Pdu(b) => &**b,
-						SendingEvent::Flush => &[],
-					})
-					.collect::<Vec<_>>(),
-			)))
-				.into(),
-		},
-	)
-	.await
-	{
-		Ok(_) => Ok(dest.clone()),
-		Err(e) => Err((dest.clone(), e)),
+	fn write_into(self, body: &mut BytesMut) {
+		if self.to_device.is_empty() && self.changed.is_empty() && self.counts.is_empty() {
+			return;
+		}
+
+		let Ok(serde_json::Value::Object(mut json)) = serde_json::from_slice(&body[..]) else {
+			return;
+		};
+
+		if !self.to_device.is_empty() {
+			let to_device = serde_json::Value::from(self.to_device);
+			json.insert("de.sorunome.msc2409.to_device".to_owned(), to_device.clone());
+			json.insert("to_device".to_owned(), to_device);
+		}
+
+		if !self.changed.is_empty() {
+			let device_lists = serde_json::json!({
+				"changed": self.changed,
+				"left": [],
+			});
+			json.insert("org.matrix.msc3202.device_lists".to_owned(), device_lists.clone());
+			json.insert("device_lists".to_owned(), device_lists);
+		}
+
+		if !self.counts.is_empty() {
+			let counts = serde_json::to_value(self.counts).expect("json can be serialized");
+			json.insert("org.matrix.msc3202.device_one_time_keys_count".to_owned(), counts.clone());
+			json.insert("device_one_time_keys_count".to_owned(), counts);
+		}
+
+		body.clear();
+		serde_json::to_writer((&mut *body).writer(), &json).expect("json can be serialized");
 	}
 }
 
@@ -470,11 +945,13 @@ async fn send_events_dest_push(
 	dest: &Destination, userid: &OwnedUserId, pushkey: &str, events: Vec<SendingEvent>,
 ) -> SendingResult {
 	let mut pdus = Vec::new();
+	let mut badge = false;
 
 	for event in &events {
 		match event {
 			SendingEvent::Pdu(pdu_id) => {
-				pdus.push(
+				pdus.push((
+					pdu_id,
 					services()
 						.rooms
 						.timeline
@@ -486,57 +963,111 @@ async fn send_events_dest_push(
 								Error::bad_database("[Push] Event in servernameevent_data not found in db."),
 							)
 						})?,
-				);
+				));
+			},
+			SendingEvent::Edu(_) => {
+				// Push gateways don't need EDUs (?)
 			},
-			SendingEvent::Edu(_) | SendingEvent::Flush => {
-				// Push gateways don't need EDUs (?) and flush only;
-				// no new content
+			SendingEvent::Flush => {
+				// No new content; only the unread count changed
+				badge = true;
 			},
 		}
 	}
 
-	for pdu in pdus {
-		// Redacted events are not notification targets (we don't send push for them)
-		if let Some(unsigned) = &pdu.unsigned {
-			if let Ok(unsigned) = serde_json::from_str::<serde_json::Value>(unsigned.get()) {
-				if unsigned.get("redacted_because").is_some() {
-					continue;
+	for (pdu_id, pdu) in pdus {
+		// Each push is forgotten once handled, so retries and restarts do not
+		// notify twice for the pushes which went through before a failure
+		let mut key = dest.get_prefix();
+		key.extend_from_slice(pdu_id);
+
+		send_push_pdu(dest, userid, pushkey, &pdu).await?;
+		services()
+			.sending
+			.db
+			.delete_active_request(&key)
+			.map_err(|e| (dest.clone(), e))?;
+	}
+
+	if badge {
+		let pusher = services()
+			.pusher
+			.get_pusher(userid, pushkey)
+			.map_err(|e| (dest.clone(), e))?;
+
+		if let Some(pusher) = pusher {
+			let unread = services()
+				.pusher
+				.total_unread(userid)
+				.map_err(|e| (dest.clone(), e))?;
+
+			if let Err(e) = services().pusher.send_badge(userid, unread, &pusher).await {
+				if !pusher::Service::is_rejection(&e) {
+					return Err((dest.clone(), e));
 				}
 			}
 		}
+	}
 
-		let Some(pusher) = services()
-			.pusher
-			.get_pusher(userid, pushkey)
-			.map_err(|e| (dest.clone(), e))?
-		else {
-			continue;
-		};
+	Ok(dest.clone())
+}
 
-		let rules_for_user = services()
-			.account_data
-			.get(None, userid, GlobalAccountDataEventType::PushRules.to_string().into())
-			.unwrap_or_default()
-			.and_then(|event| serde_json::from_str::<PushRulesEvent>(event.get()).ok())
-			.map_or_else(|| push::Ruleset::server_default(userid), |ev: PushRulesEvent| ev.content.global);
+/// Sends the push for one event, unless it was redacted or the pusher is gone
+/// or disabled. Rejected pushes are dropped.
+async fn send_push_pdu(dest: &Destination, userid: &UserId, pushkey: &str, pdu: &PduEvent) -> Result<(), SendingError> {
+	// Redacted events are not notification targets (we don't send push for them)
+	if let Some(unsigned) = &pdu.unsigned {
+		if let Ok(unsigned) = serde_json::from_str::<serde_json::Value>(unsigned.get()) {
+			if unsigned.get("redacted_because").is_some() {
+				return Ok(());
+			}
+		}
+	}
 
-		let unread: UInt = services()
-			.rooms
-			.user
-			.notification_count(userid, &pdu.room_id)
-			.map_err(|e| (dest.clone(), e))?
-			.try_into()
-			.expect("notification count can't go that high");
+	let Some(pusher) = services()
+		.pusher
+		.get_pusher(userid, pushkey)
+		.map_err(|e| (dest.clone(), e))?
+	else {
+		return Ok(());
+	};
+
+	if services()
+		.pusher
+		.is_disabled(userid, pushkey)
+		.map_err(|e| (dest.clone(), e))?
+	{
+		return Ok(());
+	}
 
-		let _response = services()
-			.pusher
-			.send_push_notice(userid, unread, &pusher, rules_for_user, &pdu)
-			.await
-			.map(|_response| dest.clone())
-			.map_err(|e| (dest.clone(), e));
+	let rules_for_user = services()
+		.account_data
+		.get(None, userid, GlobalAccountDataEventType::PushRules.to_string().into())
+		.unwrap_or_default()
+		.and_then(|event| serde_json::from_str::<PushRulesEvent>(event.get()).ok())
+		.map_or_else(|| push::Ruleset::server_default(userid), |ev: PushRulesEvent| ev.content.global);
+
+	let unread: UInt = services()
+		.rooms
+		.user
+		.notification_count(userid, &pdu.room_id)
+		.map_err(|e| (dest.clone(), e))?
+		.try_into()
+		.expect("notification count can't go that high");
+
+	// Rejected notifications are dropped; on other errors the remaining
+	// events are retried with backoff
+	if let Err(e) = services()
+		.pusher
+		.send_push_notice(userid, unread, &pusher, rules_for_user, pdu)
+		.await
+	{
+		if !pusher::Service::is_rejection(&e) {
+			return Err((dest.clone(), e));
+		}
 	}
 
-	Ok(dest.clone())
+	Ok(())
 }
 
 #[tracing::instrument(skip(dest, events), name = "")]
@@ -577,37 +1108,124 @@ async fn send_events_dest_normal(
 		}
 	}
 
+	let pdu_count = pdu_jsons.len();
+	let edu_types: Vec<String> = edu_jsons
+		.iter()
+		.filter_map(|edu: &Raw<Edu>| edu.get_field("edu_type").ok().flatten())
+		.collect();
+
 	let client = &services().globals.client.sender;
 	//debug_assert!(pdu_jsons.len() + edu_jsons.len() > 0, "sending empty
 	// transaction");
-	send::send(
-		client,
-		server,
-		send_transaction_message::v1::Request {
-			origin: services().globals.server_name().to_owned(),
-			pdus: pdu_jsons,
-			edus: edu_jsons,
-			origin_server_ts: MilliSecondsSinceUnixEpoch::now(),
-			transaction_id: (&*general_purpose::URL_SAFE_NO_PAD.encode(calculate_hash(
-				&events
-					.iter()
-					.map(|e| match e {
-						SendingEvent::Edu(b) | SendingEvent::Pdu(b) => &**b,
-						SendingEvent::Flush => &[],
-					})
-					.collect::<Vec<_>>(),
-			)))
-				.into(),
-		},
-	)
-	.await
-	.map(|response| {
-		for pdu in response.pdus {
-			if pdu.1.is_err() {
-				warn!("error for {} from remote: {:?}", pdu.0, pdu.1);
+	let request = send_transaction_message::v1::Request {
+		origin: services().globals.server_name().to_owned(),
+		pdus: pdu_jsons,
+		edus: edu_jsons,
+		origin_server_ts: MilliSecondsSinceUnixEpoch::now(),
+		transaction_id: (&*general_purpose::URL_SAFE_NO_PAD.encode(calculate_hash(
+			&events
+				.iter()
+				.map(|e| match e {
+					SendingEvent::Edu(b) | SendingEvent::Pdu(b) => &**b,
+					SendingEvent::Flush => &[],
+				})
+				.collect::<Vec<_>>(),
+		)))
+			.into(),
+	};
+
+	let capture = services()
+		.sending
+		.captures
+		.is_capturing(server)
+		.then(|| capture_request(&request));
+
+	let response = send::send(client, server, request, false).await;
+
+	if let Some((txn_id, request)) = capture {
+		services().sending.captures.record(
+			server,
+			CapturedTransaction {
+				sent: SystemTime::now(),
+				txn_id,
+				request,
+				response: match &response {
+					Ok(response) => format!("{:#?}", response.pdus),
+					Err(e) => format!("error: {e}"),
+				},
+			},
+		);
+	}
+
+	match &response {
+		Ok(_) => services()
+			.sending
+			.stats
+			.record_success(server, pdu_count, &edu_types),
+		Err(e) => services().sending.stats.record_error(server, e.to_string()),
+	}
+
+	response
+		.map(|response| {
+			for pdu in response.pdus {
+				if pdu.1.is_err() {
+					warn!("error for {} from remote: {:?}", pdu.0, pdu.1);
+				}
 			}
-		}
-		dest.clone()
-	})
-	.map_err(|e| (dest.clone(), e))
+			dest.clone()
+		})
+		.map_err(|e| (dest.clone(), e))
+}
+
+/// Transaction ID and body of a transaction, as recorded by a capture
+fn capture_request(request: &send_transaction_message::v1::Request) -> (String, String) {
+	let body = serde_json::json!({
+		"origin": request.origin,
+		"origin_server_ts": request.origin_server_ts,
+		"pdus": request.pdus,
+		"edus": request.edus,
+	});
+
+	(
+		request.transaction_id.to_string(),
+		serde_json::to_string_pretty(&body).unwrap_or_default(),
+	)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{split_to_device, SELECT_APPSERVICE_TO_DEVICE_LIMIT};
+
+	#[test]
+	fn to_device_continues_below_capped_device() {
+		// the device with 'a' had more messages after count 4 which were not fetched
+		let mut to_device = vec![(7, 'b'), (2, 'a'), (4, 'a'), (3, 'b'), (9, 'c')];
+		let (rest, last_count) = split_to_device(&mut to_device, Some(4), 20);
+
+		assert_eq!(last_count, 4);
+		assert_eq!(to_device, vec![(2, 'a'), (3, 'b'), (4, 'a')]);
+		assert_eq!(rest, vec!['b', 'c']);
+	}
+
+	#[test]
+	fn to_device_after_selection_is_left() {
+		let mut to_device = vec![(5, 'a'), (12, 'b')];
+		let (rest, last_count) = split_to_device(&mut to_device, None, 10);
+
+		assert_eq!(last_count, 10);
+		assert_eq!(to_device, vec![(5, 'a')]);
+		assert_eq!(rest, vec!['b']);
+	}
+
+	#[test]
+	fn to_device_limited_per_transaction() {
+		let total = SELECT_APPSERVICE_TO_DEVICE_LIMIT as u64 + 5;
+		let mut to_device: Vec<_> = (1..=total).rev().map(|count| (count, count)).collect();
+		let (rest, last_count) = split_to_device(&mut to_device, None, total);
+
+		assert_eq!(to_device.len(), SELECT_APPSERVICE_TO_DEVICE_LIMIT);
+		assert_eq!(last_count, SELECT_APPSERVICE_TO_DEVICE_LIMIT as u64);
+		assert_eq!(rest.first(), Some(&(last_count + 1)));
+		assert_eq!(rest.len(), 5);
+	}
 }
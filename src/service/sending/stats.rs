@@ -0,0 +1,69 @@
+use std::{
+	collections::{BTreeMap, HashMap},
+	sync::Mutex,
+	time::SystemTime,
+};
+
+use ruma::{OwnedServerName, ServerName};
+
+/// Outcome of the transactions sent to each destination server since
+/// startup. These are only kept in memory.
+#[derive(Default)]
+pub struct DestinationStats {
+	destinations: Mutex<HashMap<OwnedServerName, ServerStats>>,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct ServerStats {
+	pub transactions: u64,
+	pub pdus: u64,
+
+	/// EDUs successfully sent, by type
+	pub edus: BTreeMap<String, u64>,
+
+	/// Requests sent again after failing transiently
+	pub retries: u64,
+
+	pub last_success: Option<SystemTime>,
+	pub last_error: Option<(SystemTime, String)>,
+}
+
+impl DestinationStats {
+	pub fn record_success(&self, server: &ServerName, pdus: usize, edu_types: &[String]) {
+		let mut destinations = self.destinations.lock().expect("locked");
+		let stats = destinations.entry(server.to_owned()).or_default();
+
+		stats.transactions = stats.transactions.saturating_add(1);
+		stats.pdus = stats.pdus.saturating_add(pdus as u64);
+		for edu_type in edu_types {
+			let count = stats.edus.entry(edu_type.clone()).or_default();
+			*count = count.saturating_add(1);
+		}
+
+		stats.last_success = Some(SystemTime::now());
+	}
+
+	pub fn record_error(&self, server: &ServerName, error: String) {
+		self.destinations
+			.lock()
+			.expect("locked")
+			.entry(server.to_owned())
+			.or_default()
+			.last_error = Some((SystemTime::now(), error));
+	}
+
+	pub fn record_retry(&self, server: &ServerName) {
+		let mut destinations = self.destinations.lock().expect("locked");
+		let stats = destinations.entry(server.to_owned()).or_default();
+		stats.retries = stats.retries.saturating_add(1);
+	}
+
+	#[must_use]
+	pub fn get(&self, server: &ServerName) -> Option<ServerStats> {
+		self.destinations
+			.lock()
+			.expect("locked")
+			.get(server)
+			.cloned()
+	}
+}
@@ -2,17 +2,18 @@ use std::sync::Arc;
 
 use conduit::{debug_info, Result, Server};
 use database::Database;
-use tracing::{debug, info, trace};
+use tracing::{debug, info, trace, warn};
 
 use crate::{
-	account_data, admin, appservice, globals, key_backups, media, presence, pusher, rooms, sending, transaction_ids,
-	uiaa, users,
+	account_data, admin, appservice, globals, key_backups, media, presence, pusher, rate_limit, rooms, sending,
+	transaction_ids, uiaa, users,
 };
 
 pub struct Services {
 	pub rooms: rooms::Service,
 	pub appservice: appservice::Service,
 	pub pusher: pusher::Service,
+	pub rate_limit: rate_limit::Service,
 	pub transaction_ids: transaction_ids::Service,
 	pub uiaa: uiaa::Service,
 	pub users: users::Service,
@@ -54,6 +55,7 @@ impl Services {
 			},
 			appservice: appservice::Service::build(&server, &db)?,
 			pusher: pusher::Service::build(&server, &db)?,
+			rate_limit: rate_limit::Service::build(&server, &db)?,
 			transaction_ids: transaction_ids::Service::build(&server, &db)?,
 			uiaa: uiaa::Service::build(&server, &db)?,
 			users: users::Service::build(&server, &db)?,
@@ -106,11 +108,15 @@ impl Services {
 			.lock()
 			.await
 			.len();
+		let room_type_cache = self.rooms.metadata.room_type_cache.lock().unwrap().len();
 		let resolver_overrides_cache = self.globals.resolver.overrides.read().unwrap().len();
 		let resolver_destinations_cache = self.globals.resolver.destinations.read().await.len();
 		let bad_event_ratelimiter = self.globals.bad_event_ratelimiter.read().await.len();
 		let bad_query_ratelimiter = self.globals.bad_query_ratelimiter.read().await.len();
 		let bad_signature_ratelimiter = self.globals.bad_signature_ratelimiter.read().await.len();
+		let ignored_users_cache = self.account_data.ignored_users_cache.read().unwrap().len();
+		let remote_alias_cache = self.rooms.alias.remote_cache.all().len();
+		let remote_profiles = self.users.profiles.len();
 
 		format!(
 			"\
@@ -120,11 +126,15 @@ user_visibility_cache: {user_visibility_cache}
 stateinfo_cache: {stateinfo_cache}
 lasttimelinecount_cache: {lasttimelinecount_cache}
 roomid_spacehierarchy_cache: {roomid_spacehierarchy_cache}
+room_type_cache: {room_type_cache}
 resolver_overrides_cache: {resolver_overrides_cache}
 resolver_destinations_cache: {resolver_destinations_cache}
 bad_event_ratelimiter: {bad_event_ratelimiter}
 bad_query_ratelimiter: {bad_query_ratelimiter}
 bad_signature_ratelimiter: {bad_signature_ratelimiter}
+ignored_users_cache: {ignored_users_cache}
+remote_alias_cache: {remote_alias_cache}
+remote_profiles: {remote_profiles}
 "
 		)
 	}
@@ -194,6 +204,22 @@ bad_signature_ratelimiter: {bad_signature_ratelimiter}
 		if amount > 10 {
 			self.globals.bad_signature_ratelimiter.write().await.clear();
 		}
+		if amount > 11 {
+			self.account_data
+				.ignored_users_cache
+				.write()
+				.unwrap()
+				.clear();
+		}
+		if amount > 12 {
+			self.rooms.alias.remote_cache.clear();
+		}
+		if amount > 13 {
+			self.users.profiles.clear();
+		}
+		if amount > 14 {
+			self.rooms.metadata.room_type_cache.lock().unwrap().clear();
+		}
 	}
 
 	pub async fn start(&self) -> Result<()> {
@@ -201,15 +227,35 @@ bad_signature_ratelimiter: {bad_signature_ratelimiter}
 
 		self.media.create_media_dir().await?;
 		globals::migrations::migrations(&self.db, &self.globals.config).await?;
-		globals::emerg_access::init_emergency_access();
+		if self.globals.read_only() {
+			warn!("Database opened read-only; federation, outgoing requests and writes are disabled.");
+		} else {
+			globals::emerg_access::init_emergency_access();
+		}
 
 		self.admin.start_handler().await;
-		self.sending.start_handler().await;
-		if self.globals.config.allow_local_presence {
+		if !self.globals.read_only() {
+			self.sending.start_handler().await;
+		}
+		if self.globals.config.allow_local_presence && !self.globals.read_only() {
 			self.presence.start_handler().await;
 		}
+		if self.globals.config.media_pregenerate_thumbnails && !self.globals.read_only() {
+			self.media.start_thumbnail_handler().await;
+		}
+		if !self.globals.read_only() {
+			self.media.start_tiering_handler().await;
+			self.rooms.lazy_loading.start_expiry_handler().await;
+		}
+
+		let handle = globals::counter::start_counter_sampling_task();
+
+		#[allow(clippy::let_underscore_must_use)] // needed for shutdown
+		{
+			_ = self.globals.counter_handle.lock().await.insert(handle);
+		}
 
-		if self.globals.allow_check_for_updates() {
+		if self.globals.allow_check_for_updates() && !self.globals.read_only() {
 			let handle = globals::updates::start_check_for_updates_task();
 
 			#[allow(clippy::let_underscore_must_use)] // needed for shutdown
@@ -218,6 +264,31 @@ bad_signature_ratelimiter: {bad_signature_ratelimiter}
 			}
 		}
 
+		if let Some(max_age_days) = self.globals.config.guest_account_max_age_days {
+			if !self.globals.read_only() {
+				let handle = users::guests::start_guest_expiry_task(max_age_days);
+
+				#[allow(clippy::let_underscore_must_use)] // needed for shutdown
+				{
+					_ = self.users.guest_expiry_handle.lock().await.insert(handle);
+				}
+			}
+		}
+
+		if !self.globals.read_only() {
+			let handle = users::verification::start_verification_timeout_task();
+
+			#[allow(clippy::let_underscore_must_use)] // needed for shutdown
+			{
+				_ = self
+					.users
+					.verification_timeout_handle
+					.lock()
+					.await
+					.insert(handle);
+			}
+		}
+
 		debug_info!("Services startup complete.");
 		Ok(())
 	}
@@ -227,6 +298,7 @@ bad_signature_ratelimiter: {bad_signature_ratelimiter}
 		self.sending.interrupt();
 		self.presence.interrupt();
 		self.admin.interrupt();
+		self.media.interrupt();
 
 		trace!("Services interrupt complete.");
 	}
@@ -245,12 +317,51 @@ bad_signature_ratelimiter: {bad_signature_ratelimiter}
 			}
 		}
 
+		debug!("Waiting for counter sampling worker...");
+		if let Some(counter_handle) = self.globals.counter_handle.lock().await.take() {
+			counter_handle.abort();
+
+			#[allow(clippy::let_underscore_must_use)]
+			{
+				_ = counter_handle.await;
+			}
+		}
+
+		debug!("Waiting for guest expiry worker...");
+		if let Some(guest_expiry_handle) = self.users.guest_expiry_handle.lock().await.take() {
+			guest_expiry_handle.abort();
+
+			#[allow(clippy::let_underscore_must_use)]
+			{
+				_ = guest_expiry_handle.await;
+			}
+		}
+
+		debug!("Waiting for verification timeout worker...");
+		if let Some(verification_timeout_handle) = self.users.verification_timeout_handle.lock().await.take() {
+			verification_timeout_handle.abort();
+
+			#[allow(clippy::let_underscore_must_use)]
+			{
+				_ = verification_timeout_handle.await;
+			}
+		}
+
+		debug!("Removing federation capture dumps...");
+		sending::capture::delete_uploads(self.sending.captures.take_uploads()).await;
+
 		debug!("Waiting for admin worker...");
 		self.admin.close().await;
 
 		debug!("Waiting for presence worker...");
 		self.presence.close().await;
 
+		debug!("Waiting for thumbnail worker...");
+		self.media.close().await;
+
+		debug!("Waiting for lazy-loading expiry worker...");
+		self.rooms.lazy_loading.close().await;
+
 		debug!("Waiting for sender...");
 		self.sending.close().await;
 
@@ -83,4 +83,24 @@ impl Data {
 		)
 		.map_err(|_| Error::bad_database("UiaaInfo in userdeviceid_uiaainfo is invalid."))
 	}
+
+	/// Removes the UIAA sessions and pending requests of a device
+	pub(super) fn remove_device_sessions(&self, user_id: &UserId, device_id: &DeviceId) -> Result<()> {
+		self.db
+			.userdevicesessionid_uiaarequest
+			.write()
+			.unwrap()
+			.retain(|(user, device, _), _| user != user_id || device != device_id);
+
+		let mut prefix = user_id.as_bytes().to_vec();
+		prefix.push(0xFF);
+		prefix.extend_from_slice(device_id.as_bytes());
+		prefix.push(0xFF);
+
+		for (key, _) in self.userdevicesessionid_uiaainfo.scan_prefix(prefix) {
+			self.userdevicesessionid_uiaainfo.remove(&key)?;
+		}
+
+		Ok(())
+	}
 }
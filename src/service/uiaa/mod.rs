@@ -1,4 +1,5 @@
 mod data;
+mod registration_tokens;
 
 use std::sync::Arc;
 
@@ -14,18 +15,21 @@ use ruma::{
 };
 use tracing::error;
 
+pub use self::registration_tokens::{RegistrationToken, RegistrationTokens, REGISTRATION_TOKEN_LENGTH};
 use crate::services;
 
 pub const SESSION_ID_LENGTH: usize = 32;
 
 pub struct Service {
 	pub db: Data,
+	pub registration_tokens: RegistrationTokens,
 }
 
 impl Service {
 	pub fn build(_server: &Arc<Server>, db: &Arc<Database>) -> Result<Self> {
 		Ok(Self {
 			db: Data::new(db),
+			registration_tokens: RegistrationTokens::new(db),
 		})
 	}
 
@@ -103,7 +107,8 @@ impl Service {
 				uiaainfo.completed.push(AuthType::Password);
 			},
 			AuthData::RegistrationToken(t) => {
-				if Some(t.token.trim()) == services().globals.config.registration_token.as_deref() {
+				// the use is counted once the account is created
+				if self.registration_tokens.is_valid(t.token.trim())? {
 					uiaainfo.completed.push(AuthType::RegistrationToken);
 				} else {
 					uiaainfo.auth_error = Some(ruma::api::client::error::StandardErrorBody {
@@ -157,4 +162,9 @@ impl Service {
 	) -> Option<CanonicalJsonValue> {
 		self.db.get_uiaa_request(user_id, device_id, session)
 	}
+
+	/// Drops the UIAA sessions of a device, e.g. when it logs out
+	pub fn remove_device_sessions(&self, user_id: &UserId, device_id: &DeviceId) -> Result<()> {
+		self.db.remove_device_sessions(user_id, device_id)
+	}
 }
@@ -0,0 +1,192 @@
+use std::{
+	sync::{Arc, Mutex},
+	time::Duration,
+};
+
+use conduit::{utils, Error, Result};
+use database::{Database, Map};
+use ruma::UserId;
+use serde::{Deserialize, Serialize};
+
+use crate::services;
+
+/// Length of the generated registration tokens
+pub const REGISTRATION_TOKEN_LENGTH: usize = 16;
+
+/// Registration tokens managed by admins, in addition to the
+/// `registration_token` config option which is always valid with unlimited
+/// uses. Once any token exists, registration requires one.
+pub struct RegistrationTokens {
+	registrationtoken_info: Arc<Map>,
+	userid_registrationtoken: Arc<Map>,
+
+	/// Held while a token's uses are counted, so concurrent registrations do
+	/// not lose a use
+	use_mutex: Mutex<()>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct RegistrationToken {
+	/// Number of registrations allowed, unlimited if unset
+	pub uses_allowed: Option<u64>,
+
+	/// Number of registrations the token was used for
+	pub completed: u64,
+
+	/// When the token stops being valid, in milliseconds since the epoch
+	pub expiry_time: Option<u64>,
+
+	pub created: u64,
+}
+
+impl RegistrationToken {
+	#[must_use]
+	pub fn is_valid(&self) -> bool {
+		!self
+			.uses_allowed
+			.is_some_and(|allowed| self.completed >= allowed)
+			&& !self
+				.expiry_time
+				.is_some_and(|expiry| utils::millis_since_unix_epoch() >= expiry)
+	}
+}
+
+impl RegistrationTokens {
+	pub(super) fn new(db: &Arc<Database>) -> Self {
+		Self {
+			registrationtoken_info: db["registrationtoken_info"].clone(),
+			userid_registrationtoken: db["userid_registrationtoken"].clone(),
+			use_mutex: Mutex::new(()),
+		}
+	}
+
+	/// Creates a registration token, a random one unless `token` is given.
+	pub fn create(
+		&self, token: Option<String>, uses_allowed: Option<u64>, expires_in: Option<Duration>,
+	) -> Result<String> {
+		let token = token.unwrap_or_else(|| utils::random_string(REGISTRATION_TOKEN_LENGTH));
+		if token.is_empty()
+			|| token.len() > 64
+			|| !token
+				.chars()
+				.all(|c| c.is_ascii_alphanumeric() || "._~-".contains(c))
+		{
+			return Err(Error::Err(
+				"Registration tokens must be 1 to 64 characters of A-Z, a-z, 0-9, '.', '_', '~' and '-'.".to_owned(),
+			));
+		}
+
+		if self.get(&token)?.is_some() || services().globals.registration_token().as_ref() == Some(&token) {
+			return Err(Error::Err("This registration token already exists.".to_owned()));
+		}
+
+		let created = utils::millis_since_unix_epoch();
+		let expiry_time =
+			expires_in.map(|expires_in| created.saturating_add(expires_in.as_millis().try_into().unwrap_or(u64::MAX)));
+
+		self.set(
+			&token,
+			&RegistrationToken {
+				uses_allowed,
+				completed: 0,
+				expiry_time,
+				created,
+			},
+		)?;
+
+		Ok(token)
+	}
+
+	pub fn get(&self, token: &str) -> Result<Option<RegistrationToken>> {
+		self.registrationtoken_info
+			.get(token.as_bytes())?
+			.map(|info| {
+				serde_json::from_slice(&info)
+					.map_err(|_| Error::bad_database("Invalid registration token in registrationtoken_info."))
+			})
+			.transpose()
+	}
+
+	/// All registration tokens in the database, without the config one
+	pub fn list(&self) -> Result<Vec<(String, RegistrationToken)>> {
+		self.registrationtoken_info
+			.iter()
+			.map(|(token, info)| {
+				let token = utils::string_from_bytes(&token)
+					.map_err(|_| Error::bad_database("Registration token is invalid unicode."))?;
+				let info = serde_json::from_slice(&info)
+					.map_err(|_| Error::bad_database("Invalid registration token in registrationtoken_info."))?;
+				Ok((token, info))
+			})
+			.collect()
+	}
+
+	/// Removes a registration token. Returns whether it existed.
+	pub fn revoke(&self, token: &str) -> Result<bool> {
+		let _lock = self.use_mutex.lock().expect("locked");
+		if self.get(token)?.is_none() {
+			return Ok(false);
+		}
+
+		self.registrationtoken_info.remove(token.as_bytes())?;
+		Ok(true)
+	}
+
+	/// Whether registering requires a token
+	pub fn is_required(&self) -> bool {
+		services().globals.registration_token().is_some() || self.registrationtoken_info.iter().next().is_some()
+	}
+
+	/// Whether `token` can be used to register, without using it
+	pub fn is_valid(&self, token: &str) -> Result<bool> {
+		if services().globals.registration_token().as_deref() == Some(token) {
+			return Ok(true);
+		}
+
+		Ok(self.get(token)?.is_some_and(|info| info.is_valid()))
+	}
+
+	/// Counts a use of `token` once the account of `user_id` registered with
+	/// it was created, and remembers which token the account used. The token
+	/// is only checked during the registration, so registrations which fail
+	/// after that do not use it up.
+	pub fn use_token(&self, user_id: &UserId, token: &str) -> Result<()> {
+		if services().globals.registration_token().as_deref() != Some(token) {
+			let _lock = self.use_mutex.lock().expect("locked");
+			if let Some(mut info) = self.get(token)? {
+				info.completed = info.completed.saturating_add(1);
+				self.set(token, &info)?;
+			}
+		}
+
+		self.userid_registrationtoken
+			.insert(user_id.as_bytes(), token.as_bytes())
+	}
+
+	/// The token the account registered with, if it used one
+	pub fn user_token(&self, user_id: &UserId) -> Result<Option<String>> {
+		self.userid_registrationtoken
+			.get(user_id.as_bytes())?
+			.map(|token| {
+				utils::string_from_bytes(&token)
+					.map_err(|_| Error::bad_database("Registration token in userid_registrationtoken is invalid."))
+			})
+			.transpose()
+	}
+
+	/// Local accounts which registered with `token`
+	pub fn users_of(&self, token: &str) -> Vec<String> {
+		self.userid_registrationtoken
+			.iter()
+			.filter(|(_, used)| used == token.as_bytes())
+			.filter_map(|(user_id, _)| utils::string_from_bytes(&user_id).ok())
+			.collect()
+	}
+
+	fn set(&self, token: &str, info: &RegistrationToken) -> Result<()> {
+		self.registrationtoken_info.insert(
+			token.as_bytes(),
+			&serde_json::to_vec(info).expect("registration token serializes"),
+		)
+	}
+}
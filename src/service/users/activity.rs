@@ -0,0 +1,184 @@
+use std::{
+	collections::HashMap,
+	sync::{Arc, Mutex},
+};
+
+use conduit::{utils, Error, Result};
+use database::{Database, Map};
+use ruma::{OwnedUserId, UserId};
+
+use crate::{appservice::RegistrationInfo, services, user_is_local};
+
+const MILLIS_PER_DAY: u64 = 24 * 60 * 60 * 1000;
+
+/// The day each local user last made an authenticated request, in days since
+/// the epoch. Only the first request of a day is written to the database; the
+/// days already recorded are remembered in memory.
+pub struct UserActivity {
+	userid_lastactiveday: Arc<Map>,
+	recorded: Mutex<HashMap<OwnedUserId, u64>>,
+}
+
+/// Numbers of local accounts by when they were last active
+#[derive(Clone, Debug, Default)]
+pub struct ActivityCounts {
+	/// Active today
+	pub day: usize,
+
+	/// Active within the last 7 days
+	pub week: usize,
+
+	/// Active within the last 30 days
+	pub month: usize,
+
+	/// Accounts which made no authenticated request since activity is tracked
+	pub never_active: usize,
+
+	pub deactivated: usize,
+
+	/// Guest accounts, which are not counted as active
+	pub guests: usize,
+
+	/// Accounts in the namespace of an appservice, which are not counted as
+	/// active
+	pub appservice: usize,
+}
+
+impl UserActivity {
+	pub(super) fn new(db: &Arc<Database>) -> Self {
+		Self {
+			userid_lastactiveday: db["userid_lastactiveday"].clone(),
+			recorded: Mutex::new(HashMap::new()),
+		}
+	}
+
+	/// Records that the user made an authenticated request today
+	pub fn record(&self, user_id: &UserId) -> Result<()> {
+		let today = today();
+		{
+			let mut recorded = self.recorded.lock().expect("locked");
+			if recorded.get(user_id) == Some(&today) {
+				return Ok(());
+			}
+
+			recorded.insert(user_id.to_owned(), today);
+		}
+
+		self.userid_lastactiveday
+			.insert(user_id.as_bytes(), &today.to_be_bytes())
+	}
+
+	/// The day the user was last active, in days since the epoch
+	pub fn last_active_day(&self, user_id: &UserId) -> Result<Option<u64>> {
+		self.userid_lastactiveday
+			.get(user_id.as_bytes())?
+			.map(|day| {
+				utils::u64_from_bytes(&day).map_err(|_| Error::bad_database("Invalid day in userid_lastactiveday."))
+			})
+			.transpose()
+	}
+
+	/// Local accounts which are neither deactivated, guests nor appservice
+	/// users, and were active within the last `days` days, today included, with
+	/// how many days ago they were last active
+	pub async fn active_within(&self, days: u64) -> Result<Vec<(OwnedUserId, u64)>> {
+		let today = today();
+		let since = today.saturating_sub(days.saturating_sub(1));
+		let appservices = services().appservice.read().await;
+		let mut active = Vec::new();
+		for user_id in services().users.iter() {
+			let user_id = user_id?;
+			if account_kind(&user_id, appservices.values())? != AccountKind::Regular {
+				continue;
+			}
+
+			if let Some(day) = self.last_active_day(&user_id)?.filter(|day| *day >= since) {
+				active.push((user_id, today.saturating_sub(day)));
+			}
+		}
+
+		active.sort_by(|(a, _), (b, _)| a.cmp(b));
+		Ok(active)
+	}
+
+	pub async fn counts(&self) -> Result<ActivityCounts> {
+		let today = today();
+		let appservices = services().appservice.read().await;
+		let mut counts = ActivityCounts::default();
+		for user_id in services().users.iter() {
+			let user_id = user_id?;
+			let count = match account_kind(&user_id, appservices.values())? {
+				AccountKind::Regular => None,
+				AccountKind::Other => continue,
+				AccountKind::Appservice => Some(&mut counts.appservice),
+				AccountKind::Guest => Some(&mut counts.guests),
+				AccountKind::Deactivated => Some(&mut counts.deactivated),
+			};
+
+			if let Some(count) = count {
+				*count = count.saturating_add(1);
+				continue;
+			}
+
+			let Some(day) = self.last_active_day(&user_id)? else {
+				counts.never_active = counts.never_active.saturating_add(1);
+				continue;
+			};
+
+			let age = today.saturating_sub(day);
+			if age < 1 {
+				counts.day = counts.day.saturating_add(1);
+			}
+			if age < 7 {
+				counts.week = counts.week.saturating_add(1);
+			}
+			if age < 30 {
+				counts.month = counts.month.saturating_add(1);
+			}
+		}
+
+		Ok(counts)
+	}
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum AccountKind {
+	Regular,
+	Appservice,
+	Guest,
+	Deactivated,
+
+	/// Remote users and the server user
+	Other,
+}
+
+/// Guests and appservice users have no password either, so they are told
+/// apart before deactivated accounts
+fn account_kind<'a, I>(user_id: &UserId, appservices: I) -> Result<AccountKind>
+where
+	I: IntoIterator<Item = &'a RegistrationInfo>,
+{
+	if !user_is_local(user_id) || user_id == services().globals.server_user {
+		return Ok(AccountKind::Other);
+	}
+
+	if appservices
+		.into_iter()
+		.any(|info| info.is_user_match(user_id))
+	{
+		return Ok(AccountKind::Appservice);
+	}
+
+	if services().users.is_guest(user_id)? {
+		return Ok(AccountKind::Guest);
+	}
+
+	if services().users.is_deactivated(user_id)? {
+		return Ok(AccountKind::Deactivated);
+	}
+
+	Ok(AccountKind::Regular)
+}
+
+/// Days since the epoch
+fn today() -> u64 { utils::millis_since_unix_epoch() / MILLIS_PER_DAY }
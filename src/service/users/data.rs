@@ -11,16 +11,24 @@ use ruma::{
 	OwnedMxcUri, OwnedUserId, UInt, UserId,
 };
 
-use crate::{services, users::clean_signatures};
+use crate::{
+	services,
+	users::{clean_signatures, DehydratedDevice},
+};
 
 pub struct Data {
 	userid_password: Arc<Map>,
 	token_userdeviceid: Arc<Map>,
+	refreshtoken_userdeviceid: Arc<Map>,
 	userid_displayname: Arc<Map>,
 	userid_avatarurl: Arc<Map>,
 	userid_blurhash: Arc<Map>,
 	userid_devicelistversion: Arc<Map>,
+	userid_dehydrateddevice: Arc<Map>,
+	userid_guestsince: Arc<Map>,
 	userdeviceid_token: Arc<Map>,
+	userdeviceid_refreshtoken: Arc<Map>,
+	userdeviceid_tokenexpiresat: Arc<Map>,
 	userdeviceid_metadata: Arc<Map>,
 	onetimekeyid_onetimekeys: Arc<Map>,
 	userid_lastonetimekeyupdate: Arc<Map>,
@@ -31,6 +39,7 @@ pub struct Data {
 	keychangeid_userid: Arc<Map>,
 	todeviceid_events: Arc<Map>,
 	userfilterid_filter: Arc<Map>,
+	logintoken_expiresatuserid: Arc<Map>,
 	_db: Arc<Database>,
 }
 
@@ -39,11 +48,16 @@ impl Data {
 		Self {
 			userid_password: db["userid_password"].clone(),
 			token_userdeviceid: db["token_userdeviceid"].clone(),
+			refreshtoken_userdeviceid: db["refreshtoken_userdeviceid"].clone(),
 			userid_displayname: db["userid_displayname"].clone(),
 			userid_avatarurl: db["userid_avatarurl"].clone(),
 			userid_blurhash: db["userid_blurhash"].clone(),
 			userid_devicelistversion: db["userid_devicelistversion"].clone(),
+			userid_dehydrateddevice: db["userid_dehydrateddevice"].clone(),
+			userid_guestsince: db["userid_guestsince"].clone(),
 			userdeviceid_token: db["userdeviceid_token"].clone(),
+			userdeviceid_refreshtoken: db["userdeviceid_refreshtoken"].clone(),
+			userdeviceid_tokenexpiresat: db["userdeviceid_tokenexpiresat"].clone(),
 			userdeviceid_metadata: db["userdeviceid_metadata"].clone(),
 			onetimekeyid_onetimekeys: db["onetimekeyid_onetimekeys"].clone(),
 			userid_lastonetimekeyupdate: db["userid_lastonetimekeyupdate"].clone(),
@@ -54,6 +68,7 @@ impl Data {
 			keychangeid_userid: db["keychangeid_userid"].clone(),
 			todeviceid_events: db["todeviceid_events"].clone(),
 			userfilterid_filter: db["userfilterid_filter"].clone(),
+			logintoken_expiresatuserid: db["logintoken_expiresatuserid"].clone(),
 			_db: db,
 		}
 	}
@@ -72,6 +87,42 @@ impl Data {
 			.is_empty())
 	}
 
+	/// When the guest account was registered, in milliseconds since the epoch;
+	/// `None` for full accounts
+	pub(super) fn guest_since(&self, user_id: &UserId) -> Result<Option<u64>> {
+		self.userid_guestsince
+			.get(user_id.as_bytes())?
+			.map(|since| {
+				utils::u64_from_bytes(&since).map_err(|_| Error::bad_database("Invalid time in userid_guestsince."))
+			})
+			.transpose()
+	}
+
+	/// Flags the account as a guest account registered at `since`, or as a
+	/// full account
+	pub(super) fn set_guest_since(&self, user_id: &UserId, since: Option<u64>) -> Result<()> {
+		match since {
+			Some(since) => self
+				.userid_guestsince
+				.insert(user_id.as_bytes(), &since.to_be_bytes()),
+			None => self.userid_guestsince.remove(user_id.as_bytes()),
+		}
+	}
+
+	/// Guest accounts with when they were registered
+	pub(super) fn guests<'a>(&'a self) -> Box<dyn Iterator<Item = Result<(OwnedUserId, u64)>> + 'a> {
+		Box::new(self.userid_guestsince.iter().map(|(user_id, since)| {
+			let user_id = UserId::parse(
+				utils::string_from_bytes(&user_id)
+					.map_err(|_| Error::bad_database("User ID in userid_guestsince is invalid unicode."))?,
+			)
+			.map_err(|_| Error::bad_database("User ID in userid_guestsince is invalid."))?;
+			let since =
+				utils::u64_from_bytes(&since).map_err(|_| Error::bad_database("Invalid time in userid_guestsince."))?;
+			Ok((user_id, since))
+		}))
+	}
+
 	/// Returns the number of users registered on this server.
 	pub(super) fn count(&self) -> Result<usize> { Ok(self.userid_password.iter().count()) }
 
@@ -276,16 +327,24 @@ impl Data {
 			self.userdeviceid_token.remove(&userdeviceid)?;
 			self.token_userdeviceid.remove(&old_token)?;
 		}
+		self.remove_refresh_token(&userdeviceid)?;
 
 		// Remove todevice events
 		let mut prefix = userdeviceid.clone();
 		prefix.push(0xFF);
 
-		for (key, _) in self.todeviceid_events.scan_prefix(prefix) {
+		for (key, _) in self.todeviceid_events.scan_prefix(prefix.clone()) {
 			self.todeviceid_events.remove(&key)?;
 		}
 
-		// TODO: Remove onetimekeys
+		// Remove onetimekeys
+		for (key, _) in self.onetimekeyid_onetimekeys.scan_prefix(prefix) {
+			self.onetimekeyid_onetimekeys.remove(&key)?;
+		}
+
+		// Remove device keys; other users' clients drop the device once the
+		// caller marks the device list update
+		self.keyid_key.remove(&userdeviceid)?;
 
 		self.userid_devicelistversion
 			.increment(user_id.as_bytes())?;
@@ -295,6 +354,40 @@ impl Data {
 		Ok(())
 	}
 
+	/// Removes the cross-signing keys of a user, and device keys, one-time
+	/// keys and to-device events left behind by removed devices. Returns the
+	/// number of removed entries.
+	pub(super) fn remove_all_keys(&self, user_id: &UserId) -> Result<usize> {
+		let mut prefix = user_id.as_bytes().to_vec();
+		prefix.push(0xFF);
+
+		let mut removed: usize = 0;
+		for map in [&self.keyid_key, &self.onetimekeyid_onetimekeys, &self.todeviceid_events] {
+			for (key, _) in map.scan_prefix(prefix.clone()) {
+				map.remove(&key)?;
+				removed = removed.saturating_add(1);
+			}
+		}
+
+		for map in [
+			&self.userid_masterkeyid,
+			&self.userid_selfsigningkeyid,
+			&self.userid_usersigningkeyid,
+			&self.userid_lastonetimekeyupdate,
+		] {
+			if map.get(user_id.as_bytes())?.is_some() {
+				map.remove(user_id.as_bytes())?;
+				removed = removed.saturating_add(1);
+			}
+		}
+
+		if removed > 0 {
+			self.mark_device_key_update(user_id)?;
+		}
+
+		Ok(removed)
+	}
+
 	/// Returns an iterator over all device ids of this user.
 	pub(super) fn all_device_ids<'a>(
 		&'a self, user_id: &UserId,
@@ -341,6 +434,9 @@ impl Data {
 			// It will be removed from userdeviceid_token by the insert later
 		}
 
+		// A new access token does not expire unless a refresh token is issued with it
+		self.remove_refresh_token(&userdeviceid)?;
+
 		// Assign token to user device combination
 		self.userdeviceid_token
 			.insert(&userdeviceid, token.as_bytes())?;
@@ -350,6 +446,111 @@ impl Data {
 		Ok(())
 	}
 
+	/// Replaces the refresh token of one device, its current access token
+	/// expiring at `expires_at` (ms since unix epoch).
+	pub(super) fn set_refresh_token(
+		&self, user_id: &UserId, device_id: &DeviceId, refresh_token: &str, expires_at: u64,
+	) -> Result<()> {
+		let mut userdeviceid = user_id.as_bytes().to_vec();
+		userdeviceid.push(0xFF);
+		userdeviceid.extend_from_slice(device_id.as_bytes());
+
+		self.remove_refresh_token(&userdeviceid)?;
+
+		self.userdeviceid_refreshtoken
+			.insert(&userdeviceid, refresh_token.as_bytes())?;
+		self.refreshtoken_userdeviceid
+			.insert(refresh_token.as_bytes(), &userdeviceid)?;
+		self.userdeviceid_tokenexpiresat
+			.insert(&userdeviceid, &expires_at.to_be_bytes())
+	}
+
+	/// Find out which device a refresh token belongs to.
+	pub(super) fn find_from_refresh_token(&self, refresh_token: &str) -> Result<Option<(OwnedUserId, OwnedDeviceId)>> {
+		self.refreshtoken_userdeviceid
+			.get(refresh_token.as_bytes())?
+			.map(|bytes| {
+				let mut parts = bytes.split(|&b| b == 0xFF);
+				let user_bytes = parts
+					.next()
+					.ok_or_else(|| Error::bad_database("User ID in refreshtoken_userdeviceid is invalid."))?;
+				let device_bytes = parts
+					.next()
+					.ok_or_else(|| Error::bad_database("Device ID in refreshtoken_userdeviceid is invalid."))?;
+
+				Ok((
+					UserId::parse(utils::string_from_bytes(user_bytes).map_err(|_| {
+						Error::bad_database("User ID in refreshtoken_userdeviceid is invalid unicode.")
+					})?)
+					.map_err(|_| Error::bad_database("User ID in refreshtoken_userdeviceid is invalid."))?,
+					utils::string_from_bytes(device_bytes)
+						.map_err(|_| Error::bad_database("Device ID in refreshtoken_userdeviceid is invalid."))?
+						.into(),
+				))
+			})
+			.transpose()
+	}
+
+	/// When the access token of the device expires (ms since unix epoch), if
+	/// it was issued with a refresh token
+	pub(super) fn token_expires_at(&self, user_id: &UserId, device_id: &DeviceId) -> Result<Option<u64>> {
+		let mut userdeviceid = user_id.as_bytes().to_vec();
+		userdeviceid.push(0xFF);
+		userdeviceid.extend_from_slice(device_id.as_bytes());
+
+		self.userdeviceid_tokenexpiresat
+			.get(&userdeviceid)?
+			.map(|bytes| {
+				utils::u64_from_bytes(&bytes)
+					.map_err(|_| Error::bad_database("Expiry in userdeviceid_tokenexpiresat is invalid."))
+			})
+			.transpose()
+	}
+
+	fn remove_refresh_token(&self, userdeviceid: &[u8]) -> Result<()> {
+		if let Some(old_token) = self.userdeviceid_refreshtoken.get(userdeviceid)? {
+			self.userdeviceid_refreshtoken.remove(userdeviceid)?;
+			self.refreshtoken_userdeviceid.remove(&old_token)?;
+		}
+
+		self.userdeviceid_tokenexpiresat.remove(userdeviceid)
+	}
+
+	/// Stores a login token for the user, valid until `expires_at` (ms since
+	/// unix epoch).
+	pub(super) fn set_login_token(&self, token: &str, user_id: &UserId, expires_at: u64) -> Result<()> {
+		let mut value = expires_at.to_be_bytes().to_vec();
+		value.extend_from_slice(user_id.as_bytes());
+
+		self.logintoken_expiresatuserid
+			.insert(token.as_bytes(), &value)
+	}
+
+	/// Removes a login token, returning its expiry and the user it was issued
+	/// for if it existed.
+	pub(super) fn take_login_token(&self, token: &str) -> Result<Option<(u64, OwnedUserId)>> {
+		let Some(value) = self.logintoken_expiresatuserid.get(token.as_bytes())? else {
+			return Ok(None);
+		};
+
+		self.logintoken_expiresatuserid.remove(token.as_bytes())?;
+
+		if value.len() < size_of::<u64>() {
+			return Err(Error::bad_database("Login token in logintoken_expiresatuserid is invalid."));
+		}
+
+		let (expires_at, user_bytes) = value.split_at(size_of::<u64>());
+		let expires_at = utils::u64_from_bytes(expires_at)
+			.map_err(|_| Error::bad_database("Expiry in logintoken_expiresatuserid is invalid."))?;
+		let user_id = UserId::parse(
+			utils::string_from_bytes(user_bytes)
+				.map_err(|_| Error::bad_database("User ID in logintoken_expiresatuserid is invalid unicode."))?,
+		)
+		.map_err(|_| Error::bad_database("User ID in logintoken_expiresatuserid is invalid."))?;
+
+		Ok(Some((expires_at, user_id)))
+	}
+
 	pub(super) fn add_one_time_key(
 		&self, user_id: &UserId, device_id: &DeviceId, one_time_key_key: &DeviceKeyId,
 		one_time_key_value: &Raw<OneTimeKey>,
@@ -756,22 +957,9 @@ impl Data {
 		&self, sender: &UserId, target_user_id: &UserId, target_device_id: &DeviceId, event_type: &str,
 		content: serde_json::Value,
 	) -> Result<()> {
-		let mut key = target_user_id.as_bytes().to_vec();
-		key.push(0xFF);
-		key.extend_from_slice(target_device_id.as_bytes());
-		key.push(0xFF);
-		key.extend_from_slice(&services().globals.next_count()?.to_be_bytes());
-
-		let mut json = serde_json::Map::new();
-		json.insert("type".to_owned(), event_type.to_owned().into());
-		json.insert("sender".to_owned(), sender.to_string().into());
-		json.insert("content".to_owned(), content);
-
-		let value = serde_json::to_vec(&json).expect("Map::to_vec always works");
-
-		self.todeviceid_events.insert(&key, &value)?;
-
-		Ok(())
+		let key = to_device_key(target_user_id, target_device_id, services().globals.next_count()?);
+		self.todeviceid_events
+			.insert(&key, &to_device_value(sender, event_type, content))
 	}
 
 	pub(super) fn get_to_device_events(
@@ -779,10 +967,7 @@ impl Data {
 	) -> Result<Vec<Raw<AnyToDeviceEvent>>> {
 		let mut events = Vec::new();
 
-		let mut prefix = user_id.as_bytes().to_vec();
-		prefix.push(0xFF);
-		prefix.extend_from_slice(device_id.as_bytes());
-		prefix.push(0xFF);
+		let prefix = to_device_prefix(user_id, device_id);
 
 		for (_, value) in self.todeviceid_events.scan_prefix(prefix) {
 			events.push(
@@ -795,10 +980,7 @@ impl Data {
 	}
 
 	pub(super) fn remove_to_device_events(&self, user_id: &UserId, device_id: &DeviceId, until: u64) -> Result<()> {
-		let mut prefix = user_id.as_bytes().to_vec();
-		prefix.push(0xFF);
-		prefix.extend_from_slice(device_id.as_bytes());
-		prefix.push(0xFF);
+		let prefix = to_device_prefix(user_id, device_id);
 
 		let mut last = prefix.clone();
 		last.extend_from_slice(&until.to_be_bytes());
@@ -823,6 +1005,52 @@ impl Data {
 		Ok(())
 	}
 
+	/// Returns up to `limit` to-device events for a device with their counts,
+	/// oldest first, starting after `since`.
+	pub(super) fn get_to_device_events_after(
+		&self, user_id: &UserId, device_id: &DeviceId, since: u64, limit: usize,
+	) -> Result<Vec<(u64, Raw<AnyToDeviceEvent>)>> {
+		let prefix = to_device_prefix(user_id, device_id);
+
+		let mut first = prefix.clone();
+		first.extend_from_slice(&since.saturating_add(1).to_be_bytes());
+
+		self.todeviceid_events
+			.iter_from(&first, false)
+			.take_while(|(key, _)| key.starts_with(&prefix))
+			.take(limit)
+			.map(|(key, value)| {
+				let count = utils::u64_from_bytes(&key[key.len() - size_of::<u64>()..key.len()])
+					.map_err(|_| Error::bad_database("ToDeviceId has invalid count bytes."))?;
+				let event = serde_json::from_slice(&value)
+					.map_err(|_| Error::bad_database("Event in todeviceid_events is invalid."))?;
+
+				Ok((count, event))
+			})
+			.collect()
+	}
+
+	pub(super) fn get_dehydrated_device(&self, user_id: &UserId) -> Result<Option<DehydratedDevice>> {
+		self.userid_dehydrateddevice
+			.get(user_id.as_bytes())?
+			.map(|bytes| {
+				serde_json::from_slice(&bytes)
+					.map_err(|_| Error::bad_database("Dehydrated device in userid_dehydrateddevice is invalid."))
+			})
+			.transpose()
+	}
+
+	pub(super) fn set_dehydrated_device(&self, user_id: &UserId, device: &DehydratedDevice) -> Result<()> {
+		self.userid_dehydrateddevice.insert(
+			user_id.as_bytes(),
+			&serde_json::to_vec(device).expect("DehydratedDevice::to_vec always works"),
+		)
+	}
+
+	pub(super) fn remove_dehydrated_device(&self, user_id: &UserId) -> Result<()> {
+		self.userid_dehydrateddevice.remove(user_id.as_bytes())
+	}
+
 	pub(super) fn update_device_metadata(&self, user_id: &UserId, device_id: &DeviceId, device: &Device) -> Result<()> {
 		let mut userdeviceid = user_id.as_bytes().to_vec();
 		userdeviceid.push(0xFF);
@@ -852,6 +1080,21 @@ impl Data {
 		Ok(())
 	}
 
+	/// Replaces the metadata of an existing device without a device list
+	/// update, for fields only the user's own clients see
+	pub(super) fn update_device_last_seen(
+		&self, user_id: &UserId, device_id: &DeviceId, device: &Device,
+	) -> Result<()> {
+		let mut userdeviceid = user_id.as_bytes().to_vec();
+		userdeviceid.push(0xFF);
+		userdeviceid.extend_from_slice(device_id.as_bytes());
+
+		self.userdeviceid_metadata.insert(
+			&userdeviceid,
+			&serde_json::to_vec(device).expect("Device::to_string always works"),
+		)
+	}
+
 	/// Get device metadata.
 	pub(super) fn get_device_metadata(&self, user_id: &UserId, device_id: &DeviceId) -> Result<Option<Device>> {
 		let mut userdeviceid = user_id.as_bytes().to_vec();
@@ -940,3 +1183,29 @@ pub(super) fn get_username_with_valid_password(username: &[u8], password: &[u8])
 		}
 	}
 }
+
+/// Prefix of the to-device messages waiting for a device
+pub(super) fn to_device_prefix(user_id: &UserId, device_id: &DeviceId) -> Vec<u8> {
+	let mut prefix = user_id.as_bytes().to_vec();
+	prefix.push(0xFF);
+	prefix.extend_from_slice(device_id.as_bytes());
+	prefix.push(0xFF);
+	prefix
+}
+
+/// Key of a to-device message; the big-endian count orders the messages of a
+/// device as they were stored
+pub(super) fn to_device_key(user_id: &UserId, device_id: &DeviceId, count: u64) -> Vec<u8> {
+	let mut key = to_device_prefix(user_id, device_id);
+	key.extend_from_slice(&count.to_be_bytes());
+	key
+}
+
+pub(super) fn to_device_value(sender: &UserId, event_type: &str, content: serde_json::Value) -> Vec<u8> {
+	let mut json = serde_json::Map::new();
+	json.insert("type".to_owned(), event_type.to_owned().into());
+	json.insert("sender".to_owned(), sender.to_string().into());
+	json.insert("content".to_owned(), content);
+
+	serde_json::to_vec(&json).expect("Map::to_vec always works")
+}
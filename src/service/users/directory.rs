@@ -0,0 +1,226 @@
+use std::{collections::BTreeSet, sync::Arc};
+
+use conduit::{utils, Error, Result};
+use database::{Database, Map};
+use ruma::{OwnedUserId, UserId};
+
+use crate::{rooms::search::tokenize, services};
+
+/// Search index of the user directory over the words of user IDs and
+/// displaynames. Every suffix of every word is indexed, so a search for the
+/// prefix of a suffix finds words containing the search term anywhere.
+/// Local users are indexed when they register or change their displayname,
+/// remote users when we first see them in a room or fetch their profile.
+pub struct UserDirectory {
+	userdirectorytoken_userid: Arc<Map>,
+	userid_userdirectorytokens: Arc<Map>,
+}
+
+impl UserDirectory {
+	pub(super) fn new(db: &Arc<Database>) -> Self {
+		Self {
+			userdirectorytoken_userid: db["userdirectorytoken_userid"].clone(),
+			userid_userdirectorytokens: db["userid_userdirectorytokens"].clone(),
+		}
+	}
+
+	/// Replaces the indexed words of the user with those of their user ID and
+	/// current displayname
+	pub fn index(&self, user_id: &UserId) -> Result<()> {
+		let displayname = services().users.displayname(user_id)?;
+		self.remove(user_id)?;
+
+		let tokens = tokens(user_id, displayname.as_deref());
+		for token in &tokens {
+			self.userdirectorytoken_userid
+				.insert(&token_key(token, user_id), &[])?;
+		}
+
+		// tokens are alphanumeric, so they are stored separated by spaces
+		let tokens: Vec<_> = tokens.iter().map(String::as_str).collect();
+		self.userid_userdirectorytokens
+			.insert(user_id.as_bytes(), tokens.join(" ").as_bytes())
+	}
+
+	/// Removes the user from the index
+	pub fn remove(&self, user_id: &UserId) -> Result<()> {
+		let Some(tokens) = self.userid_userdirectorytokens.get(user_id.as_bytes())? else {
+			return Ok(());
+		};
+
+		let tokens = utils::string_from_bytes(&tokens)
+			.map_err(|_| Error::bad_database("Tokens in userid_userdirectorytokens are invalid unicode."))?;
+		for token in tokens.split_whitespace() {
+			self.userdirectorytoken_userid
+				.remove(&token_key(token, user_id))?;
+		}
+
+		self.userid_userdirectorytokens.remove(user_id.as_bytes())
+	}
+
+	pub fn is_indexed(&self, user_id: &UserId) -> Result<bool> {
+		Ok(self
+			.userid_userdirectorytokens
+			.get(user_id.as_bytes())?
+			.is_some())
+	}
+
+	/// Users whose user ID or displayname contain every word of
+	/// `search_term`, case-insensitively. Returns `None` when the term has no
+	/// words.
+	pub fn search(&self, search_term: &str) -> Result<Option<BTreeSet<OwnedUserId>>> {
+		search(search_term, |prefix| {
+			self.userdirectorytoken_userid
+				.scan_prefix(prefix.as_bytes().to_vec())
+				.map(|(key, _)| user_of_token_key(&key))
+				.collect()
+		})
+	}
+}
+
+/// Intersects the users with a token starting with each word of
+/// `search_term`, looked up with `users_with_token_prefix`
+fn search<F>(search_term: &str, mut users_with_token_prefix: F) -> Result<Option<BTreeSet<OwnedUserId>>>
+where
+	F: FnMut(&str) -> Result<BTreeSet<OwnedUserId>>,
+{
+	let words: BTreeSet<_> = tokenize(search_term).collect();
+	if words.is_empty() {
+		return Ok(None);
+	}
+
+	let mut matches: Option<BTreeSet<OwnedUserId>> = None;
+	for word in words {
+		let users = users_with_token_prefix(&word)?;
+		let users = match matches {
+			Some(matches) => matches.intersection(&users).cloned().collect(),
+			None => users,
+		};
+
+		if users.is_empty() {
+			return Ok(Some(users));
+		}
+
+		matches = Some(users);
+	}
+
+	Ok(matches)
+}
+
+/// Every suffix of the words of the user ID and displayname
+fn tokens(user_id: &UserId, displayname: Option<&str>) -> BTreeSet<String> {
+	[Some(user_id.as_str()), displayname]
+		.into_iter()
+		.flatten()
+		.flat_map(|text| tokenize(text).collect::<Vec<_>>())
+		.flat_map(|word| {
+			word.char_indices()
+				.map(|(start, _)| word[start..].to_owned())
+				.collect::<Vec<_>>()
+		})
+		.collect()
+}
+
+fn token_key(token: &str, user_id: &UserId) -> Vec<u8> {
+	let mut key = token.as_bytes().to_vec();
+	key.push(0xFF);
+	key.extend_from_slice(user_id.as_bytes());
+	key
+}
+
+fn user_of_token_key(key: &[u8]) -> Result<OwnedUserId> {
+	let user_id = key
+		.rsplit(|&b| b == 0xFF)
+		.next()
+		.ok_or_else(|| Error::bad_database("Invalid key in userdirectorytoken_userid."))?;
+
+	UserId::parse(
+		utils::string_from_bytes(user_id)
+			.map_err(|_| Error::bad_database("User ID in userdirectorytoken_userid is invalid unicode."))?,
+	)
+	.map_err(|_| Error::bad_database("User ID in userdirectorytoken_userid is invalid."))
+}
+
+#[cfg(test)]
+mod tests {
+	use std::collections::{BTreeMap, BTreeSet};
+
+	use ruma::{user_id, OwnedUserId, UserId};
+
+	use super::{search, token_key, tokens, user_of_token_key};
+
+	/// The index as `UserDirectory::index` and `remove` write it, with the
+	/// indexed tokens of each user
+	#[derive(Default)]
+	struct Index {
+		keys: BTreeMap<Vec<u8>, ()>,
+		tokens: BTreeMap<OwnedUserId, BTreeSet<String>>,
+	}
+
+	impl Index {
+		fn index(&mut self, user_id: &UserId, displayname: Option<&str>) {
+			self.remove(user_id);
+			let tokens = tokens(user_id, displayname);
+			self.keys
+				.extend(tokens.iter().map(|token| (token_key(token, user_id), ())));
+			self.tokens.insert(user_id.to_owned(), tokens);
+		}
+
+		fn remove(&mut self, user_id: &UserId) {
+			for token in self.tokens.remove(user_id).unwrap_or_default() {
+				self.keys.remove(&token_key(&token, user_id));
+			}
+		}
+
+		/// What `Service::set_displayname` does to the index for local users
+		fn set_displayname(&mut self, user_id: &UserId, displayname: Option<&str>) {
+			if self.tokens.contains_key(user_id) {
+				self.index(user_id, displayname);
+			}
+		}
+
+		fn search(&self, search_term: &str) -> BTreeSet<OwnedUserId> {
+			search(search_term, |prefix| {
+				self.keys
+					.range(prefix.as_bytes().to_vec()..)
+					.take_while(|(key, ())| key.starts_with(prefix.as_bytes()))
+					.map(|(key, ())| user_of_token_key(key))
+					.collect()
+			})
+			.unwrap()
+			.unwrap_or_default()
+		}
+	}
+
+	#[test]
+	fn tokens_cover_substrings() {
+		let tokens = tokens(user_id!("@alice_w:example.com"), Some("Alice Wonder"));
+
+		for word in ["alice", "lice", "w", "example", "com", "wonder", "nder"] {
+			assert!(tokens.contains(word), "missing {word}");
+		}
+
+		// searching for "ICE" looks for tokens starting with "ice"
+		assert!(tokens.iter().any(|token| token.starts_with("ice")));
+		assert!(!tokens.iter().any(|token| token.starts_with("bob")));
+	}
+
+	#[test]
+	fn deactivated_user_not_found() {
+		let (alice, bob) = (user_id!("@alice:example.com"), user_id!("@bob:example.com"));
+		let mut index = Index::default();
+		index.index(alice, Some("Alice Wonder"));
+		index.index(bob, Some("Bob Wonder"));
+		assert_eq!(index.search("wonder").len(), 2);
+
+		// deactivation removes bob, then clears his displayname
+		index.remove(bob);
+		index.set_displayname(bob, None);
+		assert_eq!(index.search("wonder"), BTreeSet::from([alice.to_owned()]));
+		assert!(index.search("bob").is_empty());
+
+		index.set_displayname(alice, Some("Carol"));
+		assert!(index.search("wonder").is_empty());
+		assert_eq!(index.search("carol"), BTreeSet::from([alice.to_owned()]));
+	}
+}
@@ -0,0 +1,89 @@
+use std::time::Duration;
+
+use conduit::{utils, warn, Result};
+use ruma::{
+	events::{
+		room::member::{MembershipState, RoomMemberEventContent},
+		TimelineEventType,
+	},
+	UserId,
+};
+use serde_json::value::to_raw_value;
+use tokio::{task::JoinHandle, time::interval};
+
+use crate::{pdu::PduBuilder, services};
+
+/// How often guest accounts are checked for their age
+const GUEST_EXPIRY_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+const MILLIS_PER_DAY: u64 = 24 * 60 * 60 * 1000;
+
+/// Deactivates guest accounts older than `guest_account_max_age_days`, after
+/// making them leave their rooms.
+#[tracing::instrument]
+pub fn start_guest_expiry_task(max_age_days: u64) -> JoinHandle<()> {
+	services().server.runtime().spawn(async move {
+		let mut i = interval(GUEST_EXPIRY_INTERVAL);
+
+		loop {
+			i.tick().await;
+
+			if let Err(e) = expire_guests(max_age_days.saturating_mul(MILLIS_PER_DAY)).await {
+				warn!(%e, "Failed to expire guest accounts");
+			}
+		}
+	})
+}
+
+async fn expire_guests(max_age: u64) -> Result<()> {
+	let cutoff = utils::millis_since_unix_epoch().saturating_sub(max_age);
+	let expired: Vec<_> = services()
+		.users
+		.guests()
+		.filter_map(Result::ok)
+		.filter(|(_, since)| *since < cutoff)
+		.map(|(user_id, _)| user_id)
+		.collect();
+
+	for user_id in expired {
+		leave_joined_rooms(&user_id).await;
+		services().users.deactivate_account(&user_id)?;
+	}
+
+	Ok(())
+}
+
+/// Guests are local users, so we are resident in every room they joined and can
+/// send their leave events ourselves; errors are logged and ignored.
+async fn leave_joined_rooms(user_id: &UserId) {
+	let rooms: Vec<_> = services()
+		.rooms
+		.state_cache
+		.rooms_joined(user_id)
+		.filter_map(Result::ok)
+		.collect();
+
+	for room_id in rooms {
+		let state_lock = services().globals.roomid_mutex_state.lock(&room_id).await;
+		if let Err(e) = services()
+			.rooms
+			.timeline
+			.build_and_append_pdu(
+				PduBuilder {
+					event_type: TimelineEventType::RoomMember,
+					content: to_raw_value(&RoomMemberEventContent::new(MembershipState::Leave))
+						.expect("event is valid, we just created it"),
+					unsigned: None,
+					state_key: Some(user_id.to_string()),
+					redacts: None,
+				},
+				user_id,
+				&room_id,
+				&state_lock,
+			)
+			.await
+		{
+			warn!(%room_id, %user_id, %e, "Failed to leave room");
+		}
+	}
+}
@@ -1,17 +1,31 @@
+mod activity;
 mod data;
+mod directory;
+pub(super) mod guests;
+mod profile;
+mod sync_sessions;
+mod to_device;
+pub(super) mod verification;
 
 use std::{
-	collections::{BTreeMap, BTreeSet},
+	collections::{BTreeMap, BTreeSet, HashMap},
 	mem,
+	net::IpAddr,
 	sync::{Arc, Mutex, Mutex as StdMutex},
+	time::{Duration, Instant},
 };
 
-use conduit::{Error, Result, Server};
+pub use activity::{ActivityCounts, UserActivity};
+use conduit::{utils, Error, Result, Server};
 use data::Data;
 use database::Database;
+pub use directory::UserDirectory;
+pub use profile::{Profile, RemoteProfiles};
 use ruma::{
 	api::client::{
+		dehydrated_device::DehydratedDeviceData,
 		device::Device,
+		error::ErrorKind,
 		filter::FilterDefinition,
 		sync::sync_events::{
 			self,
@@ -21,11 +35,37 @@ use ruma::{
 	encryption::{CrossSigningKey, DeviceKeys, OneTimeKey},
 	events::AnyToDeviceEvent,
 	serde::Raw,
-	DeviceId, DeviceKeyAlgorithm, DeviceKeyId, OwnedDeviceId, OwnedDeviceKeyId, OwnedMxcUri, OwnedRoomId, OwnedUserId,
-	UInt, UserId,
+	to_device::DeviceIdOrAllDevices,
+	DeviceId, DeviceKeyAlgorithm, DeviceKeyId, MilliSecondsSinceUnixEpoch, OwnedDeviceId, OwnedDeviceKeyId,
+	OwnedMxcUri, OwnedRoomId, OwnedUserId, UInt, UserId,
 };
+use serde::{Deserialize, Serialize};
+pub use sync_sessions::{SyncGuard, SyncSession, SyncSessions};
+pub use to_device::ToDeviceRelay;
+pub use verification::{Verification, VerificationStats, Verifications, VERIFICATION_TIMEOUT};
 
-use crate::services;
+use crate::{services, user_is_local};
+
+pub const LOGIN_TOKEN_LENGTH: usize = 32;
+
+pub const REFRESH_TOKEN_LENGTH: usize = 32;
+
+/// How long a refresh token which was just used keeps returning the tokens it
+/// was exchanged for, so that concurrent refreshes by the same client do not
+/// log it out
+const REFRESH_TOKEN_GRACE: Duration = Duration::from_secs(10);
+
+/// Requests of a device from the same address within this many milliseconds
+/// of the last recorded one don't update its last-seen time
+const LAST_SEEN_RESOLUTION: u64 = 300_000;
+
+/// Tokens of a device whose access token expires (MSC2918)
+#[derive(Clone, Debug)]
+pub struct RefreshedTokens {
+	pub access_token: String,
+	pub refresh_token: String,
+	pub expires_in: Duration,
+}
 
 pub struct SlidingSyncCache {
 	lists: BTreeMap<String, SyncRequestList>,
@@ -34,11 +74,38 @@ pub struct SlidingSyncCache {
 	extensions: ExtensionsConfig,
 }
 
+/// A dehydrated device (MSC3814). It is kept apart from the user's devices so
+/// it has no access token and doesn't show up in their device list, while
+/// to-device messages for it accumulate until it is rehydrated.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct DehydratedDevice {
+	pub device_id: OwnedDeviceId,
+	pub display_name: Option<String>,
+	pub device_data: Raw<DehydratedDeviceData>,
+}
+
 type DbConnections = Mutex<BTreeMap<(OwnedUserId, OwnedDeviceId, String), Arc<Mutex<SlidingSyncCache>>>>;
 
 pub struct Service {
 	pub db: Data,
 	pub connections: DbConnections,
+	pub verifications: Verifications,
+	pub sync_sessions: SyncSessions,
+	pub profiles: RemoteProfiles,
+	pub activity: UserActivity,
+	pub directory: UserDirectory,
+	pub to_device: ToDeviceRelay,
+	pub guest_expiry_handle: tokio::sync::Mutex<Option<tokio::task::JoinHandle<()>>>,
+	pub verification_timeout_handle: tokio::sync::Mutex<Option<tokio::task::JoinHandle<()>>>,
+	login_token_lock: StdMutex<()>,
+
+	/// Held while device metadata is written, so recording when a device was
+	/// last seen does not undo a concurrent change of the device
+	device_metadata_lock: StdMutex<()>,
+
+	/// Refresh tokens used during the last `REFRESH_TOKEN_GRACE`, with when
+	/// they were used and the tokens they were exchanged for
+	used_refresh_tokens: StdMutex<HashMap<String, (Instant, RefreshedTokens)>>,
 }
 
 impl Service {
@@ -46,6 +113,17 @@ impl Service {
 		Ok(Self {
 			db: Data::new(db.clone()),
 			connections: StdMutex::new(BTreeMap::new()),
+			verifications: Verifications::default(),
+			sync_sessions: SyncSessions::default(),
+			profiles: RemoteProfiles::default(),
+			activity: UserActivity::new(db),
+			directory: UserDirectory::new(db),
+			to_device: ToDeviceRelay::default(),
+			guest_expiry_handle: tokio::sync::Mutex::new(None),
+			verification_timeout_handle: tokio::sync::Mutex::new(None),
+			login_token_lock: StdMutex::new(()),
+			device_metadata_lock: StdMutex::new(()),
+			used_refresh_tokens: StdMutex::new(HashMap::new()),
 		})
 	}
 
@@ -256,9 +334,32 @@ impl Service {
 	/// Create a new user account on this homeserver.
 	pub fn create(&self, user_id: &UserId, password: Option<&str>) -> Result<()> {
 		self.db.set_password(user_id, password)?;
-		Ok(())
+		self.directory.index(user_id)
+	}
+
+	/// Create a new guest account on this homeserver. It has no password, so
+	/// it can't log in again once its access token is gone.
+	pub fn create_guest(&self, user_id: &UserId) -> Result<()> {
+		self.db.set_password(user_id, None)?;
+		self.db
+			.set_guest_since(user_id, Some(utils::millis_since_unix_epoch()))
 	}
 
+	/// Check if an account is a guest account
+	pub fn is_guest(&self, user_id: &UserId) -> Result<bool> { Ok(self.db.guest_since(user_id)?.is_some()) }
+
+	/// Turns a guest account into a full account with `password`, keeping its
+	/// devices and rooms
+	pub fn upgrade_guest(&self, user_id: &UserId, password: Option<&str>) -> Result<()> {
+		self.db.set_password(user_id, password)?;
+		self.db.set_guest_since(user_id, None)?;
+		self.directory.index(user_id)
+	}
+
+	/// Guest accounts with when they were registered, in milliseconds since
+	/// the epoch
+	pub fn guests<'a>(&'a self) -> impl Iterator<Item = Result<(OwnedUserId, u64)>> + 'a { self.db.guests() }
+
 	/// Returns the number of users registered on this server.
 	pub fn count(&self) -> Result<usize> { self.db.count() }
 
@@ -290,7 +391,15 @@ impl Service {
 	/// Sets a new displayname or removes it if displayname is None. You still
 	/// need to nofify all rooms of this change.
 	pub async fn set_displayname(&self, user_id: &UserId, displayname: Option<String>) -> Result<()> {
-		self.db.set_displayname(user_id, displayname)
+		self.db.set_displayname(user_id, displayname)?;
+
+		// local guests and deactivated accounts are not in the directory, and stay
+		// out of it
+		if !user_is_local(user_id) || self.directory.is_indexed(user_id)? {
+			self.directory.index(user_id)?;
+		}
+
+		Ok(())
 	}
 
 	/// Get the avatar_url of a user.
@@ -309,6 +418,16 @@ impl Service {
 		self.db.set_blurhash(user_id, blurhash)
 	}
 
+	/// Displayname, avatar_url and blurhash of the user, from our local copy
+	/// for remote users
+	pub fn profile(&self, user_id: &UserId) -> Result<Profile> {
+		Ok(Profile {
+			displayname: self.displayname(user_id)?,
+			avatar_url: self.avatar_url(user_id)?,
+			blurhash: self.blurhash(user_id)?,
+		})
+	}
+
 	/// Adds a new device to a user.
 	pub fn create_device(
 		&self, user_id: &UserId, device_id: &DeviceId, token: &str, initial_device_display_name: Option<String>,
@@ -317,9 +436,25 @@ impl Service {
 			.create_device(user_id, device_id, token, initial_device_display_name)
 	}
 
-	/// Removes a device from a user.
+	/// Removes a device from a user, along with its access token, keys,
+	/// pending to-device messages, UIAA sessions and pushers, and notifies of
+	/// the device list change.
 	pub fn remove_device(&self, user_id: &UserId, device_id: &DeviceId) -> Result<()> {
-		self.db.remove_device(user_id, device_id)
+		self.remove_devices(user_id, &[device_id.to_owned()])
+	}
+
+	/// Removes several devices of a user like `remove_device`, notifying of
+	/// the device list change once.
+	pub fn remove_devices(&self, user_id: &UserId, device_ids: &[OwnedDeviceId]) -> Result<()> {
+		for device_id in device_ids {
+			self.db.remove_device(user_id, device_id)?;
+			services().uiaa.remove_device_sessions(user_id, device_id)?;
+			services()
+				.pusher
+				.remove_device_pushers(user_id, device_id)?;
+		}
+
+		self.db.mark_device_key_update(user_id)
 	}
 
 	/// Returns an iterator over all device ids of this user.
@@ -327,11 +462,96 @@ impl Service {
 		self.db.all_device_ids(user_id)
 	}
 
-	/// Replaces the access token of one device.
+	/// Replaces the access token of one device. It does not expire until a
+	/// refresh token is issued for the device.
 	pub fn set_token(&self, user_id: &UserId, device_id: &DeviceId, token: &str) -> Result<()> {
 		self.db.set_token(user_id, device_id, token)
 	}
 
+	/// Creates a short-lived, single-use token which can be exchanged for an
+	/// access token through `m.login.token`.
+	pub fn create_login_token(&self, user_id: &UserId) -> Result<String> {
+		let token = utils::random_string(LOGIN_TOKEN_LENGTH);
+		let expires_at = utils::millis_since_unix_epoch().saturating_add(services().globals.login_token_ttl());
+
+		self.db.set_login_token(&token, user_id, expires_at)?;
+
+		Ok(token)
+	}
+
+	/// Consumes a login token, returning the user it was issued for. Tokens
+	/// can only be used once; unknown tokens return `None` and expired tokens
+	/// are rejected with M_FORBIDDEN.
+	pub fn find_from_login_token(&self, token: &str) -> Result<Option<OwnedUserId>> {
+		// the lookup and removal must not interleave, or a token could be used twice
+		let _lock = self.login_token_lock.lock().expect("locked");
+
+		let Some((expires_at, user_id)) = self.db.take_login_token(token)? else {
+			return Ok(None);
+		};
+
+		if expires_at < utils::millis_since_unix_epoch() {
+			return Err(Error::BadRequest(ErrorKind::forbidden(), "Login token has expired."));
+		}
+
+		Ok(Some(user_id))
+	}
+
+	/// Issues a refresh token for the device, after which its current access
+	/// token expires. Returns the refresh token and the access token lifetime.
+	pub fn issue_refresh_token(&self, user_id: &UserId, device_id: &DeviceId) -> Result<(String, Duration)> {
+		let refresh_token = utils::random_string(REFRESH_TOKEN_LENGTH);
+		let ttl = services().globals.access_token_ttl();
+		let expires_at = utils::millis_since_unix_epoch().saturating_add(ttl);
+
+		self.db
+			.set_refresh_token(user_id, device_id, &refresh_token, expires_at)?;
+
+		Ok((refresh_token, Duration::from_millis(ttl)))
+	}
+
+	/// Exchanges a refresh token for `access_token` and a new refresh token,
+	/// invalidating both old tokens. A refresh token can only be used once,
+	/// but during `REFRESH_TOKEN_GRACE` it returns the tokens it was already
+	/// exchanged for.
+	pub fn refresh_token(&self, refresh_token: &str, access_token: String) -> Result<RefreshedTokens> {
+		let mut used = self.used_refresh_tokens.lock().expect("locked");
+		used.retain(|_, (used_at, _)| used_at.elapsed() < REFRESH_TOKEN_GRACE);
+		if let Some((_, tokens)) = used.get(refresh_token) {
+			return Ok(tokens.clone());
+		}
+
+		let Some((user_id, device_id)) = self.db.find_from_refresh_token(refresh_token)? else {
+			return Err(Error::BadRequest(
+				ErrorKind::UnknownToken {
+					soft_logout: false,
+				},
+				"Unknown refresh token.",
+			));
+		};
+
+		self.db.set_token(&user_id, &device_id, &access_token)?;
+		let (new_refresh_token, expires_in) = self.issue_refresh_token(&user_id, &device_id)?;
+
+		let tokens = RefreshedTokens {
+			access_token,
+			refresh_token: new_refresh_token,
+			expires_in,
+		};
+		used.insert(refresh_token.to_owned(), (Instant::now(), tokens.clone()));
+
+		Ok(tokens)
+	}
+
+	/// Whether the access token of the device expired, which only happens if
+	/// it was issued with a refresh token
+	pub fn is_access_token_expired(&self, user_id: &UserId, device_id: &DeviceId) -> Result<bool> {
+		Ok(self
+			.db
+			.token_expires_at(user_id, device_id)?
+			.is_some_and(|expires_at| expires_at < utils::millis_since_unix_epoch()))
+	}
+
 	pub fn add_one_time_key(
 		&self, user_id: &UserId, device_id: &DeviceId, one_time_key_key: &DeviceKeyId,
 		one_time_key_value: &Raw<OneTimeKey>,
@@ -340,8 +560,6 @@ impl Service {
 			.add_one_time_key(user_id, device_id, one_time_key_key, one_time_key_value)
 	}
 
-	// TODO: use this ?
-	#[allow(dead_code)]
 	pub fn last_one_time_keys_update(&self, user_id: &UserId) -> Result<u64> {
 		self.db.last_one_time_keys_update(user_id)
 	}
@@ -423,22 +641,75 @@ impl Service {
 		&self, sender: &UserId, target_user_id: &UserId, target_device_id: &DeviceId, event_type: &str,
 		content: serde_json::Value,
 	) -> Result<()> {
+		self.verifications
+			.observe(sender, target_user_id, target_device_id, event_type, &content);
+
 		self.db
 			.add_to_device_event(sender, target_user_id, target_device_id, event_type, content)
 	}
 
+	/// Stores the to-device messages of a request or EDU for local devices, in
+	/// order. Nothing is stored if any of the messages is invalid. Callers hold
+	/// `to_device.lock` of the sender while checking the transaction ID and
+	/// storing.
+	pub fn add_to_device_events<'a, T, M>(&self, sender: &UserId, event_type: &str, messages: M) -> Result<()>
+	where
+		T: 'a,
+		M: IntoIterator<Item = (&'a OwnedUserId, &'a BTreeMap<DeviceIdOrAllDevices, Raw<T>>)>,
+	{
+		let expanded = to_device::expand(messages, |user_id| self.all_device_ids_with_dehydrated(user_id).collect())?;
+
+		for (target_user_id, target_device_id, content) in expanded {
+			self.to_device.observe(event_type, &content);
+			self.add_to_device_event(sender, &target_user_id, &target_device_id, event_type, content)?;
+		}
+
+		Ok(())
+	}
+
 	pub fn get_to_device_events(&self, user_id: &UserId, device_id: &DeviceId) -> Result<Vec<Raw<AnyToDeviceEvent>>> {
 		self.db.get_to_device_events(user_id, device_id)
 	}
 
 	pub fn remove_to_device_events(&self, user_id: &UserId, device_id: &DeviceId, until: u64) -> Result<()> {
+		if services().globals.read_only() {
+			return Ok(());
+		}
+
 		self.db.remove_to_device_events(user_id, device_id, until)
 	}
 
 	pub fn update_device_metadata(&self, user_id: &UserId, device_id: &DeviceId, device: &Device) -> Result<()> {
+		let _lock = self.device_metadata_lock.lock().expect("locked");
 		self.db.update_device_metadata(user_id, device_id, device)
 	}
 
+	/// Records the address and time of the latest request of the device.
+	/// Unlike other changes of device metadata, this is no device list update.
+	pub fn update_device_last_seen(&self, user_id: &UserId, device_id: &DeviceId, ip: IpAddr) -> Result<()> {
+		if services().globals.read_only() {
+			return Ok(());
+		}
+
+		let _lock = self.device_metadata_lock.lock().expect("locked");
+		let Some(mut device) = self.db.get_device_metadata(user_id, device_id)? else {
+			return Ok(());
+		};
+
+		let ip = ip.to_string();
+		let now = MilliSecondsSinceUnixEpoch::now();
+		let recent = device.last_seen_ts.is_some_and(|last_seen| {
+			u64::from(now.get()).saturating_sub(last_seen.get().into()) < LAST_SEEN_RESOLUTION
+		});
+		if recent && device.last_seen_ip.as_ref() == Some(&ip) {
+			return Ok(());
+		}
+
+		device.last_seen_ip = Some(ip);
+		device.last_seen_ts = Some(now);
+		self.db.update_device_last_seen(user_id, device_id, &device)
+	}
+
 	/// Get device metadata.
 	pub fn get_device_metadata(&self, user_id: &UserId, device_id: &DeviceId) -> Result<Option<Device>> {
 		self.db.get_device_metadata(user_id, device_id)
@@ -452,18 +723,84 @@ impl Service {
 		self.db.all_devices_metadata(user_id)
 	}
 
+	/// Returns the user's device IDs followed by their dehydrated device's, if
+	/// any. Use this for everything which must reach the dehydrated device.
+	pub fn all_device_ids_with_dehydrated<'a>(
+		&'a self, user_id: &UserId,
+	) -> impl Iterator<Item = Result<OwnedDeviceId>> + 'a {
+		let dehydrated = self
+			.db
+			.get_dehydrated_device(user_id)
+			.map(|device| device.map(|device| device.device_id))
+			.transpose();
+
+		self.all_device_ids(user_id).chain(dehydrated)
+	}
+
+	pub fn get_dehydrated_device(&self, user_id: &UserId) -> Result<Option<DehydratedDevice>> {
+		self.db.get_dehydrated_device(user_id)
+	}
+
+	/// Stores a new dehydrated device, replacing and removing the previous one
+	/// together with its keys and pending to-device messages.
+	pub fn set_dehydrated_device(&self, user_id: &UserId, device: &DehydratedDevice) -> Result<()> {
+		self.remove_dehydrated_device(user_id)?;
+		self.db.set_dehydrated_device(user_id, device)
+	}
+
+	/// Removes the dehydrated device with its keys and pending to-device
+	/// messages, returning its device ID.
+	pub fn remove_dehydrated_device(&self, user_id: &UserId) -> Result<Option<OwnedDeviceId>> {
+		let Some(device) = self.db.get_dehydrated_device(user_id)? else {
+			return Ok(None);
+		};
+
+		self.db.remove_device(user_id, &device.device_id)?;
+		self.db.remove_dehydrated_device(user_id)?;
+		self.db.mark_device_key_update(user_id)?;
+
+		Ok(Some(device.device_id))
+	}
+
+	pub fn get_to_device_events_after(
+		&self, user_id: &UserId, device_id: &DeviceId, since: u64, limit: usize,
+	) -> Result<Vec<(u64, Raw<AnyToDeviceEvent>)>> {
+		self.db
+			.get_to_device_events_after(user_id, device_id, since, limit)
+	}
+
+	/// Removes all devices of a user with everything needed for end-to-end
+	/// encryption: device and cross-signing keys, one-time keys, key backups
+	/// and pending to-device events. Other servers are notified that the
+	/// devices are gone. Returns the number of removed devices and entries.
+	pub fn remove_devices_and_keys(&self, user_id: &UserId) -> Result<usize> {
+		let device_ids = self.all_device_ids(user_id).collect::<Result<Vec<_>>>()?;
+		self.remove_devices(user_id, &device_ids)?;
+		let mut removed = device_ids.len();
+
+		if self.remove_dehydrated_device(user_id)?.is_some() {
+			removed = removed.saturating_add(1);
+		}
+
+		removed = removed
+			.saturating_add(self.db.remove_all_keys(user_id)?)
+			.saturating_add(services().key_backups.delete_all_backups(user_id)?);
+
+		Ok(removed)
+	}
+
 	/// Deactivate account
 	pub fn deactivate_account(&self, user_id: &UserId) -> Result<()> {
-		// Remove all associated devices
-		for device_id in self.all_device_ids(user_id) {
-			self.remove_device(user_id, &device_id?)?;
-		}
+		// Remove all associated devices and their keys
+		self.remove_devices_and_keys(user_id)?;
 
 		// Set the password to "" to indicate a deactivated account. Hashes will never
 		// result in an empty string, so the user will not be able to log in again.
 		// Systems like changing the password without logging in should check if the
 		// account is deactivated.
 		self.db.set_password(user_id, None)?;
+		self.db.set_guest_since(user_id, None)?;
+		self.directory.remove(user_id)?;
 
 		// TODO: Unhook 3PID
 		Ok(())
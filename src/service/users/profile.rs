@@ -0,0 +1,183 @@
+use std::{
+	collections::HashMap,
+	sync::Mutex,
+	time::{Duration, Instant},
+};
+
+use conduit::{debug, debug_warn, Result};
+use ruma::{api::federation, OwnedMxcUri, OwnedUserId, UserId};
+use tokio::task::JoinHandle;
+
+use crate::services;
+
+/// How long a failed fetch of a profile we have no copy of is remembered, so
+/// requests for users on unreachable servers fail right away meanwhile
+const FAILED_FETCH_TTL: Duration = Duration::from_secs(60);
+
+/// Freshness of our local copies of remote users' profiles. Copies are served
+/// right away and refreshed over federation in the background once older than
+/// `remote_profile_ttl_secs`. When there is no copy, the fetch is awaited for
+/// at most `remote_profile_fetch_timeout_ms` and then continues in the
+/// background; a failed fetch is remembered for `FAILED_FETCH_TTL`. Fetch
+/// times are only kept in memory; copies are considered stale after a restart.
+#[derive(Default)]
+pub struct RemoteProfiles {
+	users: Mutex<HashMap<OwnedUserId, Fetch>>,
+}
+
+#[derive(Default)]
+struct Fetch {
+	fetched_at: Option<Instant>,
+	failed_at: Option<Instant>,
+	in_flight: bool,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Profile {
+	pub displayname: Option<String>,
+	pub avatar_url: Option<OwnedMxcUri>,
+	pub blurhash: Option<String>,
+}
+
+impl RemoteProfiles {
+	/// The profile of a remote user. Returns `None` when we have no copy and
+	/// fetching it failed, and an empty profile when the fetch did not finish
+	/// in time.
+	pub async fn get(&self, user_id: &UserId) -> Result<Option<Profile>> {
+		if services().users.exists(user_id)? {
+			let ttl = Duration::from_secs(services().globals.config.remote_profile_ttl_secs);
+			if self.is_stale(user_id, ttl) {
+				_ = self.refresh(user_id);
+			}
+
+			return services().users.profile(user_id).map(Some);
+		}
+
+		if self.failed_recently(user_id) {
+			return Ok(None);
+		}
+
+		let Some(fetch) = self.refresh(user_id) else {
+			// fetched for another request already
+			return Ok(Some(Profile::default()));
+		};
+
+		let timeout = Duration::from_millis(services().globals.config.remote_profile_fetch_timeout_ms);
+		match tokio::time::timeout(timeout, fetch).await {
+			Ok(Ok(Ok(profile))) => Ok(Some(profile)),
+			Ok(Ok(Err(e))) => {
+				debug_warn!("Failed to fetch the profile of {user_id}: {e}");
+				Ok(None)
+			},
+			Ok(Err(e)) => {
+				debug_warn!("Fetching the profile of {user_id} panicked: {e}");
+				Ok(None)
+			},
+			Err(_) => {
+				debug!("Fetching the profile of {user_id} takes longer than {timeout:?}, continuing in the background");
+				Ok(Some(Profile::default()))
+			},
+		}
+	}
+
+	/// Our local copy of a remote user's profile, without fetching it
+	pub fn cached(&self, user_id: &UserId) -> Result<Option<Profile>> {
+		services()
+			.users
+			.exists(user_id)?
+			.then(|| services().users.profile(user_id))
+			.transpose()
+	}
+
+	/// Number of remote users whose fetches are tracked
+	pub fn len(&self) -> usize { self.users.lock().expect("locked").len() }
+
+	#[must_use]
+	pub fn is_empty(&self) -> bool { self.len() == 0 }
+
+	/// Forgets when profiles were fetched, so every copy is refreshed on its
+	/// next use and failed fetches are retried
+	pub fn clear(&self) { self.users.lock().expect("locked").clear(); }
+
+	fn failed_recently(&self, user_id: &UserId) -> bool {
+		self.users
+			.lock()
+			.expect("locked")
+			.get(user_id)
+			.and_then(|fetch| fetch.failed_at)
+			.is_some_and(|failed_at| failed_at.elapsed() < FAILED_FETCH_TTL)
+	}
+
+	fn is_stale(&self, user_id: &UserId, ttl: Duration) -> bool {
+		!self
+			.users
+			.lock()
+			.expect("locked")
+			.get(user_id)
+			.and_then(|fetch| fetch.fetched_at)
+			.is_some_and(|fetched_at| fetched_at.elapsed() < ttl)
+	}
+
+	/// Fetches the profile in a task of its own, unless a fetch for the user is
+	/// in flight already.
+	fn refresh(&self, user_id: &UserId) -> Option<JoinHandle<Result<Profile>>> {
+		let mut users = self.users.lock().expect("locked");
+		let fetch = users.entry(user_id.to_owned()).or_default();
+		if fetch.in_flight {
+			return None;
+		}
+
+		fetch.in_flight = true;
+		let user_id = user_id.to_owned();
+		Some(services().server.runtime().spawn(async move {
+			let result = fetch_profile(&user_id).await;
+			services().users.profiles.fetched(&user_id, result.is_ok());
+			result
+		}))
+	}
+
+	/// Records a finished fetch; failed ones are not retried before the TTL
+	/// either, so unreachable servers are not asked on every request.
+	fn fetched(&self, user_id: &UserId, succeeded: bool) {
+		if let Some(fetch) = self.users.lock().expect("locked").get_mut(user_id) {
+			fetch.in_flight = false;
+			fetch.fetched_at = Some(Instant::now());
+			fetch.failed_at = (!succeeded).then(Instant::now);
+		}
+	}
+}
+
+/// Fetches the profile over federation and updates our local copy
+async fn fetch_profile(user_id: &UserId) -> Result<Profile> {
+	let response = services()
+		.sending
+		.send_federation_request(
+			user_id.server_name(),
+			federation::query::get_profile_information::v1::Request {
+				user_id: user_id.to_owned(),
+				field: None,
+			},
+		)
+		.await?;
+
+	if !services().users.exists(user_id)? {
+		services().users.create(user_id, None)?;
+	}
+
+	let users = &services().users;
+	users
+		.set_displayname(user_id, response.displayname.clone())
+		.await?;
+	users
+		.set_avatar_url(user_id, response.avatar_url.clone())
+		.await?;
+	users
+		.set_blurhash(user_id, response.blurhash.clone())
+		.await?;
+
+	Ok(Profile {
+		displayname: response.displayname,
+		avatar_url: response.avatar_url,
+		blurhash: response.blurhash,
+	})
+}
@@ -0,0 +1,130 @@
+use std::{
+	collections::BTreeMap,
+	sync::{Arc, Mutex},
+	time::{Instant, SystemTime},
+};
+
+use ruma::{DeviceId, OwnedDeviceId, OwnedUserId, UserId};
+use tokio::sync::Notify;
+
+/// Sync requests of each device, for debugging clients whose sync never
+/// returns. Long-polls register on connect and unregister when the request
+/// finishes or is dropped; the last response is remembered in memory.
+#[derive(Default)]
+pub struct SyncSessions {
+	devices: Mutex<BTreeMap<(OwnedUserId, OwnedDeviceId), SyncSession>>,
+}
+
+#[derive(Clone, Debug)]
+pub struct SyncSession {
+	/// Number of sync requests of the device currently in progress
+	pub connections: usize,
+
+	/// Since token presented by the latest request
+	pub since: Option<String>,
+
+	/// When the latest request in progress started
+	pub connected_at: Option<Instant>,
+
+	/// When the last response carrying data was returned
+	pub last_response: Option<SystemTime>,
+
+	kick: Arc<Notify>,
+}
+
+/// Registration of a sync request in progress, unregistered when dropped
+pub struct SyncGuard<'a> {
+	sessions: &'a SyncSessions,
+	user_id: OwnedUserId,
+	device_id: OwnedDeviceId,
+	kick: Arc<Notify>,
+}
+
+impl SyncSessions {
+	/// Registers a sync request of the device
+	pub fn connect(&self, user_id: &UserId, device_id: &DeviceId, since: Option<&str>) -> SyncGuard<'_> {
+		let mut devices = self.devices.lock().expect("locked");
+		let session = devices
+			.entry((user_id.to_owned(), device_id.to_owned()))
+			.or_insert_with(|| SyncSession {
+				connections: 0,
+				since: None,
+				connected_at: None,
+				last_response: None,
+				kick: Arc::new(Notify::new()),
+			});
+
+		session.connections = session.connections.saturating_add(1);
+		session.since = since.map(ToOwned::to_owned);
+		session.connected_at = Some(Instant::now());
+
+		SyncGuard {
+			sessions: self,
+			user_id: user_id.to_owned(),
+			device_id: device_id.to_owned(),
+			kick: session.kick.clone(),
+		}
+	}
+
+	/// Sync sessions of the user's devices
+	#[must_use]
+	pub fn get(&self, user_id: &UserId) -> Vec<(OwnedDeviceId, SyncSession)> {
+		self.devices
+			.lock()
+			.expect("locked")
+			.iter()
+			.filter(|((user, _), _)| user == user_id)
+			.map(|((_, device), session)| (device.clone(), session.clone()))
+			.collect()
+	}
+
+	/// Makes the long-polls of the user in progress return immediately, only
+	/// those of `device_id` if given. Returns the devices which were kicked.
+	pub fn kick(&self, user_id: &UserId, device_id: Option<&DeviceId>) -> Vec<OwnedDeviceId> {
+		self.devices
+			.lock()
+			.expect("locked")
+			.iter()
+			.filter(|((user, device), session)| {
+				user == user_id
+					&& (device_id.is_none() || device_id == Some(device.as_ref()))
+					&& session.connections > 0
+			})
+			.map(|((_, device), session)| {
+				session.kick.notify_waiters();
+				device.clone()
+			})
+			.collect()
+	}
+}
+
+impl SyncGuard<'_> {
+	/// Notified when the sync request is kicked by an admin
+	#[must_use]
+	pub fn kick(&self) -> Arc<Notify> { self.kick.clone() }
+
+	/// Records that the request returned data
+	pub fn responded(&self) {
+		if let Some(session) = self
+			.sessions
+			.devices
+			.lock()
+			.expect("locked")
+			.get_mut(&(self.user_id.clone(), self.device_id.clone()))
+		{
+			session.last_response = Some(SystemTime::now());
+		}
+	}
+}
+
+impl Drop for SyncGuard<'_> {
+	fn drop(&mut self) {
+		let mut devices = self.sessions.devices.lock().expect("locked");
+		if let Some(session) = devices.get_mut(&(self.user_id.clone(), self.device_id.clone())) {
+			session.connections = session.connections.saturating_sub(1);
+			if session.connections == 0 {
+				session.connected_at = None;
+			}
+		}
+	}
+}
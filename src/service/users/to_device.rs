@@ -0,0 +1,193 @@
+use std::{collections::BTreeMap, sync::Mutex};
+
+use conduit::{
+	utils::{mutex_map, MutexMap},
+	Error, Result,
+};
+use ruma::{
+	api::client::error::ErrorKind, serde::Raw, to_device::DeviceIdOrAllDevices, OwnedDeviceId, OwnedUserId, UserId,
+};
+
+/// Event types of the notices that a sender withholds room keys from a device
+const WITHHELD_EVENT_TYPES: [&str; 2] = ["m.room_key.withheld", "org.matrix.room_key.withheld"];
+
+/// Reason codes of withheld notices counted on their own; others are counted
+/// as `other` so senders cannot grow the counts without bounds
+const WITHHELD_CODES: [&str; 5] = ["m.blacklisted", "m.unverified", "m.unauthorised", "m.unavailable", "m.no_olm"];
+
+/// Ordering of the to-device messages relayed to local devices. The messages
+/// of one sender are stored one request or EDU at a time, in the order they
+/// were received, so a device gets `m.room_key.withheld` notices in order with
+/// the keys they relate to. Also counts the relayed withheld notices per reason
+/// code; the counts are only kept in memory.
+#[derive(Default)]
+pub struct ToDeviceRelay {
+	senders: MutexMap<OwnedUserId, ()>,
+	withheld: Mutex<BTreeMap<String, u64>>,
+}
+
+/// One message for each recipient device, in the order they are stored
+pub type Expanded = Vec<(OwnedUserId, OwnedDeviceId, serde_json::Value)>;
+
+impl ToDeviceRelay {
+	/// Held while the messages of a request or EDU of `sender` are checked
+	/// against the transaction IDs and stored
+	pub async fn lock(&self, sender: &UserId) -> mutex_map::Guard<()> { self.senders.lock(sender).await }
+
+	/// Numbers of withheld notices relayed since startup, by reason code
+	#[must_use]
+	pub fn withheld_counts(&self) -> BTreeMap<String, u64> { self.withheld.lock().expect("locked").clone() }
+
+	pub(super) fn observe(&self, event_type: &str, content: &serde_json::Value) {
+		if !WITHHELD_EVENT_TYPES.contains(&event_type) {
+			return;
+		}
+
+		let code = content
+			.get("code")
+			.and_then(serde_json::Value::as_str)
+			.filter(|code| WITHHELD_CODES.contains(code))
+			.unwrap_or("other");
+
+		let mut withheld = self.withheld.lock().expect("locked");
+		let count = withheld.entry(code.to_owned()).or_default();
+		*count = count.saturating_add(1);
+	}
+}
+
+/// Expands the messages of a request or EDU to the recipient devices, with
+/// `devices` listing the devices of a user for messages to all of them. Every
+/// message is parsed before any is stored, so a bad message fails the whole
+/// batch instead of storing part of it.
+pub fn expand<'a, T, M, D>(messages: M, devices: D) -> Result<Expanded>
+where
+	T: 'a,
+	M: IntoIterator<Item = (&'a OwnedUserId, &'a BTreeMap<DeviceIdOrAllDevices, Raw<T>>)>,
+	D: Fn(&UserId) -> Result<Vec<OwnedDeviceId>>,
+{
+	let mut expanded = Vec::new();
+	for (target_user_id, map) in messages {
+		for (target_device_id_maybe, event) in map {
+			let content: serde_json::Value = event
+				.deserialize_as()
+				.map_err(|_| Error::BadRequest(ErrorKind::InvalidParam, "Event is invalid"))?;
+
+			match target_device_id_maybe {
+				DeviceIdOrAllDevices::DeviceId(target_device_id) => {
+					expanded.push((target_user_id.clone(), target_device_id.clone(), content));
+				},
+
+				DeviceIdOrAllDevices::AllDevices => {
+					for target_device_id in devices(target_user_id)? {
+						expanded.push((target_user_id.clone(), target_device_id, content.clone()));
+					}
+				},
+			}
+		}
+	}
+
+	Ok(expanded)
+}
+
+#[cfg(test)]
+mod tests {
+	use std::collections::BTreeMap;
+
+	use ruma::{
+		device_id, events::AnyToDeviceEventContent, serde::Raw, to_device::DeviceIdOrAllDevices, user_id, OwnedUserId,
+	};
+	use serde_json::json;
+
+	use super::{expand, ToDeviceRelay};
+	use crate::users::data::{to_device_key, to_device_prefix, to_device_value};
+
+	type Messages = BTreeMap<OwnedUserId, BTreeMap<DeviceIdOrAllDevices, Raw<AnyToDeviceEventContent>>>;
+
+	fn edu(content: &serde_json::Value) -> Messages {
+		let mut devices = BTreeMap::new();
+		devices.insert(
+			DeviceIdOrAllDevices::DeviceId(device_id!("LAPTOP").to_owned()),
+			Raw::from_json(serde_json::value::to_raw_value(content).unwrap()),
+		);
+		devices.insert(
+			DeviceIdOrAllDevices::AllDevices,
+			Raw::from_json(serde_json::value::to_raw_value(content).unwrap()),
+		);
+
+		let mut messages = BTreeMap::new();
+		messages.insert(user_id!("@alice:example.com").to_owned(), devices);
+		messages
+	}
+
+	#[test]
+	fn interleaved_keys_and_withheld_keep_send_order() {
+		let sent = [
+			("m.room_key", json!({"session_id": "a"})),
+			("m.room_key.withheld", json!({"session_id": "b", "code": "m.unverified"})),
+			("m.room_key", json!({"session_id": "c"})),
+			("m.room_key.withheld", json!({"session_id": "d", "code": "m.blacklisted"})),
+			("m.room_key.withheld", json!({"session_id": "e", "code": "m.unverified"})),
+		];
+		let (alice, bob) = (user_id!("@alice:example.com"), user_id!("@bob:example.com"));
+
+		// the EDUs of a remote transaction, stored the way
+		// `add_to_device_events` does, with the counts of the other senders'
+		// messages in between; the counts cross a byte boundary
+		let relay = ToDeviceRelay::default();
+		let mut todeviceid_events = BTreeMap::new();
+		let mut count = 250_u64;
+		for (event_type, content) in &sent {
+			let expanded = expand(&edu(content), |_| {
+				Ok(vec![device_id!("PHONE").to_owned(), device_id!("LAPTOP").to_owned()])
+			})
+			.unwrap();
+
+			for (user_id, device_id, content) in expanded {
+				relay.observe(event_type, &content);
+				count += 2;
+				todeviceid_events.insert(
+					to_device_key(&user_id, &device_id, count),
+					to_device_value(bob, event_type, content),
+				);
+			}
+		}
+
+		for device_id in [device_id!("PHONE"), device_id!("LAPTOP")] {
+			// what `get_to_device_events` reads for the device
+			let prefix = to_device_prefix(alice, device_id);
+			let received: Vec<_> = todeviceid_events
+				.range(prefix.clone()..)
+				.take_while(|(key, _)| key.starts_with(&prefix))
+				.map(|(_, value)| {
+					let event: serde_json::Value = serde_json::from_slice(value).unwrap();
+					assert_eq!(event["sender"], bob.as_str());
+					(
+						event["type"].as_str().unwrap().to_owned(),
+						event["content"]["session_id"].as_str().unwrap().to_owned(),
+					)
+				})
+				.collect();
+
+			let mut expected: Vec<_> = sent
+				.iter()
+				.map(|(event_type, content)| {
+					((*event_type).to_owned(), content["session_id"].as_str().unwrap().to_owned())
+				})
+				.collect();
+			if device_id.as_str() == "LAPTOP" {
+				// addressed directly and through all devices
+				expected = expected
+					.into_iter()
+					.flat_map(|message| [message.clone(), message])
+					.collect();
+			}
+
+			assert_eq!(received, expected);
+		}
+
+		let counts = relay.withheld_counts();
+		assert_eq!(counts.get("m.unverified"), Some(&6));
+		assert_eq!(counts.get("m.blacklisted"), Some(&3));
+		assert_eq!(counts.len(), 2);
+	}
+}
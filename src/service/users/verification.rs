@@ -0,0 +1,504 @@
+use std::{
+	collections::{BTreeSet, HashMap},
+	hash::Hash,
+	sync::{
+		atomic::{AtomicU64, Ordering},
+		Mutex,
+	},
+	time::{Duration, Instant},
+};
+
+use conduit::{debug, warn, Result};
+use ruma::{DeviceId, OwnedDeviceId, OwnedServerName, OwnedUserId, UserId};
+use serde_json::json;
+use tokio::{task::JoinHandle, time::interval};
+
+use crate::{services, user_is_local};
+
+/// Inactivity after which a verification is considered timed out, as
+/// specified for `m.key.verification.*` transactions
+pub const VERIFICATION_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+
+/// How long a timed out verification with a remote counterpart is still
+/// reported as stuck before it is forgotten
+const STUCK_RETENTION: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// How often verifications are checked for their timeout
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Verifications tracked for one requesting user, and for the users of one
+/// server; further requests are relayed without being tracked
+const MAX_PER_USER: usize = 16;
+const MAX_PER_ORIGIN: usize = 1024;
+
+/// In-flight device verifications seen in the to-device messages delivered to
+/// local devices, from `m.key.verification.request` until `done` or `cancel`.
+///
+/// Verifications between two local users are cancelled on behalf of both
+/// sides when they time out. With a remote counterpart the messages are only
+/// relayed, so a timed out verification is merely reported as stuck.
+#[derive(Default)]
+pub struct Verifications {
+	transactions: Mutex<Transactions>,
+	completed: AtomicU64,
+	cancelled: AtomicU64,
+	timed_out: AtomicU64,
+}
+
+#[derive(Clone, Debug)]
+pub struct Verification {
+	pub requester: OwnedUserId,
+	pub requester_device: OwnedDeviceId,
+	pub recipient: OwnedUserId,
+
+	/// Devices the request was delivered to, narrowed down to the responding
+	/// device once the request is accepted
+	pub recipient_devices: BTreeSet<OwnedDeviceId>,
+
+	pub started: Instant,
+	last_activity: Instant,
+}
+
+#[derive(Debug, Default)]
+pub struct VerificationStats {
+	pub active: usize,
+
+	/// Timed out verifications with a remote counterpart
+	pub stuck: Vec<(String, Verification)>,
+
+	pub completed: u64,
+	pub cancelled: u64,
+	pub timed_out: u64,
+}
+
+/// Verifications by transaction ID, with their number per requesting user
+/// and server
+#[derive(Default)]
+struct Transactions {
+	entries: HashMap<String, Verification>,
+	per_user: HashMap<OwnedUserId, usize>,
+	per_origin: HashMap<OwnedServerName, usize>,
+}
+
+impl Verifications {
+	/// Tracks the verification a to-device message delivered to a local
+	/// device belongs to
+	pub(super) fn observe(
+		&self, sender: &UserId, target_user_id: &UserId, target_device_id: &DeviceId, event_type: &str,
+		content: &serde_json::Value,
+	) {
+		self.observe_at(Instant::now(), sender, target_user_id, target_device_id, event_type, content);
+	}
+
+	fn observe_at(
+		&self, now: Instant, sender: &UserId, target_user_id: &UserId, target_device_id: &DeviceId, event_type: &str,
+		content: &serde_json::Value,
+	) -> Option<()> {
+		let kind = event_type.strip_prefix("m.key.verification.")?;
+		let transaction_id = content.get("transaction_id")?.as_str()?;
+		let from_device = || -> Option<OwnedDeviceId> { Some(content.get("from_device")?.as_str()?.into()) };
+
+		let mut transactions = self.transactions.lock().expect("locked");
+		if kind == "request" {
+			let requester_device = from_device()?;
+			if !transactions.entries.contains_key(transaction_id) {
+				if !transactions.has_room_for(sender) {
+					debug!(%sender, %transaction_id, "Not tracking verification over the limit");
+					return None;
+				}
+
+				transactions.insert(
+					transaction_id.to_owned(),
+					Verification {
+						requester: sender.to_owned(),
+						requester_device,
+						recipient: target_user_id.to_owned(),
+						recipient_devices: BTreeSet::new(),
+						started: now,
+						last_activity: now,
+					},
+				);
+			}
+
+			let verification = transactions.entries.get_mut(transaction_id)?;
+			if sender == verification.requester && target_user_id == verification.recipient {
+				verification
+					.recipient_devices
+					.insert(target_device_id.to_owned());
+			}
+
+			return None;
+		}
+
+		let verification = transactions.entries.get_mut(transaction_id)?;
+		if sender != verification.requester && sender != verification.recipient {
+			return None;
+		}
+
+		match kind {
+			"done" => {
+				transactions.remove(transaction_id);
+				self.completed.fetch_add(1, Ordering::Relaxed);
+			},
+
+			// the requester tells the other devices of the recipient that one
+			// of them accepted; the verification itself goes on
+			"cancel" if content.get("code").and_then(|code| code.as_str()) == Some("m.accepted") => {
+				verification.last_activity = now;
+			},
+
+			"cancel" => {
+				transactions.remove(transaction_id);
+				self.cancelled.fetch_add(1, Ordering::Relaxed);
+			},
+
+			"ready" if sender == verification.recipient => {
+				if let Some(device) = from_device() {
+					verification.recipient_devices = BTreeSet::from([device]);
+				}
+				verification.last_activity = now;
+			},
+
+			_ => verification.last_activity = now,
+		}
+
+		None
+	}
+
+	/// Active verifications and the counts of those finished since startup
+	#[must_use]
+	pub fn stats(&self) -> VerificationStats { self.stats_at(Instant::now()) }
+
+	fn stats_at(&self, now: Instant) -> VerificationStats {
+		let transactions = self.transactions.lock().expect("locked");
+		let stuck: Vec<_> = transactions
+			.entries
+			.iter()
+			.filter(|(_, verification)| verification.is_timed_out(now))
+			.map(|(transaction_id, verification)| (transaction_id.clone(), verification.clone()))
+			.collect();
+
+		VerificationStats {
+			active: transactions.entries.len().saturating_sub(stuck.len()),
+			stuck,
+			completed: self.completed.load(Ordering::Relaxed),
+			cancelled: self.cancelled.load(Ordering::Relaxed),
+			timed_out: self.timed_out.load(Ordering::Relaxed),
+		}
+	}
+
+	/// Forgets the verifications which timed out, returning those `is_local`
+	/// to be cancelled. The others are reported as stuck for a while first.
+	fn sweep<F>(&self, now: Instant, is_local: F) -> Vec<(String, Verification)>
+	where
+		F: Fn(&Verification) -> bool,
+	{
+		let mut transactions = self.transactions.lock().expect("locked");
+		let expired: Vec<_> = transactions
+			.entries
+			.iter()
+			.filter(|(_, verification)| {
+				let idle = now.saturating_duration_since(verification.last_activity);
+				if is_local(verification) {
+					idle >= VERIFICATION_TIMEOUT
+				} else {
+					idle >= VERIFICATION_TIMEOUT.saturating_add(STUCK_RETENTION)
+				}
+			})
+			.map(|(transaction_id, _)| transaction_id.clone())
+			.collect();
+
+		let mut timed_out = Vec::new();
+		for transaction_id in expired {
+			let verification = transactions
+				.remove(&transaction_id)
+				.expect("verification exists");
+
+			if is_local(&verification) {
+				self.timed_out.fetch_add(1, Ordering::Relaxed);
+				timed_out.push((transaction_id, verification));
+			}
+		}
+
+		timed_out
+	}
+}
+
+impl Transactions {
+	fn has_room_for(&self, requester: &UserId) -> bool {
+		self.per_user.get(requester).copied().unwrap_or(0) < MAX_PER_USER
+			&& self
+				.per_origin
+				.get(requester.server_name())
+				.copied()
+				.unwrap_or(0)
+				< MAX_PER_ORIGIN
+	}
+
+	fn insert(&mut self, transaction_id: String, verification: Verification) {
+		increment(&mut self.per_user, verification.requester.clone());
+		increment(&mut self.per_origin, verification.requester.server_name().to_owned());
+		self.entries.insert(transaction_id, verification);
+	}
+
+	fn remove(&mut self, transaction_id: &str) -> Option<Verification> {
+		let verification = self.entries.remove(transaction_id)?;
+		decrement(&mut self.per_user, &verification.requester);
+		decrement(&mut self.per_origin, verification.requester.server_name());
+
+		Some(verification)
+	}
+}
+
+impl Verification {
+	fn is_local(&self) -> bool { user_is_local(&self.requester) && user_is_local(&self.recipient) }
+
+	fn is_timed_out(&self, now: Instant) -> bool {
+		now.saturating_duration_since(self.last_activity) >= VERIFICATION_TIMEOUT
+	}
+}
+
+fn increment<K: Eq + Hash>(counts: &mut HashMap<K, usize>, key: K) {
+	let count = counts.entry(key).or_default();
+	*count = count.saturating_add(1);
+}
+
+fn decrement<K, Q>(counts: &mut HashMap<K, usize>, key: &Q)
+where
+	K: Eq + Hash + std::borrow::Borrow<Q>,
+	Q: Eq + Hash + ?Sized,
+{
+	if let Some(count) = counts.get_mut(key) {
+		*count = count.saturating_sub(1);
+		if *count == 0 {
+			counts.remove(key);
+		}
+	}
+}
+
+/// Cancels the verifications between local users which timed out, and
+/// forgets the stuck ones with remote users.
+#[tracing::instrument]
+pub fn start_verification_timeout_task() -> JoinHandle<()> {
+	services().server.runtime().spawn(async move {
+		let mut i = interval(SWEEP_INTERVAL);
+
+		loop {
+			i.tick().await;
+
+			let timed_out = services()
+				.users
+				.verifications
+				.sweep(Instant::now(), Verification::is_local);
+
+			for (transaction_id, verification) in timed_out {
+				if let Err(e) = cancel_timed_out(&transaction_id, &verification) {
+					warn!("Failed to cancel timed out verification {transaction_id}: {e}");
+				}
+			}
+		}
+	})
+}
+
+/// Sends an `m.timeout` cancel to both sides of a verification, each
+/// appearing to come from the other side.
+fn cancel_timed_out(transaction_id: &str, verification: &Verification) -> Result<()> {
+	debug!(%transaction_id, requester = %verification.requester, recipient = %verification.recipient, "verification timed out");
+
+	let content = json!({
+		"transaction_id": transaction_id,
+		"code": "m.timeout",
+		"reason": "The verification timed out.",
+	});
+
+	services().users.db.add_to_device_event(
+		&verification.recipient,
+		&verification.requester,
+		&verification.requester_device,
+		"m.key.verification.cancel",
+		content.clone(),
+	)?;
+
+	for device in &verification.recipient_devices {
+		services().users.db.add_to_device_event(
+			&verification.requester,
+			&verification.recipient,
+			device,
+			"m.key.verification.cancel",
+			content.clone(),
+		)?;
+	}
+
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use std::time::{Duration, Instant};
+
+	use ruma::{device_id, user_id, UserId};
+	use serde_json::json;
+
+	use super::{Verification, Verifications, MAX_PER_ORIGIN, MAX_PER_USER, STUCK_RETENTION, VERIFICATION_TIMEOUT};
+
+	fn is_local(verification: &Verification) -> bool {
+		verification.requester.server_name() == "example.org" && verification.recipient.server_name() == "example.org"
+	}
+
+	fn send(
+		verifications: &Verifications, now: Instant, sender: &UserId, target: &UserId, kind: &str,
+		content: serde_json::Value,
+	) {
+		verifications.observe_at(
+			now,
+			sender,
+			target,
+			device_id!("TARGET"),
+			&format!("m.key.verification.{kind}"),
+			&content,
+		);
+	}
+
+	fn request(verifications: &Verifications, now: Instant, sender: &UserId, target: &UserId, transaction_id: &str) {
+		let content = json!({ "transaction_id": transaction_id, "from_device": "REQUESTER" });
+		send(verifications, now, sender, target, "request", content);
+	}
+
+	#[test]
+	fn verification_states() {
+		let (alice, bob, eve) = (
+			user_id!("@alice:example.org"),
+			user_id!("@bob:example.org"),
+			user_id!("@eve:example.org"),
+		);
+		let verifications = Verifications::default();
+		let now = Instant::now();
+
+		request(&verifications, now, alice, bob, "one");
+		request(&verifications, now, alice, bob, "two");
+		assert_eq!(verifications.stats_at(now).active, 2);
+
+		// a request reusing the transaction ID does not join the verification
+		request(&verifications, now, eve, bob, "one");
+		send(
+			&verifications,
+			now,
+			eve,
+			alice,
+			"cancel",
+			json!({ "transaction_id": "one", "code": "m.user" }),
+		);
+		assert_eq!(verifications.stats_at(now).active, 2);
+
+		let ready = json!({ "transaction_id": "one", "from_device": "BOBDEVICE" });
+		send(&verifications, now, bob, alice, "ready", ready);
+		let accepted = json!({ "transaction_id": "one", "code": "m.accepted" });
+		send(&verifications, now, alice, bob, "cancel", accepted);
+		assert_eq!(verifications.stats_at(now).active, 2);
+
+		send(&verifications, now, bob, alice, "done", json!({ "transaction_id": "one" }));
+		send(
+			&verifications,
+			now,
+			bob,
+			alice,
+			"cancel",
+			json!({ "transaction_id": "two", "code": "m.user" }),
+		);
+
+		let stats = verifications.stats_at(now);
+		assert_eq!(stats.active, 0);
+		assert_eq!(stats.completed, 1);
+		assert_eq!(stats.cancelled, 1);
+		assert!(verifications
+			.transactions
+			.lock()
+			.unwrap()
+			.per_user
+			.is_empty());
+	}
+
+	#[test]
+	fn verification_timeouts() {
+		let (alice, bob, remote) = (
+			user_id!("@alice:example.org"),
+			user_id!("@bob:example.org"),
+			user_id!("@carol:example.com"),
+		);
+		let verifications = Verifications::default();
+		let now = Instant::now();
+
+		request(&verifications, now, alice, bob, "local");
+		request(&verifications, now, remote, bob, "remote");
+
+		// activity postpones the timeout
+		let later = now + VERIFICATION_TIMEOUT / 2;
+		send(
+			&verifications,
+			later,
+			bob,
+			alice,
+			"ready",
+			json!({ "transaction_id": "local", "from_device": "BOB" }),
+		);
+		assert!(verifications
+			.sweep(now + VERIFICATION_TIMEOUT, is_local)
+			.is_empty());
+
+		let timed_out = verifications.sweep(later + VERIFICATION_TIMEOUT, is_local);
+		assert_eq!(timed_out.len(), 1);
+		assert_eq!(timed_out[0].0, "local");
+		assert_eq!(timed_out[0].1.recipient_devices.len(), 1);
+
+		// with a remote counterpart it is only reported as stuck for a while
+		let stats = verifications.stats_at(later + VERIFICATION_TIMEOUT);
+		assert_eq!(stats.stuck.len(), 1);
+		assert_eq!(stats.timed_out, 1);
+
+		let forgotten = now + VERIFICATION_TIMEOUT + STUCK_RETENTION + Duration::from_secs(1);
+		assert!(verifications.sweep(forgotten, is_local).is_empty());
+		assert_eq!(verifications.stats_at(forgotten).stuck.len(), 0);
+	}
+
+	#[test]
+	fn verification_limits() {
+		let (alice, bob) = (user_id!("@alice:example.org"), user_id!("@bob:example.org"));
+		let verifications = Verifications::default();
+		let now = Instant::now();
+
+		for i in 0..=MAX_PER_USER {
+			request(&verifications, now, alice, bob, &format!("alice{i}"));
+		}
+		assert_eq!(verifications.stats_at(now).active, MAX_PER_USER);
+
+		let users: Vec<_> = (0..MAX_PER_ORIGIN)
+			.map(|i| UserId::parse(format!("@user{i}:example.com")).unwrap())
+			.collect();
+		for user in &users {
+			request(&verifications, now, user, bob, user.as_str());
+		}
+		assert_eq!(verifications.stats_at(now).active, MAX_PER_USER + MAX_PER_ORIGIN);
+
+		let other = user_id!("@other:example.com");
+		request(&verifications, now, other, bob, "other");
+		assert_eq!(verifications.stats_at(now).active, MAX_PER_USER + MAX_PER_ORIGIN);
+
+		// finished verifications make room again
+		send(
+			&verifications,
+			now,
+			bob,
+			&users[0],
+			"cancel",
+			json!({ "transaction_id": users[0].as_str(), "code": "m.user" }),
+		);
+		request(&verifications, now, other, bob, "other");
+		assert_eq!(verifications.stats_at(now).active, MAX_PER_USER + MAX_PER_ORIGIN);
+		assert!(verifications
+			.transactions
+			.lock()
+			.unwrap()
+			.entries
+			.contains_key("other"));
+	}
+}